@@ -15,7 +15,15 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-pub(super) static mut HHDM_ADDRESS: u64 = 0;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Where the bootloader mapped all of physical memory
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Sets up `as_hhdm` and `as_phys_hhdm`
+pub fn set_hhdm_offset(offset: u64) {
+    HHDM_OFFSET.store(offset, Ordering::Relaxed);
+}
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VirtAddr(u64);
@@ -45,7 +53,7 @@ impl VirtAddr {
 
     #[inline]
     pub fn as_phys_hhdm(self) -> PhysAddr {
-        unsafe { PhysAddr(self.as_u64() - HHDM_ADDRESS) }
+        PhysAddr(self.as_u64() - HHDM_OFFSET.load(Ordering::Relaxed))
     }
 }
 
@@ -62,6 +70,32 @@ impl PhysAddr {
 
     #[inline]
     pub fn as_hhdm(self) -> VirtAddr {
-        unsafe { VirtAddr(self.as_u64() + HHDM_ADDRESS) }
+        VirtAddr(self.as_u64() + HHDM_OFFSET.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every test sets the same offset, they run in parallel
+    const OFFSET: u64 = 0xFFFF_8000_0000_0000;
+
+    #[test]
+    fn hhdm_round_trips() {
+        set_hhdm_offset(OFFSET);
+
+        let phys = PhysAddr::new(0x12_3000);
+        assert_eq!(phys.as_hhdm().as_u64(), OFFSET + 0x12_3000);
+        assert_eq!(phys.as_hhdm().as_phys_hhdm(), phys);
+    }
+
+    #[test]
+    fn addresses_order_by_value() {
+        set_hhdm_offset(OFFSET);
+
+        assert!(VirtAddr::new(0x1000) < VirtAddr::new(0x2000));
+        assert_eq!(VirtAddr::default().as_u64(), 0);
+        assert_eq!(VirtAddr::new(0x10).as_ptr::<u8>() as u64, 0x10);
     }
 }
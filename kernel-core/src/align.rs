@@ -0,0 +1,51 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/// Rounds `addr` down to a multiple of `align`, which has to be a power of two
+#[inline]
+pub const fn align_down(addr: u64, align: u64) -> u64 {
+    addr & !(align - 1)
+}
+
+/// Rounds `addr` up to a multiple of `align`, which has to be a power of two
+#[inline]
+pub const fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_values_stay() {
+        for align in [1, 8, 4096, 1 << 21] {
+            assert_eq!(align_down(align * 3, align), align * 3);
+            assert_eq!(align_up(align * 3, align), align * 3);
+        }
+    }
+
+    #[test]
+    fn rounds_to_the_neighbouring_multiples() {
+        assert_eq!(align_down(0x1234, 0x1000), 0x1000);
+        assert_eq!(align_up(0x1234, 0x1000), 0x2000);
+        assert_eq!(align_up(1, 8), 8);
+        assert_eq!(align_down(7, 8), 0);
+        assert_eq!(align_up(0, 4096), 0);
+    }
+}
@@ -0,0 +1,231 @@
+use core::ops::Range;
+
+/// Bits scanned at a time
+const WORD_BITS: usize = u64::BITS as usize;
+
+pub struct Bitmap<'a> {
+    inner: &'a mut [u8],
+}
+
+impl<'a> Bitmap<'a> {
+    pub fn new(inner: &'a mut [u8]) -> Bitmap<'a> {
+        Bitmap { inner }
+    }
+}
+
+impl Bitmap<'_> {
+    pub fn test(&self, idx: usize) -> bool {
+        (self.inner[idx / 8] & (1 << (idx % 8))) != 0
+    }
+
+    pub fn set(&mut self, idx: usize) {
+        self.inner[idx / 8] |= 1 << (idx % 8);
+    }
+
+    pub fn unset(&mut self, idx: usize) {
+        self.inner[idx / 8] &= !(1 << (idx % 8));
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len() * 8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// The first clear bit
+    pub fn find_first_zero(&self) -> Option<usize> {
+        let bit = self.scan(0, self.len(), true);
+        (bit < self.len()).then_some(bit)
+    }
+
+    /// The start of the first `len` clear bits in a row
+    pub fn find_zero_run(&self, len: usize) -> Option<usize> {
+        self.find_zero_run_from(0, len)
+    }
+
+    /// Like `find_zero_run`, but only looks at bits from `from` on
+    pub fn find_zero_run_from(&self, from: usize, len: usize) -> Option<usize> {
+        let mut start = from;
+
+        while start < self.len() {
+            start = self.scan(start, self.len(), true);
+            let end = start.checked_add(len).filter(|&end| end <= self.len())?;
+
+            // The first set bit cuts the run short, the next one can't start before it
+            let set = self.scan(start, end, false);
+            if set == end {
+                return Some(start);
+            }
+
+            start = set + 1;
+        }
+
+        None
+    }
+
+    pub fn set_range(&mut self, range: Range<usize>) {
+        self.fill(range, true);
+    }
+
+    pub fn clear_range(&mut self, range: Range<usize>) {
+        self.fill(range, false);
+    }
+
+    /// The `index`th 64 bits, what doesn't fit in the bitmap reads as set so it's never free
+    fn word(&self, index: usize) -> u64 {
+        let bytes = &self.inner[index * 8..];
+
+        let mut word = [0xFF; 8];
+        let len = bytes.len().min(8);
+        word[..len].copy_from_slice(&bytes[..len]);
+
+        u64::from_le_bytes(word)
+    }
+
+    /// Sets or clears the bits of the `index`th word in `mask`
+    fn update_word(&mut self, index: usize, mask: u64, set: bool) {
+        let start = index * 8;
+        let end = (start + 8).min(self.inner.len());
+        let bytes = &mut self.inner[start..end];
+
+        let mut word = [0; 8];
+        word[..bytes.len()].copy_from_slice(bytes);
+        let mut word = u64::from_le_bytes(word);
+
+        if set {
+            word |= mask;
+        } else {
+            word &= !mask;
+        }
+
+        let len = bytes.len();
+        bytes.copy_from_slice(&word.to_le_bytes()[..len]);
+    }
+
+    /// The first bit in `from..to` that's clear, or set without `clear`, `to` if there's none
+    fn scan(&self, from: usize, to: usize, clear: bool) -> usize {
+        let mut bit = from;
+
+        while bit < to {
+            let word = self.word(bit / WORD_BITS);
+            let found = if clear { !word } else { word } >> (bit % WORD_BITS);
+
+            if found != 0 {
+                return (bit + found.trailing_zeros() as usize).min(to);
+            }
+
+            bit = (bit / WORD_BITS + 1) * WORD_BITS;
+        }
+
+        to
+    }
+
+    fn fill(&mut self, range: Range<usize>, set: bool) {
+        assert!(range.end <= self.len(), "{range:?} is out of the bitmap");
+
+        let mut bit = range.start;
+        while bit < range.end {
+            let offset = bit % WORD_BITS;
+            let count = (WORD_BITS - offset).min(range.end - bit);
+
+            let mask = match count {
+                WORD_BITS => !0,
+                _ => ((1 << count) - 1) << offset,
+            };
+            self.update_word(bit / WORD_BITS, mask, set);
+
+            bit += count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_unset_touch_one_bit() {
+        let mut bytes = [0u8; 4];
+        let mut bitmap = Bitmap::new(&mut bytes);
+        assert_eq!(bitmap.len(), 32);
+
+        bitmap.set(0);
+        bitmap.set(9);
+        bitmap.set(31);
+        assert!(bitmap.test(0) && bitmap.test(9) && bitmap.test(31));
+        assert!(!bitmap.test(1) && !bitmap.test(8) && !bitmap.test(30));
+
+        bitmap.unset(9);
+        assert!(!bitmap.test(9));
+        assert!(bitmap.test(0) && bitmap.test(31));
+
+        assert_eq!(bytes, [0b1, 0, 0, 0b1000_0000]);
+    }
+
+    #[test]
+    fn finds_the_first_zero() {
+        let mut bytes = [0xFFu8; 20];
+        let mut bitmap = Bitmap::new(&mut bytes);
+        assert_eq!(bitmap.find_first_zero(), None);
+
+        bitmap.unset(157);
+        assert_eq!(bitmap.find_first_zero(), Some(157));
+
+        bitmap.unset(3);
+        assert_eq!(bitmap.find_first_zero(), Some(3));
+    }
+
+    #[test]
+    fn zero_runs_cross_words_and_skip_short_gaps() {
+        let mut bytes = [0xFFu8; 24];
+        let mut bitmap = Bitmap::new(&mut bytes);
+
+        bitmap.clear_range(10..13);
+        bitmap.clear_range(60..140);
+        assert_eq!(bitmap.find_zero_run(3), Some(10));
+        assert_eq!(bitmap.find_zero_run(4), Some(60));
+        assert_eq!(bitmap.find_zero_run(80), Some(60));
+        assert_eq!(bitmap.find_zero_run(81), None);
+
+        assert_eq!(bitmap.find_zero_run_from(11, 2), Some(11));
+        assert_eq!(bitmap.find_zero_run_from(13, 1), Some(60));
+    }
+
+    #[test]
+    fn runs_stop_at_the_end() {
+        let mut bytes = [0u8; 12];
+        let bitmap = Bitmap::new(&mut bytes);
+
+        assert_eq!(bitmap.find_zero_run(96), Some(0));
+        assert_eq!(bitmap.find_zero_run(97), None);
+        assert_eq!(bitmap.find_zero_run_from(90, 6), Some(90));
+        assert_eq!(bitmap.find_zero_run_from(90, 7), None);
+    }
+
+    #[test]
+    fn ranges_touch_only_their_bits() {
+        let mut bytes = [0u8; 20];
+        let mut bitmap = Bitmap::new(&mut bytes);
+
+        bitmap.set_range(5..150);
+        assert!(!bitmap.test(4) && !bitmap.test(150));
+        assert!((5..150).all(|bit| bitmap.test(bit)));
+
+        bitmap.clear_range(64..128);
+        assert!((64..128).all(|bit| !bitmap.test(bit)));
+        assert!(bitmap.test(63) && bitmap.test(128));
+
+        bitmap.set_range(7..7);
+        bitmap.clear_range(159..160);
+        assert_eq!(bytes[19], 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_range_panics() {
+        let mut bytes = [0u8; 1];
+        Bitmap::new(&mut bytes).test(8);
+    }
+}
@@ -0,0 +1,292 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::Adapter;
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+type Ptr = Option<NonNull<ListLink>>;
+
+/// What an object embeds to sit in a `List`
+#[derive(Default)]
+pub struct ListLink {
+    prev: Cell<Ptr>,
+    next: Cell<Ptr>,
+    linked: Cell<bool>,
+}
+
+impl ListLink {
+    pub const fn new() -> ListLink {
+        ListLink {
+            prev: Cell::new(None),
+            next: Cell::new(None),
+            linked: Cell::new(false),
+        }
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.linked.get()
+    }
+}
+
+impl Drop for ListLink {
+    fn drop(&mut self) {
+        debug_assert!(!self.is_linked(), "object dropped while still on a list");
+    }
+}
+
+/// A doubly linked list of objects that carry their own links, so pushing never allocates.
+///
+/// The list only holds pointers: objects have to stay put and outlive their time on it
+pub struct List<A: Adapter<Link = ListLink>> {
+    head: Ptr,
+    tail: Ptr,
+    len: usize,
+    adapter: PhantomData<A>,
+}
+
+unsafe impl<A: Adapter<Link = ListLink>> Send for List<A> where A::Object: Send {}
+
+impl<A: Adapter<Link = ListLink>> Default for List<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn link<'a>(ptr: NonNull<ListLink>) -> &'a ListLink {
+    unsafe { ptr.as_ref() }
+}
+
+impl<A: Adapter<Link = ListLink>> List<A> {
+    pub const fn new() -> List<A> {
+        List {
+            head: None,
+            tail: None,
+            len: 0,
+            adapter: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn front(&self) -> Option<NonNull<A::Object>> {
+        self.head.map(|head| unsafe { A::object(head) })
+    }
+
+    pub fn back(&self) -> Option<NonNull<A::Object>> {
+        self.tail.map(|tail| unsafe { A::object(tail) })
+    }
+
+    /// # Safety
+    /// `object` has to stay valid and in place until it comes off the list
+    pub unsafe fn push_back(&mut self, object: NonNull<A::Object>) {
+        let node = A::link(object);
+        self.claim(node);
+
+        link(node).prev.set(self.tail);
+        match self.tail {
+            Some(tail) => link(tail).next.set(Some(node)),
+            None => self.head = Some(node),
+        }
+        self.tail = Some(node);
+    }
+
+    /// # Safety
+    /// `object` has to stay valid and in place until it comes off the list
+    pub unsafe fn push_front(&mut self, object: NonNull<A::Object>) {
+        let node = A::link(object);
+        self.claim(node);
+
+        link(node).next.set(self.head);
+        match self.head {
+            Some(head) => link(head).prev.set(Some(node)),
+            None => self.tail = Some(node),
+        }
+        self.head = Some(node);
+    }
+
+    pub fn pop_front(&mut self) -> Option<NonNull<A::Object>> {
+        let object = self.front()?;
+        unsafe { self.remove(object) };
+        Some(object)
+    }
+
+    pub fn pop_back(&mut self) -> Option<NonNull<A::Object>> {
+        let object = self.back()?;
+        unsafe { self.remove(object) };
+        Some(object)
+    }
+
+    /// Takes `object` off the list wherever it is, in constant time
+    ///
+    /// # Safety
+    /// `object` has to be on this list
+    pub unsafe fn remove(&mut self, object: NonNull<A::Object>) {
+        let node = link(A::link(object));
+        assert!(node.is_linked(), "removing an object that isn't on a list");
+
+        let (prev, next) = (node.prev.take(), node.next.take());
+        match prev {
+            Some(prev) => link(prev).next.set(next),
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => link(next).prev.set(prev),
+            None => self.tail = prev,
+        }
+
+        node.linked.set(false);
+        self.len -= 1;
+    }
+
+    /// Objects from front to back
+    pub fn iter(&self) -> Iter<'_, A> {
+        Iter {
+            next: self.head,
+            list: PhantomData,
+        }
+    }
+
+    fn claim(&mut self, node: NonNull<ListLink>) {
+        let node = link(node);
+        assert!(!node.is_linked(), "object is already on a list");
+
+        node.linked.set(true);
+        node.prev.set(None);
+        node.next.set(None);
+        self.len += 1;
+    }
+}
+
+pub struct Iter<'a, A: Adapter<Link = ListLink>> {
+    next: Ptr,
+    list: PhantomData<&'a List<A>>,
+}
+
+impl<A: Adapter<Link = ListLink>> Iterator for Iter<'_, A> {
+    type Item = NonNull<A::Object>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.next?;
+        self.next = link(node).next.get();
+
+        Some(unsafe { A::object(node) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+    use std::vec::Vec;
+
+    struct Thread {
+        id: usize,
+        run: ListLink,
+    }
+
+    struct RunQueue;
+
+    unsafe impl Adapter for RunQueue {
+        type Object = Thread;
+        type Link = ListLink;
+        const OFFSET: usize = offset_of!(Thread, run);
+    }
+
+    fn threads(count: usize) -> Vec<Thread> {
+        (0..count)
+            .map(|id| Thread {
+                id,
+                run: ListLink::new(),
+            })
+            .collect()
+    }
+
+    fn ids(list: &List<RunQueue>) -> Vec<usize> {
+        list.iter().map(|t| unsafe { t.as_ref() }.id).collect()
+    }
+
+    #[test]
+    fn pushes_and_pops_at_both_ends() {
+        let mut threads = threads(4);
+        let ptr = |threads: &mut Vec<Thread>, i| NonNull::from(&mut threads[i]);
+        let mut list = List::<RunQueue>::new();
+
+        unsafe {
+            list.push_back(ptr(&mut threads, 1));
+            list.push_back(ptr(&mut threads, 2));
+            list.push_front(ptr(&mut threads, 0));
+            list.push_back(ptr(&mut threads, 3));
+        }
+        assert_eq!(ids(&list), [0, 1, 2, 3]);
+        assert_eq!(list.len(), 4);
+
+        let id = |t: Option<NonNull<Thread>>| unsafe { t.unwrap().as_ref() }.id;
+        assert_eq!(id(list.pop_front()), 0);
+        assert_eq!(id(list.pop_back()), 3);
+        assert_eq!(ids(&list), [1, 2]);
+
+        list.pop_front();
+        list.pop_front();
+        assert!(list.is_empty() && list.pop_front().is_none());
+        assert!(threads.iter().all(|t| !t.run.is_linked()));
+    }
+
+    #[test]
+    fn removes_from_the_middle() {
+        let mut threads = threads(5);
+        let mut list = List::<RunQueue>::new();
+
+        for thread in &mut threads {
+            unsafe { list.push_back(NonNull::from(thread)) };
+        }
+
+        unsafe {
+            list.remove(NonNull::from(&mut threads[2]));
+            list.remove(NonNull::from(&mut threads[0]));
+            list.remove(NonNull::from(&mut threads[4]));
+        }
+        assert_eq!(ids(&list), [1, 3]);
+        assert_eq!(unsafe { list.back().unwrap().as_ref() }.id, 3);
+
+        // Off the list, it can go back on
+        unsafe { list.push_front(NonNull::from(&mut threads[2])) };
+        assert_eq!(ids(&list), [2, 1, 3]);
+
+        while list.pop_front().is_some() {}
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_insert_panics() {
+        // Leaked, dropping a linked thread while unwinding would abort
+        let threads = threads(1).leak();
+        let mut list = List::<RunQueue>::new();
+
+        unsafe {
+            list.push_back(NonNull::from(&mut threads[0]));
+            list.push_back(NonNull::from(&mut threads[0]));
+        }
+    }
+}
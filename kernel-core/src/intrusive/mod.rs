@@ -0,0 +1,47 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+pub mod list;
+pub mod rbtree;
+
+pub use list::{List, ListLink};
+pub use rbtree::{RbTree, TreeAdapter, TreeLink};
+
+use core::ptr::NonNull;
+
+/// Ties the link an object embeds to the object, so collections can go from one to the other
+/// without allocating anything per node.
+///
+/// An object can sit in as many collections as it has links, with one adapter per link
+///
+/// # Safety
+/// `OFFSET` has to be the offset of a `Self::Link` inside `Self::Object`, e.g. from `offset_of!`
+pub unsafe trait Adapter {
+    type Object;
+    type Link;
+    const OFFSET: usize;
+
+    fn link(object: NonNull<Self::Object>) -> NonNull<Self::Link> {
+        unsafe { object.byte_add(Self::OFFSET).cast() }
+    }
+
+    /// # Safety
+    /// `link` has to be the link of a `Self::Object`
+    unsafe fn object(link: NonNull<Self::Link>) -> NonNull<Self::Object> {
+        unsafe { link.byte_sub(Self::OFFSET).cast() }
+    }
+}
@@ -0,0 +1,591 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::Adapter;
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+type Ptr = Option<NonNull<TreeLink>>;
+
+/// What an object embeds to sit in an `RbTree`
+#[derive(Default)]
+pub struct TreeLink {
+    parent: Cell<Ptr>,
+    left: Cell<Ptr>,
+    right: Cell<Ptr>,
+    red: Cell<bool>,
+    linked: Cell<bool>,
+}
+
+impl TreeLink {
+    pub const fn new() -> TreeLink {
+        TreeLink {
+            parent: Cell::new(None),
+            left: Cell::new(None),
+            right: Cell::new(None),
+            red: Cell::new(false),
+            linked: Cell::new(false),
+        }
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.linked.get()
+    }
+}
+
+impl Drop for TreeLink {
+    fn drop(&mut self) {
+        debug_assert!(!self.is_linked(), "object dropped while still in a tree");
+    }
+}
+
+/// What objects in an `RbTree` are sorted by
+pub trait TreeAdapter: Adapter<Link = TreeLink> {
+    type Key: Ord;
+
+    fn key(object: &Self::Object) -> Self::Key;
+}
+
+/// A red-black tree of objects that carry their own links, so inserting never allocates.
+///
+/// Objects with the same key are kept in insertion order. The tree only holds pointers: objects
+/// have to stay put and outlive their time in it
+pub struct RbTree<A: TreeAdapter> {
+    root: Ptr,
+    len: usize,
+    adapter: PhantomData<A>,
+}
+
+unsafe impl<A: TreeAdapter> Send for RbTree<A> where A::Object: Send {}
+
+impl<A: TreeAdapter> Default for RbTree<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn node<'a>(ptr: NonNull<TreeLink>) -> &'a TreeLink {
+    unsafe { ptr.as_ref() }
+}
+
+fn is_red(ptr: Ptr) -> bool {
+    ptr.is_some_and(|ptr| node(ptr).red.get())
+}
+
+fn minimum(mut ptr: NonNull<TreeLink>) -> NonNull<TreeLink> {
+    while let Some(left) = node(ptr).left.get() {
+        ptr = left;
+    }
+
+    ptr
+}
+
+fn maximum(mut ptr: NonNull<TreeLink>) -> NonNull<TreeLink> {
+    while let Some(right) = node(ptr).right.get() {
+        ptr = right;
+    }
+
+    ptr
+}
+
+fn successor(ptr: NonNull<TreeLink>) -> Ptr {
+    if let Some(right) = node(ptr).right.get() {
+        return Some(minimum(right));
+    }
+
+    // Up until we come from a left subtree
+    let mut child = ptr;
+    while let Some(parent) = node(child).parent.get() {
+        if node(parent).left.get() == Some(child) {
+            return Some(parent);
+        }
+        child = parent;
+    }
+
+    None
+}
+
+impl<A: TreeAdapter> RbTree<A> {
+    pub const fn new() -> RbTree<A> {
+        RbTree {
+            root: None,
+            len: 0,
+            adapter: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn key(ptr: NonNull<TreeLink>) -> A::Key {
+        A::key(unsafe { A::object(ptr).as_ref() })
+    }
+
+    /// The object with the smallest key
+    pub fn first(&self) -> Option<NonNull<A::Object>> {
+        self.root.map(|root| unsafe { A::object(minimum(root)) })
+    }
+
+    /// The object with the largest key
+    pub fn last(&self) -> Option<NonNull<A::Object>> {
+        self.root.map(|root| unsafe { A::object(maximum(root)) })
+    }
+
+    pub fn pop_first(&mut self) -> Option<NonNull<A::Object>> {
+        let object = self.first()?;
+        unsafe { self.remove(object) };
+        Some(object)
+    }
+
+    /// The first object whose key is `key`
+    pub fn find(&self, key: &A::Key) -> Option<NonNull<A::Object>> {
+        self.ceil(key)
+            .filter(|&object| A::key(unsafe { object.as_ref() }) == *key)
+    }
+
+    /// The last object with a key not above `key`, e.g. the region an address could fall in
+    pub fn floor(&self, key: &A::Key) -> Option<NonNull<A::Object>> {
+        let mut current = self.root;
+        let mut found = None;
+
+        while let Some(ptr) = current {
+            if Self::key(ptr) <= *key {
+                found = Some(ptr);
+                current = node(ptr).right.get();
+            } else {
+                current = node(ptr).left.get();
+            }
+        }
+
+        found.map(|ptr| unsafe { A::object(ptr) })
+    }
+
+    /// The first object with a key not below `key`
+    pub fn ceil(&self, key: &A::Key) -> Option<NonNull<A::Object>> {
+        let mut current = self.root;
+        let mut found = None;
+
+        while let Some(ptr) = current {
+            if Self::key(ptr) >= *key {
+                found = Some(ptr);
+                current = node(ptr).left.get();
+            } else {
+                current = node(ptr).right.get();
+            }
+        }
+
+        found.map(|ptr| unsafe { A::object(ptr) })
+    }
+
+    /// Objects in key order
+    pub fn iter(&self) -> Iter<'_, A> {
+        Iter {
+            next: self.root.map(minimum),
+            tree: PhantomData,
+        }
+    }
+
+    /// # Safety
+    /// `object` has to stay valid and in place until it comes out of the tree, and its key can't
+    /// change while it's in there
+    pub unsafe fn insert(&mut self, object: NonNull<A::Object>) {
+        let ptr = A::link(object);
+        let link = node(ptr);
+        assert!(!link.is_linked(), "object is already in a tree");
+
+        let key = A::key(unsafe { object.as_ref() });
+        let mut parent = None;
+        let mut left = false;
+        let mut current = self.root;
+
+        while let Some(next) = current {
+            parent = Some(next);
+            left = key < Self::key(next);
+            current = if left {
+                node(next).left.get()
+            } else {
+                node(next).right.get()
+            };
+        }
+
+        link.parent.set(parent);
+        link.left.set(None);
+        link.right.set(None);
+        link.red.set(true);
+        link.linked.set(true);
+
+        match parent {
+            None => self.root = Some(ptr),
+            Some(parent) if left => node(parent).left.set(Some(ptr)),
+            Some(parent) => node(parent).right.set(Some(ptr)),
+        }
+
+        self.insert_fixup(ptr);
+        self.len += 1;
+    }
+
+    /// # Safety
+    /// `object` has to be in this tree
+    pub unsafe fn remove(&mut self, object: NonNull<A::Object>) {
+        let ptr = A::link(object);
+        let link = node(ptr);
+        assert!(link.is_linked(), "removing an object that isn't in a tree");
+
+        let mut removed_red = link.red.get();
+        let (child, parent);
+
+        match (link.left.get(), link.right.get()) {
+            (None, right) => {
+                child = right;
+                parent = link.parent.get();
+                self.transplant(ptr, right);
+            }
+            (left, None) => {
+                child = left;
+                parent = link.parent.get();
+                self.transplant(ptr, left);
+            }
+            (Some(left), Some(right)) => {
+                // The successor takes the place of the removed node
+                let next = minimum(right);
+                removed_red = node(next).red.get();
+                child = node(next).right.get();
+
+                if next == right {
+                    parent = Some(next);
+                } else {
+                    parent = node(next).parent.get();
+                    self.transplant(next, child);
+                    node(next).right.set(Some(right));
+                    node(right).parent.set(Some(next));
+                }
+
+                self.transplant(ptr, Some(next));
+                node(next).left.set(Some(left));
+                node(left).parent.set(Some(next));
+                node(next).red.set(link.red.get());
+            }
+        }
+
+        if !removed_red {
+            self.remove_fixup(child, parent);
+        }
+
+        link.parent.set(None);
+        link.left.set(None);
+        link.right.set(None);
+        link.linked.set(false);
+        self.len -= 1;
+    }
+
+    /// Puts `new` where `old` hangs from its parent
+    fn transplant(&mut self, old: NonNull<TreeLink>, new: Ptr) {
+        let parent = node(old).parent.get();
+        self.replace_child(parent, old, new);
+
+        if let Some(new) = new {
+            node(new).parent.set(parent);
+        }
+    }
+
+    fn replace_child(&mut self, parent: Ptr, old: NonNull<TreeLink>, new: Ptr) {
+        match parent {
+            None => self.root = new,
+            Some(parent) if node(parent).left.get() == Some(old) => node(parent).left.set(new),
+            Some(parent) => node(parent).right.set(new),
+        }
+    }
+
+    /// Rotates around `ptr`: to the right its left child takes its place, to the left its right one
+    fn rotate(&mut self, ptr: NonNull<TreeLink>, right: bool) {
+        let link = node(ptr);
+        let (child, inner) = if right {
+            let child = link.left.get().unwrap();
+            (child, &node(child).right)
+        } else {
+            let child = link.right.get().unwrap();
+            (child, &node(child).left)
+        };
+
+        // The child's inner subtree moves over to `ptr`
+        let moved = inner.get();
+        if right {
+            link.left.set(moved);
+        } else {
+            link.right.set(moved);
+        }
+        if let Some(moved) = moved {
+            node(moved).parent.set(Some(ptr));
+        }
+
+        self.transplant(ptr, Some(child));
+        inner.set(Some(ptr));
+        link.parent.set(Some(child));
+    }
+
+    fn insert_fixup(&mut self, mut ptr: NonNull<TreeLink>) {
+        while let Some(parent) = node(ptr).parent.get().filter(|&p| is_red(Some(p))) {
+            // A red parent is never the root, so there's a grandparent
+            let grandparent = node(parent).parent.get().unwrap();
+            let parent_is_left = node(grandparent).left.get() == Some(parent);
+            let uncle = if parent_is_left {
+                node(grandparent).right.get()
+            } else {
+                node(grandparent).left.get()
+            };
+
+            if is_red(uncle) {
+                node(parent).red.set(false);
+                node(uncle.unwrap()).red.set(false);
+                node(grandparent).red.set(true);
+                ptr = grandparent;
+                continue;
+            }
+
+            // Straighten a zig-zag first, then rotate the grandparent away
+            let mut parent = parent;
+            let inner = if parent_is_left {
+                node(parent).right.get()
+            } else {
+                node(parent).left.get()
+            };
+            if inner == Some(ptr) {
+                self.rotate(parent, !parent_is_left);
+                ptr = parent;
+                parent = node(ptr).parent.get().unwrap();
+            }
+
+            node(parent).red.set(false);
+            node(grandparent).red.set(true);
+            self.rotate(grandparent, parent_is_left);
+        }
+
+        node(self.root.unwrap()).red.set(false);
+    }
+
+    /// Restores the black heights after a black node came out from under `parent`, leaving
+    /// `child` one black short
+    fn remove_fixup(&mut self, mut child: Ptr, mut parent: Ptr) {
+        while child != self.root && !is_red(child) {
+            let up = parent.unwrap();
+            // The short side always has a sibling, or the black heights were already off
+            let left = node(up).left.get() == child;
+            let sibling_of = |up: NonNull<TreeLink>| {
+                let sibling = if left {
+                    node(up).right.get()
+                } else {
+                    node(up).left.get()
+                };
+                sibling.unwrap()
+            };
+            // The sibling's children, the one away from `child` first
+            let nephews = |sibling: NonNull<TreeLink>| {
+                let (l, r) = (node(sibling).left.get(), node(sibling).right.get());
+                if left {
+                    (r, l)
+                } else {
+                    (l, r)
+                }
+            };
+
+            let mut sibling = sibling_of(up);
+            if node(sibling).red.get() {
+                node(sibling).red.set(false);
+                node(up).red.set(true);
+                self.rotate(up, !left);
+                sibling = sibling_of(up);
+            }
+
+            let (far, near) = nephews(sibling);
+            if !is_red(far) && !is_red(near) {
+                node(sibling).red.set(true);
+                child = Some(up);
+                parent = node(up).parent.get();
+                continue;
+            }
+
+            if !is_red(far) {
+                node(near.unwrap()).red.set(false);
+                node(sibling).red.set(true);
+                self.rotate(sibling, left);
+                sibling = sibling_of(up);
+            }
+
+            let (far, _) = nephews(sibling);
+            node(sibling).red.set(node(up).red.get());
+            node(up).red.set(false);
+            node(far.unwrap()).red.set(false);
+            self.rotate(up, !left);
+
+            child = self.root;
+            parent = None;
+        }
+
+        if let Some(child) = child {
+            node(child).red.set(false);
+        }
+    }
+}
+
+pub struct Iter<'a, A: TreeAdapter> {
+    next: Ptr,
+    tree: PhantomData<&'a RbTree<A>>,
+}
+
+impl<A: TreeAdapter> Iterator for Iter<'_, A> {
+    type Item = NonNull<A::Object>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ptr = self.next?;
+        self.next = successor(ptr);
+
+        Some(unsafe { A::object(ptr) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+    use std::vec::Vec;
+
+    struct Timer {
+        deadline: u64,
+        id: usize,
+        link: TreeLink,
+    }
+
+    struct Timers;
+
+    unsafe impl Adapter for Timers {
+        type Object = Timer;
+        type Link = TreeLink;
+        const OFFSET: usize = offset_of!(Timer, link);
+    }
+
+    impl TreeAdapter for Timers {
+        type Key = u64;
+
+        fn key(timer: &Timer) -> u64 {
+            timer.deadline
+        }
+    }
+
+    fn timers(deadlines: impl Iterator<Item = u64>) -> Vec<Timer> {
+        deadlines
+            .enumerate()
+            .map(|(id, deadline)| Timer {
+                deadline,
+                id,
+                link: TreeLink::new(),
+            })
+            .collect()
+    }
+
+    /// Pseudo random deadlines, with plenty of duplicates
+    fn deadlines(count: usize) -> impl Iterator<Item = u64> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..count).map(move |_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) % 500
+        })
+    }
+
+    /// Checks the red-black rules and the parent links, returns the black height
+    fn check(ptr: Ptr, parent: Ptr) -> usize {
+        let Some(ptr) = ptr else {
+            return 1;
+        };
+        let link = node(ptr);
+        assert_eq!(link.parent.get(), parent);
+
+        if link.red.get() {
+            assert!(!is_red(link.left.get()) && !is_red(link.right.get()));
+        }
+
+        let left = check(link.left.get(), Some(ptr));
+        assert_eq!(left, check(link.right.get(), Some(ptr)));
+
+        left + !link.red.get() as usize
+    }
+
+    fn contents(tree: &RbTree<Timers>) -> Vec<(u64, usize)> {
+        tree.iter()
+            .map(|t| unsafe { (t.as_ref().deadline, t.as_ref().id) })
+            .collect()
+    }
+
+    #[test]
+    fn stays_balanced_and_sorted() {
+        let mut timers = timers(deadlines(2000));
+        let mut tree = RbTree::<Timers>::new();
+
+        for timer in &mut timers {
+            unsafe { tree.insert(NonNull::from(timer)) };
+        }
+        check(tree.root, None);
+        assert!(!is_red(tree.root));
+
+        // Sorted by deadline, equal deadlines in insertion order
+        let mut expected: Vec<(u64, usize)> = timers.iter().map(|t| (t.deadline, t.id)).collect();
+        expected.sort();
+        assert_eq!(contents(&tree), expected);
+
+        for i in (0..timers.len()).step_by(3) {
+            unsafe { tree.remove(NonNull::from(&mut timers[i])) };
+            check(tree.root, None);
+        }
+        expected.retain(|&(_, id)| id % 3 != 0);
+        assert_eq!(contents(&tree), expected);
+        assert_eq!(tree.len(), expected.len());
+
+        while tree.pop_first().is_some() {
+            check(tree.root, None);
+        }
+        assert!(tree.is_empty() && tree.root.is_none());
+    }
+
+    #[test]
+    fn finds_by_key() {
+        let mut timers = timers([10, 20, 20, 30].into_iter());
+        let mut tree = RbTree::<Timers>::new();
+
+        for timer in &mut timers {
+            unsafe { tree.insert(NonNull::from(timer)) };
+        }
+
+        let id = |t: Option<NonNull<Timer>>| t.map(|t| unsafe { t.as_ref() }.id);
+        assert_eq!(id(tree.find(&20)), Some(1));
+        assert_eq!(id(tree.find(&25)), None);
+        assert_eq!(id(tree.floor(&25)), Some(2));
+        assert_eq!(id(tree.floor(&9)), None);
+        assert_eq!(id(tree.ceil(&11)), Some(1));
+        assert_eq!(id(tree.ceil(&31)), None);
+        assert_eq!(id(tree.first()), Some(0));
+        assert_eq!(id(tree.last()), Some(3));
+
+        while tree.pop_first().is_some() {}
+    }
+}
@@ -0,0 +1,32 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// Built with std only for `cargo test` on the host
+#![cfg_attr(not(test), no_std)]
+
+pub mod addr;
+pub mod align;
+pub mod bitmap;
+pub mod intrusive;
+pub mod ring;
+pub mod siphash;
+pub mod slab;
+
+pub use addr::{PhysAddr, VirtAddr};
+pub use align::{align_down, align_up};
+pub use bitmap::Bitmap;
@@ -0,0 +1,421 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fixed size queue for any number of producers, which never block, and wait for nothing but
+/// the slot they claimed.
+///
+/// Every slot carries a sequence number saying whether it's ready to be written or read, so a
+/// producer interrupted between claiming a slot and filling it only holds up readers of that slot.
+/// Pops are claimed the same way pushes are, so consumers can race each other too. `N` has to be
+/// a power of two
+pub struct MpscRing<T, const N: usize> {
+    slots: [Slot<T>; N],
+    /// Next position to push to and to pop from, counting up forever
+    tail: AtomicUsize,
+    head: AtomicUsize,
+}
+
+struct Slot<T> {
+    /// The position that can be pushed here next, plus one once it's been written
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for MpscRing<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for MpscRing<T, N> {}
+
+impl<T, const N: usize> Default for MpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> MpscRing<T, N> {
+    pub const fn new() -> MpscRing<T, N> {
+        const { assert!(N.is_power_of_two()) };
+
+        let mut slots = [const {
+            Slot {
+                sequence: AtomicUsize::new(0),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }; N];
+
+        let mut i = 0;
+        while i < N {
+            slots[i].sequence = AtomicUsize::new(i);
+            i += 1;
+        }
+
+        MpscRing {
+            slots,
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// How many values are in, which may be stale by the time it returns
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        self.tail.load(Ordering::Relaxed).wrapping_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds `value` at the back, or hands it back if the ring is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.tail.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[position % N];
+            let lag = slot.sequence.load(Ordering::Acquire).wrapping_sub(position) as isize;
+
+            if lag < 0 {
+                // Still holding the value from a lap ago
+                return Err(value);
+            } else if lag > 0 {
+                // Another producer got this position first
+                position = self.tail.load(Ordering::Relaxed);
+                continue;
+            }
+
+            match self.tail.compare_exchange_weak(
+                position,
+                position.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    unsafe { (*slot.value.get()).write(value) };
+                    slot.sequence
+                        .store(position.wrapping_add(1), Ordering::Release);
+                    return Ok(());
+                }
+                Err(current) => position = current,
+            }
+        }
+    }
+
+    /// Like `push`, but makes room by dropping the oldest values when full. `value` itself gets
+    /// dropped instead if nothing can be popped, while a pop that was interrupted is unfinished,
+    /// so this never spins on anyone. Returns whether `value` made it in
+    pub fn force_push(&self, mut value: T) -> bool {
+        loop {
+            match self.push(value) {
+                Ok(()) => return true,
+                Err(back) => value = back,
+            }
+
+            if self.pop().is_none() {
+                return false;
+            }
+        }
+    }
+
+    /// Takes the value at the front
+    pub fn pop(&self) -> Option<T> {
+        let mut position = self.head.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[position % N];
+            let lag = slot
+                .sequence
+                .load(Ordering::Acquire)
+                .wrapping_sub(position.wrapping_add(1)) as isize;
+
+            if lag < 0 {
+                // Not written yet, or the producer that claimed it didn't finish
+                return None;
+            } else if lag > 0 {
+                position = self.head.load(Ordering::Relaxed);
+                continue;
+            }
+
+            match self.head.compare_exchange_weak(
+                position,
+                position.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    let value = unsafe { (*slot.value.get()).assume_init_read() };
+                    slot.sequence
+                        .store(position.wrapping_add(N), Ordering::Release);
+                    return Some(value);
+                }
+                Err(current) => position = current,
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Drop for MpscRing<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+/// A fixed size queue between one producer and one consumer, neither ever waits on the other.
+///
+/// `split` hands out the two ends, each one can only be used from one place at a time. `N` has to
+/// be a power of two
+pub struct SpscRing<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    tail: AtomicUsize,
+    head: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Send for SpscRing<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for SpscRing<T, N> {}
+
+impl<T, const N: usize> Default for SpscRing<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> SpscRing<T, N> {
+    pub const fn new() -> SpscRing<T, N> {
+        const { assert!(N.is_power_of_two()) };
+
+        SpscRing {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        self.tail.load(Ordering::Acquire).wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (
+            Producer {
+                ring: self,
+                end: PhantomData,
+            },
+            Consumer {
+                ring: self,
+                end: PhantomData,
+            },
+        )
+    }
+}
+
+impl<T, const N: usize> Drop for SpscRing<T, N> {
+    fn drop(&mut self) {
+        let (_, mut consumer) = self.split();
+        while consumer.pop().is_some() {}
+    }
+}
+
+/// The pushing end of an `SpscRing`
+pub struct Producer<'a, T, const N: usize> {
+    ring: &'a SpscRing<T, N>,
+    /// Not `Sync`, so there's only ever one pusher
+    end: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Producer<'_, T, N> {}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Adds `value` at the back, or hands it back if the ring is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.ring.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+
+        unsafe { (*self.ring.slots[tail % N].get()).write(value) };
+        self.ring
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+}
+
+/// The popping end of an `SpscRing`
+pub struct Consumer<'a, T, const N: usize> {
+    ring: &'a SpscRing<T, N>,
+    end: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for Consumer<'_, T, N> {}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head == self.ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { (*self.ring.slots[head % N].get()).assume_init_read() };
+        self.ring
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn mpsc_is_fifo_and_bounded() {
+        let ring = MpscRing::<u32, 4>::new();
+        assert!(ring.pop().is_none());
+
+        for i in 0..4 {
+            ring.push(i).unwrap();
+        }
+        assert_eq!(ring.push(4), Err(4));
+        assert_eq!(ring.len(), 4);
+
+        assert_eq!(ring.pop(), Some(0));
+        ring.push(4).unwrap();
+        assert_eq!(
+            (0..4).map_while(|_| ring.pop()).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn force_push_drops_the_oldest() {
+        let ring = MpscRing::<u32, 4>::new();
+        for i in 0..10 {
+            ring.force_push(i);
+        }
+
+        assert_eq!(
+            (0..4).map_while(|_| ring.pop()).collect::<Vec<_>>(),
+            [6, 7, 8, 9]
+        );
+    }
+
+    #[test]
+    fn values_left_behind_are_dropped() {
+        let value = Arc::new(());
+        {
+            let ring = MpscRing::<Arc<()>, 8>::new();
+            for _ in 0..5 {
+                ring.push(value.clone()).unwrap();
+            }
+            ring.pop();
+
+            let mut spsc = SpscRing::<Arc<()>, 8>::new();
+            let (mut producer, _) = spsc.split();
+            producer.push(value.clone()).unwrap();
+        }
+
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn mpsc_loses_nothing_between_threads() {
+        const PER_PRODUCER: usize = 10_000;
+        let ring = Arc::new(MpscRing::<usize, 64>::new());
+
+        let producers: Vec<_> = (0..4)
+            .map(|producer| {
+                let ring = ring.clone();
+                thread::spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        let mut value = producer * PER_PRODUCER + i;
+                        while let Err(back) = ring.push(value) {
+                            value = back;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // Each producer's values come out in the order it pushed them
+        let mut next = [0; 4];
+        let mut received = 0;
+        while received < 4 * PER_PRODUCER {
+            let Some(value) = ring.pop() else {
+                thread::yield_now();
+                continue;
+            };
+
+            let producer = value / PER_PRODUCER;
+            assert_eq!(value % PER_PRODUCER, next[producer]);
+            next[producer] += 1;
+            received += 1;
+        }
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert!(ring.pop().is_none());
+    }
+
+    #[test]
+    fn spsc_hands_over_in_order() {
+        let mut ring = SpscRing::<usize, 16>::new();
+        let (mut producer, mut consumer) = ring.split();
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for mut value in 0..100_000 {
+                    while let Err(back) = producer.push(value) {
+                        value = back;
+                        thread::yield_now();
+                    }
+                }
+            });
+
+            let mut expected = 0;
+            while expected < 100_000 {
+                match consumer.pop() {
+                    Some(value) => {
+                        assert_eq!(value, expected);
+                        expected += 1;
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+        });
+
+        assert!(ring.is_empty());
+    }
+}
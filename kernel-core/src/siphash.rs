@@ -0,0 +1,172 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use core::hash::Hasher;
+
+/// SipHash with `C` rounds per message word and `D` finalization rounds, a keyed hash that's
+/// cheap on short inputs, like the names and numbers kernel maps get keyed on.
+///
+/// Without the key, nobody can come up with inputs that collide, so a table using it can't be
+/// made to degrade into a list
+#[derive(Clone, Debug)]
+pub struct SipHasher<const C: usize, const D: usize> {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    /// Bytes written that don't make a whole word yet, little endian
+    tail: u64,
+    ntail: usize,
+    length: usize,
+}
+
+/// What hash tables use, the rounds Rust's own `HashMap` settled on
+pub type SipHasher13 = SipHasher<1, 3>;
+/// The variant of the paper, for when the output leaves the kernel
+pub type SipHasher24 = SipHasher<2, 4>;
+
+impl<const C: usize, const D: usize> SipHasher<C, D> {
+    pub const fn new_with_keys(k0: u64, k1: u64) -> SipHasher<C, D> {
+        SipHasher {
+            v0: k0 ^ 0x736f_6d65_7073_6575,
+            v1: k1 ^ 0x646f_7261_6e64_6f6d,
+            v2: k0 ^ 0x6c79_6765_6e65_7261,
+            v3: k1 ^ 0x7465_6462_7974_6573,
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, word: u64) {
+        self.v3 ^= word;
+        for _ in 0..C {
+            self.round();
+        }
+        self.v0 ^= word;
+    }
+}
+
+impl<const C: usize, const D: usize> Hasher for SipHasher<C, D> {
+    fn write(&mut self, mut bytes: &[u8]) {
+        self.length += bytes.len();
+
+        // Top up the word left over from the last write first
+        while self.ntail != 0 && !bytes.is_empty() {
+            self.tail |= (bytes[0] as u64) << (8 * self.ntail);
+            self.ntail = (self.ntail + 1) % 8;
+            bytes = &bytes[1..];
+
+            if self.ntail == 0 {
+                let word = core::mem::take(&mut self.tail);
+                self.compress(word);
+            }
+        }
+
+        if bytes.is_empty() {
+            return;
+        }
+
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.compress(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+
+        for (i, &byte) in words.remainder().iter().enumerate() {
+            self.tail |= (byte as u64) << (8 * i);
+        }
+        self.ntail = words.remainder().len();
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+
+        let last = ((self.length as u64 & 0xFF) << 56) | self.tail;
+        state.compress(last);
+
+        state.v2 ^= 0xFF;
+        for _ in 0..D {
+            state.round();
+        }
+
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn reference_key() -> (u64, u64) {
+        let key: Vec<u8> = (0..16).collect();
+        (
+            u64::from_le_bytes(key[..8].try_into().unwrap()),
+            u64::from_le_bytes(key[8..].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn matches_the_paper() {
+        let (k0, k1) = reference_key();
+        let message: Vec<u8> = (0..15).collect();
+
+        let mut hasher = SipHasher24::new_with_keys(k0, k1);
+        hasher.write(&message);
+        assert_eq!(hasher.finish(), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn split_writes_hash_the_same() {
+        let (k0, k1) = reference_key();
+        let message: Vec<u8> = (0..64).collect();
+
+        let mut whole = SipHasher13::new_with_keys(k0, k1);
+        whole.write(&message);
+
+        for split in [1, 3, 7, 8, 9, 31] {
+            let mut parts = SipHasher13::new_with_keys(k0, k1);
+            for chunk in message.chunks(split) {
+                parts.write(chunk);
+            }
+            assert_eq!(parts.finish(), whole.finish(), "chunks of {split}");
+        }
+    }
+
+    #[test]
+    fn keys_change_the_hash() {
+        let mut a = SipHasher13::new_with_keys(1, 2);
+        let mut b = SipHasher13::new_with_keys(2, 1);
+        a.write(b"kernel");
+        b.write(b"kernel");
+
+        assert_ne!(a.finish(), b.finish());
+    }
+}
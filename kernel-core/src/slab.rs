@@ -0,0 +1,269 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::align_up;
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Where slabs get their pages from
+pub trait PageProvider {
+    /// A zeroed page, `PAGE_SIZE` bytes and aligned to it, null if there's none left
+    fn page(&self) -> *mut u8;
+}
+
+/// Objects of one size carved out of whole pages, free ones are linked through their first word
+///
+/// Every page starts with a pointer back to its slab, so the slab must not move once it allocated
+pub struct Slab {
+    pub size: usize,
+    first_free: *mut *mut (),
+}
+
+impl Slab {
+    pub const fn new(size: usize) -> Slab {
+        Slab {
+            size,
+            first_free: core::ptr::null_mut(),
+        }
+    }
+
+    /// Adds a page worth of objects to the free list, false if no page could be had
+    fn grow(&mut self, pages: &impl PageProvider) -> bool {
+        let page = pages.page();
+        if page.is_null() {
+            return false;
+        }
+
+        let hdr_offset = align_up(8, self.size as u64) as usize;
+        let avl = PAGE_SIZE - hdr_offset;
+
+        let hdr = unsafe { &mut *page.cast::<*mut Slab>() };
+        *hdr = self;
+
+        self.first_free = unsafe { page.add(hdr_offset).cast() };
+
+        let arr = self.first_free;
+        let max = avl / self.size - 1;
+        let fact = self.size / 8;
+
+        for i in 0..max {
+            unsafe { *arr.add(i * fact) = arr.add((i + 1) * fact).cast() };
+        }
+        unsafe { *arr.add(max * fact) = core::ptr::null_mut() };
+
+        true
+    }
+
+    /// A zeroed object, null if the slab is full and `pages` is out of them
+    pub fn alloc(&mut self, pages: &impl PageProvider) -> *mut u8 {
+        if self.first_free.is_null() && !self.grow(pages) {
+            return core::ptr::null_mut();
+        }
+
+        let old_free = self.first_free;
+        self.first_free = unsafe { (*old_free).cast() };
+
+        let ret: *mut u8 = old_free.cast();
+        unsafe { core::ptr::write_bytes(ret, 0, self.size) };
+
+        ret
+    }
+
+    pub fn free(&mut self, ptr: *mut u8) {
+        let new_head: *mut *mut () = ptr.cast();
+        unsafe { *new_head = self.first_free.cast() };
+        self.first_free = new_head;
+    }
+
+    /// The slab `ptr` came from, found through the pointer at the start of its page
+    ///
+    /// # Safety
+    /// `ptr` has to come from `Slab::alloc`
+    pub unsafe fn of(ptr: *mut u8) -> *mut Slab {
+        *((ptr as usize & !(PAGE_SIZE - 1)) as *const *mut Slab)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::vec::Vec;
+
+    /// Hands out pages from the host allocator and counts them
+    #[derive(Default)]
+    struct MockPages {
+        pages: RefCell<Vec<*mut u8>>,
+    }
+
+    const LAYOUT: Layout = match Layout::from_size_align(PAGE_SIZE, PAGE_SIZE) {
+        Ok(layout) => layout,
+        Err(_) => panic!(),
+    };
+
+    impl PageProvider for MockPages {
+        fn page(&self) -> *mut u8 {
+            let page = unsafe { alloc_zeroed(LAYOUT) };
+            assert!(!page.is_null());
+            self.pages.borrow_mut().push(page);
+            page
+        }
+    }
+
+    impl MockPages {
+        fn count(&self) -> usize {
+            self.pages.borrow().len()
+        }
+
+        fn contains(&self, ptr: *mut u8, len: usize) -> bool {
+            self.pages.borrow().iter().any(|&page| {
+                let page = page as usize;
+                page <= ptr as usize && ptr as usize + len <= page + PAGE_SIZE
+            })
+        }
+    }
+
+    impl Drop for MockPages {
+        fn drop(&mut self) {
+            for &page in self.pages.borrow().iter() {
+                unsafe { dealloc(page, LAYOUT) };
+            }
+        }
+    }
+
+    /// The sizes the kernel heap uses
+    const SIZES: [usize; 10] = [8, 16, 24, 32, 48, 64, 128, 256, 512, 1024];
+
+    fn per_page(size: usize) -> usize {
+        (PAGE_SIZE - align_up(8, size as u64) as usize) / size
+    }
+
+    #[test]
+    fn objects_fill_a_page_before_the_next() {
+        for size in SIZES {
+            let pages = MockPages::default();
+            let mut slab = Slab::new(size);
+
+            let mut seen = HashSet::new();
+            for _ in 0..per_page(size) {
+                let ptr = slab.alloc(&pages);
+                assert!(
+                    pages.contains(ptr, size),
+                    "size {size}: {ptr:?} out of its page"
+                );
+                assert!(
+                    seen.insert(ptr as usize),
+                    "size {size}: {ptr:?} handed out twice"
+                );
+            }
+            assert_eq!(pages.count(), 1, "size {size}");
+
+            slab.alloc(&pages);
+            assert_eq!(pages.count(), 2, "size {size}");
+        }
+    }
+
+    #[test]
+    fn objects_dont_overlap_the_header_or_each_other() {
+        for size in SIZES {
+            let pages = MockPages::default();
+            let mut slab = Slab::new(size);
+
+            let mut ptrs: Vec<usize> = (0..per_page(size))
+                .map(|_| slab.alloc(&pages) as usize)
+                .collect();
+            ptrs.sort();
+
+            assert!(ptrs[0] % PAGE_SIZE >= 8, "size {size}: overlaps the header");
+            for pair in ptrs.windows(2) {
+                assert!(pair[1] - pair[0] >= size, "size {size}: objects overlap");
+            }
+        }
+    }
+
+    #[test]
+    fn objects_are_aligned_like_their_size() {
+        for size in SIZES {
+            let pages = MockPages::default();
+            let mut slab = Slab::new(size);
+            // The largest alignment a `Layout` of this size can ask for
+            let align = 1 << size.trailing_zeros();
+
+            for _ in 0..per_page(size) {
+                let ptr = slab.alloc(&pages) as usize;
+                assert_eq!(ptr % align, 0, "size {size}: {ptr:#x}");
+            }
+        }
+    }
+
+    #[test]
+    fn freed_objects_come_back_first_and_zeroed() {
+        let pages = MockPages::default();
+        let mut slab = Slab::new(64);
+
+        let a = slab.alloc(&pages);
+        let b = slab.alloc(&pages);
+        unsafe { a.write_bytes(0xAA, 64) };
+
+        slab.free(a);
+        let again = slab.alloc(&pages);
+        assert_eq!(again, a);
+        assert!(unsafe { std::slice::from_raw_parts(again, 64) }
+            .iter()
+            .all(|&byte| byte == 0));
+        assert_ne!(slab.alloc(&pages), b);
+    }
+
+    #[test]
+    fn pages_point_back_to_their_slab() {
+        let pages = MockPages::default();
+        let mut slab = Slab::new(32);
+        let slab_ptr: *mut Slab = &mut slab;
+
+        let count = per_page(32) + 1;
+        let ptrs: Vec<*mut u8> = (0..count).map(|_| slab.alloc(&pages)).collect();
+
+        for ptr in ptrs {
+            assert_eq!(unsafe { Slab::of(ptr) }, slab_ptr);
+        }
+    }
+
+    #[test]
+    fn running_out_of_pages_gives_null() {
+        struct NoPages;
+        impl PageProvider for NoPages {
+            fn page(&self) -> *mut u8 {
+                core::ptr::null_mut()
+            }
+        }
+
+        let pages = MockPages::default();
+        let mut slab = Slab::new(1024);
+
+        let first = slab.alloc(&pages);
+        for _ in 1..per_page(1024) {
+            assert!(!slab.alloc(&NoPages).is_null());
+        }
+        assert!(slab.alloc(&NoPages).is_null());
+
+        // A failed grow leaves the slab usable
+        slab.free(first);
+        assert_eq!(slab.alloc(&NoPages), first);
+    }
+}
@@ -1,15 +1,19 @@
 fn main() {
     // Tell cargo to pass the linker script to the linker..
     println!("cargo:rustc-link-arg=-Tlinker.ld");
+    // The unwinder looks frames up in .eh_frame_hdr
+    println!("cargo:rustc-link-arg=--eh-frame-hdr");
     // ..and to re-run if it changes.
     println!("cargo:rerun-if-changed=linker.ld");
     println!("cargo:rerun-if-changed=src/handlers.asm");
+    println!("cargo:rerun-if-changed=src/oops.asm");
 
     {
         let mut build = nasm_rs::Build::new();
 
         build
             .file("src/handlers.asm")
+            .file("src/oops.asm")
             .flag("-felf64")
             .target("x86_64-unknown-none");
 
@@ -0,0 +1,493 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ec;
+use super::sdt::{GenericAddress, SdtHeader};
+use crate::cpu;
+use crate::mm::PhysAddr;
+use crate::pci::{self, Address};
+use crate::sync::Mutex;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use aml::value::{AmlType, RegionSpace};
+use aml::{AmlContext, AmlName, DebugVerbosity, LevelType, NamespaceLevel};
+
+pub use aml::value::{AmlValue, Args};
+pub use aml::AmlError;
+
+static AML: Mutex<Option<AmlContext>> = Mutex::new(None);
+
+struct Handler;
+
+/// EmbeddedControl regions get moved here by `map_ec_regions`, where `Handler` turns accesses
+/// into EC transactions. The interpreter can't access them itself, and no physical address gets
+/// anywhere near this
+const EC_WINDOW: u64 = 0xFFFF_EC00_0000_0000;
+
+/// The EC address `address` stands for, if it's in `EC_WINDOW`
+fn ec_address(address: usize) -> Option<u8> {
+    u8::try_from((address as u64).checked_sub(EC_WINDOW)?).ok()
+}
+
+/// Reads `size` bytes of EC space, little endian like the fields over it
+fn ec_read(address: usize, size: usize) -> Option<u64> {
+    ec_address(address)?;
+
+    Some((0..size).fold(0, |value, i| {
+        let byte = ec_address(address + i)
+            .ok_or(ec::Timeout)
+            .and_then(ec::read)
+            .unwrap_or_else(|_| {
+                log::warn!(
+                    "EC read at {:#x} failed",
+                    address as u64 - EC_WINDOW + i as u64
+                );
+                0xFF
+            });
+
+        value | (byte as u64) << (8 * i)
+    }))
+}
+
+/// Writes `size` bytes of EC space, returns false if `address` isn't in it
+fn ec_write(address: usize, size: usize, value: u64) -> bool {
+    if ec_address(address).is_none() {
+        return false;
+    }
+
+    for i in 0..size {
+        let written = ec_address(address + i)
+            .ok_or(ec::Timeout)
+            .and_then(|address| ec::write(address, (value >> (8 * i)) as u8));
+
+        if written.is_err() {
+            log::warn!(
+                "EC write at {:#x} failed",
+                address as u64 - EC_WINDOW + i as u64
+            );
+        }
+    }
+
+    true
+}
+
+macro mmio_read($address:expr, $ty:ty) {
+    match ec_read($address, core::mem::size_of::<$ty>()) {
+        Some(value) => value as $ty,
+        None => unsafe {
+            core::ptr::read_volatile(PhysAddr::new($address as u64).as_hhdm().as_ptr::<$ty>())
+        },
+    }
+}
+
+macro mmio_write($address:expr, $value:expr) {
+    if !ec_write($address, core::mem::size_of_val(&$value), $value as u64) {
+        unsafe {
+            core::ptr::write_volatile(
+                PhysAddr::new($address as u64).as_hhdm().as_mut_ptr(),
+                $value,
+            )
+        }
+    }
+}
+
+impl aml::Handler for Handler {
+    fn read_u8(&self, address: usize) -> u8 {
+        mmio_read!(address, u8)
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        mmio_read!(address, u16)
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        mmio_read!(address, u32)
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        mmio_read!(address, u64)
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        mmio_write!(address, value)
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) {
+        mmio_write!(address, value)
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) {
+        mmio_write!(address, value)
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) {
+        mmio_write!(address, value)
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { cpu::inb(port) }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { cpu::inw(port) }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { cpu::inl(port) }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { cpu::outb(port, value) }
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { cpu::outw(port, value) }
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { cpu::outl(port, value) }
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        pci::config::read8(Address::new(segment, bus, device, function), offset)
+    }
+
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        pci::config::read16(Address::new(segment, bus, device, function), offset)
+    }
+
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        pci::config::read32(Address::new(segment, bus, device, function), offset)
+    }
+
+    fn write_pci_u8(&self, seg: u16, bus: u8, dev: u8, func: u8, offset: u16, value: u8) {
+        pci::config::write8(Address::new(seg, bus, dev, func), offset, value)
+    }
+
+    fn write_pci_u16(&self, seg: u16, bus: u8, dev: u8, func: u8, offset: u16, value: u16) {
+        pci::config::write16(Address::new(seg, bus, dev, func), offset, value)
+    }
+
+    fn write_pci_u32(&self, seg: u16, bus: u8, dev: u8, func: u8, offset: u16, value: u32) {
+        pci::config::write32(Address::new(seg, bus, dev, func), offset, value)
+    }
+
+    fn handle_fatal_error(&self, fatal_type: u8, fatal_code: u32, fatal_arg: u64) {
+        panic!("AML Fatal: type {fatal_type:#x}, code {fatal_code:#x}, arg {fatal_arg:#x}");
+    }
+}
+
+fn parse_table(context: &mut AmlContext, table: &SdtHeader) {
+    let stream = unsafe { core::slice::from_raw_parts(table.data(), table.data_len()) };
+
+    if let Err(e) = context.parse_table(stream) {
+        log::error!("Failed to parse {}: {e:?}", table.signature());
+    }
+}
+
+pub(super) fn init() {
+    log::trace!("Initializing the AML interpreter");
+
+    let mut context = AmlContext::new(Box::new(Handler), DebugVerbosity::None);
+
+    let Some(dsdt) = super::get_table("DSDT", 0) else {
+        log::warn!("No DSDT found, AML namespace will be empty");
+        return;
+    };
+    parse_table(&mut context, dsdt);
+
+    for ssdt in (0..).map_while(|i| super::get_table("SSDT", i)) {
+        parse_table(&mut context, ssdt);
+    }
+
+    if let Err(e) = context.initialize_objects() {
+        log::error!("Failed to initialize AML objects: {e:?}");
+    }
+
+    // Tell the firmware we're routing interrupts through the IOAPIC, this changes what _PRT returns
+    let pic = AmlName::from_str("\\_PIC").unwrap();
+    let args = Args::from_list(alloc::vec![AmlValue::Integer(1)]).unwrap();
+    match context.invoke_method(&pic, args) {
+        Ok(_) | Err(AmlError::ValueDoesNotExist(_)) => {}
+        Err(e) => log::warn!("Failed to evaluate \\_PIC: {e:?}"),
+    }
+
+    *AML.lock() = Some(context);
+}
+
+/// Turns every EmbeddedControl region into a window `Handler` forwards to the EC, returns how
+/// many there were
+///
+/// Only regions declared at the time are found, which are the ones at device scope the firmware
+/// actually uses
+pub(super) fn map_ec_regions() -> usize {
+    with_context(|ctx| {
+        let mut handles = Vec::new();
+        let _ = ctx.namespace.traverse(|_, level| {
+            handles.extend(level.values.values().copied());
+            Ok(true)
+        });
+
+        let mut mapped = 0;
+        for handle in handles {
+            if let Ok(AmlValue::OpRegion { region, offset, .. }) = ctx.namespace.get_mut(handle) {
+                if *region == RegionSpace::EmbeddedControl {
+                    *region = RegionSpace::SystemMemory;
+                    *offset += EC_WINDOW;
+                    mapped += 1;
+                }
+            }
+        }
+
+        mapped
+    })
+    .unwrap_or(0)
+}
+
+/// Runs `f` with exclusive access to the AML interpreter, if it was initialized
+pub fn with_context<R>(f: impl FnOnce(&mut AmlContext) -> R) -> Option<R> {
+    AML.lock().as_mut().map(f)
+}
+
+pub fn evaluate(path: &str, args: Args) -> Result<AmlValue, AmlError> {
+    let path = AmlName::from_str(path)?;
+    with_context(|ctx| ctx.invoke_method(&path, args)).ok_or(AmlError::ValueDoesNotExist(path))?
+}
+
+/// Returns the SLP_TYPa and SLP_TYPb values for the given sleep state (e.g. 5 for S5)
+pub fn sleep_type(state: u8) -> Result<(u16, u16), AmlError> {
+    let path = AmlName::from_str(&alloc::format!("\\_S{state}"))?;
+
+    with_context(|ctx| {
+        let package = match ctx.namespace.get_by_path(&path)? {
+            AmlValue::Package(package) => package.clone(),
+            value => {
+                return Err(AmlError::IncompatibleValueConversion {
+                    current: value.type_of(),
+                    target: AmlType::Package,
+                })
+            }
+        };
+
+        let slp_typ = |i: usize| -> Result<u16, AmlError> {
+            let value = package.get(i).ok_or(AmlError::InvalidArgAccess(i as u8))?;
+            Ok(value.as_integer(ctx)? as u16)
+        };
+
+        Ok((slp_typ(0)?, slp_typ(1).unwrap_or(0)))
+    })
+    .ok_or(AmlError::ValueDoesNotExist(path))?
+}
+
+pub fn integer(value: &AmlValue) -> Option<u64> {
+    match value {
+        AmlValue::Integer(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/// Decodes a buffer holding a Generic Register descriptor, as found in `_CST` and `_PCT`
+pub fn generic_address(value: &AmlValue) -> Option<GenericAddress> {
+    let AmlValue::Buffer(buffer) = value else {
+        return None;
+    };
+    let buffer = buffer.lock();
+
+    if buffer.len() < 15 || buffer[0] != 0x82 {
+        return None;
+    }
+
+    Some(GenericAddress {
+        address_space: buffer[3],
+        bit_width: buffer[4],
+        bit_offset: buffer[5],
+        access_size: buffer[6],
+        address: u64::from_le_bytes(buffer[7..15].try_into().unwrap()),
+    })
+}
+
+/// Compresses a 7 character PNP ID (e.g. `PNP0C09`) the way `EisaId` does
+fn eisa_id(id: &str) -> Option<u64> {
+    let id = id.as_bytes();
+    if id.len() != 7 {
+        return None;
+    }
+
+    let vendor = id[..3]
+        .iter()
+        .fold(0u32, |acc, &c| acc << 5 | ((c - 0x40) & 0x1F) as u32);
+    let product = u32::from_str_radix(core::str::from_utf8(&id[3..]).ok()?, 16).ok()?;
+
+    Some((vendor << 16 | product).swap_bytes() as u64)
+}
+
+fn has_hid(ctx: &mut AmlContext, device: &AmlName, hid: &str) -> bool {
+    let Ok(path) = AmlName::from_str("_HID").and_then(|name| name.resolve(device)) else {
+        return false;
+    };
+
+    match ctx.invoke_method(&path, Args::EMPTY) {
+        Ok(AmlValue::String(value)) => value == hid,
+        Ok(AmlValue::Integer(value)) => Some(value) == eisa_id(hid),
+        _ => false,
+    }
+}
+
+/// Walks the namespace collecting the levels `f` picks, along with every device
+fn collect(f: impl Fn(&NamespaceLevel) -> bool) -> (Vec<AmlName>, Vec<AmlName>) {
+    with_context(|ctx| {
+        let (mut matches, mut devices) = (Vec::new(), Vec::new());
+
+        let _ = ctx.namespace.traverse(|name, level| {
+            if f(level) {
+                matches.push(name.clone());
+            } else if level.typ == LevelType::Device {
+                devices.push(name.clone());
+            }
+
+            Ok(true)
+        });
+
+        (matches, devices)
+    })
+    .unwrap_or_default()
+}
+
+/// Returns the paths of every device whose `_HID` is `hid`, either as a string or an EISA ID
+pub fn devices(hid: &str) -> Vec<String> {
+    let (_, devices) = collect(|_| false);
+
+    with_context(|ctx| {
+        devices
+            .iter()
+            .filter(|device| has_hid(ctx, device, hid))
+            .map(AmlName::as_string)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Returns the paths of every device in the namespace
+pub fn all_devices() -> Vec<String> {
+    let (devices, _) = collect(|level| level.typ == LevelType::Device);
+    devices.iter().map(AmlName::as_string).collect()
+}
+
+/// Returns the `_HID` of the device at `device`, EISA IDs are expanded back to their string form
+pub fn hid(device: &str) -> Option<String> {
+    match evaluate(&alloc::format!("{device}._HID"), Args::EMPTY).ok()? {
+        AmlValue::String(hid) => Some(hid),
+        AmlValue::Integer(id) => {
+            let id = (id as u32).swap_bytes();
+            let vendor = (0..3)
+                .rev()
+                .map(|i| (((id >> 16 >> (i * 5)) & 0x1F) as u8 + 0x40) as char);
+
+            Some(
+                vendor
+                    .chain(alloc::format!("{:04X}", id & 0xFFFF).chars())
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Returns the paths of every processor, declared either as a legacy `Processor` or an `ACPI0007` device
+pub fn processors() -> Vec<String> {
+    let (processors, devices) = collect(|level| level.typ == LevelType::Processor);
+
+    with_context(|ctx| {
+        processors
+            .iter()
+            .chain(
+                devices
+                    .iter()
+                    .filter(|device| has_hid(ctx, device, "ACPI0007")),
+            )
+            .map(AmlName::as_string)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Returns the names of the objects declared directly under `scope`, e.g. the `_Lxx` methods of `\_GPE`
+pub fn children(scope: &str) -> Vec<String> {
+    let Ok(scope) = AmlName::from_str(scope) else {
+        return Vec::new();
+    };
+
+    with_context(|ctx| {
+        let mut children = Vec::new();
+
+        let _ = ctx.namespace.traverse(|name, level| {
+            if *name == scope {
+                children.extend(level.values.keys().map(|seg| String::from(seg.as_str())));
+            }
+
+            Ok(true)
+        });
+
+        children
+    })
+    .unwrap_or_default()
+}
+
+/// Returns the base of every I/O port range in the `_CRS` of `device`, in order
+///
+/// The interpreter's I/O descriptors don't expose their ranges, so the buffer is walked by hand
+pub fn io_ports(device: &str) -> Result<Vec<u16>, AmlError> {
+    let AmlValue::Buffer(crs) = evaluate(&alloc::format!("{device}._CRS"), Args::EMPTY)? else {
+        return Ok(Vec::new());
+    };
+
+    let crs = crs.lock();
+    let mut ports = Vec::new();
+    let mut i = 0;
+
+    while let Some(&tag) = crs.get(i) {
+        if tag & 0x80 != 0 {
+            // Large descriptors have a 16 bit length after the tag
+            let Some(&[low, high]) = crs.get(i + 1..i + 3) else {
+                break;
+            };
+
+            i += 3 + u16::from_le_bytes([low, high]) as usize;
+            continue;
+        }
+
+        let len = (tag & 0b111) as usize;
+        let Some(body) = crs.get(i + 1..i + 1 + len) else {
+            break;
+        };
+
+        match (tag >> 3, body) {
+            // I/O port descriptor, the minimum base is what's decoded
+            (0x08, [_, low, high, ..]) => ports.push(u16::from_le_bytes([*low, *high])),
+            // Fixed location I/O port descriptor
+            (0x09, [low, high, ..]) => ports.push(u16::from_le_bytes([*low, *high])),
+            // End tag
+            (0x0F, _) => break,
+            _ => {}
+        }
+
+        i += 1 + len;
+    }
+
+    Ok(ports)
+}
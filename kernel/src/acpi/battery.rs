@@ -0,0 +1,263 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::aml::{self, AmlValue, Args};
+use crate::fs::kernelfs;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// `_STA` bit set when a battery is inserted
+const STA_BATTERY_PRESENT: u64 = 1 << 4;
+
+/// Value used by `_BIF` and `_BST` for unknown fields
+const UNKNOWN: u64 = 0xFFFF_FFFF;
+
+/// `_BST` state bits
+const DISCHARGING: u64 = 1 << 0;
+const CHARGING: u64 = 1 << 1;
+const CRITICAL: u64 = 1 << 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerUnit {
+    MilliWatt,
+    MilliAmp,
+}
+
+/// Static battery information, from `_BIF`
+#[derive(Clone, Debug)]
+pub struct BatteryInfo {
+    pub unit: PowerUnit,
+    /// Capacities are in mWh or mAh depending on `unit`
+    pub design_capacity: Option<u64>,
+    pub last_full_capacity: Option<u64>,
+    pub rechargeable: bool,
+    /// Design voltage in mV
+    pub design_voltage: Option<u64>,
+    pub model: String,
+    pub serial: String,
+    pub kind: String,
+    pub oem: String,
+}
+
+/// Current battery state, from `_BST`
+#[derive(Clone, Copy, Debug)]
+pub struct BatteryStatus {
+    pub charging: bool,
+    pub discharging: bool,
+    pub critical: bool,
+    /// mW or mA depending on the unit of the battery
+    pub rate: Option<u64>,
+    pub remaining_capacity: Option<u64>,
+    /// Voltage in mV
+    pub voltage: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Battery {
+    pub path: String,
+    pub info: BatteryInfo,
+    pub status: BatteryStatus,
+}
+
+impl Battery {
+    /// Charge level in percent of the last full charge
+    pub fn percentage(&self) -> Option<u64> {
+        let full = self.info.last_full_capacity.or(self.info.design_capacity)?;
+        let remaining = self.status.remaining_capacity?;
+
+        (full != 0).then(|| core::cmp::min(remaining * 100 / full, 100))
+    }
+}
+
+fn package(path: &str) -> Option<Vec<AmlValue>> {
+    match aml::evaluate(path, Args::EMPTY).ok()? {
+        AmlValue::Package(package) => Some(package),
+        _ => None,
+    }
+}
+
+fn known(value: Option<&AmlValue>) -> Option<u64> {
+    value.and_then(aml::integer).filter(|&v| v != UNKNOWN)
+}
+
+fn string(value: Option<&AmlValue>) -> String {
+    match value {
+        Some(AmlValue::String(s)) => s.clone(),
+        _ => String::new(),
+    }
+}
+
+fn present(device: &str) -> bool {
+    // Devices without a _STA are always there
+    match aml::evaluate(&alloc::format!("{device}._STA"), Args::EMPTY) {
+        Ok(sta) => aml::integer(&sta).is_some_and(|sta| sta & STA_BATTERY_PRESENT != 0),
+        Err(_) => true,
+    }
+}
+
+fn info(device: &str) -> Option<BatteryInfo> {
+    let bif = package(&alloc::format!("{device}._BIF"))?;
+
+    Some(BatteryInfo {
+        unit: match aml::integer(bif.first()?)? {
+            0 => PowerUnit::MilliWatt,
+            _ => PowerUnit::MilliAmp,
+        },
+        design_capacity: known(bif.get(1)),
+        last_full_capacity: known(bif.get(2)),
+        rechargeable: known(bif.get(3)) == Some(1),
+        design_voltage: known(bif.get(4)),
+        model: string(bif.get(9)),
+        serial: string(bif.get(10)),
+        kind: string(bif.get(11)),
+        oem: string(bif.get(12)),
+    })
+}
+
+fn status(device: &str) -> Option<BatteryStatus> {
+    let bst = package(&alloc::format!("{device}._BST"))?;
+    let state = aml::integer(bst.first()?)?;
+
+    Some(BatteryStatus {
+        charging: state & CHARGING != 0,
+        discharging: state & DISCHARGING != 0,
+        critical: state & CRITICAL != 0,
+        rate: known(bst.get(1)),
+        remaining_capacity: known(bst.get(2)),
+        voltage: known(bst.get(3)),
+    })
+}
+
+/// Returns every battery currently inserted, along with its charge state
+pub fn batteries() -> Vec<Battery> {
+    aml::devices("PNP0C0A")
+        .into_iter()
+        .filter(|path| present(path))
+        .filter_map(|path| {
+            Some(Battery {
+                info: info(&path)?,
+                status: status(&path)?,
+                path,
+            })
+        })
+        .collect()
+}
+
+/// Whether we're running off an AC adapter, `None` if there's no adapter to ask
+pub fn ac_online() -> Option<bool> {
+    let adapters = aml::devices("ACPI0003");
+    if adapters.is_empty() {
+        return None;
+    }
+
+    Some(adapters.iter().any(|adapter| {
+        aml::evaluate(&alloc::format!("{adapter}._PSR"), Args::EMPTY)
+            .ok()
+            .as_ref()
+            .and_then(aml::integer)
+            == Some(1)
+    }))
+}
+
+/// `/kernel/battery`, `_BST` and `_PSR` are evaluated again on every read
+fn battery_file() -> Vec<u8> {
+    let mut text = String::new();
+
+    if let Some(online) = ac_online() {
+        let _ = writeln!(text, "ac: {}", if online { "online" } else { "offline" });
+    }
+
+    for battery in batteries() {
+        let unit = match battery.info.unit {
+            PowerUnit::MilliWatt => ("mWh", "mW"),
+            PowerUnit::MilliAmp => ("mAh", "mA"),
+        };
+        let state = match (battery.status.charging, battery.status.discharging) {
+            (true, _) => "charging",
+            (_, true) => "discharging",
+            _ => "idle",
+        };
+
+        let _ = write!(text, "{}: {state}", battery.path);
+        if let Some(percentage) = battery.percentage() {
+            let _ = write!(text, " {percentage}%");
+        }
+        if let Some(remaining) = battery.status.remaining_capacity {
+            let _ = write!(text, " remaining {remaining} {}", unit.0);
+        }
+        if let Some(rate) = battery.status.rate {
+            let _ = write!(text, " rate {rate} {}", unit.1);
+        }
+        if let Some(voltage) = battery.status.voltage {
+            let _ = write!(text, " voltage {voltage} mV");
+        }
+        if battery.status.critical {
+            let _ = write!(text, " critical");
+        }
+        let _ = writeln!(text);
+
+        let info = &battery.info;
+        let _ = write!(
+            text,
+            "  {} {} {}, serial {:?}",
+            info.oem,
+            info.model,
+            if info.rechargeable {
+                "rechargeable"
+            } else {
+                "primary"
+            },
+            info.serial
+        );
+        if !info.kind.is_empty() {
+            let _ = write!(text, ", {}", info.kind);
+        }
+        if let Some(capacity) = info.design_capacity {
+            let _ = write!(text, ", design {capacity} {}", unit.0);
+        }
+        if let Some(voltage) = info.design_voltage {
+            let _ = write!(text, " at {voltage} mV");
+        }
+        let _ = writeln!(text);
+    }
+
+    text.into_bytes()
+}
+
+pub(super) fn init() {
+    kernelfs::register("battery", battery_file);
+
+    if let Some(online) = ac_online() {
+        log::info!("AC adapter {}", if online { "online" } else { "offline" });
+    }
+
+    for battery in batteries() {
+        log::info!(
+            "Battery {} ({} {}): {}%{}",
+            battery.path,
+            battery.info.oem,
+            battery.info.model,
+            battery.percentage().unwrap_or(0),
+            if battery.status.charging {
+                ", charging"
+            } else {
+                ""
+            }
+        );
+    }
+}
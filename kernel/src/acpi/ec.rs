@@ -0,0 +1,280 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::aml;
+use super::sdt::{AcpiTable, GenericAddress, SdtHeader};
+use crate::cpu;
+use crate::sync::Mutex;
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// EC status register bits
+const OBF: u8 = 1 << 0;
+const IBF: u8 = 1 << 1;
+const SCI_EVT: u8 = 1 << 5;
+
+/// EC commands
+const RD_EC: u8 = 0x80;
+const WR_EC: u8 = 0x81;
+const QR_EC: u8 = 0x84;
+
+/// Address space ID of EmbeddedControl regions, as `_REG` takes it
+const EMBEDDED_CONTROL: u64 = 3;
+
+/// How many times to poll the status register before giving up on the EC
+const TIMEOUT: usize = 100_000;
+
+/// Upper bound on queries drained per event, in case the EC keeps SCI_EVT stuck
+const MAX_QUERIES: usize = 32;
+
+static EC: Mutex<Option<EmbeddedController>> = Mutex::new(None);
+/// Set by the GPE, which stays disabled until `poll` drained the queries
+static EVENTS_PENDING: AtomicBool = AtomicBool::new(false);
+
+#[repr(C, packed)]
+struct Ecdt {
+    hdr: SdtHeader,
+    control: GenericAddress,
+    data: GenericAddress,
+    uid: u32,
+    gpe: u8,
+    id: [u8; 0],
+}
+
+unsafe impl AcpiTable for Ecdt {
+    const SIGNATURE: &'static str = "ECDT";
+}
+
+/// The EC didn't answer in time
+#[derive(Clone, Copy, Debug)]
+pub struct Timeout;
+
+pub struct EmbeddedController {
+    command: u16,
+    data: u16,
+    gpe: Option<u32>,
+    path: Option<String>,
+}
+
+impl EmbeddedController {
+    fn status(&self) -> u8 {
+        unsafe { cpu::inb(self.command) }
+    }
+
+    fn wait(&self, done: impl Fn(u8) -> bool) -> Result<(), Timeout> {
+        for _ in 0..TIMEOUT {
+            if done(self.status()) {
+                return Ok(());
+            }
+
+            core::hint::spin_loop();
+        }
+
+        Err(Timeout)
+    }
+
+    fn send_command(&self, command: u8) -> Result<(), Timeout> {
+        self.wait(|s| s & IBF == 0)?;
+        unsafe { cpu::outb(self.command, command) };
+        Ok(())
+    }
+
+    fn send_data(&self, data: u8) -> Result<(), Timeout> {
+        self.wait(|s| s & IBF == 0)?;
+        unsafe { cpu::outb(self.data, data) };
+        Ok(())
+    }
+
+    fn receive_data(&self) -> Result<u8, Timeout> {
+        self.wait(|s| s & OBF != 0)?;
+        Ok(unsafe { cpu::inb(self.data) })
+    }
+
+    pub fn read(&self, address: u8) -> Result<u8, Timeout> {
+        self.send_command(RD_EC)?;
+        self.send_data(address)?;
+        self.receive_data()
+    }
+
+    pub fn write(&self, address: u8, value: u8) -> Result<(), Timeout> {
+        self.send_command(WR_EC)?;
+        self.send_data(address)?;
+        self.send_data(value)
+    }
+
+    /// Asks the EC which event is pending, zero means none
+    pub fn query(&self) -> Result<u8, Timeout> {
+        self.send_command(QR_EC)?;
+        self.receive_data()
+    }
+
+    pub fn event_pending(&self) -> bool {
+        self.status() & SCI_EVT != 0
+    }
+}
+
+fn from_ecdt() -> Option<EmbeddedController> {
+    let ecdt = super::table::<Ecdt>()?;
+    let (control, data) = (ecdt.control, ecdt.data);
+
+    if control.address_space != GenericAddress::SYSTEM_IO
+        || data.address_space != GenericAddress::SYSTEM_IO
+    {
+        log::warn!("Only port I/O ECs are supported");
+        return None;
+    }
+
+    // The namespace path of the EC follows the table as a null terminated string
+    let id = unsafe {
+        let len = { ecdt.hdr }
+            .len()
+            .saturating_sub(core::mem::size_of::<Ecdt>());
+        core::slice::from_raw_parts(core::ptr::addr_of!(ecdt.id).cast::<u8>(), len)
+    };
+    let path = id.split(|&c| c == 0).next().unwrap_or_default();
+
+    Some(EmbeddedController {
+        command: control.address as u16,
+        data: data.address as u16,
+        gpe: Some(ecdt.gpe as u32),
+        path: core::str::from_utf8(path).ok().map(String::from),
+    })
+}
+
+fn from_namespace() -> Option<EmbeddedController> {
+    let path = aml::devices("PNP0C09").into_iter().next()?;
+
+    // The data port comes first, then the command/status one
+    let ports = aml::io_ports(&path).ok()?;
+    let (&data, &command) = (ports.first()?, ports.get(1)?);
+
+    let gpe = aml::evaluate(&alloc::format!("{path}._GPE"), aml::Args::EMPTY)
+        .ok()
+        .as_ref()
+        .and_then(aml::integer)
+        .map(|gpe| gpe as u32);
+
+    Some(EmbeddedController {
+        command,
+        data,
+        gpe,
+        path: Some(path),
+    })
+}
+
+/// Runs `f` with the EC, if there is one
+pub fn with<R>(f: impl FnOnce(&EmbeddedController) -> R) -> Option<R> {
+    EC.lock().as_ref().map(f)
+}
+
+pub fn read(address: u8) -> Result<u8, Timeout> {
+    with(|ec| ec.read(address)).unwrap_or(Err(Timeout))
+}
+
+pub fn write(address: u8, value: u8) -> Result<(), Timeout> {
+    with(|ec| ec.write(address, value)).unwrap_or(Err(Timeout))
+}
+
+/// The GPE the EC signals its events on
+pub fn gpe() -> Option<u32> {
+    with(|ec| ec.gpe).flatten()
+}
+
+/// Drains the pending EC events, handing each to the firmware's `_Qxx`
+fn handle_events() {
+    for _ in 0..MAX_QUERIES {
+        let query = match with(|ec| ec.event_pending().then(|| ec.query())).flatten() {
+            Some(Ok(query)) if query != 0 => query,
+            Some(Err(Timeout)) => {
+                log::warn!("EC query timed out");
+                break;
+            }
+            _ => break,
+        };
+
+        let Some(path) = with(|ec| ec.path.clone()).flatten() else {
+            log::debug!("Unhandled EC query {query:#04x}");
+            continue;
+        };
+
+        let method = alloc::format!("{path}._Q{query:02X}");
+        match aml::evaluate(&method, aml::Args::EMPTY) {
+            Ok(_) => {}
+            Err(aml::AmlError::ValueDoesNotExist(_)) => {
+                log::debug!("Unhandled EC query {query:#04x}")
+            }
+            Err(e) => log::warn!("Failed to evaluate {method}: {e:?}"),
+        }
+    }
+}
+
+/// Handles the EC events the GPE signaled since the last call, from the idle loop since `_Qxx`
+/// can take any lock AML can
+pub fn poll() {
+    if EVENTS_PENDING.swap(false, Ordering::Acquire) {
+        handle_events();
+
+        if let Some(gpe) = gpe() {
+            super::gpe::enable(gpe);
+        }
+    }
+}
+
+/// Routes the EC's GPE to `poll`, once the GPE blocks are set up
+pub(super) fn enable_events() {
+    if let Some(gpe) = gpe() {
+        super::gpe::install_handler(gpe, |gpe| {
+            super::gpe::disable(gpe);
+            EVENTS_PENDING.store(true, Ordering::Release);
+        });
+    }
+}
+
+pub(super) fn init() {
+    let Some(ec) = from_ecdt().or_else(from_namespace) else {
+        log::debug!("No embedded controller");
+        return;
+    };
+
+    log::info!(
+        "EC {} at ports {:#x}/{:#x}, GPE {:?}",
+        ec.path.as_deref().unwrap_or("?"),
+        ec.data,
+        ec.command,
+        ec.gpe
+    );
+
+    let path = ec.path.clone();
+    *EC.lock() = Some(ec);
+
+    let regions = aml::map_ec_regions();
+    log::debug!("{regions} EmbeddedControl regions mapped");
+
+    // Tells the firmware it can use the EC space from now on, which is optional
+    if let Some(path) = path {
+        let args = aml::Args::from_list(alloc::vec![
+            aml::AmlValue::Integer(EMBEDDED_CONTROL),
+            aml::AmlValue::Integer(1),
+        ])
+        .unwrap();
+
+        match aml::evaluate(&alloc::format!("{path}._REG"), args) {
+            Ok(_) | Err(aml::AmlError::ValueDoesNotExist(_)) => {}
+            Err(e) => log::warn!("Failed to evaluate {path}._REG: {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::madt;
+use super::sdt::GenericAddress;
+use super::{ec, gpe};
+use crate::interrupts::{self, InterruptStack};
+use crate::{cpu, hpet, ioapic, power};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// SCI_EN bit of the PM1 control registers, set once the firmware handed ACPI over to us
+const SCI_EN: u64 = 1 << 0;
+
+/// How long the firmware gets to set SCI_EN after being asked to, in milliseconds
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 3000;
+
+/// PM1 status and enable bits we care about
+pub const PWRBTN: u64 = 1 << 8;
+pub const SLPBTN: u64 = 1 << 9;
+
+/// Set by the SCI, the policy runs from `poll` since it can take any lock there is
+static POWER_BUTTON_PRESSED: AtomicBool = AtomicBool::new(false);
+
+/// Splits a PM1 event block into its status (first half) and enable (second half) registers
+fn pm1_registers(block: GenericAddress) -> (GenericAddress, GenericAddress) {
+    let width = block.bit_width / 2;
+    let status = GenericAddress {
+        bit_width: width,
+        ..block
+    };
+    let enable = GenericAddress {
+        bit_width: width,
+        address: block.address + (width / 8) as u64,
+        ..block
+    };
+
+    (status, enable)
+}
+
+fn pm1_blocks() -> impl Iterator<Item = (GenericAddress, GenericAddress)> {
+    let fadt = super::fadt();
+
+    fadt.and_then(|f| f.pm1a_evt())
+        .into_iter()
+        .chain(fadt.and_then(|f| f.pm1b_evt()))
+        .map(pm1_registers)
+}
+
+/// Asks the firmware to hand the fixed hardware over to the OS, if it didn't already
+pub fn enable_acpi_mode() {
+    let Some(fadt) = super::fadt() else {
+        return;
+    };
+    let Some(pm1a) = fadt.pm1a_cnt() else {
+        return;
+    };
+    let (smi_cmd, acpi_enable) = (fadt.smi_cmd, fadt.acpi_enable);
+
+    unsafe {
+        if pm1a.read() & SCI_EN != 0 || smi_cmd == 0 || acpi_enable == 0 {
+            return;
+        }
+
+        log::debug!("Switching to ACPI mode");
+        cpu::outb(smi_cmd as u16, acpi_enable);
+
+        for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+            if pm1a.read() & SCI_EN != 0 {
+                return;
+            }
+
+            hpet::try_sleep(1_000_000);
+        }
+    }
+
+    log::warn!("The firmware didn't switch to ACPI mode in {ACPI_ENABLE_TIMEOUT_MS} ms");
+}
+
+/// Reads and acknowledges the pending PM1 fixed events
+fn pending_fixed_events() -> u64 {
+    let mut pending = 0;
+
+    for (status, enable) in pm1_blocks() {
+        unsafe {
+            let events = status.read() & enable.read();
+            // Status bits are write-one-to-clear
+            status.write(events);
+            pending |= events;
+        }
+    }
+
+    pending
+}
+
+fn sci_handler(_stack: &mut InterruptStack) {
+    let events = pending_fixed_events();
+
+    // The SCI is level triggered, every source must be quiet before the EOI
+    gpe::dispatch();
+    core!().apic.lock().eoi();
+
+    if events & SLPBTN != 0 {
+        log::info!("Sleep button pressed");
+    }
+
+    if events & PWRBTN != 0 {
+        POWER_BUTTON_PRESSED.store(true, Ordering::Release);
+    }
+}
+
+/// Cleanly powers the machine off if the power button was pressed, called from the idle loop
+pub fn poll() {
+    if POWER_BUTTON_PRESSED.swap(false, Ordering::Acquire) {
+        log::info!("Power button pressed");
+        power::power_off();
+    }
+}
+
+pub fn init() {
+    let Some(fadt) = super::fadt() else {
+        log::warn!("No FADT, ACPI events are disabled");
+        return;
+    };
+
+    if fadt.hardware_reduced() {
+        log::warn!("Hardware reduced ACPI platforms are not supported, no fixed events");
+        return;
+    }
+
+    enable_acpi_mode();
+
+    let mut enabled = 0;
+    if fadt.flags & super::fadt::PWR_BUTTON == 0 {
+        enabled |= PWRBTN;
+    } else {
+        log::debug!("The power button is a control method device");
+    }
+
+    if fadt.flags & super::fadt::SLP_BUTTON == 0 {
+        enabled |= SLPBTN;
+    }
+
+    for (status, enable) in pm1_blocks() {
+        unsafe {
+            // Clear whatever the firmware left pending, then enable only what we handle
+            status.write(!0);
+            enable.write(enabled);
+        }
+    }
+
+    gpe::init();
+    ec::enable_events();
+
+    let sci = fadt.sci_int;
+    let vector = interrupts::allocate_handler(sci_handler).expect("No free interrupt vectors");
+    let apic_id = core!().apic.lock().id();
+
+    // The SCI is shareable, level triggered, active low unless the MADT overrides it
+    let (gsi, polarity, trigger) = madt::sci_irq(sci);
+    ioapic::route_gsi(gsi, vector, apic_id, polarity, trigger);
+
+    log::info!("SCI on IRQ {sci} (GSI {gsi}, {polarity:?}, {trigger:?}), vector {vector:#x}");
+}
+
+initcall!(acpi_events, init, [acpi, apic, ioapic]);
@@ -0,0 +1,202 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::sdt::{GenericAddress, SdtHeader};
+use crate::mm::PhysAddr;
+use crate::sync::Once;
+use alloc::boxed::Box;
+use core::mem::size_of;
+
+static FADT: Once<&'static Fadt> = Once::new();
+
+/// The reset register is supported
+pub const RESET_REG_SUP: u32 = 1 << 10;
+
+/// The power button is a control method device instead of a fixed feature
+pub const PWR_BUTTON: u32 = 1 << 4;
+
+/// The sleep button is a control method device instead of a fixed feature
+pub const SLP_BUTTON: u32 = 1 << 5;
+
+/// Fixed hardware isn't implemented, everything goes through the sleep control registers
+pub const HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// IA-PC boot architecture flag telling there's an 8042 or equivalent
+pub const HAS_8042: u16 = 1 << 1;
+
+#[repr(C, packed)]
+pub struct Fadt {
+    pub hdr: SdtHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    _reserved0: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_int: u16,
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_req: u8,
+    pub pstate_cnt: u8,
+    pub pm1a_evt_blk: u32,
+    pub pm1b_evt_blk: u32,
+    pub pm1a_cnt_blk: u32,
+    pub pm1b_cnt_blk: u32,
+    pub pm2_cnt_blk: u32,
+    pub pm_tmr_blk: u32,
+    pub gpe0_blk: u32,
+    pub gpe1_blk: u32,
+    pub pm1_evt_len: u8,
+    pub pm1_cnt_len: u8,
+    pub pm2_cnt_len: u8,
+    pub pm_tmr_len: u8,
+    pub gpe0_blk_len: u8,
+    pub gpe1_blk_len: u8,
+    pub gpe1_base: u8,
+    pub cst_cnt: u8,
+    pub p_lvl2_lat: u16,
+    pub p_lvl3_lat: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alrm: u8,
+    pub mon_alrm: u8,
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    _reserved1: u8,
+    pub flags: u32,
+    pub reset_reg: GenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_arch: u16,
+    pub fadt_minor_version: u8,
+    pub x_firmware_ctrl: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_evt_blk: GenericAddress,
+    pub x_pm1b_evt_blk: GenericAddress,
+    pub x_pm1a_cnt_blk: GenericAddress,
+    pub x_pm1b_cnt_blk: GenericAddress,
+    pub x_pm2_cnt_blk: GenericAddress,
+    pub x_pm_tmr_blk: GenericAddress,
+    pub x_gpe0_blk: GenericAddress,
+    pub x_gpe1_blk: GenericAddress,
+    pub sleep_control_reg: GenericAddress,
+    pub sleep_status_reg: GenericAddress,
+    pub hypervisor_vendor_id: u64,
+}
+
+/// Picks the extended address if the firmware provides one, or builds an IO address out of
+/// the legacy block
+fn block(x_block: GenericAddress, legacy: u32, len: u8) -> Option<GenericAddress> {
+    if !x_block.is_null() {
+        return Some(x_block);
+    }
+
+    if legacy == 0 {
+        return None;
+    }
+
+    Some(GenericAddress {
+        address_space: GenericAddress::SYSTEM_IO,
+        bit_width: len * 8,
+        bit_offset: 0,
+        access_size: 0,
+        address: legacy as u64,
+    })
+}
+
+impl Fadt {
+    pub fn dsdt_address(&self) -> PhysAddr {
+        match self.x_dsdt {
+            0 => PhysAddr::new(self.dsdt as u64),
+            x_dsdt => PhysAddr::new(x_dsdt),
+        }
+    }
+
+    pub fn hardware_reduced(&self) -> bool {
+        self.flags & HW_REDUCED_ACPI != 0
+    }
+
+    pub fn pm1a_evt(&self) -> Option<GenericAddress> {
+        block(self.x_pm1a_evt_blk, self.pm1a_evt_blk, self.pm1_evt_len)
+    }
+
+    pub fn pm1b_evt(&self) -> Option<GenericAddress> {
+        block(self.x_pm1b_evt_blk, self.pm1b_evt_blk, self.pm1_evt_len)
+    }
+
+    pub fn pm1a_cnt(&self) -> Option<GenericAddress> {
+        block(self.x_pm1a_cnt_blk, self.pm1a_cnt_blk, self.pm1_cnt_len)
+    }
+
+    pub fn pm1b_cnt(&self) -> Option<GenericAddress> {
+        block(self.x_pm1b_cnt_blk, self.pm1b_cnt_blk, self.pm1_cnt_len)
+    }
+
+    pub fn pm_timer(&self) -> Option<GenericAddress> {
+        block(self.x_pm_tmr_blk, self.pm_tmr_blk, self.pm_tmr_len)
+    }
+
+    pub fn gpe0(&self) -> Option<GenericAddress> {
+        block(self.x_gpe0_blk, self.gpe0_blk, self.gpe0_blk_len)
+    }
+
+    pub fn gpe1(&self) -> Option<GenericAddress> {
+        block(self.x_gpe1_blk, self.gpe1_blk, self.gpe1_blk_len)
+    }
+
+    pub fn reset_register(&self) -> Option<GenericAddress> {
+        let reset_reg = self.reset_reg;
+        (self.flags & RESET_REG_SUP != 0 && !reset_reg.is_null()).then_some(reset_reg)
+    }
+
+    pub fn has_8042(&self) -> bool {
+        // Before ACPI 2.0 the field doesn't exist, and every PC had one
+        { self.hdr }.revision() < 2 || self.iapc_boot_arch & HAS_8042 != 0
+    }
+}
+
+pub(super) fn init() {
+    // Older revisions are shorter, so this can't be an `AcpiTable`
+    let Some(table) = super::get_table("FACP", 0) else {
+        log::warn!("No FADT found");
+        return;
+    };
+
+    // Copy what's there and leave the rest zeroed
+    let len = core::cmp::min(table.len(), size_of::<Fadt>());
+    let mut fadt: Box<Fadt> = Box::new(unsafe { core::mem::zeroed() });
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (table as *const SdtHeader).cast::<u8>(),
+            (&mut *fadt as *mut Fadt).cast::<u8>(),
+            len,
+        )
+    };
+
+    let (revision, minor) = ({ fadt.hdr }.revision(), fadt.fadt_minor_version);
+    let (sci, flags, boot_arch) = (fadt.sci_int, fadt.flags, fadt.iapc_boot_arch);
+    log::debug!(
+        "FADT rev {revision}.{minor}: SCI {sci}, flags {flags:#x}, boot arch {boot_arch:#x}"
+    );
+
+    FADT.call_once(|| Box::leak(fadt));
+}
+
+pub fn get() -> Option<&'static Fadt> {
+    FADT.get().copied()
+}
@@ -0,0 +1,226 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::aml::{self, Args};
+use super::sdt::GenericAddress;
+use crate::sync::IrqSpinlock;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+static BLOCKS: IrqSpinlock<Vec<Block>> = IrqSpinlock::new(Vec::new());
+static HANDLERS: IrqSpinlock<BTreeMap<u32, Handler>> = IrqSpinlock::new(BTreeMap::new());
+
+/// Handles a GPE on behalf of a driver, gets the GPE number
+pub type GpeHandler = fn(u32);
+
+#[derive(Clone, Copy, Debug)]
+enum Handler {
+    /// `\_GPE._Lxx` or `\_GPE._Exx`
+    Method {
+        level: bool,
+    },
+    Driver(GpeHandler),
+}
+
+/// A GPE block: the status registers, followed by as many enable registers
+struct Block {
+    address: GenericAddress,
+    /// Length of the status and enable halves, in bytes
+    registers: usize,
+    first: u32,
+}
+
+impl Block {
+    fn new(address: GenericAddress, len: u8, first: u32) -> Block {
+        Block {
+            address,
+            registers: len as usize / 2,
+            first,
+        }
+    }
+
+    fn register(&self, offset: usize) -> GenericAddress {
+        GenericAddress {
+            bit_width: 8,
+            address: self.address.address + offset as u64,
+            ..self.address
+        }
+    }
+
+    fn status(&self, index: usize) -> GenericAddress {
+        self.register(index)
+    }
+
+    fn enable(&self, index: usize) -> GenericAddress {
+        self.register(self.registers + index)
+    }
+
+    fn contains(&self, gpe: u32) -> bool {
+        (self.first..self.first + self.registers as u32 * 8).contains(&gpe)
+    }
+
+    /// Returns the index of the register holding `gpe` and its bit
+    fn locate(&self, gpe: u32) -> (usize, u64) {
+        let offset = gpe - self.first;
+        ((offset / 8) as usize, 1 << (offset % 8))
+    }
+}
+
+fn with_gpe(gpe: u32, f: impl FnOnce(GenericAddress, GenericAddress, u64)) {
+    let blocks = BLOCKS.lock();
+
+    match blocks.iter().find(|b| b.contains(gpe)) {
+        Some(block) => {
+            let (index, bit) = block.locate(gpe);
+            f(block.status(index), block.enable(index), bit);
+        }
+        None => log::warn!("GPE {gpe:#x} is not in any block"),
+    }
+}
+
+pub fn enable(gpe: u32) {
+    with_gpe(gpe, |_, enable, bit| unsafe {
+        enable.write(enable.read() | bit)
+    });
+}
+
+pub fn disable(gpe: u32) {
+    with_gpe(gpe, |_, enable, bit| unsafe {
+        enable.write(enable.read() & !bit)
+    });
+}
+
+fn clear(gpe: u32) {
+    // Status bits are write-one-to-clear
+    with_gpe(gpe, |status, _, bit| unsafe { status.write(bit) });
+}
+
+/// Routes `gpe` to `handler` instead of its control method, then enables it
+pub fn install_handler(gpe: u32, handler: GpeHandler) {
+    HANDLERS.lock().insert(gpe, Handler::Driver(handler));
+    clear(gpe);
+    enable(gpe);
+}
+
+fn run(gpe: u32, handler: Handler) {
+    match handler {
+        Handler::Method { level } => {
+            let kind = if level { 'L' } else { 'E' };
+            let method = alloc::format!("\\_GPE._{kind}{gpe:02X}");
+
+            if let Err(e) = aml::evaluate(&method, Args::EMPTY) {
+                log::warn!("Failed to evaluate {method}: {e:?}");
+            }
+        }
+        Handler::Driver(handler) => handler(gpe),
+    }
+}
+
+/// Returns the enabled GPEs that are currently asserted
+fn pending() -> Vec<u32> {
+    let blocks = BLOCKS.lock();
+    let mut pending = Vec::new();
+
+    for block in blocks.iter() {
+        for index in 0..block.registers {
+            let active = unsafe { block.status(index).read() & block.enable(index).read() };
+
+            for bit in (0..8).filter(|bit| active & (1 << bit) != 0) {
+                pending.push(block.first + index as u32 * 8 + bit);
+            }
+        }
+    }
+
+    pending
+}
+
+/// Handles the asserted GPEs, called from the SCI handler
+pub fn dispatch() {
+    for gpe in pending() {
+        let handler = HANDLERS.lock().get(&gpe).copied();
+
+        match handler {
+            // Level GPEs stay asserted until the method dealt with the source
+            Some(handler @ Handler::Method { level: true }) => {
+                disable(gpe);
+                run(gpe, handler);
+                clear(gpe);
+                enable(gpe);
+            }
+            Some(handler) => {
+                clear(gpe);
+                run(gpe, handler);
+            }
+            None => {
+                log::warn!("Spurious GPE {gpe:#x}, disabling it");
+                disable(gpe);
+                clear(gpe);
+            }
+        }
+    }
+}
+
+/// Parses `_Lxx` and `_Exx` into the GPE they handle
+fn parse_method(name: &str) -> Option<(u32, Handler)> {
+    let level = match name.get(..2)? {
+        "_L" => true,
+        "_E" => false,
+        _ => return None,
+    };
+
+    let gpe = u32::from_str_radix(name.get(2..)?, 16).ok()?;
+    Some((gpe, Handler::Method { level }))
+}
+
+pub(super) fn init() {
+    let Some(fadt) = super::fadt() else {
+        return;
+    };
+
+    let mut blocks = Vec::new();
+    if let Some(gpe0) = fadt.gpe0() {
+        blocks.push(Block::new(gpe0, fadt.gpe0_blk_len, 0));
+    }
+    if let Some(gpe1) = fadt.gpe1() {
+        blocks.push(Block::new(gpe1, fadt.gpe1_blk_len, fadt.gpe1_base as u32));
+    }
+
+    // Start from a clean slate, only GPEs with a handler are going to be enabled
+    for block in &blocks {
+        for index in 0..block.registers {
+            unsafe {
+                block.enable(index).write(0);
+                block.status(index).write(0xFF);
+            }
+        }
+    }
+
+    let count: usize = blocks.iter().map(|b| b.registers * 8).sum();
+    *BLOCKS.lock() = blocks;
+
+    let methods: Vec<(u32, Handler)> = aml::children("\\_GPE")
+        .iter()
+        .filter_map(|name| parse_method(name))
+        .collect();
+
+    for &(gpe, handler) in &methods {
+        HANDLERS.lock().insert(gpe, handler);
+        enable(gpe);
+    }
+
+    log::info!("{count} GPEs, {} with a control method", methods.len());
+}
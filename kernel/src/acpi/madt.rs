@@ -0,0 +1,414 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::sdt::{AcpiTable, SdtHeader};
+use crate::sync::Once;
+use core::mem::size_of;
+
+static MADT: Once<&'static Madt> = Once::new();
+
+/// Bit 0 of the MADT flags, set when a dual 8259 setup is also present
+const PCAT_COMPAT: u32 = 1 << 0;
+
+/// The processor is ready to be used
+const LAPIC_ENABLED: u32 = 1 << 0;
+
+/// The processor is disabled but can be brought online by the OS
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Processor UID used by local APIC NMI entries that apply to every processor
+pub const ALL_PROCESSORS: u32 = u32::MAX;
+
+#[repr(C)]
+pub struct Madt {
+    hdr: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+    entries: [u8; 0],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// A processor, described either by a local APIC or a local x2APIC entry
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub flags: u32,
+}
+
+impl LocalApic {
+    pub fn enabled(&self) -> bool {
+        self.flags & LAPIC_ENABLED != 0
+    }
+
+    pub fn online_capable(&self) -> bool {
+        self.flags & LAPIC_ONLINE_CAPABLE != 0
+    }
+
+    pub fn usable(&self) -> bool {
+        self.enabled() || self.online_capable()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    /// `None` when it conforms to the bus, which depends on what's wired to the line
+    pub polarity: Option<Polarity>,
+    pub trigger: Option<TriggerMode>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NmiSource {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApicNmi {
+    /// [`ALL_PROCESSORS`] means the NMI is connected to every processor
+    pub processor_uid: u32,
+    pub lint: u8,
+    /// NMIs are always edge triggered, only the polarity matters
+    pub polarity: Polarity,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Entry {
+    LocalApic(LocalApic),
+    IoApic(IoApic),
+    InterruptSourceOverride(InterruptSourceOverride),
+    NmiSource(NmiSource),
+    LocalApicNmi(LocalApicNmi),
+    Unknown(u8),
+}
+
+#[repr(C, packed)]
+struct RawLocalApic {
+    processor_uid: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+struct RawIoApic {
+    id: u8,
+    _reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+
+#[repr(C, packed)]
+struct RawOverride {
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+#[repr(C, packed)]
+struct RawNmiSource {
+    flags: u16,
+    gsi: u32,
+}
+
+#[repr(C, packed)]
+struct RawLocalApicNmi {
+    processor_uid: u8,
+    flags: u16,
+    lint: u8,
+}
+
+#[repr(C, packed)]
+struct RawLocalX2Apic {
+    _reserved: u16,
+    x2apic_id: u32,
+    flags: u32,
+    processor_uid: u32,
+}
+
+#[repr(C, packed)]
+struct RawLocalX2ApicNmi {
+    flags: u16,
+    processor_uid: u32,
+    lint: u8,
+    _reserved: [u8; 3],
+}
+
+// The MPS INTI flags encode "conforms to the bus" as 0. For ISA interrupts that means active high
+// and edge triggered, but the SCI is active low and level triggered whatever bus it's on.
+fn conforming_polarity(flags: u16) -> Option<Polarity> {
+    match flags & 0b11 {
+        0b00 => None,
+        0b11 => Some(Polarity::ActiveLow),
+        _ => Some(Polarity::ActiveHigh),
+    }
+}
+
+fn conforming_trigger(flags: u16) -> Option<TriggerMode> {
+    match (flags >> 2) & 0b11 {
+        0b00 => None,
+        0b11 => Some(TriggerMode::Level),
+        _ => Some(TriggerMode::Edge),
+    }
+}
+
+/// For the NMI entries, which aren't on a bus that could say otherwise
+fn polarity(flags: u16) -> Polarity {
+    conforming_polarity(flags).unwrap_or(Polarity::ActiveHigh)
+}
+
+fn trigger(flags: u16) -> TriggerMode {
+    conforming_trigger(flags).unwrap_or(TriggerMode::Edge)
+}
+
+pub struct Entries {
+    data: &'static [u8],
+}
+
+impl Entries {
+    fn read<T>(data: &[u8]) -> Option<T> {
+        if data.len() < size_of::<T>() {
+            return None;
+        }
+
+        Some(unsafe { core::ptr::read_unaligned(data.as_ptr().cast()) })
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let &[typ, len, ..] = self.data else {
+            return None;
+        };
+        let len = len as usize;
+
+        if len < 2 || len > self.data.len() {
+            log::warn!("Malformed MADT entry of type {typ} (len {len})");
+            return None;
+        }
+
+        let body = &self.data[2..len];
+        self.data = &self.data[len..];
+
+        let entry = match typ {
+            0 => {
+                let raw: RawLocalApic = Self::read(body)?;
+                Entry::LocalApic(LocalApic {
+                    processor_uid: raw.processor_uid as u32,
+                    apic_id: raw.apic_id as u32,
+                    flags: raw.flags,
+                })
+            }
+            1 => {
+                let raw: RawIoApic = Self::read(body)?;
+                Entry::IoApic(IoApic {
+                    id: raw.id,
+                    address: raw.address,
+                    gsi_base: raw.gsi_base,
+                })
+            }
+            2 => {
+                let raw: RawOverride = Self::read(body)?;
+                Entry::InterruptSourceOverride(InterruptSourceOverride {
+                    bus: raw.bus,
+                    source: raw.source,
+                    gsi: raw.gsi,
+                    polarity: conforming_polarity(raw.flags),
+                    trigger: conforming_trigger(raw.flags),
+                })
+            }
+            3 => {
+                let raw: RawNmiSource = Self::read(body)?;
+                Entry::NmiSource(NmiSource {
+                    gsi: raw.gsi,
+                    polarity: polarity(raw.flags),
+                    trigger: trigger(raw.flags),
+                })
+            }
+            4 => {
+                let raw: RawLocalApicNmi = Self::read(body)?;
+                Entry::LocalApicNmi(LocalApicNmi {
+                    processor_uid: match raw.processor_uid {
+                        0xFF => ALL_PROCESSORS,
+                        uid => uid as u32,
+                    },
+                    lint: raw.lint,
+                    polarity: polarity(raw.flags),
+                })
+            }
+            9 => {
+                let raw: RawLocalX2Apic = Self::read(body)?;
+                Entry::LocalApic(LocalApic {
+                    processor_uid: raw.processor_uid,
+                    apic_id: raw.x2apic_id,
+                    flags: raw.flags,
+                })
+            }
+            0xA => {
+                let raw: RawLocalX2ApicNmi = Self::read(body)?;
+                Entry::LocalApicNmi(LocalApicNmi {
+                    processor_uid: raw.processor_uid,
+                    lint: raw.lint,
+                    polarity: polarity(raw.flags),
+                })
+            }
+            typ => Entry::Unknown(typ),
+        };
+
+        Some(entry)
+    }
+}
+
+impl Madt {
+    pub fn has_8259(&self) -> bool {
+        self.flags & PCAT_COMPAT != 0
+    }
+
+    pub fn entries(&self) -> Entries {
+        let len = self.hdr.data_len().saturating_sub(8);
+        let data = unsafe { core::slice::from_raw_parts(self.entries.as_ptr(), len) };
+
+        Entries { data }
+    }
+}
+
+unsafe impl AcpiTable for Madt {
+    const SIGNATURE: &'static str = "APIC";
+}
+
+pub(super) fn init() {
+    let Some(madt) = super::table::<Madt>() else {
+        log::warn!("No MADT found");
+        return;
+    };
+
+    for entry in madt.entries() {
+        match entry {
+            Entry::LocalApic(lapic) => log::debug!("{lapic:?}"),
+            Entry::InterruptSourceOverride(iso) => log::debug!("{iso:?}"),
+            Entry::NmiSource(nmi) => log::debug!("{nmi:?}"),
+            Entry::LocalApicNmi(nmi) => log::debug!("{nmi:?}"),
+            Entry::Unknown(typ) => log::debug!("Skipping MADT entry of type {typ}"),
+            _ => {}
+        }
+    }
+
+    MADT.call_once(|| madt);
+}
+
+pub fn get() -> Option<&'static Madt> {
+    MADT.get().copied()
+}
+
+fn entries() -> impl Iterator<Item = Entry> {
+    get().into_iter().flat_map(Madt::entries)
+}
+
+/// Returns every processor the firmware reports as either enabled or online capable
+pub fn cpus() -> impl Iterator<Item = LocalApic> {
+    entries().filter_map(|e| match e {
+        Entry::LocalApic(lapic) if lapic.usable() => Some(lapic),
+        _ => None,
+    })
+}
+
+pub fn io_apics() -> impl Iterator<Item = IoApic> {
+    entries().filter_map(|e| match e {
+        Entry::IoApic(io) => Some(io),
+        _ => None,
+    })
+}
+
+pub fn overrides() -> impl Iterator<Item = InterruptSourceOverride> {
+    entries().filter_map(|e| match e {
+        Entry::InterruptSourceOverride(iso) => Some(iso),
+        _ => None,
+    })
+}
+
+pub fn nmi_sources() -> impl Iterator<Item = NmiSource> {
+    entries().filter_map(|e| match e {
+        Entry::NmiSource(nmi) => Some(nmi),
+        _ => None,
+    })
+}
+
+/// Returns the local APIC NMI pins that apply to the processor with the given local APIC id
+pub fn local_apic_nmis(apic_id: u32) -> impl Iterator<Item = LocalApicNmi> {
+    let uid = cpus()
+        .find(|lapic| lapic.apic_id == apic_id)
+        .map(|lapic| lapic.processor_uid);
+
+    entries().filter_map(move |e| match e {
+        Entry::LocalApicNmi(nmi) if nmi.processor_uid == ALL_PROCESSORS => Some(nmi),
+        Entry::LocalApicNmi(nmi) if Some(nmi.processor_uid) == uid => Some(nmi),
+        _ => None,
+    })
+}
+
+/// Translates a legacy ISA IRQ into the GSI it is wired to, applying any override
+pub fn isa_irq(irq: u8) -> (u32, Polarity, TriggerMode) {
+    overrides()
+        .find(|iso| iso.bus == 0 && iso.source == irq)
+        .map(|iso| {
+            (
+                iso.gsi,
+                iso.polarity.unwrap_or(Polarity::ActiveHigh),
+                iso.trigger.unwrap_or(TriggerMode::Edge),
+            )
+        })
+        .unwrap_or((irq as u32, Polarity::ActiveHigh, TriggerMode::Edge))
+}
+
+/// The GSI the SCI is wired to, which unlike the ISA IRQs is active low and level triggered
+/// unless an override says otherwise
+pub fn sci_irq(sci: u16) -> (u32, Polarity, TriggerMode) {
+    overrides()
+        .find(|iso| iso.bus == 0 && iso.source as u16 == sci)
+        .map(|iso| {
+            (
+                iso.gsi,
+                iso.polarity.unwrap_or(Polarity::ActiveLow),
+                iso.trigger.unwrap_or(TriggerMode::Level),
+            )
+        })
+        .unwrap_or((sci as u32, Polarity::ActiveLow, TriggerMode::Level))
+}
@@ -0,0 +1,97 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::sdt::{AcpiTable, SdtHeader};
+use crate::mm::PhysAddr;
+use crate::sync::Once;
+use core::mem::size_of;
+
+static MCFG: Once<&'static Mcfg> = Once::new();
+
+#[repr(C)]
+pub struct Mcfg {
+    hdr: SdtHeader,
+    _reserved: [u8; 8],
+    entries: [u8; 0],
+}
+
+unsafe impl AcpiTable for Mcfg {
+    const SIGNATURE: &'static str = "MCFG";
+}
+
+/// An ECAM region covering the buses `start_bus..=end_bus` of a PCI segment
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct Allocation {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    _reserved: u32,
+}
+
+impl Allocation {
+    pub fn contains(&self, segment: u16, bus: u8) -> bool {
+        self.segment == segment && (self.start_bus..=self.end_bus).contains(&bus)
+    }
+
+    /// Physical address of the configuration space of a function in this region
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> PhysAddr {
+        let offset = ((bus - self.start_bus) as u64) << 20
+            | (device as u64 & 0x1F) << 15
+            | (function as u64 & 0x7) << 12;
+
+        PhysAddr::new(self.base_address + offset)
+    }
+}
+
+impl Mcfg {
+    pub fn allocations(&self) -> impl Iterator<Item = Allocation> + '_ {
+        let len = self.hdr.data_len().saturating_sub(8) / size_of::<Allocation>();
+        let entries = self.entries.as_ptr().cast::<Allocation>();
+
+        (0..len).map(move |i| unsafe { core::ptr::read_unaligned(entries.add(i)) })
+    }
+}
+
+pub(super) fn init() {
+    let Some(mcfg) = super::table::<Mcfg>() else {
+        log::debug!("No MCFG found, PCI Express configuration space is unavailable");
+        return;
+    };
+
+    for allocation in mcfg.allocations() {
+        log::debug!("{allocation:x?}");
+    }
+
+    MCFG.call_once(|| mcfg);
+}
+
+pub fn get() -> Option<&'static Mcfg> {
+    MCFG.get().copied()
+}
+
+pub fn allocations() -> impl Iterator<Item = Allocation> {
+    get().into_iter().flat_map(Mcfg::allocations)
+}
+
+/// Physical address of the configuration space of a function, if it's covered by an ECAM region
+pub fn config_address(segment: u16, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+    allocations()
+        .find(|a| a.contains(segment, bus))
+        .map(|a| a.config_address(bus, device, function))
+}
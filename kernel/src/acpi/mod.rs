@@ -16,58 +16,144 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::mm::PhysAddr;
-use crate::hpet;
+use crate::error::KError;
+use crate::sync::Once;
+use core::mem::size_of;
 use limine::LimineRsdpRequest;
 use rsdp::Rsdp;
-use sdt::{SdtHeader, Xsdt};
-use spin::Mutex;
+use sdt::{Rsdt, SdtHeader, Tables, Xsdt};
 
+pub mod aml;
+pub mod battery;
+pub mod ec;
+pub mod events;
+pub mod fadt;
+pub mod gpe;
+pub mod madt;
+pub mod mcfg;
 mod rsdp;
 pub mod sdt;
 
+pub use sdt::AcpiTable;
+
 static RSDP_REQ: LimineRsdpRequest = LimineRsdpRequest::new(0);
-static XSDT: Mutex<Option<&'static Xsdt>> = Mutex::new(None);
+static ROOT_TABLE: Once<RootTable> = Once::new();
+
+#[derive(Clone, Copy)]
+enum RootTable {
+    Rsdt(&'static Rsdt),
+    Xsdt(&'static Xsdt),
+}
+
+impl RootTable {
+    fn tables(self) -> Tables {
+        match self {
+            RootTable::Rsdt(rsdt) => rsdt.tables(),
+            RootTable::Xsdt(xsdt) => xsdt.tables(),
+        }
+    }
+}
+
+fn root_table(rsdp: &Rsdp) -> Result<RootTable, KError> {
+    if rsdp.has_xsdt() {
+        let xsdt = unsafe { rsdp.get_xsdt() };
+
+        if xsdt.header().checksum_valid() {
+            return Ok(RootTable::Xsdt(xsdt));
+        }
+
+        log::warn!("XSDT checksum mismatch, falling back to the RSDT");
+    }
+
+    let rsdt = unsafe { rsdp.get_rsdt() };
+    if !rsdt.header().checksum_valid() {
+        return Err(KError::Invalid("RSDT checksum"));
+    }
+
+    Ok(RootTable::Rsdt(rsdt))
+}
+
+pub fn fadt() -> Option<&'static fadt::Fadt> {
+    fadt::get()
+}
+
+fn valid(table: *const SdtHeader) -> bool {
+    unsafe { &*table }.checksum_valid()
+}
 
-pub fn init() {
-    let rsdp = RSDP_REQ.get_response().get().unwrap();
-    let rsdp: *const Rsdp = rsdp.address.as_ptr().unwrap().cast();
+pub fn init() -> Result<(), KError> {
+    let rsdp = RSDP_REQ
+        .get_response()
+        .get()
+        .and_then(|rsdp| rsdp.address.as_ptr())
+        .ok_or(KError::NotFound("RSDP"))?;
+    let rsdp: *const Rsdp = rsdp.cast();
     let rsdp = unsafe { Rsdp::from_ptr(rsdp) };
-    assert!(rsdp.revision() >= 2);
 
-    let xsdt = unsafe { rsdp.get_xsdt() };
-    *XSDT.lock() = Some(xsdt);
+    if !rsdp.valid() {
+        return Err(KError::Invalid("RSDP checksum"));
+    }
+
+    log::info!("ACPI revision {}", rsdp.revision());
 
-    for &table in xsdt.tables() {
+    let root = root_table(&rsdp)?;
+    ROOT_TABLE.call_once(|| root);
+
+    for table in root.tables() {
         let signature = unsafe { &*table }.signature();
-        log::info!("Table @ {table:#p} {signature}");
 
-        if signature == "HPET" {
-            hpet::init(table);
+        if !valid(table) {
+            log::warn!("Skipping table @ {table:#p} {signature}: bad checksum");
+            continue;
         }
+
+        log::info!("Table @ {table:#p} {signature}");
     }
+
+    fadt::init();
+    madt::init();
+    mcfg::init();
+    aml::init();
+    ec::init();
+    battery::init();
+
+    Ok(())
 }
 
-pub fn get_table(signature: &str, index: usize) -> Option<*const SdtHeader> {
-    if signature == "DSDT" {
-        #[repr(C, packed)]
-        struct Fadt {
-            firmware_ctrl: u32,
-            dsdt: u32,
-        }
+initcall!(acpi, init, [], Fatal);
 
-        let fadt = get_table("FACP", 0)?;
-        let fadt: Fadt = unsafe { core::ptr::read_unaligned((*fadt).data().cast()) };
-        return Some(PhysAddr::new(fadt.dsdt as u64).as_hhdm().as_ptr()) 
+/// Returns the `index`th table with a valid checksum and the given signature
+pub fn get_table(signature: &str, index: usize) -> Option<&'static SdtHeader> {
+    if signature == "DSDT" {
+        let dsdt = fadt()?.dsdt_address().as_hhdm().as_ptr();
+        return valid(dsdt).then(|| unsafe { &*dsdt });
     }
 
-    let xsdt = XSDT.lock();
-    let xsdt = xsdt.as_ref().unwrap();
+    let root = *ROOT_TABLE.get()?;
 
-    xsdt
-        .tables()
-        .iter()
-        .filter(|&&p| unsafe { &*p }.signature() == signature)
+    root.tables()
+        .filter(|&p| unsafe { &*p }.signature() == signature && valid(p))
         .nth(index)
-        .copied()
+        .map(|p| unsafe { &*p })
+}
+
+/// Returns the `index`th table of type `T`, as long as it's big enough to hold one
+pub fn table_at<T: AcpiTable>(index: usize) -> Option<&'static T> {
+    let hdr = get_table(T::SIGNATURE, index)?;
+
+    if hdr.len() < size_of::<T>() {
+        log::warn!(
+            "{} is too short: {} bytes, expected at least {}",
+            T::SIGNATURE,
+            hdr.len(),
+            size_of::<T>()
+        );
+        return None;
+    }
+
+    Some(unsafe { &*(hdr as *const SdtHeader).cast() })
+}
+
+pub fn table<T: AcpiTable>() -> Option<&'static T> {
+    table_at(0)
 }
@@ -15,8 +15,12 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use super::sdt::Xsdt;
+use super::sdt::{Rsdt, Xsdt};
 use crate::mm::PhysAddr;
+use core::mem::size_of;
+
+/// Size of the ACPI 1.0 part of the RSDP, covered by the first checksum
+const RSDP_V1_LEN: usize = 20;
 
 #[repr(C)]
 pub struct Rsdp {
@@ -41,6 +45,32 @@ impl Rsdp {
         self.revision
     }
 
+    fn checksum(&self, len: usize) -> u8 {
+        let len = core::cmp::min(len, size_of::<Self>());
+        let bytes = unsafe { core::slice::from_raw_parts((self as *const Rsdp).cast::<u8>(), len) };
+
+        bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+
+    /// Checks the signature and the checksums, the extended one only for ACPI 2.0+
+    pub fn valid(&self) -> bool {
+        if &self.signature != b"RSD PTR " || self.checksum(RSDP_V1_LEN) != 0 {
+            return false;
+        }
+
+        self.revision < 2 || self.checksum(self.lenght as usize) == 0
+    }
+
+    #[inline]
+    pub fn has_xsdt(&self) -> bool {
+        self.revision >= 2 && !self.xsdt_address.is_null()
+    }
+
+    #[inline]
+    pub unsafe fn get_rsdt(&self) -> &'static Rsdt {
+        Rsdt::from_phys(PhysAddr::new(self.rsdt_address as u64))
+    }
+
     #[inline]
     pub unsafe fn get_xsdt(&self) -> &'static Xsdt {
         Xsdt::from_phys(PhysAddr::new(self.xsdt_address as u64))
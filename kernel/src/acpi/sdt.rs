@@ -15,10 +15,12 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
+use crate::cpu;
 use crate::mm::PhysAddr;
 use core::mem::size_of;
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SdtHeader {
     signature: [u8; 4],
     lenght: u32,
@@ -36,13 +38,99 @@ impl SdtHeader {
         unsafe { core::str::from_utf8_unchecked(&self.signature) }
     }
 
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    pub fn len(&self) -> usize {
+        self.lenght as usize
+    }
+
     pub fn data_len(&self) -> usize {
-        (self.lenght as usize) - size_of::<Self>()
+        (self.lenght as usize).saturating_sub(size_of::<Self>())
     }
 
     pub fn data(&self) -> *const u8 {
         unsafe { (self as *const SdtHeader).add(1).cast() }
     }
+
+    /// Checks that all the bytes of the table, header included, sum up to zero
+    pub fn checksum_valid(&self) -> bool {
+        if self.len() < size_of::<Self>() {
+            return false;
+        }
+
+        let bytes =
+            unsafe { core::slice::from_raw_parts((self as *const Self).cast::<u8>(), self.len()) };
+        bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+    }
+}
+
+/// A fixed layout table that can be looked up with [`super::table`]
+///
+/// # Safety
+/// Implementors must be `#[repr(C)]` or `#[repr(C, packed)]`, start with a [`SdtHeader`]
+/// and be valid for any bit pattern.
+pub unsafe trait AcpiTable: Sized + 'static {
+    const SIGNATURE: &'static str;
+}
+
+/// Iterator over the table pointers of a RSDT or XSDT
+pub struct Tables {
+    entries: *const u8,
+    entry_size: usize,
+    remaining: usize,
+}
+
+impl Iterator for Tables {
+    type Item = *const SdtHeader;
+
+    fn next(&mut self) -> Option<*const SdtHeader> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        // The entries are only guaranteed to be 4 byte aligned
+        let phys = unsafe {
+            match self.entry_size {
+                4 => core::ptr::read_unaligned(self.entries.cast::<u32>()) as u64,
+                _ => core::ptr::read_unaligned(self.entries.cast::<u64>()),
+            }
+        };
+
+        self.entries = unsafe { self.entries.add(self.entry_size) };
+        self.remaining -= 1;
+
+        Some(PhysAddr::new(phys).as_hhdm().as_ptr())
+    }
+}
+
+#[repr(C)]
+pub struct Rsdt {
+    hdr: SdtHeader,
+    tables: [u32; 0],
+}
+
+impl Rsdt {
+    pub unsafe fn from_phys<'a>(ptr: PhysAddr) -> &'a Rsdt {
+        &*ptr.as_hhdm().as_ptr()
+    }
+
+    pub fn header(&self) -> &SdtHeader {
+        &self.hdr
+    }
+
+    pub fn len(&self) -> usize {
+        self.hdr.data_len() / 4
+    }
+
+    pub fn tables(&self) -> Tables {
+        Tables {
+            entries: self.hdr.data(),
+            entry_size: 4,
+            remaining: self.len(),
+        }
+    }
 }
 
 #[repr(C)]
@@ -56,11 +144,113 @@ impl Xsdt {
         &*ptr.as_hhdm().as_ptr()
     }
 
+    pub fn header(&self) -> &SdtHeader {
+        &self.hdr
+    }
+
     pub fn len(&self) -> usize {
         self.hdr.data_len() / 8
     }
 
-    pub fn tables(&self) -> &[*const SdtHeader] {
-        unsafe { core::slice::from_raw_parts(self.hdr.data().cast(), self.len()) }
+    pub fn tables(&self) -> Tables {
+        Tables {
+            entries: self.hdr.data(),
+            entry_size: 8,
+            remaining: self.len(),
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+    pub const PCI_CONFIG: u8 = 2;
+    pub const FUNCTIONAL_FIXED_HW: u8 = 0x7F;
+
+    pub fn is_null(&self) -> bool {
+        self.address == 0
+    }
+
+    /// Reads `bit_width` bits, only byte sized registers are supported for PCI
+    pub unsafe fn read(&self) -> u64 {
+        let address = self.address;
+
+        match (self.address_space, self.bit_width) {
+            (Self::SYSTEM_MEMORY, 8) => {
+                core::ptr::read_volatile(PhysAddr::new(address).as_hhdm().as_ptr::<u8>()) as u64
+            }
+            (Self::SYSTEM_MEMORY, 16) => {
+                core::ptr::read_volatile(PhysAddr::new(address).as_hhdm().as_ptr::<u16>()) as u64
+            }
+            (Self::SYSTEM_MEMORY, 32) => {
+                core::ptr::read_volatile(PhysAddr::new(address).as_hhdm().as_ptr::<u32>()) as u64
+            }
+            (Self::SYSTEM_MEMORY, 64) => {
+                core::ptr::read_volatile(PhysAddr::new(address).as_hhdm().as_ptr::<u64>())
+            }
+            (Self::SYSTEM_IO, 8) => cpu::inb(address as u16) as u64,
+            (Self::SYSTEM_IO, 16) => cpu::inw(address as u16) as u64,
+            (Self::SYSTEM_IO, 32) => cpu::inl(address as u16) as u64,
+            (Self::PCI_CONFIG, 8) => {
+                cpu::outl(0xCF8, Self::pci_config_address(address));
+                cpu::inb(0xCFC + (address & 3) as u16) as u64
+            }
+            (space, width) => {
+                log::warn!("Unsupported generic address read (space {space}, {width} bits)");
+                0
+            }
+        }
+    }
+
+    // Device in bits 32..48, function in bits 16..32 and the offset in the low 16 bits
+    fn pci_config_address(address: u64) -> u32 {
+        let device = (address >> 32) & 0x1F;
+        let function = (address >> 16) & 0x7;
+        let offset = address & 0xFF;
+
+        ((1 << 31) | (device << 11) | (function << 8) | (offset & 0xFC)) as u32
+    }
+
+    /// Writes the low `bit_width` bits of `value`, only byte sized registers are supported for PCI
+    pub unsafe fn write(&self, value: u64) {
+        let address = self.address;
+
+        match (self.address_space, self.bit_width) {
+            (Self::SYSTEM_MEMORY, 8) => core::ptr::write_volatile(
+                PhysAddr::new(address).as_hhdm().as_mut_ptr(),
+                value as u8,
+            ),
+            (Self::SYSTEM_MEMORY, 16) => core::ptr::write_volatile(
+                PhysAddr::new(address).as_hhdm().as_mut_ptr(),
+                value as u16,
+            ),
+            (Self::SYSTEM_MEMORY, 32) => core::ptr::write_volatile(
+                PhysAddr::new(address).as_hhdm().as_mut_ptr(),
+                value as u32,
+            ),
+            (Self::SYSTEM_MEMORY, 64) => {
+                core::ptr::write_volatile(PhysAddr::new(address).as_hhdm().as_mut_ptr(), value)
+            }
+            (Self::SYSTEM_IO, 8) => cpu::outb(address as u16, value as u8),
+            (Self::SYSTEM_IO, 16) => cpu::outw(address as u16, value as u16),
+            (Self::SYSTEM_IO, 32) => cpu::outl(address as u16, value as u32),
+            (Self::PCI_CONFIG, 8) => {
+                cpu::outl(0xCF8, Self::pci_config_address(address));
+                cpu::outb(0xCFC + (address & 3) as u16, value as u8);
+            }
+            (space, width) => {
+                log::warn!("Unsupported generic address write (space {space}, {width} bits)")
+            }
+        }
     }
 }
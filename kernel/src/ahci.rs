@@ -0,0 +1,655 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::apic;
+use crate::block::{self, BlockDevice, Error, SECTOR_SIZE};
+use crate::driver::{self, Driver, Match, ProbeError};
+use crate::hpet;
+use crate::interrupts::{self, InterruptStack};
+use crate::mm::dma::Dma;
+use crate::mm::mmio::Mmio;
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The ABAR, where the HBA registers live
+const ABAR: u8 = 5;
+
+/// Generic host control registers
+const CAP: usize = 0x00;
+const GHC: usize = 0x04;
+const IS: usize = 0x08;
+const PI: usize = 0x0C;
+const VS: usize = 0x10;
+
+const CAP_S64A: u32 = 1 << 31;
+const GHC_AE: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+
+/// Port registers, relative to the port's own block
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_FB: usize = 0x08;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const PX_CMD_ST: u32 = 1 << 0;
+const PX_CMD_FRE: u32 = 1 << 4;
+const PX_CMD_FR: u32 = 1 << 14;
+const PX_CMD_CR: u32 = 1 << 15;
+
+/// Task file error and device fault, busy and data request
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_DF: u32 = 1 << 5;
+const TFD_BSY: u32 = 1 << 7;
+
+/// Register, PIO setup, DMA setup and set device bits FIS interrupts, plus task file errors
+const PX_IE_DEFAULT: u32 = 0b1111 | 1 << 30;
+
+const SSTS_DET_PRESENT: u32 = 3;
+const SSTS_IPM_ACTIVE: u32 = 1;
+
+const SIG_ATA: u32 = 0x0000_0101;
+const SIG_ATAPI: u32 = 0xEB14_0101;
+
+/// ATA commands
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_PACKET: u8 = 0xA0;
+const ATA_IDENTIFY_PACKET: u8 = 0xA1;
+
+/// SCSI commands sent to ATAPI devices
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+/// CD-ROM sectors, the only size ATAPI drives use
+const ATAPI_SECTOR_SIZE: usize = 2048;
+
+/// The first command after a reset or a media change reports a unit attention
+const ATAPI_RETRIES: usize = 3;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_COMMAND: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+/// PACKET feature flag: the data phase uses DMA
+const FEATURE_DMA: u8 = 1 << 0;
+
+/// Command header flag: the command table holds a SCSI command
+const HEADER_ATAPI: u32 = 1 << 5;
+/// Where the SCSI command goes in the command table
+const ACMD: usize = 0x40;
+
+/// Sizes of the per-port DMA structures, only command slot 0 is ever used
+const COMMAND_LIST_SIZE: usize = 32 * 32;
+const RECEIVED_FIS_SIZE: usize = 256;
+const COMMAND_TABLE_SIZE: usize = 0x80 + 16;
+const PRDT: usize = 0x80;
+
+/// Largest transfer in a single command
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// How long a command may take before the port is considered hung
+const TIMEOUT_US: u64 = 5_000_000;
+
+static HBAS: Mutex<Vec<Hba>> = Mutex::new(Vec::new());
+static VECTOR: Mutex<Option<u8>> = Mutex::new(None);
+
+static DRIVER: Driver = Driver {
+    name: "ahci",
+    order: 20,
+    matches: &[Match::PciClass {
+        class: 0x01,
+        subclass: 0x06,
+        prog_if: Some(0x01),
+    }],
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+struct Hba {
+    device: crate::pci::Device,
+    mmio: Arc<Mmio>,
+    ports: Vec<Arc<Port>>,
+}
+
+struct PortMemory {
+    command_list: Dma,
+    received_fis: Dma,
+    command_table: Dma,
+}
+
+pub struct Port {
+    name: String,
+    model: String,
+    mmio: Arc<Mmio>,
+    index: usize,
+    sectors: u64,
+    sector_size: usize,
+    /// An optical drive, talked to with SCSI commands wrapped in PACKET
+    atapi: bool,
+    /// The HBA can only address 32 bits of physical memory
+    dma_32bit: bool,
+    memory: Mutex<PortMemory>,
+    /// Completions are signalled through MSI
+    msi: AtomicBool,
+    /// Set by the interrupt handler when the port raised anything
+    interrupted: AtomicBool,
+}
+
+impl Port {
+    fn read(&self, register: usize) -> u32 {
+        self.mmio
+            .read(PORT_BASE + self.index * PORT_SIZE + register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.mmio
+            .write(PORT_BASE + self.index * PORT_SIZE + register, value)
+    }
+
+    /// Waits for `done`, giving up after `TIMEOUT_US`
+    fn wait(&self, mut done: impl FnMut() -> bool) -> Result<(), Error> {
+        for _ in 0..TIMEOUT_US / 10 {
+            if done() {
+                return Ok(());
+            }
+
+            hpet::sleep(10_000);
+        }
+
+        Err(Error::Io)
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        self.write(PX_CMD, self.read(PX_CMD) & !PX_CMD_ST);
+        self.wait(|| self.read(PX_CMD) & PX_CMD_CR == 0)?;
+
+        self.write(PX_CMD, self.read(PX_CMD) & !PX_CMD_FRE);
+        self.wait(|| self.read(PX_CMD) & PX_CMD_FR == 0)
+    }
+
+    fn start(&self) -> Result<(), Error> {
+        let memory = self.memory.lock();
+        let command_list = memory.command_list.phys().as_u64();
+        let received_fis = memory.received_fis.phys().as_u64();
+        drop(memory);
+
+        self.write(PX_CLB, command_list as u32);
+        self.write(PX_CLB + 4, (command_list >> 32) as u32);
+        self.write(PX_FB, received_fis as u32);
+        self.write(PX_FB + 4, (received_fis >> 32) as u32);
+
+        self.write(PX_SERR, !0);
+        self.write(PX_IS, !0);
+        self.write(PX_CMD, self.read(PX_CMD) | PX_CMD_FRE);
+
+        self.wait(|| self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+        self.write(PX_CMD, self.read(PX_CMD) | PX_CMD_ST);
+        self.write(PX_IE, PX_IE_DEFAULT);
+
+        Ok(())
+    }
+
+    /// Runs an ATA command in slot 0, `data` being the buffer and length to transfer
+    fn issue(
+        &self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        data: Option<(&Dma, usize)>,
+        write: bool,
+    ) -> Result<(), Error> {
+        self.execute(command, lba, count, None, data, write)
+    }
+
+    /// Sends `cdb` to an ATAPI device, the data comes in by DMA
+    fn packet(&self, cdb: [u8; 12], data: Option<(&Dma, usize)>) -> Result<(), Error> {
+        // The byte count limit goes where the LBA would, it only matters for PIO
+        let limit = data.map_or(0, |(_, len)| len.min(0xFFFF) as u64);
+        self.execute(ATA_PACKET, limit << 8, 0, Some(cdb), data, false)
+    }
+
+    fn execute(
+        &self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        cdb: Option<[u8; 12]>,
+        data: Option<(&Dma, usize)>,
+        write: bool,
+    ) -> Result<(), Error> {
+        let memory = self.memory.lock();
+
+        let table = &memory.command_table;
+        let header = &memory.command_list;
+
+        if self.dma_32bit
+            && [table.phys(), data.map_or(table.phys(), |(d, _)| d.phys())]
+                .iter()
+                .any(|addr| addr.as_u64() >= 1 << 32)
+        {
+            return Err(Error::Io);
+        }
+
+        // Host to device register FIS
+        let fis = [
+            FIS_TYPE_REG_H2D,
+            FIS_COMMAND,
+            command,
+            if cdb.is_some() { FEATURE_DMA } else { 0 },
+            lba as u8,
+            (lba >> 8) as u8,
+            (lba >> 16) as u8,
+            DEVICE_LBA,
+            (lba >> 24) as u8,
+            (lba >> 32) as u8,
+            (lba >> 40) as u8,
+            0,
+            count as u8,
+            (count >> 8) as u8,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+
+        for (i, byte) in fis.iter().enumerate() {
+            table.write(i, *byte);
+        }
+
+        if let Some(cdb) = cdb {
+            for (i, byte) in cdb.iter().enumerate() {
+                table.write(ACMD + i, *byte);
+            }
+        }
+
+        let prdt_len = match data {
+            Some((buffer, len)) => {
+                table.write(PRDT, buffer.phys().as_u64());
+                table.write(PRDT + 8, 0u32);
+                table.write(PRDT + 12, (len - 1) as u32);
+                1u32
+            }
+            None => 0,
+        };
+
+        // FIS length in dwords, the write flag and how many PRDT entries follow
+        let flags = (fis.len() / 4) as u32
+            | if cdb.is_some() { HEADER_ATAPI } else { 0 }
+            | (write as u32) << 6
+            | prdt_len << 16;
+        header.write(0, flags);
+        header.write(4, 0u32);
+        header.write(8, table.phys().as_u64());
+
+        self.interrupted.store(false, Ordering::Relaxed);
+        self.write(PX_IS, !0);
+        self.write(PX_CI, 1);
+
+        // With MSI the registers are left alone until the port interrupted
+        let result = self.wait(|| {
+            if self.msi.load(Ordering::Relaxed) && !self.interrupted.load(Ordering::Acquire) {
+                return false;
+            }
+
+            self.read(PX_CI) & 1 == 0 || self.read(PX_TFD) & TFD_ERR != 0
+        });
+
+        if result.is_err() || self.read(PX_TFD) & (TFD_ERR | TFD_DF) != 0 {
+            log::warn!(
+                "{}: command {command:#x} failed, TFD {:#x}",
+                self.name,
+                self.read(PX_TFD)
+            );
+
+            // Restarting the port clears the error state
+            drop(memory);
+            let _ = self.stop().and_then(|_| self.start());
+            return Err(Error::Io);
+        }
+
+        Ok(())
+    }
+
+    fn read_atapi(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        for (i, chunk) in buffer.chunks_mut(MAX_TRANSFER).enumerate() {
+            let bounce = Dma::new(chunk.len());
+            let lba = sector as u32 + (i * MAX_TRANSFER / ATAPI_SECTOR_SIZE) as u32;
+            let count = (chunk.len() / ATAPI_SECTOR_SIZE) as u16;
+
+            let mut cdb = [0; 12];
+            cdb[0] = SCSI_READ_10;
+            cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+            cdb[7..9].copy_from_slice(&count.to_be_bytes());
+
+            self.packet(cdb, Some((&bounce, chunk.len())))?;
+            chunk.copy_from_slice(&bounce.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    /// Asks the drive for the size of the disc in it, `None` if it's empty
+    fn read_capacity(&self) -> Option<(u64, usize)> {
+        let data = Dma::new(8);
+        let mut cdb = [0; 12];
+        cdb[0] = SCSI_READ_CAPACITY_10;
+
+        (0..ATAPI_RETRIES).find_map(|_| self.packet(cdb, Some((&data, 8))).ok())?;
+
+        let last = u32::from_be_bytes(data.read::<[u8; 4]>(0)) as u64;
+        let block_size = u32::from_be_bytes(data.read::<[u8; 4]>(4)) as usize;
+        Some((last + 1, block_size))
+    }
+}
+
+impl BlockDevice for Port {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read_only(&self) -> bool {
+        self.atapi
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self, sector, buffer.len())?;
+
+        if self.atapi {
+            return self.read_atapi(sector, buffer);
+        }
+
+        for (i, chunk) in buffer.chunks_mut(MAX_TRANSFER).enumerate() {
+            let bounce = Dma::new(chunk.len());
+            let lba = sector + (i * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            let count = (chunk.len() / SECTOR_SIZE) as u16;
+
+            self.issue(
+                ATA_READ_DMA_EXT,
+                lba,
+                count,
+                Some((&bounce, chunk.len())),
+                false,
+            )?;
+            chunk.copy_from_slice(&bounce.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        if self.atapi {
+            return Err(Error::ReadOnly);
+        }
+
+        block::check(self, sector, buffer.len())?;
+
+        for (i, chunk) in buffer.chunks(MAX_TRANSFER).enumerate() {
+            let mut bounce = Dma::new(chunk.len());
+            let lba = sector + (i * MAX_TRANSFER / SECTOR_SIZE) as u64;
+            let count = (chunk.len() / SECTOR_SIZE) as u16;
+
+            bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.issue(
+                ATA_WRITE_DMA_EXT,
+                lba,
+                count,
+                Some((&bounce, chunk.len())),
+                true,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        if self.atapi {
+            return Ok(());
+        }
+
+        self.issue(ATA_FLUSH_CACHE_EXT, 0, 0, None, false)
+    }
+}
+
+/// Reads an ATA string, which has the bytes of every word swapped
+fn ata_string(identify: &[u8]) -> String {
+    let bytes: Vec<u8> = identify
+        .chunks(2)
+        .flat_map(|word| [word[1], word[0]])
+        .collect();
+
+    String::from_utf8_lossy(&bytes).trim().into()
+}
+
+fn interrupt(_stack: &mut InterruptStack) {
+    if let Some(hbas) = HBAS.try_lock() {
+        for hba in hbas.iter() {
+            let pending = hba.mmio.read::<u32>(IS);
+
+            for port in hba.ports.iter().filter(|p| pending & (1 << p.index) != 0) {
+                port.write(PX_IS, port.read(PX_IS));
+                port.interrupted.store(true, Ordering::Release);
+            }
+
+            hba.mmio.write(IS, pending);
+        }
+    }
+
+    core!().apic.lock().eoi();
+}
+
+/// Routes the HBA's MSI to the shared AHCI vector, returns whether it worked
+fn enable_msi(device: &crate::pci::Device) -> bool {
+    let Some(msi) = device.msi() else {
+        return false;
+    };
+
+    let mut vector = VECTOR.lock();
+    if vector.is_none() {
+        *vector = interrupts::allocate_handler(interrupt);
+    }
+
+    let Some(vector) = *vector else {
+        return false;
+    };
+
+    let apic_id = core!().apic.lock().id();
+    msi.enable(device, apic::msi_address(apic_id), vector as u16);
+    true
+}
+
+fn probe_port(mmio: &Arc<Mmio>, index: usize, dma_32bit: bool) -> Option<Port> {
+    let mut port = Port {
+        name: String::new(),
+        model: String::new(),
+        mmio: mmio.clone(),
+        index,
+        sectors: 0,
+        sector_size: SECTOR_SIZE,
+        atapi: false,
+        dma_32bit,
+        memory: Mutex::new(PortMemory {
+            command_list: Dma::new(COMMAND_LIST_SIZE),
+            received_fis: Dma::new(RECEIVED_FIS_SIZE),
+            command_table: Dma::new(COMMAND_TABLE_SIZE),
+        }),
+        msi: AtomicBool::new(false),
+        interrupted: AtomicBool::new(false),
+    };
+
+    let status = port.read(PX_SSTS);
+    if status & 0xF != SSTS_DET_PRESENT || (status >> 8) & 0xF != SSTS_IPM_ACTIVE {
+        return None;
+    }
+
+    match port.read(PX_SIG) {
+        SIG_ATA => {}
+        SIG_ATAPI => port.atapi = true,
+        signature => {
+            log::debug!("ahci: port {index} has unknown signature {signature:#x}");
+            return None;
+        }
+    }
+
+    if port.stop().and_then(|_| port.start()).is_err() {
+        log::warn!("ahci: port {index} doesn't start");
+        return None;
+    }
+
+    let identify = Dma::new(SECTOR_SIZE);
+    let command = match port.atapi {
+        true => ATA_IDENTIFY_PACKET,
+        false => ATA_IDENTIFY,
+    };
+    port.issue(command, 0, 0, Some((&identify, SECTOR_SIZE)), false)
+        .ok()?;
+
+    let words = identify.as_slice();
+    port.model = ata_string(&words[54..94]);
+
+    if port.atapi {
+        let Some((sectors, sector_size)) = port.read_capacity() else {
+            log::info!("ahci: port {index} is an empty optical drive");
+            return None;
+        };
+
+        if sector_size != ATAPI_SECTOR_SIZE {
+            log::warn!("ahci: port {index} has {sector_size} byte sectors");
+            return None;
+        }
+
+        port.sectors = sectors;
+        port.sector_size = sector_size;
+        port.name = block::next_name("sr");
+        return Some(port);
+    }
+
+    let word = |i: usize| u16::from_le_bytes([words[i * 2], words[i * 2 + 1]]) as u64;
+
+    // LBA48 sector count, or the LBA28 one for older drives
+    port.sectors = if word(83) & (1 << 10) != 0 {
+        word(100) | word(101) << 16 | word(102) << 32 | word(103) << 48
+    } else {
+        word(60) | word(61) << 16
+    };
+
+    port.name = block::next_name("sd");
+
+    Some(port)
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+    let mmio = Arc::new(
+        pci.map_bar(ABAR)
+            .ok_or(ProbeError::Failed("cannot map the ABAR"))?,
+    );
+    pci.enable_bus_mastering();
+
+    mmio.write(GHC, mmio.read::<u32>(GHC) | GHC_AE);
+
+    let capabilities = mmio.read::<u32>(CAP);
+    let version = mmio.read::<u32>(VS);
+    let implemented = mmio.read::<u32>(PI);
+
+    log::info!(
+        "ahci: version {}.{}, ports {implemented:#x}",
+        version >> 16,
+        version & 0xFFFF
+    );
+
+    let dma_32bit = capabilities & CAP_S64A == 0;
+    let ports: Vec<Arc<Port>> = (0..32)
+        .filter(|i| implemented & (1 << i) != 0)
+        .filter_map(|i| probe_port(&mmio, i, dma_32bit))
+        .map(Arc::new)
+        .collect();
+
+    if enable_msi(pci) {
+        mmio.write(IS, !0);
+        mmio.write(GHC, mmio.read::<u32>(GHC) | GHC_IE);
+
+        for port in &ports {
+            port.msi.store(true, Ordering::Relaxed);
+        }
+    } else {
+        log::warn!("{}: no MSI, completions are polled", pci.address);
+    }
+
+    for port in &ports {
+        log::info!(
+            "ahci: port {} is {} ({})",
+            port.index,
+            port.name,
+            port.model
+        );
+        block::register(port.clone());
+    }
+
+    HBAS.lock().push(Hba {
+        device: *pci,
+        mmio,
+        ports,
+    });
+
+    Ok(())
+}
+
+fn remove(device: &driver::Device) {
+    let Some(pci) = device.as_pci() else {
+        return;
+    };
+
+    let mut hbas = HBAS.lock();
+    let Some(i) = hbas.iter().position(|h| h.device.address == pci.address) else {
+        return;
+    };
+
+    let hba = hbas.remove(i);
+    drop(hbas);
+
+    hba.mmio.write(GHC, hba.mmio.read::<u32>(GHC) & !GHC_IE);
+    for port in &hba.ports {
+        let _ = port.flush();
+        let _ = port.stop();
+        block::unregister(&port.name);
+    }
+}
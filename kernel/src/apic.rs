@@ -16,9 +16,10 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::acpi::madt::{self, Polarity};
 use crate::cpu;
 use crate::hpet;
-use crate::mm::{PhysAddr, VirtAddr};
+use crate::registers::ReadWrite;
 
 /// The x2apic enable bit in the `IA32_APIC_BASE` MSR
 const IA32_APIC_BASE_EXTD: u64 = 1 << 10;
@@ -29,17 +30,49 @@ const IA32_APIC_BASE_EN: u64 = 1 << 11;
 /// MSR for the IA32_APIC_BASE
 const IA32_APIC_BASE: u32 = 0x1b;
 
+/// ICR destination shorthand targeting every core but the sender
+pub const ICR_SELF: u32 = 0b01 << 18;
+pub const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+/// ICR delivery status, set while the xAPIC is still sending the last IPI
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// LVT delivery mode NMI, the vector is ignored
+const DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// LVT timer mode bits
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+
 /// Physical address we want the local APIC to be mapped at
 const APIC_BASE: u64 = 0xfee0_0000;
 
+/// Enables the APIC of the BSP, the other cores do theirs as they come up
+pub fn init() {
+    core!().apic.lock().enable();
+}
+
+initcall!(apic, init, [acpi, tsc]);
+
+/// Address a device writes an MSI to in order to interrupt the core with APIC id `apic_id`
+///
+/// Without interrupt remapping only the low 8 bits of the id can be encoded
+pub fn msi_address(apic_id: u32) -> u64 {
+    APIC_BASE | ((apic_id as u64 & 0xFF) << 12)
+}
+
 #[derive(Clone, Copy)]
 #[repr(usize)]
 pub enum Register {
+    Id = 0x20,
     EndOfInterrupt = 0xb0,
     SpuriousInterruptVector = 0xf0,
     ICRHigh = 0x310,
     ICRLow = 0x300,
     LvtTimer = 0x320,
+    LvtThermal = 0x330,
+    LvtPerformanceCounter = 0x340,
+    LvtLint0 = 0x350,
+    LvtLint1 = 0x360,
     InitialCount = 0x380,
     CurrentCount = 0x390,
     DivideConfiguration = 0x3e0,
@@ -50,41 +83,49 @@ pub struct Apic {
     timer_freq: usize,
 }
 
+registers! {
+    /// A register of the xAPIC page, they're all 32 bits wide but 16 bytes apart
+    struct XApicRegister {
+        0x0 => value: ReadWrite<u32>,
+        0x4 => _reserved: [u32; 3],
+    }
+}
+
 enum ApicMode {
-    XApic(VirtAddr),
+    /// The page, indexed by the offset of a register over 16
+    XApic(&'static [XApicRegister; 256]),
     X2Apic,
 }
 
 impl Apic {
+    /// Leaves the hardware alone, `enable` switches it to x2APIC mode once ACPI is up
     pub fn new() -> Apic {
-        unsafe {
-            cpu::wrmsr(
-                IA32_APIC_BASE,
-                cpu::rdmsr(IA32_APIC_BASE) | IA32_APIC_BASE_EN | IA32_APIC_BASE_EXTD,
-            )
-        };
-
-        let mode = ApicMode::X2Apic;
-
         Apic {
-            mode,
+            mode: ApicMode::X2Apic,
             timer_freq: 0,
         }
     }
 
     pub fn enable(&mut self) {
         unsafe {
-            self.write(Register::LvtTimer, 1 << 16);
+            cpu::wrmsr(
+                IA32_APIC_BASE,
+                cpu::rdmsr(IA32_APIC_BASE) | IA32_APIC_BASE_EN | IA32_APIC_BASE_EXTD,
+            );
+
+            self.write(Register::LvtTimer, TIMER_MASKED);
             self.write(Register::DivideConfiguration, 0b1010);
             self.write(Register::InitialCount, 0);
             self.write(Register::SpuriousInterruptVector, 0x100 | 0xFF);
 
+            self.setup_nmis();
+
             let mut ticks = 0;
 
             for i in 0..16 {
                 self.write(Register::InitialCount, 0xFFFFFFFF);
                 hpet::sleep(10 * 1000 * 1000);
-                self.write(Register::LvtTimer, 1 << 16);
+                self.write(Register::LvtTimer, TIMER_MASKED);
                 ticks += 0xFFFFFFFF - self.read(Register::CurrentCount);
             }
 
@@ -93,44 +134,120 @@ impl Apic {
         }
     }
 
-    pub unsafe fn ipi(&mut self, dest_apic_id: u32, ipi: u32) {
-        let dest_apic_id = match self.mode {
-            ApicMode::XApic(_) => todo!(),
-            ApicMode::X2Apic => dest_apic_id,
-        };
+    pub fn id(&mut self) -> u32 {
+        unsafe {
+            match self.mode {
+                ApicMode::XApic(_) => self.read(Register::Id) >> 24,
+                ApicMode::X2Apic => self.read(Register::Id),
+            }
+        }
+    }
 
-        cpu::wrmsr(0x830, ((dest_apic_id as u64) << 32) | ipi as u64);
+    pub fn eoi(&mut self) {
+        unsafe { self.write(Register::EndOfInterrupt, 0) }
     }
 
-    unsafe fn write(&mut self, register: Register, value: u32) {
-        let register = register as usize;
+    /// Delivers thermal sensor interrupts of this core on `vector`
+    pub fn set_thermal_vector(&mut self, vector: u8) {
+        unsafe { self.write(Register::LvtThermal, vector as u32) }
+    }
 
-        match self.mode {
-            ApicMode::XApic(base) => {
-                let addr = VirtAddr::new(base.as_u64() + register as u64);
-                core::ptr::write_volatile(addr.as_mut_ptr(), value);
-            }
+    /// Delivers performance counter overflows of this core as NMIs
+    pub fn set_performance_nmi(&mut self) {
+        unsafe { self.write(Register::LvtPerformanceCounter, DELIVERY_NMI) }
+    }
+
+    /// Fires `vector` on this core `hz` times a second, until `stop_timer`
+    pub fn start_periodic_timer(&mut self, vector: u8, hz: u32) {
+        let count = (self.timer_freq * 1000 / hz.max(1) as usize).clamp(1, u32::MAX as usize);
+
+        unsafe {
+            self.write(Register::LvtTimer, vector as u32 | TIMER_PERIODIC);
+            self.write(Register::InitialCount, count as u32);
+        }
+    }
 
-            ApicMode::X2Apic => {
-                let msr = 0x800u32 + ((register as u32) >> 4);
-                cpu::wrmsr(msr, value as u64);
+    pub fn stop_timer(&mut self) {
+        unsafe {
+            self.write(Register::LvtTimer, TIMER_MASKED);
+            self.write(Register::InitialCount, 0);
+        }
+    }
+
+    /// Programs the LINT pins the MADT reports as connected to NMI sources
+    fn setup_nmis(&mut self) {
+        let id = self.id();
+
+        for nmi in madt::local_apic_nmis(id) {
+            let register = match nmi.lint {
+                0 => Register::LvtLint0,
+                1 => Register::LvtLint1,
+                lint => {
+                    log::warn!("Invalid LINT{lint} for the NMI of APIC {id}");
+                    continue;
+                }
+            };
+
+            // NMIs are always edge triggered
+            let mut value = DELIVERY_NMI;
+            if nmi.polarity == Polarity::ActiveLow {
+                value |= 1 << 13;
             }
+
+            unsafe { self.write(register, value) };
         }
     }
 
-    unsafe fn read(&mut self, register: Register) -> u32 {
-        let register = register as usize;
+    /// Unmasks the performance counter LVT, which the core masks whenever it delivers an overflow
+    ///
+    /// Skips the lock since it's called from that NMI, which might have interrupted its holder.
+    /// x2APIC registers are MSRs of the current core, so there's nobody to race with
+    pub unsafe fn unmask_performance_nmi() {
+        cpu::wrmsr(
+            x2apic_msr(Register::LvtPerformanceCounter),
+            DELIVERY_NMI as u64,
+        );
+    }
 
+    pub unsafe fn ipi(&mut self, dest_apic_id: u32, ipi: u32) {
         match self.mode {
-            ApicMode::XApic(base) => {
-                let addr = VirtAddr::new(base.as_u64() + register as u64);
-                core::ptr::read_volatile(addr.as_ptr())
+            ApicMode::XApic(_) => {
+                // The previous IPI has to leave before the ICR takes another one
+                while self.read(Register::ICRLow) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+
+                // Writing the low half sends it, so the destination goes first
+                self.write(Register::ICRHigh, dest_apic_id << 24);
+                self.write(Register::ICRLow, ipi);
             }
 
-            ApicMode::X2Apic => {
-                let msr = 0x800u32 + ((register as u32) >> 4);
-                cpu::rdmsr(msr) as u32
-            }
+            // The ICR is a single 64 bit MSR, there's no separate high half to write
+            ApicMode::X2Apic => cpu::wrmsr(
+                x2apic_msr(Register::ICRLow),
+                ((dest_apic_id as u64) << 32) | ipi as u64,
+            ),
+        }
+    }
+
+    unsafe fn write(&mut self, register: Register, value: u32) {
+        match self.mode {
+            ApicMode::XApic(page) => page[register as usize >> 4].value.set(value),
+
+            ApicMode::X2Apic => cpu::wrmsr(x2apic_msr(register), value as u64),
         }
     }
+
+    unsafe fn read(&mut self, register: Register) -> u32 {
+        match self.mode {
+            ApicMode::XApic(page) => page[register as usize >> 4].value.get(),
+
+            ApicMode::X2Apic => cpu::rdmsr(x2apic_msr(register)) as u32,
+        }
+    }
+}
+
+/// The MSR `register` is at in x2APIC mode
+fn x2apic_msr(register: Register) -> u32 {
+    0x800 + (register as u32 >> 4)
 }
@@ -16,84 +16,180 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use limine::LimineKernelFileRequest;
-use xmas_elf::symbol_table::Entry;
+use crate::cmdline;
+use crate::sync::Once;
+use crate::unwind::Unwinder;
+use crate::utils::HashMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use rustc_demangle::Demangle;
+use xmas_elf::symbol_table::{Entry, Entry64, Type};
 use xmas_elf::{
     sections::{SectionData, ShType},
     ElfFile,
 };
 
-static KERNEL_FILE: LimineKernelFileRequest = LimineKernelFileRequest::new(0);
-
-pub fn backtrace(rbp: Option<u64>) {
-    let kernel_elf = KERNEL_FILE
-        .get_response()
-        .get()
-        .unwrap()
-        .kernel_file
-        .get()
-        .unwrap();
-    let kernel_elf = unsafe {
-        core::slice::from_raw_parts(
-            kernel_elf.base.as_ptr().unwrap(),
-            kernel_elf.length as usize,
-        )
-    };
-    let kernel_elf = ElfFile::new(kernel_elf).unwrap();
+/// Frames past this are most likely a corrupted chain
+const MAX_FRAMES: usize = 64;
+
+/// A function in the kernel, the name stays mangled until someone looks it up
+struct Symbol {
+    start: u64,
+    size: u64,
+    name: &'static str,
+}
+
+/// Every function in the kernel sorted by address, built by `init`
+static INDEX: Once<Vec<Symbol>> = Once::new();
+/// The start of every function by demangled name, for going the other way
+static NAMES: Once<HashMap<String, u64>> = Once::new();
 
-    let mut symbol_table = None;
+/// The symbol table of the kernel, as loaded by the bootloader
+struct Symbols {
+    elf: ElfFile<'static>,
+    table: &'static [Entry64],
+}
 
-    for section in kernel_elf.section_iter() {
-        if section.get_type() == Ok(ShType::SymTab) {
-            let section_data = section
-                .get_data(&kernel_elf)
-                .expect("Failed to get kernel section data information");
+impl Symbols {
+    fn load() -> Option<Symbols> {
+        let kernel_elf = cmdline::kernel_file()?;
+        let kernel_elf = unsafe {
+            core::slice::from_raw_parts(kernel_elf.base.as_ptr()?, kernel_elf.length as usize)
+        };
+        let elf = ElfFile::new(kernel_elf).ok()?;
+
+        let table = elf
+            .section_iter()
+            .filter(|section| section.get_type() == Ok(ShType::SymTab))
+            .find_map(|section| match section.get_data(&elf) {
+                Ok(SectionData::SymbolTable64(table)) => Some(table),
+                _ => None,
+            })?;
+
+        Some(Symbols { elf, table })
+    }
 
-            if let SectionData::SymbolTable64(symtab) = section_data {
-                symbol_table = Some(symtab);
+    fn lookup(&self, rip: u64) -> Option<(Demangle<'static>, u64)> {
+        self.table.iter().find_map(|symbol| {
+            let start = symbol.value();
+            if rip < start || rip >= start + symbol.size() {
+                return None;
             }
-        }
+
+            let name = symbol.get_name(&self.elf).unwrap_or("<unknown>");
+            Some((rustc_demangle::demangle(name), rip - start))
+        })
     }
-    let symbol_table = symbol_table.unwrap();
-
-    let rbp = if let Some(rbp) = rbp {
-        rbp
-    } else {
-        unsafe {
-            let rbp: u64;
-            core::arch::asm!("mov rbp, {}", out(reg) rbp);
-            rbp
-        }
+}
+
+/// Sorts the functions of the symbol table by address, so lookups don't scan the whole ELF
+pub fn init() {
+    let Some(symbols) = Symbols::load() else {
+        log::warn!("backtrace: no kernel symbol table, backtraces won't have names");
+        return;
     };
 
-    log::info!("======== BACKTRACE ===========");
+    let mut index: Vec<Symbol> = symbols
+        .table
+        .iter()
+        .filter(|symbol| symbol.get_type() == Ok(Type::Func) && symbol.size() != 0)
+        .map(|symbol| Symbol {
+            start: symbol.value(),
+            size: symbol.size(),
+            name: symbol.get_name(&symbols.elf).unwrap_or("<unknown>"),
+        })
+        .collect();
+
+    index.sort_unstable_by_key(|symbol| symbol.start);
+    index.dedup_by_key(|symbol| symbol.start);
+
+    // Generic functions show up once per instance, the first one has to do
+    let mut names = HashMap::default();
+    for symbol in &index {
+        names
+            .entry(alloc::format!(
+                "{:#}",
+                rustc_demangle::demangle(symbol.name)
+            ))
+            .or_insert(symbol.start);
+    }
+
+    log::info!("backtrace: {} functions indexed", index.len());
+    INDEX.call_once(|| index);
+    NAMES.call_once(|| names);
+}
+
+initcall!(backtrace, init, [random]);
 
-    let mut rbp: *const u64 = rbp as _;
-    for i in 0.. {
-        if rbp.is_null() {
+/// The function `rip` is in, demangled, and how far into it
+///
+/// Before `init` there's only the symbol table to scan, which is slow but needs no heap
+pub fn lookup(rip: u64) -> Option<(Demangle<'static>, u64)> {
+    let Some(index) = INDEX.get() else {
+        return Symbols::load()?.lookup(rip);
+    };
+
+    let symbol = &index[index
+        .partition_point(|symbol| symbol.start <= rip)
+        .checked_sub(1)?];
+    (rip < symbol.start + symbol.size)
+        .then(|| (rustc_demangle::demangle(symbol.name), rip - symbol.start))
+}
+
+/// The address of the function called `name`, e.g. `kernel::kmain`, once `init` ran
+pub fn address_of(name: &str) -> Option<u64> {
+    NAMES.get()?.get(name).copied()
+}
+
+pub use crate::unwind::Registers;
+
+/// Calls `frame` with the instruction pointer of `start` and then of every caller up the stack
+///
+/// Frames are unwound with the CFI in `.eh_frame`, the frame pointer is only followed through code
+/// that has none, like the assembly stubs
+pub fn walk(start: Registers, mut frame: impl FnMut(u64)) {
+    walk_while(start, |rip| {
+        frame(rip);
+        true
+    });
+}
+
+/// Like `walk`, but stops as soon as `frame` returns false
+pub fn walk_while(start: Registers, mut frame: impl FnMut(u64) -> bool) {
+    let mut unwinder = Unwinder::new();
+    let mut registers = start;
+
+    for depth in 0..MAX_FRAMES {
+        if registers.rip == 0 || !frame(registers.rip) {
             break;
         }
 
-        let rip = unsafe { *(rbp.offset(1)) } as usize;
-        let mut name = None;
+        let caller = unwinder
+            .as_mut()
+            .and_then(|unwinder| unwinder.step(&registers, depth == 0))
+            .or_else(|| registers.frame_pointer_caller());
 
-        for data in symbol_table {
-            let st_value = data.value() as usize;
-            let st_size = data.size() as usize;
+        // The stack only grows down, going back up means the chain is garbage
+        match caller {
+            Some(caller) if caller.rsp() > registers.rsp() => registers = caller,
+            _ => break,
+        }
+    }
+}
 
-            if rip >= st_value && rip < (st_value + st_size) {
-                let mangled_name = data.get_name(&kernel_elf).unwrap_or("<unknown>");
-                let demangled_name = rustc_demangle::demangle(mangled_name);
+/// Logs the stack at `start`, or of the caller
+pub fn backtrace(start: Option<Registers>) {
+    log::info!("======== BACKTRACE ===========");
 
-                name = Some(demangled_name);
+    let mut i = 0;
+    walk(start.unwrap_or_else(Registers::current), |rip| {
+        match lookup(rip) {
+            Some((name, offset)) => {
+                log::info!("{:>2}: 0x{:016x} - {:#}+{:#x}", i, rip, name, offset)
             }
+            None => log::info!("{:>2}: 0x{:016x} - <unknown>", i, rip),
         }
 
-        if let Some(name) = name {
-            log::info!("{:>2}: 0x{:016x} - {}", i, rip, name);
-        } else {
-            log::info!("{:>2}: 0x{:016x} - <unknown>", i, rip);
-        }
-        rbp = unsafe { (*rbp) as *const u64 };
-    }
+        i += 1;
+    });
 }
@@ -0,0 +1,398 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Error, RequestQueue};
+use crate::mm::{pmm, PhysAddr};
+use crate::sync::Mutex;
+use crate::{cpu, time};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Every cached block is one page frame
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Dirty blocks older than this get written back by `update`
+const WRITEBACK_AGE_US: u64 = 5_000_000;
+
+/// How often `update` looks for old dirty blocks
+const WRITEBACK_PERIOD_US: u64 = 1_000_000;
+
+/// The cache never grows past a quarter of memory
+const MAX_FRACTION: usize = 4;
+
+/// Below a sixteenth of memory free, blocks get evicted until there's more
+const LOW_WATERMARK_FRACTION: usize = 16;
+
+/// A device is told apart by the address of its request queue
+type Key = (usize, u64);
+
+struct Entry {
+    queue: Arc<RequestQueue>,
+    page: PhysAddr,
+    /// Position in the LRU list
+    stamp: u64,
+    /// When the block was first written since it last hit the disk
+    dirty_since: Option<u64>,
+}
+
+struct Cache {
+    entries: BTreeMap<Key, Entry>,
+    /// Least recently used first
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    entries: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    clock: 0,
+});
+
+static LAST_WRITEBACK: AtomicU64 = AtomicU64::new(0);
+
+fn now_us() -> u64 {
+    unsafe { cpu::rdtsc() / time::tsc_per_us() }
+}
+
+fn key(queue: &Arc<RequestQueue>, block: u64) -> Key {
+    (Arc::as_ptr(queue) as usize, block)
+}
+
+/// Whether whole sectors of the device fit a block, the cache stays out of the way otherwise
+fn cacheable(queue: &RequestQueue) -> bool {
+    let sector_size = queue.device().sector_size();
+    sector_size <= BLOCK_SIZE && BLOCK_SIZE.is_multiple_of(sector_size)
+}
+
+/// First sector and sector count of `block`, which may be cut short at the end of the device
+fn sectors(queue: &RequestQueue, block: u64) -> Result<(u64, usize), Error> {
+    let device = queue.device();
+    let per_block = (BLOCK_SIZE / device.sector_size()) as u64;
+    let first = block * per_block;
+
+    if first >= device.sector_count() {
+        return Err(Error::OutOfRange);
+    }
+
+    Ok((first, per_block.min(device.sector_count() - first) as usize))
+}
+
+impl Entry {
+    fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.page.as_hhdm().as_ptr(), BLOCK_SIZE) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.page.as_hhdm().as_mut_ptr(), BLOCK_SIZE) }
+    }
+
+    fn write_back(&mut self, block: u64) -> Result<(), Error> {
+        if self.dirty_since.is_none() {
+            return Ok(());
+        }
+
+        let (first, count) = sectors(&self.queue, block)?;
+        let len = count * self.queue.device().sector_size();
+        self.queue
+            .write(first, self.data()[..len].to_vec())
+            .wait()?;
+        self.dirty_since = None;
+
+        Ok(())
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        pmm::free(self.page, 1);
+    }
+}
+
+impl Cache {
+    fn touch(&mut self, key: Key) {
+        self.clock += 1;
+        let stamp = self.clock;
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            self.lru.remove(&entry.stamp);
+            entry.stamp = stamp;
+            self.lru.insert(stamp, key);
+        }
+    }
+
+    /// Drops the least recently used block, writing it back first if needed
+    fn evict_one(&mut self) -> bool {
+        let Some((_, key)) = self.lru.pop_first() else {
+            return false;
+        };
+
+        let mut entry = self.entries.remove(&key).unwrap();
+        if let Err(err) = entry.write_back(key.1) {
+            log::error!(
+                "cache: lost block {} of {}: {err:?}",
+                key.1,
+                entry.queue.device().name()
+            );
+        }
+
+        true
+    }
+
+    /// Evicts until the cache is under its limit and memory isn't tight
+    fn shrink(&mut self) {
+        let total = pmm::total_pages();
+
+        while self.entries.len() >= total / MAX_FRACTION
+            || pmm::free_pages() < total / LOW_WATERMARK_FRACTION
+        {
+            if !self.evict_one() {
+                break;
+            }
+        }
+    }
+
+    /// Finds `block` of `queue`, reading it in if `fill` is set
+    fn get(
+        &mut self,
+        queue: &Arc<RequestQueue>,
+        block: u64,
+        fill: bool,
+    ) -> Result<&mut Entry, Error> {
+        let key = key(queue, block);
+
+        if !self.entries.contains_key(&key) {
+            let (first, count) = sectors(queue, block)?;
+            self.shrink();
+
+            let page = match pmm::try_alloc(1) {
+                Some(page) => page,
+                None => {
+                    self.evict_one();
+                    pmm::try_alloc(1).ok_or(Error::Io)?
+                }
+            };
+
+            let mut entry = Entry {
+                queue: queue.clone(),
+                page,
+                stamp: 0,
+                dirty_since: None,
+            };
+            entry.data_mut().fill(0);
+
+            if fill {
+                let data = queue.read(first, count).wait()?;
+                entry.data_mut()[..data.len()].copy_from_slice(&data);
+            }
+
+            self.entries.insert(key, entry);
+        }
+
+        self.touch(key);
+        Ok(self.entries.get_mut(&key).unwrap())
+    }
+
+    fn write_back(&mut self, mut filter: impl FnMut(&Key, &Entry) -> bool) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        for (key, entry) in self.entries.iter_mut() {
+            if filter(key, entry) {
+                if let Err(err) = entry.write_back(key.1) {
+                    result = Err(err);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// Splits a byte range into (block, offset in the block, length) pieces
+fn pieces(offset: u64, len: usize) -> impl Iterator<Item = (u64, usize, usize)> {
+    let mut done = 0;
+
+    core::iter::from_fn(move || {
+        if done == len {
+            return None;
+        }
+
+        let position = offset + done as u64;
+        let start = (position % BLOCK_SIZE as u64) as usize;
+        let chunk = (BLOCK_SIZE - start).min(len - done);
+        done += chunk;
+
+        Some((position / BLOCK_SIZE as u64, start, chunk))
+    })
+}
+
+/// Reads `buffer.len()` bytes at byte `offset` of the device behind `queue`
+pub fn read(queue: &Arc<RequestQueue>, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+    if !cacheable(queue) {
+        return queue.read_bytes(offset, buffer);
+    }
+
+    let mut cache = CACHE.lock();
+    let mut done = 0;
+
+    for (block, start, len) in pieces(offset, buffer.len()) {
+        let entry = cache.get(queue, block, true)?;
+        buffer[done..done + len].copy_from_slice(&entry.data()[start..start + len]);
+        done += len;
+    }
+
+    Ok(())
+}
+
+/// Writes `data` at byte `offset`, it reaches the disk on the next write-back or `sync`
+pub fn write(queue: &Arc<RequestQueue>, offset: u64, data: &[u8]) -> Result<(), Error> {
+    if queue.device().read_only() {
+        return Err(Error::ReadOnly);
+    }
+
+    if !cacheable(queue) {
+        return queue.write_bytes(offset, data);
+    }
+
+    let mut cache = CACHE.lock();
+    let now = now_us();
+    let mut done = 0;
+
+    for (block, start, len) in pieces(offset, data.len()) {
+        // A block that is overwritten whole doesn't have to be read first
+        let entry = cache.get(queue, block, len != BLOCK_SIZE)?;
+        entry.data_mut()[start..start + len].copy_from_slice(&data[done..done + len]);
+        entry.dirty_since.get_or_insert(now);
+        done += len;
+    }
+
+    Ok(())
+}
+
+/// Writes back every dirty block of `queue` and flushes the device
+pub fn sync(queue: &Arc<RequestQueue>) -> Result<(), Error> {
+    let id = Arc::as_ptr(queue) as usize;
+    CACHE.lock().write_back(|key, _| key.0 == id)?;
+
+    queue.flush().wait().map(|_| ())
+}
+
+/// Writes back every dirty block in the cache
+pub fn sync_all() -> Result<(), Error> {
+    let queues: Vec<Arc<RequestQueue>> = {
+        let mut cache = CACHE.lock();
+        cache.write_back(|_, _| true)?;
+
+        let mut queues: Vec<Arc<RequestQueue>> = Vec::new();
+        for entry in cache.entries.values() {
+            if !queues.iter().any(|q| Arc::ptr_eq(q, &entry.queue)) {
+                queues.push(entry.queue.clone());
+            }
+        }
+
+        queues
+    };
+
+    queues
+        .iter()
+        .try_for_each(|queue| queue.flush().wait().map(|_| ()))
+}
+
+/// Forgets every block of `queue` without writing anything back, for devices that went away
+pub fn invalidate(queue: &Arc<RequestQueue>) {
+    let id = Arc::as_ptr(queue) as usize;
+    let mut cache = CACHE.lock();
+
+    let keys: Vec<Key> = cache
+        .entries
+        .keys()
+        .filter(|k| k.0 == id)
+        .copied()
+        .collect();
+    for key in keys {
+        let entry = cache.entries.remove(&key).unwrap();
+        cache.lru.remove(&entry.stamp);
+    }
+}
+
+/// Number of cached blocks, and how many of them are dirty
+pub fn stats() -> (usize, usize) {
+    let cache = CACHE.lock();
+    let dirty = cache
+        .entries
+        .values()
+        .filter(|e| e.dirty_since.is_some())
+        .count();
+
+    (cache.entries.len(), dirty)
+}
+
+/// Periodic write-back of old dirty blocks, cheap when it's not time yet
+pub fn update() {
+    let now = now_us();
+    let last = LAST_WRITEBACK.load(Ordering::Relaxed);
+
+    if now.saturating_sub(last) < WRITEBACK_PERIOD_US {
+        return;
+    }
+
+    let Some(mut cache) = CACHE.try_lock() else {
+        return;
+    };
+    LAST_WRITEBACK.store(now, Ordering::Relaxed);
+
+    let old = |_: &Key, entry: &Entry| {
+        entry
+            .dirty_since
+            .is_some_and(|since| now.saturating_sub(since) >= WRITEBACK_AGE_US)
+    };
+
+    if let Err(err) = cache.write_back(old) {
+        log::warn!("cache: write-back failed: {err:?}");
+    }
+
+    cache.shrink();
+}
+
+ktest! {
+    fn writes_reach_the_disk_on_sync() {
+        use super::RamDisk;
+
+        let disk = Arc::new(RamDisk::new(16));
+        let queue = Arc::new(RequestQueue::new(disk.clone()));
+
+        // Runs over from the first block into the second
+        let offset = BLOCK_SIZE as u64 - 100;
+        write(&queue, offset, &[0xAA; 200]).unwrap();
+        assert!(disk.contents().iter().all(|&b| b == 0));
+
+        let mut data = [0; 300];
+        read(&queue, offset - 50, &mut data).unwrap();
+        assert!(data[..50].iter().all(|&b| b == 0));
+        assert!(data[50..250].iter().all(|&b| b == 0xAA));
+        assert!(data[250..].iter().all(|&b| b == 0));
+
+        sync(&queue).unwrap();
+        let contents = disk.contents();
+        let start = offset as usize;
+        assert!(contents[start..start + 200].iter().all(|&b| b == 0xAA));
+        assert_eq!(contents.iter().filter(|&&b| b != 0).count(), 200);
+
+        invalidate(&queue);
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::sync::{Mutex, RwLock};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub mod cache;
+pub mod queue;
+
+pub use queue::RequestQueue;
+
+pub const SECTOR_SIZE: usize = 512;
+
+static DEVICES: RwLock<Vec<Arc<dyn BlockDevice>>> = RwLock::new(Vec::new());
+static QUEUES: RwLock<Vec<Arc<RequestQueue>>> = RwLock::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The request goes past the end of the device
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors
+    Unaligned,
+    ReadOnly,
+    Unsupported,
+    Io,
+}
+
+/// A disk, or anything else addressed in fixed size sectors
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn sector_count(&self) -> u64;
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads `buffer.len() / sector_size()` sectors starting at `sector`
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), Error>;
+
+    /// Makes sure everything written so far reached stable storage
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A disk in memory, what ktests put under queues and caches
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    pub fn new(sectors: usize) -> RamDisk {
+        RamDisk {
+            data: Mutex::new(alloc::vec![0; sectors * SECTOR_SIZE]),
+        }
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        "ram"
+    }
+
+    fn sector_count(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        check(self, sector, buffer.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        check(self, sector, buffer.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+/// Checks a request against the geometry of `device`
+pub fn check(device: &dyn BlockDevice, sector: u64, len: usize) -> Result<(), Error> {
+    if !len.is_multiple_of(device.sector_size()) {
+        return Err(Error::Unaligned);
+    }
+
+    let sectors = (len / device.sector_size()) as u64;
+    match sector.checked_add(sectors) {
+        Some(end) if end <= device.sector_count() => Ok(()),
+        _ => Err(Error::OutOfRange),
+    }
+}
+
+pub fn register(device: Arc<dyn BlockDevice>) {
+    let size = device.sector_count() * device.sector_size() as u64;
+    log::info!(
+        "{}: {} sectors, {} MiB{}",
+        device.name(),
+        device.sector_count(),
+        size / (1024 * 1024),
+        if device.read_only() {
+            ", read only"
+        } else {
+            ""
+        }
+    );
+
+    QUEUES
+        .write()
+        .push(Arc::new(RequestQueue::new(device.clone())));
+    DEVICES.write().push(device);
+}
+
+pub fn unregister(name: &str) {
+    DEVICES.write().retain(|device| device.name() != name);
+    QUEUES.write().retain(|queue| {
+        let keep = queue.device().name() != name;
+        if !keep {
+            cache::invalidate(queue);
+        }
+
+        keep
+    });
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.read().clone()
+}
+
+/// The request queue of the device called `name`, what filesystems should go through
+pub fn queue(name: &str) -> Option<Arc<RequestQueue>> {
+    QUEUES
+        .read()
+        .iter()
+        .find(|q| q.device().name() == name)
+        .cloned()
+}
+
+/// Returns `prefix` followed by the first free letter, e.g. `vda`, `vdb`...
+pub fn next_name(prefix: &str) -> String {
+    let devices = DEVICES.read();
+
+    (b'a'..=b'z')
+        .map(|c| alloc::format!("{prefix}{}", c as char))
+        .find(|name| !devices.iter().any(|d| d.name() == name))
+        .unwrap_or_else(|| alloc::format!("{prefix}{}", devices.len()))
+}
@@ -0,0 +1,393 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{BlockDevice, Error};
+use crate::sync::Mutex;
+use crate::utils::WaitQueue;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Merged requests don't grow past this
+const MAX_MERGE: usize = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    /// Everything submitted before it reaches the disk before anything submitted after it
+    Flush,
+}
+
+struct Request {
+    op: Op,
+    sector: u64,
+    /// What to write, or where the read data goes
+    data: Vec<u8>,
+    completion: Arc<Completion>,
+}
+
+#[derive(Default)]
+struct Completion {
+    result: Mutex<Option<Result<Vec<u8>, Error>>>,
+}
+
+/// Requests waiting for a device, sorted and merged before they go out
+pub struct RequestQueue {
+    device: Arc<dyn BlockDevice>,
+    pending: Mutex<Vec<Request>>,
+    dispatching: AtomicBool,
+    completed: WaitQueue,
+    /// Sectors `write_bytes` is reading, modifying and writing back
+    locked: Mutex<Vec<Range<u64>>>,
+    unlocked: WaitQueue,
+}
+
+/// Keeps a range of sectors to one `write_bytes` until dropped
+struct RangeGuard<'a> {
+    queue: &'a RequestQueue,
+    range: Range<u64>,
+}
+
+impl Drop for RangeGuard<'_> {
+    fn drop(&mut self) {
+        self.queue
+            .locked
+            .lock()
+            .retain(|range| *range != self.range);
+        self.queue.unlocked.wake_all();
+    }
+}
+
+fn overlap(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// A submitted request, to poll or wait on
+pub struct Handle {
+    queue: Arc<RequestQueue>,
+    completion: Arc<Completion>,
+}
+
+impl Handle {
+    pub fn is_done(&self) -> bool {
+        self.completion.result.lock().is_some()
+    }
+
+    /// Waits for the request, returning the data read for reads and nothing otherwise
+    pub fn wait(self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(result) = self.completion.result.lock().take() {
+                return result;
+            }
+
+            // Whoever waits runs the queue, unless another core already is
+            if !self.queue.run() {
+                self.queue.completed.wait_until(|| {
+                    self.is_done() || !self.queue.dispatching.load(Ordering::Acquire)
+                });
+            }
+        }
+    }
+}
+
+impl RequestQueue {
+    pub fn new(device: Arc<dyn BlockDevice>) -> RequestQueue {
+        RequestQueue {
+            device,
+            pending: Mutex::new(Vec::new()),
+            dispatching: AtomicBool::new(false),
+            completed: WaitQueue::new(),
+            locked: Mutex::new(Vec::new()),
+            unlocked: WaitQueue::new(),
+        }
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    fn submit(self: &Arc<Self>, op: Op, sector: u64, data: Vec<u8>) -> Handle {
+        let completion = Arc::new(Completion::default());
+
+        self.pending.lock().push(Request {
+            op,
+            sector,
+            data,
+            completion: completion.clone(),
+        });
+
+        Handle {
+            queue: self.clone(),
+            completion,
+        }
+    }
+
+    /// Queues a read of `sectors` sectors starting at `sector`
+    pub fn read(self: &Arc<Self>, sector: u64, sectors: usize) -> Handle {
+        let len = sectors * self.device.sector_size();
+        self.submit(Op::Read, sector, vec![0; len])
+    }
+
+    pub fn write(self: &Arc<Self>, sector: u64, data: Vec<u8>) -> Handle {
+        self.submit(Op::Write, sector, data)
+    }
+
+    pub fn flush(self: &Arc<Self>) -> Handle {
+        self.submit(Op::Flush, 0, Vec::new())
+    }
+
+    /// Sends everything pending to the device, returns false if another core is already at it
+    pub fn run(&self) -> bool {
+        if self.dispatching.swap(true, Ordering::Acquire) {
+            return false;
+        }
+
+        loop {
+            let requests = core::mem::take(&mut *self.pending.lock());
+            if requests.is_empty() {
+                break;
+            }
+
+            // Flushes and requests that touch sectors a request of the batch writes, or write
+            // sectors it reads, split the batch. Requests only get reordered between the splits
+            let mut batch = Vec::new();
+            for request in requests {
+                if request.op == Op::Flush {
+                    self.dispatch(core::mem::take(&mut batch));
+
+                    let result = self.device.flush().map(|_| Vec::new());
+                    *request.completion.result.lock() = Some(result);
+                    continue;
+                }
+
+                if batch.iter().any(|other| self.conflict(other, &request)) {
+                    self.dispatch(core::mem::take(&mut batch));
+                }
+                batch.push(request);
+            }
+
+            self.dispatch(batch);
+            self.completed.wake_all();
+        }
+
+        self.dispatching.store(false, Ordering::Release);
+        self.completed.wake_all();
+        true
+    }
+
+    fn sectors(&self, request: &Request) -> Range<u64> {
+        let sectors = (request.data.len() / self.device.sector_size()) as u64;
+        request.sector..request.sector + sectors
+    }
+
+    /// Whether `a` and `b` have to reach the device in the order they were submitted
+    fn conflict(&self, a: &Request, b: &Request) -> bool {
+        (a.op == Op::Write || b.op == Op::Write) && overlap(&self.sectors(a), &self.sectors(b))
+    }
+
+    /// Sorts `batch` by sector and issues it, merging requests that touch contiguous sectors
+    fn dispatch(&self, mut batch: Vec<Request>) {
+        batch.sort_by_key(|request| request.sector);
+        let sector_size = self.device.sector_size();
+
+        let mut batch = batch.into_iter().peekable();
+        while let Some(first) = batch.next() {
+            let mut merged = vec![first];
+            let mut len = merged[0].data.len();
+
+            while let Some(next) = batch.peek() {
+                let end = merged[0].sector + (len / sector_size) as u64;
+                if next.op != merged[0].op
+                    || next.sector != end
+                    || len + next.data.len() > MAX_MERGE
+                {
+                    break;
+                }
+
+                len += next.data.len();
+                merged.push(batch.next().unwrap());
+            }
+
+            self.issue(merged, len);
+        }
+    }
+
+    /// Runs contiguous requests of the same kind as a single transfer
+    fn issue(&self, mut requests: Vec<Request>, len: usize) {
+        let sector = requests[0].sector;
+        let op = requests[0].op;
+
+        let result = if requests.len() == 1 {
+            let data = &mut requests[0].data;
+            match op {
+                Op::Read => self.device.read(sector, data),
+                _ => self.device.write(sector, data),
+            }
+        } else {
+            match op {
+                Op::Read => {
+                    let mut buffer = vec![0; len];
+                    let result = self.device.read(sector, &mut buffer);
+
+                    let mut offset = 0;
+                    for request in &mut requests {
+                        let end = offset + request.data.len();
+                        request.data.copy_from_slice(&buffer[offset..end]);
+                        offset = end;
+                    }
+
+                    result
+                }
+                _ => {
+                    let mut buffer = Vec::with_capacity(len);
+                    for request in &requests {
+                        buffer.extend_from_slice(&request.data);
+                    }
+
+                    self.device.write(sector, &buffer)
+                }
+            }
+        };
+
+        for request in requests {
+            let data = match op {
+                Op::Read => request.data,
+                _ => Vec::new(),
+            };
+
+            *request.completion.result.lock() = Some(result.map(|_| data));
+        }
+    }
+
+    /// Waits until no other `write_bytes` works on any of `range`, then claims it
+    fn lock_range(&self, range: Range<u64>) -> RangeGuard<'_> {
+        loop {
+            {
+                let mut locked = self.locked.lock();
+                if !locked.iter().any(|other| overlap(other, &range)) {
+                    locked.push(range.clone());
+                    return RangeGuard { queue: self, range };
+                }
+            }
+
+            self.unlocked.wait_until(|| {
+                !self
+                    .locked
+                    .lock()
+                    .iter()
+                    .any(|other| overlap(other, &range))
+            });
+        }
+    }
+
+    /// Reads `buffer.len()` bytes at byte `offset`, for filesystems that don't think in sectors
+    pub fn read_bytes(self: &Arc<Self>, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let sector_size = self.device.sector_size() as u64;
+        let first = offset / sector_size;
+        let last = (offset + buffer.len() as u64 - 1) / sector_size;
+
+        let data = self.read(first, (last - first + 1) as usize).wait()?;
+        let start = (offset - first * sector_size) as usize;
+        buffer.copy_from_slice(&data[start..start + buffer.len()]);
+
+        Ok(())
+    }
+
+    /// Writes `data` at byte `offset`, reading back the partial sectors at either end
+    pub fn write_bytes(self: &Arc<Self>, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let sector_size = self.device.sector_size() as u64;
+        let first = offset / sector_size;
+        let last = (offset + data.len() as u64 - 1) / sector_size;
+        let start = (offset - first * sector_size) as usize;
+        let sectors = (last - first + 1) as usize;
+
+        // Two writes into the same sector would each write back the other's old bytes
+        let _range = self.lock_range(first..last + 1);
+
+        let aligned = start == 0 && data.len().is_multiple_of(sector_size as usize);
+        let mut buffer = if aligned {
+            data.to_vec()
+        } else {
+            self.read(first, sectors).wait()?
+        };
+
+        buffer[start..start + data.len()].copy_from_slice(data);
+        self.write(first, buffer).wait().map(|_| ())
+    }
+}
+
+ktest! {
+    fn overlapping_requests_keep_their_order() {
+        use super::{RamDisk, SECTOR_SIZE};
+
+        let queue = Arc::new(RequestQueue::new(Arc::new(RamDisk::new(8))));
+
+        // Sorting by sector alone would read 4..6 before sector 5 gets written
+        let write = queue.write(5, vec![0xAA; SECTOR_SIZE]);
+        let read = queue.read(4, 2);
+        let data = read.wait().unwrap();
+        write.wait().unwrap();
+        assert!(data[SECTOR_SIZE..].iter().all(|&b| b == 0xAA));
+
+        // And would let the older write to sector 5 land last
+        let older = queue.write(5, vec![0xAA; SECTOR_SIZE]);
+        let newer = queue.write(4, vec![0xBB; 2 * SECTOR_SIZE]);
+        newer.wait().unwrap();
+        older.wait().unwrap();
+
+        let data = queue.read(5, 1).wait().unwrap();
+        assert!(data.iter().all(|&b| b == 0xBB));
+    }
+
+    fn overlapping_byte_writes_keep_each_others_bytes() {
+        use super::{RamDisk, SECTOR_SIZE};
+
+        let disk = Arc::new(RamDisk::new(4));
+        let queue = Arc::new(RequestQueue::new(disk.clone()));
+
+        // Both in sector 1, the second one running over into sector 2
+        queue.write_bytes(SECTOR_SIZE as u64 + 16, &[0xAA; 32]).unwrap();
+        queue.write_bytes(2 * SECTOR_SIZE as u64 - 8, &[0xBB; 16]).unwrap();
+
+        let data = disk.contents();
+        let (first, second) = (SECTOR_SIZE + 16, 2 * SECTOR_SIZE - 8);
+        assert!(data[first..first + 32].iter().all(|&b| b == 0xAA));
+        assert!(data[second..second + 16].iter().all(|&b| b == 0xBB));
+        assert_eq!(data.iter().filter(|&&b| b != 0).count(), 48);
+
+        // A claimed range holds off the writes that overlap it, and only those
+        let guard = queue.lock_range(1..3);
+        let claimed = |range: Range<u64>| queue.locked.lock().iter().any(|r| overlap(r, &range));
+        assert!(claimed(2..4));
+        assert!(!claimed(3..4));
+        queue.write_bytes(3 * SECTOR_SIZE as u64, &[0xCC; 4]).unwrap();
+
+        drop(guard);
+        assert!(!claimed(0..4));
+    }
+}
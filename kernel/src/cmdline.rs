@@ -0,0 +1,47 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use limine::{LimineFile, LimineKernelFileRequest};
+
+static KERNEL_FILE: LimineKernelFileRequest = LimineKernelFileRequest::new(0);
+
+/// The kernel image as loaded by the bootloader, which also carries the command line
+pub fn kernel_file() -> Option<&'static LimineFile> {
+    KERNEL_FILE.get_response().get()?.kernel_file.get()
+}
+
+/// The whole command line, empty if the bootloader didn't pass one
+pub fn get() -> &'static str {
+    kernel_file()
+        .and_then(|file| file.cmdline.to_str())
+        .and_then(|cmdline| cmdline.to_str().ok())
+        .unwrap_or("")
+}
+
+/// Returns the value of the first `key=value` option
+pub fn value(key: &str) -> Option<&'static str> {
+    get()
+        .split_whitespace()
+        .find_map(|option| option.strip_prefix(key)?.strip_prefix('='))
+}
+
+/// Whether `key` was passed, alone or with a value
+pub fn flag(key: &str) -> bool {
+    get()
+        .split_whitespace()
+        .any(|option| option.split('=').next() == Some(key))
+}
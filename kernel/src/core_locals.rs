@@ -16,18 +16,24 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::sync::{IrqSpinlock, Mutex};
 use crate::{
     apic::Apic,
     cpu::{self, IA32_GS_BASE},
+    cpufreq::Governor,
+    cpuidle::IdleStats,
     interrupts::Tss,
     mm::VirtAddr,
+    net::buffer::Pool,
 };
 use alloc::boxed::Box;
 use core::{
     mem::size_of,
     sync::atomic::{AtomicUsize, Ordering},
 };
-use spin::Mutex;
+
+/// Cores past this many are left parked, so a core id can index per core arrays
+pub const MAX_CORES: usize = 64;
 
 static CORES_ONLINE: AtomicUsize = AtomicUsize::new(0);
 
@@ -37,7 +43,10 @@ pub struct CoreLocals {
 
     pub id: usize,
     pub tss: Mutex<Box<Tss>>,
-    pub apic: Mutex<Apic>,
+    pub apic: IrqSpinlock<Apic>,
+    pub idle: IdleStats,
+    pub cpufreq: Governor,
+    pub packet_buffers: Pool,
 }
 
 trait CoreGuard: Sync + Sized {}
@@ -65,6 +74,15 @@ pub fn get_core_locals() -> &'static CoreLocals {
     }
 }
 
+/// The current core's id, 0 until its core locals are set up
+pub fn id() -> usize {
+    if initialized() {
+        get_core_locals().id
+    } else {
+        0
+    }
+}
+
 pub fn cores_online() -> usize {
     CORES_ONLINE.load(Ordering::SeqCst)
 }
@@ -77,7 +95,10 @@ pub fn init() {
         address: core_locals_ptr.as_u64(),
         id: CORES_ONLINE.fetch_add(1, Ordering::SeqCst),
         tss: Mutex::new(Box::new(Tss::new())),
-        apic: Mutex::new(Apic::new()),
+        apic: IrqSpinlock::new(Apic::new()),
+        idle: IdleStats::new(),
+        cpufreq: Governor::new(),
+        packet_buffers: Pool::new(),
     };
 
     unsafe {
@@ -16,16 +16,54 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::mm::VirtAddr;
+use crate::mm::{PhysAddr, VirtAddr};
+use core::arch::x86_64::{CpuidResult, __cpuid_count};
 
 pub const IA32_GS_BASE: u32 = 0xc0000101;
 
+/// RFLAGS.IF, set while interrupts are enabled
+pub const RFLAGS_IF: u64 = 1 << 9;
+
 pub fn get_cr2() -> VirtAddr {
     let cr2: u64;
     unsafe { core::arch::asm!("mov {}, cr2", out(reg) cr2) };
     VirtAddr::new(cr2)
 }
 
+pub fn get_cr3() -> PhysAddr {
+    let cr3: u64;
+    unsafe { core::arch::asm!("mov {}, cr3", out(reg) cr3) };
+    PhysAddr::new(cr3 & !0xFFF)
+}
+
+#[inline]
+pub fn rflags() -> u64 {
+    let rflags: u64;
+    unsafe { core::arch::asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
+    rflags
+}
+
+/// Disables interrupts, returning the RFLAGS to hand to `restore_interrupts` after
+#[inline]
+pub fn save_and_disable_interrupts() -> u64 {
+    let rflags = rflags();
+    unsafe { core::arch::asm!("cli", options(nomem, nostack)) };
+    rflags
+}
+
+/// Enables interrupts again if they were when `rflags` was saved
+#[inline]
+pub unsafe fn restore_interrupts(rflags: u64) {
+    if rflags & RFLAGS_IF != 0 {
+        core::arch::asm!("sti", options(nomem, nostack));
+    }
+}
+
+#[inline]
+pub unsafe fn invlpg(addr: VirtAddr) {
+    core::arch::asm!("invlpg [{}]", in(reg) addr.as_u64(), options(nostack));
+}
+
 #[inline]
 pub unsafe fn wrmsr(msr: u32, value: u64) {
     let low = value as u32;
@@ -51,3 +89,75 @@ pub unsafe fn rdtsc() -> u64 {
 
     ((high as u64) << 32) | (low as u64)
 }
+
+/// Returns a value from the hardware generator, `None` if there's none or it keeps failing
+pub fn rdrand() -> Option<u64> {
+    // CPUID.01H:ECX.RDRAND
+    if cpuid(1).ecx & (1 << 30) == 0 {
+        return None;
+    }
+
+    // The generator can run dry for a moment, Intel suggests 10 attempts
+    for _ in 0..10 {
+        let (value, ok): (u64, u8);
+        unsafe {
+            core::arch::asm!("rdrand {}; setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[inline]
+pub fn cpuid(leaf: u32) -> CpuidResult {
+    cpuid_count(leaf, 0)
+}
+
+#[inline]
+pub fn cpuid_count(leaf: u32, subleaf: u32) -> CpuidResult {
+    // Safe on newer toolchains, but older ones still mark it unsafe
+    #[allow(unused_unsafe)]
+    unsafe {
+        __cpuid_count(leaf, subleaf)
+    }
+}
+
+#[inline]
+pub unsafe fn outb(port: u16, value: u8) {
+    core::arch::asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack));
+}
+
+#[inline]
+pub unsafe fn outw(port: u16, value: u16) {
+    core::arch::asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack));
+}
+
+#[inline]
+pub unsafe fn outl(port: u16, value: u32) {
+    core::arch::asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack));
+}
+
+#[inline]
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    core::arch::asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack));
+    value
+}
+
+#[inline]
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    core::arch::asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack));
+    value
+}
+
+#[inline]
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    core::arch::asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack));
+    value
+}
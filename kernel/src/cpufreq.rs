@@ -0,0 +1,250 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::aml::{self, AmlValue, Args};
+use crate::acpi::sdt::GenericAddress;
+use crate::sync::Mutex;
+use crate::{cpu, cpuidle, time};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+const IA32_PERF_CTL: u32 = 0x199;
+const IA32_PM_ENABLE: u32 = 0x770;
+const IA32_HWP_CAPABILITIES: u32 = 0x771;
+const IA32_HWP_REQUEST: u32 = 0x774;
+
+/// Energy performance preference halfway between performance (0) and power saving (0xFF)
+const EPP_BALANCED: u64 = 0x80;
+
+/// How often the governor looks at the load of a core
+const SAMPLE_US: u64 = 10_000;
+
+/// Above this load the governor goes straight to the fastest state
+const UP_THRESHOLD: u64 = 80;
+
+static DRIVER: Mutex<Option<Driver>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug)]
+pub struct PState {
+    /// Core frequency in MHz
+    pub frequency: u64,
+    control: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ControlRegister {
+    PerfCtl,
+    Io(GenericAddress),
+}
+
+enum Driver {
+    /// The hardware picks the frequency itself, we only set the bounds once per core
+    Hwp,
+    /// The governor picks one of the `_PSS` states, fastest first
+    Acpi {
+        control: ControlRegister,
+        states: Vec<PState>,
+    },
+}
+
+/// Per core governor state, kept in the core locals
+pub struct Governor {
+    last_tsc: AtomicU64,
+    last_idle: AtomicU64,
+    current: AtomicUsize,
+}
+
+impl Governor {
+    pub const fn new() -> Governor {
+        Governor {
+            last_tsc: AtomicU64::new(0),
+            last_idle: AtomicU64::new(0),
+            current: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl ControlRegister {
+    fn write(self, value: u64) {
+        unsafe {
+            match self {
+                ControlRegister::PerfCtl => {
+                    let ctl = cpu::rdmsr(IA32_PERF_CTL);
+                    cpu::wrmsr(IA32_PERF_CTL, (ctl & !0xFFFF) | (value & 0xFFFF));
+                }
+                ControlRegister::Io(register) => register.write(value),
+            }
+        }
+    }
+}
+
+fn hwp_supported() -> bool {
+    cpu::cpuid(6).eax & (1 << 7) != 0
+}
+
+fn eist_supported() -> bool {
+    cpu::cpuid(1).ecx & (1 << 7) != 0
+}
+
+fn control_register(processor: &str) -> Option<ControlRegister> {
+    let pct = aml::evaluate(&alloc::format!("{processor}._PCT"), Args::EMPTY).ok()?;
+    let AmlValue::Package(pct) = pct else {
+        return None;
+    };
+
+    let control = aml::generic_address(pct.first()?)?;
+    match control.address_space {
+        GenericAddress::FUNCTIONAL_FIXED_HW if eist_supported() => Some(ControlRegister::PerfCtl),
+        GenericAddress::SYSTEM_IO => Some(ControlRegister::Io(control)),
+        _ => None,
+    }
+}
+
+fn pstates(processor: &str) -> Option<Vec<PState>> {
+    let pss = aml::evaluate(&alloc::format!("{processor}._PSS"), Args::EMPTY).ok()?;
+    let AmlValue::Package(pss) = pss else {
+        return None;
+    };
+
+    let states: Vec<PState> = pss
+        .iter()
+        .filter_map(|state| {
+            let AmlValue::Package(state) = state else {
+                return None;
+            };
+
+            Some(PState {
+                frequency: aml::integer(state.first()?)?,
+                control: aml::integer(state.get(4)?)?,
+            })
+        })
+        .collect();
+
+    (!states.is_empty()).then_some(states)
+}
+
+/// Reads the performance states of the first processor, every core is assumed to match
+fn acpi_driver() -> Option<Driver> {
+    let processor = aml::processors().into_iter().next()?;
+
+    Some(Driver::Acpi {
+        control: control_register(&processor)?,
+        states: pstates(&processor)?,
+    })
+}
+
+fn enable_hwp() {
+    unsafe {
+        cpu::wrmsr(IA32_PM_ENABLE, 1);
+
+        let caps = cpu::rdmsr(IA32_HWP_CAPABILITIES);
+        let (highest, lowest) = (caps & 0xFF, (caps >> 24) & 0xFF);
+
+        // Desired performance 0 leaves the choice to the hardware, within [lowest, highest]
+        cpu::wrmsr(IA32_HWP_REQUEST, lowest | highest << 8 | EPP_BALANCED << 24);
+    }
+}
+
+/// Picks the slowest state that still leaves some headroom for the current load
+fn target(states: &[PState], load: u64) -> usize {
+    if load >= UP_THRESHOLD {
+        return 0;
+    }
+
+    let wanted = states[0].frequency * load / UP_THRESHOLD;
+
+    states
+        .iter()
+        .rposition(|s| s.frequency >= wanted)
+        .unwrap_or(0)
+}
+
+/// Samples the load of the current core and adjusts its frequency, called from the idle loop
+pub fn update() {
+    let driver = DRIVER.lock();
+    let Some(Driver::Acpi { control, states }) = driver.as_ref() else {
+        return;
+    };
+
+    let governor = &core!().cpufreq;
+    let now = unsafe { cpu::rdtsc() } / time::tsc_per_us();
+    let elapsed = now - governor.last_tsc.load(Ordering::Relaxed);
+
+    if elapsed < SAMPLE_US {
+        return;
+    }
+
+    let idle = cpuidle::idle_time_us();
+    let idle_delta = idle - governor.last_idle.load(Ordering::Relaxed);
+    governor.last_tsc.store(now, Ordering::Relaxed);
+    governor.last_idle.store(idle, Ordering::Relaxed);
+
+    let load = 100 - core::cmp::min(idle_delta * 100 / elapsed, 100);
+    let next = target(states, load);
+
+    if governor.current.swap(next, Ordering::Relaxed) != next {
+        control.write(states[next].control);
+    }
+}
+
+/// Returns the frequency the current core was last set to, in MHz
+pub fn current_frequency() -> Option<u64> {
+    match DRIVER.lock().as_ref()? {
+        Driver::Hwp => None,
+        Driver::Acpi { states, .. } => {
+            Some(states[core!().cpufreq.current.load(Ordering::Relaxed)].frequency)
+        }
+    }
+}
+
+/// Sets up frequency scaling on the current core, `init` must have run first
+pub fn init_core() {
+    match DRIVER.lock().as_ref() {
+        Some(Driver::Hwp) => enable_hwp(),
+        Some(Driver::Acpi { control, states }) => {
+            // Start from the fastest state, the governor slows down from there
+            control.write(states[0].control);
+            core!().cpufreq.current.store(0, Ordering::Relaxed);
+        }
+        None => {}
+    }
+}
+
+pub fn init() {
+    log::trace!("Initializing frequency scaling");
+
+    let driver = if hwp_supported() {
+        log::info!("Using hardware P-states");
+        Some(Driver::Hwp)
+    } else if let Some(driver) = acpi_driver() {
+        if let Driver::Acpi { states, .. } = &driver {
+            for state in states {
+                log::debug!("{state:?}");
+            }
+        }
+
+        Some(driver)
+    } else {
+        log::info!("No frequency scaling available");
+        None
+    };
+
+    *DRIVER.lock() = driver;
+    init_core();
+}
+
+initcall!(cpufreq, init, [acpi]);
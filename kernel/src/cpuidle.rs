@@ -0,0 +1,251 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::aml::{self, AmlValue, Args};
+use crate::acpi::sdt::GenericAddress;
+use crate::sync::Mutex;
+use crate::trace::{self, Event};
+use crate::{acpi, cpu, time};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Maximum number of idle states we keep track of
+pub const MAX_STATES: usize = 8;
+
+/// Class of a functional fixed hardware register describing a MWAIT hint
+const FFH_CLASS_MWAIT: u8 = 2;
+
+/// An idle state is only worth entering if we expect to stay in it this many times its exit latency
+const RESIDENCY_FACTOR: u64 = 3;
+
+static STATES: Mutex<Vec<CState>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug)]
+enum Entry {
+    Halt,
+    Mwait(u32),
+    Io(u16),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CState {
+    /// ACPI C-state type, 1 to 3
+    pub kind: u8,
+    /// Worst case exit latency in microseconds
+    pub latency: u64,
+    entry: Entry,
+}
+
+/// Usage and residency of a single idle state on a core
+pub struct StateStats {
+    pub usage: AtomicU64,
+    pub time_us: AtomicU64,
+}
+
+/// Per core idle statistics, kept in the core locals
+pub struct IdleStats {
+    pub states: [StateStats; MAX_STATES],
+    last_us: AtomicU64,
+}
+
+impl IdleStats {
+    pub const fn new() -> IdleStats {
+        IdleStats {
+            states: [const {
+                StateStats {
+                    usage: AtomicU64::new(0),
+                    time_us: AtomicU64::new(0),
+                }
+            }; MAX_STATES],
+            last_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Entry {
+    /// Enters the state with interrupts disabled, a pending interrupt still wakes the core up
+    unsafe fn enter(self) {
+        match self {
+            Entry::Halt => core::arch::asm!("sti; hlt; cli"),
+            Entry::Mwait(hint) => {
+                let monitor = core!() as *const _ as u64;
+
+                core::arch::asm!("monitor", in("rax") monitor, in("ecx") 0, in("edx") 0);
+                // ECX bit 0 treats interrupts as break events even when they're masked
+                core::arch::asm!("mwait", in("eax") hint, in("ecx") 1);
+            }
+            Entry::Io(port) => {
+                cpu::inb(port);
+
+                // Some chipsets need a dummy read to actually stop the core before we carry on
+                if let Some(timer) = acpi::fadt().and_then(|f| f.pm_timer()) {
+                    timer.read();
+                }
+            }
+        }
+    }
+}
+
+fn parse_cst_entry(value: &AmlValue, mwait: bool) -> Option<CState> {
+    let AmlValue::Package(package) = value else {
+        return None;
+    };
+
+    let register = aml::generic_address(package.first()?)?;
+    let kind = aml::integer(package.get(1)?)? as u8;
+    let latency = aml::integer(package.get(2)?)?;
+
+    let entry = match register.address_space {
+        GenericAddress::FUNCTIONAL_FIXED_HW if register.bit_offset == FFH_CLASS_MWAIT && mwait => {
+            Entry::Mwait(register.address as u32)
+        }
+        GenericAddress::SYSTEM_IO if kind > 1 => Entry::Io(register.address as u16),
+        _ if kind == 1 => Entry::Halt,
+        _ => return None,
+    };
+
+    Some(CState {
+        kind,
+        latency,
+        entry,
+    })
+}
+
+/// Reads the idle states of the first processor from its `_CST`, every core is assumed to match
+fn states_from_cst(mwait: bool) -> Option<Vec<CState>> {
+    let processor = aml::processors().into_iter().next()?;
+    let cst = aml::evaluate(&alloc::format!("{processor}._CST"), Args::EMPTY).ok()?;
+
+    let AmlValue::Package(cst) = cst else {
+        return None;
+    };
+
+    // The first element is the number of states that follow
+    let states: Vec<CState> = cst
+        .iter()
+        .skip(1)
+        .filter_map(|entry| parse_cst_entry(entry, mwait))
+        .take(MAX_STATES)
+        .collect();
+
+    (!states.is_empty()).then_some(states)
+}
+
+/// Builds the idle states out of the MWAIT leaf, with conservative latencies since CPUID doesn't tell
+fn states_from_cpuid() -> Vec<CState> {
+    const LATENCIES: [u64; 7] = [1, 20, 100, 200, 400, 800, 1200];
+
+    let edx = cpu::cpuid(5).edx;
+
+    (1..8)
+        .filter(|&n| (edx >> (n * 4)) & 0xF != 0)
+        .map(|n| CState {
+            kind: core::cmp::min(n, 3) as u8,
+            latency: LATENCIES[n - 1],
+            entry: Entry::Mwait(((n as u32 - 1) & 0xF) << 4),
+        })
+        .collect()
+}
+
+/// Checks for MWAIT with interrupts as break events even when they're masked
+fn mwait_supported() -> bool {
+    let leaf1 = cpu::cpuid(1);
+    if leaf1.ecx & (1 << 3) == 0 {
+        return false;
+    }
+
+    let leaf5 = cpu::cpuid(5);
+    leaf5.ecx & 0b11 == 0b11
+}
+
+/// Picks the deepest state we expect to stay in long enough to be worth its exit latency
+fn select(states: &[CState], predicted_us: u64) -> Option<(usize, CState)> {
+    states
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(i, s)| *i == 0 || s.latency * RESIDENCY_FACTOR <= predicted_us)
+        .map(|(i, s)| (i, *s))
+}
+
+/// Idles the current core once, returning after the next interrupt was handled
+pub fn enter() {
+    unsafe { core::arch::asm!("cli") };
+
+    let stats = &core!().idle;
+    let selected = select(&STATES.lock(), stats.last_us.load(Ordering::Relaxed));
+
+    let Some((index, state)) = selected else {
+        unsafe { core::arch::asm!("sti; hlt") };
+        return;
+    };
+
+    trace::event(Event::IdleEnter, [index as u64, 0]);
+    let start = unsafe { cpu::rdtsc() };
+    unsafe { state.entry.enter() };
+    let end = unsafe { cpu::rdtsc() };
+
+    let residency = (end - start) / time::tsc_per_us();
+    trace::event(Event::IdleExit, [residency, 0]);
+    stats.last_us.store(residency, Ordering::Relaxed);
+    stats.states[index].usage.fetch_add(1, Ordering::Relaxed);
+    stats.states[index]
+        .time_us
+        .fetch_add(residency, Ordering::Relaxed);
+
+    // Let the interrupt that woke us up in
+    unsafe { core::arch::asm!("sti; nop") };
+}
+
+/// Returns the idle states in use, shallowest first
+pub fn states() -> Vec<CState> {
+    STATES.lock().clone()
+}
+
+/// Total time the current core spent idle, in microseconds
+pub fn idle_time_us() -> u64 {
+    let stats = &core!().idle;
+    stats
+        .states
+        .iter()
+        .map(|s| s.time_us.load(Ordering::Relaxed))
+        .sum()
+}
+
+pub fn init() {
+    log::trace!("Initializing the idle states");
+
+    let mwait = mwait_supported();
+    let states = states_from_cst(mwait)
+        .or_else(|| mwait.then(states_from_cpuid))
+        .filter(|states| !states.is_empty())
+        .unwrap_or_else(|| {
+            alloc::vec![CState {
+                kind: 1,
+                latency: 1,
+                entry: Entry::Halt,
+            }]
+        });
+
+    for state in &states {
+        log::debug!("{state:?}");
+    }
+
+    *STATES.lock() = states;
+}
+
+initcall!(cpuidle, init, [acpi]);
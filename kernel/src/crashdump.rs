@@ -0,0 +1,239 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::backtrace::{self, Registers};
+use crate::interrupts::{self, Exception, InterruptStack};
+use crate::logging::Entry;
+use crate::mm::{heap, pmm};
+use crate::utils::Crc32;
+use crate::{cmdline, core_locals, cpu, logging, monitor, serial, time};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+/// Bumped whenever a line changes meaning, `tools/crashdump` checks it
+const VERSION: u32 = 1;
+
+const BEGIN: &str = "-----BEGIN BERYL CRASH DUMP-----";
+/// Followed by the CRC-32 of every line in between and `-----`
+const END: &str = "-----END BERYL CRASH DUMP crc32=";
+
+/// How many of the latest log records go in
+const LOG_TAIL: usize = 64;
+
+/// The serial console, checksumming the lines on their way out
+///
+/// The checksum covers the lines with bare LFs, the CRs only go to the wire
+struct Dump {
+    crc: Crc32,
+}
+
+impl Write for Dump {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.crc.update(s.as_bytes());
+
+        for line in s.split_inclusive('\n') {
+            match line.strip_suffix('\n') {
+                Some(text) => serial::_print(format_args!("{text}\r\n")),
+                None => serial::_print(format_args!("{line}")),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps free text on its line, backslashes and line breaks come out escaped
+struct Escape<'a>(&'a mut Dump);
+
+impl Write for Escape<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                c => self.0.write_char(c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn regs(out: &mut Dump, core: usize, stack: &InterruptStack) -> fmt::Result {
+    write!(out, "regs {core}")?;
+
+    for (name, value) in [
+        ("rax", stack.rax),
+        ("rbx", stack.rbx),
+        ("rcx", stack.rcx),
+        ("rdx", stack.rdx),
+        ("rsi", stack.rsi),
+        ("rdi", stack.rdi),
+        ("rbp", stack.rbp),
+        ("rsp", stack.rsp),
+        ("r8", stack.r8),
+        ("r9", stack.r9),
+        ("r10", stack.r10),
+        ("r11", stack.r11),
+        ("r12", stack.r12),
+        ("r13", stack.r13),
+        ("r14", stack.r14),
+        ("r15", stack.r15),
+        ("rip", stack.rip),
+        ("rflags", stack.rflags),
+        ("cs", stack.cs),
+        ("ss", stack.ss),
+    ] {
+        write!(out, " {name}={value:#x}")?;
+    }
+
+    writeln!(out)
+}
+
+fn frames(out: &mut Dump, core: usize, start: Registers) -> fmt::Result {
+    let mut result = Ok(());
+    let mut i = 0;
+
+    backtrace::walk(start, |rip| {
+        let line = match backtrace::lookup(rip) {
+            Some((name, offset)) => writeln!(out, "frame {core} {i} {rip:#x} {name:#}+{offset:#x}"),
+            None => writeln!(out, "frame {core} {i} {rip:#x} ?"),
+        };
+
+        result = result.and(line);
+        i += 1;
+    });
+
+    result
+}
+
+fn cores(out: &mut Dump, exception: Option<&Exception>) -> fmt::Result {
+    let count = core_locals::cores_online().clamp(1, core_locals::MAX_CORES);
+    let current = monitor::current_core();
+    writeln!(out, "cores {count} {current}")?;
+
+    for core in 0..count {
+        let state = monitor::state(core, exception);
+        let status = match (core == current, state.is_some()) {
+            (true, true) => "exception",
+            (true, false) => "panicked",
+            (false, true) => "stopped",
+            (false, false) => "running",
+        };
+        writeln!(out, "core {core} {status}")?;
+
+        if let Some(stack) = &state {
+            regs(out, core, stack)?;
+        }
+
+        let start = match state {
+            Some(stack) => Registers::from(&stack),
+            None if core == current => Registers::current(),
+            None => continue,
+        };
+        frames(out, core, start)?;
+    }
+
+    Ok(())
+}
+
+fn log(out: &mut Dump, entry: &Entry) -> fmt::Result {
+    let module = match entry.module {
+        "" => "-",
+        module => module,
+    };
+
+    write!(
+        out,
+        "log {} {} {} {} {}:{} {module} ",
+        entry.sequence,
+        time::ticks_to_us(entry.ticks),
+        entry.level.as_str(),
+        entry.core,
+        entry.file,
+        entry.line
+    )?;
+    write!(Escape(out), "{}", entry.args)?;
+    writeln!(out)
+}
+
+fn body(out: &mut Dump, info: &PanicInfo, exception: Option<&Exception>) -> fmt::Result {
+    writeln!(out, "version {VERSION}")?;
+    writeln!(out, "kernel {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, "uptime_us {}", time::uptime_us())?;
+
+    out.write_str("panic ")?;
+    write!(Escape(out), "{}", info.message())?;
+    writeln!(out)?;
+
+    if let Some(location) = info.location() {
+        writeln!(
+            out,
+            "location {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?;
+    }
+
+    if let Some(exception) = exception {
+        writeln!(
+            out,
+            "exception {:#x} {:#x} {:#x} {}",
+            exception.vector,
+            exception.stack.code,
+            cpu::get_cr2().as_u64(),
+            interrupts::exception_name(exception.vector)
+        )?;
+    }
+
+    cores(out, exception)?;
+
+    write!(
+        out,
+        "mem total_pages={} free_pages={} ballooned_pages={}",
+        pmm::total_pages(),
+        pmm::free_pages(),
+        pmm::ballooned()
+    )?;
+    match heap::try_used() {
+        Some(used) => writeln!(out, " heap_used={used}")?,
+        None => writeln!(out)?,
+    }
+
+    let mut result = Ok(());
+    logging::tail(LOG_TAIL, |entry| result = result.and(log(out, entry)));
+
+    result
+}
+
+/// Prints what a bug report needs in the format `tools/crashdump` reads, `nocrashdump` skips it
+///
+/// One line per fact, the first word says what it is and free text comes last, escaped
+pub fn emit(info: &PanicInfo, exception: Option<&Exception>) {
+    if cmdline::flag("nocrashdump") || !serial::has_console() {
+        return;
+    }
+
+    serial::_print(format_args!("\r\n{BEGIN}\r\n"));
+
+    let mut out = Dump { crc: Crc32::new() };
+    let _ = body(&mut out, info, exception);
+
+    serial::_print(format_args!("{END}{:08x}-----\r\n", out.crc.finish()));
+}
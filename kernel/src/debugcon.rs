@@ -0,0 +1,50 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use core::fmt::{Arguments, Result, Write};
+
+/// QEMU and Bochs' debug console, bytes written here go straight to the host
+const PORT: u16 = 0xE9;
+
+/// Reading the port back gives its number when the console is attached
+pub fn present() -> bool {
+    unsafe { crate::cpu::inb(PORT) == PORT as u8 }
+}
+
+pub fn write_bytes(bytes: &[u8]) {
+    unsafe {
+        core::arch::asm!("rep outsb",
+         in("rsi") bytes.as_ptr(),
+         in("rcx") bytes.len(),
+         in("dx") PORT,
+        );
+    }
+}
+
+struct DebugconWriter;
+
+impl Write for DebugconWriter {
+    fn write_str(&mut self, s: &str) -> Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: Arguments) {
+    let _ = DebugconWriter.write_fmt(args);
+}
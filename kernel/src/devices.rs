@@ -0,0 +1,246 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::{aml, madt};
+use crate::driver;
+use crate::fb_renderer;
+use crate::pci;
+use crate::sync::Rcu;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Looked up far more than it changes, which is only while drivers probe
+static TREE: Rcu<Vec<Node>> = Rcu::new(Vec::new());
+
+/// The root of the tree, every other device descends from it
+pub const ROOT: Id = Id(0);
+
+/// Index of a node, stays valid for as long as the kernel runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Id(pub u32);
+
+#[derive(Clone, Debug)]
+pub enum Kind {
+    Root,
+    Cpu {
+        processor_uid: u32,
+        apic_id: u32,
+    },
+    /// A PCI segment, parent of the functions on its root buses
+    PciSegment(u16),
+    Pci(pci::Device),
+    Acpi {
+        path: String,
+        hid: Option<String>,
+    },
+    Platform(&'static str),
+    /// A framebuffer set up by the bootloader, by its index
+    Display {
+        output: usize,
+        width: usize,
+        height: usize,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub children: Vec<Id>,
+    pub kind: Kind,
+    /// Name of the driver bound to the device
+    pub driver: Option<&'static str>,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Root => write!(f, "root"),
+            Kind::Cpu {
+                processor_uid,
+                apic_id,
+            } => write!(f, "cpu {processor_uid} (apic {apic_id})"),
+            Kind::PciSegment(segment) => write!(f, "pci segment {segment:04x}"),
+            Kind::Pci(device) => write!(f, "pci {device}"),
+            Kind::Acpi {
+                path,
+                hid: Some(hid),
+            } => write!(f, "acpi {path} ({hid})"),
+            Kind::Acpi { path, hid: None } => write!(f, "acpi {path}"),
+            Kind::Platform(name) => write!(f, "{name}"),
+            Kind::Display {
+                output,
+                width,
+                height,
+            } => write!(f, "display {output} ({width}x{height})"),
+        }
+    }
+}
+
+impl Kind {
+    fn is(&self, device: &driver::Device) -> bool {
+        match (self, device) {
+            (Kind::Pci(a), driver::Device::Pci(b)) => a.address == b.address,
+            (Kind::Acpi { path: a, .. }, driver::Device::Acpi { path: b }) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Adds a device under `parent`, returns `None` if the parent doesn't exist
+pub fn add(parent: Id, kind: Kind) -> Option<Id> {
+    TREE.update(|tree| {
+        let id = Id(tree.len() as u32);
+
+        tree.get_mut(parent.0 as usize)?.children.push(id);
+        tree.push(Node {
+            children: Vec::new(),
+            kind,
+            driver: None,
+        });
+
+        Some(id)
+    })
+}
+
+/// Records that `driver` took `device`
+pub fn bind(device: &driver::Device, driver: &'static str) {
+    TREE.update(|tree| {
+        if let Some(node) = tree.iter_mut().find(|node| node.kind.is(device)) {
+            node.driver = Some(driver);
+        }
+    });
+}
+
+/// Logs the whole tree at debug level, one device per line
+pub fn dump() {
+    fn dump_node(tree: &[Node], id: Id, depth: usize) {
+        let node = &tree[id.0 as usize];
+
+        match node.driver {
+            Some(driver) => log::debug!("{:depth$}[{}] {} <{driver}>", "", id.0, node.kind),
+            None => log::debug!("{:depth$}[{}] {}", "", id.0, node.kind),
+        }
+
+        for &child in &node.children {
+            dump_node(tree, child, depth + 2);
+        }
+    }
+
+    let tree = TREE.read();
+    if !tree.is_empty() {
+        dump_node(&tree, ROOT, 0);
+    }
+}
+
+fn add_pci() {
+    let devices = pci::devices();
+    let mut segments: Vec<(u16, Id)> = Vec::new();
+    let mut bridges: Vec<(u16, u8, Id)> = Vec::new();
+
+    // Bridges are always scanned before what sits behind them
+    for device in devices {
+        let address = device.address;
+        let parent = bridges
+            .iter()
+            .find(|&&(segment, bus, _)| segment == address.segment && bus == address.bus)
+            .map(|&(_, _, id)| id);
+
+        let parent =
+            parent.unwrap_or_else(
+                || match segments.iter().find(|&&(s, _)| s == address.segment) {
+                    Some(&(_, id)) => id,
+                    None => {
+                        let id = add(ROOT, Kind::PciSegment(address.segment)).unwrap();
+                        segments.push((address.segment, id));
+                        id
+                    }
+                },
+            );
+
+        let id = add(parent, Kind::Pci(device)).unwrap();
+        if let Some(secondary) = device.secondary_bus() {
+            bridges.push((address.segment, secondary, id));
+        }
+    }
+}
+
+fn add_acpi() {
+    let mut paths: Vec<(String, Id)> = Vec::new();
+
+    // Parents come before their children in a namespace walk
+    for path in aml::all_devices() {
+        let parent = paths
+            .iter()
+            .filter(|(p, _)| path.starts_with(p.as_str()) && path[p.len()..].starts_with('.'))
+            .max_by_key(|(p, _)| p.len())
+            .map_or(ROOT, |&(_, id)| id);
+
+        let hid = aml::hid(&path);
+        let id = add(
+            parent,
+            Kind::Acpi {
+                path: path.clone(),
+                hid,
+            },
+        )
+        .unwrap();
+
+        paths.push((path, id));
+    }
+}
+
+pub fn init() {
+    log::trace!("Building the device tree");
+
+    TREE.update(|tree| {
+        tree.push(Node {
+            children: Vec::new(),
+            kind: Kind::Root,
+            driver: None,
+        })
+    });
+
+    for cpu in madt::cpus().filter(|cpu| cpu.usable()) {
+        add(
+            ROOT,
+            Kind::Cpu {
+                processor_uid: cpu.processor_uid,
+                apic_id: cpu.apic_id,
+            },
+        );
+    }
+
+    for _ in madt::io_apics() {
+        add(ROOT, Kind::Platform("ioapic"));
+    }
+
+    for (output, (width, height)) in fb_renderer::outputs().into_iter().enumerate() {
+        add(
+            ROOT,
+            Kind::Display {
+                output,
+                width,
+                height,
+            },
+        );
+    }
+
+    add_pci();
+    add_acpi();
+}
+
+initcall!(devices, init, [acpi, pci]);
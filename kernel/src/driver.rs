@@ -0,0 +1,217 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::aml;
+use crate::block;
+use crate::devices;
+use crate::oops;
+use crate::pci;
+use crate::power::{self, Action};
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+static BOUND: Mutex<Vec<(&'static Driver, Device)>> = Mutex::new(Vec::new());
+
+extern "C" {
+    static __drivers_start: u8;
+    static __drivers_end: u8;
+}
+
+/// Registers a `Driver` static, it's going to be probed by `driver::init`
+#[macro_export]
+macro_rules! driver {
+    ($driver:path) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".drivers"]
+            static ENTRY: &$crate::driver::Driver = &$driver;
+        };
+    };
+}
+
+/// What a driver can bind to
+#[derive(Clone, Copy, Debug)]
+pub enum Match {
+    PciId {
+        vendor: u16,
+        device: u16,
+    },
+    /// `prog_if` set to `None` matches any programming interface
+    PciClass {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+    AcpiHid(&'static str),
+}
+
+#[derive(Clone, Debug)]
+pub enum Device {
+    Pci(pci::Device),
+    Acpi { path: String },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ProbeError {
+    /// The driver doesn't want the device after all, the next one gets a chance
+    Unsupported,
+    Failed(&'static str),
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    /// Drivers are probed in ascending order and removed in the opposite one
+    pub order: u8,
+    pub matches: &'static [Match],
+    pub probe: fn(&Device) -> Result<(), ProbeError>,
+    pub remove: Option<fn(&Device)>,
+}
+
+impl Match {
+    fn devices(&self, pci: &[pci::Device]) -> Vec<Device> {
+        match *self {
+            Match::PciId { vendor, device } => pci
+                .iter()
+                .filter(|d| d.vendor_id == vendor && d.device_id == device)
+                .map(|&d| Device::Pci(d))
+                .collect(),
+            Match::PciClass {
+                class,
+                subclass,
+                prog_if,
+            } => pci
+                .iter()
+                .filter(|d| d.class == class && d.subclass == subclass)
+                .filter(|d| prog_if.is_none_or(|p| d.prog_if == p))
+                .map(|&d| Device::Pci(d))
+                .collect(),
+            Match::AcpiHid(hid) => aml::devices(hid)
+                .into_iter()
+                .map(|path| Device::Acpi { path })
+                .collect(),
+        }
+    }
+}
+
+impl Device {
+    fn same(&self, other: &Device) -> bool {
+        match (self, other) {
+            (Device::Pci(a), Device::Pci(b)) => a.address == b.address,
+            (Device::Acpi { path: a }, Device::Acpi { path: b }) => a == b,
+            _ => false,
+        }
+    }
+
+    pub fn as_pci(&self) -> Option<&pci::Device> {
+        match self {
+            Device::Pci(device) => Some(device),
+            Device::Acpi { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Device::Pci(device) => write!(f, "pci {}", device.address),
+            Device::Acpi { path } => write!(f, "acpi {path}"),
+        }
+    }
+}
+
+/// Every driver in the `.drivers` section, in probe order
+fn drivers() -> Vec<&'static Driver> {
+    let mut drivers: Vec<&'static Driver> = unsafe {
+        let start = core::ptr::addr_of!(__drivers_start) as *const &'static Driver;
+        let end = core::ptr::addr_of!(__drivers_end) as *const &'static Driver;
+
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize).to_vec()
+    };
+
+    drivers.sort_by_key(|d| d.order);
+    drivers
+}
+
+fn bound(device: &Device) -> bool {
+    BOUND.lock().iter().any(|(_, d)| d.same(device))
+}
+
+fn probe(driver: &'static Driver, device: Device) {
+    let Ok(result) = oops::recoverable(driver.name, || (driver.probe)(&device)) else {
+        log::error!(
+            "{}: {device}: probe oopsed, leaving it unbound",
+            driver.name
+        );
+        return;
+    };
+
+    match result {
+        Ok(()) => {
+            log::info!("{}: bound to {device}", driver.name);
+            devices::bind(&device, driver.name);
+            BOUND.lock().push((driver, device));
+        }
+        Err(ProbeError::Unsupported) => {}
+        Err(ProbeError::Failed(reason)) => log::warn!("{}: {device}: {reason}", driver.name),
+    }
+}
+
+/// Removes every bound device, in the opposite order they were probed in
+fn teardown(_action: Action) {
+    // Dirty blocks have to reach their disks while the drivers are still around
+    if let Err(err) = block::cache::sync_all() {
+        log::error!("Writing back the block cache failed: {err:?}");
+    }
+
+    let bound = core::mem::take(&mut *BOUND.lock());
+
+    for (driver, device) in bound.iter().rev() {
+        if let Some(remove) = driver.remove {
+            log::debug!("{}: removing {device}", driver.name);
+            remove(device);
+        }
+    }
+}
+
+/// Returns the devices bound to a driver, with the driver's name
+pub fn bound_devices() -> Vec<(&'static str, Device)> {
+    BOUND
+        .lock()
+        .iter()
+        .map(|(driver, device)| (driver.name, device.clone()))
+        .collect()
+}
+
+pub fn init() {
+    log::trace!("Probing drivers");
+
+    let pci = pci::devices();
+
+    for driver in drivers() {
+        for device in driver.matches.iter().flat_map(|m| m.devices(&pci)) {
+            if !bound(&device) {
+                probe(driver, device);
+            }
+        }
+    }
+
+    power::register_notifier(teardown);
+}
+
+initcall!(drivers, init, [apic, devices, initramfs, ioapic, net, pci]);
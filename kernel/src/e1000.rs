@@ -0,0 +1,460 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::madt;
+use crate::apic;
+use crate::driver::{self, Driver, Match, ProbeError};
+use crate::hpet;
+use crate::interrupts::{self, InterruptStack};
+use crate::ioapic;
+use crate::mm::dma::Dma;
+use crate::mm::mmio::Mmio;
+use crate::net::{self, Error, MacAddress, NetDevice, PacketBuffer, MAX_FRAME};
+use crate::pci;
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Registers
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EERD: usize = 0x0014;
+const ICR: usize = 0x00C0;
+const IMS: usize = 0x00D0;
+const IMC: usize = 0x00D8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+const MTA: usize = 0x5200;
+const RAL: usize = 0x5400;
+const RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+const RAH_AV: u32 = 1 << 31;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+/// Enable, pad short packets, collision threshold and distance
+const TCTL_DEFAULT: u32 = 1 << 1 | 1 << 3 | 0x10 << 4 | 0x40 << 12;
+/// Inter packet gap recommended for copper
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+/// Interrupt causes
+const ICR_TXDW: u32 = 1 << 0;
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXDMT0: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT0: u32 = 1 << 7;
+
+/// Descriptor fields
+const DESC_STATUS_DD: u8 = 1 << 0;
+const DESC_STATUS_EOP: u8 = 1 << 1;
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const RX_DESCRIPTORS: usize = 64;
+const TX_DESCRIPTORS: usize = 64;
+/// Matches the 2048 byte receive buffer size selected in RCTL
+const BUFFER_SIZE: usize = 2048;
+
+static ADAPTERS: Mutex<Vec<Arc<Adapter>>> = Mutex::new(Vec::new());
+static VECTOR: Mutex<Option<u8>> = Mutex::new(None);
+
+static DRIVER: Driver = Driver {
+    name: "e1000",
+    order: 30,
+    matches: &[
+        // 82540EM, what QEMU emulates by default
+        Match::PciId {
+            vendor: 0x8086,
+            device: 0x100E,
+        },
+        // 82545EM
+        Match::PciId {
+            vendor: 0x8086,
+            device: 0x100F,
+        },
+        // 82574L, QEMU's e1000e
+        Match::PciId {
+            vendor: 0x8086,
+            device: 0x10D3,
+        },
+    ],
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+/// A descriptor ring and the buffers its descriptors point to
+struct Ring {
+    descriptors: Dma,
+    buffers: Dma,
+    next: usize,
+}
+
+struct Adapter {
+    name: String,
+    device: pci::Device,
+    mmio: Mmio,
+    mac: MacAddress,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+    link: AtomicBool,
+    /// The legacy ISA IRQ, when MSI isn't available
+    irq: Option<u8>,
+}
+
+impl Ring {
+    fn new(count: usize) -> Ring {
+        let ring = Ring {
+            descriptors: Dma::new(count * DESCRIPTOR_SIZE),
+            buffers: Dma::new(count * BUFFER_SIZE),
+            next: 0,
+        };
+
+        for i in 0..count {
+            let buffer = ring.buffers.phys().as_u64() + (i * BUFFER_SIZE) as u64;
+            ring.descriptors.write(i * DESCRIPTOR_SIZE, buffer);
+        }
+
+        ring
+    }
+
+    fn count(&self) -> usize {
+        self.descriptors.len() / DESCRIPTOR_SIZE
+    }
+
+    fn status(&self, i: usize) -> u8 {
+        self.descriptors.read(i * DESCRIPTOR_SIZE + 12)
+    }
+
+    fn set_status(&self, i: usize, status: u8) {
+        self.descriptors.write(i * DESCRIPTOR_SIZE + 12, status);
+    }
+}
+
+impl Adapter {
+    /// Reads a word of the EEPROM, through EERD
+    fn read_eeprom(&self, address: u8) -> Option<u16> {
+        // The 82574 moved the address and done bit compared to the older parts
+        let (shift, done) = match self.device.device_id {
+            0x10D3 => (2, 1 << 1),
+            _ => (8, 1 << 4),
+        };
+
+        self.mmio.write(EERD, (address as u32) << shift | 1);
+
+        for _ in 0..1000 {
+            let value = self.mmio.read::<u32>(EERD);
+            if value & done != 0 {
+                return Some((value >> 16) as u16);
+            }
+
+            hpet::sleep(10_000);
+        }
+
+        None
+    }
+
+    /// The MAC from the EEPROM, or whatever the firmware left in the first receive address
+    fn read_mac(&self) -> MacAddress {
+        let eeprom: Option<Vec<u16>> = (0..3).map(|i| self.read_eeprom(i)).collect();
+        if let Some(words) = eeprom {
+            let mut mac = [0; 6];
+            for (i, word) in words.iter().enumerate() {
+                mac[i * 2..i * 2 + 2].copy_from_slice(&word.to_le_bytes());
+            }
+
+            return MacAddress(mac);
+        }
+
+        let low = self.mmio.read::<u32>(RAL).to_le_bytes();
+        let high = self.mmio.read::<u32>(RAH).to_le_bytes();
+        MacAddress([low[0], low[1], low[2], low[3], high[0], high[1]])
+    }
+
+    fn reset(&self) {
+        self.mmio.write(IMC, !0);
+        self.mmio
+            .write(CTRL, self.mmio.read::<u32>(CTRL) | CTRL_RST);
+        hpet::sleep(1_000_000);
+
+        while self.mmio.read::<u32>(CTRL) & CTRL_RST != 0 {
+            core::hint::spin_loop();
+        }
+
+        self.mmio.write(IMC, !0);
+        self.mmio.read::<u32>(ICR);
+    }
+
+    fn setup(&self) {
+        let [a, b, c, d, e, f] = self.mac.0;
+        self.mmio.write(RAL, u32::from_le_bytes([a, b, c, d]));
+        self.mmio
+            .write(RAH, u16::from_le_bytes([e, f]) as u32 | RAH_AV);
+
+        for i in 0..128 {
+            self.mmio.write(MTA + i * 4, 0u32);
+        }
+
+        let rx = self.rx.lock();
+        let base = rx.descriptors.phys().as_u64();
+        self.mmio.write(RDBAL, base as u32);
+        self.mmio.write(RDBAH, (base >> 32) as u32);
+        self.mmio.write(RDLEN, rx.descriptors.len() as u32);
+        self.mmio.write(RDH, 0u32);
+        self.mmio.write(RDT, rx.count() as u32 - 1);
+        self.mmio.write(RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx = self.tx.lock();
+        for i in 0..tx.count() {
+            tx.set_status(i, DESC_STATUS_DD);
+        }
+
+        let base = tx.descriptors.phys().as_u64();
+        self.mmio.write(TDBAL, base as u32);
+        self.mmio.write(TDBAH, (base >> 32) as u32);
+        self.mmio.write(TDLEN, tx.descriptors.len() as u32);
+        self.mmio.write(TDH, 0u32);
+        self.mmio.write(TDT, 0u32);
+        self.mmio.write(TCTL, TCTL_DEFAULT);
+        self.mmio.write(TIPG, TIPG_DEFAULT);
+
+        self.mmio
+            .write(CTRL, self.mmio.read::<u32>(CTRL) | CTRL_SLU | CTRL_ASDE);
+    }
+
+    fn update_link(&self) {
+        let up = self.mmio.read::<u32>(STATUS) & STATUS_LU != 0;
+        if self.link.swap(up, Ordering::Relaxed) != up {
+            log::info!("{}: link {}", self.name, if up { "up" } else { "down" });
+        }
+    }
+}
+
+impl NetDevice for Adapter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.link.load(Ordering::Relaxed)
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+
+        if !self.link_up() {
+            return Err(Error::LinkDown);
+        }
+
+        let mut tx = self.tx.lock();
+        let i = tx.next;
+
+        // Descriptors are handed back with DD set once the frame went out
+        if tx.status(i) & DESC_STATUS_DD == 0 {
+            return Err(Error::Busy);
+        }
+
+        let offset = i * BUFFER_SIZE;
+        tx.buffers.as_mut_slice()[offset..offset + frame.len()].copy_from_slice(frame);
+
+        let descriptor = i * DESCRIPTOR_SIZE;
+        tx.descriptors.write(descriptor + 8, frame.len() as u16);
+        tx.descriptors
+            .write(descriptor + 11, TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS);
+        tx.set_status(i, 0);
+
+        tx.next = (i + 1) % tx.count();
+        self.mmio.write(TDT, tx.next as u32);
+
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<PacketBuffer> {
+        let mut rx = self.rx.lock();
+
+        loop {
+            let i = rx.next;
+            let status = rx.status(i);
+            if status & DESC_STATUS_DD == 0 {
+                return None;
+            }
+
+            let descriptor = i * DESCRIPTOR_SIZE;
+            let len = rx.descriptors.read::<u16>(descriptor + 8) as usize;
+            let errors = rx.descriptors.read::<u8>(descriptor + 13);
+
+            // Frames never span buffers at this MTU, anything else is bogus
+            let frame = (status & DESC_STATUS_EOP != 0 && errors == 0).then(|| {
+                let offset = i * BUFFER_SIZE;
+                PacketBuffer::from_slice(
+                    &rx.buffers.as_slice()[offset..offset + len.min(BUFFER_SIZE)],
+                )
+            });
+
+            // Give the descriptor back to the device
+            rx.set_status(i, 0);
+            rx.next = (i + 1) % rx.count();
+            self.mmio.write(RDT, i as u32);
+
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+fn interrupt(_stack: &mut InterruptStack) {
+    if let Some(adapters) = ADAPTERS.try_lock() {
+        for adapter in adapters.iter() {
+            // Reading ICR acknowledges every cause
+            let causes = adapter.mmio.read::<u32>(ICR);
+
+            if causes & ICR_LSC != 0 {
+                adapter.update_link();
+            }
+
+            if causes & ICR_RXO != 0 {
+                log::warn!("{}: receive overrun", adapter.name);
+            }
+        }
+    }
+
+    core!().apic.lock().eoi();
+}
+
+/// Routes the adapter's interrupt to the shared e1000 vector, MSI if possible, returns the
+/// legacy IRQ used otherwise
+fn enable_interrupts(device: &pci::Device) -> Option<Option<u8>> {
+    let mut vector = VECTOR.lock();
+    if vector.is_none() {
+        *vector = interrupts::allocate_handler(interrupt);
+    }
+
+    let vector = (*vector)?;
+    let apic_id = core!().apic.lock().id();
+
+    if let Some(msi) = device.msi() {
+        msi.enable(device, apic::msi_address(apic_id), vector as u16);
+        return Some(None);
+    }
+
+    // The 82540 has no MSI, QEMU wires its INTx to an ISA IRQ
+    let line = device.read8(pci::INTERRUPT_LINE);
+    if line >= 16 {
+        return None;
+    }
+
+    ioapic::route_isa_irq(line, vector, apic_id);
+    Some(Some(line))
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+    let mmio = pci
+        .map_bar(0)
+        .ok_or(ProbeError::Failed("cannot map BAR0"))?;
+    pci.enable_bus_mastering();
+
+    let mut adapter = Adapter {
+        name: net::next_name("eth"),
+        device: *pci,
+        mmio,
+        mac: MacAddress([0; 6]),
+        rx: Mutex::new(Ring::new(RX_DESCRIPTORS)),
+        tx: Mutex::new(Ring::new(TX_DESCRIPTORS)),
+        link: AtomicBool::new(false),
+        irq: None,
+    };
+
+    adapter.reset();
+    adapter.mac = adapter.read_mac();
+    adapter.setup();
+    adapter.update_link();
+
+    let interrupts = enable_interrupts(pci);
+    adapter.irq = interrupts.flatten();
+
+    let adapter = Arc::new(adapter);
+    ADAPTERS.lock().push(adapter.clone());
+
+    match interrupts {
+        Some(_) => adapter
+            .mmio
+            .write(IMS, ICR_TXDW | ICR_LSC | ICR_RXDMT0 | ICR_RXO | ICR_RXT0),
+        None => log::warn!("{}: no interrupts, link changes go unnoticed", pci.address),
+    }
+
+    net::register(adapter);
+    Ok(())
+}
+
+fn remove(device: &driver::Device) {
+    let Some(pci) = device.as_pci() else {
+        return;
+    };
+
+    let mut adapters = ADAPTERS.lock();
+    let Some(i) = adapters
+        .iter()
+        .position(|a| a.device.address == pci.address)
+    else {
+        return;
+    };
+
+    let adapter = adapters.remove(i);
+    drop(adapters);
+
+    net::unregister(&adapter.name);
+
+    adapter.mmio.write(IMC, !0);
+    adapter.mmio.write(RCTL, 0u32);
+    adapter.mmio.write(TCTL, 0u32);
+
+    match (adapter.irq, adapter.device.msi()) {
+        (Some(irq), _) => ioapic::mask(madt::isa_irq(irq).0),
+        (None, Some(msi)) => msi.disable(&adapter.device),
+        (None, None) => {}
+    }
+}
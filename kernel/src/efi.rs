@@ -0,0 +1,264 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::mm::{PhysAddr, VirtAddr};
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::vec::Vec;
+use limine::LimineEfiSystemTableRequest;
+
+static EFI_SYSTEM_TABLE_REQ: LimineEfiSystemTableRequest = LimineEfiSystemTableRequest::new(0);
+
+/// Serializes calls into the firmware, runtime services aren't reentrant
+static RUNTIME: Mutex<Option<&'static RuntimeServices>> = Mutex::new(None);
+
+/// Limine doesn't call SetVirtualAddressMap, so the firmware runs at its physical addresses
+/// through the identity map, which only covers this much
+const IDENTITY_MAP_END: u64 = 4 << 30;
+
+const ERROR_BIT: usize = 1 << (usize::BITS - 1);
+
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status(usize);
+
+impl Status {
+    pub const SUCCESS: Status = Status(0);
+    pub const UNSUPPORTED: Status = Status(ERROR_BIT | 3);
+    pub const BUFFER_TOO_SMALL: Status = Status(ERROR_BIT | 5);
+    pub const NOT_FOUND: Status = Status(ERROR_BIT | 14);
+
+    fn result(self) -> Result<(), Status> {
+        match self.0 & ERROR_BIT {
+            0 => Ok(()),
+            _ => Err(self),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+impl Guid {
+    /// EFI_GLOBAL_VARIABLE, the vendor of the architectural variables like `BootOrder`
+    pub const GLOBAL_VARIABLE: Guid = Guid(
+        0x8BE4DF61,
+        0x93CA,
+        0x11D2,
+        [0xAA, 0x0D, 0x00, 0xE0, 0x98, 0x03, 0x2B, 0x8C],
+    );
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    _pad1: u8,
+    pub nanosecond: u32,
+    pub time_zone: i16,
+    pub daylight: u8,
+    _pad2: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(u32)]
+pub enum ResetType {
+    Cold = 0,
+    Shutdown = 2,
+}
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    _reserved: u32,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    hdr: TableHeader,
+    firmware_vendor: u64,
+    firmware_revision: u32,
+    console_in_handle: u64,
+    con_in: u64,
+    console_out_handle: u64,
+    con_out: u64,
+    standard_error_handle: u64,
+    std_err: u64,
+    runtime_services: u64,
+    boot_services: u64,
+    number_of_table_entries: usize,
+    configuration_table: u64,
+}
+
+#[repr(C)]
+struct RuntimeServices {
+    hdr: TableHeader,
+    get_time: extern "efiapi" fn(*mut Time, *mut u8) -> Status,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable:
+        extern "efiapi" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut u8) -> Status,
+    get_next_variable_name: usize,
+    set_variable: usize,
+    get_next_high_monotonic_count: usize,
+    reset_system: extern "efiapi" fn(ResetType, Status, usize, *const u8) -> !,
+}
+
+impl SystemTable {
+    pub fn revision(&self) -> (u16, u16) {
+        ((self.hdr.revision >> 16) as u16, self.hdr.revision as u16)
+    }
+
+    pub fn firmware_revision(&self) -> u32 {
+        self.firmware_revision
+    }
+
+    /// The firmware vendor, a null terminated UCS-2 string
+    pub fn firmware_vendor(&self) -> String {
+        if self.firmware_vendor == 0 {
+            return String::new();
+        }
+
+        let vendor = PhysAddr::new(self.firmware_vendor)
+            .as_hhdm()
+            .as_ptr::<u16>();
+        let len = (0..256)
+            .take_while(|&i| unsafe { *vendor.add(i) } != 0)
+            .count();
+
+        String::from_utf16_lossy(unsafe { core::slice::from_raw_parts(vendor, len) })
+    }
+}
+
+pub fn system_table() -> Option<&'static SystemTable> {
+    let response = EFI_SYSTEM_TABLE_REQ.get_response().get()?;
+    let address = response.address.as_ptr()? as u64;
+
+    // Older revisions of the protocol hand out a HHDM address, newer ones a physical one
+    let hhdm = PhysAddr::new(0).as_hhdm().as_u64();
+    let address = if address >= hhdm {
+        VirtAddr::new(address)
+    } else {
+        PhysAddr::new(address).as_hhdm()
+    };
+
+    Some(unsafe { &*address.as_ptr() })
+}
+
+/// Runs `f` with exclusive access to the runtime services, if they're usable
+fn with_runtime<R>(f: impl FnOnce(&RuntimeServices) -> R) -> Result<R, Status> {
+    RUNTIME.lock().map(f).ok_or(Status::UNSUPPORTED)
+}
+
+pub fn get_time() -> Result<Time, Status> {
+    let mut time = Time::default();
+
+    with_runtime(|rt| (rt.get_time)(&mut time, core::ptr::null_mut()))?.result()?;
+    Ok(time)
+}
+
+/// Reads the variable `name` of `vendor`, returning its attributes and contents
+pub fn get_variable(name: &str, vendor: &Guid) -> Result<(u32, Vec<u8>), Status> {
+    let name: Vec<u16> = name.encode_utf16().chain(core::iter::once(0)).collect();
+
+    let mut attributes = 0;
+    let mut data = Vec::new();
+    let mut size = 0;
+
+    // The first call tells us how big the buffer should be
+    loop {
+        let status = with_runtime(|rt| {
+            (rt.get_variable)(
+                name.as_ptr(),
+                vendor,
+                &mut attributes,
+                &mut size,
+                data.as_mut_ptr(),
+            )
+        })?;
+
+        match status {
+            Status::BUFFER_TOO_SMALL => data.resize(size, 0),
+            status => {
+                status.result()?;
+                data.truncate(size);
+                return Ok((attributes, data));
+            }
+        }
+    }
+}
+
+/// Resets the machine through the firmware, returns only if runtime services aren't usable
+pub fn reset(kind: ResetType) {
+    let _ = with_runtime(|rt| {
+        log::debug!("Resetting through EFI ({kind:?})");
+        (rt.reset_system)(kind, Status::SUCCESS, 0, core::ptr::null())
+    });
+}
+
+pub fn init() {
+    log::trace!("Initializing EFI runtime services");
+
+    let Some(system_table) = system_table() else {
+        log::debug!("Not booted through UEFI");
+        return;
+    };
+
+    let (major, minor) = system_table.revision();
+    log::info!(
+        "UEFI {major}.{minor}, firmware {} rev {:#x}",
+        system_table.firmware_vendor(),
+        system_table.firmware_revision()
+    );
+
+    let address = system_table.runtime_services;
+    if address == 0 || address >= IDENTITY_MAP_END {
+        log::warn!("EFI runtime services at {address:#x} aren't identity mapped, not using them");
+        return;
+    }
+
+    let runtime: &'static RuntimeServices = unsafe { &*PhysAddr::new(address).as_hhdm().as_ptr() };
+
+    let entries = [
+        runtime.get_time as usize,
+        runtime.get_variable as usize,
+        runtime.reset_system as usize,
+    ];
+    if entries
+        .iter()
+        .any(|&entry| entry as u64 >= IDENTITY_MAP_END)
+    {
+        log::warn!("EFI runtime services code isn't identity mapped, not using them");
+        return;
+    }
+
+    *RUNTIME.lock() = Some(runtime);
+}
+
+initcall!(efi, init, []);
@@ -0,0 +1,77 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use core::fmt;
+
+/// Why something in the kernel couldn't be done, for code that doesn't have a more specific error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KError {
+    /// The firmware or the bootloader didn't hand us this
+    NotFound(&'static str),
+    /// This is there, but makes no sense
+    Invalid(&'static str),
+    /// This is there, but works in a way we can't drive
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KError::NotFound(what) => write!(f, "no {what}"),
+            KError::Invalid(what) => write!(f, "invalid {what}"),
+            KError::Unsupported(what) => write!(f, "unsupported {what}"),
+        }
+    }
+}
+
+/// What a subsystem failing to come up means for the rest of the boot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Nothing useful can run without it
+    Fatal,
+    /// The kernel gets by without it, with less
+    Degrade,
+}
+
+/// Brings `subsystem` up with `init`, panicking or carrying on without it as `policy` says.
+/// Returns whether it came up
+pub fn init(subsystem: &str, policy: Policy, init: fn() -> Result<(), KError>) -> bool {
+    let result = init();
+    check(subsystem, policy, result);
+
+    result.is_ok()
+}
+
+/// Panics or warns about `subsystem` failing, as `policy` says
+pub fn check(subsystem: &str, policy: Policy, result: Result<(), KError>) {
+    let Err(error) = result else {
+        return;
+    };
+
+    match policy {
+        Policy::Fatal => panic!("{subsystem}: {error}, can't go on"),
+        Policy::Degrade => log::warn!("{subsystem}: {error}, carrying on without it"),
+    }
+}
+
+ktest! {
+    fn degraded_init_carries_on() {
+        assert!(init("test", Policy::Fatal, || Ok(())));
+        assert!(!init("test", Policy::Degrade, || Err(KError::NotFound("test"))));
+    }
+}
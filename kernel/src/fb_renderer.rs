@@ -16,70 +16,574 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::backtrace::{self, Registers};
+use crate::error::KError;
+use crate::font::{Font, GlyphMap};
 use crate::framebuffer::Framebuffer;
+use crate::interrupts::Exception;
+use crate::mm::pmm;
+use crate::sync::Mutex;
+use crate::vga::{self, TextBuffer};
+use crate::{cmdline, efi, logging, modules};
+use alloc::vec;
+use alloc::vec::Vec;
 use core::fmt::{self, Arguments, Write};
 use limine::LimineFramebufferRequest;
-use psf2::Font;
-use spin::Mutex;
 use vte::{Params, Parser, Perform};
 
+/// The console is dark on light, so what terminals call white is drawn in grays to stay readable
+const PALETTE: [u32; 16] = [
+    0x00_00_00, // black
+    0xAA_00_00, // red
+    0x00_AA_00, // green
+    0xFF_9F_06, // yellow
+    0x00_00_AA, // blue
+    0xAA_00_AA, // magenta
+    0x00_AA_AA, // cyan
+    0x55_55_55, // white
+    0x40_40_40, // bright black
+    0xFF_55_55, // bright red
+    0x22_CC_22, // bright green
+    0xE0_B0_00, // bright yellow
+    0x55_55_FF, // bright blue
+    0xFF_55_FF, // bright magenta
+    0x00_CC_CC, // bright cyan
+    0x80_80_80, // bright white
+];
+
+const DEFAULT_FG: u32 = 0xFF_00_00_00;
+const DEFAULT_BG: u32 = 0xFF_FF_FF_FF;
+const PANIC_BG: u32 = 0xFF_80_10_10;
+
+/// Lines kept once they leave the screen
+const SCROLLBACK_LINES: usize = 2000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Default,
+    /// One of the 256 xterm colors
+    Indexed(u8),
+    Rgb(u32),
+}
+
+fn rgb(r: u8, g: u8, b: u8) -> u32 {
+    u32::from_le_bytes([b, g, r, 0xFF])
+}
+
+/// Maps the xterm 256 color palette: the 16 base colors, a 6x6x6 cube and 24 grays
+fn indexed(index: u8) -> u32 {
+    match index {
+        0..=15 => PALETTE[index as usize] | 0xFF_00_00_00,
+        16..=231 => {
+            let level = |n: u8| if n == 0 { 0 } else { 55 + n * 40 };
+            let i = index - 16;
+            rgb(level(i / 36), level(i / 6 % 6), level(i % 6))
+        }
+        232..=255 => {
+            let gray = 8 + (index - 232) * 10;
+            rgb(gray, gray, gray)
+        }
+    }
+}
+
+/// Halfway between `a` and `b`, for dim text
+fn blend(a: u32, b: u32) -> u32 {
+    let [a0, a1, a2, _] = a.to_le_bytes();
+    let [b0, b1, b2, _] = b.to_le_bytes();
+    let mix = |x: u8, y: u8| ((x as u16 + y as u16) / 2) as u8;
+
+    u32::from_le_bytes([mix(a0, b0), mix(a1, b1), mix(a2, b2), 0xFF])
+}
+
+/// The `index`th parameter of a CSI sequence, where 0 or nothing at all mean `default`
+fn param(params: &Params, index: usize, default: usize) -> usize {
+    match params.iter().nth(index).and_then(|p| p.first()) {
+        Some(&value) if value != 0 => value as usize,
+        _ => default,
+    }
+}
+
+/// Reads the rest of a `38;5;n` or `38;2;r;g;b` color
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(Color::Indexed(params.next()?.min(255) as u8)),
+        2 => {
+            let mut channel = || params.next().map(|c| c.min(255) as u8);
+            let (r, g, b) = (channel()?, channel()?, channel()?);
+            Some(Color::Rgb(rgb(r, g, b)))
+        }
+        _ => None,
+    }
+}
+
+/// A glyph on the screen with its colors, packed to keep the scrollback small
+#[derive(Clone, Copy)]
+struct Cell {
+    glyph: u16,
+    fg: [u8; 3],
+    bg: [u8; 3],
+}
+
+impl Cell {
+    fn new(glyph: u16, fg: u32, bg: u32) -> Cell {
+        let pack = |color: u32| {
+            let [b, g, r, _] = color.to_le_bytes();
+            [b, g, r]
+        };
+
+        Cell {
+            glyph,
+            fg: pack(fg),
+            bg: pack(bg),
+        }
+    }
+
+    fn fg(&self) -> u32 {
+        u32::from_le_bytes([self.fg[0], self.fg[1], self.fg[2], 0xFF])
+    }
+
+    fn bg(&self) -> u32 {
+        u32::from_le_bytes([self.bg[0], self.bg[1], self.bg[2], 0xFF])
+    }
+}
+
+/// The text on the screen and the lines that left it, all allocated up front so printing never
+/// has to allocate
+struct Scrollback {
+    columns: usize,
+    rows: usize,
+    /// Ring of `SCROLLBACK_LINES` lines of `columns` cells each
+    lines: Vec<Cell>,
+    first: usize,
+    len: usize,
+    /// What's on the screen, `rows` lines of `columns` cells
+    screen: Vec<Cell>,
+    /// Lines the view is scrolled back by, 0 while following the output
+    view: usize,
+}
+
+impl Scrollback {
+    fn new(columns: usize, rows: usize, blank: Cell) -> Scrollback {
+        Scrollback {
+            columns,
+            rows,
+            lines: vec![blank; SCROLLBACK_LINES * columns],
+            first: 0,
+            len: 0,
+            screen: vec![blank; rows * columns],
+            view: 0,
+        }
+    }
+
+    fn set(&mut self, column: usize, row: usize, cell: Cell) {
+        if column < self.columns && row < self.rows {
+            self.screen[row * self.columns + column] = cell;
+        }
+    }
+
+    /// Moves the top line of the screen into the scrollback and the rest up, leaving a blank
+    /// line at the bottom
+    fn scroll_up(&mut self, blank: Cell) {
+        let columns = self.columns;
+
+        let slot = (self.first + self.len) % SCROLLBACK_LINES;
+        if self.len == SCROLLBACK_LINES {
+            self.first = (self.first + 1) % SCROLLBACK_LINES;
+        } else {
+            self.len += 1;
+        }
+
+        self.lines[slot * columns..(slot + 1) * columns].copy_from_slice(&self.screen[..columns]);
+        self.screen.copy_within(columns.., 0);
+
+        let last = self.screen.len() - columns;
+        self.screen[last..].fill(blank);
+    }
+
+    /// Line `row` of the screen as the current view shows it
+    fn visible(&self, row: usize) -> &[Cell] {
+        let columns = self.columns;
+        let index = self.len - self.view + row;
+
+        if index < self.len {
+            let slot = (self.first + index) % SCROLLBACK_LINES;
+            &self.lines[slot * columns..(slot + 1) * columns]
+        } else {
+            let row = index - self.len;
+            &self.screen[row * columns..(row + 1) * columns]
+        }
+    }
+}
+
 struct Performer<'fb, 'font> {
-    framebuffer: Framebuffer<'fb>,
-    font: Font<&'font [u8]>,
+    /// Only the terminal on the screen has it
+    framebuffer: Option<Framebuffer<'fb>>,
+    /// Likewise, for a console in VGA text mode
+    text: Option<TextBuffer>,
+    /// Glyphs are code page 437 characters rather than the font's, for VGA text mode
+    cp437: bool,
+    font: Font<'font>,
     cursor_x: usize,
     cursor_y: usize,
     offset: (usize, usize),
     max: (usize, usize),
+    /// Colors text is drawn in, resolved from the attributes below
     color: u32,
     bg: u32,
+    fg_color: Color,
+    bg_color: Color,
+    bold: bool,
+    dim: bool,
+    inverse: bool,
+    /// Missing until the heap is up
+    scrollback: Option<Scrollback>,
+    /// Likewise, the font's unicode table gets searched from scratch until then
+    glyphs: Option<GlyphMap>,
+    /// Off for the panic screen, which can't count on the heap
+    may_allocate: bool,
 }
 
 impl<'fb, 'font> Performer<'fb, 'font> {
     pub fn new(
-        framebuffer: Framebuffer<'fb>,
-        font: &'font [u8],
+        framebuffer: Option<Framebuffer<'fb>>,
+        font: Font<'font>,
         offset: (usize, usize),
         max: (usize, usize),
     ) -> Performer<'fb, 'font> {
         Performer {
             framebuffer,
-            font: Font::new(font).unwrap(),
+            text: None,
+            cp437: false,
+            font,
             cursor_x: 0,
             cursor_y: 0,
             offset,
             max,
-            color: 0,
-            bg: !0,
+            color: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            fg_color: Color::Default,
+            bg_color: Color::Default,
+            bold: false,
+            dim: false,
+            inverse: false,
+            scrollback: None,
+            glyphs: None,
+            may_allocate: true,
         }
     }
 
+    fn heap_ready(&self) -> bool {
+        self.may_allocate && pmm::total_pages() != 0
+    }
+
+    fn scrollback(&mut self) -> Option<&mut Scrollback> {
+        if self.scrollback.is_none() && self.heap_ready() {
+            let blank = Cell::new(self.glyph(' '), DEFAULT_FG, DEFAULT_BG);
+            self.scrollback = Some(Scrollback::new(self.columns(), self.rows(), blank));
+        }
+
+        self.scrollback.as_mut()
+    }
+
+    /// The glyph that draws `chr`, the font's replacement one if it has none
+    fn glyph(&mut self, chr: char) -> u16 {
+        if self.cp437 {
+            return vga::encode(chr) as u16;
+        }
+
+        if self.glyphs.is_none() && self.heap_ready() {
+            self.glyphs = Some(GlyphMap::new(&self.font));
+        }
+
+        match &self.glyphs {
+            Some(glyphs) => glyphs.get(chr),
+            None => self
+                .font
+                .lookup(chr)
+                .unwrap_or_else(|| self.font.replacement()) as u16,
+        }
+    }
+
+    /// Moves the view `lines` back into the scrollback, or forward if negative
+    pub fn scroll(&mut self, lines: isize) {
+        let Some(scrollback) = self.scrollback.as_mut() else {
+            return;
+        };
+
+        let view = (scrollback.view as isize + lines).clamp(0, scrollback.len as isize) as usize;
+        if view != scrollback.view {
+            scrollback.view = view;
+            self.redraw();
+        }
+    }
+
+    /// Goes back to the live screen if the view is scrolled back, before anything gets printed
+    fn follow(&mut self) {
+        if self.scrollback.as_ref().is_some_and(|s| s.view != 0) {
+            self.scroll(isize::MIN / 2);
+        }
+    }
+
+    fn redraw(&mut self) {
+        let Some(scrollback) = self.scrollback.take() else {
+            return;
+        };
+
+        let (width, height) = (self.font.width(), self.font.height());
+        for row in 0..scrollback.rows {
+            for (column, cell) in scrollback.visible(row).iter().enumerate() {
+                self.draw(
+                    cell.glyph,
+                    cell.fg(),
+                    cell.bg(),
+                    column * width,
+                    row * height,
+                );
+            }
+        }
+
+        self.scrollback = Some(scrollback);
+    }
+
+    fn update_colors(&mut self) {
+        let mut fg = match self.fg_color {
+            Color::Default => DEFAULT_FG,
+            // Bold brightens the base colors, like on most terminals
+            Color::Indexed(index) if self.bold && index < 8 => indexed(index + 8),
+            Color::Indexed(index) => indexed(index),
+            Color::Rgb(color) => color,
+        };
+        let mut bg = match self.bg_color {
+            Color::Default => DEFAULT_BG,
+            Color::Indexed(index) => indexed(index),
+            Color::Rgb(color) => color,
+        };
+
+        if self.dim {
+            fg = blend(fg, bg);
+        }
+
+        if self.inverse {
+            core::mem::swap(&mut fg, &mut bg);
+        }
+
+        self.color = fg;
+        self.bg = bg;
+    }
+
+    /// Select Graphic Rendition, `CSI ... m`
+    fn sgr(&mut self, params: &Params) {
+        // Colon separated subparameters read the same as semicolon separated ones
+        let mut params = params.iter().flatten().copied().peekable();
+        if params.peek().is_none() {
+            self.reset_attributes();
+        }
+
+        while let Some(param) = params.next() {
+            match param {
+                0 => self.reset_attributes(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                7 => self.inverse = true,
+                22 => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                27 => self.inverse = false,
+                30..=37 => self.fg_color = Color::Indexed((param - 30) as u8),
+                38 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.fg_color = color;
+                    }
+                }
+                39 => self.fg_color = Color::Default,
+                40..=47 => self.bg_color = Color::Indexed((param - 40) as u8),
+                48 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.bg_color = color;
+                    }
+                }
+                49 => self.bg_color = Color::Default,
+                90..=97 => self.fg_color = Color::Indexed((param - 90 + 8) as u8),
+                100..=107 => self.bg_color = Color::Indexed((param - 100 + 8) as u8),
+                // Italics, underline, blinking and the like have no glyphs here
+                _ => {}
+            }
+        }
+
+        self.update_colors();
+    }
+
+    fn columns(&self) -> usize {
+        self.max.0 / self.font.width()
+    }
+
+    fn rows(&self) -> usize {
+        self.max.1 / self.font.height()
+    }
+
+    /// The cursor as a column and row
+    fn cell(&self) -> (usize, usize) {
+        (
+            self.cursor_x / self.font.width(),
+            self.cursor_y / self.font.height(),
+        )
+    }
+
+    /// Moves the cursor to `column`, `row`, clamped to the screen
+    fn move_to(&mut self, column: usize, row: usize) {
+        self.cursor_x = column.min(self.columns() - 1) * self.font.width();
+        self.cursor_y = row.min(self.rows() - 1) * self.font.height();
+    }
+
+    /// Fills `count` cells of `row` from `column` on with the background color
+    fn erase_cells(&mut self, column: usize, row: usize, count: usize) {
+        let (width, height) = (self.font.width(), self.font.height());
+        let count = count.min(self.columns().saturating_sub(column));
+
+        let blank = Cell::new(self.glyph(' '), self.color, self.bg);
+        if let Some(scrollback) = self.scrollback() {
+            for column in column..column + count {
+                scrollback.set(column, row, blank);
+            }
+        }
+
+        if let Some(text) = self.text.as_mut() {
+            for column in column..column + count {
+                text.write(column, row, b' ', vga::attribute(blank.fg(), blank.bg()));
+            }
+        }
+
+        if let Some(framebuffer) = self.framebuffer.as_mut() {
+            framebuffer.fill_rect(
+                self.offset.0 + column * width,
+                self.offset.1 + row * height,
+                count * width,
+                height,
+                self.bg,
+            );
+        }
+    }
+
+    fn erase_rows(&mut self, rows: core::ops::Range<usize>) {
+        let columns = self.columns();
+        for row in rows {
+            self.erase_cells(0, row, columns);
+        }
+    }
+
+    /// Erase in Display, `CSI n J`: after the cursor, before it, or everything
+    fn erase_display(&mut self, mode: usize) {
+        let (column, row) = self.cell();
+        let (columns, rows) = (self.columns(), self.rows());
+
+        match mode {
+            0 => {
+                self.erase_cells(column, row, columns);
+                self.erase_rows(row + 1..rows);
+            }
+            1 => {
+                self.erase_rows(0..row);
+                self.erase_cells(0, row, column + 1);
+            }
+            2 | 3 => self.erase_rows(0..rows),
+            _ => {}
+        }
+    }
+
+    /// Erase in Line, `CSI n K`: after the cursor, before it, or the whole line
+    fn erase_line(&mut self, mode: usize) {
+        let (column, row) = self.cell();
+
+        match mode {
+            0 => self.erase_cells(column, row, self.columns()),
+            1 => self.erase_cells(0, row, column + 1),
+            2 => self.erase_cells(0, row, self.columns()),
+            _ => {}
+        }
+    }
+
+    fn reset_attributes(&mut self) {
+        self.fg_color = Color::Default;
+        self.bg_color = Color::Default;
+        self.bold = false;
+        self.dim = false;
+        self.inverse = false;
+    }
+
     pub fn write_char(&mut self, chr: char, x: usize, y: usize) {
-        let chr = self.font.get_ascii(chr as u8).expect("A");
+        let (color, bg) = (self.color, self.bg);
+        let glyph = self.glyph(chr);
+        self.draw(glyph, color, bg, x, y);
+
+        let (column, row) = (x / self.font.width(), y / self.font.height());
+        if let Some(scrollback) = self.scrollback() {
+            scrollback.set(column, row, Cell::new(glyph, color, bg));
+        }
+    }
+
+    fn draw(&mut self, glyph: u16, color: u32, bg: u32, x: usize, y: usize) {
+        if let Some(text) = self.text.as_mut() {
+            let (column, row) = (x / self.font.width(), y / self.font.height());
+            return text.write(column, row, glyph as u8, vga::attribute(color, bg));
+        }
+
+        let Some(framebuffer) = self.framebuffer.as_mut() else {
+            return;
+        };
+        let Some(glyph) = self.font.glyph(glyph as usize) else {
+            return;
+        };
 
-        for (y_idx, row) in chr.enumerate() {
+        for (y_idx, row) in glyph.rows().enumerate() {
             for (x_idx, pixel) in row.enumerate() {
-                self.framebuffer.write(
+                framebuffer.write(
                     x + x_idx + self.offset.0,
                     y + y_idx + self.offset.1,
-                    self.bg,
+                    if pixel { color } else { bg },
                 );
-                if pixel {
-                    self.framebuffer.write(
-                        x + x_idx + self.offset.0,
-                        y + y_idx + self.offset.1,
-                        self.color,
-                    );
-                }
             }
         }
     }
 
-    pub fn clear(&mut self) {
-        let offset = self.offset;
-        let max = self.max;
+    /// Moves the cursor to the start of the next line, scrolling everything up a line if it's
+    /// on the last one already
+    fn new_line(&mut self) {
+        let height = self.font.height();
+        let rows = self.rows();
+
+        self.cursor_x = 0;
+        if self.cursor_y + height < rows * height {
+            self.cursor_y += height;
+            return;
+        }
+
+        let blank = Cell::new(self.glyph(' '), DEFAULT_FG, DEFAULT_BG);
+        if let Some(scrollback) = self.scrollback() {
+            scrollback.scroll_up(blank);
+        }
+
+        let width = self.columns() * self.font.width();
+        let (x, y) = self.offset;
+        if let Some(framebuffer) = self.framebuffer.as_mut() {
+            framebuffer.scroll_up(x, y, width, rows * height, height, DEFAULT_BG);
+        }
+        if let Some(text) = self.text.as_mut() {
+            text.scroll_up(vga::attribute(DEFAULT_FG, DEFAULT_BG));
+        }
+    }
+
+    /// Draws the terminal from scratch, after it got the screen
+    fn repaint(&mut self) {
+        let ((x, y), (width, height)) = (self.offset, self.max);
+        if let Some(framebuffer) = self.framebuffer.as_mut() {
+            framebuffer.fill_rect(x, y, width + 3, height + 3, DEFAULT_BG);
+        }
+        if let Some(text) = self.text.as_mut() {
+            text.clear(vga::attribute(DEFAULT_FG, DEFAULT_BG));
+        }
 
-        self.framebuffer
-            .clear_part(!0, offset.0, offset.1, max.0 + 3, max.1 + 3);
+        self.redraw();
     }
 }
 
@@ -87,32 +591,25 @@ impl Perform for Performer<'_, '_> {
     fn print(&mut self, chr: char) {
         self.write_char(chr, self.cursor_x, self.cursor_y);
 
-        self.cursor_x += self.font.width() as usize;
-        if self.cursor_x >= self.max.0 {
-            self.cursor_x = 0;
-            self.cursor_y += self.font.height() as usize;
-        }
-
-        if self.cursor_y >= self.max.1 {
-            self.cursor_y = 0;
-            self.cursor_x = 0;
-            self.clear();
+        let width = self.font.width();
+        self.cursor_x += width;
+        if self.cursor_x >= self.columns() * width {
+            self.new_line();
         }
     }
 
     fn execute(&mut self, b: u8) {
         match b {
-            b'\n' => {
-                self.cursor_y += self.font.height() as usize;
-                self.cursor_x = 0;
-
-                if self.cursor_y >= self.max.1 {
-                    self.cursor_y = 0;
-                    self.cursor_x = 0;
-                    self.clear();
-                }
+            b'\n' => self.new_line(),
+            b'\r' => self.cursor_x = 0,
+            // Backspace only moves back, the next character overwrites
+            0x08 => self.cursor_x = self.cursor_x.saturating_sub(self.font.width()),
+            b'\t' => {
+                let (column, row) = self.cell();
+                self.move_to((column / 8 + 1) * 8, row);
             }
-            _ => unimplemented!("Unknown byte: {b:#x}"),
+            // Bells and the rest of the C0 controls do nothing here
+            _ => {}
         }
     }
 
@@ -121,30 +618,26 @@ impl Perform for Performer<'_, '_> {
             return;
         }
 
-        for param in params.iter() {
-            match &param {
-                &[0] => self.color = 0,
-                &[0x1] => {}
-                &[0x25] => {}
-                &[31] => {
-                    self.color = u32::from_le_bytes([0, 0, 170, 255]);
-                }
-                &[32] => self.color = u32::from_le_bytes([0, 170, 0, 255]),
-                &[33] => {
-                    self.color = u32::from_le_bytes([6, 159, 255, 255]);
-                }
-                &[34] => {
-                    self.color = u32::from_le_bytes([170, 0, 0, 255]);
-                }
-                &[35] => {
-                    self.color = u32::from_le_bytes([170, 0, 170, 255]);
-                }
-                x => unimplemented!("Unknown param: {x:#x?}"),
-            }
+        let (column, row) = self.cell();
+
+        match action {
+            'm' => self.sgr(params),
+            // Cursor Position, 1-based row and column
+            'H' | 'f' => self.move_to(param(params, 1, 1) - 1, param(params, 0, 1) - 1),
+            'A' => self.move_to(column, row.saturating_sub(param(params, 0, 1))),
+            'B' => self.move_to(column, row + param(params, 0, 1)),
+            'C' => self.move_to(column + param(params, 0, 1), row),
+            'D' => self.move_to(column.saturating_sub(param(params, 0, 1)), row),
+            // Cursor Horizontal Absolute
+            'G' => self.move_to(param(params, 0, 1) - 1, row),
+            'J' => self.erase_display(param(params, 0, 0)),
+            'K' => self.erase_line(param(params, 0, 0)),
+            _ => {}
         }
     }
 }
 
+/// A terminal: its parser and everything it shows, on the screen only while it's the active one
 pub struct Writer<'fb, 'font> {
     parser: Parser,
     performer: Performer<'fb, 'font>,
@@ -152,8 +645,8 @@ pub struct Writer<'fb, 'font> {
 
 impl<'fb, 'font> Writer<'fb, 'font> {
     pub fn new(
-        framebuffer: Framebuffer<'fb>,
-        font: &'font [u8],
+        framebuffer: Option<Framebuffer<'fb>>,
+        font: Font<'font>,
         offset: (usize, usize),
         max: (usize, usize),
     ) -> Writer<'fb, 'font> {
@@ -162,10 +655,35 @@ impl<'fb, 'font> Writer<'fb, 'font> {
             performer: Performer::new(framebuffer, font, offset, max),
         }
     }
+
+    /// A terminal in the VGA text buffer, `font` only sets the units the cursor moves in
+    pub fn text(text: Option<TextBuffer>, font: Font<'font>) -> Writer<'fb, 'font> {
+        let max = (vga::COLUMNS * font.width(), vga::ROWS * font.height());
+        let mut writer = Writer::new(None, font, (0, 0), max);
+        writer.performer.text = text;
+        writer.performer.cp437 = true;
+
+        writer
+    }
+}
+
+impl Writer<'_, '_> {
+    pub fn flush(&mut self) {
+        if let Some(framebuffer) = self.performer.framebuffer.as_mut() {
+            framebuffer.flush();
+        }
+
+        let (column, row) = self.performer.cell();
+        if let Some(text) = self.performer.text.as_mut() {
+            text.set_cursor(column, row);
+        }
+    }
 }
 
 impl Write for Writer<'_, '_> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.performer.follow();
+
         for c in s.bytes() {
             self.parser.advance(&mut self.performer, c);
         }
@@ -174,41 +692,540 @@ impl Write for Writer<'_, '_> {
     }
 }
 
+/// Terminals multiplexed on the screen, switched with Alt+F1 and up; the log goes to the first
+pub const TERMINALS: usize = 4;
+
+/// Bootloader framebuffers past these are ignored
+pub const MAX_OUTPUTS: usize = 4;
+
+struct Console {
+    /// Only the first exists before the heap, the rest come up the first time they're used
+    terminals: [Option<Writer<'static, 'static>>; TERMINALS],
+    /// Terminals with an output of their own, see `attach_outputs`
+    pinned: [bool; TERMINALS],
+    /// The terminal on the primary output
+    active: usize,
+    /// Terminals are in the VGA text buffer rather than on a framebuffer
+    text: bool,
+    font: Font<'static>,
+    offset: (usize, usize),
+    max: (usize, usize),
+    /// The boot splash image and what goes around it
+    splash: Option<(Framebuffer<'static>, u32)>,
+    /// The screen while the splash covers it, the terminals keep going unseen meanwhile
+    covered: Option<Framebuffer<'static>>,
+}
+
+impl Console {
+    /// A console showing `writer` as its first terminal, the others get made like it
+    fn new(mut writer: Writer<'static, 'static>, font: Font<'static>) -> Console {
+        let text = writer.performer.cp437;
+        let (offset, max) = (writer.performer.offset, writer.performer.max);
+        writer.flush();
+
+        let mut terminals: [Option<Writer>; TERMINALS] = Default::default();
+        terminals[0] = Some(writer);
+
+        Console {
+            terminals,
+            pinned: [false; TERMINALS],
+            active: 0,
+            text,
+            font,
+            offset,
+            max,
+            splash: None,
+            covered: None,
+        }
+    }
+
+    /// Draws the splash on `fb` and keeps it away from the terminals
+    fn cover(&mut self, mut fb: Framebuffer<'static>) {
+        if let Some((image, background)) = &self.splash {
+            fb.clear(*background);
+
+            let x = fb.width().saturating_sub(image.width()) / 2;
+            let y = fb.height().saturating_sub(image.height()) / 2;
+            fb.blit(image, x, y);
+            fb.flush();
+        }
+
+        self.covered = Some(fb);
+    }
+
+    /// Gives the screen back to the active terminal
+    fn uncover(&mut self) {
+        let Some(mut fb) = self.covered.take() else {
+            return;
+        };
+        window(&mut fb);
+
+        let active = self.active;
+        if let Some(writer) = self.terminal(active) {
+            writer.performer.framebuffer = Some(fb);
+            writer.performer.repaint();
+            writer.flush();
+        }
+    }
+
+    /// Takes the screen from the active terminal, if it has it
+    fn take_screen(&mut self) -> Option<Framebuffer<'static>> {
+        let active = self.active;
+        self.terminal(active)?.performer.framebuffer.take()
+    }
+
+    /// Moves `terminal` to an output of its own, drawn by `writer`, the primary output goes to the
+    /// next terminal if it was showing this one
+    fn pin(&mut self, terminal: usize, writer: Writer<'static, 'static>) {
+        let moving = terminal == self.active;
+        let screen = if moving { self.take_screen() } else { None };
+
+        self.terminals[terminal] = Some(writer);
+        self.pinned[terminal] = true;
+
+        if !moving {
+            return;
+        }
+
+        let Some(next) = (0..TERMINALS).find(|&t| !self.pinned[t]) else {
+            return;
+        };
+        self.active = next;
+
+        if let (Some(fb), Some(writer)) = (screen, self.terminal(next)) {
+            writer.performer.framebuffer = Some(fb);
+            writer.performer.repaint();
+            writer.flush();
+        }
+    }
+
+    fn terminal(&mut self, index: usize) -> Option<&mut Writer<'static, 'static>> {
+        let terminal = self.terminals.get_mut(index)?;
+
+        if terminal.is_none() && pmm::total_pages() != 0 {
+            let mut writer = match self.text {
+                true => Writer::text(None, self.font),
+                false => Writer::new(None, self.font, self.offset, self.max),
+            };
+            writer.performer.scrollback();
+            *terminal = Some(writer);
+        }
+
+        terminal.as_mut()
+    }
+}
+
 static FB_INFO: LimineFramebufferRequest = LimineFramebufferRequest::new(0);
-static FONT: &[u8] = include_bytes!("../cozette.psf");
-static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+static BUILTIN_FONT: &[u8] = include_bytes!("../cozette.psf");
+
+/// Picks the font module named by `font=<file name>`, or the one with `font` as its command line,
+/// falling back to the font built into the kernel
+fn font() -> Font<'static> {
+    let builtin = Font::parse(BUILTIN_FONT).unwrap();
+
+    let fonts = modules::all().iter().filter_map(|module| {
+        let path = modules::path(module)?;
+        let font = Font::parse(modules::data(module)?)?;
 
-pub fn init() {
-    let mut fb = {
-        let fb_info = FB_INFO.get_response().get().unwrap();
-        Framebuffer::from_limine(fb_info).unwrap()
+        Some((module, path, font))
+    });
+
+    let chosen = match cmdline::value("font") {
+        Some(name) => {
+            let found = fonts
+                .clone()
+                .find(|&(_, path, _)| {
+                    let file = path.rsplit('/').next().unwrap_or(path);
+                    file == name || file.strip_suffix(".psf") == Some(name)
+                })
+                .map(|(_, _, font)| font);
+
+            if found.is_none() {
+                log::warn!(
+                    "console: no valid PSF font module called {name}, using the built in one"
+                );
+            }
+
+            found
+        }
+        None => fonts
+            .clone()
+            .find(|&(module, _, _)| modules::cmdline(module) == Some("font"))
+            .map(|(_, _, font)| font),
     };
+
+    chosen.unwrap_or(builtin)
+}
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+/// Bootloader framebuffers nothing draws on yet, by their index
+static OUTPUTS: Mutex<[Option<Framebuffer<'static>>; MAX_OUTPUTS]> =
+    Mutex::new([const { None }; MAX_OUTPUTS]);
+
+/// Draws the console window on `fb`, returns where the text goes and how big it can get
+fn window(fb: &mut Framebuffer) -> ((usize, usize), (usize, usize)) {
     fb.clear(0x00_00_80_83);
 
     // Pseudo console window
-    fb.clear_part(0, 100, 100, fb.width() - 200, fb.height() - 200);
-    fb.clear_part(!0, 101, 101, fb.width() - 202, fb.height() - 202);
-    fb.clear_part(0xE0_E0_E0_E0, 102, 102, fb.width() - 204, fb.height() - 204);
-    fb.clear_part(0xE0_E0_E0_E0, 103, 103, fb.width() - 206, fb.height() - 206);
-    fb.clear_part(0xB7_B7_B7_B7, 104, 104, fb.width() - 208, fb.height() - 208);
-    fb.clear_part(0, 105, 105, fb.width() - 210, fb.height() - 210);
-    fb.clear_part(!0, 106, 106, fb.width() - 212, fb.height() - 212);
+    fb.fill_rect(100, 100, fb.width() - 200, fb.height() - 200, 0);
+    fb.fill_rect(101, 101, fb.width() - 202, fb.height() - 202, !0);
+    fb.fill_rect(102, 102, fb.width() - 204, fb.height() - 204, 0xE0_E0_E0_E0);
+    fb.fill_rect(103, 103, fb.width() - 206, fb.height() - 206, 0xE0_E0_E0_E0);
+    fb.fill_rect(104, 104, fb.width() - 208, fb.height() - 208, 0xB7_B7_B7_B7);
+    fb.fill_rect(105, 105, fb.width() - 210, fb.height() - 210, 0);
+    fb.fill_rect(106, 106, fb.width() - 212, fb.height() - 212, !0);
+
+    ((110, 110), (fb.width() - 225, fb.height() - 225))
+}
+
+/// Moves the console to `fb`, e.g. after a display driver took over from the bootloader's one
+///
+/// The terminals survive if the text area stays the same size, they start over otherwise
+pub fn switch(mut fb: Framebuffer<'static>) {
+    let (offset, max) = window(&mut fb);
+
+    // Picking a font can log, which needs the console unlocked
+    let current = CONSOLE.lock().as_ref().map(|console| console.font);
+    let font = current.unwrap_or_else(font);
+
+    let mut console = CONSOLE.lock();
+
+    let same = console
+        .as_ref()
+        .is_some_and(|c| c.offset == offset && c.max == max);
+
+    if !same {
+        let old = console.take();
+        let writer = Writer::new(Some(fb), font, offset, max);
+        let new = console.insert(Console::new(writer, font));
+
+        if let Some(mut old) = old {
+            for terminal in 0..TERMINALS {
+                if let (true, Some(writer)) = (old.pinned[terminal], old.terminals[terminal].take())
+                {
+                    new.pin(terminal, writer);
+                }
+            }
+
+            new.splash = old.splash;
+            if old.covered.is_some() {
+                let fb = new.take_screen().unwrap();
+                new.cover(fb);
+            }
+        }
+
+        return;
+    }
+
+    let console = console.as_mut().unwrap();
+    if console.covered.is_some() {
+        return console.cover(fb);
+    }
+
+    let active = console.active;
+    if let Some(writer) = console.terminal(active) {
+        writer.performer.framebuffer = Some(fb);
+        writer.performer.repaint();
+        writer.flush();
+    }
+}
 
-    let width = fb.width();
-    let height = fb.height();
-    let writer = Writer::new(fb, FONT, (110, 110), (width - 225, height - 225));
-    *WRITER.lock() = Some(writer);
+/// Leaves the machine without a console on screen, the log only goes to the other consoles
+fn headless(reason: &str) {
+    logging::remove_console("fb");
+    log::warn!("console: {reason}, running headless");
+}
+
+/// Brings the console up on the first framebuffer we can draw on and keeps the others for later.
+/// Without one it still falls back to VGA text mode or no console at all, but reports it
+pub fn init() -> Result<(), KError> {
+    if let Some(fb_info) = FB_INFO.get_response().get() {
+        let mut outputs = OUTPUTS.lock();
+        for (output, fb) in outputs.iter_mut().zip(fb_info.framebuffers()) {
+            *output = Framebuffer::from_limine(fb);
+        }
+    }
+
+    let fb = OUTPUTS.lock().iter_mut().find_map(|output| output.take());
+    let Some(mut fb) = fb else {
+        text_mode();
+        return Err(KError::NotFound("usable framebuffer"));
+    };
+
+    let (offset, max) = window(&mut fb);
+    let font = font();
+    *CONSOLE.lock() = Some(Console::new(Writer::new(Some(fb), font, offset, max), font));
+    logging::replay("fb");
+    Ok(())
+}
+
+/// Falls back to the VGA text buffer without a framebuffer, unless we were booted by UEFI, which
+/// leaves no VGA behind, or `novga` was passed
+fn text_mode() {
+    if cmdline::flag("novga") || efi::system_table().is_some() {
+        return headless("no usable framebuffer");
+    }
+
+    let font = font();
+    let mut writer = Writer::text(Some(unsafe { TextBuffer::new() }), font);
+    writer.performer.repaint();
+
+    *CONSOLE.lock() = Some(Console::new(writer, font));
+    logging::replay("fb");
+    log::info!("console: no usable framebuffer, using VGA text mode");
+}
+
+/// Whether there's a console on screen, there's none on headless machines
+pub fn present() -> bool {
+    CONSOLE.lock().is_some()
+}
+
+/// Size of every framebuffer the bootloader set up, usable or not
+pub fn outputs() -> Vec<(usize, usize)> {
+    let Some(fb_info) = FB_INFO.get_response().get() else {
+        return Vec::new();
+    };
+
+    fb_info
+        .framebuffers()
+        .iter()
+        .map(|fb| (fb.width as usize, fb.height as usize))
+        .collect()
+}
+
+/// Takes output `index` for a driver to draw on, if nothing else does
+pub fn take_output(index: usize) -> Option<Framebuffer<'static>> {
+    OUTPUTS.lock().get_mut(index)?.take()
+}
+
+/// Gives terminals an output of their own, as `fbterm=<terminal>:<output>,...`, e.g. `fbterm=0:1`
+/// moves the log to the second screen and leaves the first to the other terminals
+///
+/// Terminals get created on the spot, so the heap has to be up
+pub fn attach_outputs() {
+    let Some(assignments) = cmdline::value("fbterm") else {
+        return;
+    };
+
+    for assignment in assignments.split(',') {
+        let parsed = assignment
+            .split_once(':')
+            .and_then(|(t, o)| Some((t.parse::<usize>().ok()?, o.parse::<usize>().ok()?)));
+
+        let Some((terminal, output)) = parsed.filter(|&(t, _)| t < TERMINALS) else {
+            log::warn!("console: invalid fbterm entry {assignment:?}");
+            continue;
+        };
+
+        let Some(mut fb) = take_output(output) else {
+            log::warn!("console: output {output} isn't available for terminal {terminal}");
+            continue;
+        };
+
+        let (offset, max) = window(&mut fb);
+        {
+            let mut console = CONSOLE.lock();
+            let Some(console) = console.as_mut() else {
+                return;
+            };
+
+            let mut writer = Writer::new(Some(fb), console.font, offset, max);
+            writer.performer.scrollback();
+            writer.flush();
+            console.pin(terminal, writer);
+        }
+
+        log::info!("console: terminal {terminal} on output {output}");
+    }
+}
+
+initcall!(fbterm, attach_outputs, []);
+
+/// Puts terminal `index` on the primary output, if it exists or can be brought up
+///
+/// Called from keyboard interrupts, so it gives up if the console is busy
+pub fn switch_to(index: usize) {
+    let Some(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+    let Some(console) = console.as_mut() else {
+        return;
+    };
+
+    // Pinned terminals are always on their own output
+    if index == console.active
+        || console.pinned.get(index) != Some(&false)
+        || console.terminal(index).is_none()
+    {
+        return;
+    }
+
+    let active = console.active;
+    let (framebuffer, text) = console
+        .terminal(active)
+        .map(|writer| {
+            (
+                writer.performer.framebuffer.take(),
+                writer.performer.text.take(),
+            )
+        })
+        .unwrap_or_default();
+
+    let writer = console.terminal(index).unwrap();
+    writer.performer.framebuffer = framebuffer;
+    writer.performer.text = text;
+    writer.performer.repaint();
+    writer.flush();
+
+    console.active = index;
+}
+
+/// Covers the terminals with `image`, centered on `background`, until `toggle_splash`
+pub fn show_splash(image: Framebuffer<'static>, background: u32) {
+    let mut console = CONSOLE.lock();
+    let Some(console) = console.as_mut() else {
+        return;
+    };
+
+    console.splash = Some((image, background));
+    if let Some(fb) = console.covered.take().or_else(|| console.take_screen()) {
+        console.cover(fb);
+    }
+}
+
+/// Goes from the splash to the terminals and back
+///
+/// Called from keyboard interrupts, so it gives up if the console is busy
+pub fn toggle_splash() {
+    let Some(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+    let Some(console) = console.as_mut() else {
+        return;
+    };
+
+    if console.covered.is_some() {
+        console.uncover();
+    } else if console.splash.is_some() {
+        if let Some(fb) = console.take_screen() {
+            console.cover(fb);
+        }
+    }
+}
+
+/// Takes the whole screen over to show why the kernel died: the panic, the registers if an
+/// exception caused it and a backtrace
+///
+/// The console lock has to be free, see `unlock`
+pub fn panic_screen(info: &core::panic::PanicInfo, exception: Option<Exception>) {
+    let mut console = CONSOLE.lock();
+    let Some(console) = console.as_mut() else {
+        return;
+    };
+
+    // Straight from the terminals, as `terminal` would allocate
+    let active = console.active;
+    let screen = console.covered.take().or_else(|| {
+        console.terminals[active]
+            .as_mut()
+            .and_then(|writer| writer.performer.framebuffer.take())
+    });
+
+    let mut writer = match screen {
+        Some(mut fb) => {
+            fb.clear(PANIC_BG);
+            let (width, height) = (fb.width(), fb.height());
+            let margin = 2 * console.font.width();
+            let size = (
+                width.saturating_sub(2 * margin),
+                height.saturating_sub(2 * margin),
+            );
+
+            Writer::new(Some(fb), console.font, (margin, margin), size)
+        }
+        None => {
+            let text = console.terminals[active]
+                .as_mut()
+                .and_then(|writer| writer.performer.text.take());
+            let Some(text) = text else {
+                return;
+            };
+
+            Writer::text(Some(text), console.font)
+        }
+    };
+    writer.performer.may_allocate = false;
+
+    let [b, g, r, _] = PANIC_BG.to_le_bytes();
+    let _ = write!(
+        writer,
+        "\x1b[38;2;255;255;255;48;2;{r};{g};{b}m\x1b[2J\x1b[H"
+    );
+    let _ = writeln!(writer, "\x1b[1mKernel panic\x1b[22m\n\n{info}\n");
+
+    let start = match exception {
+        Some(exception) => {
+            let _ = writeln!(writer, "{}\n", exception.stack);
+            Registers::from(&exception.stack)
+        }
+        None => Registers::current(),
+    };
+
+    let _ = writeln!(writer, "Backtrace:");
+    backtrace::walk(start, |rip| {
+        let _ = match backtrace::lookup(rip) {
+            Some((name, offset)) => writeln!(writer, "  {rip:016x} {name:#}+{offset:#x}"),
+            None => writeln!(writer, "  {rip:016x} <unknown>"),
+        };
+    });
+
+    writer.flush();
+}
+
+/// Which terminal is on the screen
+pub fn active() -> usize {
+    CONSOLE.lock().as_ref().map_or(0, |console| console.active)
+}
+
+/// Scrolls the terminal on screen a page back into its scrollback, or forward towards the live
+/// output
+///
+/// Called from keyboard interrupts, so it gives up if the console is busy
+pub fn scroll_page(back: bool) {
+    let Some(mut console) = CONSOLE.try_lock() else {
+        return;
+    };
+    let Some(console) = console.as_mut() else {
+        return;
+    };
+    let active = console.active;
+    let Some(writer) = console.terminal(active) else {
+        return;
+    };
+
+    let page = writer.performer.rows().saturating_sub(1).max(1) as isize;
+    writer.performer.scroll(if back { page } else { -page });
+    writer.flush();
 }
 
 pub unsafe fn unlock() {
-    WRITER.force_unlock()
+    CONSOLE.force_unlock()
+}
+
+/// Writes to terminal `index`, shown right away if it's the active one
+pub fn write_to(index: usize, args: Arguments) {
+    let mut console = CONSOLE.lock();
+    let Some(writer) = console.as_mut().and_then(|c| c.terminal(index)) else {
+        return;
+    };
+
+    let _ = writer.write_fmt(args);
+    writer.flush();
 }
 
 #[doc(hidden)]
 pub fn _print(args: Arguments) {
-    let mut w = WRITER.lock();
-    let w = w.as_mut().unwrap();
-    let _ = w.write_fmt(args);
+    write_to(0, args);
 }
 
 #[macro_export]
@@ -0,0 +1,287 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use alloc::vec::Vec;
+
+/// PSF1 header: magic, mode, bytes per glyph
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_MODE_512: u8 = 1 << 0;
+const PSF1_MODE_HAS_TABLE: u8 = 1 << 1;
+const PSF1_MODE_HAS_SEQUENCES: u8 = 1 << 2;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_SEQUENCE_START: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 1 << 0;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_SEQUENCE_START: u8 = 0xFE;
+
+/// Which characters each glyph draws, right after the glyphs
+#[derive(Clone, Copy)]
+enum UnicodeTable<'a> {
+    /// UCS-2 code points
+    Psf1(&'a [u8]),
+    /// UTF-8 characters
+    Psf2(&'a [u8]),
+}
+
+/// A PC Screen Font, version 1 or 2
+#[derive(Clone, Copy)]
+pub struct Font<'a> {
+    glyphs: &'a [u8],
+    count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+    unicode: Option<UnicodeTable<'a>>,
+}
+
+/// The bitmap of a glyph, rows top to bottom, each padded to whole bytes
+pub struct Glyph<'a> {
+    data: &'a [u8],
+    width: usize,
+}
+
+impl Glyph<'_> {
+    pub fn rows(&self) -> impl Iterator<Item = impl Iterator<Item = bool> + '_> + '_ {
+        let bytes_per_row = self.width.div_ceil(8);
+
+        self.data
+            .chunks_exact(bytes_per_row)
+            .map(move |row| (0..self.width).map(move |x| row[x / 8] & (0x80 >> (x % 8)) != 0))
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl<'a> Font<'a> {
+    /// Parses a PSF1 or PSF2 font, checking that every glyph is there
+    pub fn parse(data: &'a [u8]) -> Option<Font<'a>> {
+        let (header_size, count, bytes_per_glyph, width, height, has_table) =
+            if data.len() >= PSF2_HEADER_SIZE && data[..4] == PSF2_MAGIC {
+                let header_size = u32_at(data, 8) as usize;
+                let flags = u32_at(data, 12);
+                let count = u32_at(data, 16) as usize;
+                let bytes_per_glyph = u32_at(data, 20) as usize;
+                let height = u32_at(data, 24) as usize;
+                let width = u32_at(data, 28) as usize;
+                let has_table = flags & PSF2_HAS_UNICODE_TABLE != 0;
+
+                (
+                    header_size,
+                    count,
+                    bytes_per_glyph,
+                    width,
+                    height,
+                    has_table,
+                )
+            } else if data.len() >= PSF1_HEADER_SIZE && data[..2] == PSF1_MAGIC {
+                let mode = data[2];
+                let count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+                let height = data[3] as usize;
+                let has_table = mode & (PSF1_MODE_HAS_TABLE | PSF1_MODE_HAS_SEQUENCES) != 0;
+
+                (PSF1_HEADER_SIZE, count, height, 8, height, has_table)
+            } else {
+                return None;
+            };
+
+        if width == 0 || height == 0 || bytes_per_glyph < width.div_ceil(8) * height {
+            return None;
+        }
+
+        let end = header_size.checked_add(count.checked_mul(bytes_per_glyph)?)?;
+        let table = data.get(end..).unwrap_or_default();
+        let unicode = has_table.then_some(if data[..2] == PSF1_MAGIC {
+            UnicodeTable::Psf1(table)
+        } else {
+            UnicodeTable::Psf2(table)
+        });
+
+        Some(Font {
+            glyphs: data.get(header_size..end)?,
+            count,
+            bytes_per_glyph,
+            width,
+            height,
+            unicode,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn glyph(&self, index: usize) -> Option<Glyph<'a>> {
+        if index >= self.count {
+            return None;
+        }
+
+        let start = index * self.bytes_per_glyph;
+        Some(Glyph {
+            data: &self.glyphs[start..start + self.width.div_ceil(8) * self.height],
+            width: self.width,
+        })
+    }
+
+    /// Every character the unicode table maps to a single glyph, with that glyph's index
+    fn mappings(&self) -> Mappings<'a> {
+        Mappings {
+            table: self.unicode,
+            offset: 0,
+            glyph: 0,
+            in_sequences: false,
+        }
+    }
+
+    /// Finds the glyph of `chr`, going through the whole unicode table if there is one and
+    /// taking ASCII to be at its own code if there isn't
+    pub fn lookup(&self, chr: char) -> Option<usize> {
+        let index = if self.unicode.is_some() {
+            self.mappings().find(|&(c, _)| c == chr)?.1
+        } else if chr.is_ascii() {
+            chr as usize
+        } else {
+            return None;
+        };
+
+        (index < self.count).then_some(index)
+    }
+
+    /// What's drawn for characters the font lacks
+    pub fn replacement(&self) -> usize {
+        self.lookup(char::REPLACEMENT_CHARACTER)
+            .or_else(|| self.lookup('?'))
+            .unwrap_or(0)
+    }
+}
+
+/// Walks a unicode table, skipping the multi character sequences as nothing composes them
+struct Mappings<'a> {
+    table: Option<UnicodeTable<'a>>,
+    offset: usize,
+    glyph: usize,
+    in_sequences: bool,
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = (char, usize);
+
+    fn next(&mut self) -> Option<(char, usize)> {
+        loop {
+            let chr = match self.table? {
+                UnicodeTable::Psf1(data) => {
+                    let bytes = data.get(self.offset..self.offset + 2)?;
+                    self.offset += 2;
+
+                    match u16::from_le_bytes([bytes[0], bytes[1]]) {
+                        PSF1_SEPARATOR => None,
+                        PSF1_SEQUENCE_START => {
+                            self.in_sequences = true;
+                            continue;
+                        }
+                        code => char::from_u32(code as u32),
+                    }
+                }
+                UnicodeTable::Psf2(data) => {
+                    let &lead = data.get(self.offset)?;
+                    let len = match lead {
+                        PSF2_SEPARATOR => 1,
+                        PSF2_SEQUENCE_START => {
+                            self.in_sequences = true;
+                            self.offset += 1;
+                            continue;
+                        }
+                        0xC0..=0xDF => 2,
+                        0xE0..=0xEF => 3,
+                        0xF0..=0xF7 => 4,
+                        _ => 1,
+                    };
+
+                    let bytes = data.get(self.offset..self.offset + len)?;
+                    self.offset += len;
+
+                    if lead == PSF2_SEPARATOR {
+                        None
+                    } else {
+                        // Something that isn't UTF-8 maps nothing, but doesn't end the glyph
+                        match core::str::from_utf8(bytes) {
+                            Ok(s) => s.chars().next(),
+                            Err(_) => continue,
+                        }
+                    }
+                }
+            };
+
+            match chr {
+                // The end of this glyph's entry
+                None => {
+                    self.glyph += 1;
+                    self.in_sequences = false;
+                }
+                Some(chr) if !self.in_sequences => return Some((chr, self.glyph)),
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+/// The unicode table sorted for quick lookups, once there's a heap to keep it in
+pub struct GlyphMap {
+    entries: Vec<(char, u16)>,
+    replacement: u16,
+}
+
+impl GlyphMap {
+    pub fn new(font: &Font) -> GlyphMap {
+        let mut entries: Vec<(char, u16)> = match font.unicode {
+            Some(_) => font
+                .mappings()
+                .filter(|&(_, glyph)| glyph < font.count)
+                .map(|(chr, glyph)| (chr, glyph as u16))
+                .collect(),
+            None => (0..font.count.min(128) as u8)
+                .map(|code| (code as char, code as u16))
+                .collect(),
+        };
+
+        // Stable, so the first glyph listed for a character wins, like `Font::lookup`
+        entries.sort_by_key(|&(chr, _)| chr);
+        entries.dedup_by_key(|&mut (chr, _)| chr);
+
+        GlyphMap {
+            entries,
+            replacement: font.replacement() as u16,
+        }
+    }
+
+    /// The glyph of `chr`, or the replacement one if the font lacks it
+    pub fn get(&self, chr: char) -> u16 {
+        match self.entries.binary_search_by_key(&chr, |&(c, _)| c) {
+            Ok(index) => self.entries[index].1,
+            Err(_) => self.replacement,
+        }
+    }
+}
@@ -16,39 +16,202 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use limine::LimineFramebufferResponse;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use limine::LimineFramebuffer;
+
+/// Pushes the rectangle at `x`, `y` of size `width`, `height` to the screen
+pub type FlushFn = fn(x: usize, y: usize, width: usize, height: usize);
+
+/// Limine's only memory model, direct color
+const MEMORY_MODEL_RGB: u8 = 1;
+
+/// Where each channel sits in a pixel, as `(shift, size)` in bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bytes_per_pixel: usize,
+    pub red: (u8, u8),
+    pub green: (u8, u8),
+    pub blue: (u8, u8),
+}
+
+impl PixelFormat {
+    /// 32 bits with blue in the low byte, which is also how colors are passed around
+    pub const XRGB8888: PixelFormat = PixelFormat {
+        bytes_per_pixel: 4,
+        red: (16, 8),
+        green: (8, 8),
+        blue: (0, 8),
+    };
+
+    /// Converts a `0xAARRGGBB` color to what goes in the framebuffer, in the low bytes
+    fn encode(&self, color: u32) -> u32 {
+        if *self == Self::XRGB8888 {
+            return color;
+        }
+
+        let [b, g, r, _] = color.to_le_bytes();
+        let channel = |value: u8, (shift, size): (u8, u8)| {
+            let value = value as u32;
+            let scaled = if size >= 8 {
+                value << (size - 8)
+            } else {
+                value >> (8 - size)
+            };
+
+            scaled.checked_shl(shift as u32).unwrap_or(0)
+        };
+
+        channel(r, self.red) | channel(g, self.green) | channel(b, self.blue)
+    }
+
+    /// The inverse of `encode`, what's lost to narrower channels stays lost
+    fn decode(&self, pixel: u32) -> u32 {
+        if *self == Self::XRGB8888 {
+            return pixel;
+        }
+
+        let channel = |(shift, size): (u8, u8)| {
+            let mask = 1u32.checked_shl(size as u32).map_or(!0, |bit| bit - 1);
+            let value = pixel.checked_shr(shift as u32).unwrap_or(0) & mask;
+
+            match size {
+                0 => 0,
+                1..=7 => (value * 255 / mask) as u8,
+                _ => (value >> (size - 8)) as u8,
+            }
+        };
+
+        u32::from_le_bytes([
+            channel(self.blue),
+            channel(self.green),
+            channel(self.red),
+            0xFF,
+        ])
+    }
+}
+
+/// The memory behind a framebuffer
+enum Backing<'a> {
+    Borrowed(&'a mut [u8]),
+    /// An off-screen surface
+    Owned(Vec<u8>),
+}
+
+impl Deref for Backing<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Backing::Borrowed(backing) => backing,
+            Backing::Owned(backing) => backing,
+        }
+    }
+}
+
+impl DerefMut for Backing<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Backing::Borrowed(backing) => backing,
+            Backing::Owned(backing) => backing,
+        }
+    }
+}
 
 pub struct Framebuffer<'backing> {
-    backing: &'backing mut [u32],
+    backing: Backing<'backing>,
     width: usize,
-    stride: usize,
+    /// Bytes from the start of a line to the next
+    pitch: usize,
     height: usize,
+    format: PixelFormat,
+    /// Set for framebuffers that aren't scanned out directly, e.g. a virtio-gpu resource
+    flush: Option<FlushFn>,
+    /// Bounding box of the pixels written since the last flush, as `(x0, y0, x1, y1)`
+    dirty: Option<(usize, usize, usize, usize)>,
 }
 
 impl Framebuffer<'static> {
-    pub fn from_limine(response: &LimineFramebufferResponse) -> Option<Framebuffer> {
-        let fbs = response.framebuffers();
-        let fb = fbs.first()?;
+    /// Wraps one of the framebuffers the bootloader set up, if we know how to draw on it
+    pub fn from_limine(fb: &LimineFramebuffer) -> Option<Framebuffer<'static>> {
+        let format = PixelFormat {
+            bytes_per_pixel: fb.bpp as usize / 8,
+            red: (fb.red_mask_shift, fb.red_mask_size),
+            green: (fb.green_mask_shift, fb.green_mask_size),
+            blue: (fb.blue_mask_shift, fb.blue_mask_size),
+        };
 
-        let framebuffer_ptr = fb.address.as_ptr()? as *mut u32;
+        if fb.memory_model != MEMORY_MODEL_RGB
+            || !fb.bpp.is_multiple_of(8)
+            || !(1..=4).contains(&format.bytes_per_pixel)
+        {
+            log::warn!(
+                "Unsupported framebuffer: {} bpp, memory model {}",
+                fb.bpp,
+                fb.memory_model
+            );
+            return None;
+        }
+
+        let framebuffer_ptr = fb.address.as_ptr()?;
         let width = fb.width as usize;
-        let stride = (fb.pitch / 4) as usize;
+        let pitch = fb.pitch as usize;
         let height = fb.height as usize;
 
-        let backing = unsafe { core::slice::from_raw_parts_mut(framebuffer_ptr, stride * height) };
+        let backing = unsafe { core::slice::from_raw_parts_mut(framebuffer_ptr, pitch * height) };
 
         Some(Framebuffer {
-            backing,
+            backing: Backing::Borrowed(backing),
             width,
-            stride,
+            pitch,
             height,
+            format,
+            flush: None,
+            dirty: None,
         })
     }
+
+    /// Wraps memory the caller keeps alive for as long as the framebuffer is in use, laid out as
+    /// `PixelFormat::XRGB8888`
+    pub fn from_raw(
+        backing: &'static mut [u32],
+        width: usize,
+        height: usize,
+        flush: Option<FlushFn>,
+    ) -> Framebuffer<'static> {
+        assert!(backing.len() >= width * height);
+
+        let len = core::mem::size_of_val(backing);
+        let backing = unsafe { core::slice::from_raw_parts_mut(backing.as_mut_ptr().cast(), len) };
+
+        Framebuffer {
+            backing: Backing::Borrowed(backing),
+            width,
+            pitch: width * 4,
+            height,
+            format: PixelFormat::XRGB8888,
+            flush,
+            dirty: None,
+        }
+    }
 }
 
 impl<'backing> Framebuffer<'backing> {
+    /// A surface on the heap to compose something off-screen, then `blit` it somewhere in one go
     pub fn new(width: usize, height: usize) -> Framebuffer<'backing> {
-        todo!()
+        let format = PixelFormat::XRGB8888;
+        let pitch = width * format.bytes_per_pixel;
+
+        Framebuffer {
+            backing: Backing::Owned(vec![0; pitch * height]),
+            width,
+            pitch,
+            height,
+            format,
+            flush: None,
+            dirty: None,
+        }
     }
 }
 
@@ -56,26 +219,151 @@ impl Framebuffer<'_> {
     pub fn width(&self) -> usize {
         self.width
     }
-    pub fn stride(&self) -> usize {
-        self.stride
-    }
     pub fn height(&self) -> usize {
         self.height
     }
 
+    /// Grows the dirty box to cover `x0..x1`, `y0..y1`
+    fn touch(&mut self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        if self.flush.is_some() {
+            self.dirty = Some(match self.dirty {
+                Some((a0, b0, a1, b1)) => (a0.min(x0), b0.min(y0), a1.max(x1), b1.max(y1)),
+                None => (x0, y0, x1, y1),
+            });
+        }
+    }
+
+    /// Stores an already encoded pixel
+    fn put(&mut self, x: usize, y: usize, pixel: u32) {
+        let bytes_per_pixel = self.format.bytes_per_pixel;
+        let offset = y * self.pitch + x * bytes_per_pixel;
+
+        self.backing[offset..offset + bytes_per_pixel]
+            .copy_from_slice(&pixel.to_le_bytes()[..bytes_per_pixel]);
+    }
+
+    /// Loads an encoded pixel
+    fn get(&self, x: usize, y: usize) -> u32 {
+        let bytes_per_pixel = self.format.bytes_per_pixel;
+        let offset = y * self.pitch + x * bytes_per_pixel;
+
+        let mut pixel = [0; 4];
+        pixel[..bytes_per_pixel].copy_from_slice(&self.backing[offset..offset + bytes_per_pixel]);
+        u32::from_le_bytes(pixel)
+    }
+
+    /// Clips a `width` by `height` rectangle at `x`, `y` to the framebuffer
+    fn clip(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        (
+            width.min(self.width.saturating_sub(x)),
+            height.min(self.height.saturating_sub(y)),
+        )
+    }
+
     pub fn write(&mut self, x: usize, y: usize, color: u32) {
-        self.backing[x + y * self.stride] = color;
+        self.put(x, y, self.format.encode(color));
+        self.touch(x, y, x + 1, y + 1);
+    }
+
+    /// Moves the rectangle at `x`, `y` of size `width`, `height` up by `lines` rows of pixels,
+    /// filling the ones left behind at the bottom with `color`
+    pub fn scroll_up(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        lines: usize,
+        color: u32,
+    ) {
+        let lines = lines.min(height);
+
+        self.copy_rect(x, y + lines, width, height - lines, x, y);
+        self.fill_rect(x, y + height - lines, width, lines, color);
+    }
+
+    /// Copies the `width` by `height` rectangle at `from_x`, `from_y` to `x`, `y`, the two can
+    /// overlap
+    pub fn copy_rect(
+        &mut self,
+        from_x: usize,
+        from_y: usize,
+        width: usize,
+        height: usize,
+        x: usize,
+        y: usize,
+    ) {
+        let (width, height) = self.clip(from_x, from_y, width, height);
+        let (width, height) = self.clip(x, y, width, height);
+
+        let bytes_per_pixel = self.format.bytes_per_pixel;
+        let len = width * bytes_per_pixel;
+        let copy_row = |backing: &mut [u8], row: usize| {
+            let source = (from_y + row) * self.pitch + from_x * bytes_per_pixel;
+            let dest = (y + row) * self.pitch + x * bytes_per_pixel;
+            backing.copy_within(source..source + len, dest);
+        };
+
+        // Moving down, the bottom rows go first so they're copied before being overwritten
+        if y > from_y {
+            (0..height)
+                .rev()
+                .for_each(|row| copy_row(&mut self.backing, row));
+        } else {
+            (0..height).for_each(|row| copy_row(&mut self.backing, row));
+        }
+
+        self.touch(x, y, x + width, y + height);
+    }
+
+    /// Draws all of `source` with its top left corner at `x`, `y`, converting the pixels if the
+    /// formats differ
+    pub fn blit(&mut self, source: &Framebuffer, x: usize, y: usize) {
+        let (width, height) = self.clip(x, y, source.width, source.height);
+
+        if source.format == self.format {
+            let bytes_per_pixel = self.format.bytes_per_pixel;
+            let len = width * bytes_per_pixel;
+
+            for row in 0..height {
+                let from = row * source.pitch;
+                let to = (y + row) * self.pitch + x * bytes_per_pixel;
+                self.backing[to..to + len].copy_from_slice(&source.backing[from..from + len]);
+            }
+        } else {
+            for row in 0..height {
+                for column in 0..width {
+                    let color = source.format.decode(source.get(column, row));
+                    self.put(x + column, y + row, self.format.encode(color));
+                }
+            }
+        }
+
+        self.touch(x, y, x + width, y + height);
     }
 
     pub fn clear(&mut self, color: u32) {
-        self.backing.fill(color);
+        self.fill_rect(0, 0, self.width, self.height, color);
     }
 
-    pub fn clear_part(&mut self, color: u32, x: usize, y: usize, width: usize, height: usize) {
-        for cy in 0..height {
-            for cx in 0..width {
-                self.write(cx + x, cy + y, color);
+    /// Pushes whatever changed since the last call to the screen, if it isn't there already
+    pub fn flush(&mut self) {
+        if let (Some(flush), Some((x0, y0, x1, y1))) = (self.flush, self.dirty.take()) {
+            flush(x0, y0, x1 - x0, y1 - y0);
+        }
+    }
+
+    /// Fills the `width` by `height` rectangle at `x`, `y` with `color`, clipped to the framebuffer
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        let (width, height) = self.clip(x, y, width, height);
+        let pixel = self.format.encode(color);
+
+        for cy in y..y + height {
+            for cx in x..x + width {
+                self.put(cx, cy, pixel);
             }
         }
+
+        self.touch(x, y, x + width, y + height);
     }
 }
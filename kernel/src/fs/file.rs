@@ -0,0 +1,233 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{DirEntry, Error, FileType, Inode, Metadata};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// How a file is opened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Creates the file if it doesn't exist
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Empties the file on open
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Every write goes at the end of the file
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+    /// Fails unless the path is a directory
+    pub const DIRECTORY: OpenFlags = OpenFlags(1 << 5);
+
+    pub const fn from_bits(bits: u32) -> OpenFlags {
+        OpenFlags(bits)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(&self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Whence {
+    Start,
+    Current,
+    End,
+}
+
+/// An open file, what a handle refers to
+pub struct File {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    /// Byte offset for files, index of the next entry for directories
+    offset: u64,
+}
+
+impl File {
+    pub fn open(path: &str, flags: OpenFlags) -> Result<File, Error> {
+        let inode = match super::lookup(path) {
+            Ok(inode) => inode,
+            Err(Error::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                super::create(path, FileType::File)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        let kind = inode.metadata()?.kind;
+        if flags.contains(OpenFlags::DIRECTORY) && kind != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+
+        if kind == FileType::Directory && flags.contains(OpenFlags::WRITE) {
+            return Err(Error::IsDirectory);
+        }
+
+        if flags.contains(OpenFlags::TRUNCATE) {
+            if !flags.contains(OpenFlags::WRITE) {
+                return Err(Error::PermissionDenied);
+            }
+
+            inode.truncate(0)?;
+        }
+
+        Ok(File {
+            inode,
+            flags,
+            offset: 0,
+        })
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(Error::PermissionDenied);
+        }
+
+        let read = self.inode.read_at(self.offset, buffer)?;
+        self.offset += read as u64;
+
+        Ok(read)
+    }
+
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(Error::PermissionDenied);
+        }
+
+        if self.flags.contains(OpenFlags::APPEND) {
+            self.offset = self.inode.metadata()?.size;
+        }
+
+        let written = self.inode.write_at(self.offset, buffer)?;
+        self.offset += written as u64;
+
+        Ok(written)
+    }
+
+    /// Moves the offset, returns where it ended up
+    pub fn seek(&mut self, offset: i64, whence: Whence) -> Result<u64, Error> {
+        let base = match whence {
+            Whence::Start => 0,
+            Whence::Current => self.offset,
+            Whence::End => self.inode.metadata()?.size,
+        };
+
+        self.offset = base
+            .checked_add_signed(offset)
+            .ok_or(Error::InvalidArgument)?;
+
+        Ok(self.offset)
+    }
+
+    pub fn stat(&self) -> Result<Metadata, Error> {
+        self.inode.metadata()
+    }
+
+    /// Returns the next entry of a directory, `None` once they are over
+    pub fn readdir(&mut self) -> Result<Option<DirEntry>, Error> {
+        let entry = self.inode.readdir()?.into_iter().nth(self.offset as usize);
+        if entry.is_some() {
+            self.offset += 1;
+        }
+
+        Ok(entry)
+    }
+}
+
+/// Small integers standing for open files, one table per process
+pub type Handle = usize;
+
+/// The open files of a process, closed handles get reused lowest first
+#[derive(Default)]
+pub struct HandleTable {
+    files: Vec<Option<File>>,
+}
+
+impl HandleTable {
+    pub const fn new() -> HandleTable {
+        HandleTable { files: Vec::new() }
+    }
+
+    fn get(&mut self, handle: Handle) -> Result<&mut File, Error> {
+        self.files
+            .get_mut(handle)
+            .and_then(|f| f.as_mut())
+            .ok_or(Error::BadHandle)
+    }
+
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Handle, Error> {
+        let file = File::open(path, flags)?;
+
+        match self.files.iter().position(|f| f.is_none()) {
+            Some(handle) => {
+                self.files[handle] = Some(file);
+                Ok(handle)
+            }
+            None => {
+                self.files.push(Some(file));
+                Ok(self.files.len() - 1)
+            }
+        }
+    }
+
+    pub fn close(&mut self, handle: Handle) -> Result<(), Error> {
+        self.files
+            .get_mut(handle)
+            .and_then(|f| f.take())
+            .ok_or(Error::BadHandle)?;
+
+        // Keep the table as short as its highest open handle
+        while self.files.last().is_some_and(|f| f.is_none()) {
+            self.files.pop();
+        }
+
+        Ok(())
+    }
+
+    pub fn read(&mut self, handle: Handle, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.get(handle)?.read(buffer)
+    }
+
+    pub fn write(&mut self, handle: Handle, buffer: &[u8]) -> Result<usize, Error> {
+        self.get(handle)?.write(buffer)
+    }
+
+    pub fn seek(&mut self, handle: Handle, offset: i64, whence: Whence) -> Result<u64, Error> {
+        self.get(handle)?.seek(offset, whence)
+    }
+
+    pub fn stat(&mut self, handle: Handle) -> Result<Metadata, Error> {
+        self.get(handle)?.stat()
+    }
+
+    pub fn readdir(&mut self, handle: Handle) -> Result<Option<DirEntry>, Error> {
+        self.get(handle)?.readdir()
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ustar;
+use crate::net::ipv4::{Address, Endpoint};
+use crate::net::tftp;
+use crate::{cmdline, modules};
+use alloc::sync::Arc;
+/// Module picked when none has `initramfs` as its command line
+const DEFAULT_MODULE: &str = "/initramfs.tar";
+const DEFAULT_INIT: &str = "/sbin/init";
+
+/// Parses `initramfs=tftp://<server>[:<port>]/<path>`
+fn tftp_source() -> Option<(Endpoint, &'static str)> {
+    let url = cmdline::value("initramfs")?.strip_prefix("tftp://")?;
+    let (host, path) = url.split_once('/')?;
+
+    let (address, port) = match host.split_once(':') {
+        Some((address, port)) => (address, port.parse().ok()?),
+        None => (host, tftp::PORT),
+    };
+
+    Some(((Address::parse(address)?, port), path))
+}
+
+/// Mounts the initramfs module at `/`, unless it's to be fetched over the network
+pub fn init() {
+    if cmdline::value("initramfs").is_some() {
+        return;
+    }
+
+    let modules = modules::all();
+    if modules.is_empty() {
+        log::warn!("initramfs: the bootloader passed no modules");
+        return;
+    }
+
+    let module = modules
+        .iter()
+        .find(|m| modules::cmdline(m) == Some("initramfs"))
+        .or_else(|| {
+            modules
+                .iter()
+                .find(|m| modules::path(m) == Some(DEFAULT_MODULE))
+        });
+
+    let Some(module) = module else {
+        log::warn!(
+            "initramfs: no {DEFAULT_MODULE} among {} modules",
+            modules.len()
+        );
+        return;
+    };
+
+    let Some(archive) = modules::data(module) else {
+        return;
+    };
+
+    mount(archive, modules::path(module).unwrap_or("?"));
+}
+
+initcall!(initramfs, init, []);
+
+/// Downloads the initramfs named by `initramfs=tftp://...` and mounts it at `/`, once the
+/// network drivers are up
+pub fn fetch() {
+    let Some(value) = cmdline::value("initramfs") else {
+        return;
+    };
+
+    let Some((server, path)) = tftp_source() else {
+        log::error!("initramfs: bad source {value}, expected tftp://<server>[:<port>]/<path>");
+        return;
+    };
+
+    log::info!("initramfs: fetching {path} from {}:{}", server.0, server.1);
+
+    match tftp::fetch(server, path) {
+        // Like a module, the archive stays in memory for good
+        Ok(archive) => mount(archive.leak(), value),
+        Err(err) => log::error!("initramfs: download failed ({err})"),
+    }
+}
+
+initcall!(initramfs_fetch, fetch, [drivers, initramfs]);
+
+fn mount(archive: &'static [u8], source: &str) {
+    match ustar::parse(archive) {
+        Ok(fs) => {
+            log::info!("initramfs: {source} ({} KiB)", archive.len() / 1024);
+            let _ = super::mount("/", Arc::new(fs));
+        }
+        Err(err) => {
+            log::error!("initramfs: not a ustar archive ({err:?})");
+            return;
+        }
+    }
+
+    let init = cmdline::value("init").unwrap_or(DEFAULT_INIT);
+    match super::metadata(init) {
+        Ok(metadata) => log::info!("initramfs: init server {init}, {} bytes", metadata.size),
+        Err(_) => log::warn!("initramfs: no init server at {init}"),
+    }
+}
@@ -0,0 +1,502 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use crate::block::{self, RequestQueue};
+use crate::cmdline;
+use crate::sync::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Logical block size, the only one anybody ever writes
+const BLOCK_SIZE: u64 = 2048;
+
+/// The volume descriptors start after 32 KiB of system area
+const FIRST_DESCRIPTOR: u64 = 16;
+
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+/// Directory record flags
+const FLAG_DIRECTORY: u8 = 1 << 1;
+
+/// Bound on chained continuation areas, a broken image could loop forever otherwise
+const MAX_CONTINUATIONS: usize = 16;
+
+/// Rock Ridge SL component flags
+const SL_CONTINUE: u8 = 1 << 0;
+const SL_CURRENT: u8 = 1 << 1;
+const SL_PARENT: u8 = 1 << 2;
+const SL_ROOT: u8 = 1 << 3;
+
+/// A read-only ISO9660 filesystem, with Rock Ridge names, modes and symlinks when present
+pub struct Iso9660 {
+    root: Arc<Node>,
+}
+
+struct Volume {
+    queue: Arc<RequestQueue>,
+    /// Set if the root carries a SUSP `SP` entry advertising Rock Ridge
+    rock_ridge: bool,
+    /// Bytes to skip at the start of every system use area, from the `SP` entry
+    susp_skip: usize,
+}
+
+struct Node {
+    volume: Arc<Volume>,
+    kind: FileType,
+    mode: u32,
+    inode: u64,
+    extent: u32,
+    size: u32,
+    /// Target of a Rock Ridge symlink, read back as its contents
+    target: Vec<u8>,
+    /// Filled on the first lookup or readdir
+    children: Mutex<Option<BTreeMap<String, Arc<Node>>>>,
+}
+
+/// A directory record, borrowed from the sector holding it
+struct Record<'a> {
+    extent: u32,
+    size: u32,
+    flags: u8,
+    name: &'a [u8],
+    system_use: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Record<'a>> {
+        let len = *bytes.first()? as usize;
+        if len < 34 || len > bytes.len() {
+            return None;
+        }
+
+        let name_len = bytes[32] as usize;
+        // The name is padded to an even offset, the system use area follows
+        let system_use = 33 + name_len + (1 - name_len % 2);
+
+        Some(Record {
+            extent: u32::from_le_bytes(bytes[2..6].try_into().unwrap()),
+            size: u32::from_le_bytes(bytes[10..14].try_into().unwrap()),
+            flags: bytes[25],
+            name: bytes.get(33..33 + name_len)?,
+            system_use: bytes.get(system_use.min(len)..len)?,
+        })
+    }
+
+    fn is_directory(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    /// `.` and `..` are stored as the single bytes 0 and 1
+    fn is_special(&self) -> bool {
+        matches!(self.name, [0] | [1])
+    }
+
+    /// The plain ISO9660 name, without the `;1` version and the trailing dot of extensionless files
+    fn iso_name(&self) -> String {
+        let name = String::from_utf8_lossy(self.name);
+        let name = name.split(';').next().unwrap_or("");
+        let name = name.strip_suffix('.').unwrap_or(name);
+
+        name.to_ascii_lowercase()
+    }
+}
+
+/// What the Rock Ridge entries of a record have to say about it
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u32>,
+    symlink: Option<Vec<u8>>,
+    /// Extent of a directory that was moved elsewhere to dodge the depth limit
+    child_link: Option<u32>,
+    /// This is the moved directory, which only shows up through its child link
+    relocated: bool,
+}
+
+impl Volume {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::cache::read(&self.queue, offset, buffer).map_err(|_| Error::Io)
+    }
+
+    fn read_extent(&self, extent: u32, size: u32) -> Result<Vec<u8>, Error> {
+        let mut data = alloc::vec![0; size as usize];
+        self.read(extent as u64 * BLOCK_SIZE, &mut data)?;
+        Ok(data)
+    }
+
+    /// Walks the SUSP entries of a system use area, following continuation areas
+    fn rock_ridge(&self, system_use: &[u8]) -> Result<RockRidge, Error> {
+        let mut rr = RockRidge::default();
+        if !self.rock_ridge {
+            return Ok(rr);
+        }
+
+        let mut area = system_use.get(self.susp_skip..).unwrap_or(&[]).to_vec();
+        let mut symlink_continues = false;
+
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut offset = 0;
+
+            while offset + 4 <= area.len() {
+                let len = area[offset + 2] as usize;
+                if len < 4 || offset + len > area.len() {
+                    break;
+                }
+
+                let entry = &area[offset..offset + len];
+                offset += len;
+
+                match &entry[..2] {
+                    b"NM" if len >= 5 => {
+                        let name = rr.name.get_or_insert_with(String::new);
+                        name.push_str(&String::from_utf8_lossy(&entry[5..]));
+                    }
+                    b"PX" if len >= 8 => {
+                        let mode = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+                        rr.mode = Some(mode & 0o7777);
+                    }
+                    b"SL" if len >= 5 => {
+                        let target = rr.symlink.get_or_insert_with(Vec::new);
+                        symlink_continues =
+                            symlink_components(&entry[5..], target, symlink_continues);
+                    }
+                    b"CL" if len >= 8 => {
+                        rr.child_link = Some(u32::from_le_bytes(entry[4..8].try_into().unwrap()));
+                    }
+                    b"RE" => rr.relocated = true,
+                    b"CE" if len >= 28 => {
+                        let block = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+                        let offset = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+                        let size = u32::from_le_bytes(entry[20..24].try_into().unwrap());
+                        continuation = Some((block, offset, size));
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+            }
+
+            let Some((block, offset, size)) = continuation else {
+                break;
+            };
+
+            area = alloc::vec![0; size as usize];
+            self.read(block as u64 * BLOCK_SIZE + offset as u64, &mut area)?;
+        }
+
+        Ok(rr)
+    }
+}
+
+/// Appends the components of an `SL` entry to `target`, returns whether the last one continues
+fn symlink_components(mut data: &[u8], target: &mut Vec<u8>, mut continues: bool) -> bool {
+    while data.len() >= 2 {
+        let (flags, len) = (data[0], data[1] as usize);
+        let Some(content) = data.get(2..2 + len) else {
+            break;
+        };
+        data = &data[2 + len..];
+
+        if !continues && !target.is_empty() && target != b"/" {
+            target.push(b'/');
+        }
+
+        match flags {
+            f if f & SL_ROOT != 0 => {
+                target.clear();
+                target.push(b'/');
+            }
+            f if f & SL_CURRENT != 0 => target.push(b'.'),
+            f if f & SL_PARENT != 0 => target.extend_from_slice(b".."),
+            _ => target.extend_from_slice(content),
+        }
+
+        continues = flags & SL_CONTINUE != 0;
+    }
+
+    continues
+}
+
+impl Node {
+    /// Builds the node for `record` along with the name it's listed under
+    fn new(
+        volume: Arc<Volume>,
+        record: &Record,
+        inode: u64,
+    ) -> Result<Option<(String, Node)>, Error> {
+        let rr = volume.rock_ridge(record.system_use)?;
+        if rr.relocated {
+            return Ok(None);
+        }
+
+        let (kind, extent, size) = if let Some(extent) = rr.child_link {
+            // The real directory lives elsewhere, its size is in its own `.` record
+            let first = volume.read_extent(extent, BLOCK_SIZE as u32)?;
+            let dot = Record::parse(&first).ok_or(Error::Io)?;
+            (FileType::Directory, extent, dot.size)
+        } else if rr.symlink.is_some() {
+            (FileType::Symlink, record.extent, 0)
+        } else if record.is_directory() {
+            (FileType::Directory, record.extent, record.size)
+        } else {
+            (FileType::File, record.extent, record.size)
+        };
+
+        let mode = rr.mode.unwrap_or(match kind {
+            FileType::Directory => 0o555,
+            _ => 0o444,
+        });
+
+        let name = rr.name.unwrap_or_else(|| record.iso_name());
+
+        Ok(Some((
+            name,
+            Node {
+                volume,
+                kind,
+                mode,
+                inode,
+                extent,
+                size,
+                target: rr.symlink.unwrap_or_default(),
+                children: Mutex::new(None),
+            },
+        )))
+    }
+
+    fn children(&self) -> Result<BTreeMap<String, Arc<Node>>, Error> {
+        if self.kind != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+
+        let mut children = self.children.lock();
+        if let Some(children) = children.as_ref() {
+            return Ok(children.clone());
+        }
+
+        let size = (self.size as u64).next_multiple_of(BLOCK_SIZE) as u32;
+        let data = self.volume.read_extent(self.extent, size)?;
+        let mut entries = BTreeMap::new();
+
+        for (index, sector) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            // Records never cross a sector, a zero length pads out the rest of it
+            let mut offset = 0;
+            while let Some(record) = Record::parse(&sector[offset..]) {
+                let position = (self.extent as u64 + index as u64) * BLOCK_SIZE + offset as u64;
+                offset += sector[offset] as usize;
+
+                if record.is_special() {
+                    continue;
+                }
+
+                if let Some((name, node)) = Node::new(self.volume.clone(), &record, position)? {
+                    entries.insert(name, Arc::new(node));
+                }
+            }
+        }
+
+        *children = Some(entries.clone());
+        Ok(entries)
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Result<Metadata, Error> {
+        let size = match self.kind {
+            FileType::Symlink => self.target.len() as u64,
+            _ => self.size as u64,
+        };
+
+        Ok(Metadata {
+            kind: self.kind,
+            size,
+            mode: self.mode,
+            inode: self.inode,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        match self.kind {
+            FileType::Directory => Err(Error::IsDirectory),
+            FileType::Symlink => {
+                let start = (offset as usize).min(self.target.len());
+                let len = buffer.len().min(self.target.len() - start);
+                buffer[..len].copy_from_slice(&self.target[start..start + len]);
+
+                Ok(len)
+            }
+            _ => {
+                let start = offset.min(self.size as u64);
+                let len = (buffer.len() as u64).min(self.size as u64 - start) as usize;
+                self.volume
+                    .read(self.extent as u64 * BLOCK_SIZE + start, &mut buffer[..len])?;
+
+                Ok(len)
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.children()?
+            .get(name)
+            .map(|node| node.clone() as Arc<dyn Inode>)
+            .ok_or(Error::NotFound)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, Error> {
+        Ok(self
+            .children()?
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.kind,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
+
+impl FileSystem for Iso9660 {
+    fn name(&self) -> &str {
+        "iso9660"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Finds the primary volume descriptor, `None` if `queue` doesn't hold an ISO9660 image
+fn primary_descriptor(queue: &Arc<RequestQueue>) -> Option<Vec<u8>> {
+    let mut descriptor = alloc::vec![0; BLOCK_SIZE as usize];
+
+    for index in FIRST_DESCRIPTOR.. {
+        queue.read_bytes(index * BLOCK_SIZE, &mut descriptor).ok()?;
+
+        if &descriptor[1..6] != b"CD001" {
+            return None;
+        }
+
+        match descriptor[0] {
+            DESCRIPTOR_PRIMARY => return Some(descriptor),
+            DESCRIPTOR_TERMINATOR => return None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Mounts the ISO9660 image on `queue`, if there is one
+pub fn open(queue: Arc<RequestQueue>) -> Result<Iso9660, Error> {
+    let descriptor = primary_descriptor(&queue).ok_or(Error::Unsupported)?;
+
+    let block_size = u16::from_le_bytes(descriptor[128..130].try_into().unwrap());
+    if block_size as u64 != BLOCK_SIZE {
+        log::warn!("iso9660: logical blocks of {block_size} bytes are not supported");
+        return Err(Error::Unsupported);
+    }
+
+    let record = Record::parse(&descriptor[156..190]).ok_or(Error::Io)?;
+
+    let mut volume = Volume {
+        queue,
+        rock_ridge: false,
+        susp_skip: 0,
+    };
+
+    // Rock Ridge announces itself with an `SP` entry in the `.` record of the root
+    let first = volume.read_extent(record.extent, BLOCK_SIZE as u32)?;
+    if let Some(dot) = Record::parse(&first) {
+        if let [b'S', b'P', 7, 1, 0xBE, 0xEF, skip, ..] = *dot.system_use {
+            volume.rock_ridge = true;
+            volume.susp_skip = skip as usize;
+        }
+    }
+
+    let label = String::from_utf8_lossy(&descriptor[40..72])
+        .trim_end()
+        .to_string();
+    log::info!(
+        "iso9660: volume {label:?}{}",
+        if volume.rock_ridge {
+            ", Rock Ridge"
+        } else {
+            ""
+        }
+    );
+
+    let root = Node {
+        volume: Arc::new(volume),
+        kind: FileType::Directory,
+        mode: 0o555,
+        inode: record.extent as u64 * BLOCK_SIZE,
+        extent: record.extent,
+        size: record.size,
+        target: Vec::new(),
+        children: Mutex::new(None),
+    };
+
+    Ok(Iso9660 {
+        root: Arc::new(root),
+    })
+}
+
+/// Mounts the first ISO9660 image found on a block device at `/cdrom`, or at `/` if it's the
+/// device named by `root=`, which then replaces the initramfs
+pub fn init() {
+    let root = cmdline::value("root");
+    let mut cdrom = false;
+
+    for device in block::devices() {
+        let name = device.name().to_string();
+        let Some(queue) = block::queue(&name) else {
+            continue;
+        };
+
+        let is_root = root == Some(name.as_str());
+        if !is_root && cdrom {
+            continue;
+        }
+
+        let Ok(fs) = open(queue) else {
+            continue;
+        };
+
+        let path = if is_root {
+            let _ = super::unmount("/");
+            "/"
+        } else {
+            cdrom = true;
+            "/cdrom"
+        };
+
+        log::info!("iso9660: {name} on {path}");
+        if let Err(err) = super::mount(path, Arc::new(fs)) {
+            log::warn!("iso9660: cannot mount {name} on {path}: {err:?}");
+        }
+    }
+}
+
+initcall!(iso9660, init, [drivers, initramfs_fetch]);
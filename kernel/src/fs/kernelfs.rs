@@ -0,0 +1,348 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use crate::acpi::madt;
+use crate::mm::{heap, pmm};
+use crate::sync::Mutex;
+use crate::{block, cmdline, core_locals, driver, initcall, interrupts, logging, net, stack, time};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+
+/// A synthetic file, whose contents are made up every time it's looked up
+struct File {
+    name: &'static str,
+    generate: fn() -> Vec<u8>,
+}
+
+static FILES: Mutex<Vec<File>> = Mutex::new(Vec::new());
+
+/// A read-only filesystem of files describing the running kernel
+pub struct KernelFs {
+    root: Arc<Root>,
+}
+
+struct Root;
+
+/// A snapshot of a file, taken when it was looked up so reads in pieces stay consistent
+struct Node {
+    inode: u64,
+    data: Vec<u8>,
+}
+
+impl Inode for Root {
+    fn metadata(&self) -> Result<Metadata, Error> {
+        Ok(Metadata {
+            kind: FileType::Directory,
+            size: FILES.lock().len() as u64,
+            mode: 0o555,
+            inode: 1,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        let (index, generate) = {
+            let files = FILES.lock();
+            let index = files
+                .iter()
+                .position(|f| f.name == name)
+                .ok_or(Error::NotFound)?;
+
+            (index, files[index].generate)
+        };
+
+        // Generators take their own locks, so they run without the list held
+        Ok(Arc::new(Node {
+            inode: index as u64 + 2,
+            data: generate(),
+        }))
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, Error> {
+        Ok(FILES
+            .lock()
+            .iter()
+            .map(|f| DirEntry {
+                name: f.name.into(),
+                kind: FileType::File,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Result<Metadata, Error> {
+        Ok(Metadata {
+            kind: FileType::File,
+            size: self.data.len() as u64,
+            mode: 0o444,
+            inode: self.inode,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let start = (offset as usize).min(self.data.len());
+        let len = buffer.len().min(self.data.len() - start);
+        buffer[..len].copy_from_slice(&self.data[start..start + len]);
+
+        Ok(len)
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
+
+impl FileSystem for KernelFs {
+    fn name(&self) -> &str {
+        "kernelfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Adds a file to the kernelfs, `generate` runs on every lookup
+pub fn register(name: &'static str, generate: fn() -> Vec<u8>) {
+    let mut files = FILES.lock();
+
+    match files.iter_mut().find(|f| f.name == name) {
+        Some(file) => file.generate = generate,
+        None => files.push(File { name, generate }),
+    }
+}
+
+fn meminfo() -> Vec<u8> {
+    let (cached, dirty) = block::cache::stats();
+    let mut text = String::new();
+
+    let _ = writeln!(text, "total:     {} KiB", pmm::total_pages() * 4);
+    let _ = writeln!(text, "free:      {} KiB", pmm::free_pages() * 4);
+    let _ = writeln!(text, "heap:      {} KiB", heap::used() / 1024);
+    let _ = writeln!(text, "ballooned: {} KiB", pmm::ballooned() * 4);
+    let _ = writeln!(
+        text,
+        "cached:    {} KiB",
+        cached * block::cache::BLOCK_SIZE / 1024
+    );
+    let _ = writeln!(
+        text,
+        "dirty:     {} KiB",
+        dirty * block::cache::BLOCK_SIZE / 1024
+    );
+
+    text.into_bytes()
+}
+
+fn interrupts() -> Vec<u8> {
+    let cores = core_locals::cores_online();
+    let mut text = String::from("vector");
+
+    for core in 0..cores {
+        let _ = write!(text, " {:>10}", alloc::format!("core{core}"));
+    }
+    text.push('\n');
+
+    for vector in 0..=255u8 {
+        let counts: Vec<u64> = (0..cores)
+            .map(|core| interrupts::count(core, vector))
+            .collect();
+
+        if counts.iter().all(|&count| count == 0) {
+            continue;
+        }
+
+        let _ = write!(text, "{vector:#6x}");
+        for count in counts {
+            let _ = write!(text, " {count:>10}");
+        }
+        text.push('\n');
+    }
+
+    text.into_bytes()
+}
+
+fn stacks() -> Vec<u8> {
+    let mut text = String::new();
+
+    stack::for_each(|core, kind, used, intact| {
+        let _ = writeln!(
+            text,
+            "core{core} {:<7} {used:>6} bytes{}",
+            alloc::format!("{kind}"),
+            if intact { "" } else { " overflowed" }
+        );
+    });
+
+    text.into_bytes()
+}
+
+fn initcalls() -> Vec<u8> {
+    let mut text = String::new();
+
+    initcall::for_each(|record| {
+        let _ = write!(
+            text,
+            "{:<16} {:>8} us",
+            record.name,
+            time::ticks_to_us(record.ticks)
+        );
+        let _ = match record.result {
+            Ok(()) => writeln!(text),
+            Err(err) if record.skipped => writeln!(text, " skipped: {err}"),
+            Err(err) => writeln!(text, " failed: {err}"),
+        };
+    });
+
+    text.into_bytes()
+}
+
+fn cpus() -> Vec<u8> {
+    let mut text = String::new();
+    let _ = writeln!(text, "online: {}", core_locals::cores_online());
+
+    for cpu in madt::cpus() {
+        let _ = writeln!(
+            text,
+            "apic {:>3}: {}",
+            cpu.apic_id,
+            if cpu.usable() { "usable" } else { "disabled" }
+        );
+    }
+
+    text.into_bytes()
+}
+
+fn mounts() -> Vec<u8> {
+    let mut text = String::new();
+
+    for (path, fs) in super::mounts() {
+        let _ = writeln!(text, "{path} {fs}");
+    }
+
+    text.into_bytes()
+}
+
+fn block_devices() -> Vec<u8> {
+    let mut text = String::new();
+
+    for device in block::devices() {
+        let _ = writeln!(
+            text,
+            "{} {} sectors of {} bytes{}",
+            device.name(),
+            device.sector_count(),
+            device.sector_size(),
+            if device.read_only() {
+                ", read-only"
+            } else {
+                ""
+            }
+        );
+    }
+
+    text.into_bytes()
+}
+
+fn drivers() -> Vec<u8> {
+    let mut text = String::new();
+
+    for (driver, device) in driver::bound_devices() {
+        let _ = writeln!(text, "{driver} {device}");
+    }
+
+    text.into_bytes()
+}
+
+fn net_devices() -> Vec<u8> {
+    let mut text = String::new();
+
+    for interface in net::interfaces() {
+        let device = interface.device();
+        let stats = interface.stats();
+
+        let _ = writeln!(
+            text,
+            "{} {} mtu {} link {} rx {} packets {} bytes {} dropped tx {} packets {} bytes {} errors",
+            device.name(),
+            device.mac(),
+            device.mtu(),
+            if device.link_up() { "up" } else { "down" },
+            stats.rx_packets.load(Ordering::Relaxed),
+            stats.rx_bytes.load(Ordering::Relaxed),
+            stats.rx_dropped.load(Ordering::Relaxed),
+            stats.tx_packets.load(Ordering::Relaxed),
+            stats.tx_bytes.load(Ordering::Relaxed),
+            stats.tx_errors.load(Ordering::Relaxed),
+        );
+    }
+
+    text.into_bytes()
+}
+
+fn arp() -> Vec<u8> {
+    let mut text = String::new();
+
+    for interface in net::interfaces() {
+        for (address, mac) in interface.arp().entries() {
+            let _ = writeln!(text, "{address} {mac} {}", interface.name());
+        }
+    }
+
+    text.into_bytes()
+}
+
+fn command_line() -> Vec<u8> {
+    let mut text = String::from(cmdline::get());
+    text.push('\n');
+    text.into_bytes()
+}
+
+/// Mounts the kernelfs at `/kernel` with the built-in files
+pub fn init() {
+    register("meminfo", meminfo);
+    register("interrupts", interrupts);
+    register("cpus", cpus);
+    register("stacks", stacks);
+    register("initcalls", initcalls);
+    register("mounts", mounts);
+    register("block", block_devices);
+    register("drivers", drivers);
+    register("net", net_devices);
+    register("arp", arp);
+    register("cmdline", command_line);
+    register("dmesg", logging::history);
+
+    if let Err(err) = super::mount(
+        "/kernel",
+        Arc::new(KernelFs {
+            root: Arc::new(Root),
+        }),
+    ) {
+        log::warn!("kernelfs: cannot mount /kernel: {err:?}");
+    }
+}
+
+initcall!(kernelfs, init, [initramfs]);
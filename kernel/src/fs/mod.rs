@@ -0,0 +1,290 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::sync::Mutex;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub mod file;
+pub mod initramfs;
+pub mod iso9660;
+pub mod kernelfs;
+pub mod ninep;
+pub mod tmpfs;
+pub mod ustar;
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    Exists,
+    NotEmpty,
+    ReadOnly,
+    PermissionDenied,
+    InvalidPath,
+    InvalidArgument,
+    /// The handle doesn't refer to an open file
+    BadHandle,
+    Unsupported,
+    Io,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Metadata {
+    pub kind: FileType,
+    pub size: u64,
+    /// Unix permission bits
+    pub mode: u32,
+    /// Unique within the filesystem
+    pub inode: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+}
+
+/// A file or directory of some filesystem, everything not overridden is unsupported
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, Error>;
+
+    /// Reads from `offset`, returns how many bytes were read, 0 at the end of the file
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::IsDirectory)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// Creates an empty file or directory called `name` in this directory
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Removes `name` from this directory, directories have to be empty
+    fn unlink(&self, _name: &str) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    /// Moves `old` in this directory to `new` in `target`, both on the same filesystem
+    fn rename(&self, _old: &str, _target: &Arc<dyn Inode>, _new: &str) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Lets a filesystem recognize its own inodes, e.g. the target of a rename
+    fn as_any(&self) -> &dyn core::any::Any;
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Short name of the filesystem type, e.g. `9p`
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// Splits `path` into its components, resolving `.` and `..`
+fn components(path: &str) -> Result<Vec<&str>, Error> {
+    if !path.starts_with('/') {
+        return Err(Error::InvalidPath);
+    }
+
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+
+    Ok(components)
+}
+
+/// Returns `path` in canonical form, e.g. `/a/b` for `/a/./c/../b/`
+pub fn normalize(path: &str) -> Result<String, Error> {
+    let components = components(path)?;
+    Ok(alloc::format!("/{}", components.join("/")))
+}
+
+/// Finds the filesystem `path` lives in and how many of its components the mount point takes
+fn mount_for(path: &[&str]) -> Option<(Arc<dyn FileSystem>, usize)> {
+    let mounts = MOUNTS.lock();
+
+    mounts
+        .iter()
+        .filter_map(|mount| {
+            let prefix = components(&mount.path).ok()?;
+            path.starts_with(&prefix)
+                .then(|| (mount.fs.clone(), prefix.len()))
+        })
+        .max_by_key(|&(_, len)| len)
+}
+
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, Error> {
+    let components = components(path)?;
+    let (fs, skip) = mount_for(&components).ok_or(Error::NotFound)?;
+
+    components[skip..]
+        .iter()
+        .try_fold(fs.root(), |inode, name| inode.lookup(name))
+}
+
+/// Splits a path into its parent directory and last component
+fn split(path: &str) -> Result<(Arc<dyn Inode>, String), Error> {
+    let path = normalize(path)?;
+    let (parent, name) = path.rsplit_once('/').unwrap();
+
+    if name.is_empty() {
+        return Err(Error::InvalidPath);
+    }
+
+    let parent = if parent.is_empty() { "/" } else { parent };
+    Ok((lookup(parent)?, name.to_string()))
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, Error> {
+    lookup(path)?.metadata()
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, Error> {
+    lookup(path)?.readdir()
+}
+
+/// Reads the whole file at `path`
+pub fn read(path: &str) -> Result<Vec<u8>, Error> {
+    let inode = lookup(path)?;
+    let mut data = alloc::vec![0; inode.metadata()?.size as usize];
+
+    let mut read = 0;
+    while read < data.len() {
+        match inode.read_at(read as u64, &mut data[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    data.truncate(read);
+    Ok(data)
+}
+
+/// Replaces the contents of the file at `path`, creating it if needed
+pub fn write(path: &str, data: &[u8]) -> Result<(), Error> {
+    let inode = match lookup(path) {
+        Ok(inode) => inode,
+        Err(Error::NotFound) => create(path, FileType::File)?,
+        Err(e) => return Err(e),
+    };
+
+    inode.truncate(0)?;
+
+    let mut written = 0;
+    while written < data.len() {
+        match inode.write_at(written as u64, &data[written..])? {
+            0 => return Err(Error::Io),
+            n => written += n,
+        }
+    }
+
+    Ok(())
+}
+
+pub fn create(path: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+    let (parent, name) = split(path)?;
+    parent.create(&name, kind)
+}
+
+pub fn remove(path: &str) -> Result<(), Error> {
+    let (parent, name) = split(path)?;
+    parent.unlink(&name)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), Error> {
+    let (old_parent, old) = split(from)?;
+    let (new_parent, new) = split(to)?;
+
+    old_parent.rename(&old, &new_parent, &new)
+}
+
+/// Makes `fs` visible at `path`, which doesn't have to exist in the filesystem above
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), Error> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+
+    if mounts.iter().any(|m| m.path == path) {
+        return Err(Error::Exists);
+    }
+
+    log::info!("Mounted {} on {path}", fs.name());
+    mounts.push(Mount { path, fs });
+
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<(), Error> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+
+    let index = mounts
+        .iter()
+        .position(|m| m.path == path)
+        .ok_or(Error::NotFound)?;
+    mounts.remove(index);
+
+    Ok(())
+}
+
+/// Returns every mount point along with its filesystem type
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|m| (m.path.clone(), m.fs.name().to_string()))
+        .collect()
+}
@@ -0,0 +1,584 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Message types
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const VERSION: &str = "9P2000.L";
+const NOFID: u32 = !0;
+const NOTAG: u16 = !0;
+
+/// Room taken by the header of a read or write, what's left of `msize` is payload
+const IO_HEADER_SIZE: u32 = 24;
+
+/// Linux open flags
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_DIRECTORY: u32 = 0o200000;
+
+const GETATTR_BASIC: u64 = 0x7FF;
+const SETATTR_SIZE: u32 = 1 << 3;
+const AT_REMOVEDIR: u32 = 0x200;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Directory entry types
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/// Carries 9P2000.L messages to the server and back, e.g. a virtio-9p device
+pub trait Channel: Send + Sync {
+    /// Largest message the transport can carry
+    fn msize(&self) -> u32;
+
+    /// Sends a T-message and returns the R-message answering it
+    fn rpc(&self, request: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// A T-message being built, every field is little endian
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(kind: u8) -> Message {
+        let tag = if kind == TVERSION { NOTAG } else { 0 };
+
+        let mut message = Message(Vec::new());
+        message.u32(0).u8(kind).u16(tag);
+        message
+    }
+
+    fn u8(&mut self, value: u8) -> &mut Message {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Message {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Message {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Message {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn str(&mut self, value: &str) -> &mut Message {
+        self.u16(value.len() as u16);
+        self.0.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> &mut Message {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        &self.0
+    }
+}
+
+/// Walks the body of an R-message, running off the end is an I/O error
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(Error::Io)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).map_err(|_| Error::Io)
+    }
+
+    /// Skips a qid, returning its type and path
+    fn qid(&mut self) -> Result<(u8, u64), Error> {
+        let kind = self.u8()?;
+        let _version = self.u32()?;
+        Ok((kind, self.u64()?))
+    }
+}
+
+fn errno(code: u32) -> Error {
+    match code {
+        1 | 13 => Error::PermissionDenied,
+        2 => Error::NotFound,
+        17 => Error::Exists,
+        20 => Error::NotDirectory,
+        21 => Error::IsDirectory,
+        30 => Error::ReadOnly,
+        36 | 22 => Error::InvalidPath,
+        39 => Error::NotEmpty,
+        95 => Error::Unsupported,
+        _ => Error::Io,
+    }
+}
+
+struct Client {
+    channel: Arc<dyn Channel>,
+    msize: u32,
+    next_fid: AtomicU32,
+}
+
+impl Client {
+    /// Sends `message` and returns the body of the answer, which has to be of type `kind + 1`
+    fn call(&self, message: &mut Message) -> Result<Vec<u8>, Error> {
+        let expected = message.0[4] + 1;
+        let response = self.channel.rpc(message.finish())?;
+
+        let mut header = Reader {
+            data: &response,
+            pos: 4,
+        };
+
+        match header.u8()? {
+            RLERROR => {
+                header.u16()?;
+                Err(errno(header.u32()?))
+            }
+            kind if kind == expected => Ok(response[7..].to_vec()),
+            _ => Err(Error::Io),
+        }
+    }
+
+    fn fid(&self) -> u32 {
+        self.next_fid.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Walks from `fid` through `names` into a new fid, returns it with the type of what it points at
+    fn walk(&self, fid: u32, names: &[&str]) -> Result<(u32, u8), Error> {
+        let new = self.fid();
+
+        let mut message = Message::new(TWALK);
+        message.u32(fid).u32(new).u16(names.len() as u16);
+        for name in names {
+            message.str(name);
+        }
+
+        let body = self.call(&mut message)?;
+        let mut reader = Reader {
+            data: &body,
+            pos: 0,
+        };
+
+        let count = reader.u16()? as usize;
+        if count < names.len() {
+            // A partial walk doesn't create the new fid
+            return Err(Error::NotFound);
+        }
+
+        let mut kind = 0;
+        for _ in 0..count {
+            kind = reader.qid()?.0;
+        }
+
+        Ok((new, kind))
+    }
+
+    fn clunk(&self, fid: u32) {
+        let _ = self.call(Message::new(TCLUNK).u32(fid));
+    }
+
+    /// Opens a copy of `fid`, the caller clunks it when done
+    fn open(&self, fid: u32, flags: u32) -> Result<u32, Error> {
+        let (clone, _) = self.walk(fid, &[])?;
+
+        match self.call(Message::new(TLOPEN).u32(clone).u32(flags)) {
+            Ok(_) => Ok(clone),
+            Err(e) => {
+                self.clunk(clone);
+                Err(e)
+            }
+        }
+    }
+
+    /// Most data a single read or write can move
+    fn io_size(&self) -> usize {
+        (self.msize - IO_HEADER_SIZE) as usize
+    }
+}
+
+struct NinePInode {
+    client: Arc<Client>,
+    fid: u32,
+}
+
+impl Drop for NinePInode {
+    fn drop(&mut self) {
+        self.client.clunk(self.fid);
+    }
+}
+
+impl NinePInode {
+    fn child(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        let (fid, _) = self.client.walk(self.fid, &[name])?;
+
+        Ok(Arc::new(NinePInode {
+            client: self.client.clone(),
+            fid,
+        }))
+    }
+
+    fn with_open<T>(
+        &self,
+        flags: u32,
+        f: impl FnOnce(u32) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let fid = self.client.open(self.fid, flags)?;
+        let result = f(fid);
+        self.client.clunk(fid);
+        result
+    }
+}
+
+impl Inode for NinePInode {
+    fn metadata(&self) -> Result<Metadata, Error> {
+        let body = self
+            .client
+            .call(Message::new(TGETATTR).u32(self.fid).u64(GETATTR_BASIC))?;
+
+        let mut reader = Reader {
+            data: &body,
+            pos: 0,
+        };
+
+        let _valid = reader.u64()?;
+        let (_, inode) = reader.qid()?;
+        let mode = reader.u32()?;
+        let _uid = reader.u32()?;
+        let _gid = reader.u32()?;
+        let _nlink = reader.u64()?;
+        let _rdev = reader.u64()?;
+        let size = reader.u64()?;
+
+        let kind = match mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFREG => FileType::File,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Other,
+        };
+
+        Ok(Metadata {
+            kind,
+            size,
+            mode: mode & !S_IFMT,
+            inode,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.with_open(O_RDONLY, |fid| {
+            let mut read = 0;
+
+            while read < buffer.len() {
+                let count = (buffer.len() - read).min(self.client.io_size());
+                let body = self.client.call(
+                    Message::new(TREAD)
+                        .u32(fid)
+                        .u64(offset + read as u64)
+                        .u32(count as u32),
+                )?;
+
+                let mut reader = Reader {
+                    data: &body,
+                    pos: 0,
+                };
+                let len = reader.u32()? as usize;
+                let data = reader.take(len.min(count))?;
+
+                buffer[read..read + data.len()].copy_from_slice(data);
+                read += data.len();
+
+                if data.is_empty() {
+                    break;
+                }
+            }
+
+            Ok(read)
+        })
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, Error> {
+        self.with_open(O_WRONLY, |fid| {
+            let mut written = 0;
+
+            for chunk in buffer.chunks(self.client.io_size()) {
+                let body = self.client.call(
+                    Message::new(TWRITE)
+                        .u32(fid)
+                        .u64(offset + written as u64)
+                        .u32(chunk.len() as u32)
+                        .bytes(chunk),
+                )?;
+
+                let count = Reader {
+                    data: &body,
+                    pos: 0,
+                }
+                .u32()? as usize;
+                written += count;
+
+                if count < chunk.len() {
+                    break;
+                }
+            }
+
+            Ok(written)
+        })
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Error> {
+        let mut message = Message::new(TSETATTR);
+        message
+            .u32(self.fid)
+            .u32(SETATTR_SIZE)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u64(size);
+
+        // atime and mtime, seconds and nanoseconds
+        for _ in 0..4 {
+            message.u64(0);
+        }
+
+        self.client.call(&mut message).map(|_| ())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.child(name)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, Error> {
+        self.with_open(O_RDONLY | O_DIRECTORY, |fid| {
+            let mut entries = Vec::new();
+            let mut offset = 0;
+
+            loop {
+                let body = self.client.call(
+                    Message::new(TREADDIR)
+                        .u32(fid)
+                        .u64(offset)
+                        .u32(self.client.io_size() as u32),
+                )?;
+
+                let mut reader = Reader {
+                    data: &body,
+                    pos: 0,
+                };
+                let count = reader.u32()? as usize;
+                if count == 0 {
+                    return Ok(entries);
+                }
+
+                let end = reader.pos + count;
+                while reader.pos < end {
+                    reader.qid()?;
+                    offset = reader.u64()?;
+                    let kind = match reader.u8()? {
+                        DT_DIR => FileType::Directory,
+                        DT_REG => FileType::File,
+                        DT_LNK => FileType::Symlink,
+                        _ => FileType::Other,
+                    };
+                    let name = reader.str()?;
+
+                    if name != "." && name != ".." {
+                        entries.push(DirEntry {
+                            name: name.to_string(),
+                            kind,
+                        });
+                    }
+                }
+            }
+        })
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        match kind {
+            FileType::File => {
+                // Lcreate turns the fid it's given into an open fid of the new file
+                let (fid, _) = self.client.walk(self.fid, &[])?;
+                let result = self.client.call(
+                    Message::new(TLCREATE)
+                        .u32(fid)
+                        .str(name)
+                        .u32(O_RDWR | O_CREAT)
+                        .u32(0o644)
+                        .u32(0),
+                );
+
+                self.client.clunk(fid);
+                result?;
+            }
+            FileType::Directory => {
+                self.client.call(
+                    Message::new(TMKDIR)
+                        .u32(self.fid)
+                        .str(name)
+                        .u32(0o755)
+                        .u32(0),
+                )?;
+            }
+            _ => return Err(Error::Unsupported),
+        }
+
+        self.child(name)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Error> {
+        let unlink = |flags| {
+            self.client
+                .call(Message::new(TUNLINKAT).u32(self.fid).str(name).u32(flags))
+                .map(|_| ())
+        };
+
+        match unlink(0) {
+            Err(Error::IsDirectory) => unlink(AT_REMOVEDIR),
+            result => result,
+        }
+    }
+
+    fn rename(&self, old: &str, target: &Arc<dyn Inode>, new: &str) -> Result<(), Error> {
+        let target = target
+            .as_any()
+            .downcast_ref::<NinePInode>()
+            .ok_or(Error::Unsupported)?;
+
+        self.client
+            .call(
+                Message::new(TRENAMEAT)
+                    .u32(self.fid)
+                    .str(old)
+                    .u32(target.fid)
+                    .str(new),
+            )
+            .map(|_| ())
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
+
+pub struct NinePFs {
+    root: Arc<NinePInode>,
+}
+
+impl FileSystem for NinePFs {
+    fn name(&self) -> &str {
+        "9p"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Negotiates the protocol over `channel` and attaches to `aname` on the server
+pub fn connect(channel: Arc<dyn Channel>, aname: &str) -> Result<NinePFs, Error> {
+    let mut version = Message::new(TVERSION);
+    version.u32(channel.msize()).str(VERSION);
+
+    let mut client = Client {
+        msize: channel.msize(),
+        channel,
+        next_fid: AtomicU32::new(1),
+    };
+
+    let body = client.call(&mut version)?;
+    let mut reader = Reader {
+        data: &body,
+        pos: 0,
+    };
+
+    client.msize = reader.u32()?.min(client.msize);
+    if reader.str()? != VERSION {
+        return Err(Error::Unsupported);
+    }
+
+    let root = 0;
+    client.call(
+        Message::new(TATTACH)
+            .u32(root)
+            .u32(NOFID)
+            .str("root")
+            .str(aname)
+            .u32(0),
+    )?;
+
+    Ok(NinePFs {
+        root: Arc::new(NinePInode {
+            client: Arc::new(client),
+            fid: root,
+        }),
+    })
+}
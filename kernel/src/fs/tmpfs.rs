@@ -0,0 +1,309 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use crate::sync::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A filesystem living entirely on the kernel heap, gone on reboot
+pub struct TmpFs {
+    root: Arc<Node>,
+}
+
+struct Node {
+    kind: FileType,
+    mode: u32,
+    inode: u64,
+    next_inode: Arc<AtomicU64>,
+    /// Shared by the whole filesystem and taken by renames, so two of them never lock a pair
+    /// of directories in opposite order
+    rename_lock: Arc<Mutex<()>>,
+    data: Mutex<Vec<u8>>,
+    children: Mutex<BTreeMap<String, Arc<Node>>>,
+}
+
+impl Node {
+    fn new(kind: FileType, next_inode: Arc<AtomicU64>, rename_lock: Arc<Mutex<()>>) -> Node {
+        Node {
+            kind,
+            mode: if kind == FileType::Directory {
+                0o755
+            } else {
+                0o644
+            },
+            inode: next_inode.fetch_add(1, Ordering::Relaxed),
+            next_inode,
+            rename_lock,
+            data: Mutex::new(Vec::new()),
+            children: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn directory(&self) -> Result<(), Error> {
+        match self.kind {
+            FileType::Directory => Ok(()),
+            _ => Err(Error::NotDirectory),
+        }
+    }
+
+    fn file(&self) -> Result<(), Error> {
+        match self.kind {
+            FileType::Directory => Err(Error::IsDirectory),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `other` is this node or somewhere below it
+    fn contains(&self, other: &Node) -> bool {
+        core::ptr::eq(self, other)
+            || self
+                .children
+                .lock()
+                .values()
+                .any(|child| child.contains(other))
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Result<Metadata, Error> {
+        let size = match self.kind {
+            FileType::Directory => self.children.lock().len(),
+            _ => self.data.lock().len(),
+        };
+
+        Ok(Metadata {
+            kind: self.kind,
+            size: size as u64,
+            mode: self.mode,
+            inode: self.inode,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.file()?;
+        let data = self.data.lock();
+
+        let start = (offset as usize).min(data.len());
+        let len = buffer.len().min(data.len() - start);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, Error> {
+        self.file()?;
+        let mut data = self.data.lock();
+
+        // Writing past the end leaves a hole of zeroes
+        let start = offset as usize;
+        let end = start + buffer.len();
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+
+        data[start..end].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Error> {
+        self.file()?;
+
+        let mut data = self.data.lock();
+        data.resize(size as usize, 0);
+        data.shrink_to_fit();
+
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.directory()?;
+
+        self.children
+            .lock()
+            .get(name)
+            .map(|node| node.clone() as Arc<dyn Inode>)
+            .ok_or(Error::NotFound)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, Error> {
+        self.directory()?;
+
+        Ok(self
+            .children
+            .lock()
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.kind,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        self.directory()?;
+
+        if !matches!(kind, FileType::File | FileType::Directory) {
+            return Err(Error::Unsupported);
+        }
+
+        let mut children = self.children.lock();
+        if children.contains_key(name) {
+            return Err(Error::Exists);
+        }
+
+        let node = Arc::new(Node::new(
+            kind,
+            self.next_inode.clone(),
+            self.rename_lock.clone(),
+        ));
+        children.insert(name.to_string(), node.clone());
+
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Error> {
+        self.directory()?;
+        let mut children = self.children.lock();
+
+        let node = children.get(name).ok_or(Error::NotFound)?;
+        if !node.children.lock().is_empty() {
+            return Err(Error::NotEmpty);
+        }
+
+        children.remove(name);
+        Ok(())
+    }
+
+    fn rename(&self, old: &str, target: &Arc<dyn Inode>, new: &str) -> Result<(), Error> {
+        self.directory()?;
+
+        let target = target
+            .as_any()
+            .downcast_ref::<Node>()
+            .ok_or(Error::Unsupported)?;
+        target.directory()?;
+
+        let _rename = self.rename_lock.lock();
+
+        let node = self
+            .children
+            .lock()
+            .get(old)
+            .cloned()
+            .ok_or(Error::NotFound)?;
+
+        // A directory can't end up inside itself
+        if node.kind == FileType::Directory && node.contains(target) {
+            return Err(Error::InvalidPath);
+        }
+
+        if let Some(existing) = target.children.lock().get(new) {
+            if Arc::ptr_eq(existing, &node) {
+                return Ok(());
+            }
+
+            match (existing.kind, node.kind) {
+                (FileType::Directory, FileType::Directory) => {
+                    if !existing.children.lock().is_empty() {
+                        return Err(Error::NotEmpty);
+                    }
+                }
+                (FileType::Directory, _) => return Err(Error::IsDirectory),
+                (_, FileType::Directory) => return Err(Error::NotDirectory),
+                _ => {}
+            }
+        }
+
+        self.children.lock().remove(old);
+        target.children.lock().insert(new.to_string(), node);
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
+
+impl TmpFs {
+    pub fn new() -> TmpFs {
+        let next_inode = Arc::new(AtomicU64::new(1));
+        let rename_lock = Arc::new(Mutex::new(()));
+
+        TmpFs {
+            root: Arc::new(Node::new(FileType::Directory, next_inode, rename_lock)),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Mounts a fresh tmpfs at `/tmp`, the default place for scratch files
+pub fn init() {
+    if let Err(err) = super::mount("/tmp", Arc::new(TmpFs::new())) {
+        log::warn!("tmpfs: cannot mount /tmp: {err:?}");
+    }
+}
+
+initcall!(tmpfs, init, [initramfs]);
+
+ktest! {
+    fn renames_move_files_between_directories() {
+        use crate::fs;
+
+        fs::mount("/ktest-tmpfs", Arc::new(TmpFs::new())).unwrap();
+        fs::create("/ktest-tmpfs/a", FileType::Directory).unwrap();
+        fs::create("/ktest-tmpfs/b", FileType::Directory).unwrap();
+        fs::write("/ktest-tmpfs/a/file", b"contents").unwrap();
+
+        fs::rename("/ktest-tmpfs/a/file", "/ktest-tmpfs/b/moved").unwrap();
+        assert_eq!(fs::read("/ktest-tmpfs/a/file"), Err(Error::NotFound));
+        assert_eq!(fs::read("/ktest-tmpfs/b/moved").unwrap(), b"contents");
+
+        let entries = fs::read_dir("/ktest-tmpfs/b").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "moved");
+
+        // A directory can't be moved below itself, and only empty ones can be replaced
+        assert_eq!(
+            fs::rename("/ktest-tmpfs/a", "/ktest-tmpfs/a/inner"),
+            Err(Error::InvalidPath)
+        );
+        assert_eq!(
+            fs::rename("/ktest-tmpfs/a", "/ktest-tmpfs/b"),
+            Err(Error::NotEmpty)
+        );
+
+        fs::remove("/ktest-tmpfs/b/moved").unwrap();
+        fs::rename("/ktest-tmpfs/a", "/ktest-tmpfs/b").unwrap();
+        assert_eq!(fs::read_dir("/ktest-tmpfs/a").err(), Some(Error::NotFound));
+
+        fs::unmount("/ktest-tmpfs").unwrap();
+    }
+}
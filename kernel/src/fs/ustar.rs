@@ -0,0 +1,243 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const BLOCK_SIZE: usize = 512;
+
+/// Header fields
+const NAME: core::ops::Range<usize> = 0..100;
+const MODE: core::ops::Range<usize> = 100..108;
+const SIZE: core::ops::Range<usize> = 124..136;
+const TYPE: usize = 156;
+const MAGIC: core::ops::Range<usize> = 257..262;
+const PREFIX: core::ops::Range<usize> = 345..500;
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIRECTORY: u8 = b'5';
+
+/// A read-only filesystem over a ustar archive that stays in memory for good
+pub struct UstarFs {
+    root: Arc<Node>,
+}
+
+struct Node {
+    kind: FileType,
+    mode: u32,
+    inode: u64,
+    data: &'static [u8],
+    children: BTreeMap<String, Arc<Node>>,
+}
+
+/// A node while the archive is being parsed, before it gets frozen into a `Node`
+#[derive(Default)]
+struct Builder {
+    kind: Option<FileType>,
+    mode: u32,
+    data: &'static [u8],
+    children: BTreeMap<String, Builder>,
+}
+
+impl Builder {
+    fn freeze(self, inode: &mut u64) -> Arc<Node> {
+        *inode += 1;
+        let number = *inode;
+
+        Arc::new(Node {
+            kind: self.kind.unwrap_or(FileType::Directory),
+            mode: if self.kind.is_some() {
+                self.mode
+            } else {
+                0o755
+            },
+            inode: number,
+            data: self.data,
+            children: self
+                .children
+                .into_iter()
+                .map(|(name, child)| (name, child.freeze(inode)))
+                .collect(),
+        })
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Result<Metadata, Error> {
+        Ok(Metadata {
+            kind: self.kind,
+            size: self.data.len() as u64,
+            mode: self.mode,
+            inode: self.inode,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        if self.kind == FileType::Directory {
+            return Err(Error::IsDirectory);
+        }
+
+        let start = (offset as usize).min(self.data.len());
+        let len = buffer.len().min(self.data.len() - start);
+        buffer[..len].copy_from_slice(&self.data[start..start + len]);
+
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        if self.kind != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+
+        self.children
+            .get(name)
+            .map(|node| node.clone() as Arc<dyn Inode>)
+            .ok_or(Error::NotFound)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, Error> {
+        if self.kind != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+
+        Ok(self
+            .children
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.kind,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
+
+impl FileSystem for UstarFs {
+    fn name(&self) -> &str {
+        "ustar"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Reads a NUL or space terminated octal field
+fn octal(field: &[u8]) -> Option<u64> {
+    let mut digits = field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ');
+
+    digits.try_fold(0, |value, &digit| match digit {
+        b'0'..=b'7' => Some(value * 8 + (digit - b'0') as u64),
+        _ => None,
+    })
+}
+
+fn string(field: &[u8]) -> &str {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).unwrap_or("")
+}
+
+/// Parses `archive`, which has to outlive the filesystem, like a bootloader module does
+pub fn parse(archive: &'static [u8]) -> Result<UstarFs, Error> {
+    let mut root = Builder {
+        kind: Some(FileType::Directory),
+        mode: 0o755,
+        ..Default::default()
+    };
+
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+
+        // The archive ends with two zero blocks, one is enough to stop
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        if &header[MAGIC] != b"ustar" {
+            return Err(Error::Io);
+        }
+
+        let size = octal(&header[SIZE]).ok_or(Error::Io)? as usize;
+        let data_start = offset + BLOCK_SIZE;
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(Error::Io)?;
+        offset = data_start + size.next_multiple_of(BLOCK_SIZE);
+
+        let kind = match header[TYPE] {
+            TYPE_FILE | TYPE_FILE_OLD => FileType::File,
+            TYPE_DIRECTORY => FileType::Directory,
+            TYPE_SYMLINK => FileType::Symlink,
+            // Hard links, devices, FIFOs and extended headers
+            _ => continue,
+        };
+
+        let prefix = string(&header[PREFIX]);
+        let name = string(&header[NAME]);
+        let path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            alloc::format!("{prefix}/{name}")
+        };
+
+        let components: Vec<&str> = path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect();
+
+        let Some((last, parents)) = components.split_last() else {
+            continue;
+        };
+
+        let parent = parents.iter().fold(&mut root, |dir, name| {
+            dir.children.entry(name.to_string()).or_default()
+        });
+
+        let node = parent.children.entry(last.to_string()).or_default();
+        node.kind = Some(kind);
+        node.mode = octal(&header[MODE]).unwrap_or(0o644) as u32 & 0o7777;
+
+        // Symlinks keep their target in the header, which reads back as their contents
+        node.data = if kind == FileType::Symlink {
+            let target = &header[157..257];
+            &target[..target.iter().position(|&b| b == 0).unwrap_or(100)]
+        } else {
+            data
+        };
+    }
+
+    let mut inode = 0;
+    Ok(UstarFs {
+        root: root.freeze(&mut inode),
+    })
+}
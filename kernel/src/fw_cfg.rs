@@ -0,0 +1,211 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::cpu;
+use crate::driver::{self, Driver, Match, ProbeError};
+use crate::fs::kernelfs;
+use crate::mm::dma::Dma;
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Port I/O interface, the only one on x86
+const PORT_SELECTOR: u16 = 0x510;
+const PORT_DATA: u16 = 0x511;
+const PORT_DMA: u16 = 0x514;
+
+/// Well known items
+const SIGNATURE: u16 = 0x0000;
+const ID: u16 = 0x0001;
+const FILE_DIR: u16 = 0x0019;
+
+const ID_DMA: u32 = 1 << 1;
+
+/// DMA control bits
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_READ: u32 = 1 << 1;
+const DMA_SELECT: u32 = 1 << 3;
+const DMA_ACCESS_SIZE: usize = 16;
+
+const FILE_ENTRY_SIZE: usize = 64;
+const FILE_NAME_SIZE: usize = 56;
+
+static FW_CFG: Mutex<Option<FwCfg>> = Mutex::new(None);
+
+static DRIVER: Driver = Driver {
+    name: "fw_cfg",
+    order: 5,
+    matches: &[Match::AcpiHid("QEMU0002")],
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+struct FwCfg {
+    dma: bool,
+    files: Vec<File>,
+}
+
+/// An entry of the file directory
+#[derive(Clone, Debug)]
+pub struct File {
+    pub name: String,
+    pub size: usize,
+    pub select: u16,
+}
+
+impl FwCfg {
+    fn select(&self, key: u16) {
+        unsafe { cpu::outw(PORT_SELECTOR, key) }
+    }
+
+    fn read_byte(&self) -> u8 {
+        unsafe { cpu::inb(PORT_DATA) }
+    }
+
+    /// Reads the item `key` from the start, a byte at a time or through DMA
+    fn read(&self, key: u16, buffer: &mut [u8]) -> bool {
+        if self.dma {
+            let dma = Dma::new(buffer.len().max(1));
+            let ok = self.transfer(key, DMA_READ, &dma, buffer.len());
+            buffer.copy_from_slice(&dma.as_slice()[..buffer.len()]);
+            return ok;
+        }
+
+        self.select(key);
+        buffer.iter_mut().for_each(|b| *b = self.read_byte());
+        true
+    }
+
+    /// Runs a DMA transfer of `len` bytes of `buffer`, waiting for the host to finish it
+    fn transfer(&self, key: u16, operation: u32, buffer: &Dma, len: usize) -> bool {
+        let access = Dma::new(DMA_ACCESS_SIZE);
+        let control = (key as u32) << 16 | DMA_SELECT | operation;
+        access.write(0, control.to_be());
+        access.write(4, (len as u32).to_be());
+        access.write(8, buffer.phys().as_u64().to_be());
+
+        // Writing the low half of the address starts the transfer
+        let address = access.phys().as_u64();
+        unsafe {
+            cpu::outl(PORT_DMA, ((address >> 32) as u32).to_be());
+            cpu::outl(PORT_DMA + 4, (address as u32).to_be());
+        }
+
+        // The host clears the control field once it's done, leaving only the error bit
+        loop {
+            let control = u32::from_be(access.read(0));
+            if control & !DMA_ERROR == 0 {
+                return control & DMA_ERROR == 0;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    fn read_files(&mut self) {
+        let mut count = [0u8; 4];
+        self.read(FILE_DIR, &mut count);
+        let count = u32::from_be_bytes(count) as usize;
+
+        let mut directory = vec![0u8; 4 + count * FILE_ENTRY_SIZE];
+        self.read(FILE_DIR, &mut directory);
+
+        self.files = directory[4..]
+            .chunks_exact(FILE_ENTRY_SIZE)
+            .map(|entry| {
+                let name = &entry[8..8 + FILE_NAME_SIZE];
+                let len = name.iter().position(|&b| b == 0).unwrap_or(FILE_NAME_SIZE);
+
+                File {
+                    name: String::from_utf8_lossy(&name[..len]).into(),
+                    size: u32::from_be_bytes(entry[0..4].try_into().unwrap()) as usize,
+                    select: u16::from_be_bytes(entry[4..6].try_into().unwrap()),
+                }
+            })
+            .collect();
+    }
+}
+
+/// Every file the host provides, `opt/` ones included
+pub fn files() -> Vec<File> {
+    FW_CFG
+        .lock()
+        .as_ref()
+        .map(|f| f.files.clone())
+        .unwrap_or_default()
+}
+
+/// Reads the whole file called `name`, e.g. one passed with `-fw_cfg name=opt/...`
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    let fw_cfg = FW_CFG.lock();
+    let fw_cfg = fw_cfg.as_ref()?;
+    let file = fw_cfg.files.iter().find(|f| f.name == name)?;
+
+    let mut data = vec![0; file.size];
+    fw_cfg.read(file.select, &mut data).then_some(data)
+}
+
+/// `/kernel/fw_cfg`, the size and name of every file
+fn fw_cfg_file() -> Vec<u8> {
+    let mut out = String::new();
+    for file in files() {
+        let _ = writeln!(out, "{:>10} {}", file.size, file.name);
+    }
+
+    out.into_bytes()
+}
+
+fn probe(_device: &driver::Device) -> Result<(), ProbeError> {
+    let mut fw_cfg = FwCfg {
+        dma: false,
+        files: Vec::new(),
+    };
+
+    let mut signature = [0u8; 4];
+    fw_cfg.read(SIGNATURE, &mut signature);
+    if &signature != b"QEMU" {
+        return Err(ProbeError::Failed("no fw_cfg signature"));
+    }
+
+    let mut id = [0u8; 4];
+    fw_cfg.read(ID, &mut id);
+    fw_cfg.dma = u32::from_le_bytes(id) & ID_DMA != 0;
+    fw_cfg.read_files();
+
+    log::info!(
+        "fw_cfg: {} files{}",
+        fw_cfg.files.len(),
+        if fw_cfg.dma { ", DMA" } else { "" }
+    );
+
+    for file in fw_cfg.files.iter().filter(|f| f.name.starts_with("opt/")) {
+        log::info!("fw_cfg: {} ({} bytes)", file.name, file.size);
+    }
+
+    *FW_CFG.lock() = Some(fw_cfg);
+    kernelfs::register("fw_cfg", fw_cfg_file);
+
+    Ok(())
+}
+
+fn remove(_device: &driver::Device) {
+    FW_CFG.lock().take();
+}
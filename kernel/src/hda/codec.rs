@@ -0,0 +1,325 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Controller, Error};
+use alloc::vec::Vec;
+
+/// Verbs with a 12 bit identifier and 8 bit payload
+const GET_PARAMETER: u32 = 0xF00;
+const GET_CONNECTION_LIST: u32 = 0xF02;
+const SET_CONNECTION_SELECT: u32 = 0x701;
+const SET_POWER_STATE: u32 = 0x705;
+const SET_STREAM_CHANNEL: u32 = 0x706;
+const SET_PIN_CONTROL: u32 = 0x707;
+const SET_EAPD: u32 = 0x70C;
+const GET_CONFIG_DEFAULT: u32 = 0xF1C;
+
+/// Verbs with a 4 bit identifier and 16 bit payload
+const SET_CONVERTER_FORMAT: u32 = 0x2;
+const SET_AMP_GAIN_MUTE: u32 = 0x3;
+
+/// Parameters
+const PARAM_VENDOR_ID: u32 = 0x00;
+const PARAM_NODE_COUNT: u32 = 0x04;
+const PARAM_FUNCTION_GROUP_TYPE: u32 = 0x05;
+const PARAM_WIDGET_CAPS: u32 = 0x09;
+const PARAM_PIN_CAPS: u32 = 0x0C;
+const PARAM_CONNECTION_LIST_LENGTH: u32 = 0x0E;
+const PARAM_OUTPUT_AMP_CAPS: u32 = 0x12;
+
+const FUNCTION_GROUP_AUDIO: u32 = 0x01;
+
+const WIDGET_OUTPUT: u32 = 0x0;
+const WIDGET_MIXER: u32 = 0x2;
+const WIDGET_SELECTOR: u32 = 0x3;
+const WIDGET_PIN: u32 = 0x4;
+
+const WIDGET_CAPS_OUTPUT_AMP: u32 = 1 << 2;
+const PIN_CAPS_OUTPUT: u32 = 1 << 4;
+const PIN_CAPS_EAPD: u32 = 1 << 16;
+
+const PIN_CONTROL_OUT: u32 = 1 << 6;
+const PIN_CONTROL_HEADPHONE: u32 = 1 << 7;
+const EAPD_ENABLE: u32 = 1 << 1;
+
+/// Set amp gain/mute payload bits
+const AMP_OUTPUT: u32 = 1 << 15;
+const AMP_LEFT: u32 = 1 << 13;
+const AMP_RIGHT: u32 = 1 << 12;
+const AMP_MUTE: u32 = 1 << 7;
+
+/// Configuration default fields
+const CONNECTIVITY_NONE: u32 = 0b01;
+const DEVICE_LINE_OUT: u32 = 0x0;
+const DEVICE_SPEAKER: u32 = 0x1;
+const DEVICE_HEADPHONE: u32 = 0x2;
+
+/// Paths through more widgets than this aren't worth following
+const MAX_PATH: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+struct Widget {
+    nid: u8,
+    kind: u32,
+    caps: u32,
+}
+
+/// A route from an output converter to a pin, every widget on the way included
+#[derive(Clone, Debug)]
+pub struct Path {
+    /// Converter first, pin last
+    pub nodes: Vec<u8>,
+    pub amp_steps: Vec<(u8, u32)>,
+}
+
+pub struct Codec {
+    pub address: u8,
+    pub vendor_id: u32,
+    pub path: Option<Path>,
+}
+
+fn verb(codec: u8, nid: u8, verb: u32, payload: u32) -> u32 {
+    (codec as u32) << 28 | (nid as u32) << 20 | verb << 8 | payload
+}
+
+/// Builds a command for the verbs that take a 16 bit payload
+fn verb16(codec: u8, nid: u8, verb: u32, payload: u32) -> u32 {
+    (codec as u32) << 28 | (nid as u32) << 20 | verb << 16 | payload
+}
+
+impl Codec {
+    fn parameter(
+        controller: &mut Controller,
+        codec: u8,
+        nid: u8,
+        param: u32,
+    ) -> Result<u32, Error> {
+        controller.command(verb(codec, nid, GET_PARAMETER, param))
+    }
+
+    fn connections(controller: &mut Controller, codec: u8, nid: u8) -> Result<Vec<u8>, Error> {
+        let length = Self::parameter(controller, codec, nid, PARAM_CONNECTION_LIST_LENGTH)?;
+
+        // Long form entries are 16 bits wide, no codec worth driving here uses them
+        if length & (1 << 7) != 0 {
+            return Ok(Vec::new());
+        }
+
+        let count = length & 0x7F;
+        let mut connections = Vec::new();
+        for offset in (0..count).step_by(4) {
+            let entries = controller.command(verb(codec, nid, GET_CONNECTION_LIST, offset))?;
+            for i in 0..(count - offset).min(4) {
+                connections.push((entries >> (i * 8)) as u8);
+            }
+        }
+
+        Ok(connections)
+    }
+
+    /// Walks the codec's widgets and finds an output path, favouring speakers
+    pub fn probe(controller: &mut Controller, address: u8) -> Result<Codec, Error> {
+        let vendor_id = Self::parameter(controller, address, 0, PARAM_VENDOR_ID)?;
+        let groups = Self::parameter(controller, address, 0, PARAM_NODE_COUNT)?;
+
+        let mut widgets = Vec::new();
+        for group in node_range(groups) {
+            let kind = Self::parameter(controller, address, group, PARAM_FUNCTION_GROUP_TYPE)?;
+            if kind & 0xFF != FUNCTION_GROUP_AUDIO {
+                continue;
+            }
+
+            controller.command(verb(address, group, SET_POWER_STATE, 0))?;
+
+            let nodes = Self::parameter(controller, address, group, PARAM_NODE_COUNT)?;
+            for nid in node_range(nodes) {
+                let caps = Self::parameter(controller, address, nid, PARAM_WIDGET_CAPS)?;
+                widgets.push(Widget {
+                    nid,
+                    kind: (caps >> 20) & 0xF,
+                    caps,
+                });
+            }
+        }
+
+        let mut best: Option<(u32, Path)> = None;
+        for pin in widgets.iter().filter(|w| w.kind == WIDGET_PIN) {
+            let pin_caps = Self::parameter(controller, address, pin.nid, PARAM_PIN_CAPS)?;
+            if pin_caps & PIN_CAPS_OUTPUT == 0 {
+                continue;
+            }
+
+            let config = controller.command(verb(address, pin.nid, GET_CONFIG_DEFAULT, 0))?;
+            if config >> 30 == CONNECTIVITY_NONE {
+                continue;
+            }
+
+            let rank = match (config >> 20) & 0xF {
+                DEVICE_SPEAKER => 0,
+                DEVICE_LINE_OUT => 1,
+                DEVICE_HEADPHONE => 2,
+                _ => continue,
+            };
+
+            if best.as_ref().is_some_and(|(r, _)| *r <= rank) {
+                continue;
+            }
+
+            let mut nodes = Vec::new();
+            if find_converter(controller, address, &widgets, pin.nid, &mut nodes)? {
+                nodes.reverse();
+                best = Some((
+                    rank,
+                    Path {
+                        nodes,
+                        amp_steps: Vec::new(),
+                    },
+                ));
+            }
+        }
+
+        let path = match best {
+            Some((_, mut path)) => {
+                for &nid in &path.nodes {
+                    let widget = widgets.iter().find(|w| w.nid == nid).unwrap();
+                    if widget.caps & WIDGET_CAPS_OUTPUT_AMP != 0 {
+                        let caps =
+                            Self::parameter(controller, address, nid, PARAM_OUTPUT_AMP_CAPS)?;
+                        path.amp_steps.push((nid, (caps >> 8) & 0x7F));
+                    }
+                }
+
+                Some(path)
+            }
+            None => None,
+        };
+
+        Ok(Codec {
+            address,
+            vendor_id,
+            path,
+        })
+    }
+
+    /// Powers the path up, picks its connections and routes `stream` into the converter
+    pub fn setup_output(
+        &self,
+        controller: &mut Controller,
+        stream: u8,
+        format: u16,
+    ) -> Result<(), Error> {
+        let path = self.path.as_ref().ok_or(Error::NoOutput)?;
+        let address = self.address;
+
+        for (i, &nid) in path.nodes.iter().enumerate() {
+            controller.command(verb(address, nid, SET_POWER_STATE, 0))?;
+
+            // Each widget takes its input from the previous one on the path
+            if i > 0 {
+                let connections = Self::connections(controller, address, nid)?;
+                if let Some(index) = connections.iter().position(|&c| c == path.nodes[i - 1]) {
+                    controller.command(verb(address, nid, SET_CONNECTION_SELECT, index as u32))?;
+                }
+            }
+        }
+
+        let converter = path.nodes[0];
+        controller.command(verb(
+            address,
+            converter,
+            SET_STREAM_CHANNEL,
+            (stream as u32) << 4,
+        ))?;
+        controller.command(verb16(
+            address,
+            converter,
+            SET_CONVERTER_FORMAT,
+            format as u32,
+        ))?;
+
+        let pin = *path.nodes.last().unwrap();
+        controller.command(verb(
+            address,
+            pin,
+            SET_PIN_CONTROL,
+            PIN_CONTROL_OUT | PIN_CONTROL_HEADPHONE,
+        ))?;
+
+        let pin_caps = Self::parameter(controller, address, pin, PARAM_PIN_CAPS)?;
+        if pin_caps & PIN_CAPS_EAPD != 0 {
+            controller.command(verb(address, pin, SET_EAPD, EAPD_ENABLE))?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets every amplifier on the path to `percent` of its range, 0 mutes
+    pub fn set_volume(&self, controller: &mut Controller, percent: u8) -> Result<(), Error> {
+        let path = self.path.as_ref().ok_or(Error::NoOutput)?;
+
+        for &(nid, steps) in &path.amp_steps {
+            let gain = steps * percent.min(100) as u32 / 100;
+            let mute = if percent == 0 { AMP_MUTE } else { 0 };
+            let payload = AMP_OUTPUT | AMP_LEFT | AMP_RIGHT | mute | gain;
+
+            controller.command(verb16(self.address, nid, SET_AMP_GAIN_MUTE, payload))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The nodes a node count parameter describes
+fn node_range(count: u32) -> impl Iterator<Item = u8> {
+    let start = (count >> 16) & 0xFF;
+    let count = count & 0xFF;
+    (start..start + count).map(|nid| nid as u8)
+}
+
+/// Depth first search from `nid` towards an output converter, leaving the route in `path`
+fn find_converter(
+    controller: &mut Controller,
+    address: u8,
+    widgets: &[Widget],
+    nid: u8,
+    path: &mut Vec<u8>,
+) -> Result<bool, Error> {
+    if path.len() >= MAX_PATH || path.contains(&nid) {
+        return Ok(false);
+    }
+
+    let Some(widget) = widgets.iter().find(|w| w.nid == nid) else {
+        return Ok(false);
+    };
+
+    path.push(nid);
+
+    match widget.kind {
+        WIDGET_OUTPUT => return Ok(true),
+        WIDGET_PIN | WIDGET_MIXER | WIDGET_SELECTOR => {
+            for next in Codec::connections(controller, address, nid)? {
+                if find_converter(controller, address, widgets, next, path)? {
+                    return Ok(true);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    path.pop();
+    Ok(false)
+}
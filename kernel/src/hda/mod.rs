@@ -0,0 +1,432 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::driver::{self, Driver, Match, ProbeError};
+use crate::hpet;
+use crate::mm::dma::Dma;
+use crate::mm::mmio::Mmio;
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+use codec::Codec;
+
+mod codec;
+
+/// Global registers
+const GCAP: usize = 0x00;
+const VMIN: usize = 0x02;
+const VMAJ: usize = 0x03;
+const GCTL: usize = 0x08;
+const STATESTS: usize = 0x0E;
+const CORBLBASE: usize = 0x40;
+const CORBUBASE: usize = 0x44;
+const CORBWP: usize = 0x48;
+const CORBRP: usize = 0x4A;
+const CORBCTL: usize = 0x4C;
+const CORBSIZE: usize = 0x4E;
+const RIRBLBASE: usize = 0x50;
+const RIRBUBASE: usize = 0x54;
+const RIRBWP: usize = 0x58;
+const RINTCNT: usize = 0x5A;
+const RIRBCTL: usize = 0x5C;
+const RIRBSIZE: usize = 0x5E;
+
+const GCTL_CRST: u32 = 1 << 0;
+const CORBRP_RESET: u16 = 1 << 15;
+const RIRBWP_RESET: u16 = 1 << 15;
+const RING_RUN: u8 = 1 << 1;
+/// 256 entries, for both rings
+const RING_SIZE_256: u8 = 0b10;
+const RING_ENTRIES: usize = 256;
+
+/// Stream descriptors, inputs first then outputs
+const STREAMS: usize = 0x80;
+const STREAM_SIZE: usize = 0x20;
+const SD_CTL: usize = 0x00;
+const SD_CTL_STREAM: usize = 0x02;
+const SD_STS: usize = 0x03;
+const SD_CBL: usize = 0x08;
+const SD_LVI: usize = 0x0C;
+const SD_FMT: usize = 0x12;
+const SD_BDPL: usize = 0x18;
+const SD_BDPU: usize = 0x1C;
+
+const SD_CTL_SRST: u8 = 1 << 0;
+const SD_CTL_RUN: u8 = 1 << 1;
+/// Buffer completion, FIFO error and descriptor error
+const SD_STS_BCIS: u8 = 1 << 2;
+const SD_STS_ALL: u8 = 0b111 << 2;
+
+const BDL_ENTRY_SIZE: usize = 16;
+const BDL_IOC: u32 = 1 << 0;
+
+/// The stream tag the output converter listens to
+const STREAM_TAG: u8 = 1;
+
+/// PCM gets played this much at a time
+const BUFFER_SIZE: usize = 256 * 1024;
+/// Buffer halves must stay 128 byte aligned
+const BUFFER_ALIGN: usize = 256;
+
+const COMMAND_TIMEOUT_US: u64 = 100_000;
+const DEFAULT_VOLUME: u8 = 80;
+
+static HDA: Mutex<Option<Hda>> = Mutex::new(None);
+
+static DRIVER: Driver = Driver {
+    name: "hda",
+    order: 50,
+    matches: &[Match::PciClass {
+        class: 0x04,
+        subclass: 0x03,
+        prog_if: None,
+    }],
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    Timeout,
+    /// No codec has a usable output path
+    NoOutput,
+    UnsupportedFormat,
+}
+
+/// 16 bit signed little endian PCM, interleaved
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub rate: u32,
+    pub channels: u8,
+}
+
+impl Format {
+    /// The stream format register value: base rate, multiplier, divisor, bits and channels
+    fn register(&self) -> Option<u16> {
+        let (base_44k1, multiplier, divisor) = match self.rate {
+            8000 => (0, 1, 6),
+            11025 => (1, 1, 4),
+            16000 => (0, 1, 3),
+            22050 => (1, 1, 2),
+            24000 => (0, 1, 2),
+            32000 => (0, 2, 3),
+            44100 => (1, 1, 1),
+            48000 => (0, 1, 1),
+            88200 => (1, 2, 1),
+            96000 => (0, 2, 1),
+            _ => return None,
+        };
+
+        if !(1..=16).contains(&self.channels) {
+            return None;
+        }
+
+        let bits_16 = 0b001;
+        Some(
+            base_44k1 << 14
+                | (multiplier - 1) << 11
+                | (divisor - 1) << 8
+                | bits_16 << 4
+                | (self.channels as u16 - 1),
+        )
+    }
+
+    fn bytes_per_second(&self) -> u64 {
+        self.rate as u64 * self.channels as u64 * 2
+    }
+}
+
+pub struct Controller {
+    mmio: Mmio,
+    corb: Dma,
+    rirb: Dma,
+    rirb_read: usize,
+}
+
+struct Hda {
+    device: crate::pci::Device,
+    controller: Controller,
+    codec: Codec,
+    /// Register offset of the output stream descriptor in use
+    stream: usize,
+    buffer: Dma,
+    bdl: Dma,
+}
+
+impl Controller {
+    /// Sends a verb through the CORB and waits for its response in the RIRB
+    pub fn command(&mut self, verb: u32) -> Result<u32, Error> {
+        let write = (self.mmio.read::<u16>(CORBWP) as usize + 1) % RING_ENTRIES;
+        self.corb.write(write * 4, verb);
+        self.mmio.write(CORBWP, write as u16);
+
+        let next = (self.rirb_read + 1) % RING_ENTRIES;
+        for _ in 0..COMMAND_TIMEOUT_US / 10 {
+            if self.mmio.read::<u16>(RIRBWP) as usize & 0xFF == next {
+                self.rirb_read = next;
+                return Ok(self.rirb.read(next * 8));
+            }
+
+            hpet::sleep(10_000);
+        }
+
+        Err(Error::Timeout)
+    }
+
+    fn reset(&self) -> Result<(), Error> {
+        self.mmio
+            .write(GCTL, self.mmio.read::<u32>(GCTL) & !GCTL_CRST);
+        wait(|| self.mmio.read::<u32>(GCTL) & GCTL_CRST == 0)?;
+
+        self.mmio
+            .write(GCTL, self.mmio.read::<u32>(GCTL) | GCTL_CRST);
+        wait(|| self.mmio.read::<u32>(GCTL) & GCTL_CRST != 0)?;
+
+        // Codecs get 521 us to ask for an address after the link comes out of reset
+        hpet::sleep(1_000_000);
+        Ok(())
+    }
+
+    fn setup_rings(&mut self) -> Result<(), Error> {
+        self.mmio.write(CORBCTL, 0u8);
+        self.mmio.write(RIRBCTL, 0u8);
+        wait(|| self.mmio.read::<u8>(CORBCTL) & RING_RUN == 0)?;
+        wait(|| self.mmio.read::<u8>(RIRBCTL) & RING_RUN == 0)?;
+
+        let corb = self.corb.phys().as_u64();
+        self.mmio.write(CORBSIZE, RING_SIZE_256);
+        self.mmio.write(CORBLBASE, corb as u32);
+        self.mmio.write(CORBUBASE, (corb >> 32) as u32);
+
+        // Not every controller acknowledges the read pointer reset, QEMU's included
+        self.mmio.write(CORBRP, CORBRP_RESET);
+        let _ = wait(|| self.mmio.read::<u16>(CORBRP) & CORBRP_RESET != 0);
+        self.mmio.write(CORBRP, 0u16);
+        self.mmio.write(CORBWP, 0u16);
+
+        let rirb = self.rirb.phys().as_u64();
+        self.mmio.write(RIRBSIZE, RING_SIZE_256);
+        self.mmio.write(RIRBLBASE, rirb as u32);
+        self.mmio.write(RIRBUBASE, (rirb >> 32) as u32);
+        self.mmio.write(RIRBWP, RIRBWP_RESET);
+        self.mmio.write(RINTCNT, 1u16);
+        self.rirb_read = 0;
+
+        self.mmio.write(CORBCTL, RING_RUN);
+        self.mmio.write(RIRBCTL, RING_RUN);
+        Ok(())
+    }
+}
+
+impl Hda {
+    fn stream_reset(&self) -> Result<(), Error> {
+        let mmio = &self.controller.mmio;
+        let ctl = self.stream + SD_CTL;
+
+        mmio.write(ctl, mmio.read::<u8>(ctl) & !SD_CTL_RUN);
+        wait(|| mmio.read::<u8>(ctl) & SD_CTL_RUN == 0)?;
+
+        mmio.write(ctl, SD_CTL_SRST);
+        wait(|| mmio.read::<u8>(ctl) & SD_CTL_SRST != 0)?;
+        mmio.write(ctl, 0u8);
+        wait(|| mmio.read::<u8>(ctl) & SD_CTL_SRST == 0)
+    }
+
+    /// Plays `pcm` once, it has to fit in the stream buffer
+    fn play_chunk(&mut self, pcm: &[u8], format: &Format, register: u16) -> Result<(), Error> {
+        // Pad with silence to two aligned halves, a BDL needs at least two entries
+        let len = pcm.len().next_multiple_of(BUFFER_ALIGN);
+        let buffer = self.buffer.as_mut_slice();
+        buffer[..pcm.len()].copy_from_slice(pcm);
+        buffer[pcm.len()..len].fill(0);
+
+        let half = len / 2;
+        let base = self.buffer.phys().as_u64();
+        for (i, offset) in [0, half].into_iter().enumerate() {
+            let entry = i * BDL_ENTRY_SIZE;
+            self.bdl.write(entry, base + offset as u64);
+            self.bdl.write(entry + 8, half as u32);
+            self.bdl.write(entry + 12, if i == 1 { BDL_IOC } else { 0 });
+        }
+
+        self.stream_reset()?;
+
+        let mmio = &self.controller.mmio;
+        let bdl = self.bdl.phys().as_u64();
+        mmio.write(self.stream + SD_CBL, len as u32);
+        mmio.write(self.stream + SD_LVI, 1u16);
+        mmio.write(self.stream + SD_FMT, register);
+        mmio.write(self.stream + SD_BDPL, bdl as u32);
+        mmio.write(self.stream + SD_BDPU, (bdl >> 32) as u32);
+        mmio.write(self.stream + SD_CTL_STREAM, STREAM_TAG << 4);
+        mmio.write(self.stream + SD_STS, SD_STS_ALL);
+        mmio.write(self.stream + SD_CTL, SD_CTL_RUN);
+
+        // The last entry interrupts on completion, which shows up in the status register
+        let duration_us = len as u64 * 1_000_000 / format.bytes_per_second();
+        let mut done = false;
+        for _ in 0..(duration_us + 1_000_000) / 1_000 {
+            if mmio.read::<u8>(self.stream + SD_STS) & SD_STS_BCIS != 0 {
+                done = true;
+                break;
+            }
+
+            hpet::sleep(1_000_000);
+        }
+
+        mmio.write(self.stream + SD_CTL, 0u8);
+        mmio.write(self.stream + SD_STS, SD_STS_ALL);
+
+        done.then_some(()).ok_or(Error::Timeout)
+    }
+}
+
+/// Waits up to a millisecond or so for `done`
+fn wait(mut done: impl FnMut() -> bool) -> Result<(), Error> {
+    for _ in 0..100 {
+        if done() {
+            return Ok(());
+        }
+
+        hpet::sleep(10_000);
+    }
+
+    Err(Error::Timeout)
+}
+
+pub fn present() -> bool {
+    HDA.lock().is_some()
+}
+
+/// Plays 16 bit PCM, blocking until it's done
+pub fn play(samples: &[i16], format: Format) -> Result<(), Error> {
+    let register = format.register().ok_or(Error::UnsupportedFormat)?;
+
+    let mut hda = HDA.lock();
+    let hda = hda.as_mut().ok_or(Error::NoOutput)?;
+
+    hda.codec
+        .setup_output(&mut hda.controller, STREAM_TAG, register)?;
+
+    let frame = format.channels as usize * 2;
+    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    for chunk in bytes.chunks(BUFFER_SIZE - BUFFER_SIZE % frame) {
+        hda.play_chunk(chunk, &format, register)?;
+    }
+
+    Ok(())
+}
+
+/// Sets the output volume, from 0 (muted) to 100
+pub fn set_volume(percent: u8) -> Result<(), Error> {
+    let mut hda = HDA.lock();
+    let hda = hda.as_mut().ok_or(Error::NoOutput)?;
+
+    hda.codec.set_volume(&mut hda.controller, percent)
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+    let mmio = pci
+        .map_bar(0)
+        .ok_or(ProbeError::Failed("cannot map BAR0"))?;
+    pci.enable_bus_mastering();
+
+    let mut controller = Controller {
+        mmio,
+        corb: Dma::new(RING_ENTRIES * 4),
+        rirb: Dma::new(RING_ENTRIES * 8),
+        rirb_read: 0,
+    };
+
+    controller
+        .reset()
+        .map_err(|_| ProbeError::Failed("controller doesn't come out of reset"))?;
+    controller
+        .setup_rings()
+        .map_err(|_| ProbeError::Failed("cannot start the CORB and RIRB"))?;
+
+    let capabilities = controller.mmio.read::<u16>(GCAP);
+    let inputs = ((capabilities >> 8) & 0xF) as usize;
+    let outputs = ((capabilities >> 12) & 0xF) as usize;
+    let codecs = controller.mmio.read::<u16>(STATESTS);
+
+    log::info!(
+        "hda: version {}.{}, {outputs} output streams, codecs {codecs:#x}",
+        controller.mmio.read::<u8>(VMAJ),
+        controller.mmio.read::<u8>(VMIN)
+    );
+
+    if outputs == 0 {
+        return Err(ProbeError::Failed("no output streams"));
+    }
+
+    let mut output = None;
+    for address in (0..15).filter(|i| codecs & (1 << i) != 0) {
+        match Codec::probe(&mut controller, address) {
+            Ok(codec) => {
+                log::info!(
+                    "hda: codec {address} is {:04x}:{:04x}{}",
+                    codec.vendor_id >> 16,
+                    codec.vendor_id & 0xFFFF,
+                    if codec.path.is_some() {
+                        ", has an output"
+                    } else {
+                        ""
+                    }
+                );
+
+                if output.is_none() && codec.path.is_some() {
+                    output = Some(codec);
+                }
+            }
+            Err(err) => log::warn!("hda: codec {address} doesn't respond: {err:?}"),
+        }
+    }
+
+    let codec = output.ok_or(ProbeError::Failed("no codec with an output path"))?;
+    codec
+        .set_volume(&mut controller, DEFAULT_VOLUME)
+        .map_err(|_| ProbeError::Failed("cannot set the volume"))?;
+
+    *HDA.lock() = Some(Hda {
+        device: *pci,
+        controller,
+        codec,
+        stream: STREAMS + inputs * STREAM_SIZE,
+        buffer: Dma::new(BUFFER_SIZE),
+        bdl: Dma::new(2 * BDL_ENTRY_SIZE),
+    });
+
+    Ok(())
+}
+
+fn remove(device: &driver::Device) {
+    let mut hda = HDA.lock();
+    if hda.as_ref().is_some_and(|h| {
+        device
+            .as_pci()
+            .is_some_and(|p| p.address == h.device.address)
+    }) {
+        if let Some(hda) = hda.take() {
+            let _ = hda.stream_reset();
+            hda.controller.mmio.write(CORBCTL, 0u8);
+            hda.controller.mmio.write(RIRBCTL, 0u8);
+        }
+    }
+}
@@ -16,9 +16,12 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::acpi::sdt::SdtHeader;
+use crate::acpi::{self, sdt::SdtHeader, AcpiTable};
+use crate::error::KError;
+use crate::registers::{ReadOnly, ReadWrite};
+use crate::sync::Once;
+use crate::time;
 use bilge::prelude::*;
-use spin::Mutex;
 
 #[bitsize(32)]
 struct EventTimerBlockId {
@@ -30,6 +33,11 @@ struct EventTimerBlockId {
     pci_vendor_id: u16,
 }
 
+/// Address space of the registers in `Address`, system memory is the only one allowed
+const SYSTEM_MEMORY: u8 = 0;
+/// The longest tick the spec allows, in femtoseconds
+const MAX_PERIOD: u32 = 100_000_000;
+
 #[repr(C, packed)]
 struct Address {
     asid: u8,
@@ -41,6 +49,7 @@ struct Address {
 
 #[repr(C, packed)]
 struct HpetTable {
+    hdr: SdtHeader,
     event_timer_block_id: EventTimerBlockId,
     address: Address,
     hpet_number: u8,
@@ -48,8 +57,12 @@ struct HpetTable {
     page_protection: u8,
 }
 
+unsafe impl AcpiTable for HpetTable {
+    const SIGNATURE: &'static str = "HPET";
+}
+
 #[bitsize(64)]
-#[derive(DebugBits)]
+#[derive(Clone, Copy, DebugBits)]
 struct HpetGeneralCaps {
     rev_id: u8,
     num_tim_cap: u5,
@@ -60,51 +73,70 @@ struct HpetGeneralCaps {
     counter_clock_period: u32,
 }
 
-#[repr(C)]
-struct HpetTimerInfo {
-    config_and_caps: u64,
-    comparator_value: u64,
-    fsb_interrupt_route: u64,
-    _reserved: u64,
+#[bitsize(64)]
+#[derive(Clone, Copy, FromBits, DebugBits)]
+struct HpetGeneralConfig {
+    enable: bool,
+    legacy_route: bool,
+    _reserved: u62,
 }
 
-#[repr(C)]
-struct HpetRegisters {
-    caps: HpetGeneralCaps,
-    _res0: u64,
-    general_config: u64,
-    _res1: u64,
-    general_irq_status: u64,
-    _res2: [u64; 25],
-    counter_val: u64,
-    _res3: u64,
-    timers: [HpetTimerInfo; 32],
+registers! {
+    struct HpetTimerInfo {
+        0x00 => config_and_caps: ReadWrite<u64>,
+        0x08 => comparator_value: ReadWrite<u64>,
+        0x10 => fsb_interrupt_route: ReadWrite<u64>,
+        0x18 => _reserved: u64,
+    }
+}
+
+registers! {
+    struct HpetRegisters {
+        0x000 => caps: ReadOnly<HpetGeneralCaps>,
+        0x008 => _res0: u64,
+        0x010 => general_config: ReadWrite<HpetGeneralConfig>,
+        0x018 => _res1: u64,
+        0x020 => general_irq_status: ReadWrite<u64>,
+        0x028 => _res2: [u64; 25],
+        0x0F0 => counter_val: ReadWrite<u64>,
+        0x0F8 => _res3: u64,
+        0x100 => timers: [HpetTimerInfo; 32],
+    }
 }
 
 pub struct Hpet {
-    regs: &'static mut HpetRegisters,
+    regs: &'static HpetRegisters,
 }
 
 impl Hpet {
-    fn new(table: *const SdtHeader) -> Hpet {
-        let table: &HpetTable = unsafe { &*(&*table).data().cast() };
-        let regs = unsafe { &mut *(table.address.address as *mut HpetRegisters) };
+    fn new(table: &HpetTable) -> Result<Hpet, KError> {
+        if table.address.asid != SYSTEM_MEMORY {
+            return Err(KError::Unsupported("HPET address space"));
+        }
 
-        log::debug!("Caps: {:x?}", regs.caps);
+        let regs = unsafe { &*(table.address.address as *const HpetRegisters) };
 
-        regs.general_config = 0;
-        regs.counter_val = 0;
-        regs.general_config = 1;
+        log::debug!("Caps: {:x?}", regs.caps.get());
+
+        let period = regs.caps.get().counter_clock_period();
+        if period == 0 || period > MAX_PERIOD {
+            return Err(KError::Invalid("HPET tick period"));
+        }
 
-        Hpet { regs }
+        // Stopped and out of legacy replacement mode while the counter gets reset
+        regs.general_config.set(HpetGeneralConfig::from(0));
+        regs.counter_val.set(0);
+        regs.general_config.modify(|config| config.set_enable(true));
+
+        Ok(Hpet { regs })
     }
 
     fn raw_tick_count(&self) -> u64 {
-        self.regs.counter_val
+        self.regs.counter_val.get()
     }
 
-    fn sleep(&mut self, nano: u64) {
-        let time = nano * 1_000_000 / (self.regs.caps.counter_clock_period() as u64);
+    fn sleep(&self, nano: u64) {
+        let time = nano * 1_000_000 / (self.regs.caps.get().counter_clock_period() as u64);
         let now = self.raw_tick_count();
         let target = now + time;
 
@@ -117,13 +149,41 @@ impl Hpet {
 unsafe impl Sync for Hpet {}
 unsafe impl Send for Hpet {}
 
-static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+static HPET: Once<Hpet> = Once::new();
 
-pub fn init(table: *const SdtHeader) {
+pub fn init() -> Result<(), KError> {
     log::trace!("Initializing the HPET");
-    *HPET.lock() = Some(Hpet::new(table));
+
+    let table = acpi::table::<HpetTable>().ok_or(KError::NotFound("HPET table"))?;
+    let hpet = Hpet::new(table)?;
+
+    HPET.call_once(|| hpet);
+    Ok(())
+}
+
+initcall!(hpet, init, [acpi]);
+
+/// Whether there's a HPET, `sleep` spins on the TSC otherwise
+pub fn present() -> bool {
+    HPET.get().is_some()
 }
 
+/// Busy waits `nano` nanoseconds, on the TSC if there's no HPET, which then has to be calibrated
 pub fn sleep(nano: u64) {
-    HPET.lock().as_mut().unwrap().sleep(nano)
+    assert!(
+        try_sleep(nano),
+        "nothing to sleep on before calibrating the TSC"
+    );
+}
+
+/// Like `sleep`, but returns false instead of panicking if there's nothing to sleep on, for the
+/// panic path
+pub fn try_sleep(nano: u64) -> bool {
+    match HPET.get() {
+        Some(hpet) => hpet.sleep(nano),
+        None if time::calibrated() => time::spin(nano),
+        None => return false,
+    }
+
+    true
 }
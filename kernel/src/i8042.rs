@@ -0,0 +1,273 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::sync::Mutex;
+use crate::{acpi, cpu};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer comes from the aux port
+const STATUS_AUX: u8 = 1 << 5;
+
+/// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_AUX: u8 = 0xA7;
+const ENABLE_AUX: u8 = 0xA8;
+const TEST_AUX: u8 = 0xA9;
+const SELF_TEST: u8 = 0xAA;
+const TEST_KEYBOARD: u8 = 0xAB;
+const DISABLE_KEYBOARD: u8 = 0xAD;
+const ENABLE_KEYBOARD: u8 = 0xAE;
+const WRITE_AUX: u8 = 0xD4;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// Configuration byte
+const CONFIG_KEYBOARD_IRQ: u8 = 1 << 0;
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// How many times to poll the status register before giving up
+const TIMEOUT: usize = 100_000;
+
+/// Bytes drained at most, a stuck controller would keep the output buffer full forever
+const DRAIN_LIMIT: usize = 64;
+
+static CONTROLLER: Mutex<Option<Controller>> = Mutex::new(None);
+/// Called after the controller was reset, so the devices behind it can be set up again
+static RESET_HANDLERS: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+static RECOVERING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    Keyboard,
+    Aux,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    Timeout,
+    SelfTestFailed(u8),
+    NoSuchPort,
+    /// The FADT says there is no 8042, and poking at its ports could hit something else
+    NoController,
+}
+
+#[derive(Clone, Copy)]
+struct Controller {
+    keyboard: bool,
+    aux: bool,
+}
+
+fn status() -> u8 {
+    unsafe { cpu::inb(STATUS) }
+}
+
+fn wait_input_empty() -> Result<(), Error> {
+    (0..TIMEOUT)
+        .any(|_| status() & STATUS_INPUT_FULL == 0)
+        .then_some(())
+        .ok_or(Error::Timeout)
+}
+
+fn wait_output_full() -> Result<(), Error> {
+    (0..TIMEOUT)
+        .any(|_| status() & STATUS_OUTPUT_FULL != 0)
+        .then_some(())
+        .ok_or(Error::Timeout)
+}
+
+fn write_command(command: u8) -> Result<(), Error> {
+    wait_input_empty()?;
+    unsafe { cpu::outb(COMMAND, command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), Error> {
+    wait_input_empty()?;
+    unsafe { cpu::outb(DATA, byte) };
+    Ok(())
+}
+
+/// Waits for a byte from the controller or one of its devices
+pub fn read() -> Result<u8, Error> {
+    wait_output_full()?;
+    Ok(unsafe { cpu::inb(DATA) })
+}
+
+/// Returns the next byte in the output buffer and which port it came from, without waiting
+pub fn poll() -> Option<(Port, u8)> {
+    let status = status();
+    if status & STATUS_OUTPUT_FULL == 0 {
+        return None;
+    }
+
+    let port = if status & STATUS_AUX != 0 {
+        Port::Aux
+    } else {
+        Port::Keyboard
+    };
+
+    Some((port, unsafe { cpu::inb(DATA) }))
+}
+
+/// Throws away whatever is sitting in the output buffer
+pub fn drain() {
+    for _ in 0..DRAIN_LIMIT {
+        if poll().is_none() {
+            break;
+        }
+    }
+}
+
+fn command_with_reply(command: u8) -> Result<u8, Error> {
+    write_command(command)?;
+    read()
+}
+
+fn read_config() -> Result<u8, Error> {
+    command_with_reply(READ_CONFIG)
+}
+
+fn write_config(config: u8) -> Result<(), Error> {
+    write_command(WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Sends a byte to the device on `port`, its reply comes back through `read` or `poll`
+pub fn send(port: Port, byte: u8) -> Result<(), Error> {
+    let result = match port {
+        Port::Keyboard => write_data(byte),
+        Port::Aux => write_command(WRITE_AUX).and_then(|_| write_data(byte)),
+    };
+
+    if result.is_err() {
+        recover();
+    }
+
+    result
+}
+
+/// Brings the controller to a known state: both ports tested, enabled if they work and with
+/// their interrupts on and translation off
+fn reset() -> Result<Controller, Error> {
+    write_command(DISABLE_KEYBOARD)?;
+    write_command(DISABLE_AUX)?;
+    drain();
+
+    let mut config = read_config()?;
+    config &= !(CONFIG_KEYBOARD_IRQ | CONFIG_AUX_IRQ | CONFIG_TRANSLATION);
+    write_config(config)?;
+
+    match command_with_reply(SELF_TEST)? {
+        SELF_TEST_PASSED => {}
+        reply => return Err(Error::SelfTestFailed(reply)),
+    }
+
+    // The self test can reset the controller on some chipsets
+    write_config(config)?;
+
+    // A single channel controller ignores enabling the aux port, leaving its clock disabled
+    write_command(ENABLE_AUX)?;
+    let dual = read_config()? & CONFIG_AUX_CLOCK_DISABLED == 0;
+    write_command(DISABLE_AUX)?;
+
+    let keyboard = command_with_reply(TEST_KEYBOARD)? == PORT_TEST_PASSED;
+    let aux = dual && command_with_reply(TEST_AUX)? == PORT_TEST_PASSED;
+
+    if keyboard {
+        write_command(ENABLE_KEYBOARD)?;
+        config |= CONFIG_KEYBOARD_IRQ;
+    }
+
+    if aux {
+        write_command(ENABLE_AUX)?;
+        config |= CONFIG_AUX_IRQ;
+    }
+
+    write_config(config)?;
+    drain();
+
+    Ok(Controller { keyboard, aux })
+}
+
+/// Initializes the controller if nobody did yet, returns whether `port` works
+pub fn init(port: Port) -> Result<(), Error> {
+    let mut controller = CONTROLLER.lock();
+
+    let state = match *controller {
+        Some(state) => state,
+        None => {
+            if acpi::fadt().is_some_and(|fadt| !fadt.has_8042()) {
+                return Err(Error::NoController);
+            }
+
+            let state = reset()?;
+            log::info!(
+                "i8042: keyboard port {}, aux port {}",
+                if state.keyboard { "ok" } else { "missing" },
+                if state.aux { "ok" } else { "missing" }
+            );
+
+            *controller.insert(state)
+        }
+    };
+
+    match port {
+        Port::Keyboard if state.keyboard => Ok(()),
+        Port::Aux if state.aux => Ok(()),
+        _ => Err(Error::NoSuchPort),
+    }
+}
+
+/// Registers `handler` to set its device up again after the controller gets reset
+pub fn register_reset_handler(handler: fn()) {
+    RESET_HANDLERS.lock().push(handler);
+}
+
+/// Resets a controller that stopped accepting input, then lets the drivers set their
+/// devices up again
+pub fn recover() {
+    if RECOVERING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    log::warn!("i8042: controller wedged, resetting");
+
+    match reset() {
+        Ok(state) => {
+            *CONTROLLER.lock() = Some(state);
+
+            let handlers = RESET_HANDLERS.lock().clone();
+            for handler in handlers {
+                handler();
+            }
+        }
+        Err(err) => log::error!("i8042: reset failed: {err:?}"),
+    }
+
+    RECOVERING.store(false, Ordering::Release);
+}
@@ -0,0 +1,272 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::{self, KError, Policy};
+use crate::sync::Mutex;
+use crate::time;
+use alloc::vec;
+use alloc::vec::Vec;
+
+extern "C" {
+    static __initcalls_start: u8;
+    static __initcalls_end: u8;
+}
+
+/// A subsystem registered with `initcall!`
+pub struct Initcall {
+    pub name: &'static str,
+    /// Initcalls that have to run first, whether they succeeded or not
+    pub after: &'static [&'static str],
+    /// Initcalls that have to run first and succeed, it's skipped if any of them didn't
+    pub needs: &'static [&'static str],
+    pub policy: Policy,
+    pub init: fn() -> Result<(), KError>,
+}
+
+/// What an init function can return, for the ones that can't fail
+pub trait InitResult {
+    fn into_result(self) -> Result<(), KError>;
+}
+
+impl InitResult for () {
+    fn into_result(self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+impl InitResult for Result<(), KError> {
+    fn into_result(self) -> Result<(), KError> {
+        self
+    }
+}
+
+/// Registers `init` to be run by `initcall::run` once every initcall in `after` has, and every
+/// one in `needs` has come up.
+///
+/// A failure is handled as `policy` says, `Degrade` unless given
+#[macro_export]
+macro_rules! initcall {
+    ($name:ident, $init:path, [$($after:ident),*]) => {
+        $crate::initcall!($name, $init, [$($after),*], needs [], Degrade);
+    };
+    ($name:ident, $init:path, [$($after:ident),*], needs [$($needs:ident),*]) => {
+        $crate::initcall!($name, $init, [$($after),*], needs [$($needs),*], Degrade);
+    };
+    ($name:ident, $init:path, [$($after:ident),*], $policy:ident) => {
+        $crate::initcall!($name, $init, [$($after),*], needs [], $policy);
+    };
+    ($name:ident, $init:path, [$($after:ident),*], needs [$($needs:ident),*], $policy:ident) => {
+        const _: () = {
+            fn call() -> ::core::result::Result<(), $crate::error::KError> {
+                $crate::initcall::InitResult::into_result($init())
+            }
+
+            #[used]
+            #[link_section = ".initcalls"]
+            static ENTRY: $crate::initcall::Initcall = $crate::initcall::Initcall {
+                name: stringify!($name),
+                after: &[$(stringify!($after)),*],
+                needs: &[$(stringify!($needs)),*],
+                policy: $crate::error::Policy::$policy,
+                init: call,
+            };
+        };
+    };
+}
+
+/// How an initcall went, in the order they ran
+pub struct Record {
+    pub name: &'static str,
+    pub ticks: u64,
+    pub result: Result<(), KError>,
+    /// It didn't run, `result` says which of its `needs` didn't come up
+    pub skipped: bool,
+}
+
+static RECORDS: Mutex<Vec<Record>> = Mutex::new(Vec::new());
+
+fn initcalls() -> &'static [Initcall] {
+    unsafe {
+        let start = core::ptr::addr_of!(__initcalls_start) as *const Initcall;
+        let end = core::ptr::addr_of!(__initcalls_end) as *const Initcall;
+
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+fn index(calls: &[Initcall], name: &str) -> Option<usize> {
+    calls.iter().position(|call| call.name == name)
+}
+
+/// Runs every initcall after what it depends on, picking the first by name when more are ready
+/// so the order doesn't change between builds
+pub fn run() {
+    let calls = initcalls();
+    let start = time::ticks();
+
+    run_calls(calls, |record| RECORDS.lock().push(record));
+
+    // The TSC gets calibrated along the way, so the times only make sense now
+    for record in RECORDS.lock().iter() {
+        log::debug!(
+            "initcall: {} took {} us",
+            record.name,
+            time::ticks_to_us(record.ticks)
+        );
+    }
+
+    log::info!(
+        "initcall: {} subsystems up in {} ms",
+        calls.len(),
+        time::ticks_to_us(time::ticks() - start) / 1000
+    );
+}
+
+/// Runs `calls` in dependency order, handing how each went to `record`
+fn run_calls(calls: &[Initcall], mut record: impl FnMut(Record)) {
+    for (i, call) in calls.iter().enumerate() {
+        assert_eq!(
+            index(calls, call.name),
+            Some(i),
+            "initcall {} is registered twice",
+            call.name
+        );
+
+        for &after in call.after.iter().chain(call.needs) {
+            assert!(
+                index(calls, after).is_some(),
+                "initcall {} runs after unknown {after}",
+                call.name
+            );
+        }
+    }
+
+    let mut done = vec![false; calls.len()];
+    let mut up = vec![false; calls.len()];
+
+    for _ in 0..calls.len() {
+        let ready = (0..calls.len())
+            .filter(|&i| !done[i])
+            .filter(|&i| {
+                calls[i]
+                    .after
+                    .iter()
+                    .chain(calls[i].needs)
+                    .all(|&after| done[index(calls, after).unwrap()])
+            })
+            .min_by_key(|&i| calls[i].name);
+
+        let Some(i) = ready else {
+            let stuck: Vec<&str> = (0..calls.len())
+                .filter(|&i| !done[i])
+                .map(|i| calls[i].name)
+                .collect();
+            panic!("initcall dependency cycle among {stuck:?}");
+        };
+
+        let call = &calls[i];
+        let missing = call
+            .needs
+            .iter()
+            .copied()
+            .find(|&need| !up[index(calls, need).unwrap()]);
+
+        let before = time::ticks();
+        let result = match missing {
+            Some(need) => {
+                log::warn!("initcall: skipping {}, {need} didn't come up", call.name);
+                Err(KError::NotFound(need))
+            }
+            None => {
+                log::trace!("initcall: {}", call.name);
+                (call.init)()
+            }
+        };
+        let ticks = time::ticks() - before;
+
+        error::check(call.name, call.policy, result);
+        done[i] = true;
+        up[i] = result.is_ok();
+
+        record(Record {
+            name: call.name,
+            ticks,
+            result,
+            skipped: missing.is_some(),
+        });
+    }
+}
+
+/// Calls `f` with how every initcall went, in the order they ran
+pub fn for_each(mut f: impl FnMut(&Record)) {
+    for record in RECORDS.lock().iter() {
+        f(record);
+    }
+}
+
+ktest! {
+    fn initcalls_ran_after_dependencies() {
+        let records = RECORDS.lock();
+        assert_eq!(records.len(), initcalls().len());
+
+        for call in initcalls() {
+            let position = |name| records.iter().position(|r| r.name == name).unwrap();
+
+            for &after in call.after.iter().chain(call.needs) {
+                assert!(position(after) < position(call.name));
+            }
+        }
+    }
+
+    fn failed_needs_skip_their_dependents() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        static RAN: AtomicBool = AtomicBool::new(false);
+
+        let call = |name, after, needs, init| Initcall {
+            name,
+            after,
+            needs,
+            policy: Policy::Degrade,
+            init,
+        };
+        fn mark() -> Result<(), KError> {
+            RAN.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+
+        let calls = [
+            call("a", &[], &[], || Err(KError::NotFound("test"))),
+            call("b", &[], &["a"], mark),
+            call("c", &["a"], &[], || Ok(())),
+            call("d", &[], &["b"], mark),
+        ];
+
+        let mut records = Vec::new();
+        run_calls(&calls, |record| records.push(record));
+        assert!(!RAN.load(Ordering::Relaxed));
+
+        let record = |name| records.iter().find(|r| r.name == name).unwrap();
+        assert_eq!(record("b").result, Err(KError::NotFound("a")));
+        assert!(record("b").skipped);
+        // `after` only orders, `c` runs even though `a` failed
+        assert_eq!(record("c").result, Ok(()));
+        assert!(!record("c").skipped);
+        assert_eq!(record("d").result, Err(KError::NotFound("b")));
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::mm::pmm;
+use crate::{cmdline, cpu, monitor, oops};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// Injectors armed with `inject` only fail allocations on this core
+const ANY_CORE: usize = usize::MAX;
+
+const OFF: u8 = 0;
+const NTH: u8 = 1;
+const RANDOM: u8 = 2;
+
+/// Where an allocation can be made to fail
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Site {
+    /// `pmm::try_alloc` returns `None`, so the heap returns null when it needs pages
+    Pmm,
+    /// The global allocator returns null
+    Heap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Only the `n`th call from now fails, 1 being the next one
+    Nth(u64),
+    /// Every call fails with a chance of one in `n`
+    Random(u64),
+}
+
+struct Injector {
+    mode: AtomicU8,
+    param: AtomicU64,
+    calls: AtomicU64,
+    core: AtomicUsize,
+    injected: AtomicU64,
+}
+
+impl Injector {
+    const fn new() -> Injector {
+        Injector {
+            mode: AtomicU8::new(OFF),
+            param: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+            core: AtomicUsize::new(ANY_CORE),
+            injected: AtomicU64::new(0),
+        }
+    }
+}
+
+static INJECTORS: [Injector; 2] = [Injector::new(), Injector::new()];
+
+/// Splitmix64 state, the entropy pool takes a lock and allocates so it can't be used from here
+static STATE: AtomicU64 = AtomicU64::new(0x9E37_79B9_7F4A_7C15);
+
+/// Disarms the injector it came from when dropped
+#[must_use]
+pub struct Guard(Site);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        disarm(self.0);
+    }
+}
+
+fn random() -> u64 {
+    let mut z = STATE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Whether the allocation about to happen at `site` should fail, called by the allocators
+///
+/// Costs a relaxed load while nothing is armed, and never allocates or logs
+#[inline]
+pub fn should_fail(site: Site) -> bool {
+    let injector = &INJECTORS[site as usize];
+    let mode = injector.mode.load(Ordering::Relaxed);
+    if mode == OFF {
+        return false;
+    }
+
+    let core = injector.core.load(Ordering::Relaxed);
+    if core != ANY_CORE && core != monitor::current_core() {
+        return false;
+    }
+
+    let param = injector.param.load(Ordering::Relaxed);
+    let fail = match mode {
+        // Exactly one caller sees the count hit `param`, it's the one that disarms
+        NTH => {
+            let fail = injector.calls.fetch_add(1, Ordering::Relaxed) + 1 == param;
+            if fail {
+                injector.mode.store(OFF, Ordering::Relaxed);
+            }
+
+            fail
+        }
+        _ => random().is_multiple_of(param.max(1)),
+    };
+
+    if fail {
+        injector.injected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fail
+}
+
+fn arm(site: Site, mode: Mode, core: usize) {
+    let injector = &INJECTORS[site as usize];
+    injector.mode.store(OFF, Ordering::SeqCst);
+
+    let (kind, param) = match mode {
+        Mode::Nth(n) => (NTH, n),
+        Mode::Random(n) => (RANDOM, n),
+    };
+
+    injector.param.store(param, Ordering::SeqCst);
+    injector.calls.store(0, Ordering::SeqCst);
+    injector.core.store(core, Ordering::SeqCst);
+    injector.mode.store(kind, Ordering::SeqCst);
+}
+
+pub fn disarm(site: Site) {
+    INJECTORS[site as usize].mode.store(OFF, Ordering::SeqCst);
+}
+
+/// Makes allocations at `site` fail as `mode` says, on the current core, until the guard is dropped
+pub fn inject(site: Site, mode: Mode) -> Guard {
+    arm(site, mode, monitor::current_core());
+    Guard(site)
+}
+
+/// Like `inject`, but on every core and for good, what `inject=` on the command line does
+pub fn inject_all(site: Site, mode: Mode) {
+    arm(site, mode, ANY_CORE);
+}
+
+/// How many allocations failed because of injection at `site` since boot
+pub fn injected(site: Site) -> u64 {
+    INJECTORS[site as usize].injected.load(Ordering::Relaxed)
+}
+
+pub fn parse_site(site: &str) -> Option<Site> {
+    match site {
+        "pmm" => Some(Site::Pmm),
+        "heap" => Some(Site::Heap),
+        _ => None,
+    }
+}
+
+/// Parses `nth:<n>` or `random:<n>`
+pub fn parse_mode(mode: &str) -> Option<Mode> {
+    let (kind, n) = mode.split_once(':')?;
+    let n = n.parse().ok().filter(|&n| n > 0)?;
+
+    match kind {
+        "nth" => Some(Mode::Nth(n)),
+        "random" => Some(Mode::Random(n)),
+        _ => None,
+    }
+}
+
+/// Arms what `inject=pmm:nth:100,heap:random:1000` asks for
+pub fn init() {
+    let Some(options) = cmdline::value("inject") else {
+        return;
+    };
+
+    STATE.store(unsafe { cpu::rdtsc() }, Ordering::Relaxed);
+
+    for option in options.split(',') {
+        let parsed = option
+            .split_once(':')
+            .and_then(|(site, mode)| Some((parse_site(site)?, parse_mode(mode)?)));
+
+        match parsed {
+            Some((site, mode)) => {
+                log::warn!("Injecting allocation failures at {site:?}: {mode:?}");
+                inject_all(site, mode);
+            }
+            None => log::warn!("inject: can't parse {option:?}"),
+        }
+    }
+}
+
+initcall!(inject, init, []);
+
+ktest! {
+    fn nth_fails_once() {
+        let _guard = inject(Site::Pmm, Mode::Nth(2));
+
+        let first = pmm::try_alloc(1).expect("first call shouldn't fail");
+        assert!(pmm::try_alloc(1).is_none());
+        let third = pmm::try_alloc(1).expect("third call shouldn't fail");
+
+        pmm::free(first, 1);
+        pmm::free(third, 1);
+    }
+
+    fn random_fails_sometimes() {
+        // Allocated first, growing the heap would hit the injection too
+        let mut pages = Vec::with_capacity(64);
+        let _guard = inject(Site::Pmm, Mode::Random(2));
+        let before = injected(Site::Pmm);
+
+        for _ in 0..64 {
+            if let Some(page) = pmm::try_alloc(1) {
+                pages.push(page);
+            }
+        }
+
+        let failed = injected(Site::Pmm) - before;
+        assert_eq!(failed as usize, 64 - pages.len());
+        assert!(failed > 0 && failed < 64);
+
+        for page in pages {
+            pmm::free(page, 1);
+        }
+    }
+
+    fn heap_try_reserve_fails() {
+        let mut buffer = Vec::<u8>::new();
+        {
+            let _guard = inject(Site::Heap, Mode::Nth(1));
+            assert!(buffer.try_reserve(4096).is_err());
+        }
+
+        assert!(buffer.try_reserve(4096).is_ok());
+    }
+
+    fn infallible_oom_is_an_oops() {
+        let _guard = inject(Site::Heap, Mode::Nth(1));
+        let result = oops::recoverable("oom", || Box::new([0u8; 64]));
+        assert!(result.is_err());
+    }
+}
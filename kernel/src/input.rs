@@ -0,0 +1,216 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::fb_renderer;
+use crate::utils::MpscRing;
+
+/// Events nobody read yet, older ones are dropped past this
+const QUEUE_LIMIT: usize = 256;
+
+static EVENTS: MpscRing<KeyEvent, QUEUE_LIMIT> = MpscRing::new();
+
+/// A physical key, independent of the layout printed on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Backquote,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Digit0,
+    Minus,
+    Equal,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    /// The extra key next to left shift on ISO keyboards
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftSuper,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightSuper,
+    Menu,
+    RightCtrl,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    Up,
+    Left,
+    Down,
+    Right,
+    NumLock,
+    KeypadDivide,
+    KeypadMultiply,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const SHIFT: Modifiers = Modifiers(1 << 0);
+    pub const CTRL: Modifiers = Modifiers(1 << 1);
+    pub const ALT: Modifiers = Modifiers(1 << 2);
+    pub const ALTGR: Modifiers = Modifiers(1 << 3);
+    pub const SUPER: Modifiers = Modifiers(1 << 4);
+    pub const CAPS_LOCK: Modifiers = Modifiers(1 << 5);
+    pub const NUM_LOCK: Modifiers = Modifiers(1 << 6);
+    pub const SCROLL_LOCK: Modifiers = Modifiers(1 << 7);
+
+    pub fn contains(&self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn set(&mut self, other: Modifiers, value: bool) {
+        if value {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+
+    pub fn toggle(&mut self, other: Modifiers) {
+        self.0 ^= other.0;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    /// Modifiers in effect after this event
+    pub modifiers: Modifiers,
+    /// What the key types with the current keymap, if anything
+    pub char: Option<char>,
+}
+
+/// Queues an event from an input driver, called from interrupt context
+///
+/// Shift+PageUp and Shift+PageDown scroll the console, Alt+F<n> switches terminals and
+/// Alt+Escape toggles the boot splash, those never make it to the queue
+pub fn push(event: KeyEvent) {
+    if event.pressed && event.modifiers.contains(Modifiers::SHIFT) {
+        match event.key {
+            KeyCode::PageUp => return fb_renderer::scroll_page(true),
+            KeyCode::PageDown => return fb_renderer::scroll_page(false),
+            _ => {}
+        }
+    }
+
+    if event.pressed && event.modifiers.contains(Modifiers::ALT) {
+        let terminal = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4]
+            .iter()
+            .position(|&key| key == event.key);
+
+        if let Some(terminal) = terminal {
+            return fb_renderer::switch_to(terminal);
+        }
+
+        if event.key == KeyCode::Escape {
+            return fb_renderer::toggle_splash();
+        }
+    }
+
+    EVENTS.force_push(event);
+}
+
+/// Returns the next character typed, skipping releases and keys that don't type anything
+pub fn read_char() -> Option<char> {
+    while let Some(event) = EVENTS.pop() {
+        if let (true, Some(c)) = (event.pressed, event.char) {
+            return Some(c);
+        }
+    }
+
+    None
+}
@@ -16,10 +16,17 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::cpu;
-use alloc::{boxed::Box, vec};
+use crate::acpi::madt;
+use crate::core_locals::{self, MAX_CORES};
+use crate::kbench::Bencher;
+use crate::stack::{self, Stack};
+use crate::sync::{IrqSpinlock, PerCpu, Rcu};
+use crate::trace::{self, Event};
+use crate::{backtrace, cpu, syscall, time};
+use alloc::boxed::Box;
+use core::fmt;
 use core::mem::size_of;
-use spin::Mutex;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 #[repr(C, packed)]
 #[derive(Default, Clone, Copy, Debug)]
@@ -44,14 +51,12 @@ pub struct Tss {
 
 impl Tss {
     pub fn new() -> Tss {
-        let kstack = unsafe { vec![0u8; 64 * 1024].leak().as_mut_ptr().add(64 * 1024) };
+        let kstack = Stack::new().top();
         let mut ists = [0u64; 7];
-        ists.iter_mut().for_each(|ist| {
-            *ist = unsafe { vec![0u8; 64 * 1024].leak().as_mut_ptr().add(64 * 1024) } as u64;
-        });
+        ists.iter_mut().for_each(|ist| *ist = Stack::new().top());
 
         Tss {
-            rsp: [kstack as u64; 3],
+            rsp: [kstack; 3],
             ist: ists,
             ..Default::default()
         }
@@ -99,12 +104,20 @@ impl IDTDescriptor {
     }
 }
 
+/// Exceptions that get an IST stack of their own, they can't trust the one they interrupted
+const IST_VECTORS: [(usize, u8); 3] = [(0x2, 1), (0x8, 2), (0x12, 3)];
+
 pub fn init() {
     let idt: &mut [IDTDescriptor; 256] = Box::leak(Box::new([IDTDescriptor::default(); 256]));
 
     unsafe {
-        for (i, &ist) in HANDLERS.iter().enumerate() {
-            idt[i] = IDTDescriptor::new(0, ISTType::KernelModeIntGate, 0x08, ist);
+        for (i, &handler) in HANDLERS.iter().enumerate() {
+            let ist = IST_VECTORS
+                .iter()
+                .find(|&&(vector, _)| vector == i)
+                .map_or(0, |&(_, ist)| ist);
+
+            idt[i] = IDTDescriptor::new(ist, ISTType::KernelModeIntGate, 0x08, handler);
         }
     }
 
@@ -114,6 +127,10 @@ pub fn init() {
     };
 
     unsafe { load_idt(&desc) };
+
+    // Spurious interrupts must not be acknowledged, there's nothing to do for them
+    register_handler(SPURIOUS_VECTOR, |_| {});
+    register_handler(syscall::VECTOR, syscall::handler);
 }
 
 #[repr(C, packed)]
@@ -153,70 +170,178 @@ pub struct InterruptStack {
     pub ss: u64,
 }
 
-static INTERRUPT_HANDLERS: Mutex<[Option<fn(&mut InterruptStack)>; 256]> = Mutex::new([None; 256]);
+impl fmt::Display for InterruptStack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "rax {:016x} rcx {:016x} rdx {:016x} rbx {:016x}",
+            self.rax, self.rcx, self.rdx, self.rbx
+        )?;
+        writeln!(
+            f,
+            "rsp {:016x} rbp {:016x} rsi {:016x} rdi {:016x}",
+            self.rsp, self.rbp, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}",
+            self.r8, self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}",
+            self.r12, self.r13, self.r14, self.r15
+        )?;
+        writeln!(f, "rfl {:016x}", self.rflags)?;
+        writeln!(f, "rip {:016x}", self.rip)?;
+        writeln!(f, "cr2 {:016x}", cpu::get_cr2().as_u64())?;
+        write!(f, "cs {:02x} ss {:02x}", self.cs, self.ss)
+    }
+}
+
+/// An exception nobody handled, kept for the panic it turns into
+#[derive(Clone, Copy)]
+pub struct Exception {
+    pub vector: usize,
+    pub stack: InterruptStack,
+}
+
+static EXCEPTION: IrqSpinlock<Option<Exception>> = IrqSpinlock::new(None);
+
+/// The unhandled exception behind the current panic, if it came from one
+pub fn exception() -> Option<Exception> {
+    *EXCEPTION.try_lock()?
+}
+
+/// Like `exception`, but clears it, for when the panic turned out to be recoverable
+pub fn take_exception() -> Option<Exception> {
+    EXCEPTION.try_lock()?.take()
+}
+
+pub fn exception_name(vector: usize) -> &'static str {
+    match vector {
+        0x0 => "divide error",
+        0x1 => "debug",
+        0x2 => "non-maskable interrupt",
+        0x3 => "breakpoint",
+        0x4 => "overflow",
+        0x5 => "bound range exceeded",
+        0x6 => "invalid opcode",
+        0x7 => "device not available",
+        0x8 => "double fault",
+        0xA => "invalid TSS",
+        0xB => "segment not present",
+        0xC => "stack-segment fault",
+        0xD => "general protection fault",
+        0xE => "page fault",
+        0x10 => "x87 floating point exception",
+        0x11 => "alignment check",
+        0x12 => "machine check",
+        0x13 => "SIMD floating point exception",
+        0x14 => "virtualization exception",
+        0x15 => "control protection exception",
+        _ => "unhandled interrupt",
+    }
+}
+
+/// Read on every interrupt, written only when drivers come and go
+static INTERRUPT_HANDLERS: Rcu<[Option<fn(&mut InterruptStack)>; 256]> = Rcu::new([None; 256]);
+
+static COUNTS: PerCpu<[AtomicU64; 256]> =
+    PerCpu::new([const { [const { AtomicU64::new(0) }; 256] }; MAX_CORES]);
+
+/// How deep in device interrupt handlers each core is, exceptions don't count
+static NESTING: PerCpu<AtomicUsize> = PerCpu::new([const { AtomicUsize::new(0) }; MAX_CORES]);
+
+/// First vector handed out to devices, everything below is reserved for exceptions
+const FIRST_DEVICE_VECTOR: usize = 0x20;
+
+/// The APIC spurious interrupt vector
+pub const SPURIOUS_VECTOR: usize = 0xFF;
 
 pub fn register_handler(ist: usize, handler: fn(&mut InterruptStack)) {
-    INTERRUPT_HANDLERS.lock()[ist] = Some(handler);
+    INTERRUPT_HANDLERS.update(|handlers| handlers[ist] = Some(handler));
+}
+
+/// Installs `handler` on the first free device vector and returns it
+pub fn allocate_handler(handler: fn(&mut InterruptStack)) -> Option<u8> {
+    INTERRUPT_HANDLERS.update(|handlers| {
+        let vector = (FIRST_DEVICE_VECTOR..SPURIOUS_VECTOR).find(|&v| handlers[v].is_none())?;
+        handlers[vector] = Some(handler);
+
+        Some(vector as u8)
+    })
+}
+
+/// Uninstalls the handler on `vector`, once this returns no core can pick it up anymore
+pub fn free_handler(vector: u8) {
+    INTERRUPT_HANDLERS.update(|handlers| handlers[vector as usize] = None);
+}
+
+/// Whether the current core is running a device or IPI interrupt handler
+pub fn in_interrupt() -> bool {
+    NESTING.get().load(Ordering::Relaxed) != 0
+}
+
+/// How many times `vector` fired on core `core`
+pub fn count(core: usize, vector: u8) -> u64 {
+    COUNTS.of(core)[vector as usize].load(Ordering::Relaxed)
 }
 
 #[no_mangle]
 unsafe extern "C" fn generic_interrupt_handler(ist: usize, stack: *mut InterruptStack) {
     let stack = &mut *stack;
 
+    COUNTS.get()[ist].fetch_add(1, Ordering::Relaxed);
+    trace::event(Event::IrqEntry, [ist as u64, 0]);
+
     if ist == 0xE && stack.cs & 3 == 3 {
         log::info!("USER MODE PAGE FAULT: Error code {:#x}", stack.code);
     } else if ist == 0xE {
         log::error!("KERNEL MODE PAGE FAULT: Error code {:#x}", stack.code);
     }
 
-    let handler = {
-        let handlers = INTERRUPT_HANDLERS.lock();
-        handlers[ist]
-    };
+    let handler = INTERRUPT_HANDLERS.read()[ist];
 
     match handler {
-        Some(handler) => handler(stack),
-        None => {
-            crate::backtrace::backtrace(Some(stack.rbp));
-            log::error!(
-                r#"Interrupt {:#x}, error code {:#x} on core {}
-                Registers at exception:
-                    rax {:016x} rcx {:016x} rdx {:016x} rbx {:016x}
-                    rsp {:016x} rbp {:016x} rsi {:016x} rdi {:016x}
-                    r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}
-                    r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}
-                    rfl {:016x}
-                    rip {:016x}
-                    cr2 {:016x}
-                    cs {:02x} ss {:02x}
-                "#,
-                ist,
-                stack.code,
-                core!().id,
-                stack.rax,
-                stack.rcx,
-                stack.rdx,
-                stack.rbx,
-                stack.rsp,
-                stack.rbp,
-                stack.rsi,
-                stack.rdi,
-                stack.r8,
-                stack.r9,
-                stack.r10,
-                stack.r11,
-                stack.r12,
-                stack.r13,
-                stack.r14,
-                stack.r15,
-                stack.rflags,
-                stack.rip,
-                cpu::get_cr2().as_u64(),
-                stack.cs,
-                stack.ss
-            );
-            crate::hcf()
+        // A syscall is the caller carrying on, not an interrupt
+        Some(handler) if ist >= FIRST_DEVICE_VECTOR && ist != syscall::VECTOR => {
+            let nesting = NESTING.get();
+            nesting.fetch_add(1, Ordering::Relaxed);
+            handler(stack);
+            nesting.fetch_sub(1, Ordering::Relaxed);
         }
+        Some(handler) => handler(stack),
+        None => unhandled(ist, stack),
+    }
+
+    trace::event(Event::IrqExit, [ist as u64, 0]);
+    stack::check();
+}
+
+/// Panics over an interrupt nobody expected, for handlers that find out it wasn't theirs
+pub fn unhandled(vector: usize, stack: &InterruptStack) -> ! {
+    // The first core to get here wins, the others panic without the frame
+    if let Some(mut exception) = EXCEPTION.try_lock() {
+        exception.get_or_insert(Exception {
+            vector,
+            stack: *stack,
+        });
+    }
+
+    match backtrace::lookup(stack.rip) {
+        Some((name, offset)) => panic!(
+            "{} ({vector:#x}), error code {:#x} at {:#x} ({name:#}+{offset:#x})",
+            exception_name(vector),
+            stack.code,
+            stack.rip
+        ),
+        None => panic!(
+            "{} ({vector:#x}), error code {:#x} at {:#x}",
+            exception_name(vector),
+            stack.code,
+            stack.rip
+        ),
     }
 }
 
@@ -737,3 +862,77 @@ static HANDLERS: [unsafe extern "C" fn(); 256] = [
     interrupt_handler_254,
     interrupt_handler_255,
 ];
+
+ktest! {
+    fn self_ipi_reaches_its_handler() {
+        use crate::apic::ICR_SELF;
+
+        static FIRED: AtomicU64 = AtomicU64::new(0);
+
+        fn handler(_: &mut InterruptStack) {
+            FIRED.fetch_add(1, Ordering::Relaxed);
+            core!().apic.lock().eoi();
+        }
+
+        let vector = allocate_handler(handler).expect("no free vector");
+        let before = count(core!().id, vector);
+
+        unsafe {
+            core!().apic.lock().ipi(0, vector as u32 | ICR_SELF);
+            core::arch::asm!("sti; nop; cli");
+        }
+
+        free_handler(vector);
+        assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+        assert_eq!(count(core!().id, vector), before + 1);
+    }
+}
+
+kbench! {
+    fn self_ipi_round_trip(b: &mut Bencher) {
+        use crate::apic::ICR_SELF;
+
+        fn handler(_: &mut InterruptStack) {
+            core!().apic.lock().eoi();
+        }
+
+        let vector = allocate_handler(handler).expect("no free vector");
+        b.iter(|| unsafe {
+            core!().apic.lock().ipi(0, vector as u32 | ICR_SELF);
+            core::arch::asm!("sti; nop; cli");
+        });
+
+        free_handler(vector);
+    }
+
+    /// From sending an IPI to another core to seeing its handler run, waking it up included
+    fn ipi_round_trip(b: &mut Bencher) {
+        static PONGS: AtomicU64 = AtomicU64::new(0);
+
+        fn pong(_: &mut InterruptStack) {
+            PONGS.fetch_add(1, Ordering::Release);
+            core!().apic.lock().eoi();
+        }
+
+        let me = core!().apic.lock().id();
+        let target = madt::cpus().find(|cpu| cpu.apic_id != me);
+        let (Some(target), true) = (target, core_locals::cores_online() > 1) else {
+            b.skip("needs a second core");
+            return;
+        };
+
+        let vector = allocate_handler(pong).expect("no free vector");
+        b.iter(|| {
+            let before = PONGS.load(Ordering::Acquire);
+            unsafe { core!().apic.lock().ipi(target.apic_id, vector as u32) };
+
+            let deadline = time::ticks() + 1_000_000 * time::tsc_per_us();
+            while PONGS.load(Ordering::Acquire) == before {
+                assert!(time::ticks() < deadline, "core {} didn't answer", target.apic_id);
+                core::hint::spin_loop();
+            }
+        });
+
+        free_handler(vector);
+    }
+}
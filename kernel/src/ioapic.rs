@@ -0,0 +1,198 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::acpi::madt::{self, Polarity, TriggerMode};
+use crate::cpu;
+use crate::mm::mmio::{self, Mmio};
+use crate::mm::PhysAddr;
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+
+/// Offset of the register select register
+const IOREGSEL: usize = 0x00;
+
+/// Offset of the data window register
+const IOWIN: usize = 0x10;
+
+/// Up to the end of the data window
+const SIZE: usize = IOWIN + 4;
+
+/// Index of the version register, bits 16..24 hold the last redirection entry
+const IOAPICVER: u32 = 0x01;
+
+/// Index of the first redirection table register
+const IOREDTBL: u32 = 0x10;
+
+/// Interrupt mask bit of a redirection entry
+const REDIR_MASKED: u64 = 1 << 16;
+
+/// Trigger mode bit of a redirection entry (set = level)
+const REDIR_LEVEL: u64 = 1 << 15;
+
+/// Polarity bit of a redirection entry (set = active low)
+const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+
+pub struct IoApic {
+    mmio: Mmio,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    fn new(info: madt::IoApic) -> Option<IoApic> {
+        let Some(mmio) = mmio::map(PhysAddr::new(info.address as u64), SIZE) else {
+            log::warn!("IOAPIC {} @ {:#x}: can't map it", info.id, info.address);
+            return None;
+        };
+
+        let mut ioapic = IoApic {
+            mmio,
+            gsi_base: info.gsi_base,
+            redirection_entries: 0,
+        };
+
+        let version = ioapic.read(IOAPICVER);
+        ioapic.redirection_entries = ((version >> 16) & 0xFF) + 1;
+
+        log::debug!(
+            "IOAPIC {} @ {:#x}: GSIs {}..{}",
+            info.id,
+            info.address,
+            ioapic.gsi_base,
+            ioapic.gsi_base + ioapic.redirection_entries
+        );
+
+        for pin in 0..ioapic.redirection_entries {
+            ioapic.write_redirection(pin, REDIR_MASKED);
+        }
+
+        Some(ioapic)
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        self.mmio.write(IOREGSEL, register);
+        self.mmio.read(IOWIN)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.mmio.write(IOREGSEL, register);
+        self.mmio.write(IOWIN, value);
+    }
+
+    fn read_redirection(&mut self, pin: u32) -> u64 {
+        let low = self.read(IOREDTBL + pin * 2) as u64;
+        let high = self.read(IOREDTBL + pin * 2 + 1) as u64;
+
+        (high << 32) | low
+    }
+
+    fn write_redirection(&mut self, pin: u32, value: u64) {
+        // Mask the entry while it's being updated so a half written entry never fires
+        self.write(IOREDTBL + pin * 2, REDIR_MASKED as u32);
+        self.write(IOREDTBL + pin * 2 + 1, (value >> 32) as u32);
+        self.write(IOREDTBL + pin * 2, value as u32);
+    }
+}
+
+static IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+fn with_ioapic<R>(gsi: u32, f: impl FnOnce(&mut IoApic, u32) -> R) -> Option<R> {
+    let mut ioapics = IOAPICS.lock();
+    let ioapic = ioapics.iter_mut().find(|io| io.handles(gsi))?;
+    let pin = gsi - ioapic.gsi_base;
+
+    Some(f(ioapic, pin))
+}
+
+pub fn init() {
+    log::trace!("Initializing the IOAPICs");
+
+    if madt::get().is_some_and(|madt| madt.has_8259()) {
+        // Mask every line of both 8259s, everything goes through the IOAPICs from now on
+        unsafe {
+            cpu::outb(0x21, 0xFF);
+            cpu::outb(0xA1, 0xFF);
+        }
+    }
+
+    let mut ioapics = IOAPICS.lock();
+    ioapics.extend(madt::io_apics().filter_map(IoApic::new));
+
+    for nmi in madt::nmi_sources() {
+        let gsi = nmi.gsi;
+        if let Some(ioapic) = ioapics.iter_mut().find(|io| io.handles(gsi)) {
+            // Delivery mode NMI, the vector is ignored
+            let entry = (0b100 << 8) | flags(nmi.polarity, nmi.trigger);
+            ioapic.write_redirection(gsi - ioapic.gsi_base, entry);
+        }
+    }
+}
+
+initcall!(ioapic, init, [acpi]);
+
+fn flags(polarity: Polarity, trigger: TriggerMode) -> u64 {
+    let mut flags = 0;
+
+    if polarity == Polarity::ActiveLow {
+        flags |= REDIR_ACTIVE_LOW;
+    }
+
+    if trigger == TriggerMode::Level {
+        flags |= REDIR_LEVEL;
+    }
+
+    flags
+}
+
+/// Routes a GSI to `vector` on the core with local APIC id `dest_apic_id`, unmasking it
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    dest_apic_id: u32,
+    polarity: Polarity,
+    trigger: TriggerMode,
+) {
+    assert!(
+        dest_apic_id <= 0xFF,
+        "APIC id {dest_apic_id} is not reachable without interrupt remapping"
+    );
+
+    let entry = ((dest_apic_id as u64) << 56) | flags(polarity, trigger) | vector as u64;
+
+    with_ioapic(gsi, |ioapic, pin| ioapic.write_redirection(pin, entry))
+        .unwrap_or_else(|| log::warn!("No IOAPIC handles GSI {gsi}"));
+}
+
+/// Routes a legacy ISA IRQ, honoring the interrupt source overrides from the MADT
+pub fn route_isa_irq(irq: u8, vector: u8, dest_apic_id: u32) {
+    let (gsi, polarity, trigger) = madt::isa_irq(irq);
+    log::debug!("ISA IRQ {irq} -> GSI {gsi} ({polarity:?}, {trigger:?}) -> vector {vector:#x}");
+
+    route_gsi(gsi, vector, dest_apic_id, polarity, trigger);
+}
+
+pub fn mask(gsi: u32) {
+    with_ioapic(gsi, |ioapic, pin| {
+        let entry = ioapic.read_redirection(pin);
+        ioapic.write_redirection(pin, entry | REDIR_MASKED);
+    });
+}
@@ -0,0 +1,179 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::{cmdline, core_locals, ktest, oops, time};
+
+/// How long a batch of iterations should take, what the iteration count is grown to
+const BATCH_US: u64 = 10_000;
+/// Batches timed per benchmark, the median is what gets reported
+const BATCHES: usize = 9;
+const MAX_ITERATIONS: u64 = 1 << 24;
+
+extern "C" {
+    static __kbenches_start: u8;
+    static __kbenches_end: u8;
+}
+
+/// A benchmark registered with `kbench!`
+pub struct Bench {
+    pub name: &'static str,
+    pub module: &'static str,
+    pub run: fn(&mut Bencher),
+}
+
+/// Registers the functions inside as benchmarks, run by `kbench::run` on a `kbench` boot
+///
+/// Each one gets a `Bencher` and calls `iter` with what to time, or `skip` if it can't run
+#[macro_export]
+macro_rules! kbench {
+    ($($(#[$meta:meta])* fn $name:ident($bencher:ident: $ty:ty) $body:block)*) => {
+        $(
+            $(#[$meta])*
+            fn $name($bencher: $ty) $body
+
+            const _: () = {
+                #[used]
+                #[link_section = ".kbenches"]
+                static ENTRY: $crate::kbench::Bench = $crate::kbench::Bench {
+                    name: stringify!($name),
+                    module: module_path!(),
+                    run: $name,
+                };
+            };
+        )*
+    };
+}
+
+/// Nanoseconds per iteration over the batches of a benchmark
+#[derive(Clone, Copy)]
+struct Stats {
+    median: u64,
+    min: u64,
+    max: u64,
+    iterations: u64,
+}
+
+pub struct Bencher {
+    result: Option<Result<Stats, &'static str>>,
+}
+
+impl Bencher {
+    /// Times `f`, in batches of as many calls as it takes to fill `BATCH_US`
+    pub fn iter<R>(&mut self, mut f: impl FnMut() -> R) {
+        let batch_ticks = BATCH_US * time::tsc_per_us();
+
+        // Doubling the count doubles as the warm-up
+        let mut iterations = 1;
+        while iterations < MAX_ITERATIONS && batch(&mut f, iterations) < batch_ticks {
+            iterations *= 2;
+        }
+
+        let mut ns = [0; BATCHES];
+        for ns in &mut ns {
+            let ticks = batch(&mut f, iterations);
+            *ns = ticks * 1000 / (time::tsc_per_us() * iterations);
+        }
+        ns.sort_unstable();
+
+        self.result = Some(Ok(Stats {
+            median: ns[BATCHES / 2],
+            min: ns[0],
+            max: ns[BATCHES - 1],
+            iterations,
+        }));
+    }
+
+    /// Reports the benchmark as skipped, for when the machine lacks what it needs
+    pub fn skip(&mut self, reason: &'static str) {
+        self.result = Some(Err(reason));
+    }
+}
+
+/// TSC ticks `iterations` calls to `f` take
+fn batch<R>(f: &mut impl FnMut() -> R, iterations: u64) -> u64 {
+    let start = time::ticks();
+    for _ in 0..iterations {
+        core::hint::black_box(f());
+    }
+
+    time::ticks() - start
+}
+
+/// Every benchmark in the `.kbenches` section, in link order
+fn benches() -> &'static [Bench] {
+    unsafe {
+        let start = core::ptr::addr_of!(__kbenches_start) as *const Bench;
+        let end = core::ptr::addr_of!(__kbenches_end) as *const Bench;
+
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Runs every registered benchmark when booted with `kbench`, or built with the `kbench` feature,
+/// then reports to QEMU's isa-debug-exit
+///
+/// `kbench=<prefix>` only runs the benchmarks whose path starts with it. Results come out one per
+/// line as `kbench: <path> median=<ns> min=<ns> max=<ns> iters=<n>`, what `tools/kbenchcmp`
+/// compares between runs
+pub fn run() {
+    let filter = cmdline::value("kbench");
+    if !cfg!(feature = "kbench") && !cmdline::flag("kbench") && filter.is_none() {
+        return;
+    }
+
+    let benches = benches();
+    log::info!(
+        "kbench: {} benchmarks, tsc_per_us={} cores={}",
+        benches.len(),
+        time::tsc_per_us(),
+        core_locals::cores_online()
+    );
+
+    let mut failed = 0;
+    for bench in benches {
+        let module = bench
+            .module
+            .strip_prefix("kernel::")
+            .unwrap_or(bench.module);
+        if filter.is_some_and(|prefix| !module.starts_with(prefix)) {
+            continue;
+        }
+
+        let mut bencher = Bencher { result: None };
+        let oopsed = oops::recoverable(bench.name, || (bench.run)(&mut bencher)).is_err();
+
+        match bencher.result {
+            _ if oopsed => {
+                log::error!("kbench: {module}::{} FAILED", bench.name);
+                failed += 1;
+            }
+            Some(Ok(stats)) => log::info!(
+                "kbench: {module}::{} median={} min={} max={} iters={}",
+                bench.name,
+                stats.median,
+                stats.min,
+                stats.max,
+                stats.iterations
+            ),
+            Some(Err(reason)) => log::info!("kbench: {module}::{} skipped: {reason}", bench.name),
+            None => log::warn!("kbench: {module}::{} timed nothing", bench.name),
+        }
+    }
+
+    log::info!("kbench: done");
+    ktest::exit(failed == 0);
+}
@@ -0,0 +1,229 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::cmdline;
+use crate::input::KeyCode::{self, *};
+use crate::input::Modifiers;
+use crate::sync::Mutex;
+
+const KEYMAPS: &[&Keymap] = &[&US, &IT];
+
+static CURRENT: Mutex<&Keymap> = Mutex::new(&US);
+
+/// What each key of a layout types: alone, with shift, with AltGr and with both, `'\0'`
+/// meaning nothing
+pub struct Keymap {
+    pub name: &'static str,
+    pub keys: &'static [(KeyCode, char, char, char, char)],
+}
+
+impl Keymap {
+    pub fn translate(&self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        let c = common(key, modifiers).or_else(|| {
+            let &(_, normal, shifted, altgr, shifted_altgr) =
+                self.keys.iter().find(|entry| entry.0 == key)?;
+
+            // Caps lock only shifts letters
+            let shift = modifiers.contains(Modifiers::SHIFT)
+                ^ (modifiers.contains(Modifiers::CAPS_LOCK) && normal.is_alphabetic());
+
+            let c = match (modifiers.contains(Modifiers::ALTGR), shift) {
+                (false, false) => normal,
+                (false, true) => shifted,
+                (true, false) => altgr,
+                (true, true) => shifted_altgr,
+            };
+
+            (c != '\0').then_some(c)
+        })?;
+
+        // Ctrl turns letters into the matching control characters
+        if modifiers.contains(Modifiers::CTRL) && c.is_ascii_alphabetic() {
+            return Some((c.to_ascii_lowercase() as u8 & 0x1F) as char);
+        }
+
+        Some(c)
+    }
+}
+
+/// Keys that type the same thing whatever the layout
+fn common(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    let num_lock = modifiers.contains(Modifiers::NUM_LOCK);
+
+    Some(match key {
+        Escape => '\x1B',
+        Backspace => '\x08',
+        Tab => '\t',
+        Enter | KeypadEnter => '\n',
+        Space => ' ',
+        Delete => '\x7F',
+        KeypadDivide => '/',
+        KeypadMultiply => '*',
+        KeypadMinus => '-',
+        KeypadPlus => '+',
+        KeypadPeriod if num_lock => '.',
+        Keypad0 if num_lock => '0',
+        Keypad1 if num_lock => '1',
+        Keypad2 if num_lock => '2',
+        Keypad3 if num_lock => '3',
+        Keypad4 if num_lock => '4',
+        Keypad5 if num_lock => '5',
+        Keypad6 if num_lock => '6',
+        Keypad7 if num_lock => '7',
+        Keypad8 if num_lock => '8',
+        Keypad9 if num_lock => '9',
+        _ => return None,
+    })
+}
+
+pub fn current() -> &'static Keymap {
+    *CURRENT.lock()
+}
+
+/// Switches to the keymap called `name`, returns whether it exists
+pub fn set(name: &str) -> bool {
+    let Some(keymap) = KEYMAPS.iter().find(|k| k.name == name) else {
+        return false;
+    };
+
+    *CURRENT.lock() = keymap;
+    log::info!("keyboard: using the {name} keymap");
+    true
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    KEYMAPS.iter().map(|keymap| keymap.name)
+}
+
+/// Switches to the keymap given with `keymap=` on the command line
+pub fn init() {
+    if let Some(name) = cmdline::value("keymap") {
+        if !set(name) {
+            log::warn!("keyboard: no keymap called {name}");
+        }
+    }
+}
+
+initcall!(keymap, init, []);
+
+pub static US: Keymap = Keymap {
+    name: "us",
+    keys: &[
+        (Backquote, '`', '~', '\0', '\0'),
+        (Digit1, '1', '!', '\0', '\0'),
+        (Digit2, '2', '@', '\0', '\0'),
+        (Digit3, '3', '#', '\0', '\0'),
+        (Digit4, '4', '$', '\0', '\0'),
+        (Digit5, '5', '%', '\0', '\0'),
+        (Digit6, '6', '^', '\0', '\0'),
+        (Digit7, '7', '&', '\0', '\0'),
+        (Digit8, '8', '*', '\0', '\0'),
+        (Digit9, '9', '(', '\0', '\0'),
+        (Digit0, '0', ')', '\0', '\0'),
+        (Minus, '-', '_', '\0', '\0'),
+        (Equal, '=', '+', '\0', '\0'),
+        (Q, 'q', 'Q', '\0', '\0'),
+        (W, 'w', 'W', '\0', '\0'),
+        (E, 'e', 'E', '\0', '\0'),
+        (R, 'r', 'R', '\0', '\0'),
+        (T, 't', 'T', '\0', '\0'),
+        (Y, 'y', 'Y', '\0', '\0'),
+        (U, 'u', 'U', '\0', '\0'),
+        (I, 'i', 'I', '\0', '\0'),
+        (O, 'o', 'O', '\0', '\0'),
+        (P, 'p', 'P', '\0', '\0'),
+        (LeftBracket, '[', '{', '\0', '\0'),
+        (RightBracket, ']', '}', '\0', '\0'),
+        (Backslash, '\\', '|', '\0', '\0'),
+        (A, 'a', 'A', '\0', '\0'),
+        (S, 's', 'S', '\0', '\0'),
+        (D, 'd', 'D', '\0', '\0'),
+        (F, 'f', 'F', '\0', '\0'),
+        (G, 'g', 'G', '\0', '\0'),
+        (H, 'h', 'H', '\0', '\0'),
+        (J, 'j', 'J', '\0', '\0'),
+        (K, 'k', 'K', '\0', '\0'),
+        (L, 'l', 'L', '\0', '\0'),
+        (Semicolon, ';', ':', '\0', '\0'),
+        (Quote, '\'', '"', '\0', '\0'),
+        (NonUsBackslash, '\\', '|', '\0', '\0'),
+        (Z, 'z', 'Z', '\0', '\0'),
+        (X, 'x', 'X', '\0', '\0'),
+        (C, 'c', 'C', '\0', '\0'),
+        (V, 'v', 'V', '\0', '\0'),
+        (B, 'b', 'B', '\0', '\0'),
+        (N, 'n', 'N', '\0', '\0'),
+        (M, 'm', 'M', '\0', '\0'),
+        (Comma, ',', '<', '\0', '\0'),
+        (Period, '.', '>', '\0', '\0'),
+        (Slash, '/', '?', '\0', '\0'),
+    ],
+};
+
+pub static IT: Keymap = Keymap {
+    name: "it",
+    keys: &[
+        (Backquote, '\\', '|', '\0', '\0'),
+        (Digit1, '1', '!', '\0', '\0'),
+        (Digit2, '2', '"', '\0', '\0'),
+        (Digit3, '3', '£', '\0', '\0'),
+        (Digit4, '4', '$', '\0', '\0'),
+        (Digit5, '5', '%', '€', '\0'),
+        (Digit6, '6', '&', '\0', '\0'),
+        (Digit7, '7', '/', '\0', '\0'),
+        (Digit8, '8', '(', '\0', '\0'),
+        (Digit9, '9', ')', '\0', '\0'),
+        (Digit0, '0', '=', '\0', '\0'),
+        (Minus, '\'', '?', '\0', '\0'),
+        (Equal, 'ì', '^', '\0', '\0'),
+        (Q, 'q', 'Q', '\0', '\0'),
+        (W, 'w', 'W', '\0', '\0'),
+        (E, 'e', 'E', '€', '\0'),
+        (R, 'r', 'R', '\0', '\0'),
+        (T, 't', 'T', '\0', '\0'),
+        (Y, 'y', 'Y', '\0', '\0'),
+        (U, 'u', 'U', '\0', '\0'),
+        (I, 'i', 'I', '\0', '\0'),
+        (O, 'o', 'O', '\0', '\0'),
+        (P, 'p', 'P', '\0', '\0'),
+        (LeftBracket, 'è', 'é', '[', '{'),
+        (RightBracket, '+', '*', ']', '}'),
+        (Backslash, 'ù', '§', '\0', '\0'),
+        (A, 'a', 'A', '\0', '\0'),
+        (S, 's', 'S', '\0', '\0'),
+        (D, 'd', 'D', '\0', '\0'),
+        (F, 'f', 'F', '\0', '\0'),
+        (G, 'g', 'G', '\0', '\0'),
+        (H, 'h', 'H', '\0', '\0'),
+        (J, 'j', 'J', '\0', '\0'),
+        (K, 'k', 'K', '\0', '\0'),
+        (L, 'l', 'L', '\0', '\0'),
+        (Semicolon, 'ò', 'ç', '@', '\0'),
+        (Quote, 'à', '°', '#', '\0'),
+        (NonUsBackslash, '<', '>', '\0', '\0'),
+        (Z, 'z', 'Z', '\0', '\0'),
+        (X, 'x', 'X', '\0', '\0'),
+        (C, 'c', 'C', '\0', '\0'),
+        (V, 'v', 'V', '\0', '\0'),
+        (B, 'b', 'B', '\0', '\0'),
+        (N, 'n', 'N', '\0', '\0'),
+        (M, 'm', 'M', '\0', '\0'),
+        (Comma, ',', ';', '\0', '\0'),
+        (Period, '.', ':', '\0', '\0'),
+        (Slash, '-', '_', '\0', '\0'),
+    ],
+};
@@ -0,0 +1,188 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::madt;
+use crate::driver::{self, Driver, Match, ProbeError};
+use crate::i8042::{self, Port};
+use crate::input::{self, KeyCode, KeyEvent, Modifiers};
+use crate::interrupts::{self, InterruptStack};
+use crate::ioapic;
+use crate::sync::{IrqSpinlock, Mutex};
+use scancode::Decoder;
+
+pub mod keymap;
+mod scancode;
+
+/// Keyboard commands and replies
+const KEYBOARD_SET_LEDS: u8 = 0xED;
+const KEYBOARD_SCANCODE_SET: u8 = 0xF0;
+const KEYBOARD_ENABLE_SCANNING: u8 = 0xF4;
+const KEYBOARD_ACK: u8 = 0xFA;
+
+const KEYBOARD_IRQ: u8 = 1;
+
+static STATE: IrqSpinlock<State> = IrqSpinlock::new(State {
+    decoder: Decoder::new(),
+    modifiers: Modifiers::NUM_LOCK,
+});
+static VECTOR: Mutex<Option<u8>> = Mutex::new(None);
+
+static DRIVER: Driver = Driver {
+    name: "ps2-keyboard",
+    order: 40,
+    matches: &[Match::AcpiHid("PNP0303")],
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+struct State {
+    decoder: Decoder,
+    modifiers: Modifiers,
+}
+
+impl State {
+    /// Tracks modifiers and lock keys, returns whether the LEDs need updating
+    fn update_modifiers(&mut self, key: KeyCode, pressed: bool) -> bool {
+        let modifier = match key {
+            KeyCode::LeftShift | KeyCode::RightShift => Modifiers::SHIFT,
+            KeyCode::LeftCtrl | KeyCode::RightCtrl => Modifiers::CTRL,
+            KeyCode::LeftAlt => Modifiers::ALT,
+            KeyCode::RightAlt => Modifiers::ALTGR,
+            KeyCode::LeftSuper | KeyCode::RightSuper => Modifiers::SUPER,
+            KeyCode::CapsLock | KeyCode::NumLock | KeyCode::ScrollLock if pressed => {
+                self.modifiers.toggle(match key {
+                    KeyCode::CapsLock => Modifiers::CAPS_LOCK,
+                    KeyCode::NumLock => Modifiers::NUM_LOCK,
+                    _ => Modifiers::SCROLL_LOCK,
+                });
+
+                return true;
+            }
+            _ => return false,
+        };
+
+        self.modifiers.set(modifier, pressed);
+        false
+    }
+
+    fn leds(&self) -> u8 {
+        (self.modifiers.contains(Modifiers::SCROLL_LOCK) as u8)
+            | (self.modifiers.contains(Modifiers::NUM_LOCK) as u8) << 1
+            | (self.modifiers.contains(Modifiers::CAPS_LOCK) as u8) << 2
+    }
+}
+
+/// Sends a byte to the keyboard and waits for it to be acknowledged, only usable while the
+/// interrupt isn't routed yet
+fn send(byte: u8) -> bool {
+    i8042::send(Port::Keyboard, byte).is_ok() && i8042::read() == Ok(KEYBOARD_ACK)
+}
+
+/// Sets the keyboard up again after an i8042 reset, the interrupt handler eats the
+/// acknowledgements
+fn reinit() {
+    let leds = STATE.lock().leds();
+
+    for byte in [
+        KEYBOARD_SCANCODE_SET,
+        2,
+        KEYBOARD_SET_LEDS,
+        leds,
+        KEYBOARD_ENABLE_SCANNING,
+    ] {
+        if i8042::send(Port::Keyboard, byte).is_err() {
+            return;
+        }
+    }
+}
+
+fn interrupt(_stack: &mut InterruptStack) {
+    while let Some((port, byte)) = i8042::poll() {
+        if port != Port::Keyboard {
+            continue;
+        }
+
+        let mut state = STATE.lock();
+        let Some((key, pressed)) = state.decoder.feed(byte) else {
+            continue;
+        };
+
+        let update_leds = state.update_modifiers(key, pressed);
+        let (modifiers, leds) = (state.modifiers, state.leds());
+        drop(state);
+
+        if update_leds {
+            // The acknowledgements come back through here and the decoder ignores them
+            let _ = i8042::send(Port::Keyboard, KEYBOARD_SET_LEDS)
+                .and_then(|_| i8042::send(Port::Keyboard, leds));
+        }
+
+        input::push(KeyEvent {
+            key,
+            pressed,
+            modifiers,
+            char: keymap::current().translate(key, modifiers),
+        });
+    }
+
+    core!().apic.lock().eoi();
+}
+
+fn probe(_device: &driver::Device) -> Result<(), ProbeError> {
+    i8042::init(Port::Keyboard).map_err(|err| match err {
+        i8042::Error::NoSuchPort => ProbeError::Unsupported,
+        _ => ProbeError::Failed("i8042 doesn't respond"),
+    })?;
+
+    // Scancode set 2 as the keyboard sends it, i8042 translation stays off
+    if !send(KEYBOARD_SCANCODE_SET) || !send(2) {
+        return Err(ProbeError::Failed(
+            "keyboard doesn't support scancode set 2",
+        ));
+    }
+
+    let vector = interrupts::allocate_handler(interrupt)
+        .ok_or(ProbeError::Failed("no free interrupt vectors"))?;
+    *VECTOR.lock() = Some(vector);
+
+    let apic_id = core!().apic.lock().id();
+    ioapic::route_isa_irq(KEYBOARD_IRQ, vector, apic_id);
+
+    let leds = STATE.lock().leds();
+    send(KEYBOARD_SET_LEDS);
+    send(leds);
+
+    if !send(KEYBOARD_ENABLE_SCANNING) {
+        log::warn!("keyboard: scanning not acknowledged");
+    }
+
+    i8042::register_reset_handler(reinit);
+
+    log::info!("keyboard: PS/2 on IRQ {KEYBOARD_IRQ}, vector {vector:#x}");
+    Ok(())
+}
+
+fn remove(_device: &driver::Device) {
+    let (gsi, _, _) = madt::isa_irq(KEYBOARD_IRQ);
+    ioapic::mask(gsi);
+
+    if let Some(vector) = VECTOR.lock().take() {
+        interrupts::free_handler(vector);
+    }
+}
@@ -0,0 +1,202 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::input::KeyCode::{self, *};
+
+const EXTENDED: u8 = 0xE0;
+const RELEASE: u8 = 0xF0;
+/// Pause sends `E1 14 77 E1 F0 14 F0 77` on press and nothing on release
+const PAUSE: u8 = 0xE1;
+const PAUSE_LEN: u8 = 8;
+
+/// Extended left shift, sent around some keys to undo a held shift or num lock
+const FAKE_SHIFT: u8 = 0x12;
+const FAKE_SHIFT_RIGHT: u8 = 0x59;
+
+/// Decodes scancode set 2 one byte at a time
+pub struct Decoder {
+    extended: bool,
+    release: bool,
+    /// Bytes of the pause sequence still to swallow
+    pause: u8,
+}
+
+impl Decoder {
+    pub const fn new() -> Decoder {
+        Decoder {
+            extended: false,
+            release: false,
+            pause: 0,
+        }
+    }
+
+    /// Feeds a byte from the keyboard, returns the key and whether it was pressed once a
+    /// whole scancode came in
+    pub fn feed(&mut self, byte: u8) -> Option<(KeyCode, bool)> {
+        if self.pause > 0 {
+            self.pause -= 1;
+            return None;
+        }
+
+        match byte {
+            EXTENDED => {
+                self.extended = true;
+                None
+            }
+            RELEASE => {
+                self.release = true;
+                None
+            }
+            PAUSE => {
+                self.pause = PAUSE_LEN - 1;
+                Some((Pause, true))
+            }
+            code => {
+                let extended = core::mem::take(&mut self.extended);
+                let pressed = !core::mem::take(&mut self.release);
+
+                if extended && (code == FAKE_SHIFT || code == FAKE_SHIFT_RIGHT) {
+                    return None;
+                }
+
+                let key = if extended {
+                    extended_key(code)
+                } else {
+                    key(code)
+                };
+
+                key.map(|key| (key, pressed))
+            }
+        }
+    }
+}
+
+fn key(code: u8) -> Option<KeyCode> {
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Backquote,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Digit1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Digit2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Digit4,
+        0x26 => Digit3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Digit5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Digit6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Digit7,
+        0x3E => Digit8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Digit0,
+        0x46 => Digit9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equal,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6B => Keypad4,
+        0x6C => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7A => Keypad3,
+        0x7B => KeypadMinus,
+        0x7C => KeypadMultiply,
+        0x7D => Keypad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+fn extended_key(code: u8) -> Option<KeyCode> {
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1F => LeftSuper,
+        0x27 => RightSuper,
+        0x2F => Menu,
+        0x4A => KeypadDivide,
+        0x5A => KeypadEnter,
+        0x69 => End,
+        0x6B => Left,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => Down,
+        0x74 => Right,
+        0x75 => Up,
+        0x7A => PageDown,
+        0x7C => PrintScreen,
+        0x7D => PageUp,
+        _ => return None,
+    })
+}
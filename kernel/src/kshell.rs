@@ -0,0 +1,816 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::fs::file::OpenFlags;
+use crate::keyboard::keymap;
+use crate::mm::{vmm, PhysAddr, VirtAddr};
+use crate::pci::caps::Capability;
+use crate::sync::Mutex;
+use crate::{
+    block, cmdline, cpu, cpufreq, cpuidle, efi, fb_renderer, fs, fw_cfg, hda, input, oops, pci,
+    power, serial, syscall, virtio,
+};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+const PROMPT: &str = "kshell> ";
+const MAX_LINE: usize = 256;
+
+/// Terminal the console session runs on, Alt+F2
+const TERMINAL: usize = 1;
+
+/// Where a session reads from and answers to
+#[derive(Clone, Copy)]
+enum Port {
+    Serial(usize),
+    Console,
+    VirtioConsole,
+}
+
+impl Port {
+    fn read(self) -> Option<char> {
+        match self {
+            Port::Serial(port) => serial::read_byte(port).map(|b| b as char),
+            // Keys typed on other terminals aren't meant for the shell
+            Port::Console if fb_renderer::active() == TERMINAL => input::read_char(),
+            Port::Console => None,
+            Port::VirtioConsole => virtio::console::read_byte().map(|b| b as char),
+        }
+    }
+}
+
+impl Write for Port {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match *self {
+            Port::Serial(port) => serial::write_bytes(port, s.as_bytes()),
+            Port::Console => fb_renderer::write_to(TERMINAL, format_args!("{s}")),
+            Port::VirtioConsole => virtio::console::_print(format_args!("{s}")),
+        }
+
+        Ok(())
+    }
+}
+
+struct Session {
+    port: Port,
+    line: String,
+    /// Terminals send CR LF, the LF shouldn't run an empty line
+    after_cr: bool,
+}
+
+impl Session {
+    const fn new(port: Port) -> Session {
+        Session {
+            port,
+            line: String::new(),
+            after_cr: false,
+        }
+    }
+
+    fn poll(&mut self) {
+        while let Some(c) = self.port.read() {
+            let after_cr = core::mem::replace(&mut self.after_cr, c == '\r');
+
+            match c {
+                '\n' if after_cr => {}
+                '\r' | '\n' => {
+                    let _ = self.port.write_str("\r\n");
+                    let line = core::mem::take(&mut self.line);
+                    run(&mut self.port, line.trim());
+                    let _ = self.port.write_str(PROMPT);
+                }
+                '\x08' | '\x7F' => {
+                    if self.line.pop().is_some() {
+                        let _ = self.port.write_str("\x08 \x08");
+                    }
+                }
+                c if !c.is_control() && self.line.len() < MAX_LINE => {
+                    self.line.push(c);
+                    let _ = self.port.write_char(c);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+static SERIAL: Mutex<Option<Session>> = Mutex::new(None);
+static CONSOLE: Mutex<Option<Session>> = Mutex::new(None);
+static VIRTIO_CONSOLE: Mutex<Option<Session>> = Mutex::new(None);
+
+type Command = fn(&mut Port, &[&str]) -> Result<(), &'static str>;
+
+/// Name, usage and handler of every command
+const COMMANDS: &[(&str, &str, Command)] = &[
+    ("help", "help", help),
+    ("md", "md <addr> [len]          dump virtual memory", md),
+    ("mdp", "mdp <phys> [len]        dump physical memory", mdp),
+    ("rdmsr", "rdmsr <msr>", rdmsr),
+    ("wrmsr", "wrmsr <msr> <value>", wrmsr),
+    (
+        "pci",
+        "pci [bars|caps <bdf>|read|write <bdf> <offset> [value]]",
+        pci,
+    ),
+    ("cores", "cores                   online cores", cores),
+    ("mem", "mem                     memory usage", mem),
+    ("stacks", "stacks                  stack usage", stacks),
+    ("idle", "idle                    idle state usage", idle),
+    ("freq", "freq                    core frequency", freq),
+    ("heap", "heap [count]            top allocation sites", heap),
+    ("dmesg", "dmesg", dmesg),
+    ("loglevel", "loglevel <sink> <level>", loglevel),
+    ("ls", "ls <path>", ls),
+    ("cat", "cat <path>", cat),
+    (
+        "fwcfg",
+        "fwcfg <name>            print a fw_cfg file",
+        fwcfg,
+    ),
+    (
+        "blk",
+        "blk <dev> read <offset> [len]|write <offset> <byte> [len]",
+        blk,
+    ),
+    (
+        "sync",
+        "sync [dev]              write back the block cache",
+        sync,
+    ),
+    ("date", "date                    firmware clock", date),
+    (
+        "efivar",
+        "efivar <name>           EFI global variable",
+        efivar,
+    ),
+    ("keymap", "keymap [name]", keymap),
+    ("tone", "tone <hz> [ms]          play a square wave", tone),
+    ("volume", "volume <percent>", volume),
+    ("test", "test panic|pagefault|ud|divide", test),
+    ("inject", "inject pmm|heap [off|nth:N|random:N]", inject),
+    ("trace", "trace start [groups]|stop|clear|dump", trace),
+    (
+        "profile",
+        "profile start [hz]|stop|dump|top [count]",
+        profile,
+    ),
+    ("reboot", "reboot", reboot),
+    ("poweroff", "poweroff", poweroff),
+];
+
+fn run(port: &mut Port, line: &str) {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    let args: Vec<&str> = words.collect();
+
+    let Some((_, usage, command)) = COMMANDS.iter().find(|(n, _, _)| *n == name) else {
+        let _ = write!(port, "{name}: unknown command, try help\r\n");
+        return;
+    };
+
+    match oops::recoverable("kshell", || command(port, &args)) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => {
+            let _ = write!(port, "{name}: {err}\r\nusage: {usage}\r\n");
+        }
+        Err(_) => {
+            let _ = write!(port, "{name}: oops, see the log\r\n");
+        }
+    }
+}
+
+/// Parses `0x` prefixed hex or decimal, or takes the address of the kernel function named `arg`
+pub fn number(arg: Option<&&str>) -> Result<u64, &'static str> {
+    let arg = arg.ok_or("missing argument")?;
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+    .or_else(|| crate::backtrace::address_of(arg))
+    .ok_or("invalid number")
+}
+
+/// Writes `text` turning bare LFs into CR LF, which serial terminals want
+fn write_text(port: &mut Port, text: &[u8]) {
+    for line in String::from_utf8_lossy(text).split_inclusive('\n') {
+        let _ = port.write_str(line.strip_suffix('\n').unwrap_or(line));
+        if line.ends_with('\n') {
+            let _ = port.write_str("\r\n");
+        }
+    }
+}
+
+fn help(port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    for (_, usage, _) in COMMANDS {
+        let _ = write!(port, "  {usage}\r\n");
+    }
+
+    Ok(())
+}
+
+/// Dumps `len` bytes at `addr`, 16 a line with their ASCII on the side
+pub fn dump(port: &mut impl Write, addr: u64, len: u64) {
+    let end = addr.saturating_add(len);
+
+    for line in (addr..end).step_by(16) {
+        let count = (end - line).min(16) as usize;
+        if !vmm::readable(line) || !vmm::readable(line + count as u64 - 1) {
+            let _ = write!(port, "{line:016x}: not mapped\r\n");
+            continue;
+        }
+
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes[..count].iter_mut().enumerate() {
+            *byte = unsafe { core::ptr::read_volatile((line + i as u64) as *const u8) };
+        }
+
+        let _ = write!(port, "{line:016x}:");
+        for (i, byte) in bytes.iter().enumerate() {
+            let _ = match i < count {
+                true => write!(port, " {byte:02x}"),
+                false => port.write_str("   "),
+            };
+        }
+
+        let _ = port.write_str("  ");
+        for &byte in &bytes[..count] {
+            let c = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            let _ = port.write_char(c);
+        }
+        let _ = port.write_str("\r\n");
+    }
+}
+
+fn md(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let addr = number(args.first())?;
+    let len = args.get(1).map_or(Ok(64), |len| number(Some(len)))?;
+
+    dump(port, addr, len);
+    Ok(())
+}
+
+fn mdp(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let addr = PhysAddr::new(number(args.first())?).as_hhdm();
+    let len = args.get(1).map_or(Ok(64), |len| number(Some(len)))?;
+
+    dump(port, addr.as_u64(), len);
+    Ok(())
+}
+
+fn rdmsr(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let msr = number(args.first())? as u32;
+    let _ = write!(port, "{msr:#x}: {:#018x}\r\n", unsafe { cpu::rdmsr(msr) });
+
+    Ok(())
+}
+
+fn wrmsr(_port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let msr = number(args.first())? as u32;
+    let value = number(args.get(1))?;
+
+    unsafe { cpu::wrmsr(msr, value) };
+    Ok(())
+}
+
+/// Parses `bus:device.function`, optionally behind a `segment:`
+fn pci_address(arg: Option<&&str>) -> Result<pci::Address, &'static str> {
+    let arg = arg.ok_or("missing address")?;
+    let (rest, function) = arg.rsplit_once('.').ok_or("invalid address")?;
+    let mut parts = rest.rsplit(':');
+
+    let hex = |part: Option<&str>| part.and_then(|p| u16::from_str_radix(p, 16).ok());
+    let device = hex(parts.next()).ok_or("invalid address")?;
+    let bus = hex(parts.next()).ok_or("invalid address")?;
+    let segment = parts.next().map_or(Some(0), |p| hex(Some(p)));
+    let function = hex(Some(function)).ok_or("invalid address")?;
+
+    match segment {
+        Some(segment) if bus <= 0xFF && device < 32 && function < 8 => Ok(pci::Address::new(
+            segment,
+            bus as u8,
+            device as u8,
+            function as u8,
+        )),
+        _ => Err("invalid address"),
+    }
+}
+
+fn pci(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let Some(&op) = args.first() else {
+        for device in pci::devices() {
+            let _ = write!(
+                port,
+                "{} {:04x}:{:04x} {}\r\n",
+                device.address,
+                device.vendor_id,
+                device.device_id,
+                device.class_name()
+            );
+        }
+        return Ok(());
+    };
+
+    let address = pci_address(args.get(1))?;
+    let device = pci::devices()
+        .into_iter()
+        .find(|device| device.address == address)
+        .ok_or("no such device")?;
+
+    if op == "bars" {
+        for n in 0..6 {
+            let _ = match device.bar(n) {
+                Some(pci::bar::Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                    is_64bit,
+                }) => write!(
+                    port,
+                    "BAR{n}: memory at {address:#x} size {size:#x}{}{}\r\n",
+                    if is_64bit { ", 64 bit" } else { "" },
+                    if prefetchable { ", prefetchable" } else { "" }
+                ),
+                Some(pci::bar::Bar::Io { port: base, size }) => {
+                    write!(port, "BAR{n}: io at {base:#x} size {size:#x}\r\n")
+                }
+                None => Ok(()),
+            };
+        }
+        return Ok(());
+    }
+
+    if op == "caps" {
+        return pci_capabilities(port, &device);
+    }
+
+    let offset = number(args.get(2))?;
+    if offset > 0xFFC || offset % 4 != 0 {
+        return Err("offset has to be dword aligned and below 0x1000");
+    }
+
+    match op {
+        "read" => {
+            let value = device.read32(offset as u16);
+            let _ = write!(port, "{address} {offset:#05x}: {value:#010x}\r\n");
+        }
+        "write" => device.write32(offset as u16, number(args.get(3))? as u32),
+        _ => return Err("unknown operation"),
+    }
+
+    Ok(())
+}
+
+fn pci_capabilities(port: &mut Port, device: &pci::Device) -> Result<(), &'static str> {
+    for capability in device.capabilities() {
+        let _ = write!(port, "[{:02x}] ", capability.id());
+        let _ = match capability {
+            Capability::Msi(msi) => write!(
+                port,
+                "MSI: {} vectors{}{}\r\n",
+                msi.vectors(),
+                if msi.is_64bit() { ", 64 bit" } else { "" },
+                if msi.maskable() { ", maskable" } else { "" }
+            ),
+            Capability::MsiX(msix) => {
+                let (table_bar, table) = msix.table();
+                let (pba_bar, pba) = msix.pending_bits();
+                write!(
+                    port,
+                    "MSI-X: {} entries, table BAR{table_bar}+{table:#x}, \
+                     pending bits BAR{pba_bar}+{pba:#x}\r\n",
+                    msix.table_size()
+                )
+            }
+            Capability::PciExpress(pcie) => write!(
+                port,
+                "PCI Express v{}, port type {}\r\n",
+                pcie.version(),
+                pcie.device_type()
+            ),
+            Capability::Other { offset, .. } => write!(port, "at {offset:#x}\r\n"),
+        };
+    }
+
+    for capability in device.extended_capabilities() {
+        let _ = write!(
+            port,
+            "[{:04x}] v{} at {:#x}\r\n",
+            capability.id, capability.version, capability.offset
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints a `/kernel` file, most commands are just a shortcut for one
+fn kernel_file(port: &mut Port, name: &str) -> Result<(), &'static str> {
+    let path = alloc::format!("/kernel/{name}");
+    let data = fs::read(&path).map_err(|_| "kernelfs isn't mounted")?;
+
+    write_text(port, &data);
+    Ok(())
+}
+
+/// There are no tasks yet, what runs is the idle loop on every core
+fn cores(port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    kernel_file(port, "cpus")
+}
+
+fn mem(port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    kernel_file(port, "meminfo")
+}
+
+fn stacks(port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    kernel_file(port, "stacks")
+}
+
+/// How many times and for how long the current core entered each idle state
+fn idle(port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    let stats = &core!().idle;
+
+    for (state, stats) in cpuidle::states().iter().zip(stats.states.iter()) {
+        let _ = write!(
+            port,
+            "C{}: {} entries, {} us\r\n",
+            state.kind,
+            stats.usage.load(Ordering::Relaxed),
+            stats.time_us.load(Ordering::Relaxed)
+        );
+    }
+
+    Ok(())
+}
+
+fn freq(port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    match cpufreq::current_frequency() {
+        Some(frequency) => write!(port, "{frequency} MHz\r\n"),
+        None => write!(port, "not managed by the kernel\r\n"),
+    }
+    .map_err(|_| "write failed")
+}
+
+fn heap(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let count = match args.first() {
+        Some(count) => count.parse().map_err(|_| "invalid count")?,
+        None => 20,
+    };
+    let (sites, untracked) =
+        crate::mm::heap::top_sites(count).ok_or("boot with heapprof to track call sites")?;
+
+    let _ = write!(port, "     bytes    live    total  site\r\n");
+    for site in sites {
+        let _ = write!(
+            port,
+            "{:>10} {:>7} {:>8}  ",
+            site.live_bytes, site.live, site.total
+        );
+        let _ = match crate::backtrace::lookup(site.caller) {
+            Some((name, offset)) => write!(port, "{name:#}+{offset:#x}\r\n"),
+            None => write!(port, "{:#x}\r\n", site.caller),
+        };
+    }
+
+    if untracked != 0 {
+        let _ = write!(
+            port,
+            "{untracked} allocations untracked, the tables were full\r\n"
+        );
+    }
+    Ok(())
+}
+
+fn dmesg(port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    write_text(port, &crate::logging::history());
+    Ok(())
+}
+
+fn loglevel(_port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let [sink, level] = args else {
+        return Err("expected a sink and a level");
+    };
+    let level = level.parse().map_err(|_| "invalid level")?;
+
+    if crate::logging::set_filter(sink, level) {
+        Ok(())
+    } else {
+        Err("no such sink")
+    }
+}
+
+fn inject(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let site = args.first().ok_or("missing site")?;
+    let site = crate::inject::parse_site(site).ok_or("unknown site")?;
+
+    match args.get(1).copied() {
+        Some("off") => crate::inject::disarm(site),
+        Some(mode) => {
+            let mode = crate::inject::parse_mode(mode).ok_or("invalid mode")?;
+            crate::inject::inject_all(site, mode);
+        }
+        None => {}
+    }
+
+    let _ = write!(
+        port,
+        "{site:?}: {} failures injected\r\n",
+        crate::inject::injected(site)
+    );
+    Ok(())
+}
+
+fn trace(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    match args.first().copied() {
+        Some("start") => crate::trace::start(args.get(1).copied().unwrap_or("all")),
+        Some("stop") => {
+            crate::trace::stop();
+            Ok(())
+        }
+        Some("clear") => {
+            crate::trace::clear();
+            Ok(())
+        }
+        Some("dump") => crate::trace::dump(port).map_err(|_| "write failed"),
+        _ => Err("expected start, stop, clear or dump"),
+    }
+}
+
+fn profile(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    match args.first().copied() {
+        Some("start") => {
+            let hz = match args.get(1) {
+                Some(hz) => hz.parse().map_err(|_| "invalid frequency")?,
+                None => 1000,
+            };
+
+            let source = crate::profile::start(hz)?;
+            let _ = write!(port, "sampling at {hz} Hz with {}\r\n", source.name());
+            Ok(())
+        }
+        Some("stop") => {
+            crate::profile::stop();
+            Ok(())
+        }
+        Some("dump") => crate::profile::dump(port).map_err(|_| "write failed"),
+        Some("top") => {
+            let count = match args.get(1) {
+                Some(count) => count.parse().map_err(|_| "invalid count")?,
+                None => 20,
+            };
+
+            crate::profile::top(port, count).map_err(|_| "write failed")
+        }
+        _ => Err("expected start, stop, dump or top"),
+    }
+}
+
+fn ls(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let path = args.first().copied().unwrap_or("/");
+    let dir = syscall::open(path, OpenFlags::READ | OpenFlags::DIRECTORY)
+        .map_err(|_| "cannot read directory")?;
+
+    while let Ok(Some(entry)) = syscall::readdir(dir) {
+        let _ = write!(port, "{:?}\t{}\r\n", entry.kind, entry.name);
+    }
+
+    let _ = syscall::close(dir);
+    Ok(())
+}
+
+fn cat(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let path = args.first().ok_or("missing path")?;
+    let file = syscall::open(path, OpenFlags::READ).map_err(|_| "cannot read file")?;
+
+    let mut data = Vec::new();
+    let mut buffer = [0; 512];
+    let result = loop {
+        match syscall::read(file, &mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => data.extend_from_slice(&buffer[..read]),
+            Err(_) => break Err("cannot read file"),
+        }
+    };
+
+    let _ = syscall::close(file);
+    write_text(port, &data);
+    result
+}
+
+/// Prints a file the host passed in through fw_cfg, like `-fw_cfg name=opt/...` on QEMU
+fn fwcfg(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let name = args.first().ok_or("missing name")?;
+    let data = fw_cfg::read_file(name).ok_or("no such file")?;
+
+    write_text(port, &data);
+    Ok(())
+}
+
+fn date(port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    let time = efi::get_time().map_err(|_| "no EFI runtime services")?;
+
+    let _ = write!(
+        port,
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}\r\n",
+        time.year, time.month, time.day, time.hour, time.minute, time.second
+    );
+    Ok(())
+}
+
+/// Dumps a variable of the global vendor, like `BootOrder`
+fn efivar(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let name = args.first().ok_or("missing name")?;
+
+    let (attributes, data) = match efi::get_variable(name, &efi::Guid::GLOBAL_VARIABLE) {
+        Ok(variable) => variable,
+        Err(efi::Status::NOT_FOUND) => return Err("no such variable"),
+        Err(_) => return Err("no EFI runtime services"),
+    };
+
+    let _ = write!(port, "attributes {attributes:#x}\r\n");
+    dump(port, data.as_ptr() as u64, data.len() as u64);
+    Ok(())
+}
+
+/// Reads or writes a block device through the block cache
+fn blk(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let queue = block::queue(args.first().ok_or("missing device")?).ok_or("no such device")?;
+    let offset = number(args.get(2))?;
+
+    match args.get(1).copied() {
+        Some("read") => {
+            let len = args.get(3).map_or(Ok(64), |len| number(Some(len)))?;
+            let mut data = vec![0; len as usize];
+            block::cache::read(&queue, offset, &mut data).map_err(|_| "read failed")?;
+
+            for (i, line) in data.chunks(16).enumerate() {
+                let _ = write!(port, "{:016x}:", offset + i as u64 * 16);
+                for byte in line {
+                    let _ = write!(port, " {byte:02x}");
+                }
+                let _ = port.write_str("\r\n");
+            }
+
+            Ok(())
+        }
+        Some("write") => {
+            let byte = u8::try_from(number(args.get(3))?).map_err(|_| "invalid byte")?;
+            let len = args.get(4).map_or(Ok(1), |len| number(Some(len)))?;
+
+            block::cache::write(&queue, offset, &vec![byte; len as usize])
+                .map_err(|_| "write failed")
+        }
+        _ => Err("unknown operation"),
+    }
+}
+
+/// Writes back the block cache, of one device or of all of them
+fn sync(_port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let result = match args.first() {
+        Some(name) => block::cache::sync(&block::queue(name).ok_or("no such device")?),
+        None => block::cache::sync_all(),
+    };
+
+    result.map_err(|_| "write-back failed")
+}
+
+/// Shows the keymaps, or switches to one
+fn keymap(port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    if let Some(name) = args.first() {
+        return keymap::set(name).then_some(()).ok_or("no such keymap");
+    }
+
+    for name in keymap::names() {
+        let current = name == keymap::current().name;
+        let _ = write!(port, "{}{name}\r\n", if current { "* " } else { "  " });
+    }
+
+    Ok(())
+}
+
+/// Plays a square wave through the HD audio output
+fn tone(_port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    const RATE: u32 = 48000;
+
+    if !hda::present() {
+        return Err("no HD audio output");
+    }
+
+    let hz = number(args.first())?.clamp(20, 20000) as usize;
+    let ms = args
+        .get(1)
+        .map_or(Ok(200), |ms| number(Some(ms)))?
+        .min(5000) as usize;
+
+    let half_period = RATE as usize / hz / 2;
+    let samples: Vec<i16> = (0..RATE as usize * ms / 1000)
+        .map(|i| match (i / half_period) % 2 {
+            0 => 8000,
+            _ => -8000,
+        })
+        .flat_map(|sample| [sample, sample])
+        .collect();
+
+    let format = hda::Format {
+        rate: RATE,
+        channels: 2,
+    };
+    hda::play(&samples, format).map_err(|_| "playback failed")
+}
+
+fn volume(_port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    let percent = u8::try_from(number(args.first())?)
+        .ok()
+        .filter(|&percent| percent <= 100)
+        .ok_or("expected 0 to 100")?;
+
+    hda::set_volume(percent).map_err(|_| "no HD audio output")
+}
+
+/// Crashes on purpose, to check the exception and panic paths
+fn test(_port: &mut Port, args: &[&str]) -> Result<(), &'static str> {
+    match args.first().copied() {
+        Some("panic") => panic!("kshell: test panic"),
+        Some("pagefault") => {
+            // The bootloader only identity maps the first 4 GiB of the lower half
+            let addr = VirtAddr::new(0x7FFF_0000_0000);
+            if vmm::translate(addr).is_some() {
+                return Err("test address is mapped");
+            }
+
+            unsafe { core::ptr::read_volatile(addr.as_ptr::<u64>()) };
+        }
+        Some("ud") => unsafe { core::arch::asm!("ud2") },
+        Some("divide") => unsafe {
+            core::arch::asm!("xor edx, edx", "div ecx", in("ecx") 0, inout("eax") 1 => _, out("edx") _);
+        },
+        _ => return Err("unknown test"),
+    }
+
+    Ok(())
+}
+
+fn reboot(_port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    power::restart()
+}
+
+fn poweroff(_port: &mut Port, _args: &[&str]) -> Result<(), &'static str> {
+    power::power_off()
+}
+
+/// Starts the shell on the first serial port, the console and the virtio console, with `kshell` on
+/// the command line
+pub fn init() {
+    if !cmdline::flag("kshell") {
+        return;
+    }
+
+    let mut sessions = [
+        (0..serial::PORTS.len())
+            .find(|&port| serial::present(port))
+            .map(|port| (&SERIAL, Port::Serial(port))),
+        fb_renderer::present().then_some((&CONSOLE, Port::Console)),
+        virtio::console::present().then_some((&VIRTIO_CONSOLE, Port::VirtioConsole)),
+    ];
+
+    for (session, port) in sessions.iter_mut().flatten() {
+        let _ = port.write_str(PROMPT);
+        *session.lock() = Some(Session::new(*port));
+    }
+
+    log::info!("kshell: listening, Alt+F2 on the console");
+}
+
+initcall!(kshell, init, [drivers, kernelfs]);
+
+/// Handles whatever was typed since the last call, from the idle loop
+pub fn poll() {
+    for session in [&SERIAL, &CONSOLE, &VIRTIO_CONSOLE] {
+        if let Some(mut session) = session.try_lock() {
+            if let Some(session) = session.as_mut() {
+                session.poll();
+            }
+        }
+    }
+}
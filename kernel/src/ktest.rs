@@ -0,0 +1,118 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::{cmdline, cpu, oops, time};
+
+/// The isa-debug-exit device QEMU gets with `-device isa-debug-exit,iobase=0xf4,iosize=0x04`
+const EXIT_PORT: u16 = 0xF4;
+/// QEMU exits with `(value << 1) | 1`, so 33 when everything passed and 35 otherwise
+const EXIT_PASSED: u32 = 0x10;
+const EXIT_FAILED: u32 = 0x11;
+
+extern "C" {
+    static __ktests_start: u8;
+    static __ktests_end: u8;
+}
+
+/// A test registered with `ktest!`
+pub struct Test {
+    pub name: &'static str,
+    pub module: &'static str,
+    pub run: fn(),
+}
+
+/// Registers the functions inside as kernel tests, run by `ktest::run` on a `ktest` boot
+///
+/// A test fails by panicking, the usual `assert!`s do
+#[macro_export]
+macro_rules! ktest {
+    ($($(#[$meta:meta])* fn $name:ident() $body:block)*) => {
+        $(
+            $(#[$meta])*
+            fn $name() $body
+
+            const _: () = {
+                #[used]
+                #[link_section = ".ktests"]
+                static ENTRY: $crate::ktest::Test = $crate::ktest::Test {
+                    name: stringify!($name),
+                    module: module_path!(),
+                    run: $name,
+                };
+            };
+        )*
+    };
+}
+
+/// Every test in the `.ktests` section, in link order
+fn tests() -> &'static [Test] {
+    unsafe {
+        let start = core::ptr::addr_of!(__ktests_start) as *const Test;
+        let end = core::ptr::addr_of!(__ktests_end) as *const Test;
+
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Runs every registered test when booted with `ktest`, or built with the `ktest` feature, then
+/// reports to QEMU's isa-debug-exit
+///
+/// `ktest=<prefix>` only runs the tests whose path starts with it
+pub fn run() {
+    let filter = cmdline::value("ktest");
+    if !cfg!(feature = "ktest") && !cmdline::flag("ktest") && filter.is_none() {
+        return;
+    }
+
+    let tests = tests();
+    log::info!("ktest: {} tests registered", tests.len());
+
+    let (mut passed, mut failed) = (0, 0);
+    for test in tests {
+        let module = test.module.strip_prefix("kernel::").unwrap_or(test.module);
+        if filter.is_some_and(|prefix| !module.starts_with(prefix)) {
+            continue;
+        }
+
+        let start = time::uptime_us();
+        let result = oops::recoverable(test.name, test.run);
+        let elapsed = time::uptime_us() - start;
+
+        match result {
+            Ok(()) => {
+                log::info!("ktest: {module}::{} ... ok ({elapsed} us)", test.name);
+                passed += 1;
+            }
+            Err(_) => {
+                log::error!("ktest: {module}::{} ... FAILED", test.name);
+                failed += 1;
+            }
+        }
+    }
+
+    log::info!("ktest: {passed} passed, {failed} failed");
+    crate::stack::log_usage();
+    exit(failed == 0);
+}
+
+/// Tells QEMU whether everything passed, returning if there's no isa-debug-exit to do that
+pub fn exit(passed: bool) {
+    let code = if passed { EXIT_PASSED } else { EXIT_FAILED };
+    unsafe { cpu::outl(EXIT_PORT, code) };
+
+    log::warn!("no isa-debug-exit device, carrying on");
+}
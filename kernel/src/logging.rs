@@ -16,15 +16,611 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::{core, core_locals, fb_print, serial_print};
+use crate::sync::IrqSpinlock;
+use crate::{cmdline, core, core_locals, debugcon, serial, serial_print, time, utils, virtio};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Arguments, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use log::{Level, LevelFilter, Log, Metadata, Record};
-use spin::Mutex;
 
-static LOGGER_LOCK: Mutex<()> = Mutex::new(());
+/// Consoles used when `console=` doesn't pick any
+const DEFAULT_CONSOLES: &str = "serial,fb,virtio";
+
+/// Sinks that can be registered at once, there's no heap for a list early on
+const MAX_SINKS: usize = 8;
+
+static LOGGER_LOCK: IrqSpinlock<()> = IrqSpinlock::new(());
 static LOGGER: Logger = Logger;
+static SINKS: IrqSpinlock<[Option<Sink>; MAX_SINKS]> = IrqSpinlock::new([None; MAX_SINKS]);
+
+/// Somewhere log records go
+pub trait LogSink: Sync {
+    fn name(&self) -> &str;
+
+    /// Called with the logger lock held, so records from different cores don't interleave
+    fn log(&self, entry: &Entry);
+}
+
+#[derive(Clone, Copy)]
+struct Sink {
+    sink: &'static dyn LogSink,
+    /// The most verbose level it gets
+    filter: LevelFilter,
+}
+
+/// A record on its way to the sinks
+pub struct Entry<'a> {
+    pub sequence: u64,
+    /// TSC ticks since boot
+    pub ticks: u64,
+    pub level: Level,
+    pub core: u16,
+    pub file: &'a str,
+    pub line: u32,
+    /// Module path of the caller
+    pub module: &'a str,
+    pub args: &'a Arguments<'a>,
+}
+
+impl Entry<'_> {
+    fn prefix(&self) -> Prefix {
+        Prefix {
+            time_us: time::ticks_to_us(self.ticks),
+            sequence: self.sequence,
+            core: self.core,
+        }
+    }
+
+    /// The line with ANSI colors, for terminals
+    pub fn colored(&self) -> impl fmt::Display + '_ {
+        struct Colored<'a>(&'a Entry<'a>);
+
+        impl fmt::Display for Colored<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let entry = self.0;
+                let color = match entry.level {
+                    Level::Info => "32",  // green info
+                    Level::Warn => "33",  // yellow warn
+                    Level::Error => "31", // red error
+                    Level::Debug => "35", // gray debug
+                    Level::Trace => "34", // blue trace
+                };
+
+                writeln!(
+                    f,
+                    "\x1b[37;1m{} {}:{} \x1b[{color};1m{} \x1b[0m{}",
+                    entry.prefix(),
+                    entry.file,
+                    entry.line,
+                    level_name(entry.level),
+                    entry.args
+                )
+            }
+        }
+
+        Colored(self)
+    }
+
+    /// The line as plain text, like `history` has it
+    pub fn plain(&self) -> impl fmt::Display + '_ {
+        struct Plain<'a>(&'a Entry<'a>);
+
+        impl fmt::Display for Plain<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let entry = self.0;
+                writeln!(
+                    f,
+                    "{} {}:{} {} {}",
+                    entry.prefix(),
+                    entry.file,
+                    entry.line,
+                    level_name(entry.level),
+                    entry.args
+                )
+            }
+        }
+
+        Plain(self)
+    }
+}
+
+struct SerialSink;
+
+impl LogSink for SerialSink {
+    fn name(&self) -> &str {
+        "serial"
+    }
+
+    fn log(&self, entry: &Entry) {
+        if FRAMED.load(Ordering::Relaxed) {
+            serial::write_console(Frame::new(entry).bytes());
+        } else {
+            serial_print!("{}", entry.colored());
+        }
+    }
+}
+
+struct DebugconSink;
+
+impl LogSink for DebugconSink {
+    fn name(&self) -> &str {
+        "debugcon"
+    }
+
+    fn log(&self, entry: &Entry) {
+        if FRAMED.load(Ordering::Relaxed) {
+            debugcon::write_bytes(Frame::new(entry).bytes());
+        } else {
+            debugcon::_print(format_args!("{}", entry.colored()));
+        }
+    }
+}
+
+struct VirtioSink;
+
+impl LogSink for VirtioSink {
+    fn name(&self) -> &str {
+        "virtio"
+    }
+
+    fn log(&self, entry: &Entry) {
+        virtio::console::_print(format_args!("{}", entry.colored()));
+    }
+}
+
+struct FramebufferSink;
+
+impl LogSink for FramebufferSink {
+    fn name(&self) -> &str {
+        "fb"
+    }
+
+    fn log(&self, entry: &Entry) {
+        fb_print!("{}", entry.colored());
+    }
+}
+
+/// Keeps the records for `records` and `history`
+struct RingSink;
+
+impl LogSink for RingSink {
+    fn name(&self) -> &str {
+        "ring"
+    }
+
+    fn log(&self, entry: &Entry) {
+        // A reader interrupted on this core holds the lock, the record is dropped then
+        if let Some(mut ring) = RING.try_lock() {
+            let header = Header {
+                sequence: entry.sequence,
+                ticks: entry.ticks,
+                level: entry.level,
+                core: entry.core,
+                len: 0,
+            };
+
+            ring.push(
+                header,
+                format_args!(
+                    "{}:{}\0{}\0{}",
+                    entry.file, entry.line, entry.module, entry.args
+                ),
+            );
+        }
+    }
+}
+
+/// Starts every framed record, so readers can pick them out of whatever else is on the line
+pub const FRAME_MAGIC: [u8; 4] = *b"\x1eBLG";
+/// Longer file names and module paths get cut short in frames
+const MAX_NAME: usize = 256;
+/// Magic, length, the fixed fields, three strings and the checksum
+const MAX_FRAME: usize = 4 + 2 + 23 + 3 * 2 + 2 * MAX_NAME + MAX_TEXT + 4;
+
+/// Set by `logformat=framed`, serial and debugcon get frames instead of text then
+static FRAMED: AtomicBool = AtomicBool::new(false);
+
+/// A record for host tools, `tools/logdecode` reads them back
+///
+/// After `FRAME_MAGIC` and the little endian u16 length of the payload come the payload and its
+/// CRC-32. The payload is the sequence number and the time since boot in microseconds as u64s,
+/// the level as a u8 (1 is error, 5 trace), the core as a u16, the line as a u32, then the file,
+/// the module and the message, each a u16 length and UTF-8
+struct Frame {
+    bytes: [u8; MAX_FRAME],
+    len: usize,
+}
+
+impl Frame {
+    fn new(entry: &Entry) -> Frame {
+        let mut frame = Frame {
+            bytes: [0; MAX_FRAME],
+            len: 0,
+        };
+
+        frame.push(&FRAME_MAGIC);
+        // The payload length, once it's known
+        frame.push(&[0, 0]);
+        frame.push(&entry.sequence.to_le_bytes());
+        frame.push(&time::ticks_to_us(entry.ticks).to_le_bytes());
+        frame.push(&[entry.level as u8]);
+        frame.push(&entry.core.to_le_bytes());
+        frame.push(&entry.line.to_le_bytes());
+        frame.string(MAX_NAME, format_args!("{}", entry.file));
+        frame.string(MAX_NAME, format_args!("{}", entry.module));
+        frame.string(MAX_TEXT, *entry.args);
+
+        let payload = 6..frame.len;
+        frame.bytes[4..6].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        let crc = utils::crc32(&frame.bytes[payload]);
+        frame.push(&crc.to_le_bytes());
+
+        frame
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    /// Writes `args` as a length and its text, cut short past `limit` bytes
+    fn string(&mut self, limit: usize, args: Arguments) {
+        struct Limited<'a> {
+            frame: &'a mut Frame,
+            left: usize,
+        }
+
+        impl Write for Limited<'_> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                let bytes = &s.as_bytes()[..s.len().min(self.left)];
+                self.frame.push(bytes);
+                self.left -= bytes.len();
+                Ok(())
+            }
+        }
+
+        let at = self.len;
+        self.push(&[0, 0]);
+
+        let _ = Limited {
+            frame: self,
+            left: limit,
+        }
+        .write_fmt(args);
+
+        let len = (self.len - at - 2) as u16;
+        self.bytes[at..at + 2].copy_from_slice(&len.to_le_bytes());
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+static CONSOLE_SINKS: [&dyn LogSink; 4] =
+    [&SerialSink, &DebugconSink, &FramebufferSink, &VirtioSink];
+
+/// How much of the log is kept around for reading back, oldest records go first
+const RING_SIZE: usize = 64 * 1024;
+/// Longer messages get cut short in the ring
+const MAX_TEXT: usize = 1024;
+
+static RING: IrqSpinlock<Ring> = IrqSpinlock::new(Ring {
+    buffer: [0; RING_SIZE],
+    start: 0,
+    len: 0,
+});
+
+/// Numbers every record, including the ones the ring had no room for
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A log record read back from the ring
+pub struct LogRecord {
+    pub sequence: u64,
+    /// Since boot, 0 for records from before the TSC was calibrated
+    pub time_us: u64,
+    pub level: Level,
+    pub core: u16,
+    /// Where it was logged from, as `file:line`
+    pub location: String,
+    /// Without colors, cut short past `MAX_TEXT`
+    pub message: String,
+}
+
+/// What comes before the text of a record in the ring
+struct Header {
+    sequence: u64,
+    /// TSC ticks since boot, turned into time when read so early records get it right too
+    ticks: u64,
+    level: Level,
+    core: u16,
+    len: u16,
+}
+
+impl Header {
+    const SIZE: usize = 21;
+
+    fn encode(&self) -> [u8; Header::SIZE] {
+        let mut bytes = [0; Header::SIZE];
+        bytes[0..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.ticks.to_le_bytes());
+        bytes[16] = self.level as u8;
+        bytes[17..19].copy_from_slice(&self.core.to_le_bytes());
+        bytes[19..21].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; Header::SIZE]) -> Header {
+        Header {
+            sequence: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            ticks: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            level: level_from(bytes[16]),
+            core: u16::from_le_bytes([bytes[17], bytes[18]]),
+            len: u16::from_le_bytes([bytes[19], bytes[20]]),
+        }
+    }
+}
+
+/// The latest log records, kept from the moment the logger is up since it doesn't need the heap
+///
+/// Each record is a `Header` followed by its text: the location, the module and the message,
+/// separated by NULs
+struct Ring {
+    buffer: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    fn read(&self, offset: usize, out: &mut [u8]) {
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = self.buffer[(self.start + offset + i) % RING_SIZE];
+        }
+    }
+
+    fn write(&mut self, offset: usize, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.buffer[(self.start + offset + i) % RING_SIZE] = byte;
+        }
+    }
+
+    fn header(&self, offset: usize) -> Header {
+        let mut bytes = [0; Header::SIZE];
+        self.read(offset, &mut bytes);
+        Header::decode(&bytes)
+    }
+
+    fn push(&mut self, mut header: Header, args: Arguments) {
+        // Room for the longest record, the oldest ones make way
+        while RING_SIZE - self.len < Header::SIZE + MAX_TEXT {
+            let size = Header::SIZE + self.header(0).len as usize;
+            self.start = (self.start + size) % RING_SIZE;
+            self.len -= size;
+        }
+
+        let at = self.len;
+        self.len += Header::SIZE;
+
+        let mut text = RecordText {
+            ring: self,
+            written: 0,
+        };
+        let _ = text.write_fmt(args);
+
+        header.len = text.written as u16;
+        self.write(at, &header.encode());
+    }
+
+    /// Calls `f` with every record and its text, oldest first, without allocating
+    fn for_each(&self, mut f: impl FnMut(&Header, &[u8])) {
+        let mut text = [0; MAX_TEXT];
+        let mut offset = 0;
+
+        while offset < self.len {
+            let header = self.header(offset);
+            let text = &mut text[..header.len as usize];
+            self.read(offset + Header::SIZE, text);
+
+            f(&header, text);
+            offset += Header::SIZE + header.len as usize;
+        }
+    }
+
+    /// Like `for_each`, with the text split back into an `Entry`
+    fn for_each_entry(&self, mut f: impl FnMut(&Entry)) {
+        self.for_each(|header, text| {
+            let mut parts = text.splitn(3, |&byte| byte == 0);
+            let mut part = || core::str::from_utf8(parts.next().unwrap_or_default()).unwrap_or("?");
+            let (location, module, message) = (part(), part(), part());
+            let (file, line) = location.rsplit_once(':').unwrap_or((location, "0"));
+
+            f(&Entry {
+                sequence: header.sequence,
+                ticks: header.ticks,
+                level: header.level,
+                core: header.core,
+                file,
+                line: line.parse().unwrap_or(0),
+                module,
+                args: &format_args!("{message}"),
+            });
+        });
+    }
+
+    fn count(&self) -> usize {
+        let mut count = 0;
+        self.for_each(|_, _| count += 1);
+        count
+    }
+
+    fn records(&self) -> Vec<LogRecord> {
+        let mut records = Vec::new();
+
+        self.for_each(|header, text| {
+            let mut parts = text.splitn(3, |&byte| byte == 0);
+            let mut part =
+                || String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+
+            let (location, _module, message) = (part(), part(), part());
+
+            records.push(LogRecord {
+                sequence: header.sequence,
+                time_us: time::ticks_to_us(header.ticks),
+                level: header.level,
+                core: header.core,
+                location,
+                message,
+            });
+        });
+
+        records
+    }
+}
+
+/// The text of the record being pushed, which `Ring::push` made room for
+struct RecordText<'a> {
+    ring: &'a mut Ring,
+    written: usize,
+}
+
+impl Write for RecordText<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = &s.as_bytes()[..s.len().min(MAX_TEXT - self.written)];
+        let end = self.ring.len;
+
+        self.ring.write(end, bytes);
+        self.ring.len += bytes.len();
+        self.written += bytes.len();
+
+        Ok(())
+    }
+}
+
+/// What every line starts with: the time since boot, the sequence number and the core
+struct Prefix {
+    time_us: u64,
+    sequence: u64,
+    core: u16,
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>5}.{:06}] #{} [{}]",
+            self.time_us / 1_000_000,
+            self.time_us % 1_000_000,
+            self.sequence,
+            self.core
+        )
+    }
+}
 
 pub unsafe fn unlock() {
-    LOGGER_LOCK.force_unlock()
+    LOGGER_LOCK.force_unlock();
+    SINKS.force_unlock();
+}
+
+/// Starts sending records up to `filter` to `sink`, or changes the filter if it's already there
+pub fn register(sink: &'static dyn LogSink, filter: LevelFilter) {
+    let mut sinks = SINKS.lock();
+
+    if let Some(existing) = sinks
+        .iter_mut()
+        .flatten()
+        .find(|s| s.sink.name() == sink.name())
+    {
+        existing.filter = filter;
+        return;
+    }
+
+    match sinks.iter_mut().find(|s| s.is_none()) {
+        Some(slot) => *slot = Some(Sink { sink, filter }),
+        None => {
+            drop(sinks);
+            log::warn!("logging: no room for the {} sink", sink.name());
+        }
+    }
+}
+
+pub fn unregister(name: &str) {
+    for slot in SINKS.lock().iter_mut() {
+        if slot.is_some_and(|s| s.sink.name() == name) {
+            *slot = None;
+        }
+    }
+}
+
+/// Drops console `name`, which turned out to be missing, falling back to serial and debugcon if it
+/// was the only one left
+pub fn remove_console(name: &str) {
+    unregister(name);
+
+    let any_left = SINKS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|s| CONSOLE_SINKS.iter().any(|c| c.name() == s.sink.name()));
+
+    if !any_left {
+        register(&SerialSink, LevelFilter::Trace);
+        if debugcon::present() {
+            register(&DebugconSink, LevelFilter::Trace);
+        }
+    }
+}
+
+/// Sends what the ring kept so far to sink `name`, for consoles that come up after the logger and
+/// missed the records from early boot
+pub fn replay(name: &str) {
+    let sink = SINKS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|s| s.sink.name() == name)
+        .copied();
+    let Some(sink) = sink else {
+        return;
+    };
+
+    let _logger = LOGGER_LOCK.lock();
+    let ring = RING.lock();
+
+    ring.for_each_entry(|entry| {
+        if entry.level <= sink.filter {
+            sink.sink.log(entry);
+        }
+    });
+}
+
+/// Calls `f` with the last `count` records in the ring, oldest first, nothing if it's locked
+pub fn tail(count: usize, mut f: impl FnMut(&Entry)) {
+    let Some(ring) = RING.try_lock() else {
+        return;
+    };
+
+    let mut skip = ring.count().saturating_sub(count);
+    ring.for_each_entry(|entry| match skip {
+        0 => f(entry),
+        _ => skip -= 1,
+    });
+}
+
+/// Changes what a registered sink gets, returns false if there's no sink called `name`
+pub fn set_filter(name: &str, filter: LevelFilter) -> bool {
+    match SINKS
+        .lock()
+        .iter_mut()
+        .flatten()
+        .find(|s| s.sink.name() == name)
+    {
+        Some(sink) => {
+            sink.filter = filter;
+            true
+        }
+        None => false,
+    }
 }
 
 struct Logger;
@@ -35,48 +631,125 @@ impl Log for Logger {
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            let _logger = LOGGER_LOCK.lock();
-
-            let file = record.file().unwrap_or("unknown");
-            let line = record.line().unwrap_or(0);
-            let level = record.level();
-
-            macro generic_log($($arg:tt)*) {
-                {
-                    serial_print!("{}", format_args!($($arg)*));
+        if !self.enabled(record.metadata()) {
+            return;
+        }
 
-                    if !matches!(record.metadata().level(), Level::Trace | Level::Debug | Level::Error) {
-                        fb_print!("{}", format_args!($($arg)*));
-                    }
-                }
-            }
+        let _logger = LOGGER_LOCK.lock();
 
-            let core_id = if core_locals::initialized() {
-                core!().id
+        let entry = Entry {
+            sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            ticks: time::ticks(),
+            level: record.level(),
+            core: if core_locals::initialized() {
+                core!().id as u16
             } else {
                 0
-            };
-            generic_log!("\x1b[37;1m[{core_id}] {file}:{line} ");
-
-            match record.level() {
-                Level::Info => generic_log!("\x1b[32;1minfo "), // green info
-                Level::Warn => generic_log!("\x1b[33;1mwarn "), // yellow warn
-                Level::Error => generic_log!("\x1b[31;1merror "), // red error
-                Level::Debug => generic_log!("\x1b[35;1mdebug "), // gray debug
-                Level::Trace => generic_log!("\x1b[34;1mtrace "), // blue trace
-            }
+            },
+            file: record.file().unwrap_or("unknown"),
+            line: record.line().unwrap_or(0),
+            module: record.module_path().unwrap_or(record.target()),
+            args: record.args(),
+        };
 
-            generic_log!("\x1b[0m");
-            generic_log!("{}\n", record.args());
+        // A copy, so sinks can log about registering others
+        let sinks = *SINKS.lock();
+        for sink in sinks.iter().flatten() {
+            if entry.level <= sink.filter {
+                sink.sink.log(&entry);
+            }
         }
     }
 
     fn flush(&self) {}
 }
 
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+fn level_from(value: u8) -> Level {
+    match value {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+/// The records kept so far, oldest first
+pub fn records() -> Vec<LogRecord> {
+    RING.lock().records()
+}
+
+/// The records kept so far as text, a line each, oldest first
+pub fn history() -> Vec<u8> {
+    let mut text = String::new();
+
+    for record in records() {
+        let prefix = Prefix {
+            time_us: record.time_us,
+            sequence: record.sequence,
+            core: record.core,
+        };
+
+        let _ = writeln!(
+            text,
+            "{prefix} {} {} {}",
+            record.location,
+            level_name(record.level),
+            record.message
+        );
+    }
+
+    text.into_bytes()
+}
+
+/// Registers the ring and the consoles picked with `console=serial,debugcon,fb,virtio`, in
+/// frames on serial and debugcon with `logformat=framed`, `ttyS1,9600n8` also picks serial
+///
+/// Runs before anything else can log, the ring keeps the records until the consoles are up and
+/// `replay` gets them there
 pub fn init() {
+    register(&RingSink, LevelFilter::Trace);
+    FRAMED.store(
+        cmdline::value("logformat") == Some("framed"),
+        Ordering::Relaxed,
+    );
+
+    let consoles = cmdline::value("console").unwrap_or(DEFAULT_CONSOLES);
+    for name in consoles.split(',') {
+        // Which port and its settings are up to the serial driver
+        let name = if name.starts_with("ttyS") {
+            "serial"
+        } else {
+            name
+        };
+        let Some(&sink) = CONSOLE_SINKS.iter().find(|sink| sink.name() == name) else {
+            continue;
+        };
+
+        // The framebuffer console is small, it only gets what's worth reading there
+        let filter = if name == "fb" {
+            LevelFilter::Info
+        } else {
+            LevelFilter::Trace
+        };
+        register(sink, filter);
+    }
+
     log::set_logger(&LOGGER)
         .map(|()| log::set_max_level(LevelFilter::Trace))
         .unwrap();
+
+    if consoles.split(',').any(|name| name == "debugcon") && !debugcon::present() {
+        log::warn!("debugcon requested but port 0xE9 isn't attached");
+    }
 }
@@ -21,35 +21,94 @@
 #![feature(format_args_nl)]
 #![feature(decl_macro)]
 
+use error::Policy;
 use limine::LimineBootInfoRequest;
 
 extern crate alloc;
 
+#[macro_use]
+mod core_locals;
+#[macro_use]
+mod ktest;
+#[macro_use]
+mod initcall;
+#[macro_use]
+mod driver;
+#[macro_use]
+mod kbench;
+#[macro_use]
+mod registers;
 mod acpi;
+mod ahci;
 mod apic;
 mod backtrace;
-#[macro_use]
-mod core_locals;
+mod block;
+mod cmdline;
 mod cpu;
+mod cpufreq;
+mod cpuidle;
+mod crashdump;
+mod debugcon;
+mod devices;
+mod e1000;
+mod efi;
+mod error;
 #[macro_use]
 mod fb_renderer;
+mod font;
 mod framebuffer;
+mod fs;
+mod fw_cfg;
 mod gdt;
+mod hda;
 mod hpet;
+mod i8042;
+mod inject;
+mod input;
 mod interrupts;
+mod ioapic;
+mod keyboard;
+mod kshell;
 mod logging;
 mod mm;
+mod modules;
+mod monitor;
+mod net;
+mod nvme;
+mod oops;
+mod pci;
+mod power;
+mod process;
+mod profile;
+mod qoi;
+mod random;
 #[macro_use]
 mod serial;
 mod smp;
+mod speaker;
+mod splash;
+mod stack;
+mod sync;
+mod syscall;
+mod thermal;
+mod time;
+mod trace;
+mod tpm;
+mod unwind;
+mod usb;
 mod utils;
+mod vga;
+mod virtio;
 
 static BOOT_INFO: LimineBootInfoRequest = LimineBootInfoRequest::new(0);
 
 #[no_mangle]
 extern "C" fn _start() -> ! {
+    time::init();
     logging::init();
-    fb_renderer::init();
+    serial::init();
+    mm::init_hhdm();
+    error::init("framebuffer", Policy::Degrade, fb_renderer::init);
 
     log::info!("Beryl v{} loading", env!("CARGO_PKG_VERSION"));
     let boot_info = BOOT_INFO.get_response().get().unwrap();
@@ -60,38 +119,71 @@ extern "C" fn _start() -> ! {
     );
 
     mm::init();
+    stack::switch(kmain)
+}
+
+/// The rest of the boot, on a stack `stack` can keep an eye on
+extern "C" fn kmain() -> ! {
+    // The same per core setup the other cores go through in `smp`, before anything can fault
     core_locals::init();
     gdt::init();
+    stack::init_core();
     interrupts::init();
-    acpi::init();
-
-    {
-        let mut apic = core!().apic.lock();
-        apic.enable();
-    }
 
-    log::info!("Finished intializzation, starting other cores!");
+    initcall::run();
+    devices::dump();
 
-    smp::init();
+    ktest::run();
+    kbench::run();
 
-    hcf();
+    idle();
 }
 
 #[panic_handler]
 fn rust_panic(info: &core::panic::PanicInfo) -> ! {
+    oops::recover(info);
+    sync::lockdep::disable();
+
     unsafe {
         logging::unlock();
         fb_renderer::unlock();
+        virtio::console::unlock();
     }
 
+    monitor::stop_other_cores();
+    let exception = interrupts::exception();
+
     log::error!("PANIC: {info:#?}");
-    backtrace::backtrace(None);
+    if let Some(exception) = exception {
+        log::error!("Registers at exception:\n{}", exception.stack);
+    }
+    backtrace::backtrace(exception.map(|exception| (&exception.stack).into()));
+    crashdump::emit(info, exception.as_ref());
 
-    // TODO: Panic on every core
+    fb_renderer::panic_screen(info, exception);
+    net::netconsole::flush();
+    speaker::beep_code(1, 3);
 
+    monitor::enter(exception);
     hcf();
 }
 
+/// Parks the current core, waking up only to handle interrupts
+pub fn idle() -> ! {
+    loop {
+        cpuidle::enter();
+        acpi::events::poll();
+        acpi::ec::poll();
+        cpufreq::update();
+        virtio::balloon::update();
+        virtio::rng::update();
+        block::cache::update();
+        stack::update();
+        net::poll();
+        kshell::poll();
+    }
+}
+
 #[inline]
 pub fn hcf() -> ! {
     use core::arch::asm;
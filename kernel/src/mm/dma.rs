@@ -0,0 +1,83 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::vmm::PAGE_SIZE;
+use super::{align_up, pmm, PhysAddr, VirtAddr};
+
+/// Zeroed, physically contiguous memory a device can DMA to, accessed through the HHDM
+pub struct Dma {
+    phys: PhysAddr,
+    pages: usize,
+}
+
+impl Dma {
+    pub fn new(len: usize) -> Dma {
+        let pages = (align_up(len.max(1) as u64, PAGE_SIZE) / PAGE_SIZE) as usize;
+
+        Dma {
+            phys: pmm::alloc(pages),
+            pages,
+        }
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt(&self) -> VirtAddr {
+        self.phys.as_hhdm()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages * PAGE_SIZE as usize
+    }
+
+    fn check<T>(&self, offset: usize) {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.len(),
+            "DMA access at {offset:#x} is out of bounds ({:#x} bytes)",
+            self.len()
+        );
+    }
+
+    /// Volatile read, the device may write the memory behind our back
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        self.check::<T>(offset);
+        unsafe { core::ptr::read_volatile(self.virt().as_ptr::<u8>().add(offset).cast()) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        self.check::<T>(offset);
+        unsafe {
+            core::ptr::write_volatile(self.virt().as_mut_ptr::<u8>().add(offset).cast(), value)
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt().as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt().as_mut_ptr(), self.len()) }
+    }
+}
+
+impl Drop for Dma {
+    fn drop(&mut self) {
+        pmm::free(self.phys, self.pages);
+    }
+}
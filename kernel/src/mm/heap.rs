@@ -15,15 +15,28 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use super::{addr::VirtAddr, align_up, pmm, slab::Slab};
+use super::sites::{self, Site, Sites};
+use super::slab::{PmmPages, Slab};
+use super::{align_up, pmm, VirtAddr};
+use crate::cmdline;
+use crate::inject;
+use crate::kbench::Bencher;
+use crate::sync::Mutex;
+use crate::trace::{self, Event};
+use alloc::vec::Vec;
 use core::alloc::{GlobalAlloc, Layout};
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 struct Alloc {
     slabs: [Slab; 10],
     mem_used: usize,
+    /// Allocations by call site, with `heapprof`
+    sites: Option<Sites>,
 }
 
+/// Set once `sites` is, so allocations only look for their caller when it's needed
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
 impl Alloc {
     pub const fn new() -> Alloc {
         Alloc {
@@ -40,23 +53,32 @@ impl Alloc {
                 Slab::new(1024),
             ],
             mem_used: 0,
+            sites: None,
         }
     }
 
+    /// Null once the pmm has nothing left to give
     pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        self.mem_used += layout.size();
         let slab_i = [8, 16, 24, 32, 48, 64, 128, 256, 512, 1024]
             .into_iter()
             .enumerate()
             .find_map(|(i, s)| if s >= layout.size() { Some(i) } else { None });
-        if let Some(i) = slab_i {
-            return self.slabs[i].alloc();
-        }
+        let ret = match slab_i {
+            Some(i) => self.slabs[i].alloc(&PmmPages),
+            None => {
+                let pages = align_up(layout.size() as u64, 4096) / 4096;
+                match pmm::try_alloc(pages as usize) {
+                    Some(page) => page.as_hhdm().as_mut_ptr(),
+                    None => core::ptr::null_mut(),
+                }
+            }
+        };
 
-        let pages = align_up(layout.size() as u64, 4096) / 4096;
-        let ret = pmm::alloc(pages as usize).as_hhdm();
+        if !ret.is_null() {
+            self.mem_used += layout.size();
+        }
 
-        unsafe { ret.as_mut_ptr() }
+        ret
     }
 
     pub fn free(&mut self, ptr: *mut u8, layout: Layout) {
@@ -66,7 +88,7 @@ impl Alloc {
             pmm::free(VirtAddr::new(ptr as u64).as_phys_hhdm(), pages as usize);
         }
 
-        let slab: &mut Slab = unsafe { &mut *((ptr as u64 & !0xFFF) as *mut Slab) };
+        let slab: &mut Slab = unsafe { &mut *Slab::of(ptr) };
         slab.free(ptr);
     }
 
@@ -81,6 +103,9 @@ impl Alloc {
             }
 
             let new_ptr = self.alloc(Layout::from_size_align(new_size, layout.align()).unwrap());
+            if new_ptr.is_null() {
+                return new_ptr;
+            }
 
             if layout.size() > new_size {
                 unsafe {
@@ -95,10 +120,13 @@ impl Alloc {
             return new_ptr;
         }
 
-        let slab: &mut Slab = unsafe { &mut *((ptr as u64 & !0xFFF) as *mut Slab) };
+        let slab: &mut Slab = unsafe { &mut *Slab::of(ptr) };
 
         if new_size > slab.size {
             let new_ptr = self.alloc(Layout::from_size_align(new_size, layout.align()).unwrap());
+            if new_ptr.is_null() {
+                return new_ptr;
+            }
 
             unsafe {
                 core::ptr::copy_nonoverlapping(ptr, new_ptr, slab.size);
@@ -106,7 +134,7 @@ impl Alloc {
 
             slab.free(ptr);
             return new_ptr;
-        }   
+        }
 
         ptr
     }
@@ -119,21 +147,145 @@ unsafe impl Sync for LockedAlloc {}
 
 unsafe impl GlobalAlloc for LockedAlloc {
     unsafe fn alloc(&self, l: Layout) -> *mut u8 {
-        self.0.lock().alloc(l)
+        if inject::should_fail(inject::Site::Heap) {
+            return core::ptr::null_mut();
+        }
+
+        let caller = caller();
+        let mut heap = self.0.lock();
+        let p = heap.alloc(l);
+        if p.is_null() {
+            return p;
+        }
+
+        if let (Some(sites), Some(caller)) = (&mut heap.sites, caller) {
+            sites.allocated(p, l.size(), caller);
+        }
+        drop(heap);
+
+        trace::event(Event::HeapAlloc, [p as u64, l.size() as u64]);
+        p
     }
 
     unsafe fn dealloc(&self, p: *mut u8, l: Layout) {
-        self.0.lock().free(p, l)
+        trace::event(Event::HeapFree, [p as u64, l.size() as u64]);
+
+        let mut heap = self.0.lock();
+        if let Some(sites) = &mut heap.sites {
+            sites.freed(p, l.size());
+        }
+        heap.free(p, l)
     }
 
     unsafe fn realloc(&self, p: *mut u8, l: Layout, ns: usize) -> *mut u8 {
-        self.0.lock().realloc(p, l, ns)
+        // Null leaves the old block alone, only growing can fail
+        if ns > l.size() && inject::should_fail(inject::Site::Heap) {
+            return core::ptr::null_mut();
+        }
+
+        let caller = caller();
+        let mut heap = self.0.lock();
+        let new = heap.realloc(p, l, ns);
+        if new.is_null() {
+            return new;
+        }
+
+        if let (Some(sites), Some(caller)) = (&mut heap.sites, caller) {
+            sites.freed(p, l.size());
+            sites.allocated(new, ns, caller);
+        }
+        drop(heap);
+
+        trace::event(Event::HeapFree, [p as u64, l.size() as u64]);
+        trace::event(Event::HeapAlloc, [new as u64, ns as u64]);
+        new
     }
 }
 
+/// Where the allocation being made comes from, if allocations are being profiled
+fn caller() -> Option<u64> {
+    PROFILING.load(Ordering::Relaxed).then(sites::caller)
+}
+
 pub fn used() -> usize {
     GLOBAL_ALLOC.0.lock().mem_used
 }
 
+/// Like `used`, but gives up if the allocator is locked, for the panic path
+pub fn try_used() -> Option<usize> {
+    Some(GLOBAL_ALLOC.0.try_lock()?.mem_used)
+}
+
+/// The `count` call sites holding the most live bytes, and how many allocations went untracked
+///
+/// `None` unless booted with `heapprof`
+pub fn top_sites(count: usize) -> Option<(Vec<Site>, u64)> {
+    // Filled under the heap lock, where there's no allocating
+    let mut top = Vec::with_capacity(sites::SITES);
+
+    let heap = GLOBAL_ALLOC.0.lock();
+    let sites = heap.sites.as_ref()?;
+    top.extend(sites.iter().filter(|site| site.live != 0).copied());
+    let untracked = sites.untracked();
+    drop(heap);
+
+    top.sort_unstable_by_key(|site| core::cmp::Reverse(site.live_bytes));
+    top.truncate(count);
+    Some((top, untracked))
+}
+
+/// Starts bucketing allocations by call site when booted with `heapprof`
+///
+/// Needs the symbol index, allocations made before this aren't counted
+pub fn init_sites() {
+    if !cmdline::flag("heapprof") {
+        return;
+    }
+
+    let Some(sites) = Sites::new() else {
+        log::warn!("heap: no memory to track allocation sites");
+        return;
+    };
+
+    GLOBAL_ALLOC.0.lock().sites = Some(sites);
+    PROFILING.store(true, Ordering::Relaxed);
+    log::info!("heap: tracking allocations by call site");
+}
+
+initcall!(heapprof, init_sites, [backtrace]);
+
 #[global_allocator]
 static GLOBAL_ALLOC: LockedAlloc = LockedAlloc(Mutex::new(Alloc::new()));
+
+ktest! {
+    fn heap_accounts_for_frees() {
+        let before = used();
+        let buffer = alloc::vec![0u8; 64 * 1024];
+        assert!(used() >= before + buffer.len());
+
+        drop(buffer);
+        assert_eq!(used(), before);
+    }
+
+    fn pmm_failures_come_back_as_null() {
+        use alloc::vec::Vec;
+        use inject::{Mode, Site};
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let before = used();
+        {
+            let _guard = inject::inject(Site::Pmm, Mode::Nth(1));
+            assert!(buffer.try_reserve_exact(64 * 1024).is_err());
+        }
+
+        assert_eq!(used(), before);
+        assert!(buffer.try_reserve_exact(64 * 1024).is_ok());
+    }
+}
+
+kbench! {
+    /// Goes through the 8 byte slab
+    fn alloc_free_small(b: &mut Bencher) {
+        b.iter(|| alloc::boxed::Box::new(0u64));
+    }
+}
@@ -0,0 +1,117 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::vmm::{self, NO_CACHE, NO_EXECUTE, PAGE_SIZE, WRITABLE, WRITE_THROUGH};
+use super::{align_down, align_up, PhysAddr, VirtAddr};
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Device memory gets mapped in its own PML4 slot, away from the HHDM and the kernel
+const MMIO_BASE: u64 = 0xFFFF_C000_0000_0000;
+
+static NEXT: AtomicU64 = AtomicU64::new(MMIO_BASE);
+static RESERVED: Mutex<Vec<Range<u64>>> = Mutex::new(Vec::new());
+
+/// An uncached mapping of a device's registers
+pub struct Mmio {
+    base: VirtAddr,
+    phys: PhysAddr,
+    len: usize,
+}
+
+impl Mmio {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn check<T>(&self, offset: usize) {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.len,
+            "MMIO access at {offset:#x} is out of bounds ({:#x} bytes)",
+            self.len
+        );
+    }
+
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        self.check::<T>(offset);
+        unsafe { core::ptr::read_volatile(self.base.as_ptr::<u8>().add(offset).cast()) }
+    }
+
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        self.check::<T>(offset);
+        unsafe { core::ptr::write_volatile(self.base.as_mut_ptr::<u8>().add(offset).cast(), value) }
+    }
+}
+
+/// The virtual range isn't handed out again, only the pages and the physical range are released
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        let start = align_down(self.base.as_u64(), PAGE_SIZE);
+        let end = align_up(self.base.as_u64() + self.len as u64, PAGE_SIZE);
+
+        for page in (start..end).step_by(PAGE_SIZE as usize) {
+            vmm::unmap(VirtAddr::new(page));
+        }
+
+        let phys = self.phys.as_u64()..self.phys.as_u64() + self.len as u64;
+        RESERVED.lock().retain(|range| *range != phys);
+    }
+}
+
+/// Claims `range` for a driver, fails if someone else already did
+fn reserve(range: Range<u64>) -> bool {
+    let mut reserved = RESERVED.lock();
+
+    if reserved
+        .iter()
+        .any(|r| r.start < range.end && range.start < r.end)
+    {
+        return false;
+    }
+
+    reserved.push(range);
+    true
+}
+
+/// Maps `len` bytes of device memory at `phys` uncached, unless somebody mapped them already
+pub fn map(phys: PhysAddr, len: usize) -> Option<Mmio> {
+    let start = align_down(phys.as_u64(), PAGE_SIZE);
+    let end = align_up(phys.as_u64() + len as u64, PAGE_SIZE);
+
+    if !reserve(phys.as_u64()..phys.as_u64() + len as u64) {
+        log::warn!("MMIO range {:#x}+{len:#x} is already in use", phys.as_u64());
+        return None;
+    }
+
+    let virt = NEXT.fetch_add(end - start, Ordering::Relaxed);
+
+    for offset in (0..end - start).step_by(PAGE_SIZE as usize) {
+        vmm::map(
+            VirtAddr::new(virt + offset),
+            PhysAddr::new(start + offset),
+            WRITABLE | WRITE_THROUGH | NO_CACHE | NO_EXECUTE,
+        );
+    }
+
+    Some(Mmio {
+        base: VirtAddr::new(virt + (phys.as_u64() - start)),
+        phys,
+        len,
+    })
+}
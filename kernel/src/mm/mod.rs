@@ -17,38 +17,31 @@
 */
 use limine::LimineHhdmRequest;
 
-pub mod addr;
+pub mod dma;
 pub mod heap;
+pub mod mmio;
 pub mod pmm;
+pub mod sites;
 pub mod slab;
+pub mod vmm;
 
-pub use addr::*;
+pub use kernel_core::addr::{PhysAddr, VirtAddr};
+pub use kernel_core::{align_down, align_up};
 
 static HHDM_ADDRESS_REQUEST: LimineHhdmRequest = LimineHhdmRequest::new(0);
 
-#[inline]
-pub const fn align_down(addr: u64, align: u64) -> u64 {
-    addr & !(align - 1)
-}
+/// Sets up `as_hhdm` and friends, which the console needs before the rest of memory management
+pub fn init_hhdm() {
+    let hhdm = HHDM_ADDRESS_REQUEST
+        .get_response()
+        .get()
+        .expect("Cannot get the HHDM address");
+
+    log::debug!("HHDM @ {:#x}", hhdm.offset);
 
-#[inline]
-pub const fn align_up(addr: u64, align: u64) -> u64 {
-    (addr + align - 1) & !(align - 1)
+    kernel_core::addr::set_hhdm_offset(hhdm.offset);
 }
 
 pub fn init() {
-    {
-        let hhdm = HHDM_ADDRESS_REQUEST
-            .get_response()
-            .get()
-            .expect("Cannot get the HHDM address");
-
-        log::debug!("HHDM @ {:#x}", hhdm.offset);
-
-        unsafe {
-            core::ptr::write(&mut HHDM_ADDRESS, hhdm.offset);
-        }
-    }
-
     pmm::init();
 }
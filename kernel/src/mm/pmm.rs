@@ -16,15 +16,25 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 use super::PhysAddr;
+use crate::inject::{self, Site};
+use crate::kbench::Bencher;
+use crate::sync::Mutex;
+use crate::trace::{self, Event};
 use crate::utils::Bitmap;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use limine::{LimineMemmapRequest, LimineMemoryMapEntryType};
-use spin::Mutex;
 
 static BITMAP: Mutex<Option<Bitmap>> = Mutex::new(None);
 static MEMMAP: LimineMemmapRequest = LimineMemmapRequest::new(0);
 static LAST_USED_INDEX: AtomicUsize = AtomicUsize::new(0);
 
+/// Pages the hypervisor took through the balloon, nobody can use them until it gives them back
+static BALLOONED: AtomicUsize = AtomicUsize::new(0);
+
+/// Usable pages, and how many of them nobody holds right now
+static TOTAL_PAGES: AtomicUsize = AtomicUsize::new(0);
+static FREE_PAGES: AtomicUsize = AtomicUsize::new(0);
+
 pub(super) fn init() {
     log::trace!("Initializing the pmm");
 
@@ -75,12 +85,14 @@ pub(super) fn init() {
             continue;
         }
 
-        for i in (0..entry.len).step_by(4096) {
-            let idx = (entry.base + i) / 4096;
-            bitmap.unset(idx as usize);
-        }
+        let first = (entry.base / 4096) as usize;
+        bitmap.clear_range(first..first + (entry.len / 4096) as usize);
+
+        TOTAL_PAGES.fetch_add((entry.len / 4096) as usize, Ordering::Relaxed);
     }
 
+    FREE_PAGES.store(TOTAL_PAGES.load(Ordering::Relaxed), Ordering::Relaxed);
+
     *BITMAP.lock() = Some(bitmap);
 }
 
@@ -95,10 +107,7 @@ pub fn alloc(pages: usize) -> PhysAddr {
 }
 
 pub fn alloc_nozero(pages: usize) -> PhysAddr {
-    alloc_inner(pages).unwrap_or_else(|| {
-        LAST_USED_INDEX.store(0, Ordering::Relaxed);
-        alloc_inner(pages).expect("OOM")
-    })
+    alloc_pages(pages).expect("OOM")
 }
 
 pub fn free(phys: PhysAddr, pages: usize) {
@@ -107,33 +116,88 @@ pub fn free(phys: PhysAddr, pages: usize) {
 
     let page = (phys.as_u64() / 0x1000) as usize;
     LAST_USED_INDEX.store(page, Ordering::Relaxed);
-    for i in page..(page + pages) {
-        bitmap.unset(i);
+    bitmap.clear_range(page..page + pages);
+
+    FREE_PAGES.fetch_add(pages, Ordering::Relaxed);
+    trace::event(Event::PmmFree, [phys.as_u64(), pages as u64]);
+}
+
+/// Like `alloc_nozero`, but `None` instead of OOM, for allocations that can be given up
+///
+/// Only these see `Site::Pmm` failures, `alloc` and `alloc_nozero` callers can't handle them
+pub fn try_alloc(pages: usize) -> Option<PhysAddr> {
+    if inject::should_fail(Site::Pmm) {
+        return None;
     }
+
+    alloc_pages(pages)
+}
+
+fn alloc_pages(pages: usize) -> Option<PhysAddr> {
+    let page = alloc_inner(pages).or_else(|| {
+        LAST_USED_INDEX.store(0, Ordering::Relaxed);
+        alloc_inner(pages)
+    })?;
+
+    trace::event(Event::PmmAlloc, [page.as_u64(), pages as u64]);
+    Some(page)
+}
+
+pub fn total_pages() -> usize {
+    TOTAL_PAGES.load(Ordering::Relaxed)
+}
+
+pub fn free_pages() -> usize {
+    FREE_PAGES.load(Ordering::Relaxed)
+}
+
+/// Takes a page out of the pool on behalf of the balloon, `None` rather than OOM if memory is tight
+pub fn balloon_inflate() -> Option<PhysAddr> {
+    let page = try_alloc(1)?;
+
+    BALLOONED.fetch_add(1, Ordering::Relaxed);
+    Some(page)
+}
+
+/// Puts a page the hypervisor gave back into the pool
+pub fn balloon_deflate(page: PhysAddr) {
+    free(page, 1);
+    BALLOONED.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn ballooned() -> usize {
+    BALLOONED.load(Ordering::Relaxed)
 }
 
 fn alloc_inner(pages: usize) -> Option<PhysAddr> {
     let mut bitmap = BITMAP.lock();
     let bitmap = bitmap.as_mut().unwrap();
 
-    let mut p = 0;
+    let page = bitmap.find_zero_run_from(LAST_USED_INDEX.load(Ordering::Relaxed), pages)?;
+    bitmap.set_range(page..page + pages);
+
+    LAST_USED_INDEX.store(page + pages, Ordering::Relaxed);
+    FREE_PAGES.fetch_sub(pages, Ordering::Relaxed);
 
-    while LAST_USED_INDEX.load(Ordering::Relaxed) < bitmap.len() {
-        if bitmap.test(LAST_USED_INDEX.fetch_add(1, Ordering::Relaxed)) == false {
-            p += 1;
+    Some(PhysAddr::new((page * 0x1000) as u64))
+}
 
-            if p == pages {
-                let page = LAST_USED_INDEX.load(Ordering::Relaxed) - pages;
-                for i in page..LAST_USED_INDEX.load(Ordering::Relaxed) {
-                    bitmap.set(i);
-                }
+ktest! {
+    fn alloc_zeroes_and_free_returns() {
+        let before = free_pages();
+        let page = alloc(4);
+        assert_eq!(free_pages(), before - 4);
 
-                return Some(PhysAddr::new((page * 0x1000) as u64));
-            }
-        } else {
-            p = 0;
-        }
+        let bytes = unsafe { core::slice::from_raw_parts(page.as_hhdm().as_ptr::<u8>(), 4 * 0x1000) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+
+        free(page, 4);
+        assert_eq!(free_pages(), before);
     }
+}
 
-    None
+kbench! {
+    fn alloc_free_page(b: &mut Bencher) {
+        b.iter(|| free(alloc_nozero(1), 1));
+    }
 }
@@ -0,0 +1,266 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{pmm, PhysAddr};
+use crate::backtrace::{self, Registers};
+use core::fmt::{self, Write};
+use core::mem::size_of;
+
+/// Live allocations tracked at once, past this they go untracked
+const ALLOCATIONS: usize = 1 << 16;
+/// Distinct call sites, allocations from any more go untracked
+pub const SITES: usize = 1024;
+/// How far from its slot an allocation can end up before the table counts as full
+const MAX_PROBES: usize = 256;
+
+const PAGES: usize =
+    (ALLOCATIONS * size_of::<Allocation>() + SITES * size_of::<Site>()).div_ceil(0x1000);
+
+/// Frames of the allocator itself and of `alloc` and `core`, whose callers are the interesting part
+const ALLOCATOR: &[&str] = &[
+    "alloc::",
+    "<alloc::",
+    "core::",
+    "<core::",
+    "kernel::mm::heap::",
+    "<kernel::mm::heap::",
+    "kernel::mm::sites::",
+    "__rust_",
+    "__rg_",
+];
+
+#[derive(Clone, Copy)]
+struct Allocation {
+    /// 0 for a free slot
+    ptr: u64,
+    site: u32,
+}
+
+/// What the allocations made from one place in the kernel add up to
+#[derive(Clone, Copy)]
+pub struct Site {
+    /// Where the call into the allocator returns to, 0 for a free slot
+    pub caller: u64,
+    pub live_bytes: u64,
+    pub live: u64,
+    /// Allocations ever made from here
+    pub total: u64,
+}
+
+/// Heap allocations bucketed by call site, kept in pages of their own so it doesn't watch itself
+pub struct Sites {
+    page: PhysAddr,
+    allocations: &'static mut [Allocation],
+    sites: &'static mut [Site],
+    /// Allocations that found the tables full, their frees go unnoticed
+    untracked: u64,
+}
+
+/// The start of a demangled name, which can't go through the heap from inside the allocator
+struct Prefix {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl Write for Prefix {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+
+        match len == s.len() {
+            true => Ok(()),
+            false => Err(fmt::Error),
+        }
+    }
+}
+
+impl Prefix {
+    fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.len];
+        core::str::from_utf8(bytes)
+            .or_else(|err| core::str::from_utf8(&bytes[..err.valid_up_to()]))
+            .unwrap_or("")
+    }
+}
+
+fn hash(value: u64) -> usize {
+    (value.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize
+}
+
+/// Whether `rip` is in the allocator, or in the `alloc` and `core` code on the way to it
+fn allocator(rip: u64) -> bool {
+    let Some((name, _)) = backtrace::lookup(rip) else {
+        return false;
+    };
+
+    let mut prefix = Prefix {
+        bytes: [0; 128],
+        len: 0,
+    };
+    let _ = write!(prefix, "{name:#}");
+    let name = prefix.as_str();
+
+    ALLOCATOR.iter().any(|frame| name.starts_with(frame))
+        || name.contains(" as alloc::")
+        || name.contains(" as core::alloc::")
+}
+
+/// The return address into whatever called the allocator
+#[inline(never)]
+pub fn caller() -> u64 {
+    let mut caller = 0;
+    backtrace::walk_while(Registers::current(), |rip| {
+        caller = rip;
+        allocator(rip)
+    });
+
+    caller
+}
+
+impl Sites {
+    pub fn new() -> Option<Sites> {
+        let page = pmm::try_alloc(PAGES)?;
+        let base = page.as_hhdm().as_mut_ptr::<u8>();
+
+        // Zeroed slots are free ones
+        unsafe {
+            core::ptr::write_bytes(base, 0, PAGES * 0x1000);
+
+            let allocations = base.cast::<Allocation>();
+            let sites = allocations.add(ALLOCATIONS).cast::<Site>();
+            Some(Sites {
+                page,
+                allocations: core::slice::from_raw_parts_mut(allocations, ALLOCATIONS),
+                sites: core::slice::from_raw_parts_mut(sites, SITES),
+                untracked: 0,
+            })
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Site> {
+        self.sites.iter().filter(|site| site.caller != 0)
+    }
+
+    pub fn untracked(&self) -> u64 {
+        self.untracked
+    }
+
+    /// The slot of `caller`, taking a free one the first time it shows up
+    fn site(&mut self, caller: u64) -> Option<usize> {
+        let start = hash(caller);
+
+        (0..SITES).map(|i| (start + i) % SITES).find(|&i| {
+            let site = &mut self.sites[i];
+            if site.caller == 0 {
+                site.caller = caller;
+            }
+
+            site.caller == caller
+        })
+    }
+
+    fn find(&self, ptr: u64) -> Option<usize> {
+        let start = hash(ptr);
+
+        (0..MAX_PROBES)
+            .map(|i| (start + i) % ALLOCATIONS)
+            .take_while(|&i| self.allocations[i].ptr != 0)
+            .find(|&i| self.allocations[i].ptr == ptr)
+    }
+
+    pub fn allocated(&mut self, ptr: *mut u8, size: usize, caller: u64) {
+        let start = hash(ptr as u64);
+        let slot = (0..MAX_PROBES)
+            .map(|i| (start + i) % ALLOCATIONS)
+            .find(|&i| self.allocations[i].ptr == 0);
+
+        let (Some(site), Some(slot)) = (self.site(caller), slot) else {
+            self.untracked += 1;
+            return;
+        };
+
+        self.allocations[slot] = Allocation {
+            ptr: ptr as u64,
+            site: site as u32,
+        };
+
+        let site = &mut self.sites[site];
+        site.live_bytes += size as u64;
+        site.live += 1;
+        site.total += 1;
+    }
+
+    pub fn freed(&mut self, ptr: *mut u8, size: usize) {
+        let Some(slot) = self.find(ptr as u64) else {
+            return;
+        };
+
+        let site = &mut self.sites[self.allocations[slot].site as usize];
+        site.live_bytes = site.live_bytes.saturating_sub(size as u64);
+        site.live = site.live.saturating_sub(1);
+
+        // Backward shift deletion, so lookups can keep stopping at the first free slot
+        let mut hole = slot;
+        let mut next = slot;
+        loop {
+            next = (next + 1) % ALLOCATIONS;
+            let ptr = self.allocations[next].ptr;
+            if ptr == 0 {
+                break;
+            }
+
+            // Entries whose home is cyclically in (hole, next] are already as close as they get
+            let home = hash(ptr) % ALLOCATIONS;
+            let stays = match hole <= next {
+                true => hole < home && home <= next,
+                false => hole < home || home <= next,
+            };
+            if !stays {
+                self.allocations[hole] = self.allocations[next];
+                hole = next;
+            }
+        }
+
+        self.allocations[hole].ptr = 0;
+    }
+}
+
+impl Drop for Sites {
+    fn drop(&mut self) {
+        pmm::free(self.page, PAGES);
+    }
+}
+
+ktest! {
+    fn sites_follow_allocations_and_frees() {
+        let mut sites = Sites::new().unwrap();
+
+        sites.allocated(0x1000 as *mut u8, 64, 0xAAAA);
+        sites.allocated(0x2000 as *mut u8, 32, 0xAAAA);
+        sites.allocated(0x3000 as *mut u8, 8, 0xBBBB);
+        sites.freed(0x1000 as *mut u8, 64);
+        // Not tracked, like the frees of allocations made before tracking started
+        sites.freed(0x4000 as *mut u8, 16);
+
+        let site = *sites.iter().find(|site| site.caller == 0xAAAA).unwrap();
+        assert_eq!((site.live_bytes, site.live, site.total), (32, 1, 2));
+        assert!(sites.find(0x2000).is_some());
+        assert!(sites.find(0x1000).is_none());
+        assert_eq!(sites.iter().count(), 2);
+    }
+}
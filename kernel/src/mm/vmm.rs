@@ -0,0 +1,147 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{pmm, PhysAddr, VirtAddr};
+use crate::cpu;
+use crate::sync::Mutex;
+
+pub const PRESENT: u64 = 1 << 0;
+pub const WRITABLE: u64 = 1 << 1;
+pub const USER: u64 = 1 << 2;
+pub const WRITE_THROUGH: u64 = 1 << 3;
+pub const NO_CACHE: u64 = 1 << 4;
+pub const HUGE: u64 = 1 << 7;
+pub const GLOBAL: u64 = 1 << 8;
+pub const NO_EXECUTE: u64 = 1 << 63;
+
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Serializes changes to the kernel half of the page tables, which every core shares
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn index(virt: VirtAddr, level: usize) -> usize {
+    ((virt.as_u64() >> (12 + 9 * (level - 1))) & 0x1FF) as usize
+}
+
+fn table(phys: PhysAddr) -> &'static mut [u64; 512] {
+    unsafe { &mut *phys.as_hhdm().as_mut_ptr() }
+}
+
+/// Walks down to the page table holding `virt`, creating the missing levels on the way
+fn walk_create(virt: VirtAddr) -> &'static mut [u64; 512] {
+    let mut current = table(cpu::get_cr3());
+
+    for level in (2..=4).rev() {
+        let entry = &mut current[index(virt, level)];
+
+        if *entry & PRESENT == 0 {
+            // Intermediate levels are permissive, the leaf entry decides
+            *entry = pmm::alloc(1).as_u64() | PRESENT | WRITABLE;
+        }
+
+        assert!(*entry & HUGE == 0, "{virt:x?} is covered by a huge page");
+        current = table(PhysAddr::new(*entry & ADDRESS_MASK));
+    }
+
+    current
+}
+
+/// Maps the 4 KiB page at `virt` to `phys`
+pub fn map(virt: VirtAddr, phys: PhysAddr, flags: u64) {
+    let _guard = LOCK.lock();
+    let table = walk_create(virt);
+
+    table[index(virt, 1)] = (phys.as_u64() & ADDRESS_MASK) | flags | PRESENT;
+    unsafe { cpu::invlpg(virt) };
+}
+
+/// Walks down to the page table holding `virt`, `None` if a level on the way is missing
+fn walk(virt: VirtAddr) -> Option<&'static mut [u64; 512]> {
+    let mut current = table(cpu::get_cr3());
+
+    for level in (2..=4).rev() {
+        let entry = current[index(virt, level)];
+
+        if entry & PRESENT == 0 || entry & HUGE != 0 {
+            return None;
+        }
+
+        current = table(PhysAddr::new(entry & ADDRESS_MASK));
+    }
+
+    Some(current)
+}
+
+/// Unmaps the 4 KiB page at `virt`, nothing happens if it wasn't mapped
+pub fn unmap(virt: VirtAddr) {
+    let _guard = LOCK.lock();
+    let Some(table) = walk(virt) else {
+        return;
+    };
+
+    table[index(virt, 1)] = 0;
+    unsafe { cpu::invlpg(virt) };
+}
+
+/// The entry covering `virt` at every level from the PML4 down, `None` past where the walk stops
+pub fn entries(virt: VirtAddr) -> [Option<u64>; 4] {
+    let mut entries = [None; 4];
+    let mut current = table(cpu::get_cr3());
+
+    for level in (1..=4).rev() {
+        let entry = current[index(virt, level)];
+        entries[4 - level] = Some(entry);
+
+        if entry & PRESENT == 0 || entry & HUGE != 0 {
+            break;
+        }
+
+        current = table(PhysAddr::new(entry & ADDRESS_MASK));
+    }
+
+    entries
+}
+
+/// Returns the physical address `virt` is mapped to, huge pages included
+/// Whether reading `addr` won't fault, it's canonical and mapped
+pub fn readable(addr: u64) -> bool {
+    ((addr as i64) << 16 >> 16) as u64 == addr && translate(VirtAddr::new(addr)).is_some()
+}
+
+pub fn translate(virt: VirtAddr) -> Option<PhysAddr> {
+    let mut current = table(cpu::get_cr3());
+
+    for level in (1..=4).rev() {
+        let entry = current[index(virt, level)];
+
+        if entry & PRESENT == 0 {
+            return None;
+        }
+
+        let page_size = 1u64 << (12 + 9 * (level - 1));
+        if level == 1 || entry & HUGE != 0 {
+            let base = entry & ADDRESS_MASK & !(page_size - 1);
+            return Some(PhysAddr::new(base + (virt.as_u64() & (page_size - 1))));
+        }
+
+        current = table(PhysAddr::new(entry & ADDRESS_MASK));
+    }
+
+    None
+}
@@ -0,0 +1,42 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use limine::{LimineFile, LimineModuleRequest, NonNullPtr};
+
+static MODULES: LimineModuleRequest = LimineModuleRequest::new(0);
+
+/// Files the bootloader loaded along with the kernel
+pub fn all() -> &'static [NonNullPtr<LimineFile>] {
+    MODULES
+        .get_response()
+        .get()
+        .map_or(&[], |response| response.modules())
+}
+
+pub fn path(module: &LimineFile) -> Option<&str> {
+    module.path.to_str().and_then(|p| p.to_str().ok())
+}
+
+pub fn cmdline(module: &LimineFile) -> Option<&str> {
+    module.cmdline.to_str().and_then(|c| c.to_str().ok())
+}
+
+/// What `module` contains, it sits in memory the bootloader never hands back so it lives forever
+pub fn data(module: &LimineFile) -> Option<&'static [u8]> {
+    let base = module.base.as_ptr()?;
+    Some(unsafe { core::slice::from_raw_parts(base, module.length as usize) })
+}
@@ -0,0 +1,351 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::apic::ICR_ALL_EXCLUDING_SELF;
+use crate::backtrace::{self, Registers};
+use crate::core_locals::MAX_CORES;
+use crate::interrupts::{self, Exception, InterruptStack};
+use crate::kshell::{dump, number};
+use crate::mm::{vmm, PhysAddr, VirtAddr};
+use crate::sync::IrqSpinlock;
+use crate::{cmdline, core_locals, hpet, power, serial};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+const PROMPT: &str = "monitor> ";
+const MAX_LINE: usize = 128;
+
+/// How long to wait for the other cores to stop, in milliseconds
+const STOP_TIMEOUT_MS: usize = 100;
+
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+/// What every other core was doing when it got stopped
+static STATES: IrqSpinlock<[Option<InterruptStack>; MAX_CORES]> =
+    IrqSpinlock::new([None; MAX_CORES]);
+
+/// The serial console, with bare LFs turned into CR LF
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            match line.strip_suffix('\n') {
+                Some(text) if !text.ends_with('\r') => serial::_print(format_args!("{text}\r\n")),
+                _ => serial::_print(format_args!("{line}")),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn stop_handler(stack: &mut InterruptStack) {
+    if let Some(state) = STATES.lock().get_mut(core!().id) {
+        *state = Some(*stack);
+    }
+    STOPPED.fetch_add(1, Ordering::SeqCst);
+
+    loop {
+        unsafe { core::arch::asm!("cli; hlt") };
+    }
+}
+
+/// Reserves the vector used to stop the other cores, there might be none free by the time of a panic
+pub fn init() {
+    match interrupts::allocate_handler(stop_handler) {
+        Some(vector) => VECTOR.store(vector, Ordering::Relaxed),
+        None => log::warn!("monitor: no free vector, other cores won't be stopped on panic"),
+    }
+}
+
+initcall!(monitor, init, []);
+
+/// Stops every other core, saving their registers for the monitor
+pub fn stop_other_cores() {
+    let vector = VECTOR.load(Ordering::Relaxed);
+    let others = core_locals::cores_online().saturating_sub(1);
+    if vector == 0 || others == 0 || !core_locals::initialized() {
+        return;
+    }
+
+    // The panic might have happened with the APIC locked
+    let Some(mut apic) = core!().apic.try_lock() else {
+        return;
+    };
+    unsafe { apic.ipi(0, vector as u32 | ICR_ALL_EXCLUDING_SELF) };
+    drop(apic);
+
+    for _ in 0..STOP_TIMEOUT_MS {
+        if STOPPED.load(Ordering::SeqCst) == others {
+            return;
+        }
+
+        if !hpet::try_sleep(1_000_000) {
+            for _ in 0..100_000 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    let stopped = STOPPED.load(Ordering::SeqCst);
+    log::warn!("Only {stopped} of {others} cores stopped");
+}
+
+type Command = fn(&mut Console, &[&str], Option<&Exception>) -> Result<bool, &'static str>;
+
+/// Name, usage and handler of every command, handlers return whether to leave the monitor
+const COMMANDS: &[(&str, &str, Command)] = &[
+    ("help", "help", help),
+    (
+        "regs",
+        "regs                    registers at the exception",
+        regs,
+    ),
+    ("bt", "bt [core]               backtrace", bt),
+    (
+        "cores",
+        "cores                   where every core stopped",
+        cores,
+    ),
+    ("md", "md <addr> [len]         dump virtual memory", md),
+    ("mdp", "mdp <phys> [len]        dump physical memory", mdp),
+    ("pt", "pt <addr>               walk the page tables", pt),
+    ("reboot", "reboot", reboot),
+    (
+        "halt",
+        "halt                    leave the monitor and halt",
+        halt,
+    ),
+];
+
+fn help(out: &mut Console, _: &[&str], _: Option<&Exception>) -> Result<bool, &'static str> {
+    for (_, usage, _) in COMMANDS {
+        let _ = writeln!(out, "  {usage}");
+    }
+
+    Ok(false)
+}
+
+fn regs(
+    out: &mut Console,
+    _: &[&str],
+    exception: Option<&Exception>,
+) -> Result<bool, &'static str> {
+    let exception = exception.ok_or("the panic didn't come from an exception")?;
+    let _ = writeln!(out, "{}", exception.stack);
+
+    Ok(false)
+}
+
+/// The panicking core, which might have died before its core locals were set up
+pub fn current_core() -> usize {
+    core_locals::id()
+}
+
+/// The saved registers of `core`, or of the exception when it's the panicking one
+pub fn state(core: usize, exception: Option<&Exception>) -> Option<InterruptStack> {
+    if core == current_core() {
+        return exception.map(|exception| exception.stack);
+    }
+
+    STATES.lock().get(core).copied().flatten()
+}
+
+fn bt(
+    out: &mut Console,
+    args: &[&str],
+    exception: Option<&Exception>,
+) -> Result<bool, &'static str> {
+    let start = match args.first() {
+        Some(core) => {
+            let core = number(Some(core))? as usize;
+            let stack = state(core, exception).ok_or("no saved state for that core")?;
+            Registers::from(&stack)
+        }
+        None => exception.map_or_else(Registers::current, |e| Registers::from(&e.stack)),
+    };
+
+    let mut i = 0;
+    backtrace::walk(start, |rip| {
+        let _ = match backtrace::lookup(rip) {
+            Some((name, offset)) => writeln!(out, "{i:>2}: {rip:#018x} - {name:#}+{offset:#x}"),
+            None => writeln!(out, "{i:>2}: {rip:#018x} - <unknown>"),
+        };
+
+        i += 1;
+    });
+
+    Ok(false)
+}
+
+fn cores(
+    out: &mut Console,
+    _: &[&str],
+    exception: Option<&Exception>,
+) -> Result<bool, &'static str> {
+    for core in 0..core_locals::cores_online().clamp(1, MAX_CORES) {
+        let _ = write!(out, "core {core}: ");
+
+        let Some(stack) = state(core, exception) else {
+            let _ = match core == current_core() {
+                true => writeln!(out, "panicked"),
+                false => writeln!(out, "didn't stop"),
+            };
+            continue;
+        };
+
+        let _ = match backtrace::lookup(stack.rip) {
+            Some((name, offset)) => write!(out, "{name:#}+{offset:#x}"),
+            None => write!(out, "{:#018x}", stack.rip),
+        };
+        let _ = writeln!(out, " rsp {:#018x}", stack.rsp);
+    }
+
+    Ok(false)
+}
+
+fn md(out: &mut Console, args: &[&str], _: Option<&Exception>) -> Result<bool, &'static str> {
+    let addr = number(args.first())?;
+    let len = args.get(1).map_or(Ok(64), |len| number(Some(len)))?;
+
+    dump(out, addr, len);
+    Ok(false)
+}
+
+fn mdp(out: &mut Console, args: &[&str], _: Option<&Exception>) -> Result<bool, &'static str> {
+    let addr = PhysAddr::new(number(args.first())?).as_hhdm();
+    let len = args.get(1).map_or(Ok(64), |len| number(Some(len)))?;
+
+    dump(out, addr.as_u64(), len);
+    Ok(false)
+}
+
+fn pt(out: &mut Console, args: &[&str], _: Option<&Exception>) -> Result<bool, &'static str> {
+    let addr = VirtAddr::new(number(args.first())?);
+    let levels = ["pml4", "pdpt", "pd", "pt"];
+
+    for (name, entry) in levels.iter().zip(vmm::entries(addr)) {
+        let Some(entry) = entry else {
+            break;
+        };
+
+        let _ = write!(out, "{name:<4} {entry:#018x}");
+        for (bit, flag) in [
+            (vmm::PRESENT, " present"),
+            (vmm::WRITABLE, " writable"),
+            (vmm::USER, " user"),
+            (vmm::NO_CACHE, " uncached"),
+            (vmm::HUGE, " huge"),
+            (vmm::GLOBAL, " global"),
+            (vmm::NO_EXECUTE, " nx"),
+        ] {
+            if entry & bit != 0 {
+                let _ = out.write_str(flag);
+            }
+        }
+        let _ = writeln!(out);
+    }
+
+    let _ = match vmm::translate(addr) {
+        Some(phys) => writeln!(out, "-> {:#x}", phys.as_u64()),
+        None => writeln!(out, "-> not mapped"),
+    };
+
+    Ok(false)
+}
+
+fn reboot(_: &mut Console, _: &[&str], _: Option<&Exception>) -> Result<bool, &'static str> {
+    power::reboot()
+}
+
+fn halt(_: &mut Console, _: &[&str], _: Option<&Exception>) -> Result<bool, &'static str> {
+    Ok(true)
+}
+
+fn run(out: &mut Console, line: &str, exception: Option<&Exception>) -> bool {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return false;
+    };
+
+    // No heap here, it might be what broke
+    let mut args = [""; 4];
+    let mut count = 0;
+    for (arg, word) in args.iter_mut().zip(words) {
+        *arg = word;
+        count += 1;
+    }
+
+    let Some((_, usage, command)) = COMMANDS.iter().find(|(n, _, _)| *n == name) else {
+        let _ = writeln!(out, "{name}: unknown command, try help");
+        return false;
+    };
+
+    command(out, &args[..count], exception).unwrap_or_else(|err| {
+        let _ = writeln!(out, "{name}: {err}\nusage: {usage}");
+        false
+    })
+}
+
+/// Lets whoever is on the serial console look around before the machine halts, `nomonitor` skips it
+pub fn enter(exception: Option<Exception>) {
+    if cmdline::flag("nomonitor") || !serial::has_console() {
+        return;
+    }
+
+    let mut out = Console;
+    let _ = writeln!(out, "\nPanic monitor, type help for the commands");
+    let _ = out.write_str(PROMPT);
+
+    let mut line = [0u8; MAX_LINE];
+    let mut len = 0;
+    let mut after_cr = false;
+
+    loop {
+        let Some(byte) = serial::poll_console() else {
+            core::hint::spin_loop();
+            continue;
+        };
+
+        match byte {
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => {
+                let _ = writeln!(out);
+                let text = core::str::from_utf8(&line[..len]).unwrap_or("");
+                if run(&mut out, text, exception.as_ref()) {
+                    return;
+                }
+
+                len = 0;
+                let _ = out.write_str(PROMPT);
+            }
+            0x08 | 0x7F if len > 0 => {
+                len -= 1;
+                let _ = out.write_str("\x08 \x08");
+            }
+            b' '..=b'~' if len < MAX_LINE => {
+                line[len] = byte;
+                len += 1;
+                let _ = out.write_char(byte as char);
+            }
+            _ => {}
+        }
+
+        after_cr = byte == b'\r';
+    }
+}
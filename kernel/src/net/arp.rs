@@ -0,0 +1,210 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ethernet::{Header as EthernetHeader, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use super::ipv4::Address;
+use super::{Error, Interface, MacAddress, PacketBuffer};
+use crate::sync::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const HARDWARE_ETHERNET: u16 = 1;
+
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+const PACKET_SIZE: usize = 28;
+
+/// How long a learned address is trusted before asking again
+const ENTRY_LIFETIME_US: u64 = 60_000_000;
+
+/// Requests for an address that doesn't answer go out at most this often
+const REQUEST_INTERVAL_US: u64 = 1_000_000;
+
+/// Packets held per address while waiting for a reply, older ones make room
+const PENDING_LIMIT: usize = 16;
+
+struct Entry {
+    mac: Option<MacAddress>,
+    updated: u64,
+    last_request: Option<u64>,
+    /// IPv4 packets waiting for the address to be resolved
+    pending: Vec<PacketBuffer>,
+}
+
+/// Neighbors of an interface
+#[derive(Default)]
+pub struct Cache {
+    entries: Mutex<BTreeMap<Address, Entry>>,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache::default()
+    }
+
+    pub fn lookup(&self, address: Address) -> Option<MacAddress> {
+        let entries = self.entries.lock();
+        let entry = entries.get(&address)?;
+
+        (super::now_us().saturating_sub(entry.updated) < ENTRY_LIFETIME_US)
+            .then_some(entry.mac)
+            .flatten()
+    }
+
+    /// Learns where `address` is, returns the packets that were waiting for it
+    fn insert(&self, address: Address, mac: MacAddress) -> Vec<PacketBuffer> {
+        let mut entries = self.entries.lock();
+        let entry = entries.entry(address).or_insert(Entry {
+            mac: None,
+            updated: 0,
+            last_request: None,
+            pending: Vec::new(),
+        });
+
+        entry.mac = Some(mac);
+        entry.updated = super::now_us();
+        entry.last_request = None;
+
+        core::mem::take(&mut entry.pending)
+    }
+
+    /// Every address with a known MAC
+    pub fn entries(&self) -> Vec<(Address, MacAddress)> {
+        self.entries
+            .lock()
+            .iter()
+            .filter_map(|(&address, entry)| Some((address, entry.mac?)))
+            .collect()
+    }
+}
+
+fn build(
+    operation: u16,
+    sender: (MacAddress, Address),
+    target: (MacAddress, Address),
+) -> PacketBuffer {
+    let mut buffer = PacketBuffer::new();
+    let packet = buffer.put(PACKET_SIZE);
+
+    packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet[4..6].copy_from_slice(&[6, 4]);
+    packet[6..8].copy_from_slice(&operation.to_be_bytes());
+    packet[8..14].copy_from_slice(&sender.0 .0);
+    packet[14..18].copy_from_slice(&sender.1 .0);
+    packet[18..24].copy_from_slice(&target.0 .0);
+    packet[24..28].copy_from_slice(&target.1 .0);
+
+    buffer
+}
+
+fn request(interface: &Interface, address: Address) -> Result<(), Error> {
+    let config = interface.ipv4().ok_or(Error::NoRoute)?;
+    let packet = build(
+        OPERATION_REQUEST,
+        (interface.mac(), config.address),
+        (MacAddress([0; 6]), address),
+    );
+
+    interface.send(MacAddress::BROADCAST, ETHERTYPE_ARP, packet)
+}
+
+/// Sends an IPv4 packet to the neighbor `next_hop`, resolving it first if needed
+pub fn send(
+    interface: &Arc<Interface>,
+    next_hop: Address,
+    packet: PacketBuffer,
+) -> Result<(), Error> {
+    if let Some(mac) = interface.arp().lookup(next_hop) {
+        return interface.send(mac, ETHERTYPE_IPV4, packet);
+    }
+
+    let now = super::now_us();
+    let ask = {
+        let mut entries = interface.arp().entries.lock();
+        let entry = entries.entry(next_hop).or_insert(Entry {
+            mac: None,
+            updated: 0,
+            last_request: None,
+            pending: Vec::new(),
+        });
+
+        if entry.pending.len() >= PENDING_LIMIT {
+            entry.pending.remove(0);
+        }
+        entry.pending.push(packet);
+
+        let ask = entry
+            .last_request
+            .is_none_or(|last| now.saturating_sub(last) >= REQUEST_INTERVAL_US);
+        if ask {
+            entry.last_request = Some(now);
+        }
+
+        ask
+    };
+
+    if ask {
+        request(interface, next_hop)?;
+    }
+
+    Ok(())
+}
+
+pub fn receive(interface: &Arc<Interface>, _ethernet: &EthernetHeader, packet: &[u8]) {
+    if packet.len() < PACKET_SIZE {
+        return;
+    }
+
+    let hardware = u16::from_be_bytes([packet[0], packet[1]]);
+    let protocol = u16::from_be_bytes([packet[2], packet[3]]);
+    if hardware != HARDWARE_ETHERNET
+        || protocol != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+
+    let operation = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac = MacAddress(packet[8..14].try_into().unwrap());
+    let sender = Address(packet[14..18].try_into().unwrap());
+    let target = Address(packet[24..28].try_into().unwrap());
+
+    let Some(config) = interface.ipv4() else {
+        return;
+    };
+
+    // Anyone asking us also tells us where they are, and so do replies and announcements
+    let known = interface.arp().lookup(sender).is_some();
+    if target == config.address || known {
+        for pending in interface.arp().insert(sender, sender_mac) {
+            let _ = interface.send(sender_mac, ETHERTYPE_IPV4, pending);
+        }
+    }
+
+    if operation == OPERATION_REQUEST && target == config.address {
+        let reply = build(
+            OPERATION_REPLY,
+            (interface.mac(), config.address),
+            (sender_mac, sender),
+        );
+        let _ = interface.send(sender_mac, ETHERTYPE_ARP, reply);
+    }
+}
@@ -0,0 +1,165 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::MAX_FRAME;
+use crate::core_locals;
+use crate::sync::Mutex;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+
+/// Room kept in front of the data for the headers of the layers below
+pub const HEADROOM: usize = 128;
+
+const CAPACITY: usize = 2048;
+
+/// Free buffers a core keeps around, the rest go back to the heap
+const POOL_LIMIT: usize = 256;
+
+const _: () = assert!(CAPACITY - HEADROOM >= MAX_FRAME);
+
+/// Free packet buffers of one core
+pub struct Pool {
+    free: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl Pool {
+    pub const fn new() -> Pool {
+        Pool {
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// A lock held by whatever this core was interrupted in just means going to the heap
+    fn take(&self) -> Box<[u8]> {
+        self.free
+            .try_lock()
+            .and_then(|mut free| free.pop())
+            .unwrap_or_else(|| vec![0; CAPACITY].into_boxed_slice())
+    }
+
+    fn give(&self, storage: Box<[u8]>) {
+        if let Some(mut free) = self.free.try_lock() {
+            if free.len() < POOL_LIMIT {
+                free.push(storage);
+            }
+        }
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Pool {
+        Pool::new()
+    }
+}
+
+fn pool() -> Option<&'static Pool> {
+    core_locals::initialized().then(|| &core!().packet_buffers)
+}
+
+/// A frame or packet, with room in front to prepend headers without copying the payload
+pub struct PacketBuffer {
+    storage: Option<Box<[u8]>>,
+    start: usize,
+    end: usize,
+}
+
+impl PacketBuffer {
+    /// An empty buffer, taken from the pool of the current core
+    pub fn new() -> PacketBuffer {
+        let storage = match pool() {
+            Some(pool) => pool.take(),
+            None => vec![0; CAPACITY].into_boxed_slice(),
+        };
+
+        PacketBuffer {
+            storage: Some(storage),
+            start: HEADROOM,
+            end: HEADROOM,
+        }
+    }
+
+    pub fn from_slice(data: &[u8]) -> PacketBuffer {
+        let mut buffer = PacketBuffer::new();
+        buffer.put(data.len()).copy_from_slice(data);
+        buffer
+    }
+
+    fn storage(&mut self) -> &mut [u8] {
+        self.storage.as_mut().unwrap()
+    }
+
+    /// Space left after the data
+    pub fn tailroom(&self) -> usize {
+        CAPACITY - self.end
+    }
+
+    /// Grows the data by `len` bytes at the end and returns them
+    pub fn put(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.tailroom(), "packet buffer overflow");
+
+        let end = self.end;
+        self.end += len;
+        &mut self.storage()[end..end + len]
+    }
+
+    /// Grows the data by `len` bytes at the front, for a header, and returns them
+    pub fn push(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.start, "out of headroom");
+
+        self.start -= len;
+        let start = self.start;
+        &mut self.storage()[start..start + len]
+    }
+
+    /// Grows the data to `len` bytes with zeroes, if it's shorter
+    pub fn pad(&mut self, len: usize) {
+        let missing = len.saturating_sub(self.len());
+        self.put(missing).fill(0);
+    }
+}
+
+impl Default for PacketBuffer {
+    fn default() -> PacketBuffer {
+        PacketBuffer::new()
+    }
+}
+
+impl Deref for PacketBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.storage.as_ref().unwrap()[self.start..self.end]
+    }
+}
+
+impl DerefMut for PacketBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let (start, end) = (self.start, self.end);
+        &mut self.storage()[start..end]
+    }
+}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        // Back to whichever core drops it, which is usually the one that took it
+        if let (Some(storage), Some(pool)) = (self.storage.take(), pool()) {
+            pool.give(storage);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{MacAddress, PacketBuffer};
+
+pub const HEADER_SIZE: usize = 14;
+
+/// Shortest frame on the wire without the FCS, shorter ones get padded
+pub const MIN_FRAME: usize = 60;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+}
+
+impl Header {
+    /// Splits `frame` into its header and payload
+    pub fn parse(frame: &[u8]) -> Option<(Header, &[u8])> {
+        if frame.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let header = Header {
+            destination: MacAddress(frame[0..6].try_into().unwrap()),
+            source: MacAddress(frame[6..12].try_into().unwrap()),
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+        };
+
+        Some((header, &frame[HEADER_SIZE..]))
+    }
+}
+
+impl Header {
+    /// Turns the payload in `buffer` into a frame
+    pub fn write(&self, buffer: &mut PacketBuffer) {
+        let header = buffer.push(HEADER_SIZE);
+
+        header[0..6].copy_from_slice(&self.destination.0);
+        header[6..12].copy_from_slice(&self.source.0);
+        header[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+
+        buffer.pad(MIN_FRAME);
+    }
+}
@@ -0,0 +1,47 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ipv4::{self, Header, PROTOCOL_ICMP};
+use super::Interface;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const HEADER_SIZE: usize = 8;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+/// Answers echo requests, everything else is ignored
+pub fn receive(interface: &Arc<Interface>, header: &Header, message: &[u8]) {
+    if message.len() < HEADER_SIZE || ipv4::checksum(&[message]) != 0 {
+        return;
+    }
+
+    if message[0] != TYPE_ECHO_REQUEST || message[1] != 0 {
+        return;
+    }
+
+    // Same identifier, sequence number and data, only the type and checksum change
+    let mut reply: Vec<u8> = message.to_vec();
+    reply[0] = TYPE_ECHO_REPLY;
+    reply[2..4].fill(0);
+
+    let checksum = ipv4::checksum(&[&reply]);
+    reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let _ = ipv4::reply(interface, header, PROTOCOL_ICMP, &reply);
+}
@@ -0,0 +1,160 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ethernet::Header;
+use super::{arp, ipv4, Error, MacAddress, NetDevice, PacketBuffer};
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Handles the payload of a frame of some ethertype
+pub type Handler = fn(&Arc<Interface>, &Header, &[u8]);
+
+static PROTOCOLS: Mutex<Vec<(u16, Handler)>> = Mutex::new(Vec::new());
+
+#[derive(Default)]
+pub struct Stats {
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    /// Frames not meant for us, malformed or of an unknown protocol
+    pub rx_dropped: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub tx_errors: AtomicU64,
+}
+
+/// The stack's view of a network device
+pub struct Interface {
+    device: Arc<dyn NetDevice>,
+    stats: Stats,
+    ipv4: Mutex<Option<ipv4::Config>>,
+    arp: arp::Cache,
+}
+
+impl Interface {
+    pub fn new(device: Arc<dyn NetDevice>) -> Interface {
+        Interface {
+            device,
+            stats: Stats::default(),
+            ipv4: Mutex::new(None),
+            arp: arp::Cache::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    pub fn mac(&self) -> MacAddress {
+        self.device.mac()
+    }
+
+    pub fn device(&self) -> &Arc<dyn NetDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn ipv4(&self) -> Option<ipv4::Config> {
+        *self.ipv4.lock()
+    }
+
+    pub fn set_ipv4(&self, config: Option<ipv4::Config>) {
+        match config {
+            Some(config) => log::info!("{}: {config}", self.name()),
+            None => log::info!("{}: no address", self.name()),
+        }
+
+        *self.ipv4.lock() = config;
+    }
+
+    pub fn arp(&self) -> &arp::Cache {
+        &self.arp
+    }
+
+    /// Sends the payload in `frame` to `destination`, in a frame of type `ethertype`
+    pub fn send(
+        &self,
+        destination: MacAddress,
+        ethertype: u16,
+        mut frame: PacketBuffer,
+    ) -> Result<(), Error> {
+        let header = Header {
+            destination,
+            source: self.mac(),
+            ethertype,
+        };
+        header.write(&mut frame);
+
+        match self.device.transmit(&frame) {
+            Ok(()) => {
+                self.stats.tx_packets.fetch_add(1, Ordering::Relaxed);
+                self.stats
+                    .tx_bytes
+                    .fetch_add(frame.len() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(err) => {
+                self.stats.tx_errors.fetch_add(1, Ordering::Relaxed);
+                Err(err)
+            }
+        }
+    }
+
+    /// Takes one frame off the device and hands it to its protocol, false if there was none
+    pub fn receive(self: &Arc<Self>) -> bool {
+        let Some(frame) = self.device.receive() else {
+            return false;
+        };
+
+        self.stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .rx_bytes
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+
+        let handler = Header::parse(&frame).and_then(|(header, payload)| {
+            let ours = header.destination == self.mac() || header.destination.is_multicast();
+            let handler = PROTOCOLS
+                .lock()
+                .iter()
+                .find(|&&(ethertype, _)| ethertype == header.ethertype)
+                .map(|&(_, handler)| handler);
+
+            ours.then_some(((header, payload), handler?))
+        });
+
+        match handler {
+            Some(((header, payload), handler)) => handler(self, &header, payload),
+            None => {
+                self.stats.rx_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        true
+    }
+}
+
+/// Hands every received frame of type `ethertype` to `handler`
+pub fn register_protocol(ethertype: u16, handler: Handler) {
+    let mut protocols = PROTOCOLS.lock();
+
+    protocols.retain(|&(e, _)| e != ethertype);
+    protocols.push((ethertype, handler));
+}
@@ -0,0 +1,379 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ethernet::{self, Header as EthernetHeader};
+use super::{arp, Error, Interface, MacAddress, PacketBuffer};
+use crate::cmdline;
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub const HEADER_SIZE: usize = 20;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const DEFAULT_TTL: u8 = 64;
+
+/// Flags and fragment offset
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET: u16 = 0x1FFF;
+
+/// Handles the payload of a packet of some IP protocol
+pub type Handler = fn(&Arc<Interface>, &Header, &[u8]);
+
+static PROTOCOLS: Mutex<Vec<(u8, Handler)>> = Mutex::new(Vec::new());
+
+/// Identification of the next packet sent
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address(pub [u8; 4]);
+
+impl Address {
+    pub const BROADCAST: Address = Address([0xFF; 4]);
+
+    /// Parses dotted decimal, e.g. `10.0.2.15`
+    pub fn parse(text: &str) -> Option<Address> {
+        let mut octets = [0; 4];
+        let mut parts = text.split('.');
+
+        for octet in octets.iter_mut() {
+            *octet = parts.next()?.parse().ok()?;
+        }
+
+        parts.next().is_none().then_some(Address(octets))
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+/// An address and port
+pub type Endpoint = (Address, u16);
+
+/// Address of an interface and how to reach the rest of the world from it
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub address: Address,
+    pub prefix_len: u8,
+    pub gateway: Option<Address>,
+}
+
+impl Config {
+    /// Parses `address/prefix`, e.g. `10.0.2.15/24`
+    pub fn parse(text: &str, gateway: Option<&str>) -> Option<Config> {
+        let (address, prefix_len) = text.split_once('/').unwrap_or((text, "24"));
+        let prefix_len = prefix_len.parse().ok().filter(|&len| len <= 32)?;
+
+        Some(Config {
+            address: Address::parse(address)?,
+            prefix_len,
+            gateway: match gateway {
+                Some(gateway) => Some(Address::parse(gateway)?),
+                None => None,
+            },
+        })
+    }
+
+    fn netmask(&self) -> u32 {
+        (!0u64 << (32 - self.prefix_len)) as u32
+    }
+
+    /// Whether `address` is on the local subnet
+    pub fn contains(&self, address: Address) -> bool {
+        address.to_u32() & self.netmask() == self.address.to_u32() & self.netmask()
+    }
+
+    pub fn broadcast(&self) -> Address {
+        Address((self.address.to_u32() | !self.netmask()).to_be_bytes())
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {gateway}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub source: Address,
+    pub destination: Address,
+    pub protocol: u8,
+    pub ttl: u8,
+}
+
+impl Header {
+    /// Splits `packet` into its header and payload, checking the checksum
+    pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+
+        let header_len = (packet[0] & 0xF) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < HEADER_SIZE || total_len < header_len || total_len > packet.len() {
+            return None;
+        }
+
+        if checksum(&[&packet[..header_len]]) != 0 {
+            return None;
+        }
+
+        // Fragments are dropped, nothing gets reassembled
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0 {
+            return None;
+        }
+
+        let header = Header {
+            ttl: packet[8],
+            protocol: packet[9],
+            source: Address(packet[12..16].try_into().unwrap()),
+            destination: Address(packet[16..20].try_into().unwrap()),
+        };
+
+        // Ethernet padding follows the packet on short frames
+        Some((header, &packet[header_len..total_len]))
+    }
+
+    /// Turns the payload in `buffer` into a packet
+    fn write(&self, buffer: &mut PacketBuffer) {
+        let total_len = (HEADER_SIZE + buffer.len()) as u16;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let header = buffer.push(HEADER_SIZE);
+
+        header[0..2].copy_from_slice(&[0x45, 0]);
+        header[2..4].copy_from_slice(&total_len.to_be_bytes());
+        header[4..6].copy_from_slice(&id.to_be_bytes());
+        header[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+        header[8..12].copy_from_slice(&[self.ttl, self.protocol, 0, 0]);
+        header[12..16].copy_from_slice(&self.source.0);
+        header[16..20].copy_from_slice(&self.destination.0);
+
+        let checksum = checksum(&[header]);
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// The internet checksum over the concatenation of `parts`
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd = None;
+
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        match odd.take() {
+            Some(high) => sum += u16::from_be_bytes([high, byte]) as u32,
+            None => odd = Some(byte),
+        }
+    }
+
+    if let Some(high) = odd {
+        sum += u16::from_be_bytes([high, 0]) as u32;
+    }
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// The pseudo header TCP and UDP include in their checksum
+pub fn pseudo_header(source: Address, destination: Address, protocol: u8, len: usize) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&source.0);
+    header[4..8].copy_from_slice(&destination.0);
+    header[9] = protocol;
+    header[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+
+    header
+}
+
+/// Hands every received packet of IP protocol `protocol` to `handler`
+pub fn register_protocol(protocol: u8, handler: Handler) {
+    let mut protocols = PROTOCOLS.lock();
+
+    protocols.retain(|&(p, _)| p != protocol);
+    protocols.push((protocol, handler));
+}
+
+pub fn receive(interface: &Arc<Interface>, _ethernet: &EthernetHeader, packet: &[u8]) {
+    let Some(config) = interface.ipv4() else {
+        return;
+    };
+
+    let Some((header, payload)) = Header::parse(packet) else {
+        return;
+    };
+
+    // The loopback carries traffic for all of 127/8 and for every other address of ours
+    let destination = header.destination;
+    let looped = interface.device().loopback()
+        && (config.contains(destination)
+            || super::interfaces()
+                .iter()
+                .any(|i| i.ipv4().is_some_and(|c| c.address == destination)));
+
+    if destination != config.address
+        && destination != config.broadcast()
+        && destination != Address::BROADCAST
+        && !looped
+    {
+        return;
+    }
+
+    let handler = PROTOCOLS
+        .lock()
+        .iter()
+        .find(|&&(protocol, _)| protocol == header.protocol)
+        .map(|&(_, handler)| handler);
+
+    if let Some(handler) = handler {
+        handler(interface, &header, payload);
+    }
+}
+
+/// Picks the interface and next hop for `destination`
+pub fn route(destination: Address) -> Option<(Arc<Interface>, Config, Address)> {
+    let interfaces = super::interfaces();
+    let configured = || {
+        interfaces
+            .iter()
+            .filter_map(|interface| Some((interface, interface.ipv4()?)))
+    };
+
+    // Our own addresses never leave the machine, and talk to themselves from that same address
+    if configured().any(|(_, c)| c.address == destination) {
+        if let Some((interface, config)) = configured().find(|(i, _)| i.device().loopback()) {
+            let config = Config {
+                address: destination,
+                ..config
+            };
+            return Some((interface.clone(), config, destination));
+        }
+    }
+
+    // The local subnet first, then whatever default gateway there is
+    if let Some((interface, config)) = configured().find(|(_, c)| c.contains(destination)) {
+        return Some((interface.clone(), config, destination));
+    }
+
+    configured().find_map(|(interface, config)| Some((interface.clone(), config, config.gateway?)))
+}
+
+/// Sends `payload` to `destination` as a packet of IP protocol `protocol`
+pub fn send(destination: Address, protocol: u8, payload: &[u8]) -> Result<(), Error> {
+    let (interface, config, next_hop) = route(destination).ok_or(Error::NoRoute)?;
+    send_on(
+        &interface,
+        config.address,
+        destination,
+        next_hop,
+        protocol,
+        payload,
+    )
+}
+
+/// Sends a reply back out of the interface the request came in on
+pub fn reply(
+    interface: &Arc<Interface>,
+    request: &Header,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let config = interface.ipv4().ok_or(Error::NoRoute)?;
+    let next_hop = match config.contains(request.source) {
+        true => request.source,
+        false => config.gateway.ok_or(Error::NoRoute)?,
+    };
+
+    send_on(
+        interface,
+        config.address,
+        request.source,
+        next_hop,
+        protocol,
+        payload,
+    )
+}
+
+fn send_on(
+    interface: &Arc<Interface>,
+    source: Address,
+    destination: Address,
+    next_hop: Address,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), Error> {
+    if HEADER_SIZE + payload.len() > interface.device().mtu() {
+        return Err(Error::TooLong);
+    }
+
+    let mut packet = PacketBuffer::from_slice(payload);
+    Header {
+        source,
+        destination,
+        protocol,
+        ttl: DEFAULT_TTL,
+    }
+    .write(&mut packet);
+
+    let broadcast = interface
+        .ipv4()
+        .is_some_and(|config| destination == config.broadcast());
+
+    if destination == Address::BROADCAST || broadcast {
+        return interface.send(MacAddress::BROADCAST, ethernet::ETHERTYPE_IPV4, packet);
+    }
+
+    if interface.device().loopback() {
+        return interface.send(interface.mac(), ethernet::ETHERTYPE_IPV4, packet);
+    }
+
+    arp::send(interface, next_hop, packet)
+}
+
+/// Gives the first interface the address passed with `ip=`, and `gateway=` if any
+pub fn configure_from_cmdline(interface: &Arc<Interface>) {
+    let Some(ip) = cmdline::value("ip") else {
+        return;
+    };
+
+    match Config::parse(ip, cmdline::value("gateway")) {
+        Some(config) => interface.set_ipv4(Some(config)),
+        None => log::warn!("net: bad address ip={ip}"),
+    }
+}
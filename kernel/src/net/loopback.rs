@@ -0,0 +1,77 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ipv4::{Address, Config};
+use super::{Error, MacAddress, NetDevice, PacketBuffer};
+use crate::sync::Mutex;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// Frames sent and not yet polled back, past this the new ones get dropped
+const QUEUE_LIMIT: usize = 1024;
+
+/// Hands every frame sent straight back to the stack
+struct Loopback {
+    queue: Mutex<VecDeque<PacketBuffer>>,
+}
+
+impl NetDevice for Loopback {
+    fn name(&self) -> &str {
+        "lo"
+    }
+
+    fn mac(&self) -> MacAddress {
+        MacAddress([0; 6])
+    }
+
+    fn link_up(&self) -> bool {
+        true
+    }
+
+    fn loopback(&self) -> bool {
+        true
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), Error> {
+        let mut queue = self.queue.lock();
+        if queue.len() >= QUEUE_LIMIT {
+            return Err(Error::Busy);
+        }
+
+        queue.push_back(PacketBuffer::from_slice(frame));
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<PacketBuffer> {
+        self.queue.lock().pop_front()
+    }
+}
+
+/// Brings up `lo` at 127.0.0.1/8
+pub fn init() {
+    let device = Arc::new(Loopback {
+        queue: Mutex::new(VecDeque::new()),
+    });
+    super::register(device);
+
+    let interface = super::interface("lo").unwrap();
+    interface.set_ipv4(Some(Config {
+        address: Address([127, 0, 0, 1]),
+        prefix_len: 8,
+        gateway: None,
+    }));
+}
@@ -0,0 +1,208 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::sync::Mutex;
+use crate::{cpu, time};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub mod arp;
+pub mod buffer;
+pub mod ethernet;
+pub mod icmp;
+pub mod interface;
+pub mod ipv4;
+pub mod loopback;
+pub mod netconsole;
+pub mod tcp;
+pub mod tftp;
+pub mod udp;
+
+pub use buffer::PacketBuffer;
+pub use interface::Interface;
+
+/// Largest payload of an ethernet frame
+pub const MTU: usize = 1500;
+
+/// Largest ethernet frame, header included but without the FCS
+pub const MAX_FRAME: usize = MTU + 14;
+
+/// Frames taken off one interface per `poll`, so a busy one can't starve the rest
+const POLL_BUDGET: usize = 64;
+
+static DEVICES: Mutex<Vec<Arc<dyn NetDevice>>> = Mutex::new(Vec::new());
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+
+/// Set while some core is running `poll`
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+
+    /// Group addresses, broadcast included
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The frame doesn't fit in a single transmit buffer
+    TooLong,
+    /// Every transmit descriptor is still owned by the device
+    Busy,
+    LinkDown,
+    /// No interface can reach the destination
+    NoRoute,
+    /// Some other socket is bound to the port
+    AddressInUse,
+    ConnectionRefused,
+    ConnectionReset,
+    NotConnected,
+    TimedOut,
+}
+
+/// A network interface moving whole ethernet frames
+pub trait NetDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn mac(&self) -> MacAddress;
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn link_up(&self) -> bool;
+
+    /// Frames sent come right back, there is no link layer to resolve addresses on
+    fn loopback(&self) -> bool {
+        false
+    }
+
+    /// Queues `frame` for transmission, without the FCS
+    fn transmit(&self, frame: &[u8]) -> Result<(), Error>;
+
+    /// Returns the oldest frame received and not yet taken, if any
+    fn receive(&self) -> Option<PacketBuffer>;
+}
+
+pub fn register(device: Arc<dyn NetDevice>) {
+    log::info!(
+        "{}: {}, link {}",
+        device.name(),
+        device.mac(),
+        if device.link_up() { "up" } else { "down" }
+    );
+
+    let interface = Arc::new(Interface::new(device.clone()));
+    let first = {
+        let mut interfaces = INTERFACES.lock();
+        interfaces.push(interface.clone());
+        interfaces.iter().filter(|i| !i.device().loopback()).count() == 1
+    };
+
+    if first && !device.loopback() {
+        ipv4::configure_from_cmdline(&interface);
+    }
+
+    DEVICES.lock().push(device);
+}
+
+pub fn unregister(name: &str) {
+    DEVICES.lock().retain(|device| device.name() != name);
+    INTERFACES
+        .lock()
+        .retain(|interface| interface.name() != name);
+}
+
+/// Microseconds since boot, for timeouts
+pub fn now_us() -> u64 {
+    unsafe { cpu::rdtsc() / time::tsc_per_us() }
+}
+
+/// Hooks the protocols into the stack and brings up the loopback, before any device shows up
+pub fn init() {
+    interface::register_protocol(ethernet::ETHERTYPE_ARP, arp::receive);
+    interface::register_protocol(ethernet::ETHERTYPE_IPV4, ipv4::receive);
+    ipv4::register_protocol(ipv4::PROTOCOL_ICMP, icmp::receive);
+    ipv4::register_protocol(ipv4::PROTOCOL_TCP, tcp::receive);
+    ipv4::register_protocol(ipv4::PROTOCOL_UDP, udp::receive);
+
+    loopback::init();
+    netconsole::init();
+}
+
+initcall!(net, init, []);
+
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+pub fn interface(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES.lock().iter().find(|i| i.name() == name).cloned()
+}
+
+/// Runs the stack: takes received frames off every interface and hands them to their protocol
+///
+/// Returns false without doing anything if another core is already at it
+pub fn poll() -> bool {
+    if POLLING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+
+    for interface in interfaces() {
+        for _ in 0..POLL_BUDGET {
+            if !interface.receive() {
+                break;
+            }
+        }
+    }
+
+    tcp::update();
+    netconsole::flush();
+
+    POLLING.store(false, Ordering::Release);
+    true
+}
+
+/// Whether some core is running `poll` right now
+pub fn polling() -> bool {
+    POLLING.load(Ordering::Acquire)
+}
+
+/// Returns `prefix` followed by the first free number, e.g. `eth0`, `eth1`...
+pub fn next_name(prefix: &str) -> String {
+    let devices = DEVICES.lock();
+
+    (0..)
+        .map(|n| alloc::format!("{prefix}{n}"))
+        .find(|name| !devices.iter().any(|d| d.name() == name))
+        .unwrap()
+}
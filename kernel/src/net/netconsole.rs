@@ -0,0 +1,136 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ipv4::{Address, Endpoint};
+use super::udp::UdpSocket;
+use crate::cmdline;
+use crate::logging::{self, Entry, LogSink};
+use crate::sync::Mutex;
+use alloc::collections::VecDeque;
+use core::fmt::{self, Write};
+use log::LevelFilter;
+
+/// Log text waiting to go out, the oldest goes first when the network can't keep up
+const PENDING_LIMIT: usize = 32 * 1024;
+
+/// Lines are packed into datagrams up to this size
+const DATAGRAM_SIZE: usize = 1024;
+
+static TARGET: Mutex<Option<(UdpSocket, Endpoint)>> = Mutex::new(None);
+static PENDING: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+struct Pending<'a>(&'a mut VecDeque<u8>);
+
+impl Write for Pending<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.extend(s.as_bytes());
+
+        let excess = self.0.len().saturating_sub(PENDING_LIMIT);
+        self.0.drain(..excess);
+
+        Ok(())
+    }
+}
+
+/// Queues log lines, they go out with the next `flush`
+struct NetconsoleSink;
+
+impl LogSink for NetconsoleSink {
+    fn name(&self) -> &str {
+        "netconsole"
+    }
+
+    fn log(&self, entry: &Entry) {
+        // Whatever this core interrupted owns the queue, the line is dropped then
+        if let Some(mut pending) = PENDING.try_lock() {
+            let _ = write!(Pending(&mut pending), "{}", entry.plain());
+        }
+    }
+}
+
+/// Sends the queued log text, as far as the network lets it
+pub fn flush() {
+    let Some(target) = TARGET.try_lock() else {
+        return;
+    };
+    let Some((socket, destination)) = target.as_ref() else {
+        return;
+    };
+
+    loop {
+        // Take a datagram worth of whole lines, so sending can log without deadlocking
+        let datagram: alloc::vec::Vec<u8> = {
+            let Some(mut pending) = PENDING.try_lock() else {
+                return;
+            };
+
+            let len = pending.len().min(DATAGRAM_SIZE);
+            let len = match pending.range(..len).rposition(|&b| b == b'\n') {
+                Some(newline) if len < pending.len() => newline + 1,
+                _ => len,
+            };
+
+            pending.drain(..len).collect()
+        };
+
+        if datagram.is_empty() {
+            return;
+        }
+
+        // No route yet or a full transmit ring, try again on the next poll
+        if socket.send_to(&datagram, *destination).is_err() {
+            if let Some(mut pending) = PENDING.try_lock() {
+                for &byte in datagram.iter().rev() {
+                    pending.push_front(byte);
+                }
+            }
+            return;
+        }
+    }
+}
+
+/// Parses `address:port`
+fn parse(text: &str) -> Option<Endpoint> {
+    let (address, port) = text.split_once(':')?;
+    Some((Address::parse(address)?, port.parse().ok()?))
+}
+
+/// Starts streaming the log to `netconsole=<address>:<port>`, beginning with what was logged so far
+pub fn init() {
+    let Some(value) = cmdline::value("netconsole") else {
+        return;
+    };
+
+    let Some(destination) = parse(value) else {
+        log::warn!("netconsole: bad target {value}, expected <address>:<port>");
+        return;
+    };
+
+    let socket = match UdpSocket::bind(None, 0) {
+        Ok(socket) => socket,
+        Err(err) => {
+            log::warn!("netconsole: no socket: {err:?}");
+            return;
+        }
+    };
+
+    PENDING.lock().extend(logging::history());
+    *TARGET.lock() = Some((socket, destination));
+    logging::register(&NetconsoleSink, LevelFilter::Trace);
+
+    log::info!("netconsole: logging to {}:{}", destination.0, destination.1);
+}
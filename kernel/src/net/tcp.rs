@@ -0,0 +1,945 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ipv4::{self, Endpoint, Header, PROTOCOL_TCP};
+use super::{Error, Interface};
+use crate::random;
+use crate::sync::Mutex;
+use crate::utils::WaitQueue;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+const HEADER_SIZE: usize = 20;
+
+/// Control bits
+const FIN: u8 = 1 << 0;
+const SYN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+const PSH: u8 = 1 << 3;
+const ACK: u8 = 1 << 4;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// What the peer can take when it doesn't say otherwise
+const DEFAULT_MSS: usize = 536;
+
+const SEND_BUFFER: usize = 64 * 1024;
+/// Small enough to advertise without window scaling
+const RECEIVE_BUFFER: usize = 32 * 1024;
+
+const INITIAL_RTO_US: u64 = 1_000_000;
+const MAX_RTO_US: u64 = 60_000_000;
+/// Retransmissions of the same data before the connection is given up on
+const MAX_RETRIES: u32 = 8;
+
+/// Far shorter than the 2 MSL of the RFC, nothing here lives that long anyway
+const TIME_WAIT_US: u64 = 10_000_000;
+
+/// Connections a listener holds before new ones get refused
+const BACKLOG: usize = 16;
+
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+static CONNECTIONS: Mutex<BTreeMap<(Endpoint, Endpoint), Arc<Connection>>> =
+    Mutex::new(BTreeMap::new());
+static LISTENERS: Mutex<BTreeMap<u16, Weak<Listener>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// Whether sequence number `a` comes before `b`
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+struct Segment<'a> {
+    source_port: u16,
+    destination_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(header: &Header, data: &'a [u8]) -> Option<Segment<'a>> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let pseudo =
+            ipv4::pseudo_header(header.source, header.destination, PROTOCOL_TCP, data.len());
+        if ipv4::checksum(&[&pseudo, data]) != 0 {
+            return None;
+        }
+
+        let header_len = (data[12] >> 4) as usize * 4;
+        if header_len < HEADER_SIZE || header_len > data.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &data[HEADER_SIZE..header_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+
+                    if kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some(Segment {
+            source_port: u16::from_be_bytes([data[0], data[1]]),
+            destination_port: u16::from_be_bytes([data[2], data[3]]),
+            seq: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            ack: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+            flags: data[13],
+            window: u16::from_be_bytes([data[14], data[15]]),
+            mss,
+            payload: &data[header_len..],
+        })
+    }
+
+    /// Sequence space taken, SYN and FIN count as one each
+    fn len(&self) -> u32 {
+        self.payload.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn transmit(
+    local: Endpoint,
+    remote: Endpoint,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let header_len = HEADER_SIZE + if mss.is_some() { 4 } else { 0 };
+    let mut segment = Vec::with_capacity(header_len + payload.len());
+
+    segment.extend_from_slice(&local.1.to_be_bytes());
+    segment.extend_from_slice(&remote.1.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[((header_len / 4) as u8) << 4, flags]);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0; 4]);
+
+    if let Some(mss) = mss {
+        segment.extend_from_slice(&[OPTION_MSS, 4]);
+        segment.extend_from_slice(&mss.to_be_bytes());
+    }
+
+    segment.extend_from_slice(payload);
+
+    let pseudo = ipv4::pseudo_header(local.0, remote.0, PROTOCOL_TCP, segment.len());
+    let checksum = ipv4::checksum(&[&pseudo, &segment]);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    ipv4::send(remote.0, PROTOCOL_TCP, &segment)
+}
+
+/// Answers a segment that belongs to no connection
+fn reset(local: Endpoint, remote: Endpoint, segment: &Segment) {
+    if segment.flags & RST != 0 {
+        return;
+    }
+
+    let _ = match segment.flags & ACK != 0 {
+        true => transmit(local, remote, segment.ack, 0, RST, 0, None, &[]),
+        false => transmit(
+            local,
+            remote,
+            0,
+            segment.seq.wrapping_add(segment.len()),
+            RST | ACK,
+            0,
+            None,
+            &[],
+        ),
+    };
+}
+
+/// Transmission control block, everything about one connection
+struct Tcb {
+    state: State,
+    /// Why the connection ended, reported by the next call on it
+    error: Option<Error>,
+    local: Endpoint,
+    remote: Endpoint,
+
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    /// Largest segment the peer takes
+    mss: usize,
+    /// Bytes from `snd_una` on, sent or not
+    send: VecDeque<u8>,
+    /// The user is done writing, a FIN follows the data
+    closing: bool,
+    fin_sent: bool,
+
+    rcv_nxt: u32,
+    receive: VecDeque<u8>,
+    /// Window in the last segment sent, to know when an update is worth it
+    advertised: usize,
+
+    rto: u64,
+    retransmit_at: Option<u64>,
+    retries: u32,
+    time_wait_until: u64,
+
+    /// Who gets the connection once it's established, for passive opens
+    listener: Option<Weak<Listener>>,
+}
+
+impl Tcb {
+    fn new(state: State, local: Endpoint, remote: Endpoint) -> Tcb {
+        let iss = random::u64() as u32;
+
+        Tcb {
+            state,
+            error: None,
+            local,
+            remote,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            send: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            rcv_nxt: 0,
+            receive: VecDeque::new(),
+            advertised: 0,
+            rto: INITIAL_RTO_US,
+            retransmit_at: None,
+            retries: 0,
+            time_wait_until: 0,
+            listener: None,
+        }
+    }
+
+    fn window(&self) -> usize {
+        RECEIVE_BUFFER - self.receive.len()
+    }
+
+    /// What fits in the MTU of the interface the peer is reached through
+    fn our_mss(&self) -> u16 {
+        ipv4::route(self.remote.0).map_or(DEFAULT_MSS, |(interface, ..)| {
+            interface.device().mtu() - ipv4::HEADER_SIZE - HEADER_SIZE
+        }) as u16
+    }
+
+    fn segment(&mut self, seq: u32, flags: u8, payload: &[u8]) {
+        let (ack, flags) = match self.state {
+            State::SynSent => (0, flags),
+            _ => (self.rcv_nxt, flags | ACK),
+        };
+
+        let mss = (flags & SYN != 0).then(|| self.our_mss());
+        self.advertised = self.window();
+
+        let window = self.advertised as u16;
+        let _ = transmit(
+            self.local,
+            self.remote,
+            seq,
+            ack,
+            flags,
+            window,
+            mss,
+            payload,
+        );
+    }
+
+    fn ack_now(&mut self) {
+        self.segment(self.snd_nxt, 0, &[]);
+    }
+
+    fn send_syn(&mut self) {
+        self.segment(self.iss, SYN, &[]);
+    }
+
+    fn arm(&mut self, now: u64) {
+        self.retransmit_at.get_or_insert(now + self.rto);
+    }
+
+    /// Sends whatever data and FIN the peer's window allows
+    fn output(&mut self, now: u64) {
+        if !matches!(
+            self.state,
+            State::Established
+                | State::CloseWait
+                | State::FinWait1
+                | State::Closing
+                | State::LastAck
+        ) {
+            return;
+        }
+
+        while !self.fin_sent {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let unsent = self.send.len() - in_flight;
+            let window = (self.snd_wnd as usize).saturating_sub(in_flight);
+
+            if unsent > 0 && window > 0 {
+                let len = unsent.min(self.mss).min(window);
+                let payload: Vec<u8> = self
+                    .send
+                    .range(in_flight..in_flight + len)
+                    .copied()
+                    .collect();
+
+                self.segment(self.snd_nxt, PSH, &payload);
+                self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+                self.arm(now);
+            } else if unsent == 0 && self.closing {
+                self.segment(self.snd_nxt, FIN, &[]);
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                self.fin_sent = true;
+                self.arm(now);
+
+                self.state = match self.state {
+                    State::Established => State::FinWait1,
+                    State::CloseWait => State::LastAck,
+                    state => state,
+                };
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn close(&mut self, error: Option<Error>) {
+        self.state = State::Closed;
+        self.error = self.error.or(error);
+        self.retransmit_at = None;
+    }
+
+    /// Runs the timers, due when `now` passed them
+    fn timers(&mut self, now: u64) {
+        if self.state == State::TimeWait && now >= self.time_wait_until {
+            self.close(None);
+            return;
+        }
+
+        if self.retransmit_at.is_none_or(|at| now < at) {
+            return;
+        }
+
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.close(Some(Error::TimedOut));
+            return;
+        }
+
+        self.rto = (self.rto * 2).min(MAX_RTO_US);
+        self.retransmit_at = Some(now + self.rto);
+
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(),
+            _ => {
+                // Go back to the oldest unacknowledged byte and send everything again
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+
+                // A zero window gets probed with a byte at a time
+                self.snd_wnd = self.snd_wnd.max(1);
+                self.output(now);
+            }
+        }
+    }
+
+    fn syn_sent(&mut self, segment: &Segment, now: u64) {
+        let acceptable = segment.ack == self.iss.wrapping_add(1);
+
+        if segment.flags & ACK != 0 && !acceptable {
+            reset(self.local, self.remote, segment);
+            return;
+        }
+
+        if segment.flags & RST != 0 {
+            if acceptable {
+                self.close(Some(Error::ConnectionRefused));
+            }
+            return;
+        }
+
+        if segment.flags & SYN == 0 {
+            return;
+        }
+
+        self.rcv_nxt = segment.seq.wrapping_add(1);
+        self.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+        self.snd_wnd = segment.window as u32;
+
+        if segment.flags & ACK != 0 {
+            self.state = State::Established;
+            self.snd_una = segment.ack;
+            self.retransmit_at = None;
+            self.retries = 0;
+            self.ack_now();
+            self.output(now);
+        } else {
+            // Both ends opened at once
+            self.state = State::SynReceived;
+            self.send_syn();
+        }
+    }
+
+    /// Processes a segment of a synchronized connection, returns the listener to hand it to
+    /// if it just got established
+    fn synchronized(&mut self, segment: &Segment, now: u64) -> Option<Arc<Listener>> {
+        // Only segments starting where we expect them are taken, the rest is acked and dropped
+        let offset = self.rcv_nxt.wrapping_sub(segment.seq);
+        let in_order = segment.seq == self.rcv_nxt
+            || (before(self.rcv_nxt, segment.seq.wrapping_add(segment.len()))
+                && !before(self.rcv_nxt, segment.seq));
+
+        if segment.flags & RST != 0 {
+            if in_order {
+                self.close(Some(Error::ConnectionReset));
+            }
+            return None;
+        }
+
+        if !in_order {
+            if segment.len() > 0 || before(segment.seq, self.rcv_nxt) {
+                self.ack_now();
+            }
+            return None;
+        }
+
+        if segment.flags & SYN != 0 && before(self.rcv_nxt.wrapping_sub(1), segment.seq) {
+            reset(self.local, self.remote, segment);
+            self.close(Some(Error::ConnectionReset));
+            return None;
+        }
+
+        if segment.flags & ACK == 0 {
+            return None;
+        }
+
+        let mut established = None;
+
+        if self.state == State::SynReceived {
+            if segment.ack != self.iss.wrapping_add(1) {
+                reset(self.local, self.remote, segment);
+                return None;
+            }
+
+            self.state = State::Established;
+            self.snd_una = segment.ack;
+            self.retransmit_at = None;
+            self.retries = 0;
+            established = self.listener.take().and_then(|l| l.upgrade());
+        }
+
+        if before(self.snd_una, segment.ack) && !before(self.snd_nxt, segment.ack) {
+            let mut acked = segment.ack.wrapping_sub(self.snd_una) as usize;
+            let fin_acked = self.fin_sent && segment.ack == self.snd_nxt;
+            if fin_acked {
+                acked -= 1;
+            }
+
+            self.send.drain(..acked.min(self.send.len()));
+            self.snd_una = segment.ack;
+            self.rto = INITIAL_RTO_US;
+            self.retries = 0;
+            self.retransmit_at = (self.snd_una != self.snd_nxt).then_some(now + self.rto);
+
+            if fin_acked {
+                match self.state {
+                    State::FinWait1 => self.state = State::FinWait2,
+                    State::Closing => {
+                        self.state = State::TimeWait;
+                        self.time_wait_until = now + TIME_WAIT_US;
+                    }
+                    State::LastAck => {
+                        self.close(None);
+                        return established;
+                    }
+                    _ => {}
+                }
+            }
+        } else if before(self.snd_nxt, segment.ack) {
+            // Acknowledges something never sent
+            self.ack_now();
+            return established;
+        }
+
+        self.snd_wnd = segment.window as u32;
+
+        let mut need_ack = false;
+        let payload = segment.payload.get(offset as usize..).unwrap_or(&[]);
+        let mut complete = true;
+
+        if !payload.is_empty()
+            && matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            )
+        {
+            let accepted = payload.len().min(self.window());
+            self.receive.extend(&payload[..accepted]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+
+            complete = accepted == payload.len();
+            need_ack = true;
+        }
+
+        // The FIN only counts once everything before it made it in
+        let fin = segment.seq.wrapping_add(segment.len()).wrapping_sub(1);
+        if segment.flags & FIN != 0 && complete && fin == self.rcv_nxt {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            need_ack = true;
+
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => {
+                    self.state = State::TimeWait;
+                    self.time_wait_until = now + TIME_WAIT_US;
+                }
+                _ => {}
+            }
+        }
+
+        if need_ack {
+            self.ack_now();
+        }
+
+        self.output(now);
+        established
+    }
+}
+
+struct Connection {
+    tcb: Mutex<Tcb>,
+    /// Woken on every change of state or buffers
+    changed: WaitQueue,
+}
+
+impl Connection {
+    /// Waits until `done` holds, driving the stack meanwhile
+    fn wait(&self, mut done: impl FnMut(&Tcb) -> bool) {
+        loop {
+            if done(&self.tcb.lock()) {
+                return;
+            }
+
+            // Whoever waits runs the stack, unless another core already is
+            if !super::poll() {
+                self.changed
+                    .wait_until(|| done(&self.tcb.lock()) || !super::polling());
+            }
+        }
+    }
+}
+
+struct Listener {
+    port: u16,
+    /// Established connections nobody accepted yet
+    backlog: Mutex<VecDeque<Arc<Connection>>>,
+    arrived: WaitQueue,
+}
+
+/// Picks a local port no connection or listener uses
+fn ephemeral_port(connections: &BTreeMap<(Endpoint, Endpoint), Arc<Connection>>) -> Option<u16> {
+    let listeners = LISTENERS.lock();
+
+    EPHEMERAL_PORTS.clone().find(|&port| {
+        !listeners.contains_key(&port) && !connections.keys().any(|(local, _)| local.1 == port)
+    })
+}
+
+/// A connected TCP socket, closed gracefully when dropped
+pub struct TcpStream {
+    connection: Arc<Connection>,
+}
+
+impl TcpStream {
+    /// Opens a connection to `remote`, waiting until it's established
+    pub fn connect(remote: Endpoint) -> Result<TcpStream, Error> {
+        let (_, config, _) = ipv4::route(remote.0).ok_or(Error::NoRoute)?;
+
+        let connection = {
+            let mut connections = CONNECTIONS.lock();
+            let port = ephemeral_port(&connections).ok_or(Error::AddressInUse)?;
+            let local = (config.address, port);
+
+            let mut tcb = Tcb::new(State::SynSent, local, remote);
+            tcb.send_syn();
+            tcb.arm(super::now_us());
+
+            let connection = Arc::new(Connection {
+                tcb: Mutex::new(tcb),
+                changed: WaitQueue::new(),
+            });
+            connections.insert((local, remote), connection.clone());
+
+            connection
+        };
+
+        connection.wait(|tcb| tcb.state != State::SynSent);
+
+        let tcb = connection.tcb.lock();
+        match tcb.state {
+            State::Closed => Err(tcb.error.unwrap_or(Error::ConnectionRefused)),
+            _ => {
+                drop(tcb);
+                Ok(TcpStream { connection })
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> Endpoint {
+        self.connection.tcb.lock().local
+    }
+
+    pub fn peer_addr(&self) -> Endpoint {
+        self.connection.tcb.lock().remote
+    }
+
+    pub fn state(&self) -> State {
+        self.connection.tcb.lock().state
+    }
+
+    /// Reads what arrived, waiting for something if nothing did; 0 means the peer is done
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.connection.wait(|tcb| {
+            !tcb.receive.is_empty()
+                || !matches!(
+                    tcb.state,
+                    State::SynReceived | State::Established | State::FinWait1 | State::FinWait2
+                )
+        });
+
+        let mut tcb = self.connection.tcb.lock();
+        if tcb.receive.is_empty() {
+            return match tcb.error {
+                Some(error) => Err(error),
+                None => Ok(0),
+            };
+        }
+
+        let len = buffer.len().min(tcb.receive.len());
+        for (byte, received) in buffer.iter_mut().zip(tcb.receive.drain(..len)) {
+            *byte = received;
+        }
+
+        // Tell the peer the window opened again, if it was about to stall
+        if tcb.advertised < tcb.mss && tcb.window() >= tcb.mss {
+            tcb.ack_now();
+        }
+
+        Ok(len)
+    }
+
+    /// Queues as much of `data` as fits, waiting for room if there is none
+    pub fn write(&self, data: &[u8]) -> Result<usize, Error> {
+        self.connection.wait(|tcb| {
+            tcb.send.len() < SEND_BUFFER
+                || !matches!(tcb.state, State::Established | State::CloseWait)
+        });
+
+        let mut tcb = self.connection.tcb.lock();
+        if !matches!(tcb.state, State::Established | State::CloseWait) || tcb.closing {
+            return Err(tcb.error.unwrap_or(Error::NotConnected));
+        }
+
+        let len = data.len().min(SEND_BUFFER - tcb.send.len());
+        tcb.send.extend(&data[..len]);
+        tcb.output(super::now_us());
+
+        Ok(len)
+    }
+
+    pub fn write_all(&self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            let written = self.write(data)?;
+            data = &data[written..];
+        }
+
+        Ok(())
+    }
+
+    /// Sends a FIN once everything written went out, reading still works until the peer closes
+    pub fn close(&self) {
+        let mut tcb = self.connection.tcb.lock();
+
+        match tcb.state {
+            State::SynSent => tcb.close(None),
+            State::SynReceived | State::Established | State::CloseWait => {
+                tcb.closing = true;
+                tcb.output(super::now_us());
+            }
+            _ => {}
+        }
+
+        drop(tcb);
+        self.connection.changed.wake_all();
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A socket waiting for connections on a port, stops listening when dropped
+pub struct TcpListener {
+    listener: Arc<Listener>,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, Error> {
+        let mut listeners = LISTENERS.lock();
+        listeners.retain(|_, listener| listener.strong_count() > 0);
+
+        if listeners.contains_key(&port) {
+            return Err(Error::AddressInUse);
+        }
+
+        let listener = Arc::new(Listener {
+            port,
+            backlog: Mutex::new(VecDeque::new()),
+            arrived: WaitQueue::new(),
+        });
+        listeners.insert(port, Arc::downgrade(&listener));
+
+        Ok(TcpListener { listener })
+    }
+
+    pub fn try_accept(&self) -> Option<TcpStream> {
+        let connection = self.listener.backlog.lock().pop_front()?;
+        Some(TcpStream { connection })
+    }
+
+    /// Waits for a connection and returns it
+    pub fn accept(&self) -> TcpStream {
+        loop {
+            if let Some(stream) = self.try_accept() {
+                return stream;
+            }
+
+            if !super::poll() {
+                self.listener
+                    .arrived
+                    .wait_until(|| !self.listener.backlog.lock().is_empty() || !super::polling());
+            }
+        }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        LISTENERS.lock().remove(&self.listener.port);
+    }
+}
+
+/// Starts a passive open for a SYN that hit a listener
+fn listen(listener: Arc<Listener>, local: Endpoint, remote: Endpoint, segment: &Segment) {
+    let pending = CONNECTIONS
+        .lock()
+        .values()
+        .filter(|c| c.tcb.lock().state == State::SynReceived)
+        .count();
+
+    if listener.backlog.lock().len() + pending >= BACKLOG {
+        reset(local, remote, segment);
+        return;
+    }
+
+    let mut tcb = Tcb::new(State::SynReceived, local, remote);
+    tcb.rcv_nxt = segment.seq.wrapping_add(1);
+    tcb.snd_wnd = segment.window as u32;
+    tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+    tcb.listener = Some(Arc::downgrade(&listener));
+    tcb.send_syn();
+    tcb.arm(super::now_us());
+
+    let connection = Arc::new(Connection {
+        tcb: Mutex::new(tcb),
+        changed: WaitQueue::new(),
+    });
+    CONNECTIONS.lock().insert((local, remote), connection);
+}
+
+pub fn receive(_interface: &Arc<Interface>, header: &Header, data: &[u8]) {
+    let Some(segment) = Segment::parse(header, data) else {
+        return;
+    };
+
+    let local = (header.destination, segment.destination_port);
+    let remote = (header.source, segment.source_port);
+    let now = super::now_us();
+
+    let connection = CONNECTIONS.lock().get(&(local, remote)).cloned();
+    let Some(connection) = connection else {
+        let listener = LISTENERS
+            .lock()
+            .get(&local.1)
+            .and_then(|listener| listener.upgrade());
+
+        match listener {
+            Some(listener) if segment.flags & (SYN | ACK | RST) == SYN => {
+                listen(listener, local, remote, &segment)
+            }
+            _ => reset(local, remote, &segment),
+        }
+
+        return;
+    };
+
+    let mut tcb = connection.tcb.lock();
+    let established = match tcb.state {
+        State::SynSent => {
+            tcb.syn_sent(&segment, now);
+            None
+        }
+        State::Closed => None,
+        _ => tcb.synchronized(&segment, now),
+    };
+    drop(tcb);
+
+    if let Some(listener) = established {
+        listener.backlog.lock().push_back(connection.clone());
+        listener.arrived.wake_all();
+    }
+
+    connection.changed.wake_all();
+}
+
+/// Runs retransmissions and timeouts, and forgets connections that are over
+pub fn update() {
+    let now = super::now_us();
+    let connections: Vec<Arc<Connection>> = CONNECTIONS.lock().values().cloned().collect();
+
+    for connection in &connections {
+        let mut tcb = connection.tcb.lock();
+        let state = tcb.state;
+        tcb.timers(now);
+
+        if tcb.state != state {
+            drop(tcb);
+            connection.changed.wake_all();
+        }
+    }
+
+    CONNECTIONS
+        .lock()
+        .retain(|_, connection| connection.tcb.lock().state != State::Closed);
+}
+
+ktest! {
+    fn loopback_connection_moves_data_and_closes() {
+        let listener = TcpListener::bind(40000).unwrap();
+        let client = TcpStream::connect((ipv4::Address([127, 0, 0, 1]), 40000)).unwrap();
+        let server = listener.accept();
+        assert_eq!(client.state(), State::Established);
+        assert_eq!(client.peer_addr(), (ipv4::Address([127, 0, 0, 1]), 40000));
+        assert_eq!(server.local_addr(), client.peer_addr());
+        assert_eq!(server.peer_addr(), client.local_addr());
+
+        let mut buffer = [0; 16];
+        client.write_all(b"ping").unwrap();
+        assert_eq!(server.read(&mut buffer), Ok(4));
+        assert_eq!(&buffer[..4], b"ping");
+
+        server.write_all(b"pong").unwrap();
+        assert_eq!(client.read(&mut buffer), Ok(4));
+        assert_eq!(&buffer[..4], b"pong");
+
+        // The FIN reads as the end of the stream, on both sides
+        client.close();
+        assert_eq!(server.read(&mut buffer), Ok(0));
+        assert_eq!(server.state(), State::CloseWait);
+
+        server.close();
+        assert_eq!(client.read(&mut buffer), Ok(0));
+        assert_eq!(client.state(), State::TimeWait);
+    }
+
+    fn connecting_to_a_closed_port_is_refused() {
+        let result = TcpStream::connect((ipv4::Address([127, 0, 0, 1]), 40001));
+        assert_eq!(result.err(), Some(Error::ConnectionRefused));
+    }
+
+    fn unacknowledged_data_is_retransmitted_until_timeout() {
+        // Nobody listens on the other end and the stack never sees this TCB, so nothing gets acked
+        let local = (ipv4::Address([127, 0, 0, 1]), 40002);
+        let remote = (ipv4::Address([127, 0, 0, 1]), 40003);
+        let mut tcb = Tcb::new(State::Established, local, remote);
+        tcb.snd_una = tcb.iss.wrapping_add(1);
+        tcb.snd_wnd = RECEIVE_BUFFER as u32;
+        tcb.send.extend(b"lost");
+
+        tcb.output(0);
+        let sent = tcb.iss.wrapping_add(5);
+        assert_eq!(tcb.snd_nxt, sent);
+        assert_eq!(tcb.retransmit_at, Some(INITIAL_RTO_US));
+
+        tcb.timers(INITIAL_RTO_US);
+        assert_eq!(tcb.retries, 1);
+        assert_eq!(tcb.rto, 2 * INITIAL_RTO_US);
+        assert_eq!(tcb.snd_nxt, sent);
+        assert_eq!(tcb.snd_una, tcb.iss.wrapping_add(1));
+
+        while let Some(at) = tcb.retransmit_at {
+            tcb.timers(at);
+        }
+
+        assert_eq!(tcb.state, State::Closed);
+        assert_eq!(tcb.error, Some(Error::TimedOut));
+        assert_eq!(tcb.retries, MAX_RETRIES + 1);
+    }
+}
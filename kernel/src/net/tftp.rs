@@ -0,0 +1,185 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ipv4::Endpoint;
+use super::udp::UdpSocket;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const PORT: u16 = 69;
+
+const OPCODE_RRQ: u16 = 1;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+const OPCODE_OACK: u16 = 6;
+
+/// What servers that ignore the blksize option send
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Asked for with the blksize option, an ethernet MTU minus the IPv4, UDP and TFTP headers
+const BLOCK_SIZE: usize = 1468;
+
+const TIMEOUT_US: u64 = 1_000_000;
+/// Times the last packet is sent again before giving up
+const RETRIES: u32 = 5;
+
+#[derive(Debug)]
+pub enum Error {
+    Net(super::Error),
+    /// The server sent an error packet, with its code and message
+    Server(u16, String),
+    /// The server stopped answering
+    TimedOut,
+    /// The server sent something that makes no sense at this point
+    Protocol,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Net(err) => write!(f, "{err:?}"),
+            Error::Server(code, message) => write!(f, "server error {code}: {message}"),
+            Error::TimedOut => write!(f, "timed out"),
+            Error::Protocol => write!(f, "protocol violation"),
+        }
+    }
+}
+
+impl From<super::Error> for Error {
+    fn from(err: super::Error) -> Error {
+        Error::Net(err)
+    }
+}
+
+fn request(filename: &str) -> Vec<u8> {
+    let mut packet = Vec::new();
+
+    packet.extend_from_slice(&OPCODE_RRQ.to_be_bytes());
+    for field in [
+        filename,
+        "octet",
+        "blksize",
+        &alloc::format!("{BLOCK_SIZE}"),
+    ] {
+        packet.extend_from_slice(field.as_bytes());
+        packet.push(0);
+    }
+
+    packet
+}
+
+fn ack(block: u16) -> [u8; 4] {
+    let mut packet = [0; 4];
+    packet[0..2].copy_from_slice(&OPCODE_ACK.to_be_bytes());
+    packet[2..4].copy_from_slice(&block.to_be_bytes());
+    packet
+}
+
+/// Reads the options of an OACK, only the block size matters
+fn negotiated_block_size(options: &[u8]) -> Option<usize> {
+    let mut fields = options.split(|&b| b == 0);
+
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        if name.eq_ignore_ascii_case(b"blksize") {
+            return core::str::from_utf8(value).ok()?.parse().ok();
+        }
+    }
+
+    None
+}
+
+/// Downloads `filename` from the TFTP server at `server`
+pub fn fetch(server: Endpoint, filename: &str) -> Result<Vec<u8>, Error> {
+    let socket = UdpSocket::bind(None, 0)?;
+
+    let mut last_sent = request(filename);
+    let mut destination = server;
+    // The server answers from a port of its own, which it sticks to for the transfer
+    let mut peer = None;
+
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut expected: u16 = 1;
+    let mut file = Vec::new();
+    let mut buffer = vec![0; 4 + BLOCK_SIZE];
+
+    socket.send_to(&last_sent, destination)?;
+    let mut retries = 0;
+
+    loop {
+        let Some((len, source)) = socket.recv_from_timeout(&mut buffer, TIMEOUT_US) else {
+            retries += 1;
+            if retries > RETRIES {
+                return Err(Error::TimedOut);
+            }
+
+            socket.send_to(&last_sent, destination)?;
+            continue;
+        };
+
+        if source.0 != server.0 || peer.is_some_and(|peer| peer != source) || len < 4 {
+            continue;
+        }
+
+        if peer.is_none() {
+            peer = Some(source);
+            destination = source;
+        }
+
+        let packet = &buffer[..len];
+        let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+        let number = u16::from_be_bytes([packet[2], packet[3]]);
+
+        match opcode {
+            OPCODE_ERROR => {
+                let message = packet[4..].split(|&b| b == 0).next().unwrap_or(&[]);
+                let message = String::from_utf8_lossy(message).into_owned();
+                return Err(Error::Server(number, message));
+            }
+            // Only as the first answer, when the server took our options
+            OPCODE_OACK if expected == 1 && file.is_empty() => {
+                block_size = negotiated_block_size(&packet[2..]).ok_or(Error::Protocol)?;
+                if block_size > BLOCK_SIZE {
+                    return Err(Error::Protocol);
+                }
+
+                last_sent = ack(0).to_vec();
+            }
+            OPCODE_DATA if number == expected => {
+                let data = &packet[4..];
+                file.extend_from_slice(data);
+                last_sent = ack(number).to_vec();
+
+                // A short block ends the transfer, the final ACK isn't retried
+                if data.len() < block_size {
+                    socket.send_to(&last_sent, destination)?;
+                    return Ok(file);
+                }
+
+                expected = expected.wrapping_add(1);
+            }
+            // A duplicate of the block before, our ACK got lost
+            OPCODE_DATA if number == expected.wrapping_sub(1) => {}
+            _ => return Err(Error::Protocol),
+        }
+
+        socket.send_to(&last_sent, destination)?;
+        retries = 0;
+    }
+}
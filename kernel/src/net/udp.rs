@@ -0,0 +1,232 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::ipv4::{self, Address, Endpoint, Header, PROTOCOL_UDP};
+use super::{Error, Interface};
+use crate::sync::Mutex;
+use crate::utils::WaitQueue;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+pub const HEADER_SIZE: usize = 8;
+
+/// Datagrams a socket holds before new ones get dropped
+const RECEIVE_LIMIT: usize = 64;
+
+/// Ports handed out to sockets bound to port 0
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+static SOCKETS: Mutex<BTreeMap<u16, Weak<Inner>>> = Mutex::new(BTreeMap::new());
+
+pub struct Datagram {
+    pub source: Endpoint,
+    pub data: Vec<u8>,
+}
+
+struct Inner {
+    port: u16,
+    /// Only datagrams to this address get in, any address if `None`
+    address: Option<Address>,
+    received: Mutex<VecDeque<Datagram>>,
+    arrived: WaitQueue,
+}
+
+/// A UDP socket, unbound when dropped
+pub struct UdpSocket {
+    inner: Arc<Inner>,
+}
+
+impl UdpSocket {
+    /// Binds `port` on `address`, or on every address if `None`; port 0 picks a free one
+    pub fn bind(address: Option<Address>, port: u16) -> Result<UdpSocket, Error> {
+        let mut sockets = SOCKETS.lock();
+        sockets.retain(|_, socket| socket.strong_count() > 0);
+
+        let port = match port {
+            0 => EPHEMERAL_PORTS
+                .clone()
+                .find(|port| !sockets.contains_key(port))
+                .ok_or(Error::AddressInUse)?,
+            port if sockets.contains_key(&port) => return Err(Error::AddressInUse),
+            port => port,
+        };
+
+        let inner = Arc::new(Inner {
+            port,
+            address,
+            received: Mutex::new(VecDeque::new()),
+            arrived: WaitQueue::new(),
+        });
+        sockets.insert(port, Arc::downgrade(&inner));
+
+        Ok(UdpSocket { inner })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.inner.port
+    }
+
+    /// Sends `data` as one datagram to `destination`
+    pub fn send_to(&self, data: &[u8], destination: Endpoint) -> Result<usize, Error> {
+        let (address, port) = destination;
+        let (_, config, _) = ipv4::route(address).ok_or(Error::NoRoute)?;
+        let source = self.inner.address.unwrap_or(config.address);
+
+        let len = HEADER_SIZE + data.len();
+        let mut datagram = Vec::with_capacity(len);
+        datagram.extend_from_slice(&self.inner.port.to_be_bytes());
+        datagram.extend_from_slice(&port.to_be_bytes());
+        datagram.extend_from_slice(&(len as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+
+        let pseudo = ipv4::pseudo_header(source, address, PROTOCOL_UDP, len);
+        let checksum = match ipv4::checksum(&[&pseudo, &datagram]) {
+            // Zero means no checksum, all ones is the same value in one's complement
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+        ipv4::send(address, PROTOCOL_UDP, &datagram)?;
+        Ok(data.len())
+    }
+
+    /// Takes the oldest datagram received, if any
+    pub fn try_recv_from(&self, buffer: &mut [u8]) -> Option<(usize, Endpoint)> {
+        let datagram = self.inner.received.lock().pop_front()?;
+
+        // Whatever doesn't fit is lost, like with any datagram socket
+        let len = buffer.len().min(datagram.data.len());
+        buffer[..len].copy_from_slice(&datagram.data[..len]);
+
+        Some((len, datagram.source))
+    }
+
+    /// Waits for a datagram, returns how much of it was copied and who sent it
+    pub fn recv_from(&self, buffer: &mut [u8]) -> (usize, Endpoint) {
+        loop {
+            if let Some(received) = self.try_recv_from(buffer) {
+                return received;
+            }
+
+            // Whoever waits runs the stack, unless another core already is
+            if !super::poll() {
+                self.inner
+                    .arrived
+                    .wait_until(|| !self.inner.received.lock().is_empty() || !super::polling());
+            }
+        }
+    }
+
+    /// Like `recv_from`, but gives up after `timeout_us` microseconds
+    pub fn recv_from_timeout(
+        &self,
+        buffer: &mut [u8],
+        timeout_us: u64,
+    ) -> Option<(usize, Endpoint)> {
+        let deadline = super::now_us() + timeout_us;
+
+        loop {
+            if let Some(received) = self.try_recv_from(buffer) {
+                return Some(received);
+            }
+
+            if super::now_us() >= deadline {
+                return None;
+            }
+
+            // Nothing wakes a waiter when time runs out, so spin while another core polls
+            if !super::poll() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.inner.port);
+    }
+}
+
+pub fn receive(_interface: &Arc<Interface>, header: &Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+
+    let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let destination_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    let checksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+
+    let datagram = &datagram[..len];
+    let pseudo = ipv4::pseudo_header(header.source, header.destination, PROTOCOL_UDP, len);
+    if checksum != 0 && ipv4::checksum(&[&pseudo, datagram]) != 0 {
+        return;
+    }
+
+    let Some(socket) = SOCKETS
+        .lock()
+        .get(&destination_port)
+        .and_then(|socket| socket.upgrade())
+    else {
+        return;
+    };
+
+    if socket
+        .address
+        .is_some_and(|address| address != header.destination)
+    {
+        return;
+    }
+
+    let mut received = socket.received.lock();
+    if received.len() < RECEIVE_LIMIT {
+        received.push_back(Datagram {
+            source: (header.source, source_port),
+            data: datagram[HEADER_SIZE..].to_vec(),
+        });
+    }
+    drop(received);
+
+    socket.arrived.wake_all();
+}
+
+ktest! {
+    fn loopback_datagrams_reach_the_bound_port() {
+        let loopback = Address([127, 0, 0, 1]);
+        let receiver = UdpSocket::bind(None, 0).unwrap();
+        let sender = UdpSocket::bind(Some(loopback), 0).unwrap();
+        assert_ne!(receiver.local_port(), sender.local_port());
+
+        sender
+            .send_to(b"hello", (loopback, receiver.local_port()))
+            .unwrap();
+
+        let mut buffer = [0; 16];
+        let (len, source) = receiver.recv_from(&mut buffer);
+        assert_eq!(&buffer[..len], b"hello");
+        assert_eq!(source, (loopback, sender.local_port()));
+        assert!(sender.try_recv_from(&mut buffer).is_none());
+    }
+}
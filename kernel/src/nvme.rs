@@ -0,0 +1,656 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::madt;
+use crate::apic;
+use crate::block::{self, BlockDevice, Error};
+use crate::driver::{self, Driver, Match, ProbeError};
+use crate::hpet;
+use crate::interrupts::{self, InterruptStack};
+use crate::mm::dma::Dma;
+use crate::mm::mmio::Mmio;
+use crate::mm::vmm::PAGE_SIZE;
+use crate::pci;
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Controller registers
+const CAP: usize = 0x00;
+const VS: usize = 0x08;
+const CC: usize = 0x14;
+const CSTS: usize = 0x1C;
+const AQA: usize = 0x24;
+const ASQ: usize = 0x28;
+const ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+/// 64 byte submission and 16 byte completion entries
+const CC_QUEUE_ENTRY_SIZES: u32 = 6 << 16 | 4 << 20;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+/// Admin commands
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+/// I/O commands
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const IDENTIFY_NAMESPACE: u32 = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 2;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// Create I/O queue flags: physically contiguous, interrupts enabled
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+
+const SQ_ENTRY_SIZE: usize = 64;
+const CQ_ENTRY_SIZE: usize = 16;
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 64;
+
+/// One I/O queue pair per processor, up to this many
+const MAX_IO_QUEUES: usize = 8;
+
+/// Largest transfer in a single command, unless the controller wants less
+const MAX_TRANSFER: usize = 128 * 1024;
+
+/// How long a command may take before the controller is considered hung
+const TIMEOUT_US: u64 = 5_000_000;
+
+static CONTROLLERS: Mutex<Vec<Arc<Controller>>> = Mutex::new(Vec::new());
+
+/// A distinct handler per I/O queue, so the vector alone tells which queue completed
+static QUEUE_HANDLERS: [fn(&mut InterruptStack); MAX_IO_QUEUES] = [
+    queue_interrupt::<0>,
+    queue_interrupt::<1>,
+    queue_interrupt::<2>,
+    queue_interrupt::<3>,
+    queue_interrupt::<4>,
+    queue_interrupt::<5>,
+    queue_interrupt::<6>,
+    queue_interrupt::<7>,
+];
+
+static DRIVER: Driver = Driver {
+    name: "nvme",
+    order: 20,
+    matches: &[Match::PciClass {
+        class: 0x01,
+        subclass: 0x08,
+        prog_if: Some(0x02),
+    }],
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+/// A submission and completion queue pair
+struct Queue {
+    id: u16,
+    size: u16,
+    sq: Dma,
+    cq: Dma,
+    sq_tail: u16,
+    cq_head: u16,
+    phase: bool,
+    next_command: u16,
+    /// PRP list of the command in flight, commands go one at a time per queue
+    prp_list: Dma,
+}
+
+struct IoQueue {
+    queue: Mutex<Queue>,
+    /// Set by the queue's MSI-X handler
+    interrupted: AtomicBool,
+    msix: bool,
+}
+
+struct Controller {
+    index: usize,
+    device: pci::Device,
+    mmio: Mmio,
+    /// The MSI-X table, when it doesn't share a BAR with the registers
+    msix_table: Option<Mmio>,
+    doorbell_stride: usize,
+    admin: Mutex<Queue>,
+    io: Vec<IoQueue>,
+    max_transfer: usize,
+    vectors: Vec<u8>,
+}
+
+struct Namespace {
+    name: String,
+    controller: Arc<Controller>,
+    id: u32,
+    sectors: u64,
+    sector_size: usize,
+}
+
+/// The 16 dwords of a submission queue entry
+#[derive(Clone, Copy, Default)]
+struct Command([u32; 16]);
+
+impl Command {
+    fn new(opcode: u8, namespace: u32) -> Command {
+        let mut command = Command::default();
+        command.0[0] = opcode as u32;
+        command.0[1] = namespace;
+        command
+    }
+
+    fn prp(mut self, prp1: u64, prp2: u64) -> Command {
+        self.0[6] = prp1 as u32;
+        self.0[7] = (prp1 >> 32) as u32;
+        self.0[8] = prp2 as u32;
+        self.0[9] = (prp2 >> 32) as u32;
+        self
+    }
+
+    /// Sets command dword `n`, from 10 to 15
+    fn dword(mut self, n: usize, value: u32) -> Command {
+        self.0[n] = value;
+        self
+    }
+}
+
+impl Queue {
+    fn new(id: u16, size: u16) -> Queue {
+        Queue {
+            id,
+            size,
+            sq: Dma::new(size as usize * SQ_ENTRY_SIZE),
+            cq: Dma::new(size as usize * CQ_ENTRY_SIZE),
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            next_command: 0,
+            prp_list: Dma::new(PAGE_SIZE as usize),
+        }
+    }
+}
+
+impl Controller {
+    fn doorbell(&self, queue: u16, completion: bool) -> usize {
+        DOORBELLS + (2 * queue as usize + completion as usize) * self.doorbell_stride
+    }
+
+    /// Submits `command` on `queue` and waits for it, returns dword 0 of the completion
+    fn submit(
+        &self,
+        queue: &mut Queue,
+        interrupted: Option<&AtomicBool>,
+        mut command: Command,
+    ) -> Result<u32, Error> {
+        let id = queue.next_command;
+        queue.next_command = queue.next_command.wrapping_add(1);
+        command.0[0] |= (id as u32) << 16;
+
+        let slot = queue.sq_tail as usize * SQ_ENTRY_SIZE;
+        for (i, dword) in command.0.iter().enumerate() {
+            queue.sq.write(slot + i * 4, *dword);
+        }
+
+        if let Some(interrupted) = interrupted {
+            interrupted.store(false, Ordering::Relaxed);
+        }
+
+        queue.sq_tail = (queue.sq_tail + 1) % queue.size;
+        self.mmio
+            .write(self.doorbell(queue.id, false), queue.sq_tail as u32);
+
+        let entry = queue.cq_head as usize * CQ_ENTRY_SIZE;
+        let mut status = 0;
+
+        // The phase bit flips on every pass over the ring, a match means a new entry
+        let done = wait(|| {
+            if interrupted.is_some_and(|i| !i.load(Ordering::Acquire)) {
+                return false;
+            }
+
+            status = queue.cq.read::<u32>(entry + 12);
+            (status >> 16) & 1 == queue.phase as u32
+        });
+
+        if done.is_err() {
+            log::warn!("nvme{}: queue {} timed out", self.index, queue.id);
+            return Err(Error::Io);
+        }
+
+        let result = queue.cq.read::<u32>(entry);
+        queue.cq_head += 1;
+        if queue.cq_head == queue.size {
+            queue.cq_head = 0;
+            queue.phase = !queue.phase;
+        }
+
+        self.mmio
+            .write(self.doorbell(queue.id, true), queue.cq_head as u32);
+
+        match (status >> 17) & 0x7FF {
+            0 => Ok(result),
+            code => {
+                log::warn!("nvme{}: command failed with status {code:#x}", self.index);
+                Err(Error::Io)
+            }
+        }
+    }
+
+    fn admin(&self, command: Command) -> Result<u32, Error> {
+        self.submit(&mut self.admin.lock(), None, command)
+    }
+
+    fn identify(&self, cns: u32, namespace: u32) -> Result<Dma, Error> {
+        let data = Dma::new(PAGE_SIZE as usize);
+        self.admin(
+            Command::new(ADMIN_IDENTIFY, namespace)
+                .prp(data.phys().as_u64(), 0)
+                .dword(10, cns),
+        )?;
+
+        Ok(data)
+    }
+
+    /// Runs a read or write of `len` bytes of `buffer` on the calling core's queue
+    fn io(
+        &self,
+        opcode: u8,
+        namespace: u32,
+        lba: u64,
+        blocks: u32,
+        buffer: Option<(&Dma, usize)>,
+    ) -> Result<(), Error> {
+        let io = &self.io[core!().id % self.io.len()];
+        let mut queue = io.queue.lock();
+
+        let (prp1, prp2) = match buffer {
+            None => (0, 0),
+            Some((buffer, len)) => {
+                let base = buffer.phys().as_u64();
+                let pages = len.div_ceil(PAGE_SIZE as usize);
+
+                match pages {
+                    1 => (base, 0),
+                    2 => (base, base + PAGE_SIZE),
+                    _ => {
+                        // Every page after the first goes in the list
+                        for page in 1..pages {
+                            queue
+                                .prp_list
+                                .write((page - 1) * 8, base + page as u64 * PAGE_SIZE);
+                        }
+
+                        (base, queue.prp_list.phys().as_u64())
+                    }
+                }
+            }
+        };
+
+        let command = Command::new(opcode, namespace)
+            .prp(prp1, prp2)
+            .dword(10, lba as u32)
+            .dword(11, (lba >> 32) as u32)
+            .dword(12, blocks.saturating_sub(1));
+
+        let interrupted = io.msix.then_some(&io.interrupted);
+        self.submit(&mut queue, interrupted, command).map(|_| ())
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self, sector, buffer.len())?;
+        let max = self.controller.max_transfer;
+
+        for (i, chunk) in buffer.chunks_mut(max).enumerate() {
+            let bounce = Dma::new(chunk.len());
+            let lba = sector + (i * max / self.sector_size) as u64;
+            let blocks = (chunk.len() / self.sector_size) as u32;
+
+            self.controller
+                .io(IO_READ, self.id, lba, blocks, Some((&bounce, chunk.len())))?;
+            chunk.copy_from_slice(&bounce.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        block::check(self, sector, buffer.len())?;
+        let max = self.controller.max_transfer;
+
+        for (i, chunk) in buffer.chunks(max).enumerate() {
+            let mut bounce = Dma::new(chunk.len());
+            let lba = sector + (i * max / self.sector_size) as u64;
+            let blocks = (chunk.len() / self.sector_size) as u32;
+
+            bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.controller
+                .io(IO_WRITE, self.id, lba, blocks, Some((&bounce, chunk.len())))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.controller.io(IO_FLUSH, self.id, 0, 0, None)
+    }
+}
+
+/// Waits for `done`, giving up after `TIMEOUT_US`
+fn wait(mut done: impl FnMut() -> bool) -> Result<(), Error> {
+    for _ in 0..TIMEOUT_US / 10 {
+        if done() {
+            return Ok(());
+        }
+
+        hpet::sleep(10_000);
+    }
+
+    Err(Error::Io)
+}
+
+fn queue_interrupt<const QUEUE: usize>(_stack: &mut InterruptStack) {
+    if let Some(controllers) = CONTROLLERS.try_lock() {
+        for io in controllers.iter().filter_map(|c| c.io.get(QUEUE)) {
+            io.interrupted.store(true, Ordering::Release);
+        }
+    }
+
+    core!().apic.lock().eoi();
+}
+
+/// Turns the controller off and waits for it to acknowledge
+fn disable(mmio: &Mmio, timeout_us: u64) -> Result<(), Error> {
+    mmio.write(CC, mmio.read::<u32>(CC) & !CC_EN);
+
+    for _ in 0..timeout_us / 10 {
+        if mmio.read::<u32>(CSTS) & CSTS_RDY == 0 {
+            return Ok(());
+        }
+
+        hpet::sleep(10_000);
+    }
+
+    Err(Error::Io)
+}
+
+fn enable(mmio: &Mmio, timeout_us: u64) -> Result<(), Error> {
+    mmio.write(CC, CC_QUEUE_ENTRY_SIZES | CC_EN);
+
+    for _ in 0..timeout_us / 10 {
+        let status = mmio.read::<u32>(CSTS);
+        if status & CSTS_CFS != 0 {
+            return Err(Error::Io);
+        }
+
+        if status & CSTS_RDY != 0 {
+            return Ok(());
+        }
+
+        hpet::sleep(10_000);
+    }
+
+    Err(Error::Io)
+}
+
+/// Reads an ASCII identify field, padded with spaces
+fn identify_string(data: &Dma, range: core::ops::Range<usize>) -> String {
+    String::from_utf8_lossy(&data.as_slice()[range])
+        .trim()
+        .into()
+}
+
+/// Allocates a vector per I/O queue and points MSI-X entry `queue` at it
+fn setup_msix(
+    device: &pci::Device,
+    mmio: &Mmio,
+    table: &mut Option<Mmio>,
+    queues: usize,
+) -> Option<Vec<u8>> {
+    let msix = device.msix()?;
+    if msix.table_size() <= queues {
+        return None;
+    }
+
+    let (bar, _) = msix.table();
+    if bar != 0 {
+        *table = Some(device.map_bar(bar)?);
+    }
+
+    let table = table.as_ref().unwrap_or(mmio);
+    let cpus: Vec<u32> = madt::cpus()
+        .filter(|cpu| cpu.usable())
+        .map(|cpu| cpu.apic_id)
+        .collect();
+    let current = core!().apic.lock().id();
+
+    let mut vectors = Vec::new();
+    for (queue, handler) in QUEUE_HANDLERS.iter().enumerate().take(queues) {
+        let Some(vector) = interrupts::allocate_handler(*handler) else {
+            vectors.into_iter().for_each(interrupts::free_handler);
+            return None;
+        };
+
+        // Spread the queues over the processors
+        let apic_id = cpus.get(queue).copied().unwrap_or(current);
+        msix.set_entry(table, queue + 1, apic::msi_address(apic_id), vector as u32);
+        vectors.push(vector);
+    }
+
+    msix.enable(device);
+    Some(vectors)
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+    let mmio = pci
+        .map_bar(0)
+        .ok_or(ProbeError::Failed("cannot map BAR0"))?;
+    pci.enable_bus_mastering();
+
+    let capabilities = mmio.read::<u64>(CAP);
+    let max_entries = (capabilities & 0xFFFF) as u16 + 1;
+    let doorbell_stride = 4 << ((capabilities >> 32) & 0xF);
+    let timeout_us = ((capabilities >> 24) & 0xFF).max(1) * 500_000;
+    let version = mmio.read::<u32>(VS);
+
+    disable(&mmio, timeout_us).map_err(|_| ProbeError::Failed("controller doesn't stop"))?;
+
+    let admin = Queue::new(0, ADMIN_QUEUE_SIZE.min(max_entries));
+    mmio.write(AQA, (admin.size as u32 - 1) << 16 | (admin.size as u32 - 1));
+    mmio.write(ASQ, admin.sq.phys().as_u64());
+    mmio.write(ACQ, admin.cq.phys().as_u64());
+
+    enable(&mmio, timeout_us).map_err(|_| ProbeError::Failed("controller doesn't start"))?;
+
+    let mut controller = Controller {
+        index: CONTROLLERS.lock().len(),
+        device: *pci,
+        mmio,
+        msix_table: None,
+        doorbell_stride,
+        admin: Mutex::new(admin),
+        io: Vec::new(),
+        max_transfer: MAX_TRANSFER,
+        vectors: Vec::new(),
+    };
+
+    let identify = controller
+        .identify(IDENTIFY_CONTROLLER, 0)
+        .map_err(|_| ProbeError::Failed("identify failed"))?;
+
+    // MDTS is a power of two of the minimum page size, 0 meaning no limit
+    let mdts = identify.read::<u8>(77);
+    if mdts != 0 {
+        let limit = (PAGE_SIZE as usize) << mdts;
+        controller.max_transfer = controller.max_transfer.min(limit);
+    }
+
+    log::info!(
+        "nvme{}: {} ({}), NVMe {}.{}",
+        controller.index,
+        identify_string(&identify, 24..64),
+        identify_string(&identify, 4..24),
+        version >> 16,
+        (version >> 8) & 0xFF
+    );
+
+    let cpus = madt::cpus().filter(|cpu| cpu.usable()).count();
+    let wanted = cpus.clamp(1, MAX_IO_QUEUES) as u32;
+    let granted = controller
+        .admin(
+            Command::new(ADMIN_SET_FEATURES, 0)
+                .dword(10, FEATURE_NUMBER_OF_QUEUES)
+                .dword(11, (wanted - 1) << 16 | (wanted - 1)),
+        )
+        .map_err(|_| ProbeError::Failed("cannot allocate I/O queues"))?;
+    let queues = wanted.min((granted & 0xFFFF) + 1).min((granted >> 16) + 1) as usize;
+
+    let mut msix_table = None;
+    let vectors = setup_msix(pci, &controller.mmio, &mut msix_table, queues);
+    if vectors.is_none() {
+        log::warn!("nvme{}: no MSI-X, completions are polled", controller.index);
+    }
+
+    controller.msix_table = msix_table;
+    controller.vectors = vectors.clone().unwrap_or_default();
+
+    for i in 0..queues {
+        let id = i as u16 + 1;
+        let queue = Queue::new(id, IO_QUEUE_SIZE.min(max_entries));
+        let size = queue.size as u32 - 1;
+
+        let (interrupts, vector) = match vectors {
+            Some(_) => (QUEUE_INTERRUPTS, id as u32),
+            None => (0, 0),
+        };
+
+        controller
+            .admin(
+                Command::new(ADMIN_CREATE_CQ, 0)
+                    .prp(queue.cq.phys().as_u64(), 0)
+                    .dword(10, size << 16 | id as u32)
+                    .dword(11, vector << 16 | interrupts | QUEUE_CONTIGUOUS),
+            )
+            .and_then(|_| {
+                controller.admin(
+                    Command::new(ADMIN_CREATE_SQ, 0)
+                        .prp(queue.sq.phys().as_u64(), 0)
+                        .dword(10, size << 16 | id as u32)
+                        .dword(11, (id as u32) << 16 | QUEUE_CONTIGUOUS),
+                )
+            })
+            .map_err(|_| ProbeError::Failed("cannot create I/O queues"))?;
+
+        controller.io.push(IoQueue {
+            queue: Mutex::new(queue),
+            interrupted: AtomicBool::new(false),
+            msix: vectors.is_some(),
+        });
+    }
+
+    let active = controller
+        .identify(IDENTIFY_ACTIVE_NAMESPACES, 0)
+        .map_err(|_| ProbeError::Failed("cannot list namespaces"))?;
+    let namespaces: Vec<u32> = (0..PAGE_SIZE as usize / 4)
+        .map(|i| active.read::<u32>(i * 4))
+        .take_while(|&id| id != 0)
+        .collect();
+
+    let controller = Arc::new(controller);
+    CONTROLLERS.lock().push(controller.clone());
+
+    for id in namespaces {
+        let Ok(info) = controller.identify(IDENTIFY_NAMESPACE, id) else {
+            continue;
+        };
+
+        // The formatted LBA size picks one of the LBA formats, which gives its size as a power of two
+        let format = (info.read::<u8>(26) & 0xF) as usize;
+        let lba_shift = (info.read::<u32>(128 + format * 4) >> 16) & 0xFF;
+
+        block::register(Arc::new(Namespace {
+            name: alloc::format!("nvme{}n{id}", controller.index),
+            controller: controller.clone(),
+            id,
+            sectors: info.read(0),
+            sector_size: 1 << lba_shift,
+        }));
+    }
+
+    log::info!("nvme{}: {queues} I/O queues", controller.index);
+    Ok(())
+}
+
+fn remove(device: &driver::Device) {
+    let Some(pci) = device.as_pci() else {
+        return;
+    };
+
+    let mut controllers = CONTROLLERS.lock();
+    let Some(i) = controllers
+        .iter()
+        .position(|c| c.device.address == pci.address)
+    else {
+        return;
+    };
+
+    let controller = controllers.remove(i);
+    drop(controllers);
+
+    let prefix = alloc::format!("nvme{}n", controller.index);
+    for device in block::devices() {
+        if device.name().starts_with(&prefix) {
+            let _ = device.flush();
+            block::unregister(device.name());
+        }
+    }
+
+    let _ = disable(&controller.mmio, TIMEOUT_US);
+    if let Some(msix) = controller.device.msix() {
+        msix.disable(&controller.device);
+    }
+
+    for &vector in &controller.vectors {
+        interrupts::free_handler(vector);
+    }
+}
@@ -0,0 +1,44 @@
+global oops_call
+global oops_resume
+
+; u64 oops_call(void (*f)(void *), void *data, u64 *saved_rsp)
+;
+; Calls f(data) and returns 0. If f oopses, oops_resume unwinds back here and it returns 1 instead.
+oops_call:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    ; The flags go last, the oops might come from a handler that cleared IF
+    pushfq
+    mov [rdx], rsp
+
+    mov rax, rdi
+    mov rdi, rsi
+    call rax
+
+    xor eax, eax
+    add rsp, 8
+    jmp .restore
+
+.resume:
+    mov eax, 1
+    popfq
+
+.restore:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+; noreturn oops_resume(u64 saved_rsp)
+;
+; Throws away everything above the oops_call that saved `saved_rsp`.
+oops_resume:
+    mov rsp, rdi
+    jmp oops_call.resume
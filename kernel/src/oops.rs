@@ -0,0 +1,119 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::core_locals::MAX_CORES;
+use crate::sync::{lockdep, Lazy, PerCpu};
+use crate::{backtrace, cmdline, interrupts};
+use core::panic::PanicInfo;
+use core::ptr::{self, addr_of_mut};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+extern "C" {
+    fn oops_call(f: extern "C" fn(*mut u8), data: *mut u8, saved_rsp: *mut u64) -> u64;
+    fn oops_resume(saved_rsp: u64) -> !;
+}
+
+/// A recoverable context, on the stack of whoever runs it
+struct Context {
+    name: &'static str,
+    /// Where `oops_call` left its registers
+    saved_rsp: u64,
+    /// The context this one is nested in
+    previous: *mut Context,
+}
+
+/// The innermost recoverable context of every core
+static CONTEXTS: PerCpu<AtomicPtr<Context>> =
+    PerCpu::new([const { AtomicPtr::new(ptr::null_mut()) }; MAX_CORES]);
+static OOPSES: AtomicUsize = AtomicUsize::new(0);
+static PANIC_ON_OOPS: Lazy<bool> = Lazy::new(|| cmdline::flag("panic_on_oops"));
+
+/// What `recoverable` returns when the code it ran faulted or panicked
+#[derive(Debug)]
+pub struct Oops;
+
+/// Runs `f`, turning a fault or panic in it into an `Oops` instead of taking the machine down
+///
+/// Nothing `f` left behind gets cleaned up, any lock it held stays locked, so this is only for
+/// code whose state isn't shared with what keeps running, like a shell command or a driver probe
+pub fn recoverable<R>(name: &'static str, f: impl FnOnce() -> R) -> Result<R, Oops> {
+    extern "C" fn call<F: FnMut()>(data: *mut u8) {
+        unsafe { (*(data as *mut F))() }
+    }
+
+    fn run<F: FnMut()>(f: &mut F, saved_rsp: *mut u64) -> u64 {
+        unsafe { oops_call(call::<F>, f as *mut F as *mut u8, saved_rsp) }
+    }
+
+    let held = lockdep::held();
+    let slot = CONTEXTS.get();
+    let mut context = Context {
+        name,
+        saved_rsp: 0,
+        previous: slot.load(Ordering::Relaxed),
+    };
+    let context = addr_of_mut!(context);
+    slot.store(context, Ordering::SeqCst);
+
+    let mut f = Some(f);
+    let mut result = None;
+    let oopsed = run(&mut || result = f.take().map(|f| f()), unsafe {
+        addr_of_mut!((*context).saved_rsp)
+    });
+
+    slot.store(unsafe { (*context).previous }, Ordering::SeqCst);
+    match oopsed {
+        0 => result.ok_or(Oops),
+        _ => {
+            lockdep::forget(held);
+            Err(Oops)
+        }
+    }
+}
+
+/// Called first thing on panic, if the core was in a recoverable context this logs what happened
+/// and goes back there, otherwise it returns and the panic goes on
+///
+/// `panic_on_oops` makes every oops a panic
+pub fn recover(info: &PanicInfo) {
+    // Taken out so a panic while reporting this one is a real panic
+    let context = CONTEXTS.get().swap(ptr::null_mut(), Ordering::SeqCst);
+    if context.is_null() || *PANIC_ON_OOPS {
+        return;
+    }
+    let context = unsafe { &*context };
+
+    let exception = interrupts::take_exception();
+    let count = OOPSES.fetch_add(1, Ordering::Relaxed) + 1;
+
+    log::error!("OOPS #{count} in {}: {info}", context.name);
+    if let Some(exception) = exception {
+        log::error!("Registers at exception:\n{}", exception.stack);
+    }
+    backtrace::backtrace(exception.map(|exception| (&exception.stack).into()));
+    log::error!("{}: killed, carrying on", context.name);
+
+    unsafe { oops_resume(context.saved_rsp) }
+}
+
+ktest! {
+    fn recoverable_catches_panics() {
+        assert_eq!(recoverable("ktest", || 42).unwrap(), 42);
+        assert!(recoverable("ktest", || panic!("expected")).is_err());
+        assert!(recoverable("ktest", || recoverable("nested", || panic!("expected"))).is_ok());
+    }
+}
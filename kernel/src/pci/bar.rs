@@ -0,0 +1,141 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Device, COMMAND};
+use crate::mm::mmio::{self, Mmio};
+use crate::mm::PhysAddr;
+
+/// First BAR register, there are 6 of them on a regular function and 2 on a bridge
+const BAR0: u16 = 0x10;
+
+/// Command register bits enabling I/O and memory decoding
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+impl Device {
+    fn bar_count(&self) -> u8 {
+        if self.is_bridge() {
+            2
+        } else {
+            6
+        }
+    }
+
+    /// Writes all ones to a BAR and returns what sticks, restoring the original value after
+    fn probe_bar(&self, offset: u16) -> u32 {
+        let original = self.read32(offset);
+
+        self.write32(offset, !0);
+        let mask = self.read32(offset);
+        self.write32(offset, original);
+
+        mask
+    }
+
+    /// Decodes BAR `n`, `None` if it's unimplemented or the upper half of a 64 bit BAR
+    pub fn bar(&self, n: u8) -> Option<Bar> {
+        if n >= self.bar_count() {
+            return None;
+        }
+
+        let offset = BAR0 + n as u16 * 4;
+        if n > 0 && self.read32(offset - 4) & (BAR_IO | 0b11 << 1) == BAR_TYPE_64 {
+            return None;
+        }
+
+        let low = self.read32(offset);
+
+        // Sizing a BAR while it's decoding could make it claim random addresses
+        let command = self.read16(COMMAND);
+        self.write16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+        let bar = if low & BAR_IO != 0 {
+            let mask = self.probe_bar(offset) & !0b11;
+            let size = (!mask).wrapping_add(1) & 0xFFFF;
+
+            (size != 0).then_some(Bar::Io {
+                port: (low & !0b11) as u16,
+                size,
+            })
+        } else {
+            let is_64bit = low & (0b11 << 1) == BAR_TYPE_64;
+            let mut address = (low & !0xF) as u64;
+            let mut mask = (self.probe_bar(offset) & !0xF) as u64;
+
+            if is_64bit && n + 1 < self.bar_count() {
+                address |= (self.read32(offset + 4) as u64) << 32;
+                mask |= (self.probe_bar(offset + 4) as u64) << 32;
+            } else {
+                mask |= 0xFFFF_FFFF_0000_0000;
+            }
+
+            let size = (!mask).wrapping_add(1);
+
+            (mask & 0xFFFF_FFF0 != 0).then_some(Bar::Memory {
+                address,
+                size,
+                prefetchable: low & BAR_PREFETCHABLE != 0,
+                is_64bit,
+            })
+        };
+
+        self.write16(COMMAND, command);
+        bar
+    }
+
+    /// Maps memory BAR `n` uncached and turns on memory decoding
+    pub fn map_bar(&self, n: u8) -> Option<Mmio> {
+        let Some(Bar::Memory { address, size, .. }) = self.bar(n) else {
+            log::warn!("{}: BAR{n} is not a memory BAR", self.address);
+            return None;
+        };
+
+        if address == 0 {
+            log::warn!("{}: BAR{n} was never assigned an address", self.address);
+            return None;
+        }
+
+        let mmio = mmio::map(PhysAddr::new(address), size as usize)?;
+        self.write16(COMMAND, self.read16(COMMAND) | COMMAND_MEMORY);
+
+        Some(mmio)
+    }
+
+    /// Lets the function do DMA
+    pub fn enable_bus_mastering(&self) {
+        self.write16(COMMAND, self.read16(COMMAND) | COMMAND_BUS_MASTER);
+    }
+}
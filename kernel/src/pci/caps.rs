@@ -0,0 +1,303 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{config, Device, STATUS};
+use crate::mm::mmio::Mmio;
+
+/// Status register bit telling there's a capability list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const CAPABILITIES_POINTER: u16 = 0x34;
+
+/// Where the extended capabilities start, only reachable through ECAM
+const EXTENDED_CAPABILITIES: u16 = 0x100;
+
+/// Capability IDs
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+/// Upper bound on list entries, so a broken device can't loop us forever
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Capability {
+    Msi(Msi),
+    MsiX(MsiX),
+    PciExpress(PciExpress),
+    Other { id: u8, offset: u16 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Msi {
+    offset: u16,
+    control: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MsiX {
+    offset: u16,
+    control: u16,
+    table: u32,
+    pba: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PciExpress {
+    capabilities: u16,
+}
+
+impl Msi {
+    const CONTROL: u16 = 2;
+    const ADDRESS: u16 = 4;
+    const ENABLE: u16 = 1 << 0;
+    const ADDRESS_64: u16 = 1 << 7;
+    const PER_VECTOR_MASKING: u16 = 1 << 8;
+
+    pub fn is_64bit(&self) -> bool {
+        self.control & Self::ADDRESS_64 != 0
+    }
+
+    pub fn maskable(&self) -> bool {
+        self.control & Self::PER_VECTOR_MASKING != 0
+    }
+
+    /// How many vectors the function can ask for
+    pub fn vectors(&self) -> usize {
+        1 << ((self.control >> 1) & 0b111)
+    }
+
+    /// Programs a single message and enables MSI
+    pub fn enable(&self, device: &Device, address: u64, data: u16) {
+        let data_offset = if self.is_64bit() {
+            device.write32(self.offset + Self::ADDRESS + 4, (address >> 32) as u32);
+            self.offset + 12
+        } else {
+            self.offset + 8
+        };
+
+        device.write32(self.offset + Self::ADDRESS, address as u32);
+        device.write16(data_offset, data);
+
+        // One message only, i.e. multiple message enable left at zero
+        let control = device.read16(self.offset + Self::CONTROL) & !(0b111 << 4);
+        device.write16(self.offset + Self::CONTROL, control | Self::ENABLE);
+    }
+
+    pub fn disable(&self, device: &Device) {
+        let control = device.read16(self.offset + Self::CONTROL);
+        device.write16(self.offset + Self::CONTROL, control & !Self::ENABLE);
+    }
+}
+
+impl MsiX {
+    const CONTROL: u16 = 2;
+    const ENABLE: u16 = 1 << 15;
+    const FUNCTION_MASK: u16 = 1 << 14;
+    const ENTRY_SIZE: usize = 16;
+
+    pub fn table_size(&self) -> usize {
+        (self.control & 0x7FF) as usize + 1
+    }
+
+    /// BAR index and offset of the vector table
+    pub fn table(&self) -> (u8, u32) {
+        ((self.table & 0b111) as u8, self.table & !0b111)
+    }
+
+    /// BAR index and offset of the pending bit array
+    pub fn pending_bits(&self) -> (u8, u32) {
+        ((self.pba & 0b111) as u8, self.pba & !0b111)
+    }
+
+    /// Programs and unmasks entry `index` of the vector table, `table` being its mapped BAR
+    pub fn set_entry(&self, table: &Mmio, index: usize, address: u64, data: u32) {
+        let entry = self.table().1 as usize + index * Self::ENTRY_SIZE;
+
+        table.write(entry, address as u32);
+        table.write(entry + 4, (address >> 32) as u32);
+        table.write(entry + 8, data);
+        table.write(entry + 12, 0u32);
+    }
+
+    pub fn enable(&self, device: &Device) {
+        let control = device.read16(self.offset + Self::CONTROL) & !Self::FUNCTION_MASK;
+        device.write16(self.offset + Self::CONTROL, control | Self::ENABLE);
+    }
+
+    pub fn disable(&self, device: &Device) {
+        let control = device.read16(self.offset + Self::CONTROL);
+        device.write16(self.offset + Self::CONTROL, control & !Self::ENABLE);
+    }
+}
+
+impl PciExpress {
+    /// Device/port type, e.g. 0 for an endpoint or 4 for a root port
+    pub fn device_type(&self) -> u8 {
+        ((self.capabilities >> 4) & 0xF) as u8
+    }
+
+    pub fn version(&self) -> u8 {
+        (self.capabilities & 0xF) as u8
+    }
+}
+
+impl Capability {
+    fn parse(device: &Device, id: u8, offset: u16) -> Capability {
+        match id {
+            CAP_MSI => Capability::Msi(Msi {
+                offset,
+                control: device.read16(offset + 2),
+            }),
+            CAP_MSIX => Capability::MsiX(MsiX {
+                offset,
+                control: device.read16(offset + 2),
+                table: device.read32(offset + 4),
+                pba: device.read32(offset + 8),
+            }),
+            CAP_PCI_EXPRESS => Capability::PciExpress(PciExpress {
+                capabilities: device.read16(offset + 2),
+            }),
+            id => Capability::Other { id, offset },
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Capability::Msi(_) => CAP_MSI,
+            Capability::MsiX(_) => CAP_MSIX,
+            Capability::PciExpress(_) => CAP_PCI_EXPRESS,
+            Capability::Other { id, .. } => *id,
+        }
+    }
+}
+
+/// Iterator over the capability list of a function
+pub struct Capabilities<'a> {
+    device: &'a Device,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities<'_> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // The bottom two bits are reserved
+        let offset = self.next & !0b11;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+
+        let header = self.device.read16(offset);
+        self.next = header >> 8;
+        self.remaining -= 1;
+
+        Some(Capability::parse(self.device, header as u8, offset))
+    }
+}
+
+/// Iterator over the extended capability list of a function
+pub struct ExtendedCapabilities<'a> {
+    device: &'a Device,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for ExtendedCapabilities<'_> {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<ExtendedCapability> {
+        let offset = self.next & !0b11;
+        if offset < EXTENDED_CAPABILITIES || self.remaining == 0 {
+            return None;
+        }
+
+        let header = self.device.read32(offset);
+        if header == 0 || header == !0 {
+            return None;
+        }
+
+        self.next = (header >> 20) as u16;
+        self.remaining -= 1;
+
+        Some(ExtendedCapability {
+            id: header as u16,
+            version: ((header >> 16) & 0xF) as u8,
+            offset,
+        })
+    }
+}
+
+impl Device {
+    pub fn capabilities(&self) -> Capabilities<'_> {
+        let next = match self.read16(STATUS) & STATUS_CAPABILITIES {
+            0 => 0,
+            _ => self.read8(CAPABILITIES_POINTER) as u16,
+        };
+
+        Capabilities {
+            device: self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Extended capabilities, empty if the function isn't reachable through ECAM
+    pub fn extended_capabilities(&self) -> ExtendedCapabilities<'_> {
+        let next = if config::has_extended_space(self.address) && self.pcie().is_some() {
+            EXTENDED_CAPABILITIES
+        } else {
+            0
+        };
+
+        ExtendedCapabilities {
+            device: self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        self.capabilities().find_map(|c| match c {
+            Capability::Msi(msi) => Some(msi),
+            _ => None,
+        })
+    }
+
+    pub fn msix(&self) -> Option<MsiX> {
+        self.capabilities().find_map(|c| match c {
+            Capability::MsiX(msix) => Some(msix),
+            _ => None,
+        })
+    }
+
+    pub fn pcie(&self) -> Option<PciExpress> {
+        self.capabilities().find_map(|c| match c {
+            Capability::PciExpress(pcie) => Some(pcie),
+            _ => None,
+        })
+    }
+}
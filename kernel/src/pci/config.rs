@@ -0,0 +1,123 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::Address;
+use crate::acpi::mcfg;
+use crate::cpu;
+use crate::mm::VirtAddr;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// ECAM regions past this aren't covered by the HHDM
+const HHDM_END: u64 = 4 << 30;
+
+/// Size of the configuration space of a function through ECAM
+pub const ECAM_SIZE: u16 = 4096;
+
+/// Size of the configuration space of a function through port IO
+pub const LEGACY_SIZE: u16 = 256;
+
+/// Returns the ECAM window of `address`, if the MCFG covers it
+fn ecam(address: Address) -> Option<VirtAddr> {
+    let phys = mcfg::config_address(
+        address.segment,
+        address.bus,
+        address.device,
+        address.function,
+    )?;
+
+    (phys.as_u64() + ECAM_SIZE as u64 <= HHDM_END).then(|| phys.as_hhdm())
+}
+
+/// Whether the configuration space past the first 256 bytes is reachable
+pub fn has_extended_space(address: Address) -> bool {
+    ecam(address).is_some()
+}
+
+fn legacy_address(address: Address, offset: u16) -> u32 {
+    (1 << 31)
+        | ((address.bus as u32) << 16)
+        | ((address.device as u32) << 11)
+        | ((address.function as u32) << 8)
+        | (offset as u32 & 0xFC)
+}
+
+/// Reads the dword at `offset` (rounded down to 4 bytes), all ones if it isn't reachable
+pub fn read32(address: Address, offset: u16) -> u32 {
+    let offset = offset & !3;
+
+    if let Some(base) = ecam(address) {
+        return unsafe {
+            core::ptr::read_volatile(base.as_ptr::<u8>().add(offset as usize).cast())
+        };
+    }
+
+    if address.segment != 0 || offset >= LEGACY_SIZE {
+        log::warn!("{address} offset {offset:#x} is not reachable through port IO");
+        return !0;
+    }
+
+    unsafe {
+        cpu::outl(CONFIG_ADDRESS, legacy_address(address, offset));
+        cpu::inl(CONFIG_DATA)
+    }
+}
+
+pub fn write32(address: Address, offset: u16, value: u32) {
+    let offset = offset & !3;
+
+    if let Some(base) = ecam(address) {
+        unsafe {
+            core::ptr::write_volatile(base.as_mut_ptr::<u8>().add(offset as usize).cast(), value)
+        };
+        return;
+    }
+
+    if address.segment != 0 || offset >= LEGACY_SIZE {
+        log::warn!("{address} offset {offset:#x} is not reachable through port IO");
+        return;
+    }
+
+    unsafe {
+        cpu::outl(CONFIG_ADDRESS, legacy_address(address, offset));
+        cpu::outl(CONFIG_DATA, value);
+    }
+}
+
+pub fn read16(address: Address, offset: u16) -> u16 {
+    (read32(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read8(address: Address, offset: u16) -> u8 {
+    (read32(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+/// Read-modify-writes the bits in `mask` of the dword holding `offset`
+fn write_masked(address: Address, offset: u16, value: u32, mask: u32) {
+    let shift = (offset & 3) * 8;
+    let old = read32(address, offset) & !(mask << shift);
+    write32(address, offset, old | ((value & mask) << shift));
+}
+
+pub fn write16(address: Address, offset: u16, value: u16) {
+    write_masked(address, offset, value as u32, 0xFFFF)
+}
+
+pub fn write8(address: Address, offset: u16, value: u8) {
+    write_masked(address, offset, value as u32, 0xFF)
+}
@@ -0,0 +1,268 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::mcfg;
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub mod bar;
+pub mod caps;
+pub mod config;
+
+/// Common header registers
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION: u16 = 0x08;
+pub const PROG_IF: u16 = 0x09;
+pub const SUBCLASS: u16 = 0x0A;
+pub const CLASS: u16 = 0x0B;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+
+/// PCI-to-PCI bridge header registers
+const SECONDARY_BUS: u16 = 0x19;
+
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_BRIDGE: u8 = 0x01;
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Address {
+        Address {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+}
+
+impl Device {
+    fn probe(address: Address) -> Option<Device> {
+        let vendor_id = config::read16(address, VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+
+        Some(Device {
+            address,
+            vendor_id,
+            device_id: config::read16(address, DEVICE_ID),
+            class: config::read8(address, CLASS),
+            subclass: config::read8(address, SUBCLASS),
+            prog_if: config::read8(address, PROG_IF),
+            revision: config::read8(address, REVISION),
+            header_type: config::read8(address, HEADER_TYPE) & HEADER_TYPE_MASK,
+        })
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HEADER_BRIDGE
+    }
+
+    /// Returns the bus behind a PCI-to-PCI bridge
+    pub fn secondary_bus(&self) -> Option<u8> {
+        self.is_bridge().then(|| self.read8(SECONDARY_BUS))
+    }
+
+    pub fn read32(&self, offset: u16) -> u32 {
+        config::read32(self.address, offset)
+    }
+
+    pub fn write32(&self, offset: u16, value: u32) {
+        config::write32(self.address, offset, value)
+    }
+
+    pub fn read16(&self, offset: u16) -> u16 {
+        config::read16(self.address, offset)
+    }
+
+    pub fn write16(&self, offset: u16, value: u16) {
+        config::write16(self.address, offset, value)
+    }
+
+    pub fn read8(&self, offset: u16) -> u8 {
+        config::read8(self.address, offset)
+    }
+
+    /// A short description of the class, along the lines of what lspci prints
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x00, _) => "Unclassified device",
+            (0x01, 0x01) => "IDE interface",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "Non-Volatile memory controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "Network controller",
+            (0x03, 0x00) => "VGA compatible controller",
+            (0x03, _) => "Display controller",
+            (0x04, 0x01) => "Multimedia audio controller",
+            (0x04, 0x03) => "Audio device",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "Bridge",
+            (0x07, _) => "Communication controller",
+            (0x08, _) => "System peripheral",
+            (0x09, _) => "Input device controller",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus",
+            (0x0C, _) => "Serial bus controller",
+            (0x0D, _) => "Wireless controller",
+            (0x10, _) => "Encryption controller",
+            (0x11, _) => "Signal processing controller",
+            _ => "Unknown device",
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+            self.address,
+            self.class_name(),
+            self.class,
+            self.subclass,
+            self.vendor_id,
+            self.device_id,
+            self.revision
+        )
+    }
+}
+
+fn scan_function(devices: &mut Vec<Device>, address: Address) -> Option<Device> {
+    let device = Device::probe(address)?;
+    devices.push(device);
+
+    if let Some(secondary) = device.secondary_bus() {
+        // An unconfigured bridge reports 0, don't loop back onto the root bus
+        if secondary > address.bus {
+            scan_bus(devices, address.segment, secondary);
+        }
+    }
+
+    Some(device)
+}
+
+fn scan_bus(devices: &mut Vec<Device>, segment: u16, bus: u8) {
+    for slot in 0..32 {
+        let Some(device) = scan_function(devices, Address::new(segment, bus, slot, 0)) else {
+            continue;
+        };
+
+        if device.read8(HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+            for function in 1..8 {
+                scan_function(devices, Address::new(segment, bus, slot, function));
+            }
+        }
+    }
+}
+
+/// Returns every function found at boot
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+pub fn init() {
+    log::trace!("Initializing PCI");
+
+    let mut devices = Vec::new();
+    let mut roots: Vec<(u16, u8)> = mcfg::allocations()
+        .map(|a| (a.segment, a.start_bus))
+        .collect();
+
+    if roots.is_empty() {
+        // Without ECAM, every function of the host bridge is the root of its own bus
+        let host = Address::new(0, 0, 0, 0);
+        let functions = match config::read8(host, HEADER_TYPE) & HEADER_MULTI_FUNCTION {
+            0 => 1,
+            _ => 8,
+        };
+
+        roots = (0..functions)
+            .filter(|&f| config::read16(Address::new(0, 0, 0, f), VENDOR_ID) != 0xFFFF)
+            .map(|f| (0, f))
+            .collect();
+    }
+
+    for (segment, bus) in roots {
+        scan_bus(&mut devices, segment, bus);
+    }
+
+    devices.sort_by_key(|d| d.address);
+    devices.dedup_by_key(|d| d.address);
+
+    for device in &devices {
+        log::info!("{device}");
+
+        for bar in (0..6).filter_map(|n| device.bar(n)) {
+            log::debug!("  {bar:x?}");
+        }
+
+        for capability in device.capabilities() {
+            log::debug!("  {capability:x?}");
+        }
+
+        for capability in device.extended_capabilities() {
+            log::debug!("  {capability:x?}");
+        }
+    }
+
+    *DEVICES.lock() = devices;
+}
+
+initcall!(pci, init, [acpi]);
@@ -0,0 +1,233 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::acpi::{self, aml};
+use crate::apic::ICR_ALL_EXCLUDING_SELF;
+use crate::efi::{self, ResetType};
+use crate::interrupts::{self, InterruptStack};
+use crate::sync::Mutex;
+use crate::{core_locals, cpu, fb_renderer, hpet, logging, virtio};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// SLP_EN bit of the PM1 control registers
+const SLP_EN: u64 = 1 << 13;
+
+/// How long to wait for the other cores to acknowledge the park IPI, in milliseconds
+const PARK_TIMEOUT_MS: usize = 100;
+
+static NOTIFIERS: Mutex<Vec<fn(Action)>> = Mutex::new(Vec::new());
+static GOING_DOWN: AtomicBool = AtomicBool::new(false);
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    PowerOff,
+    Reboot,
+}
+
+fn acpi_shutdown() -> Option<()> {
+    let fadt = acpi::fadt()?;
+
+    let (slp_typa, slp_typb) = aml::sleep_type(5)
+        .map_err(|e| log::warn!("Cannot evaluate \\_S5: {e:?}"))
+        .ok()?;
+
+    let pm1a = fadt.pm1a_cnt()?;
+    let pm1b = fadt.pm1b_cnt();
+
+    acpi::events::enable_acpi_mode();
+
+    // Let the firmware prepare for the transition, it's fine if it doesn't care
+    let args = aml::Args::from_list(alloc::vec![aml::AmlValue::Integer(5)]).unwrap();
+    let _ = aml::evaluate("\\_PTS", args);
+
+    unsafe {
+        core::arch::asm!("cli");
+
+        let value = pm1a.read() & !(0b111 << 10);
+        pm1a.write(value | ((slp_typa as u64) << 10) | SLP_EN);
+
+        if let Some(pm1b) = pm1b {
+            let value = pm1b.read() & !(0b111 << 10);
+            pm1b.write(value | ((slp_typb as u64) << 10) | SLP_EN);
+        }
+    }
+
+    // Give the chipset some time to actually cut the power
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+
+    Some(())
+}
+
+/// Powers the machine off, if nothing works the current core is halted
+pub fn shutdown() -> ! {
+    log::info!("Powering off");
+
+    if acpi_shutdown().is_none() {
+        log::warn!("ACPI S5 is not available, trying the firmware");
+    }
+
+    efi::reset(ResetType::Shutdown);
+
+    unsafe {
+        // QEMU (PIIX4 and ICH9 PM blocks)
+        cpu::outw(0x604, 0x2000);
+        // Bochs and older QEMU
+        cpu::outw(0xB004, 0x2000);
+        // VirtualBox
+        cpu::outw(0x4004, 0x3400);
+    }
+
+    log::error!("Failed to power off");
+    crate::hcf()
+}
+
+fn acpi_reset() {
+    let Some(fadt) = acpi::fadt() else {
+        return;
+    };
+
+    if let Some(reset_reg) = fadt.reset_register() {
+        log::debug!("Resetting through {reset_reg:x?}");
+        unsafe { reset_reg.write(fadt.reset_value as u64) };
+    }
+}
+
+fn keyboard_controller_reset() {
+    unsafe {
+        // Wait for the input buffer to be empty, then pulse the reset line
+        for _ in 0..100_000 {
+            if cpu::inb(0x64) & 0b10 == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        cpu::outb(0x64, 0xFE);
+    }
+}
+
+/// Reboots the machine, falling back to a triple fault if nothing else works
+pub fn reboot() -> ! {
+    log::info!("Rebooting");
+
+    unsafe { core::arch::asm!("cli") };
+
+    acpi_reset();
+    efi::reset(ResetType::Cold);
+    keyboard_controller_reset();
+
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+
+    log::warn!("Reset didn't happen, forcing a triple fault");
+
+    unsafe {
+        let null_idt = [0u8; 10];
+        core::arch::asm!("lidt [{}]", "int3", in(reg) &null_idt, options(noreturn));
+    }
+}
+
+/// Registers `notifier` to run before the machine is powered off or rebooted
+pub fn register_notifier(notifier: fn(Action)) {
+    NOTIFIERS.lock().push(notifier);
+}
+
+fn park_handler(_stack: &mut InterruptStack) {
+    PARKED.fetch_add(1, Ordering::SeqCst);
+
+    loop {
+        unsafe { core::arch::asm!("cli; hlt") };
+    }
+}
+
+/// Stops every other core, they're left halted with interrupts disabled
+fn park_other_cores() {
+    let others = core_locals::cores_online() - 1;
+    if others == 0 {
+        return;
+    }
+
+    let Some(vector) = interrupts::allocate_handler(park_handler) else {
+        log::warn!("No free vector to park the other cores");
+        return;
+    };
+
+    unsafe {
+        core!()
+            .apic
+            .lock()
+            .ipi(0, vector as u32 | ICR_ALL_EXCLUDING_SELF)
+    };
+
+    for _ in 0..PARK_TIMEOUT_MS {
+        if PARKED.load(Ordering::SeqCst) == others {
+            break;
+        }
+
+        hpet::sleep(1_000_000);
+    }
+
+    let parked = PARKED.load(Ordering::SeqCst);
+    if parked != others {
+        log::warn!("Only {parked} of {others} cores parked");
+    }
+
+    // A parked core might have been holding these, it's never going to release them now
+    unsafe {
+        logging::unlock();
+        fb_renderer::unlock();
+        virtio::console::unlock();
+    }
+}
+
+/// Notifies every subsystem and parks the other cores, only the first caller gets through
+fn prepare(action: Action) {
+    if GOING_DOWN.swap(true, Ordering::SeqCst) {
+        // Someone else is already taking the machine down and is going to park us
+        loop {
+            unsafe { core::arch::asm!("sti; hlt") };
+        }
+    }
+
+    log::info!("Preparing for {action:?}");
+
+    let notifiers = NOTIFIERS.lock().clone();
+    for notifier in notifiers {
+        notifier(action);
+    }
+
+    park_other_cores();
+    log::logger().flush();
+}
+
+/// Powers the machine off after notifying every subsystem and stopping the other cores
+pub fn power_off() -> ! {
+    prepare(Action::PowerOff);
+    shutdown()
+}
+
+/// Reboots the machine after notifying every subsystem and stopping the other cores
+pub fn restart() -> ! {
+    prepare(Action::Reboot);
+    reboot()
+}
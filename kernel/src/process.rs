@@ -0,0 +1,52 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::core_locals::MAX_CORES;
+use crate::fs::file::HandleTable;
+use crate::sync::{IrqSpinlock, Mutex, PerCpu};
+use alloc::sync::Arc;
+
+/// What syscalls run on behalf of, it owns the files they open
+pub struct Process {
+    pub files: Mutex<HandleTable>,
+}
+
+impl Process {
+    pub const fn new() -> Process {
+        Process {
+            files: Mutex::new(HandleTable::new()),
+        }
+    }
+}
+
+/// Stands in for everything that has no process of its own, like the kernel shell
+static KERNEL: Process = Process::new();
+
+/// The process each core is running, the kernel's while it's `None`
+static CURRENT: PerCpu<IrqSpinlock<Option<Arc<Process>>>> =
+    PerCpu::new([const { IrqSpinlock::new(None) }; MAX_CORES]);
+
+/// Makes `process` the current one on this core, returns the one it replaces
+pub fn switch(process: Option<Arc<Process>>) -> Option<Arc<Process>> {
+    core::mem::replace(&mut *CURRENT.get().lock(), process)
+}
+
+/// Runs `f` on the current core's process
+pub fn with_current<R>(f: impl FnOnce(&Process) -> R) -> R {
+    let current = CURRENT.get().lock().clone();
+    f(current.as_deref().unwrap_or(&KERNEL))
+}
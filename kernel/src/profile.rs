@@ -0,0 +1,432 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::apic::{Apic, ICR_ALL_EXCLUDING_SELF};
+use crate::backtrace::{self, Registers};
+use crate::core_locals::{self, MAX_CORES};
+use crate::interrupts::{self, InterruptStack};
+use crate::mm::pmm;
+use crate::sync::PerCpu;
+use crate::utils::HashMap;
+use crate::{cpu, time};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{
+    AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
+
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// Event select for unhalted core cycles, counted in ring 0 and interrupting on overflow
+const UNHALTED_CORE_CYCLES: u64 = 0x3C;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// PMC0's bit in the global control and status MSRs
+const PMC0: u64 = 1 << 0;
+
+const NMI_VECTOR: usize = 2;
+
+/// Frames kept per sample, starting from the interrupted one
+const MAX_DEPTH: usize = 24;
+/// Samples every core keeps, past this they're counted as dropped
+const SAMPLES: usize = 2048;
+const BUFFER_PAGES: usize = size_of::<Buffer>().div_ceil(0x1000);
+
+/// What triggers the samples, kept in `SOURCE` with 0 meaning stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Source {
+    /// The unhalted core cycle counter overflowing into an NMI, which sees everything
+    Pmu = 1,
+    /// The APIC timer, for when there's no architectural PMU. It only sees what runs with
+    /// interrupts on, which is mostly the idle loop
+    Timer,
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    depth: usize,
+    frames: [u64; MAX_DEPTH],
+}
+
+struct Buffer {
+    len: AtomicUsize,
+    dropped: AtomicU64,
+    samples: [Sample; SAMPLES],
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+static HZ: AtomicU32 = AtomicU32::new(0);
+/// Cycles between PMU samples
+static PERIOD: AtomicU32 = AtomicU32::new(0);
+static PMU: AtomicBool = AtomicBool::new(false);
+/// Tells the other cores to pick up a change of `SOURCE`
+static RELOAD_VECTOR: AtomicU8 = AtomicU8::new(0);
+static TIMER_VECTOR: AtomicU8 = AtomicU8::new(0);
+static BUFFERS: PerCpu<AtomicPtr<Buffer>> =
+    PerCpu::new([const { AtomicPtr::new(ptr::null_mut()) }; MAX_CORES]);
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Pmu => "unhalted cycles",
+            Source::Timer => "the APIC timer",
+        }
+    }
+}
+
+/// Checks for an architectural PMU with the global control MSRs and the core cycle event
+fn pmu_supported() -> bool {
+    let leaf = cpu::cpuid(0xA);
+    let version = leaf.eax & 0xFF;
+    let counters = (leaf.eax >> 8) & 0xFF;
+    let event_length = (leaf.eax >> 24) & 0xFF;
+
+    // A set bit in EBX means the event is missing
+    version >= 2 && counters >= 1 && event_length >= 1 && leaf.ebx & 1 == 0
+}
+
+fn source() -> Option<Source> {
+    match SOURCE.load(Ordering::Acquire) {
+        1 => Some(Source::Pmu),
+        2 => Some(Source::Timer),
+        _ => None,
+    }
+}
+
+/// What PMC0 gets loaded with to overflow after `period` cycles, writes sign extend bit 31
+fn reload(period: u32) -> u64 {
+    (period as u64).wrapping_neg() & 0xFFFF_FFFF
+}
+
+/// Stores the stack at `registers` in the current core's buffer
+fn record(registers: Registers) {
+    let buffer = BUFFERS.get().load(Ordering::Acquire);
+    if buffer.is_null() {
+        return;
+    }
+
+    // Only this core writes here, and it doesn't take a sample while taking another one
+    let buffer = unsafe { &*buffer };
+    let len = buffer.len.load(Ordering::Relaxed);
+    if len == SAMPLES {
+        buffer.dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let mut sample = Sample {
+        depth: 0,
+        frames: [0; MAX_DEPTH],
+    };
+    backtrace::walk(registers, |rip| {
+        if sample.depth < MAX_DEPTH {
+            sample.frames[sample.depth] = rip;
+            sample.depth += 1;
+        }
+    });
+
+    unsafe { ptr::addr_of!(buffer.samples[len]).cast_mut().write(sample) };
+    buffer.len.store(len + 1, Ordering::Release);
+}
+
+/// Takes PMU samples, anything else arriving as an NMI is as fatal as it was before
+fn nmi_handler(stack: &mut InterruptStack) {
+    if !PMU.load(Ordering::Relaxed) || unsafe { cpu::rdmsr(IA32_PERF_GLOBAL_STATUS) } & PMC0 == 0 {
+        interrupts::unhandled(NMI_VECTOR, stack);
+    }
+
+    // An overflow can still land right after stopping, it only has to be acknowledged
+    let running = source() == Some(Source::Pmu);
+    if running {
+        record((&*stack).into());
+    }
+
+    unsafe {
+        if running {
+            cpu::wrmsr(IA32_PMC0, reload(PERIOD.load(Ordering::Relaxed)));
+        }
+        cpu::wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, PMC0);
+        Apic::unmask_performance_nmi();
+    }
+}
+
+fn timer_handler(stack: &mut InterruptStack) {
+    record((&*stack).into());
+    core!().apic.lock().eoi();
+}
+
+fn reload_handler(_stack: &mut InterruptStack) {
+    program();
+    core!().apic.lock().eoi();
+}
+
+/// Makes the current core's counters and timer match `SOURCE`
+fn program() {
+    if PMU.load(Ordering::Relaxed) {
+        unsafe {
+            cpu::wrmsr(IA32_PERF_GLOBAL_CTRL, 0);
+            cpu::wrmsr(IA32_PERFEVTSEL0, 0);
+        }
+    }
+    core!().apic.lock().stop_timer();
+
+    match source() {
+        Some(Source::Pmu) => unsafe {
+            cpu::wrmsr(IA32_PMC0, reload(PERIOD.load(Ordering::Relaxed)));
+            cpu::wrmsr(
+                IA32_PERFEVTSEL0,
+                UNHALTED_CORE_CYCLES | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN,
+            );
+            cpu::wrmsr(IA32_PERF_GLOBAL_CTRL, PMC0);
+            core!().apic.lock().set_performance_nmi();
+        },
+        Some(Source::Timer) => core!().apic.lock().start_periodic_timer(
+            TIMER_VECTOR.load(Ordering::Relaxed),
+            HZ.load(Ordering::Relaxed),
+        ),
+        None => {}
+    }
+}
+
+/// Reprograms every core, the others catch up as soon as they take interrupts
+fn program_all() {
+    program();
+
+    let vector = RELOAD_VECTOR.load(Ordering::Relaxed);
+    if core_locals::cores_online() > 1 {
+        unsafe {
+            core!()
+                .apic
+                .lock()
+                .ipi(0, vector as u32 | ICR_ALL_EXCLUDING_SELF)
+        };
+    }
+}
+
+/// Allocates a buffer for the core `buffer` belongs to, unless it has one
+fn attach(buffer: &AtomicPtr<Buffer>) -> Result<(), &'static str> {
+    if !buffer.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+
+    let page = pmm::try_alloc(BUFFER_PAGES).ok_or("no memory for the samples")?;
+    let new = page.as_hhdm().as_mut_ptr::<Buffer>();
+    unsafe {
+        ptr::addr_of_mut!((*new).len).write(AtomicUsize::new(0));
+        ptr::addr_of_mut!((*new).dropped).write(AtomicU64::new(0));
+    }
+
+    if buffer
+        .compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        pmm::free(page, BUFFER_PAGES);
+    }
+
+    Ok(())
+}
+
+/// Throws away the samples taken so far
+fn clear() {
+    for buffer in BUFFERS.iter() {
+        let buffer = buffer.load(Ordering::Acquire);
+        if !buffer.is_null() {
+            unsafe {
+                (*buffer).len.store(0, Ordering::SeqCst);
+                (*buffer).dropped.store(0, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Starts sampling every core `hz` times a second, throwing away the last profile
+pub fn start(hz: u32) -> Result<Source, &'static str> {
+    if source().is_some() {
+        return Err("already running");
+    }
+    if hz == 0 {
+        return Err("invalid frequency");
+    }
+    if RELOAD_VECTOR.load(Ordering::Relaxed) == 0 {
+        return Err("no free vector");
+    }
+
+    for buffer in BUFFERS.iter() {
+        attach(buffer)?;
+    }
+    clear();
+
+    // Core cycles tick at about the TSC rate, close enough for a sampling period
+    let period = time::tsc_per_us() * 1_000_000 / hz as u64;
+    PERIOD.store(period.clamp(1, i32::MAX as u64) as u32, Ordering::Relaxed);
+    HZ.store(hz, Ordering::Relaxed);
+
+    let source = match PMU.load(Ordering::Relaxed) {
+        true => Source::Pmu,
+        false => Source::Timer,
+    };
+    SOURCE.store(source as u8, Ordering::Release);
+    program_all();
+
+    Ok(source)
+}
+
+pub fn stop() {
+    SOURCE.store(0, Ordering::Release);
+    program_all();
+}
+
+/// Samples taken and dropped for a full buffer, over every core
+pub fn counts() -> (usize, u64) {
+    BUFFERS
+        .iter()
+        .map(|buffer| buffer.load(Ordering::Acquire))
+        .filter(|buffer| !buffer.is_null())
+        .fold((0, 0), |(taken, dropped), buffer| unsafe {
+            (
+                taken + (*buffer).len.load(Ordering::Acquire),
+                dropped + (*buffer).dropped.load(Ordering::Relaxed),
+            )
+        })
+}
+
+/// Calls `sample` with the frames of every sample taken, innermost first
+fn for_each_sample(mut sample: impl FnMut(&[u64])) {
+    for buffer in BUFFERS.iter() {
+        let buffer = buffer.load(Ordering::Acquire);
+        if buffer.is_null() {
+            continue;
+        }
+
+        let buffer = unsafe { &*buffer };
+        for sampled in &buffer.samples[..buffer.len.load(Ordering::Acquire)] {
+            sample(&sampled.frames[..sampled.depth]);
+        }
+    }
+}
+
+/// Names the function at `rip`, remembering it in `names` since the same few come up all the time
+fn symbol(names: &mut HashMap<u64, String>, rip: u64) -> &str {
+    names
+        .entry(rip)
+        .or_insert_with(|| match backtrace::lookup(rip) {
+            // Folded stacks use `;` as the separator, array types have it in their names
+            Some((name, _)) => alloc::format!("{name:#}").replace(';', ","),
+            None => alloc::format!("{rip:#x}"),
+        })
+}
+
+/// Writes the profile as folded stacks, a `outermost;...;innermost count` line per distinct stack,
+/// what flamegraph.pl and speedscope read
+pub fn dump(out: &mut impl Write) -> fmt::Result {
+    let mut names = HashMap::default();
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+
+    for_each_sample(|frames| {
+        let mut stack = String::new();
+        for &rip in frames.iter().rev() {
+            if !stack.is_empty() {
+                stack.push(';');
+            }
+            stack.push_str(symbol(&mut names, rip));
+        }
+
+        *stacks.entry(stack).or_default() += 1;
+    });
+
+    for (stack, count) in stacks {
+        write!(out, "{stack} {count}\r\n")?;
+    }
+
+    Ok(())
+}
+
+/// Writes the `count` functions most samples landed in
+pub fn top(out: &mut impl Write, count: usize) -> fmt::Result {
+    let mut names = HashMap::default();
+    let mut functions: BTreeMap<String, u64> = BTreeMap::new();
+
+    for_each_sample(|frames| {
+        if let Some(&rip) = frames.first() {
+            *functions.entry(symbol(&mut names, rip).into()).or_default() += 1;
+        }
+    });
+
+    let (taken, dropped) = counts();
+    write!(out, "{taken} samples, {dropped} dropped\r\n")?;
+
+    let mut functions: Vec<(String, u64)> = functions.into_iter().collect();
+    functions.sort_unstable_by_key(|&(_, samples)| core::cmp::Reverse(samples));
+
+    for (name, samples) in functions.into_iter().take(count) {
+        let percent = samples * 100 / taken.max(1) as u64;
+        write!(out, "{samples:>7} {percent:>3}% {name}\r\n")?;
+    }
+
+    Ok(())
+}
+
+/// Takes over the NMI vector and reserves the ones for the timer and for reprogramming the others
+pub fn init() {
+    PMU.store(pmu_supported(), Ordering::Relaxed);
+
+    interrupts::register_handler(NMI_VECTOR, nmi_handler);
+
+    let Some(timer) = interrupts::allocate_handler(timer_handler) else {
+        log::warn!("profile: no free vectors, profiling won't be available");
+        return;
+    };
+    let Some(reload) = interrupts::allocate_handler(reload_handler) else {
+        interrupts::free_handler(timer);
+        log::warn!("profile: no free vectors, profiling won't be available");
+        return;
+    };
+
+    TIMER_VECTOR.store(timer, Ordering::Relaxed);
+    RELOAD_VECTOR.store(reload, Ordering::Relaxed);
+}
+
+initcall!(profile, init, [apic]);
+
+ktest! {
+    fn samples_fold_into_stacks() {
+        attach(BUFFERS.get()).unwrap();
+        clear();
+
+        for _ in 0..2 {
+            record(Registers::current());
+        }
+
+        let mut out = String::new();
+        dump(&mut out).unwrap();
+
+        assert_eq!(counts(), (2, 0));
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.trim_end().ends_with(" 2"));
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::framebuffer::Framebuffer;
+
+const MAGIC: &[u8; 4] = b"qoif";
+const HEADER_SIZE: usize = 14;
+
+/// Anything bigger than a 4K screen is surely a broken header
+const MAX_PIXELS: usize = 4096 * 2160;
+
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+const OP_INDEX: u8 = 0b00;
+const OP_DIFF: u8 = 0b01;
+const OP_LUMA: u8 = 0b10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    NotQoi,
+    TooBig,
+    /// The data ends before every pixel is there
+    Truncated,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct Pixel {
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8,
+}
+
+impl Pixel {
+    fn hash(&self) -> usize {
+        (self.r as usize * 3 + self.g as usize * 5 + self.b as usize * 7 + self.a as usize * 11)
+            % 64
+    }
+
+    /// The color this pixel shows over `background`
+    fn over(&self, background: u32) -> u32 {
+        let [bg_b, bg_g, bg_r, _] = background.to_le_bytes();
+        let a = self.a as u16;
+        let mix = |c: u8, bg: u8| ((c as u16 * a + bg as u16 * (255 - a)) / 255) as u8;
+
+        u32::from_le_bytes([
+            mix(self.b, bg_b),
+            mix(self.g, bg_g),
+            mix(self.r, bg_r),
+            0xFF,
+        ])
+    }
+}
+
+/// Decodes a QOI image into a surface, with translucent pixels blended over `background`
+pub fn decode(data: &[u8], background: u32) -> Result<Framebuffer<'static>, Error> {
+    if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+        return Err(Error::NotQoi);
+    }
+
+    let width = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+    match width.checked_mul(height) {
+        Some(pixels) if pixels <= MAX_PIXELS => {}
+        _ => return Err(Error::TooBig),
+    }
+
+    let mut image = Framebuffer::new(width, height);
+    let mut bytes = data[HEADER_SIZE..].iter().copied();
+    let mut next = || bytes.next().ok_or(Error::Truncated);
+
+    let mut index = [Pixel::default(); 64];
+    let mut pixel = Pixel {
+        a: 255,
+        ..Default::default()
+    };
+    let mut run = 0;
+
+    for y in 0..height {
+        for x in 0..width {
+            if run > 0 {
+                run -= 1;
+            } else {
+                let op = next()?;
+                match op {
+                    OP_RGB => {
+                        pixel.r = next()?;
+                        pixel.g = next()?;
+                        pixel.b = next()?;
+                    }
+                    OP_RGBA => {
+                        pixel.r = next()?;
+                        pixel.g = next()?;
+                        pixel.b = next()?;
+                        pixel.a = next()?;
+                    }
+                    _ => match op >> 6 {
+                        OP_INDEX => pixel = index[op as usize],
+                        OP_DIFF => {
+                            pixel.r = pixel.r.wrapping_add((op >> 4) & 3).wrapping_sub(2);
+                            pixel.g = pixel.g.wrapping_add((op >> 2) & 3).wrapping_sub(2);
+                            pixel.b = pixel.b.wrapping_add(op & 3).wrapping_sub(2);
+                        }
+                        OP_LUMA => {
+                            let dg = (op & 0x3F).wrapping_sub(32);
+                            let second = next()?;
+                            let dr = dg.wrapping_add(second >> 4).wrapping_sub(8);
+                            let db = dg.wrapping_add(second & 0x0F).wrapping_sub(8);
+
+                            pixel.r = pixel.r.wrapping_add(dr);
+                            pixel.g = pixel.g.wrapping_add(dg);
+                            pixel.b = pixel.b.wrapping_add(db);
+                        }
+                        // The run op, this pixel and `run` more like it
+                        _ => run = op & 0x3F,
+                    },
+                }
+
+                index[pixel.hash()] = pixel;
+            }
+
+            image.write(x, y, pixel.over(background));
+        }
+    }
+
+    Ok(image)
+}
@@ -0,0 +1,165 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::cpu;
+use crate::sync::Mutex;
+
+/// Bits of credited entropy after which the pool is considered seeded
+const SEEDED_BITS: usize = 256;
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    key: [0; 8],
+    counter: 0,
+    entropy: 0,
+});
+
+/// ChaCha20 keyed by everything mixed in so far, rekeyed after every use
+struct Pool {
+    key: [u32; 8],
+    counter: u64,
+    /// Credited bits, capped at the key size
+    entropy: usize,
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function, with a 64 bit counter and no nonce
+fn chacha20(key: &[u32; 8], counter: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574]);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+
+    state
+}
+
+impl Pool {
+    fn next_block(&mut self) -> [u32; 16] {
+        let block = chacha20(&self.key, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        block
+    }
+
+    /// Replaces the key with fresh output, so earlier output can't be recovered from the state
+    fn rekey(&mut self) {
+        let block = self.next_block();
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (i, byte) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= (*byte as u32) << ((i % 4) * 8);
+            }
+
+            self.rekey();
+        }
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(64) {
+            let block = self.next_block();
+
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[i / 4] >> ((i % 4) * 8)) as u8;
+            }
+        }
+
+        self.rekey();
+    }
+}
+
+/// Mixes `data` into the pool, crediting it with `bits` bits of entropy
+pub fn add_entropy(data: &[u8], bits: usize) {
+    let mut pool = POOL.lock();
+
+    pool.mix(data);
+    pool.entropy = (pool.entropy + bits).min(SEEDED_BITS);
+}
+
+/// Fills `buffer` with output of the pool, whether it's seeded or not
+pub fn fill(buffer: &mut [u8]) {
+    let mut pool = POOL.lock();
+
+    // Never hand out the same output twice, even if nobody added anything in between
+    let tsc = unsafe { cpu::rdtsc() };
+    pool.mix(&tsc.to_le_bytes());
+    pool.fill(buffer);
+}
+
+pub fn u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+pub fn entropy_bits() -> usize {
+    POOL.lock().entropy
+}
+
+/// Whether enough entropy was credited for the output to be unpredictable
+pub fn seeded() -> bool {
+    entropy_bits() >= SEEDED_BITS
+}
+
+pub fn init() {
+    log::trace!("Initializing the entropy pool");
+
+    let tsc = unsafe { cpu::rdtsc() };
+    add_entropy(&tsc.to_le_bytes(), 0);
+
+    // Mixed in, but not credited until there's a reason to trust the hardware generator
+    match (0..4)
+        .map(|_| cpu::rdrand())
+        .collect::<Option<alloc::vec::Vec<u64>>>()
+    {
+        Some(values) => {
+            for value in values {
+                add_entropy(&value.to_le_bytes(), 0);
+            }
+        }
+        None => log::debug!("No RDRAND"),
+    }
+}
+
+initcall!(random, init, []);
@@ -0,0 +1,131 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use core::cell::UnsafeCell;
+
+/// A register the device only lets us read, like capabilities and status
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+/// A register reads of which mean nothing or have side effects, like doorbells
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+#[repr(transparent)]
+pub struct ReadWrite<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+// Device memory is there for every core, whoever needs `modify` to be atomic has to lock
+unsafe impl<T: Copy + Send> Sync for ReadOnly<T> {}
+unsafe impl<T: Copy + Send> Sync for WriteOnly<T> {}
+unsafe impl<T: Copy + Send> Sync for ReadWrite<T> {}
+
+impl<T: Copy> ReadOnly<T> {
+    pub fn get(&self) -> T {
+        unsafe { self.value.get().read_volatile() }
+    }
+}
+
+impl<T: Copy> WriteOnly<T> {
+    pub fn set(&self, value: T) {
+        unsafe { self.value.get().write_volatile(value) }
+    }
+}
+
+impl<T: Copy> ReadWrite<T> {
+    pub fn get(&self) -> T {
+        unsafe { self.value.get().read_volatile() }
+    }
+
+    pub fn set(&self, value: T) {
+        unsafe { self.value.get().write_volatile(value) }
+    }
+
+    /// Reads the register, lets `f` change it and writes it back, which isn't atomic
+    pub fn modify(&self, f: impl FnOnce(&mut T)) {
+        let mut value = self.get();
+        f(&mut value);
+        self.set(value);
+    }
+}
+
+/// Declares the register block of a device as a `#[repr(C)]` struct, checking at compile time
+/// that every field lands at the offset written next to it.
+///
+/// Fields are `ReadOnly`, `WriteOnly` or `ReadWrite` of an integer or a `bilge` bitfield, other
+/// register blocks, or arrays of either. Gaps have to be filled with `_reserved` fields, which the
+/// offset check catches when they're the wrong size. Blocks are only ever handed out by reference,
+/// pointing at the device's memory:
+///
+/// ```ignore
+/// registers! {
+///     struct Timer {
+///         0x00 => caps: ReadOnly<u32>,
+///         0x04 => _reserved: u32,
+///         0x08 => counter: ReadWrite<u64>,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! registers {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($offset:literal => $field_vis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[repr(C)]
+        $vis struct $name {
+            $($field_vis $field: $ty),*
+        }
+
+        const _: () = {
+            $(assert!(
+                ::core::mem::offset_of!($name, $field) == $offset,
+                concat!(stringify!($name), "::", stringify!($field), " isn't at ", stringify!($offset))
+            );)*
+        };
+    };
+}
+
+ktest! {
+    fn registers_land_at_their_offsets() {
+        registers! {
+            struct Block {
+                0x00 => caps: ReadOnly<u32>,
+                0x04 => doorbell: WriteOnly<u32>,
+                0x08 => counter: ReadWrite<u64>,
+            }
+        }
+
+        let mut memory = [0xCAFE_u64, 0];
+        let block = unsafe { &*(memory.as_mut_ptr() as *const Block) };
+
+        assert_eq!(block.caps.get(), 0xCAFE);
+        block.doorbell.set(7);
+        block.counter.set(1);
+        block.counter.modify(|counter| *counter += 41);
+
+        assert_eq!(memory, [0x7_0000_CAFE, 42]);
+    }
+}
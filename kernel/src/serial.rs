@@ -15,28 +15,372 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-
+use crate::interrupts::{self, InterruptStack};
+use crate::sync::IrqSpinlock;
+use crate::utils::MpscRing;
+use crate::{cmdline, cpu, ioapic, logging};
 use core::fmt::{Arguments, Result, Write};
+use core::sync::atomic::{AtomicU16, Ordering};
 
-struct SerialWriter;
+/// Registers, relative to the port base
+const DATA: u16 = 0;
+const IER: u16 = 1;
+const FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const SCRATCH: u16 = 7;
+/// Divisor latch, in place of DATA and IER while LCR_DLAB is set
+const DLL: u16 = 0;
+const DLM: u16 = 1;
 
-impl Write for SerialWriter {
-    fn write_str(&mut self, s: &str) -> Result {
-        unsafe {
-            core::arch::asm!("rep outsb",
-             in("rsi") s.as_ptr(),
-             in("rcx") s.len(),
-             in("dx") 0x3f8,
-            );
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+
+/// Enable and clear both FIFOs, interrupt once 14 bytes are in
+const FCR_DEFAULT: u8 = 0xC7;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+/// Gates the interrupt line on PC compatibles
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// The divisor is relative to this
+const BASE_BAUD: u32 = 115_200;
+
+/// Bytes the transmit FIFO holds once empty
+const FIFO_SIZE: usize = 16;
+
+/// How many times to poll for an empty transmitter before giving up on the port
+const TX_TIMEOUT: usize = 100_000;
+
+const RX_BUFFER_SIZE: usize = 1024;
+
+/// Base and ISA IRQ of COM1 to COM4
+pub const PORTS: [(u16, u8); 4] = [(0x3F8, 4), (0x2F8, 3), (0x3E8, 4), (0x2E8, 3)];
+
+static UARTS: IrqSpinlock<[Option<Uart>; 4]> = IrqSpinlock::new([None; 4]);
+static RX: [MpscRing<u8, RX_BUFFER_SIZE>; 4] = [const { MpscRing::new() }; 4];
+/// Base of the port kernel messages go to, 0 if there is none
+static CONSOLE: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub baud: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2
+    pub stop_bits: u8,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            baud: BASE_BAUD,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+}
+
+impl Config {
+    fn lcr(&self) -> u8 {
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+        };
+
+        (self.data_bits.clamp(5, 8) - 5) | ((self.stop_bits > 1) as u8) << 2 | parity
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Uart {
+    base: u16,
+    irq: u8,
+    config: Config,
+}
+
+fn read(base: u16, register: u16) -> u8 {
+    unsafe { cpu::inb(base + register) }
+}
+
+fn write(base: u16, register: u16, value: u8) {
+    unsafe { cpu::outb(base + register, value) }
+}
+
+/// Checks there is a 16550 at `base`, with the scratch register and a loopback round trip
+fn detect(base: u16) -> bool {
+    write(base, SCRATCH, 0x5A);
+    if read(base, SCRATCH) != 0x5A {
+        return false;
+    }
+
+    let mcr = read(base, MCR);
+    write(base, MCR, MCR_LOOPBACK);
+    write(base, DATA, 0xAE);
+    let echoed = read(base, DATA) == 0xAE;
+    write(base, MCR, mcr);
+
+    echoed
+}
+
+fn configure_uart(base: u16, config: &Config) {
+    let divisor = (BASE_BAUD / config.baud.clamp(1, BASE_BAUD)) as u16;
+
+    write(base, IER, 0);
+    write(base, LCR, LCR_DLAB);
+    write(base, DLL, divisor as u8);
+    write(base, DLM, (divisor >> 8) as u8);
+    write(base, LCR, config.lcr());
+    write(base, FCR, FCR_DEFAULT);
+    write(base, MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+}
+
+/// Sends `bytes` out of the port at `base`, a FIFO's worth at a time
+fn transmit(base: u16, bytes: &[u8]) {
+    for chunk in bytes.chunks(FIFO_SIZE) {
+        if !(0..TX_TIMEOUT).any(|_| read(base, LSR) & LSR_THR_EMPTY != 0) {
+            return;
+        }
+
+        for &byte in chunk {
+            write(base, DATA, byte);
+        }
+    }
+}
+
+/// Parses the `ttyS<n>[,<baud>[<parity>[<bits>]]]` part of `console=`, e.g. `ttyS1,9600e7`
+fn console_option() -> Option<core::result::Result<(usize, Config), &'static str>> {
+    let mut options = cmdline::value("console")?.split(',');
+    let port = options.find_map(|option| option.strip_prefix("ttyS"))?;
+
+    // The settings are optional, whatever comes next may as well be another console
+    let settings = options
+        .next()
+        .filter(|s| s.starts_with(|c: char| c.is_ascii_digit()));
+
+    Some(parse_console(port, settings))
+}
+
+fn parse_console(
+    port: &str,
+    settings: Option<&str>,
+) -> core::result::Result<(usize, Config), &'static str> {
+    let port = port
+        .parse::<usize>()
+        .ok()
+        .filter(|&port| port < PORTS.len())
+        .ok_or("no such port")?;
+    let mut config = Config::default();
+
+    let Some(settings) = settings else {
+        return Ok((port, config));
+    };
+
+    let digits = settings
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(settings.len());
+    let (baud, mut rest) = settings.split_at(digits);
+    config.baud = baud
+        .parse()
+        .ok()
+        .filter(|baud| (1..=BASE_BAUD).contains(baud))
+        .ok_or("bad baud rate")?;
+
+    if let Some(parity) = rest.chars().next().filter(char::is_ascii_alphabetic) {
+        config.parity = match parity {
+            'n' => Parity::None,
+            'o' => Parity::Odd,
+            'e' => Parity::Even,
+            _ => return Err("bad parity"),
+        };
+        rest = &rest[1..];
+    }
+
+    if !rest.is_empty() {
+        config.data_bits = rest
+            .parse()
+            .ok()
+            .filter(|bits| (5..=8).contains(bits))
+            .ok_or("bad data bits")?;
+    }
+
+    Ok((port, config))
+}
+
+/// Finds the COM ports and sets them up at 115200 8N1, the first one gets kernel messages
+///
+/// `console=ttyS<n>,<settings>` picks another port for them, and its line settings
+pub fn init() {
+    let mut uarts = UARTS.lock();
+
+    for (i, &(base, irq)) in PORTS.iter().enumerate() {
+        if !detect(base) {
+            continue;
+        }
+
+        let config = Config::default();
+        configure_uart(base, &config);
+        uarts[i] = Some(Uart { base, irq, config });
+
+        let _ = CONSOLE.compare_exchange(0, base, Ordering::Relaxed, Ordering::Relaxed);
+    }
+    drop(uarts);
+
+    match console_option() {
+        Some(Ok((port, config))) if configure(port, config) => {
+            CONSOLE.store(PORTS[port].0, Ordering::Relaxed);
         }
+        Some(Ok((port, _))) => log::warn!("serial: console ttyS{port} isn't there"),
+        Some(Err(err)) => log::warn!("serial: ignoring console settings, {err}"),
+        None => {}
+    }
+
+    if has_console() {
+        logging::replay("serial");
+    }
+}
+
+/// Routes the receive interrupts of the ports found, once the IOAPICs are up
+pub fn enable_interrupts() {
+    let uarts = *UARTS.lock();
+    let mut routed = [false; 16];
+
+    let Some(vector) = interrupts::allocate_handler(interrupt) else {
+        log::warn!("serial: no free interrupt vectors, input disabled");
+        return;
+    };
+
+    let apic_id = core!().apic.lock().id();
+
+    for (i, uart) in uarts.iter().enumerate() {
+        let Some(uart) = uart else {
+            continue;
+        };
+
+        // COM1 and COM3, COM2 and COM4 share a line
+        if !core::mem::replace(&mut routed[uart.irq as usize], true) {
+            ioapic::route_isa_irq(uart.irq, vector, apic_id);
+        }
+
+        write(uart.base, IER, IER_RX_AVAILABLE);
+        log::info!(
+            "serial: COM{} at {:#x}, IRQ {}, {} baud",
+            i + 1,
+            uart.base,
+            uart.irq,
+            uart.config.baud
+        );
+    }
+}
+
+initcall!(serial, enable_interrupts, [apic, ioapic]);
+
+fn interrupt(_stack: &mut InterruptStack) {
+    let uarts = UARTS.try_lock().map(|u| *u).unwrap_or([None; 4]);
+
+    for (i, uart) in uarts.iter().enumerate() {
+        let Some(uart) = uart else {
+            continue;
+        };
+
+        // Nobody read the oldest bytes yet, they make room for the new ones
+        while read(uart.base, LSR) & LSR_DATA_READY != 0 {
+            RX[i].force_push(read(uart.base, DATA));
+        }
+    }
+
+    core!().apic.lock().eoi();
+}
+
+/// Changes the line settings of COM`port + 1`, returns whether the port exists
+pub fn configure(port: usize, config: Config) -> bool {
+    let mut uarts = UARTS.lock();
+    let Some(Some(uart)) = uarts.get_mut(port) else {
+        return false;
+    };
+
+    let ier = read(uart.base, IER);
+    configure_uart(uart.base, &config);
+    write(uart.base, IER, ier);
+
+    uart.config = config;
+    true
+}
+
+pub fn present(port: usize) -> bool {
+    UARTS.lock().get(port).is_some_and(|u| u.is_some())
+}
+
+pub fn read_byte(port: usize) -> Option<u8> {
+    RX.get(port)?.pop()
+}
+
+pub fn write_bytes(port: usize, bytes: &[u8]) {
+    let uart = UARTS.lock().get(port).copied().flatten();
+
+    if let Some(uart) = uart {
+        transmit(uart.base, bytes);
+    }
+}
+
+/// Reads the console port directly, for when interrupts don't run anymore
+pub fn poll_console() -> Option<u8> {
+    let base = CONSOLE.load(Ordering::Relaxed);
 
+    if base != 0 && read(base, LSR) & LSR_DATA_READY != 0 {
+        Some(read(base, DATA))
+    } else {
+        None
+    }
+}
+
+/// Sends raw bytes to the port kernel messages go to
+pub fn write_console(bytes: &[u8]) {
+    let base = CONSOLE.load(Ordering::Relaxed);
+
+    if base != 0 {
+        transmit(base, bytes);
+    }
+}
+
+/// Whether kernel messages go to a serial port
+pub fn has_console() -> bool {
+    CONSOLE.load(Ordering::Relaxed) != 0
+}
+
+struct SerialWriter(u16);
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> Result {
+        transmit(self.0, s.as_bytes());
         Ok(())
     }
 }
 
 #[doc(hidden)]
 pub fn _print(args: Arguments) {
-    let _ = SerialWriter::write_fmt(&mut SerialWriter, args);
+    let base = CONSOLE.load(Ordering::Relaxed);
+
+    if base != 0 {
+        let _ = SerialWriter(base).write_fmt(args);
+    }
 }
 
 #[macro_export]
@@ -16,31 +16,86 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::acpi::madt;
+use crate::core_locals::MAX_CORES;
+use crate::error::KError;
 use limine::{LimineSmpInfo, LimineSmpRequest};
 
 static SMP: LimineSmpRequest = LimineSmpRequest::new(0).flags(1);
 
-pub fn init() {
-    let smp = SMP.get_response().get_mut().unwrap();
+/// Starts the application processors, without the bootloader's help we stay on the BSP alone
+pub fn init() -> Result<(), KError> {
+    let smp = SMP
+        .get_response()
+        .get_mut()
+        .ok_or(KError::NotFound("SMP response"))?;
+    let bsp_lapic_id = smp.bsp_lapic_id;
 
+    // The MADT is the authoritative list of processors, only start the ones it knows about
+    let madt_cpus = madt::cpus().count();
+    let use_madt = madt_cpus != 0;
+
+    if !use_madt {
+        log::warn!("No processors found in the MADT, trusting the bootloader");
+    } else if madt_cpus as u64 != smp.cpu_count {
+        log::warn!(
+            "MADT reports {madt_cpus} processors, the bootloader found {}",
+            smp.cpu_count
+        );
+    }
+
+    let mut started = 0;
     for cpu in smp.cpus() {
+        if cpu.lapic_id == bsp_lapic_id {
+            continue;
+        }
+
+        if use_madt && !madt::cpus().any(|lapic| lapic.apic_id == cpu.lapic_id) {
+            log::warn!("Skipping APIC {} which is not in the MADT", cpu.lapic_id);
+            continue;
+        }
+
+        if started + 1 == MAX_CORES {
+            log::warn!("Only {MAX_CORES} cores are supported, leaving the rest parked");
+            break;
+        }
+
         cpu.goto_address = ap_init;
+        started += 1;
     }
+
+    log::info!("Starting {started} application processors");
+    Ok(())
 }
 
+initcall!(
+    smp,
+    init,
+    [apic, cpufreq, cpuidle, monitor, profile, thermal, trace]
+);
+
 extern "C" fn ap_init(info: *const LimineSmpInfo) -> ! {
     let info = unsafe { &*info };
 
     crate::core_locals::init();
     crate::gdt::init();
+    crate::stack::init_core();
     crate::interrupts::init();
 
+    log::info!("Hello from core: {}", info.processor_id);
+    crate::stack::switch(ap_main)
+}
+
+/// The rest of bringing up a core, on a stack of the kernel's own
+extern "C" fn ap_main() -> ! {
     {
         let mut apic = core!().apic.lock();
         apic.enable();
     }
 
-    log::info!("Hello from core: {}", info.processor_id);
+    crate::cpufreq::init_core();
+    crate::thermal::init_core();
+    crate::trace::init_core();
 
-    crate::hcf()
+    crate::idle()
 }
@@ -0,0 +1,84 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::{cpu, hpet};
+
+/// PIT channel 2 drives the speaker
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2, low then high byte, square wave
+const PIT_CHANNEL2_SQUARE_WAVE: u8 = 0b1011_0110;
+const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// Gate of channel 2 and the speaker enable, in the NMI status and control port
+const PORT_B: u16 = 0x61;
+const GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+
+const TONE: u32 = 880;
+const SHORT_MS: u64 = 150;
+const LONG_MS: u64 = 600;
+const GAP_MS: u64 = 150;
+
+/// Rough spins per millisecond, when the HPET can't be used
+const SPINS_PER_MS: u64 = 200_000;
+
+/// Starts a tone at `frequency` Hz, until `stop`
+pub fn start(frequency: u32) {
+    let divisor = (PIT_FREQUENCY / frequency.clamp(19, PIT_FREQUENCY)) as u16;
+
+    unsafe {
+        cpu::outb(PIT_COMMAND, PIT_CHANNEL2_SQUARE_WAVE);
+        cpu::outb(PIT_CHANNEL2, divisor as u8);
+        cpu::outb(PIT_CHANNEL2, (divisor >> 8) as u8);
+
+        let port_b = cpu::inb(PORT_B);
+        cpu::outb(PORT_B, port_b | GATE | SPEAKER_ENABLE);
+    }
+}
+
+pub fn stop() {
+    unsafe {
+        let port_b = cpu::inb(PORT_B);
+        cpu::outb(PORT_B, port_b & !(GATE | SPEAKER_ENABLE));
+    }
+}
+
+fn delay(ms: u64) {
+    if !hpet::try_sleep(ms * 1_000_000) {
+        for _ in 0..ms * SPINS_PER_MS {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+pub fn beep(frequency: u32, ms: u64) {
+    start(frequency);
+    delay(ms);
+    stop();
+}
+
+/// Plays a BIOS style code, `long` long beeps then `short` short ones
+pub fn beep_code(long: u8, short: u8) {
+    let beeps = core::iter::repeat_n(LONG_MS, long as usize)
+        .chain(core::iter::repeat_n(SHORT_MS, short as usize));
+
+    for ms in beeps {
+        beep(TONE, ms);
+        delay(GAP_MS);
+    }
+}
@@ -0,0 +1,48 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::{cmdline, fb_renderer, modules, qoi};
+
+/// What's around the image when it doesn't fill the screen
+const BACKGROUND: u32 = 0xFF_00_00_00;
+
+/// Shows the QOI image passed as a module with `splash` as its command line instead of the
+/// console, until Alt+Escape brings the console back or a panic takes the screen. `nosplash`
+/// skips it
+pub fn init() {
+    if cmdline::flag("nosplash") || !fb_renderer::present() {
+        return;
+    }
+
+    let Some(module) = modules::all()
+        .iter()
+        .find(|module| modules::cmdline(module) == Some("splash"))
+    else {
+        return;
+    };
+
+    let Some(data) = modules::data(module) else {
+        return;
+    };
+
+    match qoi::decode(data, BACKGROUND) {
+        Ok(image) => fb_renderer::show_splash(image, BACKGROUND),
+        Err(e) => log::warn!("splash: can't decode the image: {e:?}"),
+    }
+}
+
+initcall!(splash, init, [fbterm]);
@@ -0,0 +1,280 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::core_locals::{self, MAX_CORES};
+use crate::mm::pmm;
+use crate::sync::PerCpu;
+use crate::time;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+pub const STACK_SIZE: usize = 64 * 1024;
+const STACK_PAGES: usize = STACK_SIZE / 0x1000;
+
+/// What unused stack is filled with, so how deep it ever got can be read back
+const PATTERN: u64 = 0x57AC_57AC_57AC_57AC;
+const CANARY: u64 = 0xCA4A_2D0F_F00D_BEEF;
+
+/// How far above the bottom the canary sits, what's below is left for the panic it turns into
+const SLACK: usize = 8 * 1024;
+
+/// Stacks of a core past this percentage of their usable size get a warning
+const WARN_PERCENT: usize = 75;
+const CHECK_PERIOD_US: u64 = 1_000_000;
+
+/// The kernel stack, the TSS ring 0 stack and the 7 IST stacks
+const SLOTS: usize = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// What the core runs on, handlers of interrupts without an IST stack included
+    Kernel,
+    /// Where interrupts from user mode land
+    Ring0,
+    /// Stacks of the interrupt stack table, from 1 to 7
+    Ist(u8),
+}
+
+/// A kernel stack, painted with `PATTERN` and with a canary `SLACK` bytes above its bottom
+#[derive(Clone, Copy)]
+pub struct Stack {
+    bottom: u64,
+}
+
+/// The bottom of every stack each core registered, 0 for none
+static STACKS: PerCpu<[AtomicU64; SLOTS]> =
+    PerCpu::new([const { [const { AtomicU64::new(0) }; SLOTS] }; MAX_CORES]);
+/// The deepest use each stack was already warned about
+static WARNED: PerCpu<[AtomicUsize; SLOTS]> =
+    PerCpu::new([const { [const { AtomicUsize::new(0) }; SLOTS] }; MAX_CORES]);
+static LAST_CHECK: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_CORES]);
+
+impl Kind {
+    fn slot(self) -> usize {
+        match self {
+            Kind::Kernel => 0,
+            Kind::Ring0 => 1,
+            Kind::Ist(n) => 1 + n as usize,
+        }
+    }
+
+    fn from_slot(slot: usize) -> Kind {
+        match slot {
+            0 => Kind::Kernel,
+            1 => Kind::Ring0,
+            n => Kind::Ist((n - 1) as u8),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Kernel => write!(f, "kernel"),
+            Kind::Ring0 => write!(f, "ring 0"),
+            Kind::Ist(n) => write!(f, "IST{n}"),
+        }
+    }
+}
+
+impl Stack {
+    pub fn new() -> Stack {
+        let bottom = pmm::alloc_nozero(STACK_PAGES).as_hhdm().as_u64();
+        let stack = Stack { bottom };
+
+        let words = bottom as *mut u64;
+        for i in 0..STACK_SIZE / 8 {
+            unsafe { words.add(i).write(PATTERN) };
+        }
+        unsafe { stack.canary().write(CANARY) };
+
+        stack
+    }
+
+    /// The stack a TSS entry pointing at `top` was made from
+    pub fn from_top(top: u64) -> Stack {
+        Stack {
+            bottom: top - STACK_SIZE as u64,
+        }
+    }
+
+    pub fn top(&self) -> u64 {
+        self.bottom + STACK_SIZE as u64
+    }
+
+    fn canary(&self) -> *mut u64 {
+        (self.bottom + SLACK as u64) as *mut u64
+    }
+
+    fn contains(&self, rsp: u64) -> bool {
+        (self.bottom..self.top()).contains(&rsp)
+    }
+
+    pub fn intact(&self) -> bool {
+        unsafe { self.canary().read_volatile() == CANARY }
+    }
+
+    /// The most bytes that were ever in use, going by how much of the pattern got overwritten
+    pub fn high_water(&self) -> usize {
+        let words = self.bottom as *const u64;
+
+        let untouched = (0..STACK_SIZE / 8)
+            .find(|&i| {
+                let address = words.wrapping_add(i);
+                address != self.canary() && unsafe { address.read_volatile() } != PATTERN
+            })
+            .unwrap_or(STACK_SIZE / 8);
+
+        STACK_SIZE - untouched * 8
+    }
+}
+
+/// Records `stack` as the current core's `kind` stack
+pub fn register(kind: Kind, stack: &Stack) {
+    STACKS.get()[kind.slot()].store(stack.bottom, Ordering::Relaxed);
+    WARNED.get()[kind.slot()].store(0, Ordering::Relaxed);
+}
+
+/// Registers the stacks the TSS of the current core points to
+pub fn init_core() {
+    let tss = core!().tss.lock();
+    let (rsp, ist) = (tss.rsp, tss.ist);
+
+    register(Kind::Ring0, &Stack::from_top(rsp[0]));
+    for (i, &top) in ist.iter().enumerate() {
+        register(Kind::Ist(i as u8 + 1), &Stack::from_top(top));
+    }
+}
+
+/// Moves the current core onto a fresh stack and calls `f` on it
+pub fn switch(f: extern "C" fn() -> !) -> ! {
+    let stack = Stack::new();
+    register(Kind::Kernel, &stack);
+
+    // A zero frame pointer ends the chain for backtraces
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "call {f}",
+            top = in(reg) stack.top(),
+            f = in(reg) f,
+            options(noreturn)
+        )
+    }
+}
+
+/// Stacks of `core` registered so far
+fn stacks(core: usize) -> impl Iterator<Item = (Kind, Stack)> {
+    STACKS
+        .of(core)
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, bottom)| match bottom.load(Ordering::Relaxed) {
+            0 => None,
+            bottom => Some((Kind::from_slot(slot), Stack { bottom })),
+        })
+}
+
+/// Panics if the canary of the stack the core is on got overwritten, on every interrupt return
+#[inline]
+pub fn check() {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+
+    let Some((kind, stack)) = stacks(core_locals::id()).find(|(_, stack)| stack.contains(rsp))
+    else {
+        return;
+    };
+
+    if !stack.intact() {
+        // Whatever runs on this stack after the panic shouldn't trip over it again
+        unsafe { stack.canary().write(CANARY) };
+        panic!(
+            "stack overflow: {kind} stack of core {} went past its canary, rsp {rsp:#x}",
+            core_locals::id()
+        );
+    }
+}
+
+/// Checks the current core's stacks for new depths, cheap when it's not time yet
+pub fn update() {
+    let now = time::uptime_us();
+    let last = LAST_CHECK.get();
+    if now.saturating_sub(last.load(Ordering::Relaxed)) < CHECK_PERIOD_US {
+        return;
+    }
+    last.store(now, Ordering::Relaxed);
+
+    let core = core_locals::id();
+    for (kind, stack) in stacks(core) {
+        if !stack.intact() {
+            unsafe { stack.canary().write(CANARY) };
+            panic!("stack overflow: {kind} stack of core {core} went past its canary");
+        }
+
+        let used = stack.high_water();
+        let warned = &WARNED.get()[kind.slot()];
+        if used * 100 > (STACK_SIZE - SLACK) * WARN_PERCENT && used > warned.load(Ordering::Relaxed)
+        {
+            warned.store(used, Ordering::Relaxed);
+            log::warn!(
+                "stack: {kind} stack of core {core} reached {used} of {} bytes",
+                STACK_SIZE - SLACK
+            );
+        }
+    }
+}
+
+/// Calls `stack` with the core, kind, deepest use and intactness of every stack registered
+pub fn for_each(mut stack: impl FnMut(usize, Kind, usize, bool)) {
+    for core in 0..core_locals::cores_online().max(1) {
+        for (kind, registered) in stacks(core) {
+            stack(core, kind, registered.high_water(), registered.intact());
+        }
+    }
+}
+
+/// Logs the deepest use of every stack, for the end of a test run
+pub fn log_usage() {
+    for_each(|core, kind, used, intact| {
+        log::info!(
+            "stack: core {core} {kind}: {used} of {} bytes{}",
+            STACK_SIZE - SLACK,
+            if intact { "" } else { ", canary clobbered" }
+        );
+    });
+}
+
+ktest! {
+    fn high_water_follows_the_deepest_write() {
+        let stack = Stack::new();
+        assert_eq!(stack.high_water(), 0);
+        assert!(stack.intact());
+
+        let words = stack.bottom as *mut u64;
+        unsafe { words.add(STACK_SIZE / 8 - 100).write(0) };
+        assert_eq!(stack.high_water(), 100 * 8);
+
+        unsafe { stack.canary().write(0) };
+        assert!(!stack.intact());
+        assert_eq!(stack.high_water(), 100 * 8);
+
+        pmm::free(crate::mm::VirtAddr::new(stack.bottom).as_phys_hhdm(), STACK_PAGES);
+    }
+}
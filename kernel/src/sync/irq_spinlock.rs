@@ -0,0 +1,144 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::lockdep::{self, Class};
+use crate::cpu;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+/// A spinlock that keeps interrupts off while it's held, for anything an interrupt handler takes
+///
+/// With a plain `Mutex` a handler spinning on a lock its own core holds never gets it back
+pub struct IrqSpinlock<T: ?Sized> {
+    class: Class,
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinlock<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    /// What to restore once the lock is released
+    rflags: u64,
+}
+
+impl<T> IrqSpinlock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> IrqSpinlock<T> {
+        IrqSpinlock {
+            class: Class::new(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let rflags = cpu::save_and_disable_interrupts();
+        lockdep::acquire(&self.class, self.addr());
+
+        IrqSpinlockGuard {
+            lock: self,
+            guard: ManuallyDrop::new(self.inner.lock()),
+            rflags,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let rflags = cpu::save_and_disable_interrupts();
+        let Some(guard) = self.inner.try_lock() else {
+            unsafe { cpu::restore_interrupts(rflags) };
+            return None;
+        };
+        lockdep::acquired(&self.class, self.addr());
+
+        Some(IrqSpinlockGuard {
+            lock: self,
+            guard: ManuallyDrop::new(guard),
+            rflags,
+        })
+    }
+
+    /// Unlocks without a guard, for the panic path to get at locks a dead core held
+    ///
+    /// # Safety
+    /// Whoever held the lock must never touch what it protects again
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock()
+    }
+}
+
+impl<T: Default> Default for IrqSpinlock<T> {
+    #[track_caller]
+    fn default() -> IrqSpinlock<T> {
+        IrqSpinlock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSpinlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.lock.addr());
+
+        // Unlocked before interrupts come back, or a handler could find it still held
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            cpu::restore_interrupts(self.rflags);
+        }
+    }
+}
+
+ktest! {
+    fn interrupts_stay_off_while_held() {
+        let lock = IrqSpinlock::new(0);
+        let rflags = cpu::save_and_disable_interrupts();
+        unsafe { core::arch::asm!("sti", options(nomem, nostack)) };
+
+        let guard = lock.lock();
+        assert_eq!(cpu::rflags() & cpu::RFLAGS_IF, 0);
+        drop(guard);
+        assert_ne!(cpu::rflags() & cpu::RFLAGS_IF, 0);
+
+        unsafe {
+            core::arch::asm!("cli", options(nomem, nostack));
+            cpu::restore_interrupts(rflags);
+        }
+    }
+}
@@ -0,0 +1,558 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::PerCpu;
+use crate::backtrace::{self, Registers};
+use crate::core_locals::MAX_CORES;
+use crate::{cmdline, cpu, interrupts};
+use core::fmt;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{
+    AtomicBool, AtomicPtr, AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
+
+const MAX_CLASSES: usize = 256;
+/// Locks one core can hold at once before the rest go untracked
+const MAX_HELD: usize = 32;
+/// Lock orders that get a stack saved, the graph itself has room for all of them
+const MAX_EDGES: usize = 1024;
+const FRAMES: usize = 12;
+
+/// A class that didn't fit in the table
+const UNTRACKED: u16 = u16::MAX;
+
+/// Taken from interrupt context
+const IN_IRQ: usize = 0;
+/// Taken outside interrupt context with interrupts enabled
+const IRQS_ON: usize = 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Where the locks of a class were created, every `Mutex::new` call site is one
+pub struct Class {
+    location: &'static Location<'static>,
+    /// Index in `LOCATIONS` plus one, zero until the first acquisition
+    id: AtomicU16,
+}
+
+impl Class {
+    #[track_caller]
+    pub const fn new() -> Class {
+        Class {
+            location: Location::caller(),
+            id: AtomicU16::new(0),
+        }
+    }
+
+    fn id(&self) -> Option<usize> {
+        let id = match self.id.load(Ordering::Relaxed) {
+            0 => {
+                let id = register(self.location);
+                self.id.store(id, Ordering::Relaxed);
+                id
+            }
+            id => id,
+        };
+
+        (id != UNTRACKED).then(|| id as usize - 1)
+    }
+}
+
+static LOCATIONS: [AtomicPtr<Location<'static>>; MAX_CLASSES] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CLASSES];
+/// Only ever taken to add a class, lockdep can't check its own lock
+static REGISTER: spin::Mutex<usize> = spin::Mutex::new(0);
+
+/// `AFTER[a]` has bit `b` set once `b` was taken while holding `a`
+static AFTER: [[AtomicU64; MAX_CLASSES / 64]; MAX_CLASSES] =
+    [const { [const { AtomicU64::new(0) }; MAX_CLASSES / 64] }; MAX_CLASSES];
+
+/// Where an order was first seen, `key` is `a << 16 | b` and goes in last
+struct Edge {
+    key: AtomicU32,
+    frames: [AtomicU64; FRAMES],
+}
+
+static EDGES: [Edge; MAX_EDGES] = [const {
+    Edge {
+        key: AtomicU32::new(u32::MAX),
+        frames: [const { AtomicU64::new(0) }; FRAMES],
+    }
+}; MAX_EDGES];
+static EDGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// `IN_IRQ` and `IRQS_ON` bits of every class, with where each was first seen
+static USAGE: [AtomicU8; MAX_CLASSES] = [const { AtomicU8::new(0) }; MAX_CLASSES];
+static USAGE_FRAMES: [[[AtomicU64; FRAMES]; 2]; MAX_CLASSES] =
+    [const { [const { [const { AtomicU64::new(0) }; FRAMES] }; 2] }; MAX_CLASSES];
+
+/// The locks a core holds, only touched by that core with interrupts off
+struct Held {
+    depth: AtomicUsize,
+    locks: [AtomicUsize; MAX_HELD],
+    classes: [AtomicU16; MAX_HELD],
+    /// Set while lockdep itself runs, so what it calls can't recurse into it
+    busy: AtomicBool,
+}
+
+static HELD: PerCpu<Held> = PerCpu::new(
+    [const {
+        Held {
+            depth: AtomicUsize::new(0),
+            locks: [const { AtomicUsize::new(0) }; MAX_HELD],
+            classes: [const { AtomicU16::new(0) }; MAX_HELD],
+            busy: AtomicBool::new(false),
+        }
+    }; MAX_CORES],
+);
+
+enum Violation {
+    /// A core taking a lock it already holds
+    Recursive { class: usize },
+    /// `taking` while holding `held`, when `path` says `held` was taken after `taking` before
+    Inversion {
+        held: usize,
+        taking: usize,
+        path: [u16; 8],
+        len: usize,
+    },
+    /// A class taken both from interrupts and with them enabled, `usage` is the one seen first
+    IrqUnsafe { class: usize, usage: usize },
+}
+
+fn register(location: &'static Location<'static>) -> u16 {
+    let mut count = REGISTER.lock();
+
+    let known = LOCATIONS[..*count]
+        .iter()
+        .position(|known| unsafe { *known.load(Ordering::Relaxed) == *location });
+    if let Some(index) = known {
+        return index as u16 + 1;
+    }
+
+    if *count == MAX_CLASSES {
+        return UNTRACKED;
+    }
+
+    LOCATIONS[*count].store(location as *const _ as *mut _, Ordering::Relaxed);
+    *count += 1;
+    *count as u16
+}
+
+fn location(class: usize) -> &'static Location<'static> {
+    unsafe { &*LOCATIONS[class].load(Ordering::Relaxed) }
+}
+
+fn capture(frames: &[AtomicU64; FRAMES]) {
+    let mut i = 0;
+    backtrace::walk(Registers::current(), |rip| {
+        if i < FRAMES {
+            frames[i].store(rip, Ordering::Relaxed);
+            i += 1;
+        }
+    });
+}
+
+fn after(a: usize, b: usize) -> bool {
+    AFTER[a][b / 64].load(Ordering::Relaxed) & (1 << (b % 64)) != 0
+}
+
+/// Looks for a chain of orders from `from` to `to`, filling `path` with the classes after `from`
+fn reachable(from: usize, to: usize, path: &mut [u16; 8]) -> Option<usize> {
+    let mut parent = [UNTRACKED; MAX_CLASSES];
+    let mut queue = [0u16; MAX_CLASSES];
+    let (mut head, mut tail) = (0, 1);
+    queue[0] = from as u16;
+    parent[from] = from as u16;
+
+    while head < tail {
+        let class = queue[head] as usize;
+        head += 1;
+
+        for next in 0..MAX_CLASSES {
+            if parent[next] != UNTRACKED || !after(class, next) {
+                continue;
+            }
+
+            parent[next] = class as u16;
+            if next == to {
+                let mut chain = [0u16; MAX_CLASSES];
+                let (mut len, mut at) = (0, to);
+                while at != from {
+                    chain[len] = at as u16;
+                    len += 1;
+                    at = parent[at] as usize;
+                }
+
+                let len = len.min(path.len());
+                for (slot, class) in path.iter_mut().zip(chain[..len].iter().rev()) {
+                    *slot = *class;
+                }
+
+                return Some(len);
+            }
+
+            queue[tail] = next as u16;
+            tail += 1;
+        }
+    }
+
+    None
+}
+
+fn check_order(held: &Held, taking: usize, lock: usize, kind: Kind) -> Option<Violation> {
+    let depth = held.depth.load(Ordering::Relaxed).min(MAX_HELD);
+
+    for i in 0..depth {
+        if kind == Kind::Exclusive && held.locks[i].load(Ordering::Relaxed) == lock {
+            return Some(Violation::Recursive { class: taking });
+        }
+    }
+
+    for i in 0..depth {
+        let class = held.classes[i].load(Ordering::Relaxed) as usize;
+        if class == taking || after(class, taking) {
+            continue;
+        }
+
+        let mut path = [0; 8];
+        if let Some(len) = reachable(taking, class, &mut path) {
+            return Some(Violation::Inversion {
+                held: class,
+                taking,
+                path,
+                len,
+            });
+        }
+
+        AFTER[class][taking / 64].fetch_or(1 << (taking % 64), Ordering::Relaxed);
+
+        let slot = EDGE_COUNT.fetch_add(1, Ordering::Relaxed);
+        if let Some(edge) = EDGES.get(slot) {
+            capture(&edge.frames);
+            edge.key
+                .store(((class as u32) << 16) | taking as u32, Ordering::Release);
+        }
+    }
+
+    None
+}
+
+fn check_usage(class: usize, irqs_on: bool) -> Option<Violation> {
+    let usage = if interrupts::in_interrupt() {
+        IN_IRQ
+    } else if irqs_on {
+        IRQS_ON
+    } else {
+        return None;
+    };
+
+    let bits = USAGE[class].load(Ordering::Relaxed);
+    if bits & (1 << usage) == 0 {
+        capture(&USAGE_FRAMES[class][usage]);
+        USAGE[class].fetch_or(1 << usage, Ordering::Relaxed);
+    }
+
+    let other = usage ^ 1;
+    (bits & (1 << other) != 0).then_some(Violation::IrqUnsafe {
+        class,
+        usage: other,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Exclusive,
+    /// A reader, which can take a lock its core already reads
+    Shared,
+    /// A `try_lock` that succeeded, it can't deadlock so it's only recorded as held
+    Try,
+}
+
+fn track(class: &Class, lock: usize, kind: Kind) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let rflags = cpu::save_and_disable_interrupts();
+    let held = HELD.get();
+    if held.busy.swap(true, Ordering::Relaxed) {
+        unsafe { cpu::restore_interrupts(rflags) };
+        return;
+    }
+
+    let mut violation = None;
+    if let Some(id) = class.id() {
+        if kind != Kind::Try {
+            violation = check_order(held, id, lock, kind);
+        }
+        // A `try_lock` in an interrupt gives up instead of spinning on the interrupted holder,
+        // so it doesn't make the class interrupt-taken
+        if kind != Kind::Try || !interrupts::in_interrupt() {
+            violation = violation.or_else(|| check_usage(id, rflags & cpu::RFLAGS_IF != 0));
+        }
+
+        let depth = held.depth.load(Ordering::Relaxed);
+        if depth < MAX_HELD {
+            held.locks[depth].store(lock, Ordering::Relaxed);
+            held.classes[depth].store(id as u16, Ordering::Relaxed);
+        }
+        held.depth.store(depth + 1, Ordering::Relaxed);
+    }
+
+    held.busy.store(false, Ordering::Relaxed);
+    unsafe { cpu::restore_interrupts(rflags) };
+
+    if let Some(violation) = violation {
+        report(violation);
+    }
+}
+
+/// Called before spinning on the lock at `lock`, checks the order it's being taken in
+pub fn acquire(class: &Class, lock: usize) {
+    track(class, lock, Kind::Exclusive);
+}
+
+/// Like `acquire`, for a reader
+pub fn acquire_shared(class: &Class, lock: usize) {
+    track(class, lock, Kind::Shared);
+}
+
+/// Called once a `try_lock` succeeded, which can't deadlock so it only gets recorded as held
+pub fn acquired(class: &Class, lock: usize) {
+    track(class, lock, Kind::Try);
+}
+
+pub fn release(lock: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let rflags = cpu::save_and_disable_interrupts();
+    let held = HELD.get();
+    let depth = held.depth.load(Ordering::Relaxed);
+
+    // Taken before lockdep was on, or by a core that took it too deep to be tracked
+    if let Some(i) = (0..depth.min(MAX_HELD))
+        .rev()
+        .find(|&i| held.locks[i].load(Ordering::Relaxed) == lock)
+    {
+        for j in i..depth.min(MAX_HELD) - 1 {
+            held.locks[j].store(held.locks[j + 1].load(Ordering::Relaxed), Ordering::Relaxed);
+            held.classes[j].store(
+                held.classes[j + 1].load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+        }
+        held.depth.store(depth - 1, Ordering::Relaxed);
+    } else if depth > MAX_HELD {
+        held.depth.store(depth - 1, Ordering::Relaxed);
+    }
+
+    unsafe { cpu::restore_interrupts(rflags) };
+}
+
+/// How many locks the current core holds, for `oops` to forget the ones a dead context left
+pub fn held() -> usize {
+    HELD.get().depth.load(Ordering::Relaxed)
+}
+
+/// Forgets the locks taken since `held()` returned `depth`, they'll never be released
+pub fn forget(depth: usize) {
+    let held = HELD.get();
+    held.depth.store(depth, Ordering::Relaxed);
+    held.busy.store(false, Ordering::Relaxed);
+}
+
+struct Frames<'a>(&'a [AtomicU64; FRAMES]);
+
+impl fmt::Display for Frames<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for frame in self.0 {
+            let rip = frame.load(Ordering::Relaxed);
+            if rip == 0 {
+                break;
+            }
+
+            match backtrace::lookup(rip) {
+                Some((name, offset)) => writeln!(f, "    {rip:#x} {name:#}+{offset:#x}")?,
+                None => writeln!(f, "    {rip:#x} ?")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Every saved order along a path from `from`
+struct Path<'a> {
+    from: usize,
+    path: &'a [u16],
+}
+
+impl fmt::Display for Path<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut previous = self.from;
+        for &class in self.path {
+            let class = class as usize;
+            writeln!(
+                f,
+                "  {} taken while holding {}",
+                location(class),
+                location(previous)
+            )?;
+
+            let key = ((previous as u32) << 16) | class as u32;
+            let count = EDGE_COUNT.load(Ordering::Relaxed).min(MAX_EDGES);
+            match EDGES[..count]
+                .iter()
+                .find(|edge| edge.key.load(Ordering::Acquire) == key)
+            {
+                Some(edge) => write!(f, "{}", Frames(&edge.frames))?,
+                None => writeln!(f, "    (no stack saved)")?,
+            }
+
+            previous = class;
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns lockdep off, it can't be trusted after this, and panics with what was found
+///
+/// The panic handler adds the backtrace of the offending acquisition
+fn report(violation: Violation) -> ! {
+    disable();
+
+    match violation {
+        Violation::Recursive { class } => {
+            panic!(
+                "lockdep: lock from {} taken again by the core holding it",
+                location(class)
+            )
+        }
+        Violation::Inversion {
+            held,
+            taking,
+            path,
+            len,
+        } => panic!(
+            "lockdep: {} taken while holding {}, but the other way around before:\n{}",
+            location(taking),
+            location(held),
+            Path {
+                from: taking,
+                path: &path[..len]
+            }
+        ),
+        Violation::IrqUnsafe { class, usage } => panic!(
+            "lockdep: {} taken in interrupts and with them enabled, {} here:\n{}",
+            location(class),
+            if usage == IN_IRQ {
+                "first in an interrupt"
+            } else {
+                "first with them enabled"
+            },
+            Frames(&USAGE_FRAMES[class][usage])
+        ),
+    }
+}
+
+/// For the panic path, which forces locks open and takes them again
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turns checking on when booted with `lockdep`, or always in `ktest` builds
+pub fn init() {
+    if cfg!(feature = "ktest") || cmdline::flag("lockdep") {
+        log::info!("lockdep: checking lock order and interrupt safety");
+        ENABLED.store(true, Ordering::SeqCst);
+    }
+}
+
+initcall!(lockdep, init, []);
+
+ktest! {
+    fn inversion_is_caught() {
+        use super::Mutex;
+
+        if !enabled() {
+            return;
+        }
+
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        {
+            let _a = a.lock();
+            let _b = b.lock();
+        }
+
+        let result = crate::oops::recoverable("lockdep", || {
+            let _b = b.lock();
+            let _a = a.lock();
+        });
+        assert!(result.is_err());
+
+        // The report turned lockdep off, nothing it found here was real so it can go back on
+        unsafe { b.force_unlock() };
+        ENABLED.store(true, Ordering::SeqCst);
+    }
+
+    fn try_lock_in_interrupt_is_irq_safe() {
+        use super::Mutex;
+        use crate::apic::ICR_SELF;
+        use crate::interrupts::InterruptStack;
+
+        static LOCK: Mutex<()> = Mutex::new(());
+
+        fn handler(_: &mut InterruptStack) {
+            drop(LOCK.try_lock());
+            core!().apic.lock().eoi();
+        }
+
+        if !enabled() {
+            return;
+        }
+
+        let vector = interrupts::allocate_handler(handler).expect("no free vector");
+        unsafe {
+            core!().apic.lock().ipi(0, vector as u32 | ICR_SELF);
+            core::arch::asm!("sti; nop; cli");
+        }
+        interrupts::free_handler(vector);
+
+        // What the idle loop does with the locks drivers only try from their interrupt handlers
+        let result = crate::oops::recoverable("lockdep", || unsafe {
+            core::arch::asm!("sti");
+            drop(LOCK.lock());
+            core::arch::asm!("cli");
+        });
+
+        unsafe { core::arch::asm!("cli") };
+        if result.is_err() {
+            ENABLED.store(true, Ordering::SeqCst);
+        }
+        assert!(result.is_ok(), "try_lock in an interrupt was taken for irq usage");
+    }
+}
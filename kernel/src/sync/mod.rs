@@ -0,0 +1,32 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+mod irq_spinlock;
+pub mod lockdep;
+mod mutex;
+mod per_cpu;
+pub mod rcu;
+mod rwlock;
+
+pub use irq_spinlock::IrqSpinlock;
+pub use mutex::Mutex;
+pub use per_cpu::PerCpu;
+pub use rcu::Rcu;
+pub use rwlock::RwLock;
+
+/// One-time initialization, for what gets set up once and only read afterwards
+pub use spin::{Lazy, Once};
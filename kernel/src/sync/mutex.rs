@@ -0,0 +1,116 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::lockdep::{self, Class};
+use crate::kbench::Bencher;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+/// A spinlock that tells lockdep about every acquisition, what the kernel uses instead of
+/// `spin::Mutex`
+pub struct Mutex<T: ?Sized> {
+    class: Class,
+    inner: spin::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Mutex<T> {
+    /// Every lock created by the same `new` call belongs to the same lockdep class
+    #[track_caller]
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            class: Class::new(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        // Checked before spinning, so a deadlock gets reported instead of hanging the core
+        lockdep::acquire(&self.class, self.addr());
+
+        MutexGuard {
+            mutex: self,
+            guard: self.inner.lock(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        lockdep::acquired(&self.class, self.addr());
+
+        Some(MutexGuard { mutex: self, guard })
+    }
+
+    /// Unlocks without a guard, for the panic path to get at locks a dead core held
+    ///
+    /// # Safety
+    /// Whoever held the lock must never touch what it protects again
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::release(self.mutex.addr());
+    }
+}
+
+kbench! {
+    /// Uncontended, with whatever lockdep costs on this boot
+    fn lock_unlock(b: &mut Bencher) {
+        let mutex = Mutex::new(0u64);
+        b.iter(|| *mutex.lock() += 1);
+    }
+}
@@ -0,0 +1,58 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::core_locals::{self, MAX_CORES};
+
+/// A `T` for every core, each reaching its own through its core locals
+///
+/// It's only `Sync` when `T` is: an interrupt handler can come in while its core is using `get`,
+/// and other cores can get at the copy through `of`
+pub struct PerCpu<T> {
+    cells: [T; MAX_CORES],
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(cells: [T; MAX_CORES]) -> PerCpu<T> {
+        PerCpu { cells }
+    }
+
+    /// The current core's copy, the bootstrap core's until core locals are set up
+    pub fn get(&self) -> &T {
+        &self.cells[core_locals::id()]
+    }
+
+    pub fn of(&self, core: usize) -> &T {
+        &self.cells[core]
+    }
+
+    /// The copies of every core that came online
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.cells[..core_locals::cores_online().clamp(1, MAX_CORES)].iter()
+    }
+}
+
+ktest! {
+    fn get_is_the_current_cores_copy() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static CELLS: PerCpu<AtomicUsize> = PerCpu::new([const { AtomicUsize::new(0) }; MAX_CORES]);
+
+        CELLS.get().fetch_add(1, Ordering::Relaxed);
+        assert_eq!(CELLS.of(core_locals::id()).load(Ordering::Relaxed), 1);
+        assert_eq!(CELLS.iter().map(|cell| cell.load(Ordering::Relaxed)).sum::<usize>(), 1);
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Mutex, PerCpu};
+use crate::core_locals::{self, MAX_CORES};
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+/// Where a core is with its read-side sections
+struct Reader {
+    nesting: AtomicUsize,
+    /// Bumped every time the outermost section ends
+    quiescent: AtomicU64,
+}
+
+static READERS: PerCpu<Reader> = PerCpu::new(
+    [const {
+        Reader {
+            nesting: AtomicUsize::new(0),
+            quiescent: AtomicU64::new(0),
+        }
+    }; MAX_CORES],
+);
+
+/// Data read without locks and replaced as a whole, for what's read on every interrupt but
+/// rarely changes
+///
+/// Readers never wait. Writers copy the current value, change the copy, publish it and wait
+/// for a grace period, after which no core can still be reading the old one
+pub struct Rcu<T> {
+    /// What readers see until the first update, so a `static` doesn't need the heap
+    initial: T,
+    current: AtomicPtr<T>,
+    writer: Mutex<()>,
+}
+
+/// A read-side section, the value it points to stays alive until it's dropped
+///
+/// Sections can nest and run in interrupt handlers, but must not wait on anything a writer could
+/// be holding while it waits for the grace period
+pub struct RcuReadGuard<'a, T> {
+    value: &'a T,
+    /// Has to end on the core it began on
+    _core: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+unsafe impl<T: Send> Send for Rcu<T> {}
+
+fn read_lock() {
+    READERS.get().nesting.fetch_add(1, Ordering::SeqCst);
+}
+
+fn read_unlock() {
+    let reader = READERS.get();
+    if reader.nesting.fetch_sub(1, Ordering::SeqCst) == 1 {
+        reader.quiescent.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits until every core left the read-side sections it was in, any that start later see
+/// whatever was published before this was called
+///
+/// Can't be called from inside a read-side section, that one would never end
+pub fn synchronize() {
+    let current = core_locals::id();
+    assert_eq!(
+        READERS.get().nesting.load(Ordering::SeqCst),
+        0,
+        "rcu: synchronize inside a read-side section"
+    );
+
+    for (core, reader) in READERS.iter().enumerate() {
+        if core == current {
+            continue;
+        }
+
+        let seen = reader.quiescent.load(Ordering::SeqCst);
+        while reader.nesting.load(Ordering::SeqCst) != 0
+            && reader.quiescent.load(Ordering::SeqCst) == seen
+        {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<T> Rcu<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Rcu<T> {
+        Rcu {
+            initial: value,
+            current: AtomicPtr::new(ptr::null_mut()),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        read_lock();
+
+        let current = self.current.load(Ordering::SeqCst);
+        RcuReadGuard {
+            value: if current.is_null() {
+                &self.initial
+            } else {
+                unsafe { &*current }
+            },
+            _core: PhantomData,
+        }
+    }
+
+    /// Publishes a copy of the value changed by `f`, then frees the old one once no reader can
+    /// see it anymore
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R
+    where
+        T: Clone,
+    {
+        let _writer = self.writer.lock();
+
+        let mut value = {
+            let current = self.current.load(Ordering::SeqCst);
+            if current.is_null() {
+                self.initial.clone()
+            } else {
+                unsafe { (*current).clone() }
+            }
+        };
+        let ret = f(&mut value);
+
+        let old = self
+            .current
+            .swap(Box::into_raw(Box::new(value)), Ordering::SeqCst);
+        synchronize();
+
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+
+        ret
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let current = *self.current.get_mut();
+        if !current.is_null() {
+            drop(unsafe { Box::from_raw(current) });
+        }
+    }
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        read_unlock();
+    }
+}
+
+ktest! {
+    fn readers_see_updates() {
+        let value = Rcu::new(1);
+        assert_eq!(*value.read(), 1);
+
+        let old = value.update(|value| core::mem::replace(value, 2));
+        assert_eq!(old, 1);
+        assert_eq!(*value.read(), 2);
+
+        {
+            let outer = value.read();
+            let inner = value.read();
+            assert_eq!(*outer + *inner, 4);
+        }
+        assert_eq!(READERS.get().nesting.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,296 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::fs::file::{Handle, OpenFlags, Whence};
+use crate::fs::{DirEntry, Error, FileType, Metadata};
+use crate::interrupts::InterruptStack;
+use crate::{cpu, process};
+use alloc::string::String;
+
+/// Syscalls come in through `int` on this vector, the number in rax and the arguments in rdi, rsi,
+/// rdx and r10. rax comes back with the result, or an error code below zero
+///
+/// The gate is kernel only, pointers are taken as they are until there's a user space to check
+/// them against
+pub const VECTOR: usize = 0x80;
+
+pub const OPEN: u64 = 0;
+pub const READ: u64 = 1;
+pub const WRITE: u64 = 2;
+pub const CLOSE: u64 = 3;
+pub const SEEK: u64 = 4;
+pub const STAT: u64 = 5;
+pub const READDIR: u64 = 6;
+
+/// Error `n` comes back as `-(n + 1)`
+const ERRORS: [Error; 12] = [
+    Error::NotFound,
+    Error::NotDirectory,
+    Error::IsDirectory,
+    Error::Exists,
+    Error::NotEmpty,
+    Error::ReadOnly,
+    Error::PermissionDenied,
+    Error::InvalidPath,
+    Error::InvalidArgument,
+    Error::BadHandle,
+    Error::Unsupported,
+    Error::Io,
+];
+
+const FILE_TYPES: [FileType; 4] = [
+    FileType::File,
+    FileType::Directory,
+    FileType::Symlink,
+    FileType::Other,
+];
+
+/// What `STAT` fills in, `kind` indexes `FILE_TYPES`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Stat {
+    pub kind: u32,
+    pub mode: u32,
+    pub size: u64,
+    pub inode: u64,
+}
+
+/// Names longer than this get cut short by `READDIR`
+pub const NAME_MAX: usize = 255;
+
+/// What `READDIR` fills in
+#[repr(C)]
+pub struct Dirent {
+    pub kind: u32,
+    pub len: u32,
+    pub name: [u8; NAME_MAX],
+}
+
+fn kind(kind: FileType) -> u32 {
+    FILE_TYPES.iter().position(|&k| k == kind).unwrap() as u32
+}
+
+pub fn handler(stack: &mut InterruptStack) {
+    // The gate cleared the interrupt flag, but a syscall can wait on a device like any other code
+    unsafe { cpu::restore_interrupts(stack.rflags) };
+    let result = dispatch(stack.rax, [stack.rdi, stack.rsi, stack.rdx, stack.r10]);
+    cpu::save_and_disable_interrupts();
+
+    stack.rax = match result {
+        Ok(value) => value,
+        Err(err) => {
+            let code = ERRORS.iter().position(|&e| e == err).unwrap() as i64;
+            -(code + 1) as u64
+        }
+    };
+}
+
+fn dispatch(number: u64, args: [u64; 4]) -> Result<u64, Error> {
+    let handle = args[0] as Handle;
+
+    process::with_current(|process| {
+        let mut files = process.files.lock();
+
+        match number {
+            OPEN => {
+                let path =
+                    unsafe { core::slice::from_raw_parts(args[0] as *const u8, args[1] as usize) };
+                let path = core::str::from_utf8(path).map_err(|_| Error::InvalidPath)?;
+
+                files
+                    .open(path, OpenFlags::from_bits(args[2] as u32))
+                    .map(|handle| handle as u64)
+            }
+            READ => {
+                let buffer = unsafe {
+                    core::slice::from_raw_parts_mut(args[1] as *mut u8, args[2] as usize)
+                };
+                files.read(handle, buffer).map(|read| read as u64)
+            }
+            WRITE => {
+                let buffer =
+                    unsafe { core::slice::from_raw_parts(args[1] as *const u8, args[2] as usize) };
+                files.write(handle, buffer).map(|written| written as u64)
+            }
+            CLOSE => files.close(handle).map(|()| 0),
+            SEEK => {
+                let whence = match args[2] {
+                    0 => Whence::Start,
+                    1 => Whence::Current,
+                    2 => Whence::End,
+                    _ => return Err(Error::InvalidArgument),
+                };
+
+                files.seek(handle, args[1] as i64, whence)
+            }
+            STAT => {
+                let metadata = files.stat(handle)?;
+                let stat = Stat {
+                    kind: kind(metadata.kind),
+                    mode: metadata.mode,
+                    size: metadata.size,
+                    inode: metadata.inode,
+                };
+
+                unsafe { (args[1] as *mut Stat).write(stat) };
+                Ok(0)
+            }
+            READDIR => {
+                let Some(entry) = files.readdir(handle)? else {
+                    return Ok(0);
+                };
+
+                let len = entry.name.len().min(NAME_MAX);
+                let dirent = unsafe { &mut *(args[1] as *mut Dirent) };
+                dirent.kind = kind(entry.kind);
+                dirent.len = len as u32;
+                dirent.name[..len].copy_from_slice(&entry.name.as_bytes()[..len]);
+
+                Ok(1)
+            }
+            _ => Err(Error::Unsupported),
+        }
+    })
+}
+
+/// Makes syscall `number` the way a process would
+fn call(number: u64, args: [u64; 4]) -> Result<u64, Error> {
+    let result: i64;
+
+    unsafe {
+        core::arch::asm!(
+            "int {vector}",
+            vector = const VECTOR,
+            inlateout("rax") number as i64 => result,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+        );
+    }
+
+    match result {
+        0.. => Ok(result as u64),
+        _ => Err(ERRORS[(-result - 1) as usize]),
+    }
+}
+
+pub fn open(path: &str, flags: OpenFlags) -> Result<Handle, Error> {
+    let args = [
+        path.as_ptr() as u64,
+        path.len() as u64,
+        flags.bits() as u64,
+        0,
+    ];
+    call(OPEN, args).map(|handle| handle as Handle)
+}
+
+pub fn read(handle: Handle, buffer: &mut [u8]) -> Result<usize, Error> {
+    let args = [
+        handle as u64,
+        buffer.as_mut_ptr() as u64,
+        buffer.len() as u64,
+        0,
+    ];
+    call(READ, args).map(|read| read as usize)
+}
+
+pub fn write(handle: Handle, buffer: &[u8]) -> Result<usize, Error> {
+    let args = [
+        handle as u64,
+        buffer.as_ptr() as u64,
+        buffer.len() as u64,
+        0,
+    ];
+    call(WRITE, args).map(|written| written as usize)
+}
+
+pub fn close(handle: Handle) -> Result<(), Error> {
+    call(CLOSE, [handle as u64, 0, 0, 0]).map(|_| ())
+}
+
+pub fn seek(handle: Handle, offset: i64, whence: Whence) -> Result<u64, Error> {
+    call(SEEK, [handle as u64, offset as u64, whence as u64, 0])
+}
+
+pub fn stat(handle: Handle) -> Result<Metadata, Error> {
+    let mut stat = Stat::default();
+    call(STAT, [handle as u64, &mut stat as *mut Stat as u64, 0, 0])?;
+
+    Ok(Metadata {
+        kind: FILE_TYPES[stat.kind as usize],
+        mode: stat.mode,
+        size: stat.size,
+        inode: stat.inode,
+    })
+}
+
+/// The next entry of the directory behind `handle`, `None` once they are over
+pub fn readdir(handle: Handle) -> Result<Option<DirEntry>, Error> {
+    let mut dirent = Dirent {
+        kind: 0,
+        len: 0,
+        name: [0; NAME_MAX],
+    };
+
+    if call(
+        READDIR,
+        [handle as u64, &mut dirent as *mut Dirent as u64, 0, 0],
+    )? == 0
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(DirEntry {
+        name: String::from_utf8_lossy(&dirent.name[..dirent.len as usize]).into_owned(),
+        kind: FILE_TYPES[dirent.kind as usize],
+    }))
+}
+
+ktest! {
+    fn files_through_syscalls() {
+        use crate::fs::{self, tmpfs::TmpFs};
+        use alloc::sync::Arc;
+
+        fs::mount("/ktest-syscall", Arc::new(TmpFs::new())).unwrap();
+        let previous = process::switch(Some(Arc::new(process::Process::new())));
+
+        let flags = OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE;
+        let file = open("/ktest-syscall/file", flags).unwrap();
+        assert_eq!(write(file, b"hello world").unwrap(), 11);
+        assert_eq!(seek(file, 6, Whence::Start).unwrap(), 6);
+
+        let mut buffer = [0; 16];
+        assert_eq!(read(file, &mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"world");
+        assert_eq!(stat(file).unwrap().size, 11);
+
+        let dir = open("/ktest-syscall", OpenFlags::READ | OpenFlags::DIRECTORY).unwrap();
+        assert_ne!(dir, file);
+        let entry = readdir(dir).unwrap().unwrap();
+        assert_eq!((entry.name.as_str(), entry.kind), ("file", FileType::File));
+        assert!(readdir(dir).unwrap().is_none());
+
+        assert_eq!(close(dir), Ok(()));
+        assert_eq!(close(file), Ok(()));
+        assert_eq!(close(file), Err(Error::BadHandle));
+        assert_eq!(call(u64::MAX, [0; 4]), Err(Error::Unsupported));
+
+        process::switch(previous);
+        fs::unmount("/ktest-syscall").unwrap();
+    }
+}
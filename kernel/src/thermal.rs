@@ -0,0 +1,215 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::cpu;
+use crate::fs::kernelfs;
+use crate::interrupts::{self, InterruptStack};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+const IA32_THERM_INTERRUPT: u32 = 0x19B;
+const IA32_THERM_STATUS: u32 = 0x19C;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+const IA32_PACKAGE_THERM_INTERRUPT: u32 = 0x1B2;
+
+/// Thermal status bits, the log bits are sticky until cleared by writing a zero
+const THERMAL_STATUS: u64 = 1 << 0;
+const THERMAL_LOG: u64 = 1 << 1;
+const PROCHOT_LOG: u64 = 1 << 3;
+const CRITICAL_STATUS: u64 = 1 << 4;
+const CRITICAL_LOG: u64 = 1 << 5;
+const READING_VALID: u64 = 1 << 31;
+
+/// Interrupt on high and low temperature crossings, PROCHOT# and the critical temperature
+const THERMAL_INTERRUPTS: u64 = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 4;
+
+/// Used when the processor doesn't report its TjMax
+const DEFAULT_TJ_MAX: u64 = 100;
+
+/// "GenuineIntel", as cpuid leaf 0 returns it in ebx, edx and ecx
+const VENDOR_INTEL: [u32; 3] = [0x756E_6547, 0x4965_6E69, 0x6C65_746E];
+
+static VECTOR: AtomicU8 = AtomicU8::new(0);
+static THROTTLE_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    /// Temperature of the current core in degrees Celsius
+    pub core: Option<u64>,
+    /// Temperature of the current package in degrees Celsius
+    pub package: Option<u64>,
+    /// How many times any core started throttling since boot
+    pub throttle_events: u64,
+}
+
+/// Checks for the digital thermal sensor
+fn dts_supported() -> bool {
+    cpu::cpuid(6).eax & (1 << 0) != 0
+}
+
+/// Checks for the package thermal management MSRs
+fn ptm_supported() -> bool {
+    cpu::cpuid(6).eax & (1 << 6) != 0
+}
+
+/// `MSR_TEMPERATURE_TARGET` is Intel only, reading it elsewhere faults
+fn is_intel() -> bool {
+    let vendor = cpu::cpuid(0);
+    [vendor.ebx, vendor.edx, vendor.ecx] == VENDOR_INTEL
+}
+
+fn tj_max() -> u64 {
+    if !is_intel() {
+        return DEFAULT_TJ_MAX;
+    }
+
+    match unsafe { (cpu::rdmsr(MSR_TEMPERATURE_TARGET) >> 16) & 0xFF } {
+        0 => DEFAULT_TJ_MAX,
+        tj_max => tj_max,
+    }
+}
+
+/// The sensor reports how far below TjMax we are
+fn temperature(status: u64) -> Option<u64> {
+    if status & READING_VALID == 0 {
+        return None;
+    }
+
+    Some(tj_max().saturating_sub((status >> 16) & 0x7F))
+}
+
+pub fn core_temperature() -> Option<u64> {
+    if !dts_supported() {
+        return None;
+    }
+
+    temperature(unsafe { cpu::rdmsr(IA32_THERM_STATUS) })
+}
+
+pub fn package_temperature() -> Option<u64> {
+    if !ptm_supported() {
+        return None;
+    }
+
+    temperature(unsafe { cpu::rdmsr(IA32_PACKAGE_THERM_STATUS) })
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        core: core_temperature(),
+        package: package_temperature(),
+        throttle_events: THROTTLE_EVENTS.load(Ordering::Relaxed),
+    }
+}
+
+/// `/kernel/thermal`, read on whichever core the reader runs on
+fn thermal_file() -> Vec<u8> {
+    let stats = stats();
+    let mut text = String::new();
+
+    if let Some(core) = stats.core {
+        let _ = writeln!(text, "core:     {core} C");
+    }
+    if let Some(package) = stats.package {
+        let _ = writeln!(text, "package:  {package} C");
+    }
+    let _ = writeln!(text, "tj_max:   {} C", tj_max());
+    let _ = writeln!(text, "throttle: {}", stats.throttle_events);
+
+    text.into_bytes()
+}
+
+/// Logs and acknowledges the events recorded in a thermal status MSR
+fn check_status(msr: u32, what: &str) {
+    let status = unsafe { cpu::rdmsr(msr) };
+    let temperature = temperature(status).unwrap_or(0);
+
+    if status & THERMAL_LOG != 0 {
+        if status & THERMAL_STATUS != 0 {
+            THROTTLE_EVENTS.fetch_add(1, Ordering::Relaxed);
+            log::warn!("{what} above threshold, throttling ({temperature} C)");
+        } else {
+            log::info!("{what} back to normal ({temperature} C)");
+        }
+    }
+
+    if status & PROCHOT_LOG != 0 {
+        log::warn!("{what}: PROCHOT# asserted ({temperature} C)");
+    }
+
+    if status & CRITICAL_STATUS != 0 && status & CRITICAL_LOG != 0 {
+        log::error!("{what} reached the critical temperature ({temperature} C)");
+    }
+
+    unsafe { cpu::wrmsr(msr, status & !(THERMAL_LOG | PROCHOT_LOG | CRITICAL_LOG)) };
+}
+
+fn thermal_handler(_stack: &mut InterruptStack) {
+    check_status(IA32_THERM_STATUS, "Core");
+    if ptm_supported() {
+        check_status(IA32_PACKAGE_THERM_STATUS, "Package");
+    }
+
+    core!().apic.lock().eoi();
+}
+
+/// Enables thermal interrupts on the current core, `init` must have run first
+pub fn init_core() {
+    let vector = VECTOR.load(Ordering::Relaxed);
+    if vector == 0 {
+        return;
+    }
+
+    unsafe {
+        let interrupt = cpu::rdmsr(IA32_THERM_INTERRUPT);
+        cpu::wrmsr(IA32_THERM_INTERRUPT, interrupt | THERMAL_INTERRUPTS);
+
+        if ptm_supported() {
+            let interrupt = cpu::rdmsr(IA32_PACKAGE_THERM_INTERRUPT);
+            cpu::wrmsr(IA32_PACKAGE_THERM_INTERRUPT, interrupt | THERMAL_INTERRUPTS);
+        }
+    }
+
+    core!().apic.lock().set_thermal_vector(vector);
+}
+
+pub fn init() {
+    log::trace!("Initializing thermal monitoring");
+
+    if !dts_supported() {
+        log::info!("No digital thermal sensor");
+        return;
+    }
+
+    let vector = interrupts::allocate_handler(thermal_handler).expect("No free interrupt vectors");
+    VECTOR.store(vector, Ordering::Relaxed);
+
+    init_core();
+    kernelfs::register("thermal", thermal_file);
+
+    log::info!(
+        "Core at {} C, package at {} C, TjMax {} C",
+        core_temperature().unwrap_or(0),
+        package_temperature().unwrap_or(0),
+        tj_max()
+    );
+}
+
+initcall!(thermal, init, [apic]);
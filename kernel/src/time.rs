@@ -0,0 +1,121 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::{cpu, hpet};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The TSC when the kernel started, times since boot count from here
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+/// TSC ticks per microsecond, 0 until calibrated
+static TSC_PER_US: AtomicU64 = AtomicU64::new(0);
+
+/// PIT channel 2, the one whose output can be read back, for calibrating without a HPET
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2, low then high byte, interrupt on terminal count
+const PIT_CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// Gate of channel 2, speaker enable and output of channel 2 in the NMI status and control port
+const PORT_B: u16 = 0x61;
+const GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const PIT_OUTPUT: u8 = 1 << 5;
+/// How long the PIT gets counted for, short enough for its 16 bit counter
+const PIT_CALIBRATION_MS: u64 = 10;
+
+/// TSC ticks since boot, good from the first instruction on
+pub fn ticks() -> u64 {
+    unsafe { cpu::rdtsc() }.saturating_sub(BOOT_TSC.load(Ordering::Relaxed))
+}
+
+/// TSC ticks per microsecond, as calibrated against the HPET or the PIT
+pub fn tsc_per_us() -> u64 {
+    core::cmp::max(TSC_PER_US.load(Ordering::Relaxed), 1)
+}
+
+/// How long `ticks` TSC ticks are in microseconds, 0 before calibration
+pub fn ticks_to_us(ticks: u64) -> u64 {
+    match TSC_PER_US.load(Ordering::Relaxed) {
+        0 => 0,
+        per_us => ticks / per_us,
+    }
+}
+
+/// Microseconds since boot
+pub fn uptime_us() -> u64 {
+    ticks_to_us(ticks())
+}
+
+pub fn calibrated() -> bool {
+    TSC_PER_US.load(Ordering::Relaxed) != 0
+}
+
+/// Busy waits `nano` nanoseconds on the TSC, which has to be calibrated
+pub fn spin(nano: u64) {
+    let target = ticks() + nano * tsc_per_us() / 1000;
+
+    while ticks() < target {
+        core::hint::spin_loop();
+    }
+}
+
+/// Measures the TSC against the HPET, or the PIT on machines without one
+pub fn calibrate() {
+    let per_us = if hpet::present() {
+        let start = unsafe { cpu::rdtsc() };
+        hpet::sleep(1_000_000);
+        let end = unsafe { cpu::rdtsc() };
+
+        (end - start) / 1000
+    } else {
+        pit_ticks() / (PIT_CALIBRATION_MS * 1000)
+    };
+
+    let per_us = core::cmp::max(per_us, 1);
+    TSC_PER_US.store(per_us, Ordering::Relaxed);
+
+    log::debug!("TSC: {per_us} ticks per us");
+}
+
+initcall!(tsc, calibrate, [hpet]);
+
+/// TSC ticks over `PIT_CALIBRATION_MS`, timed by a one shot on PIT channel 2 with the speaker off
+fn pit_ticks() -> u64 {
+    let count = PIT_FREQUENCY * PIT_CALIBRATION_MS / 1000;
+
+    unsafe {
+        let port_b = cpu::inb(PORT_B);
+        cpu::outb(PORT_B, (port_b & !SPEAKER_ENABLE) | GATE);
+
+        cpu::outb(PIT_COMMAND, PIT_CHANNEL2_ONE_SHOT);
+        cpu::outb(PIT_CHANNEL2, count as u8);
+        cpu::outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+        let start = cpu::rdtsc();
+        while cpu::inb(PORT_B) & PIT_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        let end = cpu::rdtsc();
+
+        cpu::outb(PORT_B, port_b & !(GATE | SPEAKER_ENABLE));
+        end - start
+    }
+}
+
+pub fn init() {
+    BOOT_TSC.store(unsafe { cpu::rdtsc() }, Ordering::Relaxed);
+}
@@ -0,0 +1,450 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::acpi::{self, sdt::SdtHeader, AcpiTable};
+use crate::error::KError;
+use crate::hpet;
+use crate::mm::mmio::{self, Mmio};
+use crate::mm::PhysAddr;
+use crate::random;
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Where the FIFO (TIS) interface lives, the TPM2 table doesn't say
+const TIS_BASE: u64 = 0xFED4_0000;
+/// Registers of locality 0
+const TIS_SIZE: usize = 0x1000;
+
+/// TPM2 table start methods
+const START_METHOD_TIS: u32 = 6;
+const START_METHOD_CRB: u32 = 7;
+
+/// TIS registers of locality 0, and their bits
+const TPM_ACCESS: usize = 0x00;
+const TPM_STS: usize = 0x18;
+const TPM_DATA_FIFO: usize = 0x24;
+const ACCESS_VALID: u8 = 1 << 7;
+const ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+const ACCESS_REQUEST_USE: u8 = 1 << 1;
+const STS_VALID: u32 = 1 << 7;
+const STS_COMMAND_READY: u32 = 1 << 6;
+const STS_GO: u32 = 1 << 5;
+const STS_DATA_AVAIL: u32 = 1 << 4;
+
+/// CRB registers of locality 0, and their bits
+const CRB_LOC_CTRL: usize = 0x08;
+const CRB_LOC_STS: usize = 0x0C;
+const CRB_CTRL_REQ: usize = 0x40;
+const CRB_CTRL_STS: usize = 0x44;
+const CRB_CTRL_START: usize = 0x4C;
+const CRB_CMD_SIZE: usize = 0x58;
+const CRB_CMD_LADDR: usize = 0x5C;
+const CRB_CMD_HADDR: usize = 0x60;
+const CRB_RSP_SIZE: usize = 0x64;
+const CRB_RSP_ADDR: usize = 0x68;
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const LOC_STS_GRANTED: u32 = 1 << 0;
+const CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+const CTRL_STS_ERROR: u32 = 1 << 0;
+
+/// The CRB control area starts at CTRL_REQ
+const CRB_CONTROL_AREA_OFFSET: u64 = 0x40;
+/// Registers up to the end of the control area, the buffers get mappings of their own
+const CRB_SIZE: usize = CRB_RSP_ADDR + 8;
+
+/// Commands and responses start with a tag, their total size and a command or response code
+const HEADER_SIZE: usize = 10;
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_GET_RANDOM: u32 = 0x17B;
+const TPM_SU_CLEAR: u16 = 0;
+const TPM_RC_SUCCESS: u32 = 0;
+const TPM_RC_INITIALIZE: u32 = 0x100;
+
+/// How long to wait on the TPM, in milliseconds, some commands are really slow
+const TIMEOUT_MS: usize = 2000;
+
+static TPM: Mutex<Option<Tpm>> = Mutex::new(None);
+
+#[repr(C, packed)]
+struct Tpm2Table {
+    hdr: SdtHeader,
+    platform_class: u16,
+    _reserved: u16,
+    control_area: u64,
+    start_method: u32,
+}
+
+unsafe impl AcpiTable for Tpm2Table {
+    const SIGNATURE: &'static str = "TPM2";
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    NoTpm,
+    Timeout,
+    /// The response was malformed or didn't fit the buffer
+    BadResponse,
+    /// The TPM returned this response code
+    Tpm(u32),
+}
+
+/// A CRB command or response buffer, the two can share a mapping when they overlap
+struct Buffer {
+    mmio: Arc<Mmio>,
+    offset: usize,
+    size: usize,
+}
+
+enum Interface {
+    Tis,
+    Crb { command: Buffer, response: Buffer },
+}
+
+struct Tpm {
+    interface: Interface,
+    mmio: Mmio,
+}
+
+fn wait(mut done: impl FnMut() -> bool) -> Result<(), Error> {
+    for _ in 0..TIMEOUT_MS {
+        if done() {
+            return Ok(());
+        }
+
+        hpet::sleep(1_000_000);
+    }
+
+    Err(Error::Timeout)
+}
+
+impl Tpm {
+    fn read8(&self, offset: usize) -> u8 {
+        self.mmio.read(offset)
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        self.mmio.write(offset, value)
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        self.mmio.read(offset)
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        self.mmio.write(offset, value)
+    }
+
+    fn request_locality(&self) -> Result<(), Error> {
+        match self.interface {
+            Interface::Tis => {
+                self.write8(TPM_ACCESS, ACCESS_REQUEST_USE);
+                wait(|| {
+                    let access = self.read8(TPM_ACCESS);
+                    access & ACCESS_VALID != 0 && access & ACCESS_ACTIVE_LOCALITY != 0
+                })
+            }
+            Interface::Crb { .. } => {
+                self.write32(CRB_LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+                wait(|| self.read32(CRB_LOC_STS) & LOC_STS_GRANTED != 0)
+            }
+        }
+    }
+
+    fn burst_count(&self) -> usize {
+        ((self.read32(TPM_STS) >> 8) & 0xFFFF) as usize
+    }
+
+    fn submit_tis(&self, command: &[u8]) -> Result<Vec<u8>, Error> {
+        self.write32(TPM_STS, STS_COMMAND_READY);
+        wait(|| self.read32(TPM_STS) & STS_COMMAND_READY != 0)?;
+
+        let mut sent = 0;
+        while sent < command.len() {
+            wait(|| self.burst_count() != 0)?;
+            let burst = core::cmp::min(self.burst_count(), command.len() - sent);
+
+            for &byte in &command[sent..sent + burst] {
+                self.write8(TPM_DATA_FIFO, byte);
+            }
+            sent += burst;
+        }
+
+        self.write32(TPM_STS, STS_GO);
+        wait(|| {
+            let sts = self.read32(TPM_STS);
+            sts & STS_VALID != 0 && sts & STS_DATA_AVAIL != 0
+        })?;
+
+        let mut response = Vec::new();
+        let mut size = HEADER_SIZE;
+
+        while response.len() < size {
+            wait(|| self.burst_count() != 0)?;
+            let burst = core::cmp::min(self.burst_count(), size - response.len());
+
+            for _ in 0..burst {
+                response.push(self.read8(TPM_DATA_FIFO));
+            }
+
+            // Now that we have the header we know how much is left
+            if response.len() >= HEADER_SIZE && size == HEADER_SIZE {
+                size = u32::from_be_bytes(response[2..6].try_into().unwrap()) as usize;
+                if size < HEADER_SIZE {
+                    return Err(Error::BadResponse);
+                }
+            }
+        }
+
+        self.write32(TPM_STS, STS_COMMAND_READY);
+        Ok(response)
+    }
+
+    fn submit_crb(
+        &self,
+        command: &[u8],
+        cmd_buffer: &Buffer,
+        rsp_buffer: &Buffer,
+    ) -> Result<Vec<u8>, Error> {
+        if command.len() > cmd_buffer.size {
+            return Err(Error::BadResponse);
+        }
+
+        self.write32(CRB_CTRL_REQ, CTRL_REQ_CMD_READY);
+        wait(|| self.read32(CRB_CTRL_REQ) & CTRL_REQ_CMD_READY == 0)?;
+
+        cmd_buffer.write(command);
+
+        self.write32(CRB_CTRL_START, 1);
+        wait(|| self.read32(CRB_CTRL_START) == 0)?;
+
+        if self.read32(CRB_CTRL_STS) & CTRL_STS_ERROR != 0 {
+            return Err(Error::BadResponse);
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        rsp_buffer.read(&mut header);
+
+        let size = u32::from_be_bytes(header[2..6].try_into().unwrap()) as usize;
+        if !(HEADER_SIZE..=rsp_buffer.size).contains(&size) {
+            return Err(Error::BadResponse);
+        }
+
+        let mut response = alloc::vec![0u8; size];
+        rsp_buffer.read(&mut response);
+
+        self.write32(CRB_CTRL_REQ, CTRL_REQ_GO_IDLE);
+        Ok(response)
+    }
+
+    fn submit(&self, command: &[u8]) -> Result<Vec<u8>, Error> {
+        match &self.interface {
+            Interface::Tis => self.submit_tis(command),
+            Interface::Crb {
+                command: cmd_buffer,
+                response: rsp_buffer,
+            } => self.submit_crb(command, cmd_buffer, rsp_buffer),
+        }
+    }
+}
+
+impl Buffer {
+    fn write(&self, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.mmio.write(self.offset + i, byte);
+        }
+    }
+
+    fn read(&self, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.mmio.read(self.offset + i);
+        }
+    }
+}
+
+/// Maps the command and response buffers the CRB control area points at
+fn crb_buffers(regs: &Mmio) -> Result<(Buffer, Buffer), KError> {
+    let command =
+        (regs.read::<u32>(CRB_CMD_HADDR) as u64) << 32 | regs.read::<u32>(CRB_CMD_LADDR) as u64;
+    let command = command..command + regs.read::<u32>(CRB_CMD_SIZE) as u64;
+    let response = regs.read::<u64>(CRB_RSP_ADDR);
+    let response = response..response + regs.read::<u32>(CRB_RSP_SIZE) as u64;
+
+    if command.end - command.start < HEADER_SIZE as u64
+        || response.end - response.start < HEADER_SIZE as u64
+    {
+        return Err(KError::Invalid("TPM CRB buffer size"));
+    }
+
+    let map = |range: &Range<u64>| {
+        mmio::map(
+            PhysAddr::new(range.start),
+            (range.end - range.start) as usize,
+        )
+        .map(Arc::new)
+        .ok_or(KError::Invalid("TPM CRB buffers"))
+    };
+    let buffer = |mmio: &Arc<Mmio>, start: u64, range: &Range<u64>| Buffer {
+        mmio: mmio.clone(),
+        offset: (range.start - start) as usize,
+        size: (range.end - range.start) as usize,
+    };
+
+    // Most TPMs use the same buffer both ways
+    if command.start < response.end && response.start < command.end {
+        let whole = command.start.min(response.start)..command.end.max(response.end);
+        let mmio = map(&whole)?;
+
+        Ok((
+            buffer(&mmio, whole.start, &command),
+            buffer(&mmio, whole.start, &response),
+        ))
+    } else {
+        Ok((
+            buffer(&map(&command)?, command.start, &command),
+            buffer(&map(&response)?, response.start, &response),
+        ))
+    }
+}
+
+/// Builds a command without sessions out of its code and parameters
+fn command(code: u32, parameters: &[u8]) -> Vec<u8> {
+    let size = (HEADER_SIZE + parameters.len()) as u32;
+
+    let mut command = Vec::with_capacity(size as usize);
+    command.extend_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+    command.extend_from_slice(&size.to_be_bytes());
+    command.extend_from_slice(&code.to_be_bytes());
+    command.extend_from_slice(parameters);
+
+    command
+}
+
+/// Sends a raw command to the TPM and returns the raw response, header included
+pub fn submit(command: &[u8]) -> Result<Vec<u8>, Error> {
+    TPM.lock().as_ref().ok_or(Error::NoTpm)?.submit(command)
+}
+
+/// Like `submit`, but fails on anything other than `TPM_RC_SUCCESS` and strips the header
+fn execute(code: u32, parameters: &[u8]) -> Result<Vec<u8>, Error> {
+    let response = submit(&command(code, parameters))?;
+
+    match u32::from_be_bytes(response[6..10].try_into().unwrap()) {
+        TPM_RC_SUCCESS => Ok(response[HEADER_SIZE..].to_vec()),
+        rc => Err(Error::Tpm(rc)),
+    }
+}
+
+/// Fills `buffer` with random bytes from the TPM's generator
+pub fn get_random(buffer: &mut [u8]) -> Result<(), Error> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        let wanted = core::cmp::min(buffer.len() - filled, u16::MAX as usize) as u16;
+        let response = execute(TPM_CC_GET_RANDOM, &wanted.to_be_bytes())?;
+
+        // TPM2B_DIGEST: a size followed by that many bytes, possibly fewer than asked
+        let size = u16::from_be_bytes(
+            response
+                .get(..2)
+                .ok_or(Error::BadResponse)?
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        let bytes = response.get(2..2 + size).ok_or(Error::BadResponse)?;
+
+        if bytes.is_empty() {
+            return Err(Error::BadResponse);
+        }
+
+        buffer[filled..filled + bytes.len()].copy_from_slice(bytes);
+        filled += bytes.len();
+    }
+
+    Ok(())
+}
+
+pub fn init() -> Result<(), KError> {
+    log::trace!("Initializing the TPM");
+
+    let Some(table) = acpi::table::<Tpm2Table>() else {
+        log::debug!("No TPM2 table");
+        return Ok(());
+    };
+
+    let (start_method, control_area) = (table.start_method, table.control_area);
+    let tpm = match start_method {
+        START_METHOD_TIS => Tpm {
+            interface: Interface::Tis,
+            mmio: mmio::map(PhysAddr::new(TIS_BASE), TIS_SIZE)
+                .ok_or(KError::Invalid("TPM TIS registers"))?,
+        },
+        START_METHOD_CRB => {
+            // The control area sits at a fixed offset into the CRB, so it can't come before it
+            let base = control_area
+                .checked_sub(CRB_CONTROL_AREA_OFFSET)
+                .ok_or(KError::Invalid("TPM2 control area address"))?;
+            let mmio = mmio::map(PhysAddr::new(base), CRB_SIZE)
+                .ok_or(KError::Invalid("TPM CRB registers"))?;
+            let (command, response) = crb_buffers(&mmio)?;
+
+            Tpm {
+                interface: Interface::Crb { command, response },
+                mmio,
+            }
+        }
+        method => {
+            log::warn!("Unsupported TPM start method {method}");
+            return Err(KError::Unsupported("TPM start method"));
+        }
+    };
+
+    if let Err(e) = tpm.request_locality() {
+        log::warn!("Cannot get locality 0 of the TPM: {e:?}");
+        return Ok(());
+    }
+
+    *TPM.lock() = Some(tpm);
+
+    // The firmware normally started it already
+    match execute(TPM_CC_STARTUP, &TPM_SU_CLEAR.to_be_bytes()) {
+        Ok(_) | Err(Error::Tpm(TPM_RC_INITIALIZE)) => {}
+        Err(e) => log::warn!("TPM2_Startup failed: {e:?}"),
+    }
+
+    let mut seed = [0; 32];
+    match get_random(&mut seed) {
+        Ok(()) => random::add_entropy(&seed, seed.len() * 8),
+        Err(e) => log::warn!("Cannot get randomness from the TPM: {e:?}"),
+    }
+
+    log::info!(
+        "TPM 2.0 over {}",
+        if start_method == START_METHOD_TIS {
+            "TIS"
+        } else {
+            "CRB"
+        }
+    );
+
+    Ok(())
+}
+
+initcall!(tpm, init, [acpi]);
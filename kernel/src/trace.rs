@@ -0,0 +1,292 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::core_locals::{self, MAX_CORES};
+use crate::mm::pmm;
+use crate::sync::PerCpu;
+use crate::utils::Crc32;
+use crate::{cmdline, time};
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+
+/// Bumped whenever the record layout changes, `tools/tracedump` checks it
+const VERSION: u32 = 1;
+
+const BEGIN: &str = "-----BEGIN BERYL TRACE-----";
+/// Followed by the CRC-32 of every line in between and `-----`
+const END: &str = "-----END BERYL TRACE crc32=";
+
+/// Records every core keeps, the oldest get overwritten
+const RING_RECORDS: usize = 8192;
+const RING_PAGES: usize = size_of::<Ring>().div_ceil(0x1000);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Event {
+    /// Vector
+    IrqEntry,
+    /// Vector
+    IrqExit,
+    /// Physical address and pages
+    PmmAlloc,
+    /// Physical address and pages
+    PmmFree,
+    /// Address and size
+    HeapAlloc,
+    /// Address and size
+    HeapFree,
+    /// C-state index
+    IdleEnter,
+    /// Residency in microseconds
+    IdleExit,
+}
+
+/// What `trace=` and `trace start` take, each turning on a few events
+const GROUPS: &[(&str, &[Event])] = &[
+    ("irq", &[Event::IrqEntry, Event::IrqExit]),
+    ("pmm", &[Event::PmmAlloc, Event::PmmFree]),
+    ("heap", &[Event::HeapAlloc, Event::HeapFree]),
+    ("idle", &[Event::IdleEnter, Event::IdleExit]),
+];
+
+/// A trace record as it sits in the ring and goes out in the dump, little endian
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Record {
+    ticks: u64,
+    event: u32,
+    core: u32,
+    args: [u64; 2],
+}
+
+#[repr(C)]
+struct Ring {
+    /// Records ever written, the next goes at `head % RING_RECORDS`
+    head: AtomicU64,
+    records: [Record; RING_RECORDS],
+}
+
+/// One bit per event, checked before anything else so a disabled tracepoint costs a load
+static EVENTS: AtomicU32 = AtomicU32::new(0);
+static RINGS: PerCpu<AtomicPtr<Ring>> =
+    PerCpu::new([const { AtomicPtr::new(ptr::null_mut()) }; MAX_CORES]);
+
+impl Event {
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Records `event` on the current core, if it's enabled
+#[inline]
+pub fn event(event: Event, args: [u64; 2]) {
+    if EVENTS.load(Ordering::Relaxed) & event.bit() != 0 {
+        write(event, args);
+    }
+}
+
+#[inline(never)]
+fn write(event: Event, args: [u64; 2]) {
+    let ring = RINGS.get().load(Ordering::Acquire);
+    if ring.is_null() {
+        return;
+    }
+
+    // Interrupts on this core take their own slot, nobody else writes here
+    let index = unsafe { (*ring).head.fetch_add(1, Ordering::Relaxed) } as usize % RING_RECORDS;
+    unsafe {
+        ptr::addr_of_mut!((*ring).records[index]).write(Record {
+            ticks: time::ticks(),
+            event: event as u32,
+            core: core_locals::id() as u32,
+            args,
+        });
+    }
+}
+
+/// Allocates a ring for the core `ring` belongs to, unless it has one
+fn attach(ring: &AtomicPtr<Ring>) -> Result<(), &'static str> {
+    if !ring.load(Ordering::Acquire).is_null() {
+        return Ok(());
+    }
+
+    let page = pmm::try_alloc(RING_PAGES).ok_or("no memory for the rings")?;
+    let new = page.as_hhdm().as_mut_ptr::<Ring>();
+    unsafe { ptr::addr_of_mut!((*new).head).write(AtomicU64::new(0)) };
+
+    // The core itself and `start` on another one can get here together
+    if ring
+        .compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        pmm::free(page, RING_PAGES);
+    }
+
+    Ok(())
+}
+
+/// Gives a core that just came up a ring, if tracing is on
+pub fn init_core() {
+    if EVENTS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    if let Err(err) = attach(RINGS.get()) {
+        log::warn!("trace: core {}: {err}", core_locals::id());
+    }
+}
+
+/// Parses a comma separated list of groups into event bits, `all` for every one
+fn parse(groups: &str) -> Option<u32> {
+    groups.split(',').try_fold(0, |bits, group| {
+        if group == "all" {
+            return Some(u32::MAX);
+        }
+
+        let (_, events) = GROUPS.iter().find(|(name, _)| *name == group)?;
+        Some(events.iter().fold(bits, |bits, event| bits | event.bit()))
+    })
+}
+
+/// Turns on the events in `groups`, giving every online core a ring
+pub fn start(groups: &str) -> Result<(), &'static str> {
+    let events = parse(groups).ok_or("unknown group")?;
+
+    for ring in RINGS.iter() {
+        attach(ring)?;
+    }
+
+    EVENTS.fetch_or(events, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn stop() {
+    EVENTS.store(0, Ordering::SeqCst);
+}
+
+pub fn clear() {
+    for ring in RINGS.iter() {
+        let ring = ring.load(Ordering::Acquire);
+        if !ring.is_null() {
+            unsafe { (*ring).head.store(0, Ordering::SeqCst) };
+        }
+    }
+}
+
+/// Turns `out` into CRLF lines, checksumming them with bare LFs like the crash dump does
+struct Framed<'a, W: Write> {
+    out: &'a mut W,
+    crc: Crc32,
+}
+
+impl<W: Write> Write for Framed<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.crc.update(s.as_bytes());
+
+        for line in s.split_inclusive('\n') {
+            match line.strip_suffix('\n') {
+                Some(text) => write!(self.out, "{text}\r\n")?,
+                None => self.out.write_str(line)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes every core's ring to `out`, oldest record first, for `tools/tracedump`
+///
+/// Tracing should be stopped first, records written meanwhile can come out torn
+pub fn dump(out: &mut impl Write) -> fmt::Result {
+    write!(out, "{BEGIN}\r\n")?;
+
+    let mut framed = Framed {
+        out,
+        crc: Crc32::new(),
+    };
+    writeln!(framed, "version {VERSION}")?;
+    writeln!(framed, "tsc_per_us {}", time::tsc_per_us())?;
+
+    for (core, ring) in RINGS.iter().enumerate() {
+        let ring = ring.load(Ordering::Acquire);
+        if ring.is_null() {
+            continue;
+        }
+
+        let head = unsafe { (*ring).head.load(Ordering::SeqCst) } as usize;
+        let count = head.min(RING_RECORDS);
+        writeln!(framed, "core {core} {count} {}", head - count)?;
+
+        for i in head - count..head {
+            let record = unsafe { ptr::addr_of!((*ring).records[i % RING_RECORDS]).read() };
+            let bytes: [u8; size_of::<Record>()] = unsafe { core::mem::transmute(record) };
+
+            for byte in bytes {
+                write!(framed, "{byte:02x}")?;
+            }
+            writeln!(framed)?;
+        }
+    }
+
+    let crc = framed.crc.finish();
+    write!(out, "{END}{crc:08x}-----\r\n")
+}
+
+/// Starts tracing from boot with `trace=irq,heap`, or `trace` for every event
+pub fn init() {
+    let groups = match cmdline::value("trace") {
+        Some(groups) => groups,
+        None if cmdline::flag("trace") => "all",
+        None => return,
+    };
+
+    match start(groups) {
+        Ok(()) => log::info!("trace: recording {groups}"),
+        Err(err) => log::warn!("trace: {err}"),
+    }
+}
+
+initcall!(trace, init, []);
+
+ktest! {
+    fn events_land_in_the_ring() {
+        use crate::cpu;
+
+        let before = EVENTS.swap(Event::IrqEntry.bit(), Ordering::SeqCst);
+        start("irq").unwrap();
+
+        // A timer interrupt would put an `IrqEntry` of its own in the ring
+        let rflags = cpu::save_and_disable_interrupts();
+        let ring = RINGS.get().load(Ordering::Acquire);
+        let head = unsafe { (*ring).head.load(Ordering::SeqCst) };
+        event(Event::IrqEntry, [0x42, 0]);
+        event(Event::HeapAlloc, [0, 0]);
+
+        let after = unsafe { (*ring).head.load(Ordering::SeqCst) };
+        let record =
+            unsafe { ptr::addr_of!((*ring).records[head as usize % RING_RECORDS]).read() };
+        unsafe { cpu::restore_interrupts(rflags) };
+
+        assert_eq!(after, head + 1);
+        assert_eq!(record.event, Event::IrqEntry as u32);
+        assert_eq!(record.args[0], 0x42);
+
+        EVENTS.store(before, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::interrupts::InterruptStack;
+use crate::mm::vmm;
+use gimli::{
+    BaseAddresses, CfaRule, EhFrame, EhFrameHdr, EndianSlice, LittleEndian, ParsedEhFrameHdr,
+    Register, RegisterRule, UnwindContext, UnwindContextStorage, UnwindSection, UnwindTableRow,
+    X86_64,
+};
+
+extern "C" {
+    static __eh_frame_hdr_start: u8;
+    static __eh_frame_hdr_end: u8;
+    static __eh_frame_start: u8;
+    static __eh_frame_end: u8;
+}
+
+type Reader = EndianSlice<'static, LittleEndian>;
+
+/// Keeps the rows being evaluated off the heap, which might be what broke
+struct OnStack;
+
+impl UnwindContextStorage<Reader> for OnStack {
+    type Rules = [(Register, RegisterRule<Reader>); 32];
+    type Stack = [UnwindTableRow<Reader, Self>; 4];
+}
+
+/// The state of a frame, as much of it as unwinding could recover
+#[derive(Clone, Copy)]
+pub struct Registers {
+    pub rip: u64,
+    /// The general purpose registers by their DWARF number, rax, rdx, rcx, rbx, rsi, rdi, rbp, rsp
+    /// and then r8 to r15
+    gprs: [Option<u64>; 16],
+}
+
+impl Registers {
+    /// Where the caller is, always inlined so it really is the caller's frame
+    #[inline(always)]
+    pub fn current() -> Registers {
+        let (rip, rsp, rbp): (u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "lea {}, [rip]",
+                "mov {}, rsp",
+                "mov {}, rbp",
+                out(reg) rip,
+                out(reg) rsp,
+                out(reg) rbp,
+            )
+        };
+
+        let mut registers = Registers {
+            rip,
+            gprs: [None; 16],
+        };
+        registers.set(X86_64::RSP, Some(rsp));
+        registers.set(X86_64::RBP, Some(rbp));
+        registers
+    }
+
+    fn get(&self, register: Register) -> Option<u64> {
+        *self.gprs.get(register.0 as usize)?
+    }
+
+    fn set(&mut self, register: Register, value: Option<u64>) {
+        if let Some(slot) = self.gprs.get_mut(register.0 as usize) {
+            *slot = value;
+        }
+    }
+
+    pub fn rsp(&self) -> Option<u64> {
+        self.get(X86_64::RSP)
+    }
+
+    /// The caller's frame going by the frame pointer, for code without unwind info
+    pub fn frame_pointer_caller(&self) -> Option<Registers> {
+        let rbp = self.get(X86_64::RBP)?;
+        if rbp % 8 != 0 {
+            return None;
+        }
+
+        let mut caller = *self;
+        caller.rip = read(rbp + 8)?;
+        caller.set(X86_64::RSP, Some(rbp + 16));
+        caller.set(X86_64::RBP, read(rbp));
+        Some(caller)
+    }
+}
+
+impl From<&InterruptStack> for Registers {
+    fn from(stack: &InterruptStack) -> Registers {
+        let gprs = [
+            stack.rax, stack.rdx, stack.rcx, stack.rbx, stack.rsi, stack.rdi, stack.rbp, stack.rsp,
+            stack.r8, stack.r9, stack.r10, stack.r11, stack.r12, stack.r13, stack.r14, stack.r15,
+        ];
+
+        Registers {
+            rip: stack.rip,
+            gprs: gprs.map(Some),
+        }
+    }
+}
+
+/// Reads a stack slot, unless it isn't mapped
+fn read(addr: u64) -> Option<u64> {
+    if !vmm::readable(addr) || !vmm::readable(addr + 7) {
+        return None;
+    }
+
+    Some(unsafe { (addr as *const u64).read_unaligned() })
+}
+
+/// Walks frames with the `.eh_frame` the linker kept in the kernel
+pub struct Unwinder {
+    hdr: ParsedEhFrameHdr<Reader>,
+    eh_frame: EhFrame<Reader>,
+    bases: BaseAddresses,
+    context: UnwindContext<Reader, OnStack>,
+}
+
+impl Unwinder {
+    pub fn new() -> Option<Unwinder> {
+        let (hdr, eh_frame) = unsafe {
+            let hdr = core::ptr::addr_of!(__eh_frame_hdr_start);
+            let hdr_end = core::ptr::addr_of!(__eh_frame_hdr_end);
+            let eh_frame = core::ptr::addr_of!(__eh_frame_start);
+            let eh_frame_end = core::ptr::addr_of!(__eh_frame_end);
+
+            (
+                core::slice::from_raw_parts(hdr, hdr_end.offset_from(hdr) as usize),
+                core::slice::from_raw_parts(eh_frame, eh_frame_end.offset_from(eh_frame) as usize),
+            )
+        };
+
+        let bases = BaseAddresses::default()
+            .set_eh_frame_hdr(hdr.as_ptr() as u64)
+            .set_eh_frame(eh_frame.as_ptr() as u64);
+        let hdr = EhFrameHdr::new(hdr, LittleEndian).parse(&bases, 8).ok()?;
+        hdr.table()?;
+
+        Some(Unwinder {
+            hdr,
+            eh_frame: EhFrame::new(eh_frame, LittleEndian),
+            bases,
+            context: UnwindContext::new_in(),
+        })
+    }
+
+    /// The caller's frame, `None` without unwind info for `registers.rip`
+    ///
+    /// Only the first frame is exactly at `rip`, the others are at a return address, which is
+    /// already past the call and might be in the next function
+    pub fn step(&mut self, registers: &Registers, first: bool) -> Option<Registers> {
+        let address = match first {
+            true => registers.rip,
+            false => registers.rip.wrapping_sub(1),
+        };
+
+        let row = self
+            .hdr
+            .table()?
+            .unwind_info_for_address(
+                &self.eh_frame,
+                &self.bases,
+                &mut self.context,
+                address,
+                EhFrame::cie_from_offset,
+            )
+            .ok()?;
+
+        let cfa = match *row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => {
+                registers.get(register)?.wrapping_add_signed(offset)
+            }
+            CfaRule::Expression(_) => return None,
+        };
+
+        // Registers without a rule keep their value, the CFA is the caller's stack pointer
+        let mut caller = *registers;
+        caller.set(X86_64::RSP, Some(cfa));
+        let mut rip = None;
+
+        for (register, rule) in row.registers() {
+            let value = match *rule {
+                RegisterRule::SameValue => registers.get(*register),
+                RegisterRule::Offset(offset) => read(cfa.wrapping_add_signed(offset)),
+                RegisterRule::ValOffset(offset) => Some(cfa.wrapping_add_signed(offset)),
+                RegisterRule::Register(other) => registers.get(other),
+                RegisterRule::Constant(value) => Some(value),
+                // Expressions don't show up in the kernel, nothing we can recover otherwise
+                _ => None,
+            };
+
+            match *register {
+                X86_64::RA => rip = value,
+                register => caller.set(register, value),
+            }
+        }
+
+        caller.rip = rip?;
+        Some(caller)
+    }
+}
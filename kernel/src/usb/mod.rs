@@ -0,0 +1,175 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub mod msc;
+mod xhci;
+
+/// Standard requests
+const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// bmRequestType: host to device, standard, endpoint recipient
+const REQUEST_TYPE_ENDPOINT_OUT: u8 = 0x02;
+
+/// Class drivers, tried in order on every interface of a new device
+static CLASS_DRIVERS: &[&ClassDriver] = &[&msc::DRIVER];
+
+static DEVICES: Mutex<Vec<Attached>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The endpoint stalled, it needs clearing before it works again
+    Stall,
+    Timeout,
+    Disconnected,
+    /// The device answered with something that makes no sense
+    Protocol,
+    Io,
+}
+
+/// The setup stage of a control transfer
+#[derive(Clone, Copy, Debug)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Endpoint {
+    /// Number and direction, bit 7 set for IN
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet: u16,
+}
+
+impl Endpoint {
+    pub const TYPE_BULK: u8 = 0b10;
+
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn is_bulk(&self) -> bool {
+        self.attributes & 0b11 == Self::TYPE_BULK
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Interface {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<Endpoint>,
+}
+
+/// A device enumerated and configured by a host controller driver
+pub trait UsbDevice: Send + Sync {
+    fn vendor_id(&self) -> u16;
+
+    fn product_id(&self) -> u16;
+
+    /// Runs a control transfer on the default pipe, returns how many bytes moved
+    fn control(&self, setup: SetupPacket, data: &mut [u8]) -> Result<usize, Error>;
+
+    /// Reads from bulk IN endpoint `endpoint`, returns how many bytes came in
+    fn bulk_in(&self, endpoint: u8, data: &mut [u8]) -> Result<usize, Error>;
+
+    fn bulk_out(&self, endpoint: u8, data: &[u8]) -> Result<usize, Error>;
+
+    /// Clears a halted endpoint, on the device and in the controller's toggle state
+    fn clear_halt(&self, endpoint: u8) -> Result<(), Error> {
+        self.control(
+            SetupPacket {
+                request_type: REQUEST_TYPE_ENDPOINT_OUT,
+                request: REQUEST_CLEAR_FEATURE,
+                value: FEATURE_ENDPOINT_HALT,
+                index: endpoint as u16,
+                length: 0,
+            },
+            &mut [],
+        )
+        .map(|_| ())
+    }
+}
+
+/// Drives one kind of interface, whatever controller the device hangs off
+pub struct ClassDriver {
+    pub name: &'static str,
+    pub matches: fn(&Interface) -> bool,
+    pub attach: fn(&Arc<dyn UsbDevice>, &Interface) -> Result<(), Error>,
+    pub detach: fn(&Arc<dyn UsbDevice>),
+}
+
+struct Attached {
+    device: Arc<dyn UsbDevice>,
+    drivers: Vec<&'static ClassDriver>,
+}
+
+/// Hands a newly configured device to the class drivers, called by host controller drivers
+pub fn attach(device: Arc<dyn UsbDevice>, interfaces: &[Interface]) {
+    let mut drivers = Vec::new();
+
+    for interface in interfaces {
+        let Some(driver) = CLASS_DRIVERS.iter().find(|d| (d.matches)(interface)) else {
+            log::debug!(
+                "usb: {:04x}:{:04x} interface {} ({:02x}/{:02x}/{:02x}) has no driver",
+                device.vendor_id(),
+                device.product_id(),
+                interface.number,
+                interface.class,
+                interface.subclass,
+                interface.protocol
+            );
+            continue;
+        };
+
+        match (driver.attach)(&device, interface) {
+            Ok(()) => drivers.push(*driver),
+            Err(err) => log::warn!(
+                "usb: {} failed on {:04x}:{:04x}: {err:?}",
+                driver.name,
+                device.vendor_id(),
+                device.product_id()
+            ),
+        }
+    }
+
+    DEVICES.lock().push(Attached { device, drivers });
+}
+
+/// Tells the class drivers `device` is gone, called by host controller drivers
+pub fn detach(device: &Arc<dyn UsbDevice>) {
+    let mut devices = DEVICES.lock();
+    let Some(i) = devices.iter().position(|a| Arc::ptr_eq(&a.device, device)) else {
+        return;
+    };
+
+    let attached = devices.remove(i);
+    drop(devices);
+
+    for driver in attached.drivers {
+        (driver.detach)(&attached.device);
+    }
+}
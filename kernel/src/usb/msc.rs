@@ -0,0 +1,472 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{ClassDriver, Error, Interface, SetupPacket, UsbDevice};
+use crate::block::{self, BlockDevice};
+use crate::hpet;
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Mass storage, SCSI transparent command set, bulk-only transport
+const CLASS_MASS_STORAGE: u8 = 0x08;
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+/// Class requests
+const REQUEST_RESET: u8 = 0xFF;
+const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+const REQUEST_TYPE_CLASS_OUT: u8 = 0x21;
+const REQUEST_TYPE_CLASS_IN: u8 = 0xA1;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_SIZE: usize = 31;
+const CBW_DATA_IN: u8 = 1 << 7;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_SIZE: usize = 13;
+
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+
+/// SCSI commands
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8A;
+const SERVICE_ACTION_IN_16: u8 = 0x9E;
+const READ_CAPACITY_16: u8 = 0x10;
+
+const DIRECT_ACCESS_DEVICE: u8 = 0x00;
+const MODE_WRITE_PROTECTED: u8 = 1 << 7;
+
+/// Largest transfer in a single command
+const MAX_TRANSFER: usize = 64 * 1024;
+
+/// Media can take a while to spin up after the device is plugged in
+const READY_ATTEMPTS: usize = 10;
+const READY_DELAY_NS: u64 = 100_000_000;
+
+static LUNS: Mutex<Vec<Arc<Lun>>> = Mutex::new(Vec::new());
+
+pub static DRIVER: ClassDriver = ClassDriver {
+    name: "usb-storage",
+    matches,
+    attach,
+    detach,
+};
+
+enum Data<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+/// Bulk-only transport to one interface, commands go one at a time
+struct Bot {
+    device: Arc<dyn UsbDevice>,
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    tag: u32,
+}
+
+struct Lun {
+    name: String,
+    bot: Arc<Mutex<Bot>>,
+    lun: u8,
+    sectors: u64,
+    sector_size: usize,
+    read_only: bool,
+    /// Past 2 TiB, only the 16 byte commands reach every sector
+    long_lba: bool,
+}
+
+impl Bot {
+    /// Runs `cdb` on `lun`, moving `data` in the direction it asks for
+    fn command(&mut self, lun: u8, cdb: &[u8], data: Data) -> Result<(), Error> {
+        self.tag = self.tag.wrapping_add(1);
+
+        let (len, flags) = match &data {
+            Data::None => (0, 0),
+            Data::In(buffer) => (buffer.len(), CBW_DATA_IN),
+            Data::Out(buffer) => (buffer.len(), 0),
+        };
+
+        let mut cbw = [0u8; CBW_SIZE];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        cbw[12] = flags;
+        cbw[13] = lun;
+        cbw[14] = cdb.len() as u8;
+        cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+
+        if let Err(err) = self.device.bulk_out(self.bulk_out, &cbw) {
+            self.reset_recovery();
+            return Err(err);
+        }
+
+        // A stalled data stage still ends with a status, once the endpoint is cleared
+        let transferred = match data {
+            Data::None => Ok(0),
+            Data::In(buffer) => self.device.bulk_in(self.bulk_in, buffer),
+            Data::Out(buffer) => self.device.bulk_out(self.bulk_out, buffer),
+        };
+
+        match transferred {
+            Err(Error::Stall) => {
+                let endpoint = if flags & CBW_DATA_IN != 0 {
+                    self.bulk_in
+                } else {
+                    self.bulk_out
+                };
+                self.device.clear_halt(endpoint)?;
+            }
+            Err(err) => {
+                self.reset_recovery();
+                return Err(err);
+            }
+            Ok(_) => {}
+        }
+
+        let mut csw = [0u8; CSW_SIZE];
+        let received = match self.device.bulk_in(self.bulk_in, &mut csw) {
+            Err(Error::Stall) => {
+                self.device.clear_halt(self.bulk_in)?;
+                self.device.bulk_in(self.bulk_in, &mut csw)
+            }
+            result => result,
+        };
+
+        let signature = u32::from_le_bytes(csw[0..4].try_into().unwrap());
+        let tag = u32::from_le_bytes(csw[4..8].try_into().unwrap());
+
+        if received != Ok(CSW_SIZE) || signature != CSW_SIGNATURE || tag != self.tag {
+            self.reset_recovery();
+            return Err(Error::Protocol);
+        }
+
+        match csw[12] {
+            CSW_PASSED => Ok(()),
+            CSW_FAILED => Err(Error::Io),
+            _ => {
+                // Phase error, the device lost track of the command
+                self.reset_recovery();
+                Err(Error::Protocol)
+            }
+        }
+    }
+
+    /// Gets the device and both pipes back to a known state
+    fn reset_recovery(&mut self) {
+        let reset = self.device.control(
+            SetupPacket {
+                request_type: REQUEST_TYPE_CLASS_OUT,
+                request: REQUEST_RESET,
+                value: 0,
+                index: self.interface as u16,
+                length: 0,
+            },
+            &mut [],
+        );
+
+        if reset.is_err() {
+            log::warn!("usb-storage: reset recovery failed");
+        }
+
+        let _ = self.device.clear_halt(self.bulk_in);
+        let _ = self.device.clear_halt(self.bulk_out);
+    }
+
+    fn max_lun(&self) -> u8 {
+        let mut max = [0u8];
+        let result = self.device.control(
+            SetupPacket {
+                request_type: REQUEST_TYPE_CLASS_IN,
+                request: REQUEST_GET_MAX_LUN,
+                value: 0,
+                index: self.interface as u16,
+                length: 1,
+            },
+            &mut max,
+        );
+
+        // Single LUN devices are allowed to stall this
+        match result {
+            Ok(1) => max[0].min(15),
+            _ => 0,
+        }
+    }
+
+    /// Waits for the medium, reading the sense data that explains each failure
+    fn wait_ready(&mut self, lun: u8) -> bool {
+        for _ in 0..READY_ATTEMPTS {
+            if self
+                .command(lun, &[TEST_UNIT_READY, 0, 0, 0, 0, 0], Data::None)
+                .is_ok()
+            {
+                return true;
+            }
+
+            let mut sense = [0u8; 18];
+            let _ = self.command(
+                lun,
+                &[REQUEST_SENSE, 0, 0, 0, sense.len() as u8, 0],
+                Data::In(&mut sense),
+            );
+
+            hpet::sleep(READY_DELAY_NS);
+        }
+
+        false
+    }
+
+    /// Returns the number of sectors and their size
+    fn capacity(&mut self, lun: u8) -> Result<(u64, usize), Error> {
+        let mut capacity = [0u8; 8];
+        self.command(
+            lun,
+            &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            Data::In(&mut capacity),
+        )?;
+
+        let last = u32::from_be_bytes(capacity[0..4].try_into().unwrap());
+        let size = u32::from_be_bytes(capacity[4..8].try_into().unwrap());
+        if last != u32::MAX {
+            return Ok((last as u64 + 1, size as usize));
+        }
+
+        let mut capacity = [0u8; 32];
+        let mut cdb = [0u8; 16];
+        cdb[0] = SERVICE_ACTION_IN_16;
+        cdb[1] = READ_CAPACITY_16;
+        cdb[13] = capacity.len() as u8;
+        self.command(lun, &cdb, Data::In(&mut capacity))?;
+
+        let last = u64::from_be_bytes(capacity[0..8].try_into().unwrap());
+        let size = u32::from_be_bytes(capacity[8..12].try_into().unwrap());
+        Ok((last + 1, size as usize))
+    }
+
+    fn write_protected(&mut self, lun: u8) -> bool {
+        let mut mode = [0u8; 4];
+        let result = self.command(
+            lun,
+            &[MODE_SENSE_6, 0, 0x3F, 0, mode.len() as u8, 0],
+            Data::In(&mut mode),
+        );
+
+        // Plenty of sticks don't implement it, assume they are writable
+        result.is_ok() && mode[2] & MODE_WRITE_PROTECTED != 0
+    }
+}
+
+impl Lun {
+    fn transfer_cdb(&self, opcode: u8, lba: u64, blocks: u32) -> ([u8; 16], usize) {
+        let mut cdb = [0u8; 16];
+
+        if self.long_lba {
+            cdb[0] = if opcode == READ_10 { READ_16 } else { WRITE_16 };
+            cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+            cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+            (cdb, 16)
+        } else {
+            cdb[0] = opcode;
+            cdb[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+            cdb[7..9].copy_from_slice(&(blocks as u16).to_be_bytes());
+            (cdb, 10)
+        }
+    }
+
+    fn max_transfer(&self) -> usize {
+        MAX_TRANSFER - MAX_TRANSFER % self.sector_size
+    }
+}
+
+impl BlockDevice for Lun {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), block::Error> {
+        block::check(self, sector, buffer.len())?;
+        let max = self.max_transfer();
+
+        for (i, chunk) in buffer.chunks_mut(max).enumerate() {
+            let lba = sector + (i * max / self.sector_size) as u64;
+            let blocks = (chunk.len() / self.sector_size) as u32;
+            let (cdb, len) = self.transfer_cdb(READ_10, lba, blocks);
+
+            self.bot
+                .lock()
+                .command(self.lun, &cdb[..len], Data::In(chunk))
+                .map_err(|_| block::Error::Io)?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), block::Error> {
+        if self.read_only {
+            return Err(block::Error::ReadOnly);
+        }
+
+        block::check(self, sector, buffer.len())?;
+        let max = self.max_transfer();
+
+        for (i, chunk) in buffer.chunks(max).enumerate() {
+            let lba = sector + (i * max / self.sector_size) as u64;
+            let blocks = (chunk.len() / self.sector_size) as u32;
+            let (cdb, len) = self.transfer_cdb(WRITE_10, lba, blocks);
+
+            self.bot
+                .lock()
+                .command(self.lun, &cdb[..len], Data::Out(chunk))
+                .map_err(|_| block::Error::Io)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), block::Error> {
+        let cdb = [SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        self.bot
+            .lock()
+            .command(self.lun, &cdb, Data::None)
+            .map_err(|_| block::Error::Io)
+    }
+}
+
+/// Reads a space padded INQUIRY string
+fn inquiry_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim().into()
+}
+
+fn matches(interface: &Interface) -> bool {
+    interface.class == CLASS_MASS_STORAGE
+        && interface.subclass == SUBCLASS_SCSI
+        && interface.protocol == PROTOCOL_BULK_ONLY
+}
+
+fn attach(device: &Arc<dyn UsbDevice>, interface: &Interface) -> Result<(), Error> {
+    let bulk = |is_in: bool| {
+        interface
+            .endpoints
+            .iter()
+            .find(|e| e.is_bulk() && e.is_in() == is_in)
+            .map(|e| e.address)
+            .ok_or(Error::Protocol)
+    };
+
+    let mut bot = Bot {
+        device: device.clone(),
+        interface: interface.number,
+        bulk_in: bulk(true)?,
+        bulk_out: bulk(false)?,
+        tag: 0,
+    };
+
+    let mut luns = Vec::new();
+
+    for lun in 0..=bot.max_lun() {
+        let mut inquiry = [0u8; 36];
+        let cdb = [INQUIRY, 0, 0, 0, inquiry.len() as u8, 0];
+        if bot.command(lun, &cdb, Data::In(&mut inquiry)).is_err() {
+            continue;
+        }
+
+        if inquiry[0] & 0x1F != DIRECT_ACCESS_DEVICE {
+            continue;
+        }
+
+        let model = alloc::format!(
+            "{} {}",
+            inquiry_string(&inquiry[8..16]),
+            inquiry_string(&inquiry[16..32])
+        );
+
+        if !bot.wait_ready(lun) {
+            log::info!("usb-storage: {model} LUN {lun} has no medium");
+            continue;
+        }
+
+        let Ok((sectors, sector_size)) = bot.capacity(lun) else {
+            log::warn!("usb-storage: {model} LUN {lun} doesn't report its capacity");
+            continue;
+        };
+
+        if sector_size == 0 || sector_size > MAX_TRANSFER {
+            log::warn!("usb-storage: {model} LUN {lun} has {sector_size} byte sectors");
+            continue;
+        }
+
+        log::info!("usb-storage: {model} LUN {lun}");
+        luns.push((lun, sectors, sector_size, bot.write_protected(lun)));
+    }
+
+    let bot = Arc::new(Mutex::new(bot));
+
+    for (lun, sectors, sector_size, read_only) in luns {
+        let lun = Arc::new(Lun {
+            name: block::next_name("sd"),
+            bot: bot.clone(),
+            lun,
+            sectors,
+            sector_size,
+            read_only,
+            long_lba: sectors > u32::MAX as u64,
+        });
+
+        LUNS.lock().push(lun.clone());
+        block::register(lun);
+    }
+
+    Ok(())
+}
+
+fn detach(device: &Arc<dyn UsbDevice>) {
+    let mut luns = LUNS.lock();
+
+    luns.retain(|lun| {
+        if !Arc::ptr_eq(&lun.bot.lock().device, device) {
+            return true;
+        }
+
+        block::unregister(&lun.name);
+        false
+    });
+}
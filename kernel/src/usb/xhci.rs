@@ -0,0 +1,1039 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{
+    Endpoint, Error, Interface, SetupPacket, UsbDevice, FEATURE_ENDPOINT_HALT,
+    REQUEST_CLEAR_FEATURE, REQUEST_TYPE_ENDPOINT_OUT,
+};
+use crate::driver::{self, Driver, Match, ProbeError};
+use crate::hpet;
+use crate::mm::dma::Dma;
+use crate::mm::mmio::Mmio;
+use crate::mm::vmm::PAGE_SIZE;
+use crate::pci;
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Capability registers
+const CAPLENGTH: usize = 0x00;
+const HCSPARAMS1: usize = 0x04;
+const HCSPARAMS2: usize = 0x08;
+const HCCPARAMS1: usize = 0x10;
+const DBOFF: usize = 0x14;
+const RTSOFF: usize = 0x18;
+
+/// Operational registers, after the capability registers
+const USBCMD: usize = 0x00;
+const USBSTS: usize = 0x04;
+const PAGESIZE: usize = 0x08;
+const CRCR: usize = 0x18;
+const DCBAAP: usize = 0x30;
+const CONFIG: usize = 0x38;
+const PORTSC: usize = 0x400;
+
+/// Interrupter 0 registers, in the runtime registers
+const ERSTSZ: usize = 0x28;
+const ERSTBA: usize = 0x30;
+const ERDP: usize = 0x38;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_RESET: u32 = 1 << 1;
+const USBSTS_HALTED: u32 = 1 << 0;
+const USBSTS_NOT_READY: u32 = 1 << 11;
+const HCCPARAMS1_CONTEXT_64: u32 = 1 << 2;
+const CRCR_CYCLE: u64 = 1 << 0;
+const ERDP_BUSY: u64 = 1 << 3;
+
+const PORTSC_CONNECTED: u32 = 1 << 0;
+const PORTSC_ENABLED: u32 = 1 << 1;
+const PORTSC_RESET: u32 = 1 << 4;
+const PORTSC_POWER: u32 = 1 << 9;
+const PORTSC_RESET_CHANGE: u32 = 1 << 21;
+/// Writing these back as read would disable the port or ack changes we haven't seen
+const PORTSC_WRITE_1_CLEAR: u32 = PORTSC_ENABLED | 0x7F << 17;
+
+/// USB legacy support extended capability, for taking the controller from the firmware
+const EXTENDED_LEGACY: u8 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+/// Leaves the SMI enables off and acks any pending SMI
+const LEGACY_SMI_DISABLE: u32 = 0xE000_0000;
+
+/// TRB types
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_SET_DEQUEUE: u32 = 16;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+
+/// TRB control flags
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_SHORT_INTERRUPT: u32 = 1 << 2;
+const TRB_INTERRUPT: u32 = 1 << 5;
+const TRB_IMMEDIATE: u32 = 1 << 6;
+const TRB_DIRECTION_IN: u32 = 1 << 16;
+
+/// Setup TRB transfer types
+const TRANSFER_NO_DATA: u32 = 0;
+const TRANSFER_OUT: u32 = 2;
+const TRANSFER_IN: u32 = 3;
+
+/// Completion codes
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_STALL: u8 = 6;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+/// Endpoint context types
+const ENDPOINT_BULK_OUT: u32 = 2;
+const ENDPOINT_CONTROL: u32 = 4;
+const ENDPOINT_BULK_IN: u32 = 6;
+/// Retry a failing transaction three times before halting
+const ENDPOINT_ERROR_COUNT: u32 = 3 << 1;
+
+/// Port speeds
+const SPEED_FULL: u32 = 1;
+const SPEED_LOW: u32 = 2;
+const SPEED_HIGH: u32 = 3;
+const SPEED_SUPER: u32 = 4;
+
+/// Standard requests and descriptors
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+const REQUEST_TYPE_DEVICE_IN: u8 = 0x80;
+const REQUEST_TYPE_DEVICE_OUT: u8 = 0x00;
+const DESCRIPTOR_DEVICE: u8 = 1;
+const DESCRIPTOR_CONFIGURATION: u8 = 2;
+const DESCRIPTOR_INTERFACE: u8 = 4;
+const DESCRIPTOR_ENDPOINT: u8 = 5;
+
+const TRB_SIZE: usize = 16;
+/// TRBs per ring, a page worth, the last one links back to the start
+const RING_SIZE: usize = PAGE_SIZE as usize / TRB_SIZE;
+
+/// A TRB's buffer can't cross a 64 KiB boundary
+const TRB_BOUNDARY: u64 = 0x10000;
+
+/// Device context index of the default control endpoint
+const CONTROL_ENDPOINT: usize = 1;
+
+/// How long a command or transfer may take before the controller is considered hung
+const TIMEOUT_US: u64 = 5_000_000;
+
+/// Ports need this long after a reset before they answer
+const RESET_RECOVERY_NS: u64 = 10_000_000;
+
+static CONTROLLERS: Mutex<Vec<Arc<Controller>>> = Mutex::new(Vec::new());
+
+static DRIVER: Driver = Driver {
+    name: "xhci",
+    order: 20,
+    matches: &[Match::PciClass {
+        class: 0x0C,
+        subclass: 0x03,
+        prog_if: Some(0x30),
+    }],
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+#[derive(Clone, Copy, Default)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(kind: u32) -> Trb {
+        Trb {
+            control: kind << 10,
+            ..Trb::default()
+        }
+    }
+
+    fn parameter(mut self, parameter: u64) -> Trb {
+        self.parameter = parameter;
+        self
+    }
+
+    fn status(mut self, status: u32) -> Trb {
+        self.status = status;
+        self
+    }
+
+    fn control(mut self, flags: u32) -> Trb {
+        self.control |= flags;
+        self
+    }
+
+    fn slot(self, slot: u8) -> Trb {
+        self.control((slot as u32) << 24)
+    }
+
+    fn endpoint(self, dci: usize) -> Trb {
+        self.control((dci as u32) << 16)
+    }
+
+    fn kind(&self) -> u32 {
+        (self.control >> 10) & 0x3F
+    }
+
+    fn completion(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// Bytes of a transfer that didn't move
+    fn residual(&self) -> usize {
+        (self.status & 0xFF_FFFF) as usize
+    }
+
+    fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+}
+
+/// A command or transfer ring
+struct Ring {
+    trbs: Dma,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new() -> Ring {
+        let ring = Ring {
+            trbs: Dma::new(RING_SIZE * TRB_SIZE),
+            enqueue: 0,
+            cycle: true,
+        };
+
+        let link = (RING_SIZE - 1) * TRB_SIZE;
+        ring.trbs.write(link, ring.trbs.phys().as_u64());
+        ring.trbs
+            .write(link + 12, TRB_LINK << 10 | TRB_TOGGLE_CYCLE);
+        ring
+    }
+
+    /// Where the controller should pick up, with the cycle state in bit 0
+    fn dequeue(&self) -> u64 {
+        (self.trbs.phys().as_u64() + (self.enqueue * TRB_SIZE) as u64) | self.cycle as u64
+    }
+
+    /// Queues `trb` and returns its physical address, which its completion event points at
+    fn push(&mut self, trb: Trb) -> u64 {
+        let offset = self.enqueue * TRB_SIZE;
+        self.trbs.write(offset, trb.parameter);
+        self.trbs.write(offset + 8, trb.status);
+        // The cycle bit hands the TRB to the controller, so it goes last
+        self.trbs
+            .write(offset + 12, trb.control | self.cycle as u32);
+
+        self.enqueue += 1;
+        if self.enqueue == RING_SIZE - 1 {
+            let link = offset + TRB_SIZE;
+            let control = self.trbs.read::<u32>(link + 12) & !TRB_CYCLE;
+            self.trbs.write(link + 12, control | self.cycle as u32);
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        self.trbs.phys().as_u64() + offset as u64
+    }
+}
+
+/// The event ring of interrupter 0, a single segment
+struct EventRing {
+    trbs: Dma,
+    /// The segment table, with its one entry
+    table: Dma,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn new() -> EventRing {
+        let ring = EventRing {
+            trbs: Dma::new(RING_SIZE * TRB_SIZE),
+            table: Dma::new(16),
+            dequeue: 0,
+            cycle: true,
+        };
+
+        ring.table.write(0, ring.trbs.phys().as_u64());
+        ring.table.write(8, RING_SIZE as u32);
+        ring
+    }
+
+    /// Takes the next event, if the controller wrote one
+    fn pop(&mut self, mmio: &Mmio, interrupter: usize) -> Option<Trb> {
+        let offset = self.dequeue * TRB_SIZE;
+        let control = self.trbs.read::<u32>(offset + 12);
+        if control & TRB_CYCLE != self.cycle as u32 {
+            return None;
+        }
+
+        let event = Trb {
+            parameter: self.trbs.read(offset),
+            status: self.trbs.read(offset + 8),
+            control,
+        };
+
+        self.dequeue += 1;
+        if self.dequeue == RING_SIZE {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+
+        let dequeue = self.trbs.phys().as_u64() + (self.dequeue * TRB_SIZE) as u64;
+        mmio.write(interrupter + ERDP, dequeue | ERDP_BUSY);
+        Some(event)
+    }
+}
+
+/// An input context, contexts are 32 or 64 bytes apart depending on the controller
+struct InputContext {
+    data: Dma,
+    size: usize,
+}
+
+impl InputContext {
+    const CONTROL: usize = 0;
+    const SLOT: usize = 1;
+
+    fn new(size: usize) -> InputContext {
+        InputContext {
+            data: Dma::new(33 * size),
+            size,
+        }
+    }
+
+    fn write(&self, context: usize, dword: usize, value: u32) {
+        self.data.write(context * self.size + dword * 4, value);
+    }
+
+    /// Fills in the context of endpoint `dci` and flags it to be added
+    fn endpoint(&self, dci: usize, kind: u32, max_packet: u16, ring: &Ring) {
+        let context = 1 + dci;
+        let dequeue = ring.dequeue();
+
+        self.write(
+            context,
+            1,
+            ENDPOINT_ERROR_COUNT | kind << 3 | (max_packet as u32) << 16,
+        );
+        self.write(context, 2, dequeue as u32);
+        self.write(context, 3, (dequeue >> 32) as u32);
+        // Average TRB length, only a scheduling hint
+        self.write(context, 4, max_packet as u32);
+        self.add(dci);
+    }
+
+    fn add(&self, dci: usize) {
+        let flags = self.data.read::<u32>(4);
+        self.write(Self::CONTROL, 1, flags | 1 << dci);
+    }
+}
+
+struct Controller {
+    index: usize,
+    device: pci::Device,
+    mmio: Mmio,
+    /// Where the operational, runtime interrupter 0 and doorbell registers start
+    operational: usize,
+    interrupter: usize,
+    doorbells: usize,
+    context_size: usize,
+    /// Device context base address array, entry 0 points at the scratchpad array
+    contexts: Dma,
+    _scratchpad: Vec<Dma>,
+    commands: Mutex<Ring>,
+    events: Mutex<EventRing>,
+    devices: Mutex<Vec<Arc<dyn UsbDevice>>>,
+}
+
+/// A device that got a slot and an address
+struct Slot {
+    controller: Arc<Controller>,
+    id: u8,
+    vendor_id: u16,
+    product_id: u16,
+    /// The output device context, written by the controller
+    _context: Dma,
+    /// Transfer rings by device context index
+    rings: Mutex<Vec<Option<Ring>>>,
+}
+
+impl Controller {
+    fn ring_doorbell(&self, slot: u8, target: usize) {
+        self.mmio
+            .write(self.doorbells + slot as usize * 4, target as u32);
+    }
+
+    /// Takes events until the one for the TRB at `trb`, events for others are dropped
+    fn wait_event(&self, events: &mut EventRing, trb: u64) -> Result<Trb, Error> {
+        let mut found = None;
+        let done = wait(|| {
+            while let Some(event) = events.pop(&self.mmio, self.interrupter) {
+                if event.parameter == trb {
+                    found = Some(event);
+                    return true;
+                }
+            }
+
+            false
+        });
+
+        match (done, found) {
+            (Ok(()), Some(event)) => Ok(event),
+            _ => {
+                log::warn!("xhci{}: TRB {trb:#x} timed out", self.index);
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// Runs `command`, returns its completion event
+    fn command(&self, command: Trb) -> Result<Trb, Error> {
+        let mut commands = self.commands.lock();
+        let trb = commands.push(command);
+
+        let mut events = self.events.lock();
+        self.ring_doorbell(0, 0);
+        let event = self.wait_event(&mut events, trb)?;
+
+        if event.kind() != TRB_COMMAND_COMPLETION || event.completion() != COMPLETION_SUCCESS {
+            log::warn!(
+                "xhci{}: command {} failed with {}",
+                self.index,
+                command.kind(),
+                event.completion()
+            );
+            return Err(Error::Io);
+        }
+
+        Ok(event)
+    }
+
+    fn port(&self, port: usize) -> usize {
+        self.operational + PORTSC + port * 0x10
+    }
+
+    /// Resets `port` unless it's already enabled, as USB 3 ports are once connected
+    fn reset_port(&self, port: usize) -> Result<(), Error> {
+        let register = self.port(port);
+        let status = self.mmio.read::<u32>(register);
+        if status & PORTSC_ENABLED != 0 {
+            return Ok(());
+        }
+
+        self.mmio
+            .write(register, status & !PORTSC_WRITE_1_CLEAR | PORTSC_RESET);
+        wait(|| self.mmio.read::<u32>(register) & PORTSC_RESET_CHANGE != 0)?;
+
+        let status = self.mmio.read::<u32>(register);
+        self.mmio.write(
+            register,
+            status & !PORTSC_WRITE_1_CLEAR | PORTSC_RESET_CHANGE,
+        );
+        hpet::sleep(RESET_RECOVERY_NS);
+
+        match status & PORTSC_ENABLED {
+            0 => Err(Error::Disconnected),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Slot {
+    /// Resets a halted endpoint and moves it past whatever was queued when it stalled
+    fn recover(&self, rings: &[Option<Ring>], dci: usize) -> Result<(), Error> {
+        let ring = rings[dci].as_ref().ok_or(Error::Protocol)?;
+
+        self.controller
+            .command(Trb::new(TRB_RESET_ENDPOINT).slot(self.id).endpoint(dci))?;
+        self.controller.command(
+            Trb::new(TRB_SET_DEQUEUE)
+                .parameter(ring.dequeue())
+                .slot(self.id)
+                .endpoint(dci),
+        )?;
+
+        Ok(())
+    }
+
+    /// Runs a bulk transfer of `len` bytes of `buffer`, stops early on a short packet
+    fn bulk(&self, endpoint: u8, buffer: &Dma, len: usize) -> Result<usize, Error> {
+        let dci = endpoint_index(endpoint);
+        let mut rings = self.rings.lock();
+        let ring = rings
+            .get_mut(dci)
+            .and_then(Option::as_mut)
+            .ok_or(Error::Protocol)?;
+
+        let mut moved = 0;
+        while moved < len {
+            let address = buffer.phys().as_u64() + moved as u64;
+            let piece = (len - moved).min((TRB_BOUNDARY - address % TRB_BOUNDARY) as usize);
+
+            let trb = ring.push(
+                Trb::new(TRB_NORMAL)
+                    .parameter(address)
+                    .status(piece as u32)
+                    .control(TRB_SHORT_INTERRUPT | TRB_INTERRUPT),
+            );
+
+            let mut events = self.controller.events.lock();
+            self.controller.ring_doorbell(self.id, dci);
+            let event = self.controller.wait_event(&mut events, trb)?;
+            check(&event)?;
+
+            let done = piece - event.residual().min(piece);
+            moved += done;
+            if done < piece {
+                break;
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+impl UsbDevice for Slot {
+    fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    fn control(&self, setup: SetupPacket, data: &mut [u8]) -> Result<usize, Error> {
+        // Descriptors and class requests fit in a page, so one data TRB does
+        let len = (setup.length as usize).min(data.len());
+        if len > PAGE_SIZE as usize {
+            return Err(Error::Protocol);
+        }
+
+        let is_in = setup.request_type & 0x80 != 0;
+        let mut buffer = Dma::new(len);
+        if !is_in {
+            buffer.as_mut_slice()[..len].copy_from_slice(&data[..len]);
+        }
+
+        let mut rings = self.rings.lock();
+        let ring = rings[CONTROL_ENDPOINT].as_mut().ok_or(Error::Protocol)?;
+
+        let transfer = match (len, is_in) {
+            (0, _) => TRANSFER_NO_DATA,
+            (_, false) => TRANSFER_OUT,
+            (_, true) => TRANSFER_IN,
+        };
+        let packet = setup.request_type as u64
+            | (setup.request as u64) << 8
+            | (setup.value as u64) << 16
+            | (setup.index as u64) << 32
+            | (len as u64) << 48;
+
+        ring.push(
+            Trb::new(TRB_SETUP)
+                .parameter(packet)
+                .status(8)
+                .control(TRB_IMMEDIATE | transfer << 16),
+        );
+
+        let data_trb = (len > 0).then(|| {
+            ring.push(
+                Trb::new(TRB_DATA)
+                    .parameter(buffer.phys().as_u64())
+                    .status(len as u32)
+                    .control(TRB_SHORT_INTERRUPT | TRB_INTERRUPT)
+                    .control(if is_in { TRB_DIRECTION_IN } else { 0 }),
+            )
+        });
+
+        // The status stage goes the other way from the data
+        let status_trb = ring.push(Trb::new(TRB_STATUS).control(TRB_INTERRUPT).control(
+            if len == 0 || !is_in {
+                TRB_DIRECTION_IN
+            } else {
+                0
+            },
+        ));
+
+        let mut events = self.controller.events.lock();
+        self.controller.ring_doorbell(self.id, CONTROL_ENDPOINT);
+
+        let mut moved = 0;
+        let mut result = Ok(());
+        if let Some(trb) = data_trb {
+            result = self
+                .controller
+                .wait_event(&mut events, trb)
+                .and_then(|event| check(&event))
+                .map(|event| moved = len - event.residual().min(len));
+        }
+
+        let result = result.and_then(|_| {
+            let event = self.controller.wait_event(&mut events, status_trb)?;
+            check(&event).map(|_| ())
+        });
+        drop(events);
+
+        match result {
+            Err(Error::Stall) => {
+                // A stalled request doesn't stick on the device, only on the controller's side
+                self.recover(&rings, CONTROL_ENDPOINT)?;
+                Err(Error::Stall)
+            }
+            Err(err) => Err(err),
+            Ok(()) => {
+                if is_in {
+                    data[..moved].copy_from_slice(&buffer.as_slice()[..moved]);
+                }
+
+                Ok(moved)
+            }
+        }
+    }
+
+    fn bulk_in(&self, endpoint: u8, data: &mut [u8]) -> Result<usize, Error> {
+        let buffer = Dma::new(data.len());
+        let moved = self.bulk(endpoint, &buffer, data.len())?;
+        data[..moved].copy_from_slice(&buffer.as_slice()[..moved]);
+        Ok(moved)
+    }
+
+    fn bulk_out(&self, endpoint: u8, data: &[u8]) -> Result<usize, Error> {
+        let mut buffer = Dma::new(data.len());
+        buffer.as_mut_slice()[..data.len()].copy_from_slice(data);
+        self.bulk(endpoint, &buffer, data.len())
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result<(), Error> {
+        self.recover(&self.rings.lock(), endpoint_index(endpoint))?;
+
+        self.control(
+            SetupPacket {
+                request_type: REQUEST_TYPE_ENDPOINT_OUT,
+                request: REQUEST_CLEAR_FEATURE,
+                value: FEATURE_ENDPOINT_HALT,
+                index: endpoint as u16,
+                length: 0,
+            },
+            &mut [],
+        )
+        .map(|_| ())
+    }
+}
+
+/// Device context index of endpoint `address`, IN endpoints come right after OUT ones
+fn endpoint_index(address: u8) -> usize {
+    (address & 0xF) as usize * 2 + (address & 0x80 != 0) as usize
+}
+
+/// Turns the completion code of a transfer event into an error
+fn check(event: &Trb) -> Result<Trb, Error> {
+    match event.completion() {
+        COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(*event),
+        COMPLETION_STALL => Err(Error::Stall),
+        code => {
+            log::debug!("xhci: transfer failed with {code}");
+            Err(Error::Io)
+        }
+    }
+}
+
+/// Waits for `done`, giving up after `TIMEOUT_US`
+fn wait(mut done: impl FnMut() -> bool) -> Result<(), Error> {
+    for _ in 0..TIMEOUT_US / 10 {
+        if done() {
+            return Ok(());
+        }
+
+        hpet::sleep(10_000);
+    }
+
+    Err(Error::Timeout)
+}
+
+/// Asks the firmware to let go of the controller, it may be using it for legacy keyboard emulation
+fn take_ownership(mmio: &Mmio) {
+    let mut offset = ((mmio.read::<u32>(HCCPARAMS1) >> 16) as usize) << 2;
+
+    while offset != 0 {
+        let capability = mmio.read::<u32>(offset);
+        if capability as u8 == EXTENDED_LEGACY {
+            mmio.write(offset, capability | LEGACY_OS_OWNED);
+            if wait(|| mmio.read::<u32>(offset) & LEGACY_BIOS_OWNED == 0).is_err() {
+                log::warn!("xhci: firmware didn't release the controller");
+            }
+
+            mmio.write(offset + 4, LEGACY_SMI_DISABLE);
+            return;
+        }
+
+        match (capability >> 8) & 0xFF {
+            0 => return,
+            next => offset += (next as usize) << 2,
+        }
+    }
+}
+
+/// Stops and resets the controller
+fn reset(mmio: &Mmio, operational: usize) -> Result<(), Error> {
+    let command = mmio.read::<u32>(operational + USBCMD);
+    mmio.write(operational + USBCMD, command & !USBCMD_RUN);
+    wait(|| mmio.read::<u32>(operational + USBSTS) & USBSTS_HALTED != 0)?;
+
+    mmio.write(operational + USBCMD, USBCMD_RESET);
+    wait(|| {
+        mmio.read::<u32>(operational + USBCMD) & USBCMD_RESET == 0
+            && mmio.read::<u32>(operational + USBSTS) & USBSTS_NOT_READY == 0
+    })
+}
+
+/// Reads a descriptor into `data`, returns how much came back
+fn get_descriptor(device: &Slot, kind: u8, data: &mut [u8]) -> Result<usize, Error> {
+    device.control(
+        SetupPacket {
+            request_type: REQUEST_TYPE_DEVICE_IN,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (kind as u16) << 8,
+            index: 0,
+            length: data.len() as u16,
+        },
+        data,
+    )
+}
+
+/// Splits a configuration descriptor into its interfaces, alternate settings are skipped
+fn parse_configuration(data: &[u8]) -> Vec<Interface> {
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut alternate = false;
+    let mut offset = 0;
+
+    while offset + 2 <= data.len() {
+        let len = data[offset] as usize;
+        if len < 2 || offset + len > data.len() {
+            break;
+        }
+
+        let descriptor = &data[offset..offset + len];
+        match descriptor[1] {
+            DESCRIPTOR_INTERFACE if len >= 9 => {
+                alternate = descriptor[3] != 0;
+                if !alternate {
+                    interfaces.push(Interface {
+                        number: descriptor[2],
+                        class: descriptor[5],
+                        subclass: descriptor[6],
+                        protocol: descriptor[7],
+                        endpoints: Vec::new(),
+                    });
+                }
+            }
+            DESCRIPTOR_ENDPOINT if len >= 7 && !alternate => {
+                if let Some(interface) = interfaces.last_mut() {
+                    interface.endpoints.push(Endpoint {
+                        address: descriptor[2],
+                        attributes: descriptor[3],
+                        max_packet: u16::from_le_bytes([descriptor[4], descriptor[5]]) & 0x7FF,
+                    });
+                }
+            }
+            _ => {}
+        }
+
+        offset += len;
+    }
+
+    interfaces
+}
+
+/// Gives the device on `port` a slot and an address, then configures it
+fn enumerate(controller: &Arc<Controller>, port: usize) -> Result<(), Error> {
+    controller.reset_port(port)?;
+    let speed = (controller.mmio.read::<u32>(controller.port(port)) >> 10) & 0xF;
+
+    let event = controller.command(Trb::new(TRB_ENABLE_SLOT))?;
+    let id = event.slot_id();
+
+    let context = Dma::new(32 * controller.context_size);
+    controller
+        .contexts
+        .write(id as usize * 8, context.phys().as_u64());
+
+    // Full speed devices may use 8 to 64 bytes, the descriptor tells which
+    let max_packet = match speed {
+        SPEED_LOW | SPEED_FULL => 8,
+        SPEED_HIGH => 64,
+        _ => 512,
+    };
+
+    let mut rings: Vec<Option<Ring>> = (0..32).map(|_| None).collect();
+    let control = Ring::new();
+
+    let input = InputContext::new(controller.context_size);
+    input.add(0);
+    input.write(InputContext::SLOT, 0, speed << 20 | 1 << 27);
+    input.write(InputContext::SLOT, 1, (port as u32 + 1) << 16);
+    input.endpoint(CONTROL_ENDPOINT, ENDPOINT_CONTROL, max_packet, &control);
+    rings[CONTROL_ENDPOINT] = Some(control);
+
+    controller.command(
+        Trb::new(TRB_ADDRESS_DEVICE)
+            .parameter(input.data.phys().as_u64())
+            .slot(id),
+    )?;
+
+    let mut device = Slot {
+        controller: controller.clone(),
+        id,
+        vendor_id: 0,
+        product_id: 0,
+        _context: context,
+        rings: Mutex::new(rings),
+    };
+
+    let mut descriptor = [0u8; 18];
+    get_descriptor(&device, DESCRIPTOR_DEVICE, &mut descriptor[..8])?;
+
+    // Super speed devices give the size as a power of two, and always use 512
+    let actual = descriptor[7] as u16;
+    if speed < SPEED_SUPER && actual != max_packet && actual != 0 {
+        let input = InputContext::new(controller.context_size);
+        input.add(CONTROL_ENDPOINT);
+        input.write(
+            1 + CONTROL_ENDPOINT,
+            1,
+            ENDPOINT_ERROR_COUNT | ENDPOINT_CONTROL << 3 | (actual as u32) << 16,
+        );
+
+        controller.command(
+            Trb::new(TRB_EVALUATE_CONTEXT)
+                .parameter(input.data.phys().as_u64())
+                .slot(id),
+        )?;
+    }
+
+    get_descriptor(&device, DESCRIPTOR_DEVICE, &mut descriptor)?;
+    device.vendor_id = u16::from_le_bytes([descriptor[8], descriptor[9]]);
+    device.product_id = u16::from_le_bytes([descriptor[10], descriptor[11]]);
+
+    let mut header = [0u8; 9];
+    get_descriptor(&device, DESCRIPTOR_CONFIGURATION, &mut header)?;
+    let total = (u16::from_le_bytes([header[2], header[3]]) as usize).min(PAGE_SIZE as usize);
+    let mut configuration = alloc::vec![0u8; total];
+    let len = get_descriptor(&device, DESCRIPTOR_CONFIGURATION, &mut configuration)?;
+    let interfaces = parse_configuration(&configuration[..len]);
+
+    // Only bulk endpoints get rings, no class driver uses anything else yet
+    let input = InputContext::new(controller.context_size);
+    let mut last = CONTROL_ENDPOINT;
+    {
+        let mut rings = device.rings.lock();
+        for endpoint in interfaces.iter().flat_map(|i| &i.endpoints) {
+            if !endpoint.is_bulk() {
+                continue;
+            }
+
+            let dci = endpoint_index(endpoint.address);
+            let kind = match endpoint.is_in() {
+                true => ENDPOINT_BULK_IN,
+                false => ENDPOINT_BULK_OUT,
+            };
+
+            let ring = Ring::new();
+            input.endpoint(dci, kind, endpoint.max_packet, &ring);
+            rings[dci] = Some(ring);
+            last = last.max(dci);
+        }
+    }
+
+    input.add(0);
+    input.write(InputContext::SLOT, 0, speed << 20 | (last as u32) << 27);
+    input.write(InputContext::SLOT, 1, (port as u32 + 1) << 16);
+    controller.command(
+        Trb::new(TRB_CONFIGURE_ENDPOINT)
+            .parameter(input.data.phys().as_u64())
+            .slot(id),
+    )?;
+
+    device.control(
+        SetupPacket {
+            request_type: REQUEST_TYPE_DEVICE_OUT,
+            request: REQUEST_SET_CONFIGURATION,
+            value: header[5] as u16,
+            index: 0,
+            length: 0,
+        },
+        &mut [],
+    )?;
+
+    log::info!(
+        "xhci{}: port {} has {:04x}:{:04x}",
+        controller.index,
+        port + 1,
+        device.vendor_id,
+        device.product_id
+    );
+
+    let device: Arc<dyn UsbDevice> = Arc::new(device);
+    controller.devices.lock().push(device.clone());
+    super::attach(device, &interfaces);
+    Ok(())
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+    let mmio = pci
+        .map_bar(0)
+        .ok_or(ProbeError::Failed("cannot map BAR0"))?;
+    pci.enable_bus_mastering();
+
+    let operational = mmio.read::<u8>(CAPLENGTH) as usize;
+    let structural = mmio.read::<u32>(HCSPARAMS1);
+    let max_slots = structural & 0xFF;
+    let max_ports = (structural >> 24) as usize;
+    let doorbells = mmio.read::<u32>(DBOFF) as usize & !0x3;
+    let interrupter = mmio.read::<u32>(RTSOFF) as usize & !0x1F;
+    let context_size = match mmio.read::<u32>(HCCPARAMS1) & HCCPARAMS1_CONTEXT_64 {
+        0 => 32,
+        _ => 64,
+    };
+
+    take_ownership(&mmio);
+    reset(&mmio, operational).map_err(|_| ProbeError::Failed("controller doesn't reset"))?;
+
+    if mmio.read::<u32>(operational + PAGESIZE) & 1 == 0 {
+        return Err(ProbeError::Failed("4 KiB pages not supported"));
+    }
+
+    mmio.write(operational + CONFIG, max_slots);
+
+    // Scratchpad pages belong to the controller, it only needs them to exist
+    let params = mmio.read::<u32>(HCSPARAMS2);
+    let scratchpad_count = ((params >> 21) & 0x1F) << 5 | params >> 27;
+    let contexts = Dma::new((max_slots as usize + 1) * 8);
+    let mut scratchpad = Vec::new();
+    if scratchpad_count > 0 {
+        let array = Dma::new(scratchpad_count as usize * 8);
+        for i in 0..scratchpad_count as usize {
+            let page = Dma::new(PAGE_SIZE as usize);
+            array.write(i * 8, page.phys().as_u64());
+            scratchpad.push(page);
+        }
+
+        contexts.write(0, array.phys().as_u64());
+        scratchpad.push(array);
+    }
+
+    let commands = Ring::new();
+    let events = EventRing::new();
+
+    mmio.write(operational + DCBAAP, contexts.phys().as_u64());
+    mmio.write(operational + CRCR, commands.dequeue() | CRCR_CYCLE);
+    mmio.write(interrupter + ERSTSZ, 1u32);
+    mmio.write(interrupter + ERDP, events.trbs.phys().as_u64());
+    mmio.write(interrupter + ERSTBA, events.table.phys().as_u64());
+
+    mmio.write(operational + USBCMD, USBCMD_RUN);
+    wait(|| mmio.read::<u32>(operational + USBSTS) & USBSTS_HALTED == 0)
+        .map_err(|_| ProbeError::Failed("controller doesn't start"))?;
+
+    let controller = Arc::new(Controller {
+        index: CONTROLLERS.lock().len(),
+        device: *pci,
+        mmio,
+        operational,
+        interrupter,
+        doorbells,
+        context_size,
+        contexts,
+        _scratchpad: scratchpad,
+        commands: Mutex::new(commands),
+        events: Mutex::new(events),
+        devices: Mutex::new(Vec::new()),
+    });
+    CONTROLLERS.lock().push(controller.clone());
+
+    let version = controller.mmio.read::<u16>(CAPLENGTH + 2);
+    log::info!(
+        "xhci{}: xHCI {:x}.{:02x}, {max_slots} slots, {max_ports} ports",
+        controller.index,
+        version >> 8,
+        version & 0xFF
+    );
+
+    // Devices plugged in later aren't picked up, there's no port change handling yet
+    for port in 0..max_ports {
+        let register = controller.port(port);
+        let status = controller.mmio.read::<u32>(register);
+        if status & PORTSC_POWER == 0 {
+            controller.mmio.write(register, PORTSC_POWER);
+            hpet::sleep(20_000_000);
+        }
+
+        if controller.mmio.read::<u32>(register) & PORTSC_CONNECTED == 0 {
+            continue;
+        }
+
+        if let Err(err) = enumerate(&controller, port) {
+            log::warn!(
+                "xhci{}: port {} failed to enumerate: {err:?}",
+                controller.index,
+                port + 1
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn remove(device: &driver::Device) {
+    let Some(pci) = device.as_pci() else {
+        return;
+    };
+
+    let mut controllers = CONTROLLERS.lock();
+    let Some(i) = controllers
+        .iter()
+        .position(|c| c.device.address == pci.address)
+    else {
+        return;
+    };
+
+    let controller = controllers.remove(i);
+    drop(controllers);
+
+    for device in core::mem::take(&mut *controller.devices.lock()) {
+        super::detach(&device);
+    }
+
+    let _ = reset(&controller.mmio, controller.operational);
+}
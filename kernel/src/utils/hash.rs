@@ -0,0 +1,83 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::random;
+use crate::sync::Once;
+use core::hash::BuildHasher;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_core::siphash::SipHasher13;
+
+/// A hash map keyed from the entropy pool, so whoever picks the keys can't make it collide
+pub type HashMap<K, V> = hashbrown::HashMap<K, V, RandomState>;
+
+/// Drawn on the first map made, every other map gets its own variation of it
+static KEYS: Once<(u64, u64)> = Once::new();
+static MAPS: AtomicU64 = AtomicU64::new(0);
+
+/// Hands out SipHash-1-3 hashers with keys nobody outside the kernel knows
+#[derive(Clone, Debug)]
+pub struct RandomState {
+    k0: u64,
+    k1: u64,
+}
+
+impl RandomState {
+    pub fn new() -> RandomState {
+        let &(k0, k1) = KEYS.call_once(|| (random::u64(), random::u64()));
+
+        // Two maps shouldn't share keys, or iterating one leaks the order of the other
+        let map = MAPS.fetch_add(1, Ordering::Relaxed);
+        RandomState {
+            k0: k0.wrapping_add(map),
+            k1,
+        }
+    }
+}
+
+impl Default for RandomState {
+    fn default() -> RandomState {
+        RandomState::new()
+    }
+}
+
+impl BuildHasher for RandomState {
+    type Hasher = SipHasher13;
+
+    fn build_hasher(&self) -> SipHasher13 {
+        SipHasher13::new_with_keys(self.k0, self.k1)
+    }
+}
+
+ktest! {
+    fn hash_map_basics() {
+        let mut map = HashMap::default();
+        for i in 0..1000u64 {
+            map.insert(i, i * 2);
+        }
+
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get(&500), Some(&1000));
+        assert_eq!(map.remove(&500), Some(1000));
+        assert!(!map.contains_key(&500));
+    }
+
+    fn maps_get_different_keys() {
+        let a = RandomState::new();
+        let b = RandomState::new();
+        assert_ne!(a.hash_one("kernel"), b.hash_one("kernel"));
+    }
+}
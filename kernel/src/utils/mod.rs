@@ -1,3 +1,71 @@
-pub mod bitmap;
+pub mod crc32;
+pub mod hash;
+pub mod wait_queue;
 
-pub use bitmap::Bitmap;
+pub use crc32::{crc32, Crc32};
+pub use hash::HashMap;
+pub use kernel_core::intrusive;
+pub use kernel_core::ring::MpscRing;
+pub use kernel_core::Bitmap;
+pub use wait_queue::WaitQueue;
+
+ktest! {
+    fn intrusive_object_on_a_list_and_in_a_tree() {
+        use core::mem::offset_of;
+        use core::ptr::NonNull;
+        use intrusive::{Adapter, List, ListLink, RbTree, TreeAdapter, TreeLink};
+
+        struct Timer {
+            deadline: u64,
+            pending: ListLink,
+            sorted: TreeLink,
+        }
+
+        struct Pending;
+        unsafe impl Adapter for Pending {
+            type Object = Timer;
+            type Link = ListLink;
+            const OFFSET: usize = offset_of!(Timer, pending);
+        }
+
+        struct Sorted;
+        unsafe impl Adapter for Sorted {
+            type Object = Timer;
+            type Link = TreeLink;
+            const OFFSET: usize = offset_of!(Timer, sorted);
+        }
+
+        impl TreeAdapter for Sorted {
+            type Key = u64;
+
+            fn key(timer: &Timer) -> u64 {
+                timer.deadline
+            }
+        }
+
+        let mut timers = [30, 10, 20].map(|deadline| Timer {
+            deadline,
+            pending: ListLink::new(),
+            sorted: TreeLink::new(),
+        });
+
+        let mut list = List::<Pending>::new();
+        let mut tree = RbTree::<Sorted>::new();
+        for timer in &mut timers {
+            let timer = NonNull::from(timer);
+            unsafe {
+                list.push_back(timer);
+                tree.insert(timer);
+            }
+        }
+
+        let deadline = |timer: NonNull<Timer>| unsafe { timer.as_ref() }.deadline;
+        assert!(list.iter().map(deadline).eq([30, 10, 20]));
+        assert!(tree.iter().map(deadline).eq([10, 20, 30]));
+
+        while let Some(timer) = tree.pop_first() {
+            unsafe { list.remove(timer) };
+        }
+        assert!(list.is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Somewhere to wait for an event to happen. There is no scheduler to put waiters to sleep,
+/// so they spin, but only on the generation counter and not on whatever the condition reads
+pub struct WaitQueue {
+    generation: AtomicU64,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits until `condition` holds, checking it again after every wake up
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            // Read before checking, so a wake up in between isn't lost
+            let generation = self.generation.load(Ordering::Acquire);
+            if condition() {
+                return;
+            }
+
+            while self.generation.load(Ordering::Acquire) == generation {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn wake_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
@@ -0,0 +1,131 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::cpu;
+use crate::mm::PhysAddr;
+
+pub const COLUMNS: usize = 80;
+pub const ROWS: usize = 25;
+
+const BUFFER: u64 = 0xB8000;
+
+/// CRT controller index and data ports, for the cursor
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const CURSOR_HIGH: u8 = 0x0E;
+const CURSOR_LOW: u8 = 0x0F;
+
+/// The 16 text mode colors, in attribute order
+const COLORS: [u32; 16] = [
+    0x00_00_00, 0x00_00_AA, 0x00_AA_00, 0x00_AA_AA, 0xAA_00_00, 0xAA_00_AA, 0xAA_55_00, 0xAA_AA_AA,
+    0x55_55_55, 0x55_55_FF, 0x55_FF_55, 0x55_FF_FF, 0xFF_55_55, 0xFF_55_FF, 0xFF_FF_55, 0xFF_FF_FF,
+];
+
+/// The upper half of code page 437, what the VGA font has past ASCII
+const CP437: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩",
+    "≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}",
+);
+
+/// The code page 437 character that draws `chr`, `?` if there's none
+pub fn encode(chr: char) -> u8 {
+    match chr {
+        ' '..='~' => chr as u8,
+        _ => CP437
+            .chars()
+            .position(|c| c == chr)
+            .map_or(b'?', |i| 0x80 + i as u8),
+    }
+}
+
+/// The closest of `colors` to `color`, as an index
+fn nearest(color: u32, colors: &[u32]) -> u8 {
+    let channels = |c: u32| c.to_le_bytes().map(|channel| channel as i32);
+    let [b, g, r, _] = channels(color);
+
+    (0..colors.len())
+        .min_by_key(|&i| {
+            let [cb, cg, cr, _] = channels(colors[i]);
+            (r - cr).pow(2) + (g - cg).pow(2) + (b - cb).pow(2)
+        })
+        .unwrap() as u8
+}
+
+/// The attribute byte closest to `fg` on `bg`; the backgrounds are only the first 8 colors, the
+/// top bit blinks
+pub fn attribute(fg: u32, bg: u32) -> u8 {
+    nearest(bg, &COLORS[..8]) << 4 | nearest(fg, &COLORS)
+}
+
+/// The 80x25 text buffer of a VGA in text mode
+pub struct TextBuffer {
+    cells: *mut u16,
+}
+
+unsafe impl Send for TextBuffer {}
+
+impl TextBuffer {
+    /// The caller has to make sure the VGA is in text mode, i.e. we weren't booted by UEFI and
+    /// the bootloader didn't set a graphics mode
+    pub unsafe fn new() -> TextBuffer {
+        TextBuffer {
+            cells: PhysAddr::new(BUFFER).as_hhdm().as_mut_ptr(),
+        }
+    }
+
+    pub fn write(&mut self, column: usize, row: usize, byte: u8, attribute: u8) {
+        if column < COLUMNS && row < ROWS {
+            let cell = (attribute as u16) << 8 | byte as u16;
+            unsafe { self.cells.add(row * COLUMNS + column).write_volatile(cell) };
+        }
+    }
+
+    /// Moves everything up a line, leaving the last one blank
+    pub fn scroll_up(&mut self, attribute: u8) {
+        unsafe { core::ptr::copy(self.cells.add(COLUMNS), self.cells, (ROWS - 1) * COLUMNS) };
+
+        for column in 0..COLUMNS {
+            self.write(column, ROWS - 1, b' ', attribute);
+        }
+    }
+
+    pub fn clear(&mut self, attribute: u8) {
+        for row in 0..ROWS {
+            for column in 0..COLUMNS {
+                self.write(column, row, b' ', attribute);
+            }
+        }
+    }
+
+    /// Puts the blinking hardware cursor at `column`, `row`
+    pub fn set_cursor(&mut self, column: usize, row: usize) {
+        let position = (row.min(ROWS - 1) * COLUMNS + column.min(COLUMNS - 1)) as u16;
+
+        unsafe {
+            cpu::outb(CRTC_INDEX, CURSOR_HIGH);
+            cpu::outb(CRTC_DATA, (position >> 8) as u8);
+            cpu::outb(CRTC_INDEX, CURSOR_LOW);
+            cpu::outb(CRTC_DATA, position as u8);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Buffer, Transport, Virtqueue, BALLOON};
+use crate::driver::{self, Driver, ProbeError};
+use crate::mm::dma::Dma;
+use crate::mm::{pmm, PhysAddr};
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 8;
+
+/// Device configuration
+const CONFIG_NUM_PAGES: usize = 0;
+const CONFIG_ACTUAL: usize = 4;
+
+/// Page frame numbers sent to the device in a single request
+const BATCH: usize = 256;
+
+/// The balloon always talks in 4 KiB pages, whatever the guest uses
+const BALLOON_PAGE_SHIFT: u64 = 12;
+
+static DEVICE: Mutex<Option<VirtioBalloon>> = Mutex::new(None);
+
+/// Set by the configuration change interrupt, the target is chased outside of it
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+static DRIVER: Driver = Driver {
+    name: "virtio-balloon",
+    order: 10,
+    matches: &super::matches(BALLOON),
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+struct VirtioBalloon {
+    transport: Transport,
+    inflate: Virtqueue,
+    deflate: Virtqueue,
+    pfns: Dma,
+    /// Pages currently in the balloon
+    pages: Vec<PhysAddr>,
+}
+
+impl VirtioBalloon {
+    /// Sends the first `count` PFNs in `pfns` on the inflate or the deflate queue
+    fn send(&mut self, inflate: bool, count: usize) {
+        let queue = if inflate {
+            &mut self.inflate
+        } else {
+            &mut self.deflate
+        };
+
+        let buffer = Buffer::readable(self.pfns.phys(), (count * 4) as u32);
+        let Some(head) = queue.push(&[buffer]) else {
+            return;
+        };
+
+        self.transport.notify(queue);
+
+        loop {
+            match queue.pop_used() {
+                Some((used, _)) if used == head => break,
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    fn inflate(&mut self, count: usize) {
+        let mut remaining = count;
+
+        while remaining > 0 {
+            let batch: Vec<PhysAddr> = (0..remaining.min(BATCH))
+                .map_while(|_| pmm::balloon_inflate())
+                .collect();
+
+            if batch.is_empty() {
+                log::warn!("virtio-balloon: out of memory, {remaining} pages short of the target");
+                break;
+            }
+
+            for (i, page) in batch.iter().enumerate() {
+                self.pfns
+                    .write(i * 4, (page.as_u64() >> BALLOON_PAGE_SHIFT) as u32);
+            }
+
+            self.send(true, batch.len());
+            remaining -= batch.len();
+            self.pages.extend(batch);
+        }
+    }
+
+    fn deflate(&mut self, count: usize) {
+        let mut remaining = count.min(self.pages.len());
+
+        while remaining > 0 {
+            let batch = remaining.min(BATCH);
+            let start = self.pages.len() - batch;
+
+            for (i, page) in self.pages[start..].iter().enumerate() {
+                self.pfns
+                    .write(i * 4, (page.as_u64() >> BALLOON_PAGE_SHIFT) as u32);
+            }
+
+            // The host is told before the pages are touched again
+            self.send(false, batch);
+
+            for page in self.pages.drain(start..) {
+                pmm::balloon_deflate(page);
+            }
+
+            remaining -= batch;
+        }
+    }
+
+    /// Inflates or deflates towards the size the host asks for
+    fn resize(&mut self) {
+        let target = self.transport.read_config::<u32>(CONFIG_NUM_PAGES) as usize;
+        let current = self.pages.len();
+
+        if target > current {
+            self.inflate(target - current);
+        } else if target < current {
+            self.deflate(current - target);
+        }
+
+        if self.pages.len() != current {
+            log::debug!("virtio-balloon: {current} -> {} pages", self.pages.len());
+        }
+
+        self.transport
+            .write_config(CONFIG_ACTUAL, self.pages.len() as u32);
+    }
+}
+
+fn interrupt() {
+    PENDING.store(true, Ordering::Release);
+}
+
+/// Follows a change of the balloon target, cheap when there is none
+pub fn update() {
+    if !PENDING.swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    match DEVICE.try_lock() {
+        Some(mut device) => {
+            if let Some(balloon) = device.as_mut() {
+                balloon.resize();
+            }
+        }
+        None => PENDING.store(true, Ordering::Release),
+    }
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+
+    if DEVICE.lock().is_some() {
+        return Err(ProbeError::Unsupported);
+    }
+
+    let mut transport = Transport::new(pci).map_err(|_| ProbeError::Failed("bad transport"))?;
+    transport
+        .negotiate(0)
+        .map_err(|_| ProbeError::Failed("feature negotiation failed"))?;
+
+    if let Err(e) = transport.enable_interrupts(interrupt) {
+        log::warn!(
+            "{}: no interrupts ({e:?}), the target is only read at boot",
+            pci.address
+        );
+    }
+
+    let inflate = transport
+        .setup_queue(INFLATE_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no inflate queue"))?;
+    let deflate = transport
+        .setup_queue(DEFLATE_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no deflate queue"))?;
+    transport.finish();
+
+    *DEVICE.lock() = Some(VirtioBalloon {
+        transport,
+        inflate,
+        deflate,
+        pfns: Dma::new(BATCH * 4),
+        pages: Vec::new(),
+    });
+
+    PENDING.store(true, Ordering::Release);
+    update();
+
+    Ok(())
+}
+
+fn remove(_device: &driver::Device) {
+    if let Some(mut balloon) = DEVICE.lock().take() {
+        let pages = balloon.pages.len();
+        balloon.deflate(pages);
+        balloon.transport.reset();
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Buffer, Transport, Virtqueue, BLOCK};
+use crate::block::{self, BlockDevice, Error, SECTOR_SIZE};
+use crate::driver::{self, Driver, ProbeError};
+use crate::mm::dma::Dma;
+use crate::mm::PhysAddr;
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Feature bits
+const F_SIZE_MAX: u64 = 1 << 1;
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+/// Device configuration
+const CONFIG_CAPACITY: usize = 0x00;
+const CONFIG_SIZE_MAX: usize = 0x08;
+
+/// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+/// Request status, written by the device in the last byte of the request
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+const HEADER_SIZE: usize = 16;
+const QUEUE_SIZE: u16 = 128;
+
+/// Largest transfer in a single request, unless the device wants less
+const MAX_TRANSFER: usize = 64 * 1024;
+
+static DISKS: Mutex<Vec<Arc<VirtioBlk>>> = Mutex::new(Vec::new());
+
+static DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    order: 10,
+    matches: &super::matches(BLOCK),
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+pub struct VirtioBlk {
+    name: String,
+    transport: Transport,
+    queue: Mutex<Virtqueue>,
+    /// Set when the request whose head descriptor is the index completes
+    done: Vec<AtomicBool>,
+    capacity: u64,
+    read_only: bool,
+    flush: bool,
+    max_transfer: usize,
+}
+
+impl VirtioBlk {
+    /// Collects completed requests, unless someone else is already touching the queue
+    fn reap(&self) {
+        let Some(mut queue) = self.queue.try_lock() else {
+            return;
+        };
+
+        while let Some((head, _)) = queue.pop_used() {
+            self.done[head as usize].store(true, Ordering::Release);
+        }
+    }
+
+    /// Submits a request and waits for it, `data` is the DMA buffer and length to transfer
+    fn request(&self, kind: u32, sector: u64, data: Option<(&Dma, usize)>) -> Result<(), Error> {
+        let header = Dma::new(HEADER_SIZE + 1);
+        header.write(0, kind);
+        header.write(8, sector);
+        header.write(HEADER_SIZE, 0xFFu8);
+
+        let status = PhysAddr::new(header.phys().as_u64() + HEADER_SIZE as u64);
+        let mut buffers = Vec::with_capacity(3);
+        buffers.push(Buffer::readable(header.phys(), HEADER_SIZE as u32));
+        if let Some((data, len)) = data {
+            buffers.push(Buffer {
+                addr: data.phys(),
+                len: len as u32,
+                writable: kind == T_IN,
+            });
+        }
+        buffers.push(Buffer::writable(status, 1));
+
+        let head = loop {
+            let mut queue = self.queue.lock();
+            if let Some(head) = queue.push(&buffers) {
+                self.transport.notify(&queue);
+                break head;
+            }
+
+            drop(queue);
+            self.reap();
+            core::hint::spin_loop();
+        };
+
+        // The interrupt handler usually beats us to it, polling covers running with interrupts off
+        while !self.done[head as usize].swap(false, Ordering::Acquire) {
+            self.reap();
+            core::hint::spin_loop();
+        }
+
+        match header.read::<u8>(HEADER_SIZE) {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(Error::Unsupported),
+            _ => Err(Error::Io),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self, sector, buffer.len())?;
+
+        for (i, chunk) in buffer.chunks_mut(self.max_transfer).enumerate() {
+            let bounce = Dma::new(chunk.len());
+            let sector = sector + (i * self.max_transfer / SECTOR_SIZE) as u64;
+
+            self.request(T_IN, sector, Some((&bounce, chunk.len())))?;
+            chunk.copy_from_slice(&bounce.as_slice()[..chunk.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        block::check(self, sector, buffer.len())?;
+
+        for (i, chunk) in buffer.chunks(self.max_transfer).enumerate() {
+            let mut bounce = Dma::new(chunk.len());
+            let sector = sector + (i * self.max_transfer / SECTOR_SIZE) as u64;
+
+            bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            self.request(T_OUT, sector, Some((&bounce, chunk.len())))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        match self.flush {
+            true => self.request(T_FLUSH, 0, None),
+            false => Ok(()),
+        }
+    }
+}
+
+fn interrupt() {
+    let Some(disks) = DISKS.try_lock() else {
+        return;
+    };
+
+    for disk in disks.iter() {
+        disk.reap();
+    }
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+    let mut transport = Transport::new(pci).map_err(|_| ProbeError::Failed("bad transport"))?;
+
+    let features = transport
+        .negotiate(F_SIZE_MAX | F_RO | F_FLUSH)
+        .map_err(|_| ProbeError::Failed("feature negotiation failed"))?;
+
+    if let Err(e) = transport.enable_interrupts(interrupt) {
+        log::warn!("{}: no interrupts ({e:?}), polling", pci.address);
+    }
+
+    let queue = transport
+        .setup_queue(0, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no request queue"))?;
+
+    let mut max_transfer = MAX_TRANSFER;
+    if features & F_SIZE_MAX != 0 {
+        let size_max = transport.read_config::<u32>(CONFIG_SIZE_MAX) as usize;
+        max_transfer = max_transfer
+            .min(size_max / SECTOR_SIZE * SECTOR_SIZE)
+            .max(SECTOR_SIZE);
+    }
+
+    let disk = Arc::new(VirtioBlk {
+        name: block::next_name("vd"),
+        capacity: transport.read_config(CONFIG_CAPACITY),
+        done: (0..queue.size()).map(|_| AtomicBool::new(false)).collect(),
+        queue: Mutex::new(queue),
+        transport,
+        read_only: features & F_RO != 0,
+        flush: features & F_FLUSH != 0,
+        max_transfer,
+    });
+
+    disk.transport.finish();
+    DISKS.lock().push(disk.clone());
+    block::register(disk);
+
+    Ok(())
+}
+
+fn remove(device: &driver::Device) {
+    let Some(pci) = device.as_pci() else {
+        return;
+    };
+
+    let mut disks = DISKS.lock();
+    if let Some(i) = disks
+        .iter()
+        .position(|d| d.transport.device().address == pci.address)
+    {
+        let disk = disks.remove(i);
+        let _ = disk.flush();
+
+        disk.transport.reset();
+        block::unregister(&disk.name);
+    }
+}
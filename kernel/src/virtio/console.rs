@@ -0,0 +1,221 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Buffer, Transport, Virtqueue, CONSOLE};
+use crate::driver::{self, Driver, ProbeError};
+use crate::mm::dma::Dma;
+use crate::mm::PhysAddr;
+use crate::sync::Mutex;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt::{self, Arguments, Write};
+
+/// Queues of port 0, the only one without `VIRTIO_CONSOLE_F_MULTIPORT`
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 16;
+
+const RX_BUFFER_SIZE: usize = 64;
+const TX_BUFFER_SIZE: usize = 4096;
+
+/// Received bytes nobody read yet, older ones are dropped past this
+const INPUT_LIMIT: usize = 4096;
+
+static DEVICE: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+static INPUT: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+static DRIVER: Driver = Driver {
+    name: "virtio-console",
+    order: 10,
+    matches: &super::matches(CONSOLE),
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+struct VirtioConsole {
+    transport: Transport,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_buffers: Dma,
+    /// Which receive buffer the descriptor at each index points at
+    rx_slots: Vec<usize>,
+    tx_buffer: Dma,
+    tx_len: usize,
+}
+
+impl VirtioConsole {
+    fn post_rx(&mut self, slot: usize) {
+        let addr = PhysAddr::new(self.rx_buffers.phys().as_u64() + (slot * RX_BUFFER_SIZE) as u64);
+
+        if let Some(head) = self
+            .rx
+            .push(&[Buffer::writable(addr, RX_BUFFER_SIZE as u32)])
+        {
+            self.rx_slots[head as usize] = slot;
+        }
+    }
+
+    /// Moves whatever the host sent into `INPUT` and gives the buffers back
+    fn receive(&mut self) {
+        let mut received = false;
+
+        while let Some((head, len)) = self.rx.pop_used() {
+            let slot = self.rx_slots[head as usize];
+            let start = slot * RX_BUFFER_SIZE;
+            let bytes =
+                &self.rx_buffers.as_slice()[start..start + (len as usize).min(RX_BUFFER_SIZE)];
+
+            let mut input = INPUT.lock();
+            input.extend(bytes);
+            while input.len() > INPUT_LIMIT {
+                input.pop_front();
+            }
+            drop(input);
+
+            self.post_rx(slot);
+            received = true;
+        }
+
+        if received {
+            self.transport.notify(&self.rx);
+        }
+    }
+
+    /// Sends the buffered output and waits for the host to take it
+    fn flush(&mut self) {
+        if self.tx_len == 0 {
+            return;
+        }
+
+        let buffer = Buffer::readable(self.tx_buffer.phys(), self.tx_len as u32);
+        self.tx_len = 0;
+
+        let Some(head) = self.tx.push(&[buffer]) else {
+            return;
+        };
+
+        self.transport.notify(&self.tx);
+
+        loop {
+            match self.tx.pop_used() {
+                Some((used, _)) if used == head => break,
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+impl Write for VirtioConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.tx_len == TX_BUFFER_SIZE {
+                self.flush();
+            }
+
+            self.tx_buffer.write(self.tx_len, byte);
+            self.tx_len += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the next byte typed on the console, if any
+pub fn read_byte() -> Option<u8> {
+    if let Some(console) = DEVICE.try_lock().as_mut().and_then(|c| c.as_mut()) {
+        console.receive();
+    }
+
+    INPUT.lock().pop_front()
+}
+
+pub fn present() -> bool {
+    DEVICE.lock().is_some()
+}
+
+pub unsafe fn unlock() {
+    DEVICE.force_unlock()
+}
+
+#[doc(hidden)]
+pub fn _print(args: Arguments) {
+    let mut console = DEVICE.lock();
+
+    if let Some(console) = console.as_mut() {
+        let _ = console.write_fmt(args);
+        console.flush();
+    }
+}
+
+fn interrupt() {
+    if let Some(console) = DEVICE.try_lock().as_mut().and_then(|c| c.as_mut()) {
+        console.receive();
+    }
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+
+    if DEVICE.lock().is_some() {
+        return Err(ProbeError::Unsupported);
+    }
+
+    let mut transport = Transport::new(pci).map_err(|_| ProbeError::Failed("bad transport"))?;
+    transport
+        .negotiate(0)
+        .map_err(|_| ProbeError::Failed("feature negotiation failed"))?;
+
+    if let Err(e) = transport.enable_interrupts(interrupt) {
+        log::warn!("{}: no interrupts ({e:?}), input is polled", pci.address);
+    }
+
+    let rx = transport
+        .setup_queue(RECEIVE_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no receive queue"))?;
+    let tx = transport
+        .setup_queue(TRANSMIT_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no transmit queue"))?;
+    transport.finish();
+
+    let slots = rx.size() as usize;
+    let mut console = VirtioConsole {
+        transport,
+        rx,
+        tx,
+        rx_buffers: Dma::new(slots * RX_BUFFER_SIZE),
+        rx_slots: alloc::vec![0; slots],
+        tx_buffer: Dma::new(TX_BUFFER_SIZE),
+        tx_len: 0,
+    };
+
+    for slot in 0..slots {
+        console.post_rx(slot);
+    }
+    console.transport.notify(&console.rx);
+
+    *DEVICE.lock() = Some(console);
+    Ok(())
+}
+
+fn remove(_device: &driver::Device) {
+    if let Some(console) = DEVICE.lock().take() {
+        console.transport.reset();
+    }
+}
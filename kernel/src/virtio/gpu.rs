@@ -0,0 +1,332 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Buffer, Transport, Virtqueue, GPU};
+use crate::driver::{self, Driver, ProbeError};
+use crate::fb_renderer;
+use crate::framebuffer::Framebuffer;
+use crate::mm::dma::Dma;
+use crate::mm::PhysAddr;
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+
+/// Commands
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+/// Responses
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Same layout as the pixels the console draws
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+const HEADER_SIZE: usize = 24;
+const MAX_SCANOUTS: usize = 16;
+const DISPLAY_ONE_SIZE: usize = 24;
+
+const CONTROL_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 64;
+
+/// Mode used when the host doesn't report a preferred one
+const DEFAULT_MODE: (u32, u32) = (1024, 768);
+
+static DEVICE: Mutex<Option<VirtioGpu>> = Mutex::new(None);
+
+static DRIVER: Driver = Driver {
+    name: "virtio-gpu",
+    order: 10,
+    matches: &super::matches(GPU),
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// The device answered with another response type than the expected one
+    Response,
+    NoDevice,
+}
+
+/// A host side 2D resource and the guest memory backing it
+struct Resource {
+    id: u32,
+    width: u32,
+    height: u32,
+    backing: Dma,
+}
+
+pub struct VirtioGpu {
+    transport: Transport,
+    control: Virtqueue,
+    resource: Option<Resource>,
+    next_resource: u32,
+}
+
+/// A command being built, every field is little endian
+struct Command(Vec<u8>);
+
+impl Command {
+    fn new(kind: u32) -> Command {
+        let mut command = Command(Vec::new());
+        command.u32(kind).u32(0).u64(0).u32(0).u32(0);
+        command
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Command {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Command {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> &mut Command {
+        self.u32(x).u32(y).u32(width).u32(height)
+    }
+}
+
+impl VirtioGpu {
+    /// Sends `command` and waits for the response, which has to be of type `expected`
+    fn submit(
+        &mut self,
+        command: &Command,
+        response_len: usize,
+        expected: u32,
+    ) -> Result<Dma, Error> {
+        let request_len = command.0.len();
+        let mut memory = Dma::new(request_len + response_len);
+        memory.as_mut_slice()[..request_len].copy_from_slice(&command.0);
+
+        let response = PhysAddr::new(memory.phys().as_u64() + request_len as u64);
+        let buffers = [
+            Buffer::readable(memory.phys(), request_len as u32),
+            Buffer::writable(response, response_len as u32),
+        ];
+
+        let head = self
+            .control
+            .push(&buffers)
+            .expect("virtio-gpu control queue is full");
+        self.transport.notify(&self.control);
+
+        // Commands are issued one at a time, the first completion is ours
+        loop {
+            match self.control.pop_used() {
+                Some((used, _)) if used == head => break,
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        }
+
+        match memory.read::<u32>(request_len) {
+            kind if kind == expected => Ok(memory),
+            _ => Err(Error::Response),
+        }
+    }
+
+    fn command(&mut self, command: &Command) -> Result<(), Error> {
+        self.submit(command, HEADER_SIZE, RESP_OK_NODATA)
+            .map(|_| ())
+    }
+
+    /// Returns the size of the first enabled scanout
+    fn preferred_mode(&mut self) -> Option<(u32, u32)> {
+        let response_len = HEADER_SIZE + MAX_SCANOUTS * DISPLAY_ONE_SIZE;
+        let info = self
+            .submit(
+                &Command::new(CMD_GET_DISPLAY_INFO),
+                response_len,
+                RESP_OK_DISPLAY_INFO,
+            )
+            .ok()?;
+
+        let request_len = HEADER_SIZE;
+        (0..MAX_SCANOUTS).find_map(|i| {
+            let pmode = request_len + HEADER_SIZE + i * DISPLAY_ONE_SIZE;
+            let (width, height) = (info.read::<u32>(pmode + 8), info.read::<u32>(pmode + 12));
+            let enabled = info.read::<u32>(pmode + 16) != 0;
+
+            (enabled && width != 0 && height != 0).then_some((width, height))
+        })
+    }
+
+    fn create_resource(&mut self, width: u32, height: u32) -> Result<Resource, Error> {
+        let id = self.next_resource;
+        self.next_resource += 1;
+
+        self.command(
+            Command::new(CMD_RESOURCE_CREATE_2D)
+                .u32(id)
+                .u32(FORMAT_B8G8R8X8_UNORM)
+                .u32(width)
+                .u32(height),
+        )?;
+
+        let backing = Dma::new(width as usize * height as usize * 4);
+        self.command(
+            Command::new(CMD_RESOURCE_ATTACH_BACKING)
+                .u32(id)
+                .u32(1)
+                .u64(backing.phys().as_u64())
+                .u32(backing.len() as u32)
+                .u32(0),
+        )?;
+
+        Ok(Resource {
+            id,
+            width,
+            height,
+            backing,
+        })
+    }
+
+    fn destroy_resource(&mut self, resource: Resource) {
+        let _ = self.command(
+            Command::new(CMD_RESOURCE_DETACH_BACKING)
+                .u32(resource.id)
+                .u32(0),
+        );
+        let _ = self.command(Command::new(CMD_RESOURCE_UNREF).u32(resource.id).u32(0));
+    }
+
+    fn set_scanout(&mut self, resource: &Resource) -> Result<(), Error> {
+        self.command(
+            Command::new(CMD_SET_SCANOUT)
+                .rect(0, 0, resource.width, resource.height)
+                .u32(0)
+                .u32(resource.id),
+        )
+    }
+
+    /// Copies a rectangle of the backing memory to the host and shows it
+    fn flush(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result<(), Error> {
+        let Some(resource) = &self.resource else {
+            return Ok(());
+        };
+
+        let (id, stride) = (resource.id, resource.width as u64 * 4);
+        let offset = y as u64 * stride + x as u64 * 4;
+
+        self.command(
+            Command::new(CMD_TRANSFER_TO_HOST_2D)
+                .rect(x, y, width, height)
+                .u64(offset)
+                .u32(id)
+                .u32(0),
+        )?;
+
+        self.command(
+            Command::new(CMD_RESOURCE_FLUSH)
+                .rect(x, y, width, height)
+                .u32(id)
+                .u32(0),
+        )
+    }
+}
+
+fn flush(x: usize, y: usize, width: usize, height: usize) {
+    if let Some(gpu) = DEVICE.lock().as_mut() {
+        let _ = gpu.flush(x as u32, y as u32, width as u32, height as u32);
+    }
+}
+
+/// Switches the display to `width` x `height` and moves the console there
+pub fn set_mode(width: u32, height: u32) -> Result<(), Error> {
+    let mut guard = DEVICE.lock();
+    let gpu = guard.as_mut().ok_or(Error::NoDevice)?;
+
+    let resource = gpu.create_resource(width, height)?;
+    if let Err(e) = gpu.set_scanout(&resource) {
+        gpu.destroy_resource(resource);
+        return Err(e);
+    }
+
+    let pixels = (width * height) as usize;
+    let backing = unsafe {
+        core::slice::from_raw_parts_mut(resource.backing.virt().as_mut_ptr::<u32>(), pixels)
+    };
+
+    let old = gpu.resource.replace(resource);
+    drop(guard);
+
+    // The resource lives until the next mode switch, after the console let go of it
+    fb_renderer::switch(Framebuffer::from_raw(
+        backing,
+        width as usize,
+        height as usize,
+        Some(flush),
+    ));
+
+    if let Some(old) = old {
+        if let Some(gpu) = DEVICE.lock().as_mut() {
+            gpu.destroy_resource(old);
+        }
+    }
+
+    log::info!("virtio-gpu: {width}x{height}");
+    Ok(())
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+
+    // Only one display for now, further ones are left alone
+    if DEVICE.lock().is_some() {
+        return Err(ProbeError::Unsupported);
+    }
+
+    let transport = Transport::new(pci).map_err(|_| ProbeError::Failed("bad transport"))?;
+    transport
+        .negotiate(0)
+        .map_err(|_| ProbeError::Failed("feature negotiation failed"))?;
+
+    let control = transport
+        .setup_queue(CONTROL_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no control queue"))?;
+    transport.finish();
+
+    let mut gpu = VirtioGpu {
+        transport,
+        control,
+        resource: None,
+        next_resource: 1,
+    };
+
+    let (width, height) = gpu.preferred_mode().unwrap_or(DEFAULT_MODE);
+    *DEVICE.lock() = Some(gpu);
+
+    set_mode(width, height).map_err(|_| ProbeError::Failed("mode setting failed"))
+}
+
+fn remove(_device: &driver::Device) {
+    // The console keeps drawing into the backing memory, so it's never freed
+    if let Some(gpu) = DEVICE.lock().as_mut() {
+        gpu.transport.reset();
+        core::mem::forget(gpu.resource.take());
+    }
+}
@@ -0,0 +1,113 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::driver::Match;
+use crate::interrupts::{self, InterruptStack};
+use crate::sync::{IrqSpinlock, Mutex};
+use alloc::vec::Vec;
+
+pub mod balloon;
+pub mod blk;
+pub mod console;
+pub mod gpu;
+pub mod net;
+pub mod ninep;
+pub mod pci;
+pub mod queue;
+pub mod rng;
+
+pub use self::pci::Transport;
+pub use queue::{Buffer, Virtqueue};
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// Device IDs, modern functions show up on the bus as 0x1040 plus these
+pub const NETWORK: u16 = 1;
+pub const BLOCK: u16 = 2;
+pub const CONSOLE: u16 = 3;
+pub const ENTROPY: u16 = 4;
+pub const BALLOON: u16 = 5;
+pub const NINE_P: u16 = 9;
+pub const GPU: u16 = 16;
+
+/// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// Feature bits every driver cares about
+pub const F_VERSION_1: u64 = 1 << 32;
+
+static VECTOR: Mutex<Option<u8>> = Mutex::new(None);
+static HANDLERS: IrqSpinlock<Vec<fn()>> = IrqSpinlock::new(Vec::new());
+
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    NotVirtio,
+    MissingCapability,
+    MapFailed,
+    FeaturesRejected,
+    QueueUnavailable,
+    NoVector,
+}
+
+/// PCI IDs of a device type, the modern one and, when it exists, the transitional one
+pub const fn matches(kind: u16) -> [Match; 2] {
+    let transitional = match kind {
+        NETWORK => 0x1000,
+        BLOCK => 0x1001,
+        BALLOON => 0x1002,
+        CONSOLE => 0x1003,
+        ENTROPY => 0x1005,
+        NINE_P => 0x1009,
+        _ => 0x1040 + kind,
+    };
+
+    [
+        Match::PciId {
+            vendor: VENDOR_ID,
+            device: 0x1040 + kind,
+        },
+        Match::PciId {
+            vendor: VENDOR_ID,
+            device: transitional,
+        },
+    ]
+}
+
+/// Every virtio device shares a vector, each handler checks its own queues
+fn interrupt(_stack: &mut InterruptStack) {
+    for handler in HANDLERS.lock().iter() {
+        handler();
+    }
+
+    core!().apic.lock().eoi();
+}
+
+/// Registers `handler` and returns the shared vector
+fn register_handler(handler: fn()) -> Result<u8, Error> {
+    let mut vector = VECTOR.lock();
+
+    if vector.is_none() {
+        *vector = Some(interrupts::allocate_handler(interrupt).ok_or(Error::NoVector)?);
+    }
+
+    HANDLERS.lock().push(handler);
+    Ok(vector.unwrap())
+}
@@ -0,0 +1,232 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Buffer, Transport, Virtqueue, NETWORK};
+use crate::driver::{self, Driver, ProbeError};
+use crate::mm::dma::Dma;
+use crate::net::{self, Error, MacAddress, NetDevice, PacketBuffer, MAX_FRAME};
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Feature bits
+const F_MAC: u64 = 1 << 5;
+const F_STATUS: u64 = 1 << 16;
+
+/// Device configuration
+const CONFIG_MAC: usize = 0x00;
+const CONFIG_STATUS: usize = 0x06;
+
+const STATUS_LINK_UP: u16 = 1 << 0;
+
+/// `virtio_net_hdr` with `num_buffers`, which is always there with `VERSION_1`
+const HEADER_SIZE: usize = 12;
+const BUFFER_SIZE: usize = HEADER_SIZE + MAX_FRAME;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+const QUEUE_SIZE: u16 = 64;
+
+static ADAPTERS: Mutex<Vec<Arc<VirtioNet>>> = Mutex::new(Vec::new());
+
+static DRIVER: Driver = Driver {
+    name: "virtio-net",
+    order: 30,
+    matches: &super::matches(NETWORK),
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+/// A virtqueue along with the buffers the device currently owns, indexed by head descriptor
+struct Ring {
+    queue: Virtqueue,
+    buffers: Vec<Option<Dma>>,
+}
+
+impl Ring {
+    fn new(queue: Virtqueue) -> Ring {
+        Ring {
+            buffers: (0..queue.size()).map(|_| None).collect(),
+            queue,
+        }
+    }
+
+    fn push(&mut self, buffer: Dma, len: usize, writable: bool) -> Result<(), Dma> {
+        let descriptor = Buffer {
+            addr: buffer.phys(),
+            len: len as u32,
+            writable,
+        };
+
+        match self.queue.push(&[descriptor]) {
+            Some(head) => {
+                self.buffers[head as usize] = Some(buffer);
+                Ok(())
+            }
+            None => Err(buffer),
+        }
+    }
+}
+
+pub struct VirtioNet {
+    name: String,
+    transport: Transport,
+    mac: MacAddress,
+    /// Whether the device reports its link state, it's assumed up otherwise
+    status: bool,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+}
+
+impl VirtioNet {
+    /// Hands every free receive descriptor a buffer
+    fn fill_rx(&self, rx: &mut Ring) {
+        while rx.queue.free_descriptors() > 0 {
+            if rx.push(Dma::new(BUFFER_SIZE), BUFFER_SIZE, true).is_err() {
+                break;
+            }
+        }
+
+        self.transport.notify(&rx.queue);
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        !self.status || self.transport.read_config::<u16>(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+
+        if !self.link_up() {
+            return Err(Error::LinkDown);
+        }
+
+        let mut tx = self.tx.lock();
+
+        // Frames the device is done with free their buffers
+        while let Some((head, _)) = tx.queue.pop_used() {
+            tx.buffers[head as usize] = None;
+        }
+
+        // No offloads, the header stays zeroed
+        let mut buffer = Dma::new(HEADER_SIZE + frame.len());
+        buffer.as_mut_slice()[HEADER_SIZE..HEADER_SIZE + frame.len()].copy_from_slice(frame);
+
+        tx.push(buffer, HEADER_SIZE + frame.len(), false)
+            .map_err(|_| Error::Busy)?;
+        self.transport.notify(&tx.queue);
+
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<PacketBuffer> {
+        let mut rx = self.rx.lock();
+        let (head, len) = rx.queue.pop_used()?;
+        let buffer = rx.buffers[head as usize].take()?;
+
+        let len = (len as usize).clamp(HEADER_SIZE, BUFFER_SIZE);
+        let frame = PacketBuffer::from_slice(&buffer.as_slice()[HEADER_SIZE..len]);
+
+        // The buffer goes straight back to the device
+        if rx.push(buffer, BUFFER_SIZE, true).is_ok() {
+            self.transport.notify(&rx.queue);
+        }
+
+        Some(frame)
+    }
+}
+
+/// Waking the core up is enough, `net::poll` collects the frames
+fn interrupt() {}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+    let mut transport = Transport::new(pci).map_err(|_| ProbeError::Failed("bad transport"))?;
+
+    let features = transport
+        .negotiate(F_MAC | F_STATUS)
+        .map_err(|_| ProbeError::Failed("feature negotiation failed"))?;
+
+    if features & F_MAC == 0 {
+        return Err(ProbeError::Failed("no MAC address"));
+    }
+
+    if let Err(e) = transport.enable_interrupts(interrupt) {
+        log::warn!("{}: no interrupts ({e:?}), polling", pci.address);
+    }
+
+    let rx = transport
+        .setup_queue(RX_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no receive queue"))?;
+    let tx = transport
+        .setup_queue(TX_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no transmit queue"))?;
+
+    let mut mac = [0; 6];
+    for (i, byte) in mac.iter_mut().enumerate() {
+        *byte = transport.read_config(CONFIG_MAC + i);
+    }
+
+    let adapter = Arc::new(VirtioNet {
+        name: net::next_name("eth"),
+        transport,
+        mac: MacAddress(mac),
+        status: features & F_STATUS != 0,
+        rx: Mutex::new(Ring::new(rx)),
+        tx: Mutex::new(Ring::new(tx)),
+    });
+
+    adapter.transport.finish();
+    adapter.fill_rx(&mut adapter.rx.lock());
+
+    ADAPTERS.lock().push(adapter.clone());
+    net::register(adapter);
+
+    Ok(())
+}
+
+fn remove(device: &driver::Device) {
+    let Some(pci) = device.as_pci() else {
+        return;
+    };
+
+    let mut adapters = ADAPTERS.lock();
+    if let Some(i) = adapters
+        .iter()
+        .position(|a| a.transport.device().address == pci.address)
+    {
+        let adapter = adapters.remove(i);
+
+        net::unregister(&adapter.name);
+        adapter.transport.reset();
+    }
+}
@@ -0,0 +1,152 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Buffer, Transport, Virtqueue, NINE_P};
+use crate::driver::{self, Driver, ProbeError};
+use crate::fs::{self, ninep::Channel};
+use crate::mm::dma::Dma;
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// The device has a mount tag in its configuration
+const F_MOUNT_TAG: u64 = 1 << 0;
+
+const CONFIG_TAG_LEN: usize = 0;
+const CONFIG_TAG: usize = 2;
+
+const REQUEST_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+
+/// Largest 9P message in either direction
+const MSIZE: u32 = 64 * 1024;
+
+static MOUNTS: Mutex<Vec<(String, Arc<VirtioNineP>)>> = Mutex::new(Vec::new());
+
+static DRIVER: Driver = Driver {
+    name: "virtio-9p",
+    order: 10,
+    matches: &super::matches(NINE_P),
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+struct Inner {
+    transport: Transport,
+    queue: Virtqueue,
+    request: Dma,
+    response: Dma,
+}
+
+pub struct VirtioNineP {
+    inner: Mutex<Inner>,
+}
+
+impl Channel for VirtioNineP {
+    fn msize(&self) -> u32 {
+        MSIZE
+    }
+
+    fn rpc(&self, request: &[u8]) -> Result<Vec<u8>, fs::Error> {
+        if request.len() > MSIZE as usize {
+            return Err(fs::Error::Io);
+        }
+
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+
+        inner.request.as_mut_slice()[..request.len()].copy_from_slice(request);
+
+        let buffers = [
+            Buffer::readable(inner.request.phys(), request.len() as u32),
+            Buffer::writable(inner.response.phys(), MSIZE),
+        ];
+        let head = inner.queue.push(&buffers).ok_or(fs::Error::Io)?;
+        inner.transport.notify(&inner.queue);
+
+        loop {
+            match inner.queue.pop_used() {
+                Some((used, _)) if used == head => break,
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        }
+
+        let size = (inner.response.read::<u32>(0) as usize).min(MSIZE as usize);
+        Ok(inner.response.as_slice()[..size].to_vec())
+    }
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+
+    let transport = Transport::new(pci).map_err(|_| ProbeError::Failed("bad transport"))?;
+    transport
+        .negotiate(F_MOUNT_TAG)
+        .map_err(|_| ProbeError::Failed("feature negotiation failed"))?;
+
+    let queue = transport
+        .setup_queue(REQUEST_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no request queue"))?;
+    transport.finish();
+
+    let len = transport.read_config::<u16>(CONFIG_TAG_LEN) as usize;
+    let tag: Vec<u8> = (0..len)
+        .map(|i| transport.read_config::<u8>(CONFIG_TAG + i))
+        .collect();
+    let tag = String::from_utf8_lossy(&tag).into_owned();
+
+    let channel = Arc::new(VirtioNineP {
+        inner: Mutex::new(Inner {
+            transport,
+            queue,
+            request: Dma::new(MSIZE as usize),
+            response: Dma::new(MSIZE as usize),
+        }),
+    });
+
+    let nine_p = fs::ninep::connect(channel.clone(), "")
+        .map_err(|_| ProbeError::Failed("cannot attach to the server"))?;
+
+    let path = alloc::format!("/mnt/{tag}");
+    fs::mount(&path, Arc::new(nine_p)).map_err(|_| ProbeError::Failed("cannot mount"))?;
+
+    MOUNTS.lock().push((path, channel));
+    Ok(())
+}
+
+fn remove(device: &driver::Device) {
+    let Some(pci) = device.as_pci() else {
+        return;
+    };
+
+    let mut mounts = MOUNTS.lock();
+    let Some(i) = mounts
+        .iter()
+        .position(|(_, channel)| channel.inner.lock().transport.device().address == pci.address)
+    else {
+        return;
+    };
+
+    // Unmounting clunks the root fid, the device has to be alive for that
+    let (path, channel) = mounts.remove(i);
+    let _ = fs::unmount(&path);
+    channel.inner.lock().transport.reset();
+}
@@ -0,0 +1,318 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Error, Virtqueue, F_VERSION_1, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK};
+use super::{STATUS_FAILED, STATUS_FEATURES_OK, VENDOR_ID};
+use crate::apic;
+use crate::mm::mmio::Mmio;
+use crate::pci::caps::{Capability, CAP_VENDOR};
+use crate::pci::Device;
+use core::sync::atomic::{fence, Ordering};
+
+/// `cfg_type` of the vendor capabilities describing where each structure lives
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_DEVICE: u8 = 4;
+
+/// Common configuration registers
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0C;
+const CONFIG_MSIX_VECTOR: usize = 0x10;
+const NUM_QUEUES: usize = 0x12;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1A;
+const QUEUE_ENABLE: usize = 0x1C;
+const QUEUE_NOTIFY_OFF: usize = 0x1E;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+const NO_VECTOR: u16 = 0xFFFF;
+
+/// A structure inside one of the function's BARs
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    bar: u8,
+    offset: usize,
+    len: usize,
+}
+
+/// The virtio over PCI transport of a modern (1.0+) device
+pub struct Transport {
+    device: Device,
+    bars: [Option<Mmio>; 6],
+    common: Region,
+    notify: Region,
+    notify_multiplier: u32,
+    config: Option<Region>,
+    msix: bool,
+}
+
+impl Transport {
+    /// Finds the configuration structures of `device`, resets it and acknowledges it
+    pub fn new(device: &Device) -> Result<Transport, Error> {
+        if device.vendor_id != VENDOR_ID || !(0x1000..=0x107F).contains(&device.device_id) {
+            return Err(Error::NotVirtio);
+        }
+
+        let (mut common, mut notify, mut config) = (None, None, None);
+        let mut notify_multiplier = 0;
+
+        for capability in device.capabilities() {
+            let Capability::Other {
+                id: CAP_VENDOR,
+                offset,
+            } = capability
+            else {
+                continue;
+            };
+
+            let region = Region {
+                bar: device.read8(offset + 4),
+                offset: device.read32(offset + 8) as usize,
+                len: device.read32(offset + 12) as usize,
+            };
+
+            if region.bar >= 6 {
+                continue;
+            }
+
+            // The first structure of each kind is the preferred one
+            match device.read8(offset + 3) {
+                CFG_COMMON => common = common.or(Some(region)),
+                CFG_NOTIFY if notify.is_none() => {
+                    notify = Some(region);
+                    notify_multiplier = device.read32(offset + 16);
+                }
+                CFG_DEVICE => config = config.or(Some(region)),
+                _ => {}
+            }
+        }
+
+        let common = common.ok_or(Error::MissingCapability)?;
+        let notify = notify.ok_or(Error::MissingCapability)?;
+
+        let mut bars = [None, None, None, None, None, None];
+        for region in [Some(common), Some(notify), config].into_iter().flatten() {
+            map_bar(&mut bars, device, region.bar)?;
+
+            if region.offset + region.len > bars[region.bar as usize].as_ref().unwrap().len() {
+                return Err(Error::MapFailed);
+            }
+        }
+
+        device.enable_bus_mastering();
+
+        let transport = Transport {
+            device: *device,
+            bars,
+            common,
+            notify,
+            notify_multiplier,
+            config,
+            msix: false,
+        };
+
+        transport.reset();
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        Ok(transport)
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn read<T: Copy>(&self, region: Region, offset: usize) -> T {
+        self.bars[region.bar as usize]
+            .as_ref()
+            .unwrap()
+            .read(region.offset + offset)
+    }
+
+    fn write<T: Copy>(&self, region: Region, offset: usize, value: T) {
+        self.bars[region.bar as usize]
+            .as_ref()
+            .unwrap()
+            .write(region.offset + offset, value)
+    }
+
+    pub fn status(&self) -> u8 {
+        self.read(self.common, DEVICE_STATUS)
+    }
+
+    /// Sets `bits` on top of the current status
+    pub fn set_status(&self, bits: u8) {
+        self.write(self.common, DEVICE_STATUS, self.status() | bits);
+    }
+
+    pub fn reset(&self) {
+        self.write(self.common, DEVICE_STATUS, 0u8);
+
+        // The device is done resetting once it reads back 0
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn device_features(&self) -> u64 {
+        self.write(self.common, DEVICE_FEATURE_SELECT, 0u32);
+        let low = self.read::<u32>(self.common, DEVICE_FEATURE);
+        self.write(self.common, DEVICE_FEATURE_SELECT, 1u32);
+        let high = self.read::<u32>(self.common, DEVICE_FEATURE);
+
+        (high as u64) << 32 | low as u64
+    }
+
+    /// Accepts the features in `wanted` the device offers, plus `VERSION_1`, and returns them
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, Error> {
+        let features = self.device_features() & (wanted | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(Error::FeaturesRejected);
+        }
+
+        self.write(self.common, DRIVER_FEATURE_SELECT, 0u32);
+        self.write(self.common, DRIVER_FEATURE, features as u32);
+        self.write(self.common, DRIVER_FEATURE_SELECT, 1u32);
+        self.write(self.common, DRIVER_FEATURE, (features >> 32) as u32);
+
+        self.set_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(Error::FeaturesRejected);
+        }
+
+        Ok(features)
+    }
+
+    /// Routes the configuration change and every queue interrupt to `handler` through MSI-X
+    ///
+    /// Has to happen before the queues are set up, they pick their vector at that point
+    pub fn enable_interrupts(&mut self, handler: fn()) -> Result<(), Error> {
+        let msix = self.device.msix().ok_or(Error::MissingCapability)?;
+
+        let (bar, _) = msix.table();
+        map_bar(&mut self.bars, &self.device, bar)?;
+
+        let vector = super::register_handler(handler)?;
+        let apic_id = core!().apic.lock().id();
+        let table = self.bars[bar as usize].as_ref().unwrap();
+
+        msix.set_entry(table, 0, apic::msi_address(apic_id), vector as u32);
+        msix.enable(&self.device);
+
+        self.write(self.common, CONFIG_MSIX_VECTOR, 0u16);
+        if self.read::<u16>(self.common, CONFIG_MSIX_VECTOR) == NO_VECTOR {
+            return Err(Error::NoVector);
+        }
+
+        self.msix = true;
+        Ok(())
+    }
+
+    pub fn queue_count(&self) -> u16 {
+        self.read(self.common, NUM_QUEUES)
+    }
+
+    /// Allocates queue `index` with at most `max_size` entries and hands it to the device
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<Virtqueue, Error> {
+        self.write(self.common, QUEUE_SELECT, index);
+
+        let size = self.read::<u16>(self.common, QUEUE_SIZE).min(max_size);
+        if index >= self.queue_count() || size == 0 {
+            return Err(Error::QueueUnavailable);
+        }
+
+        // Virtio 1.0 split rings must be a power of two long
+        let size = 1 << size.ilog2();
+        let notify_offset = self.read::<u16>(self.common, QUEUE_NOTIFY_OFF) as usize
+            * self.notify_multiplier as usize;
+
+        if notify_offset + 2 > self.notify.len {
+            return Err(Error::QueueUnavailable);
+        }
+
+        let queue = Virtqueue::new(index, size, notify_offset);
+
+        self.write(self.common, QUEUE_SIZE, size);
+        self.write(
+            self.common,
+            QUEUE_MSIX_VECTOR,
+            if self.msix { 0 } else { NO_VECTOR },
+        );
+        self.write(self.common, QUEUE_DESC, queue.descriptors_phys().as_u64());
+        self.write(self.common, QUEUE_DRIVER, queue.avail_phys().as_u64());
+        self.write(self.common, QUEUE_DEVICE, queue.used_phys().as_u64());
+        self.write(self.common, QUEUE_ENABLE, 1u16);
+
+        Ok(queue)
+    }
+
+    /// Tells the device there are new buffers in `queue`
+    pub fn notify(&self, queue: &Virtqueue) {
+        fence(Ordering::SeqCst);
+        self.write(self.notify, queue.notify_offset, queue.index());
+    }
+
+    /// Done setting up, the device can start working
+    pub fn finish(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    /// Reads the device specific configuration, retrying if the device changed it midway
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        let config = self.config.expect("Device has no configuration structure");
+
+        loop {
+            let generation = self.read::<u8>(self.common, CONFIG_GENERATION);
+            let value = self.read(config, offset);
+
+            if self.read::<u8>(self.common, CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+    }
+
+    pub fn write_config<T: Copy>(&self, offset: usize, value: T) {
+        let config = self.config.expect("Device has no configuration structure");
+        self.write(config, offset, value);
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        self.reset();
+
+        if let (true, Some(msix)) = (self.msix, self.device.msix()) {
+            msix.disable(&self.device);
+        }
+    }
+}
+
+fn map_bar(bars: &mut [Option<Mmio>; 6], device: &Device, bar: u8) -> Result<(), Error> {
+    if bars[bar as usize].is_none() {
+        bars[bar as usize] = Some(device.map_bar(bar).ok_or(Error::MapFailed)?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::mm::align_up;
+use crate::mm::dma::Dma;
+use crate::mm::PhysAddr;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+/// Descriptor flags
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+
+const DESC_SIZE: usize = 16;
+const USED_ELEM_SIZE: usize = 8;
+
+/// A physically contiguous piece of a request
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// The device writes to it rather than reading from it
+    pub writable: bool,
+}
+
+impl Buffer {
+    pub fn readable(addr: PhysAddr, len: u32) -> Buffer {
+        Buffer {
+            addr,
+            len,
+            writable: false,
+        }
+    }
+
+    pub fn writable(addr: PhysAddr, len: u32) -> Buffer {
+        Buffer {
+            addr,
+            len,
+            writable: true,
+        }
+    }
+}
+
+/// A split virtqueue: descriptor table, available ring and used ring in one DMA allocation
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: Dma,
+    avail: usize,
+    used: usize,
+    free: Vec<u16>,
+    next_avail: u16,
+    last_used: u16,
+    pub(super) notify_offset: usize,
+}
+
+impl Virtqueue {
+    pub(super) fn new(index: u16, size: u16, notify_offset: usize) -> Virtqueue {
+        let n = size as usize;
+        let avail = n * DESC_SIZE;
+        let used = align_up((avail + 6 + 2 * n) as u64, 4) as usize;
+
+        Virtqueue {
+            index,
+            size,
+            memory: Dma::new(used + 6 + USED_ELEM_SIZE * n),
+            avail,
+            used,
+            free: (0..size).rev().collect(),
+            next_avail: 0,
+            last_used: 0,
+            notify_offset,
+        }
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(super) fn descriptors_phys(&self) -> PhysAddr {
+        self.memory.phys()
+    }
+
+    pub(super) fn avail_phys(&self) -> PhysAddr {
+        PhysAddr::new(self.memory.phys().as_u64() + self.avail as u64)
+    }
+
+    pub(super) fn used_phys(&self) -> PhysAddr {
+        PhysAddr::new(self.memory.phys().as_u64() + self.used as u64)
+    }
+
+    pub fn free_descriptors(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Chains `buffers` and makes them available, returns the head descriptor identifying the request
+    ///
+    /// The device isn't told, that's `Transport::notify`
+    pub fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+
+        let descriptors: Vec<u16> = (0..buffers.len())
+            .map(|_| self.free.pop().unwrap())
+            .collect();
+
+        for (i, (buffer, &desc)) in buffers.iter().zip(&descriptors).enumerate() {
+            let next = descriptors.get(i + 1).copied();
+            let mut flags = if buffer.writable { DESC_WRITE } else { 0 };
+            if next.is_some() {
+                flags |= DESC_NEXT;
+            }
+
+            let offset = desc as usize * DESC_SIZE;
+            self.memory.write(offset, buffer.addr.as_u64());
+            self.memory.write(offset + 8, buffer.len);
+            self.memory.write(offset + 12, flags);
+            self.memory.write(offset + 14, next.unwrap_or(0));
+        }
+
+        let head = descriptors[0];
+        let slot = (self.next_avail % self.size) as usize;
+        self.memory.write(self.avail + 4 + slot * 2, head);
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        // The ring entry has to be visible before the index that publishes it
+        fence(Ordering::SeqCst);
+        self.memory.write(self.avail + 2, self.next_avail);
+
+        Some(head)
+    }
+
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.memory.read::<u16>(self.used + 2) != self.last_used
+    }
+
+    /// Takes a request the device is done with, returns its head descriptor and how many bytes were written
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+
+        let slot = (self.last_used % self.size) as usize;
+        let elem = self.used + 4 + slot * USED_ELEM_SIZE;
+        let head = self.memory.read::<u32>(elem) as u16;
+        let len = self.memory.read::<u32>(elem + 4);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut desc = head;
+        loop {
+            self.free.push(desc);
+
+            let offset = desc as usize * DESC_SIZE;
+            if self.memory.read::<u16>(offset + 12) & DESC_NEXT == 0 {
+                break;
+            }
+
+            desc = self.memory.read(offset + 14);
+        }
+
+        Some((head, len))
+    }
+}
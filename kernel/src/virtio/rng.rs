@@ -0,0 +1,144 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use super::{Buffer, Transport, Virtqueue, ENTROPY};
+use crate::driver::{self, Driver, ProbeError};
+use crate::mm::dma::Dma;
+use crate::sync::Mutex;
+use crate::{cpu, random, time};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const REQUEST_QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 8;
+
+/// Bytes asked for in each request
+const REQUEST_SIZE: usize = 64;
+
+/// Requests made at probe time, enough to seed the pool even if the device is stingy
+const SEED_REQUESTS: usize = 16;
+
+/// How often the pool gets topped up from the host after that
+const RESEED_PERIOD_US: u64 = 60_000_000;
+
+static DEVICE: Mutex<Option<VirtioRng>> = Mutex::new(None);
+static LAST_RESEED: AtomicU64 = AtomicU64::new(0);
+
+static DRIVER: Driver = Driver {
+    name: "virtio-rng",
+    order: 10,
+    matches: &super::matches(ENTROPY),
+    probe,
+    remove: Some(remove),
+};
+
+driver!(DRIVER);
+
+struct VirtioRng {
+    transport: Transport,
+    queue: Virtqueue,
+    buffer: Dma,
+}
+
+impl VirtioRng {
+    /// Asks the host for entropy and feeds what comes back to the pool, returns how many bytes it got
+    fn harvest(&mut self) -> usize {
+        let Some(head) = self
+            .queue
+            .push(&[Buffer::writable(self.buffer.phys(), REQUEST_SIZE as u32)])
+        else {
+            return 0;
+        };
+
+        self.transport.notify(&self.queue);
+
+        let len = loop {
+            match self.queue.pop_used() {
+                Some((used, len)) if used == head => break len as usize,
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        };
+
+        let len = len.min(REQUEST_SIZE);
+        random::add_entropy(&self.buffer.as_slice()[..len], len * 8);
+        len
+    }
+}
+
+/// Tops the entropy pool up from the host every minute, cheap when it's not time yet
+pub fn update() {
+    let now = unsafe { cpu::rdtsc() / time::tsc_per_us() };
+    if now.saturating_sub(LAST_RESEED.load(Ordering::Relaxed)) < RESEED_PERIOD_US {
+        return;
+    }
+
+    let Some(mut device) = DEVICE.try_lock() else {
+        return;
+    };
+    LAST_RESEED.store(now, Ordering::Relaxed);
+
+    if let Some(rng) = device.as_mut() {
+        rng.harvest();
+    }
+}
+
+fn probe(device: &driver::Device) -> Result<(), ProbeError> {
+    let pci = device.as_pci().ok_or(ProbeError::Unsupported)?;
+
+    if DEVICE.lock().is_some() {
+        return Err(ProbeError::Unsupported);
+    }
+
+    let transport = Transport::new(pci).map_err(|_| ProbeError::Failed("bad transport"))?;
+    transport
+        .negotiate(0)
+        .map_err(|_| ProbeError::Failed("feature negotiation failed"))?;
+
+    let queue = transport
+        .setup_queue(REQUEST_QUEUE, QUEUE_SIZE)
+        .map_err(|_| ProbeError::Failed("no request queue"))?;
+    transport.finish();
+
+    let mut rng = VirtioRng {
+        transport,
+        queue,
+        buffer: Dma::new(REQUEST_SIZE),
+    };
+
+    let mut harvested = 0;
+    for _ in 0..SEED_REQUESTS {
+        if random::seeded() {
+            break;
+        }
+
+        harvested += rng.harvest();
+    }
+
+    log::debug!(
+        "virtio-rng: {harvested} bytes harvested, pool has {} bits",
+        random::entropy_bits()
+    );
+
+    *DEVICE.lock() = Some(rng);
+    Ok(())
+}
+
+fn remove(_device: &driver::Device) {
+    if let Some(rng) = DEVICE.lock().take() {
+        rng.transport.reset();
+    }
+}
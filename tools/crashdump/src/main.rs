@@ -0,0 +1,354 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::process::ExitCode;
+
+const BEGIN: &str = "-----BEGIN BERYL CRASH DUMP-----";
+const END: &str = "-----END BERYL CRASH DUMP crc32=";
+/// The newest dump format this understands
+const VERSION: u32 = 1;
+
+const PAGE_SIZE: u64 = 4096;
+
+const USAGE: &str = "\
+usage: crashdump [--all] [file]
+
+Prints the crash dump in a serial capture of a kernel that panicked, from standard input without
+a file. Only the last dump in the capture is printed unless --all is passed.";
+
+#[derive(Default)]
+struct Core {
+    id: usize,
+    status: String,
+    regs: Vec<(String, u64)>,
+    frames: Vec<(u64, String)>,
+}
+
+#[derive(Default)]
+struct Dump {
+    version: u32,
+    kernel: String,
+    uptime_us: u64,
+    panic: String,
+    location: Option<String>,
+    /// Vector, error code, CR2 and name
+    exception: Option<(u64, u64, u64, String)>,
+    panicked_core: usize,
+    cores: Vec<Core>,
+    mem: Vec<(String, u64)>,
+    log: Vec<String>,
+    /// Whether the checksum matched, a mangled capture is still worth printing
+    intact: bool,
+}
+
+/// CRC-32 as zlib computes it, the kernel checksums the dump with it
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Undoes the escaping of free text
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+
+    out
+}
+
+fn hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.strip_prefix("0x").unwrap_or(text), 16).ok()
+}
+
+/// `key=value` pairs, values in hex with a `0x` prefix or decimal
+fn pairs(fields: &str) -> Vec<(String, u64)> {
+    fields
+        .split_whitespace()
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            let value = match value.strip_prefix("0x") {
+                Some(digits) => u64::from_str_radix(digits, 16).ok()?,
+                None => value.parse().ok()?,
+            };
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+fn core(dump: &mut Dump, id: usize) -> &mut Core {
+    if let Some(index) = dump.cores.iter().position(|core| core.id == id) {
+        return &mut dump.cores[index];
+    }
+
+    dump.cores.push(Core {
+        id,
+        ..Default::default()
+    });
+    dump.cores.last_mut().unwrap()
+}
+
+fn parse_line(dump: &mut Dump, line: &str) -> Option<()> {
+    let (tag, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    match tag {
+        "version" => dump.version = rest.parse().ok()?,
+        "kernel" => dump.kernel = rest.to_string(),
+        "uptime_us" => dump.uptime_us = rest.parse().ok()?,
+        "panic" => dump.panic = unescape(rest),
+        "location" => dump.location = Some(rest.to_string()),
+        "exception" => {
+            let mut fields = rest.splitn(4, ' ');
+            dump.exception = Some((
+                hex(fields.next()?)?,
+                hex(fields.next()?)?,
+                hex(fields.next()?)?,
+                fields.next().unwrap_or("").to_string(),
+            ));
+        }
+        "cores" => dump.panicked_core = rest.split_whitespace().nth(1)?.parse().ok()?,
+        "core" => {
+            let (id, status) = rest.split_once(' ')?;
+            core(dump, id.parse().ok()?).status = status.to_string();
+        }
+        "regs" => {
+            let (id, fields) = rest.split_once(' ')?;
+            core(dump, id.parse().ok()?).regs = pairs(fields);
+        }
+        "frame" => {
+            let mut fields = rest.splitn(4, ' ');
+            let id = fields.next()?.parse().ok()?;
+            let _index = fields.next()?;
+            let rip = hex(fields.next()?)?;
+            let symbol = fields.next().unwrap_or("?").to_string();
+            core(dump, id).frames.push((rip, symbol));
+        }
+        "mem" => dump.mem = pairs(rest),
+        "log" => dump.log.push(log_line(rest)?),
+        // Newer kernels might say more, what's known still gets printed
+        _ => {}
+    }
+
+    Some(())
+}
+
+/// A log record, in the same layout as the kernel's own plain lines
+fn log_line(fields: &str) -> Option<String> {
+    let mut fields = fields.splitn(7, ' ');
+    let sequence = fields.next()?;
+    let time_us: u64 = fields.next()?.parse().ok()?;
+    let level = fields.next()?;
+    let core = fields.next()?;
+    let location = fields.next()?;
+    let _module = fields.next()?;
+    let message = unescape(fields.next().unwrap_or(""));
+
+    Some(format!(
+        "[{:>5}.{:06}] #{sequence} [{core}] {location} {level} {message}",
+        time_us / 1_000_000,
+        time_us % 1_000_000
+    ))
+}
+
+/// Every dump in `capture`, in order
+fn dumps(capture: &str) -> Vec<Dump> {
+    let mut dumps = Vec::new();
+    let mut lines = capture.lines().map(|line| line.trim_end_matches('\r'));
+
+    while lines.any(|line| line.ends_with(BEGIN)) {
+        let mut dump = Dump::default();
+        let mut body = String::new();
+
+        for line in lines.by_ref() {
+            if let Some(crc) = line.strip_prefix(END) {
+                let crc = crc.strip_suffix("-----").and_then(hex);
+                dump.intact = crc == Some(crc32(body.as_bytes()) as u64);
+                break;
+            }
+
+            body.push_str(line);
+            body.push('\n');
+            if parse_line(&mut dump, line).is_none() {
+                eprintln!("crashdump: can't parse {line:?}");
+            }
+        }
+
+        dumps.push(dump);
+    }
+
+    dumps
+}
+
+fn mib(pages: u64) -> String {
+    format!("{:.1} MiB", (pages * PAGE_SIZE) as f64 / (1024.0 * 1024.0))
+}
+
+fn format(dump: &Dump) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "Beryl {} crashed after {}.{:06}s",
+        dump.kernel,
+        dump.uptime_us / 1_000_000,
+        dump.uptime_us % 1_000_000
+    );
+    if !dump.intact {
+        let _ = writeln!(
+            out,
+            "warning: the checksum doesn't match, the capture is damaged"
+        );
+    }
+    if dump.version > VERSION {
+        let _ = writeln!(
+            out,
+            "warning: dump version {} is newer than this tool, some of it is skipped",
+            dump.version
+        );
+    }
+
+    let _ = writeln!(out, "\npanic: {}", dump.panic.replace('\n', "\n       "));
+    if let Some(location) = &dump.location {
+        let _ = writeln!(out, "  at {location}");
+    }
+    if let Some((vector, code, cr2, name)) = &dump.exception {
+        let _ = writeln!(
+            out,
+            "exception: {name} ({vector:#x}), error code {code:#x}, cr2 {cr2:#018x}"
+        );
+    }
+
+    for core in &dump.cores {
+        let marker = match core.id == dump.panicked_core {
+            true => ", panicked here",
+            false => "",
+        };
+        let _ = writeln!(out, "\ncore {} ({}{marker})", core.id, core.status);
+
+        for row in core.regs.chunks(4) {
+            out.push(' ');
+            for (name, value) in row {
+                let _ = write!(out, " {name:<6} {value:016x}");
+            }
+            out.push('\n');
+        }
+
+        for (i, (rip, symbol)) in core.frames.iter().enumerate() {
+            let _ = writeln!(out, "  {i:>2}: {rip:#018x} - {symbol}");
+        }
+    }
+
+    let mem = |key: &str| {
+        dump.mem
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|&(_, value)| value)
+    };
+    if let (Some(total), Some(free)) = (mem("total_pages"), mem("free_pages")) {
+        let _ = write!(out, "\nmemory: {} free of {}", mib(free), mib(total));
+        if let Some(ballooned) = mem("ballooned_pages").filter(|&pages| pages != 0) {
+            let _ = write!(out, ", {} ballooned", mib(ballooned));
+        }
+        match mem("heap_used") {
+            Some(used) => {
+                let _ = writeln!(out, ", heap {} KiB used", used / 1024);
+            }
+            None => out.push_str(", heap locked\n"),
+        }
+    }
+
+    if !dump.log.is_empty() {
+        let _ = writeln!(out, "\nlast {} log records:", dump.log.len());
+        for line in &dump.log {
+            let _ = writeln!(out, "  {}", line.replace('\n', "\n  "));
+        }
+    }
+
+    out
+}
+
+fn main() -> ExitCode {
+    let mut all = false;
+    let mut file = None;
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--help" | "-h" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            "--all" => all = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("crashdump: unknown option {arg}\n\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+            _ => file = Some(arg),
+        }
+    }
+
+    let mut input = Vec::new();
+    let read = match &file {
+        Some(path) => std::fs::File::open(path).and_then(|mut file| file.read_to_end(&mut input)),
+        None => io::stdin().read_to_end(&mut input),
+    };
+    if let Err(err) = read {
+        eprintln!("crashdump: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    let dumps = dumps(&String::from_utf8_lossy(&input));
+    let Some(last) = dumps.last() else {
+        eprintln!("crashdump: no crash dump in the capture");
+        return ExitCode::FAILURE;
+    };
+
+    if all {
+        let printed: Vec<String> = dumps.iter().map(format).collect();
+        print!("{}", printed.join("\n"));
+    } else {
+        print!("{}", format(last));
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,135 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+
+const PREFIX: &str = "kbench: ";
+/// Changes smaller than this many percent are noise by default
+const DEFAULT_THRESHOLD: f64 = 10.0;
+
+const USAGE: &str = "\
+usage: kbenchcmp [--threshold percent] <old> <new>
+
+Compares the medians of the benchmarks in two serial captures of `make bench`, failing if any got
+slower by more than the threshold, 10% unless given.";
+
+/// Median nanoseconds per iteration of every benchmark in `capture`, by path
+fn results(capture: &str) -> BTreeMap<String, u64> {
+    let mut results = BTreeMap::new();
+
+    for line in capture.lines() {
+        let Some((_, result)) = line.split_once(PREFIX) else {
+            continue;
+        };
+
+        let mut fields = result.split_whitespace();
+        let Some(path) = fields.next() else {
+            continue;
+        };
+        let median = fields
+            .filter_map(|field| field.strip_prefix("median="))
+            .find_map(|median| median.parse().ok());
+
+        if let Some(median) = median {
+            results.insert(path.to_string(), median);
+        }
+    }
+
+    results
+}
+
+fn read(path: &str) -> Result<BTreeMap<String, u64>, String> {
+    let capture = std::fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+    Ok(results(&String::from_utf8_lossy(&capture)))
+}
+
+fn main() -> ExitCode {
+    let mut threshold = DEFAULT_THRESHOLD;
+    let mut files = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            "--threshold" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => threshold = value,
+                None => {
+                    eprintln!("kbenchcmp: --threshold takes a percentage\n\n{USAGE}");
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ if arg.starts_with("--") => {
+                eprintln!("kbenchcmp: unknown option {arg}\n\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    let [old, new] = files.as_slice() else {
+        eprintln!("kbenchcmp: expected two captures\n\n{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    let (old, new) = match (read(old), read(new)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(err), _) | (_, Err(err)) => {
+            eprintln!("kbenchcmp: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut regressions = 0;
+    println!(
+        "{:<48} {:>10} {:>10} {:>8}",
+        "benchmark", "old ns", "new ns", "change"
+    );
+
+    for (path, &after) in &new {
+        let Some(&before) = old.get(path) else {
+            println!("{path:<48} {:>10} {after:>10} {:>8}", "-", "new");
+            continue;
+        };
+
+        let change = (after as f64 - before as f64) * 100.0 / (before as f64).max(1.0);
+        let marker = if change > threshold {
+            regressions += 1;
+            "  slower"
+        } else if change < -threshold {
+            "  faster"
+        } else {
+            ""
+        };
+
+        println!("{path:<48} {before:>10} {after:>10} {change:>+7.1}%{marker}");
+    }
+
+    for path in old.keys().filter(|path| !new.contains_key(*path)) {
+        println!("{path:<48} {:>10} {:>10} {:>8}", old[path], "-", "gone");
+    }
+
+    if regressions != 0 {
+        eprintln!("kbenchcmp: {regressions} benchmarks got slower by more than {threshold}%");
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
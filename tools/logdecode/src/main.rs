@@ -0,0 +1,268 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+const MAGIC: &[u8; 4] = b"\x1eBLG";
+const LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+const USAGE: &str = "\
+usage: logdecode [--json] [--level <error|warn|info|debug|trace>] [--module <prefix>] [--raw] [file]
+
+Prints the records in a serial or debugcon capture of a kernel booted with logformat=framed,
+from standard input without a file. Whatever isn't a record, like the panic monitor, is dropped
+unless --raw is passed, then it goes to standard error.";
+
+struct Record {
+    sequence: u64,
+    time_us: u64,
+    level: u8,
+    core: u16,
+    line: u32,
+    file: String,
+    module: String,
+    message: String,
+}
+
+struct Options {
+    json: bool,
+    raw: bool,
+    /// The most verbose level printed, 1 is error
+    level: u8,
+    module: Option<String>,
+    file: Option<String>,
+}
+
+/// CRC-32 as zlib computes it, the kernel checksums every payload with it
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Reads the fields of a payload in order
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (head, rest) = self.bytes.split_at_checked(N)?;
+        self.bytes = rest;
+        head.try_into().ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = u16::from_le_bytes(self.take()?) as usize;
+        let (text, rest) = self.bytes.split_at_checked(len)?;
+        self.bytes = rest;
+        Some(String::from_utf8_lossy(text).into_owned())
+    }
+}
+
+fn parse(payload: &[u8]) -> Option<Record> {
+    let mut reader = Reader { bytes: payload };
+
+    Some(Record {
+        sequence: u64::from_le_bytes(reader.take()?),
+        time_us: u64::from_le_bytes(reader.take()?),
+        level: reader.take::<1>()?[0],
+        core: u16::from_le_bytes(reader.take()?),
+        line: u32::from_le_bytes(reader.take()?),
+        file: reader.string()?,
+        module: reader.string()?,
+        message: reader.string()?,
+    })
+}
+
+/// What starts at `input[0]`: a record and how many bytes it took, or `None` if it's no frame
+fn frame(input: &[u8]) -> Option<(Record, usize)> {
+    let rest = input.strip_prefix(MAGIC)?;
+    let len = u16::from_le_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+    let payload = rest.get(2..2 + len)?;
+    let crc = u32::from_le_bytes(rest.get(2 + len..6 + len)?.try_into().ok()?);
+
+    if crc32(payload) != crc {
+        return None;
+    }
+
+    Some((parse(payload)?, MAGIC.len() + 6 + len))
+}
+
+fn level_name(level: u8) -> &'static str {
+    LEVELS.get(level.wrapping_sub(1) as usize).unwrap_or(&"?")
+}
+
+fn json_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn format(record: &Record, json: bool) -> String {
+    let mut out = String::new();
+
+    if json {
+        let _ = write!(
+            out,
+            "{{\"sequence\":{},\"time_us\":{},\"level\":\"{}\",\"core\":{},\"file\":",
+            record.sequence,
+            record.time_us,
+            level_name(record.level),
+            record.core
+        );
+        json_string(&mut out, &record.file);
+        let _ = write!(out, ",\"line\":{},\"module\":", record.line);
+        json_string(&mut out, &record.module);
+        out.push_str(",\"message\":");
+        json_string(&mut out, &record.message);
+        out.push('}');
+    } else {
+        // The same as the kernel's own plain lines
+        let _ = write!(
+            out,
+            "[{:>5}.{:06}] #{} [{}] {}:{} {} {}",
+            record.time_us / 1_000_000,
+            record.time_us % 1_000_000,
+            record.sequence,
+            record.core,
+            record.file,
+            record.line,
+            level_name(record.level),
+            record.message
+        );
+    }
+
+    out
+}
+
+fn wanted(record: &Record, options: &Options) -> bool {
+    record.level <= options.level
+        && options
+            .module
+            .as_ref()
+            .is_none_or(|prefix| record.module.starts_with(prefix.as_str()))
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        json: false,
+        raw: false,
+        level: LEVELS.len() as u8,
+        module: None,
+        file: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => options.json = true,
+            "--raw" => options.raw = true,
+            "--level" => {
+                let level = args.next().ok_or("--level needs a level")?;
+                let index = LEVELS
+                    .iter()
+                    .position(|&name| name == level)
+                    .ok_or_else(|| format!("unknown level {level}"))?;
+                options.level = index as u8 + 1;
+            }
+            "--module" => options.module = Some(args.next().ok_or("--module needs a prefix")?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ => options.file = Some(arg),
+        }
+    }
+
+    Ok(options)
+}
+
+fn main() -> ExitCode {
+    if std::env::args().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("logdecode: {err}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut input = Vec::new();
+    let read = match &options.file {
+        Some(path) => std::fs::File::open(path).and_then(|mut file| file.read_to_end(&mut input)),
+        None => io::stdin().read_to_end(&mut input),
+    };
+    if let Err(err) = read {
+        eprintln!("logdecode: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut stdout = io::stdout().lock();
+    let mut stderr = io::stderr().lock();
+
+    let mut offset = 0;
+    while offset < input.len() {
+        if let Some((record, len)) = frame(&input[offset..]) {
+            if wanted(&record, &options) {
+                let _ = writeln!(stdout, "{}", format(&record, options.json));
+            }
+            offset += len;
+            continue;
+        }
+
+        // Up to the next thing that could be a frame
+        let next = input[offset + 1..]
+            .windows(MAGIC.len())
+            .position(|window| window == MAGIC)
+            .map_or(input.len(), |position| offset + 1 + position);
+
+        if options.raw {
+            let _ = stderr.write_all(&input[offset..next]);
+        }
+        offset = next;
+    }
+
+    ExitCode::SUCCESS
+}
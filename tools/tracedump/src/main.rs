@@ -0,0 +1,275 @@
+/*
+ * Beryl: A pragmatic microkernel written in rust
+ * Copyright (C) 2023  Franco Longo
+
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::process::ExitCode;
+
+const BEGIN: &str = "-----BEGIN BERYL TRACE-----";
+const END: &str = "-----END BERYL TRACE crc32=";
+/// The record layout this understands
+const VERSION: u32 = 1;
+
+const RECORD_SIZE: usize = 32;
+
+/// In the order of the kernel's `trace::Event`
+const EVENTS: &[&str] = &[
+    "irq_entry",
+    "irq_exit",
+    "pmm_alloc",
+    "pmm_free",
+    "heap_alloc",
+    "heap_free",
+    "idle_enter",
+    "idle_exit",
+];
+
+const USAGE: &str = "\
+usage: tracedump [--json] [file]
+
+Decodes the last trace dump in a serial capture, from standard input without a file, into a
+timeline of every core's events. --json prints Chrome trace events instead, for about:tracing
+or Perfetto.";
+
+struct Record {
+    ticks: u64,
+    event: u32,
+    core: u32,
+    args: [u64; 2],
+}
+
+#[derive(Default)]
+struct Trace {
+    version: u32,
+    tsc_per_us: u64,
+    records: Vec<Record>,
+    /// Core and records overwritten before the dump
+    dropped: Vec<(u32, u64)>,
+    intact: bool,
+}
+
+/// CRC-32 as zlib computes it, the kernel checksums the dump with it
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+fn record(line: &str) -> Option<Record> {
+    if line.len() != RECORD_SIZE * 2 {
+        return None;
+    }
+
+    let bytes: Vec<u8> = (0..RECORD_SIZE)
+        .map(|i| u8::from_str_radix(&line[i * 2..i * 2 + 2], 16).ok())
+        .collect::<Option<_>>()?;
+    let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+    Some(Record {
+        ticks: u64_at(0),
+        event: u32_at(8),
+        core: u32_at(12),
+        args: [u64_at(16), u64_at(24)],
+    })
+}
+
+fn parse_line(trace: &mut Trace, line: &str) -> Option<()> {
+    let mut fields = line.split(' ');
+
+    match fields.next()? {
+        "version" => trace.version = fields.next()?.parse().ok()?,
+        "tsc_per_us" => trace.tsc_per_us = fields.next()?.parse().ok()?,
+        "core" => {
+            let core = fields.next()?.parse().ok()?;
+            let _count: u64 = fields.next()?.parse().ok()?;
+            let dropped = fields.next()?.parse().ok()?;
+            trace.dropped.push((core, dropped));
+        }
+        _ => trace.records.push(record(line)?),
+    }
+
+    Some(())
+}
+
+/// The last dump in `capture`
+fn last_trace(capture: &str) -> Option<Trace> {
+    let mut last = None;
+    let mut lines = capture.lines().map(|line| line.trim_end_matches('\r'));
+
+    while lines.any(|line| line.ends_with(BEGIN)) {
+        let mut trace = Trace::default();
+        let mut body = String::new();
+
+        for line in lines.by_ref() {
+            if let Some(crc) = line.strip_prefix(END) {
+                let crc = crc
+                    .strip_suffix("-----")
+                    .and_then(|crc| u32::from_str_radix(crc, 16).ok());
+                trace.intact = crc == Some(crc32(body.as_bytes()));
+                break;
+            }
+
+            body.push_str(line);
+            body.push('\n');
+            if parse_line(&mut trace, line).is_none() {
+                eprintln!("tracedump: can't parse {line:?}");
+            }
+        }
+
+        last = Some(trace);
+    }
+
+    last
+}
+
+fn name(event: u32) -> &'static str {
+    EVENTS.get(event as usize).copied().unwrap_or("unknown")
+}
+
+fn describe(record: &Record) -> String {
+    let [a, b] = record.args;
+
+    match name(record.event) {
+        "irq_entry" | "irq_exit" => format!("vector {a:#x}"),
+        "pmm_alloc" | "pmm_free" => format!("{a:#x}, {b} pages"),
+        "heap_alloc" | "heap_free" => format!("{a:#x}, {b} bytes"),
+        "idle_enter" => format!("C-state {a}"),
+        "idle_exit" => format!("after {a}us"),
+        _ => format!("{a:#x} {b:#x}"),
+    }
+}
+
+fn timeline(trace: &Trace) -> String {
+    let mut out = String::new();
+    let tsc_per_us = trace.tsc_per_us.max(1);
+
+    for record in &trace.records {
+        let us = record.ticks / tsc_per_us;
+        let _ = writeln!(
+            out,
+            "[{:>5}.{:06}] [{}] {:<10} {}",
+            us / 1_000_000,
+            us % 1_000_000,
+            record.core,
+            name(record.event),
+            describe(record)
+        );
+    }
+
+    out
+}
+
+/// Chrome's trace event format, interrupts and idle periods as spans and allocations as instants
+fn json(trace: &Trace) -> String {
+    let mut out = String::from("[");
+    let tsc_per_us = trace.tsc_per_us.max(1) as f64;
+
+    for (i, record) in trace.records.iter().enumerate() {
+        let (phase, span) = match name(record.event) {
+            "irq_entry" => ("B", "irq"),
+            "irq_exit" => ("E", "irq"),
+            "idle_enter" => ("B", "idle"),
+            "idle_exit" => ("E", "idle"),
+            other => ("i", other),
+        };
+
+        let _ = write!(
+            out,
+            "{}\n{{\"name\":\"{span}\",\"ph\":\"{phase}\",\"ts\":{:.3},\"pid\":0,\"tid\":{},\
+             \"s\":\"t\",\"args\":{{\"detail\":\"{}\"}}}}",
+            if i == 0 { "" } else { "," },
+            record.ticks as f64 / tsc_per_us,
+            record.core,
+            describe(record)
+        );
+    }
+
+    out.push_str("\n]\n");
+    out
+}
+
+fn main() -> ExitCode {
+    let mut as_json = false;
+    let mut file = None;
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--help" | "-h" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            "--json" => as_json = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("tracedump: unknown option {arg}\n\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+            _ => file = Some(arg),
+        }
+    }
+
+    let mut input = Vec::new();
+    let read = match &file {
+        Some(path) => std::fs::File::open(path).and_then(|mut file| file.read_to_end(&mut input)),
+        None => io::stdin().read_to_end(&mut input),
+    };
+    if let Err(err) = read {
+        eprintln!("tracedump: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    let Some(mut trace) = last_trace(&String::from_utf8_lossy(&input)) else {
+        eprintln!("tracedump: no trace dump in the capture");
+        return ExitCode::FAILURE;
+    };
+
+    if !trace.intact {
+        eprintln!("tracedump: warning: the checksum doesn't match, the capture is damaged");
+    }
+    if trace.version != VERSION {
+        eprintln!(
+            "tracedump: dump version {} isn't {VERSION}, records may decode wrong",
+            trace.version
+        );
+    }
+    for &(core, dropped) in trace.dropped.iter().filter(|(_, dropped)| *dropped != 0) {
+        eprintln!("tracedump: core {core} overwrote its {dropped} oldest records");
+    }
+
+    // Every core's ring comes out oldest first, the TSC is what lines them up
+    trace.records.sort_by_key(|record| record.ticks);
+
+    if as_json {
+        print!("{}", json(&trace));
+    } else {
+        print!("{}", timeline(&trace));
+    }
+
+    ExitCode::SUCCESS
+}